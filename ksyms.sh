@@ -0,0 +1,24 @@
+#!/bin/sh
+# Build the kernel with its symbol table linked in, so that panics print
+# backtraces with function names (see src/ksyms.rs). Arguments are passed
+# on to cargo build, like --release or --features unmatched. TARGET names
+# the target if it isn't the default one, and NM a different nm.
+
+set -e
+
+profile=debug
+for arg in "$@"; do
+	[ "$arg" = --release ] && profile=release
+done
+target=${TARGET:-riscv64gc-unknown-none-elf}
+kernel=target/$target/$profile/rust-riscv-os
+table=target/ksyms.txt
+
+mkdir -p target
+: > "$table"
+KSYMS=$PWD/$table cargo build --features ksyms "$@"
+${NM:-nm} -n -C --defined-only "$kernel" |
+	awk '$2 ~ /^[tTwW]$/ && $3 !~ /^(\$|\.L)/ { name = substr($0, index($0, $3)); sub(/::h[0-9a-f]+$/, "", name); print $1, name }' \
+	> "$table.new"
+mv "$table.new" "$table"
+KSYMS=$PWD/$table cargo build --features ksyms "$@"
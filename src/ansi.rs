@@ -0,0 +1,404 @@
+// ANSI escape sequences, the ones terminals understand (ECMA-48 and what
+// the VT100 and xterm added to it), taken apart byte by byte. The same
+// parser reads both ways: what's written to the framebuffer console (see
+// fbcon.rs), which draws what the sequences say, and what comes in from
+// the keyboard or the terminal on the UART, where keys without a character
+// of their own send sequences (see the shell's line editor, shell.rs).
+//
+// A sequence starts with ESC. Then comes
+//
+//   [ <params> <final>   a control sequence (CSI): numbers split by ;,
+//                        maybe with a private marker like ? before them,
+//                        and a final byte, @ to ~, that says what it is
+//   O <final>            a single shift (SS3), which is how the keypad
+//                        and F1 to F4 come in application mode
+//   anything else        on its own, which is how Alt+key comes in
+//
+// Keys are decoded from those into Input events: arrows, Home and End as
+// CSI or SS3 with a letter, the editing keys and F5 and up as CSI <n> ~,
+// and the mouse, as xterm reports it, either ESC [ M and three bytes (X10)
+// or ESC [ < <button> ; <x> ; <y> M or m (SGR). Modifiers sent as a second
+// parameter (ESC [ 1 ; 5 C for Ctrl+Right) are ignored.
+//
+// The X10 mouse report looks like the CSI that deletes lines (ESC [ M), so
+// only a parser of input takes it as one.
+
+/// How many parameters of a control sequence are kept, the rest are
+/// ignored.
+pub const MAX_PARAMS: usize = 8;
+
+const ESC: u8 = 0x1b;
+
+/// A control sequence, ESC [ <params> <final>.
+#[derive(Clone, Copy)]
+pub struct Csi {
+    pub params: [u16; MAX_PARAMS],
+    pub nparams: usize,
+    /// What came right after the [, if it's a private marker (< = > ?).
+    pub private: Option<u8>,
+    pub final_byte: u8,
+}
+
+impl Csi {
+    const fn new() -> Self {
+        Csi {
+            params: [0; MAX_PARAMS],
+            nparams: 0,
+            private: None,
+            final_byte: 0,
+        }
+    }
+
+    /// Parameter `i`, or `default` if it wasn't given. A 0 means the
+    /// default too, unless that's 0.
+    pub fn param(&self, i: usize, default: u16) -> u16 {
+        match self.params.get(i).copied().unwrap_or(0) {
+            0 if i >= self.nparams || default != 0 => default,
+            p => p,
+        }
+    }
+}
+
+/// What a byte finished.
+#[derive(Clone, Copy)]
+pub enum Sequence {
+    /// A byte that isn't part of a sequence: a character, or a control
+    /// character.
+    Byte(u8),
+    Csi(Csi),
+    /// ESC O and the byte after it.
+    Ss3(u8),
+    /// ESC and a byte that doesn't start a longer sequence.
+    Esc(u8),
+    /// An X10 mouse report, the three bytes after ESC [ M.
+    Mouse([u8; 3]),
+}
+
+/// Where we are in a sequence.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Normal,
+    /// After an ESC.
+    Escape,
+    /// After an ESC [, collecting parameters.
+    Csi,
+    /// After an ESC O.
+    Ss3,
+    /// After an ESC [ M from the mouse, and this many of its bytes.
+    Mouse(usize),
+}
+
+/// Takes sequences apart.
+pub struct Parser {
+    state: State,
+    csi: Csi,
+    mouse: [u8; 3],
+    /// Whether this is input, and ESC [ M is the mouse.
+    input: bool,
+}
+
+impl Parser {
+    /// A parser of what's written to a terminal.
+    pub const fn output() -> Self {
+        Parser {
+            state: State::Normal,
+            csi: Csi::new(),
+            mouse: [0; 3],
+            input: false,
+        }
+    }
+
+    /// A parser of what comes in from a terminal or a keyboard.
+    pub const fn input() -> Self {
+        Parser {
+            input: true,
+            ..Self::output()
+        }
+    }
+
+    /// Take the next byte, and return what it finishes, if anything.
+    pub fn feed(&mut self, b: u8) -> Option<Sequence> {
+        match self.state {
+            State::Normal if b == ESC => {
+                self.state = State::Escape;
+                None
+            }
+            State::Normal => Some(Sequence::Byte(b)),
+            State::Escape => {
+                self.state = match b {
+                    b'[' => State::Csi,
+                    b'O' => State::Ss3,
+                    ESC => State::Escape,
+                    _ => {
+                        self.state = State::Normal;
+                        return Some(Sequence::Esc(b));
+                    }
+                };
+                self.csi = Csi::new();
+                None
+            }
+            State::Ss3 => {
+                self.state = State::Normal;
+                Some(Sequence::Ss3(b))
+            }
+            State::Mouse(n) => {
+                self.mouse[n] = b;
+                if n < 2 {
+                    self.state = State::Mouse(n + 1);
+                    return None;
+                }
+                self.state = State::Normal;
+                Some(Sequence::Mouse(self.mouse))
+            }
+            State::Csi => self.csi_byte(b),
+        }
+    }
+
+    /// Take the next byte of a control sequence.
+    fn csi_byte(&mut self, b: u8) -> Option<Sequence> {
+        let csi = &mut self.csi;
+        match b {
+            b'0'..=b'9' => {
+                let i = csi.nparams.max(1) - 1;
+                csi.nparams = csi.nparams.max(1);
+                if i < MAX_PARAMS {
+                    let p = &mut csi.params[i];
+                    *p = p.saturating_mul(10).saturating_add((b - b'0') as u16);
+                }
+            }
+            b';' => csi.nparams = (csi.nparams.max(1) + 1).min(MAX_PARAMS),
+            b'<'..=b'?' if csi.nparams == 0 && csi.private.is_none() => csi.private = Some(b),
+            // Intermediate bytes, and markers where they don't go:
+            // nothing we know has them.
+            b' '..=b'/' | b'<'..=b'?' => {}
+            b'@'..=b'~' => {
+                csi.final_byte = b;
+                self.state = State::Normal;
+                if self.input && b == b'M' && csi.nparams == 0 && csi.private.is_none() {
+                    self.state = State::Mouse(0);
+                    return None;
+                }
+                return Some(Sequence::Csi(*csi));
+            }
+            // An ESC starts again, and other control characters call the
+            // sequence off.
+            ESC => self.state = State::Escape,
+            _ => {
+                self.state = State::Normal;
+                return Some(Sequence::Byte(b));
+            }
+        }
+        None
+    }
+}
+
+/// What the mouse did: a button was pressed or let go at (`x`, `y`),
+/// counting from 1, or it moved there, or the wheel turned.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Mouse {
+    /// 0 to 2 for the left, middle and right button, 3 for none (a
+    /// release in X10, or a move), 64 and up for the wheel.
+    pub button: u16,
+    pub x: u16,
+    pub y: u16,
+    pub pressed: bool,
+}
+
+/// A key, or what the mouse did.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// A character, or a control character like Enter or ^A.
+    Char(u8),
+    /// Alt and a key.
+    Alt(u8),
+    ArrowUp,
+    ArrowDown,
+    ArrowRight,
+    ArrowLeft,
+    Home,
+    End,
+    Insert,
+    Delete,
+    PageUp,
+    PageDown,
+    /// F1 to F12, by number.
+    F(u8),
+    Mouse(Mouse),
+    /// A sequence we don't know.
+    Unknown,
+}
+
+/// The key a letter at the end of a CSI or SS3 sequence is.
+fn letter(c: u8) -> Input {
+    match c {
+        b'A' => Input::ArrowUp,
+        b'B' => Input::ArrowDown,
+        b'C' => Input::ArrowRight,
+        b'D' => Input::ArrowLeft,
+        b'H' => Input::Home,
+        b'F' => Input::End,
+        b'P'..=b'S' => Input::F(c - b'P' + 1),
+        _ => Input::Unknown,
+    }
+}
+
+/// The key ESC [ `n` ~ is.
+fn tilde(n: u16) -> Input {
+    match n {
+        1 | 7 => Input::Home,
+        2 => Input::Insert,
+        3 => Input::Delete,
+        4 | 8 => Input::End,
+        5 => Input::PageUp,
+        6 => Input::PageDown,
+        11..=15 => Input::F((n - 10) as u8),
+        17..=21 => Input::F((n - 11) as u8),
+        23 | 24 => Input::F((n - 12) as u8),
+        _ => Input::Unknown,
+    }
+}
+
+/// What a sequence of input means.
+pub fn decode(seq: Sequence) -> Input {
+    match seq {
+        Sequence::Byte(c) => Input::Char(c),
+        Sequence::Esc(c) => Input::Alt(c),
+        Sequence::Ss3(c) => letter(c),
+        Sequence::Mouse([b, x, y]) => {
+            // All three are offset by 32, to make them printable.
+            let button = b.wrapping_sub(32) as u16;
+            Input::Mouse(Mouse {
+                button,
+                x: x.wrapping_sub(32) as u16,
+                y: y.wrapping_sub(32) as u16,
+                pressed: button & 3 != 3,
+            })
+        }
+        Sequence::Csi(csi) => match (csi.private, csi.final_byte) {
+            (Some(b'<'), b'M' | b'm') => Input::Mouse(Mouse {
+                button: csi.param(0, 0),
+                x: csi.param(1, 1),
+                y: csi.param(2, 1),
+                pressed: csi.final_byte == b'M',
+            }),
+            (None, b'~') => tilde(csi.param(0, 0)),
+            (None, c) => letter(c),
+            _ => Input::Unknown,
+        },
+    }
+}
+
+/// Turns what comes in from a terminal into keys.
+pub struct Keys {
+    parser: Parser,
+}
+
+impl Keys {
+    pub const fn new() -> Self {
+        Keys {
+            parser: Parser::input(),
+        }
+    }
+
+    /// Take the next byte, and return the key it finishes, if it does.
+    pub fn feed(&mut self, b: u8) -> Option<Input> {
+        self.parser.feed(b).map(decode)
+    }
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest::check, testing::shown};
+    use alloc::{format, string::String, vec::Vec};
+
+    /// What `parser` makes of `bytes`.
+    fn parse(parser: &mut Parser, bytes: &[u8]) -> Vec<Sequence> {
+        bytes.iter().filter_map(|&b| parser.feed(b)).collect()
+    }
+
+    /// The control sequence `bytes` are, if they're one.
+    fn csi(bytes: &[u8]) -> Option<Csi> {
+        match parse(&mut Parser::output(), bytes)[..] {
+            [Sequence::Csi(csi)] => Some(csi),
+            _ => None,
+        }
+    }
+
+    #[test_case]
+    fn parser_test() -> Result<(), String> {
+        let seqs = parse(&mut Parser::output(), b"a\x1b[1;31mb");
+        let ok = matches!(
+            seqs[..],
+            [Sequence::Byte(b'a'), Sequence::Csi(c), Sequence::Byte(b'b')]
+                if c.nparams == 2 && c.params[..2] == [1, 31] && c.final_byte == b'm'
+        );
+        check(ok, || "ESC [ 1 ; 31 m isn't SGR 1 and 31".into())?;
+        let c = csi(b"\x1b[?25l").ok_or("ESC [ ? 25 l isn't a CSI")?;
+        check(c.private == Some(b'?') && c.param(0, 0) == 25, || {
+            "ESC [ ? 25 l lost its marker or parameter".into()
+        })?;
+        // Parameters left out, or 0, are the default.
+        let c = csi(b"\x1b[;5H").ok_or("ESC [ ; 5 H isn't a CSI")?;
+        check(
+            c.param(0, 1) == 1 && c.param(1, 1) == 5 && c.param(2, 1) == 1,
+            || format!("ESC [ ; 5 H has {} and {}", c.param(0, 1), c.param(1, 1)),
+        )?;
+        let c = csi(b"\x1b[0J").ok_or("ESC [ 0 J isn't a CSI")?;
+        check(c.param(0, 0) == 0 && c.param(0, 1) == 1, || {
+            "ESC [ 0 J isn't its default".into()
+        })?;
+        // Huge numbers stop at the top.
+        let c = csi(b"\x1b[99999999A").ok_or("a huge parameter isn't a CSI")?;
+        check(c.param(0, 1) == u16::MAX, || {
+            "a huge parameter wrapped".into()
+        })?;
+        // ESC and a byte, and a newline that calls a sequence off.
+        let seqs = parse(&mut Parser::output(), b"\x1bc\x1b[1\n");
+        check(
+            matches!(seqs[..], [Sequence::Esc(b'c'), Sequence::Byte(b'\n')]),
+            || "ESC c and ESC [ 1 newline went wrong".into(),
+        )?;
+        // ESC [ M is deleting lines on the way out, not the mouse.
+        let c = csi(b"\x1b[M").ok_or("ESC [ M isn't a CSI on the way out")?;
+        check(c.final_byte == b'M', || "ESC [ M isn't DL".into())
+    }
+
+    #[test_case]
+    fn keys_test() -> Result<(), String> {
+        let mouse = |button, x, y, pressed| {
+            Input::Mouse(Mouse {
+                button,
+                x,
+                y,
+                pressed,
+            })
+        };
+        let cases: [(&[u8], Input); 12] = [
+            (b"q", Input::Char(b'q')),
+            (b"\x1b[A", Input::ArrowUp),
+            (b"\x1bOD", Input::ArrowLeft),
+            (b"\x1bOP", Input::F(1)),
+            (b"\x1b[1;5C", Input::ArrowRight),
+            (b"\x1b[3~", Input::Delete),
+            (b"\x1b[15~", Input::F(5)),
+            (b"\x1b[24~", Input::F(12)),
+            (b"\x1bx", Input::Alt(b'x')),
+            (b"\x1b[99~", Input::Unknown),
+            (b"\x1b[M\x20\x2a\x25", mouse(0, 10, 5, true)),
+            (b"\x1b[<2;10;5m", mouse(2, 10, 5, false)),
+        ];
+        for (bytes, want) in cases {
+            let mut keys = Keys::new();
+            let got: Vec<Input> = bytes.iter().filter_map(|&b| keys.feed(b)).collect();
+            check(got[..] == [want], || {
+                format!("{:?} isn't the key it should be", shown(bytes))
+            })?;
+        }
+        Ok(())
+    }
+}
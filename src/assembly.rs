@@ -1,5 +1,21 @@
-use core::arch::global_asm;
+use crate::{board, cpu::TrapFrame};
+use core::{arch::global_asm, mem::offset_of, mem::size_of};
 
-global_asm!(include_str!("asm/boot.S"));
-global_asm!(include_str!("asm/mem.S"));
-global_asm!(include_str!("asm/trap.S"));
+// The files are put together into one, so that the macros of xlen.S are
+// there for the others. We're built for riscv64gc, which has an FPU, or for
+// riscv32imac, which doesn't.
+global_asm!(
+    include_str!("asm/xlen.S"),
+    include_str!("asm/boot.S"),
+    include_str!("asm/mem.S"),
+    include_str!("asm/trap.S"),
+    boot_hart = const board::BOOT_HART,
+    old_priv_spec = const board::OLD_PRIV_SPEC as usize,
+    regbytes = const size_of::<usize>(),
+    has_fpu = const cfg!(target_arch = "riscv64") as usize,
+    fregs = const offset_of!(TrapFrame, fregs),
+    satp = const offset_of!(TrapFrame, satp),
+    trap_stack = const offset_of!(TrapFrame, trap_stack),
+    pc = const offset_of!(TrapFrame, pc),
+    mode = const offset_of!(TrapFrame, mode),
+);
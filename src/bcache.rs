@@ -0,0 +1,616 @@
+// The block cache. Filesystems read and write their disks a block at a
+// time through here instead of going to the driver for every 512-byte
+// sector. A block is BLOCK_SIZE bytes and is known by its disk and its
+// number, counting in BLOCK_SIZE units from the start of the disk. Every
+// block that is in memory is in the cache exactly once, so everyone that
+// reads it sees the same bytes. bread() hands out a reference-counted Buf;
+// a block that nobody has a Buf for any more stays cached until the cache
+// is full or memory runs short, and then the least recently used one goes.
+// Changing a block marks it dirty. bwrite() writes it out right away, and
+// waits for the disk; bdwrite() leaves it for later, so that a block that's
+// changed again and again (a bitmap, a block of inodes) goes to the disk
+// once instead of every time. It gets there when sync() writes out every
+// dirty block, before it's thrown away, or when the flusher, a kernel
+// process that looks every second, finds it has been dirty for
+// DIRTY_EXPIRE. While memory is short, or half the cache is dirty, the
+// flusher writes out every dirty block, and looks again sooner, so that
+// there are clean blocks for reclaim() to throw away without waiting.
+//
+// Blocks can be read ahead, before anyone asks for them, and written out
+// without waiting for the disk (bwrite_start()), both with bios that run
+// while the caller goes on. A block stays in the cache while a bio has it;
+// whoever asks for a block that's still being read waits for it, and
+// bwrite() waits for a write that's already going.
+//
+// How far ahead to read is up to a Readahead, which an opened file or disk
+// keeps: as long as each read starts where the one before it stopped, the
+// window after it doubles, from READAHEAD_MIN up to READAHEAD_MAX, so that
+// a file read from start to end is on its way from the disk well before
+// it's asked for. A read from anywhere else closes the window again.
+
+use crate::{
+    block::{self, Bio, Op, SECTOR_SIZE},
+    cpu, fdt, page,
+    syscall::{SysError, TimeSpec, EINVAL, EIO},
+    timer::{self, NANOS_PER_SEC},
+    user,
+};
+use alloc::{collections::BTreeMap, rc::Rc, vec, vec::Vec};
+use core::{
+    cell::{Cell, Ref, RefCell, RefMut},
+    ptr::addr_of_mut,
+};
+
+use SysError::Errno;
+
+/// The size of a block.
+pub const BLOCK_SIZE: usize = 1024;
+const SECTORS_PER_BLOCK: u64 = (BLOCK_SIZE / SECTOR_SIZE) as u64;
+/// How many blocks we keep, unless the kernel command line says
+/// bcache=<blocks>.
+const DEFAULT_CAPACITY: usize = 128;
+/// How far sequential reads are read ahead of, in bytes, at first and at
+/// most.
+const READAHEAD_MIN: usize = 4 * BLOCK_SIZE;
+const READAHEAD_MAX: usize = 32 * BLOCK_SIZE;
+
+struct Block {
+    data: Vec<u8>,
+    dirty: bool,
+    // Whether data has the block's bytes: it doesn't while it's being read
+    // ahead, and not if that went wrong.
+    valid: bool,
+    // Being read ahead.
+    loading: bool,
+    // Being written out.
+    writing: bool,
+    // The last write went wrong. The block stays dirty.
+    failed: bool,
+    // When it was changed while it was clean, on the monotonic clock.
+    dirtied: u64,
+}
+
+impl Block {
+    fn new(valid: bool) -> Block {
+        Block {
+            data: vec![0; BLOCK_SIZE],
+            dirty: false,
+            valid,
+            loading: !valid,
+            writing: false,
+            failed: false,
+            dirtied: 0,
+        }
+    }
+
+    fn set_dirty(&mut self) {
+        if !self.dirty {
+            self.dirty = true;
+            self.dirtied = timer::monotonic_ns();
+        }
+    }
+}
+
+struct Entry {
+    block: Rc<RefCell<Block>>,
+    // When it was last handed out, for picking what to throw away.
+    last_used: u64,
+}
+
+struct Cache {
+    // By disk and block number.
+    blocks: BTreeMap<(usize, u64), Entry>,
+    capacity: usize,
+    clock: u64,
+    // Set while the cache is being changed, so that reclaim() called from
+    // the allocator doesn't change it under us.
+    busy: bool,
+}
+
+static mut CACHE: Cache = Cache {
+    blocks: BTreeMap::new(),
+    capacity: DEFAULT_CAPACITY,
+    clock: 0,
+    busy: false,
+};
+
+fn cache() -> &'static mut Cache {
+    unsafe { &mut *addr_of_mut!(CACHE) }
+}
+
+/// A block of a disk, as bread() hands it out. Dropping it (or brelse())
+/// lets the cache throw the block away again.
+pub struct Buf {
+    dev: usize,
+    block: u64,
+    inner: Rc<RefCell<Block>>,
+}
+
+impl Buf {
+    /// The block's bytes.
+    pub fn data(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.inner.borrow(), |b| &b.data[..])
+    }
+
+    /// The block's bytes, to change. That marks the block dirty, bwrite()
+    /// writes it out.
+    pub fn data_mut(&self) -> RefMut<'_, [u8]> {
+        RefMut::map(self.inner.borrow_mut(), |b| {
+            b.set_dirty();
+            &mut b.data[..]
+        })
+    }
+}
+
+/// The sectors block `block` of disk `dev` is made of. The last block of a
+/// disk can be short.
+fn sectors(dev: usize, block: u64) -> Result<(u64, usize), SysError> {
+    let d = block::get(dev).ok_or(Errno(EIO))?;
+    let first = block.checked_mul(SECTORS_PER_BLOCK).ok_or(Errno(EINVAL))?;
+    let count = d.num_sectors().saturating_sub(first).min(SECTORS_PER_BLOCK);
+    if count == 0 {
+        return Err(Errno(EINVAL));
+    }
+    Ok((first, count as usize * SECTOR_SIZE))
+}
+
+fn write_out(dev: usize, block: u64, b: &mut Block) -> Result<(), SysError> {
+    let (sector, len) = sectors(dev, block)?;
+    block::get(dev)
+        .ok_or(Errno(EIO))?
+        .write_sectors(sector, &b.data[..len])?;
+    b.dirty = false;
+    Ok(())
+}
+
+impl Cache {
+    /// Throw away the least recently used block that nobody is using,
+    /// writing it out first if it's dirty. Returns false if there is none.
+    /// The bio of a block being read or written has a reference to it, so
+    /// such a block is in use.
+    fn evict(&mut self) -> bool {
+        let victim = self
+            .blocks
+            .iter()
+            .filter(|(_, e)| Rc::strong_count(&e.block) == 1)
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(&key, _)| key);
+        let Some((dev, block)) = victim else {
+            return false;
+        };
+        let entry = self.blocks.remove(&(dev, block)).unwrap();
+        let mut b = entry.block.borrow_mut();
+        // The block is gone once we return, so this can't sleep and be
+        // restarted: wait for the disk.
+        if b.dirty && block::with_sleep(false, || write_out(dev, block, &mut b)).is_err() {
+            println!("bcache: lost a write to block {} of disk {}", block, dev);
+        }
+        true
+    }
+
+    /// Bufs of the blocks that `keep(dev, block)` says to.
+    fn bufs(&self, keep: impl Fn(usize, &Block) -> bool) -> Vec<Buf> {
+        let blocks = self
+            .blocks
+            .iter()
+            .filter(|(&(dev, _), e)| keep(dev, &e.block.borrow()));
+        blocks
+            .map(|(&(dev, block), e)| Buf {
+                dev,
+                block,
+                inner: e.block.clone(),
+            })
+            .collect()
+    }
+
+    fn get(&mut self, dev: usize, block: u64) -> Result<Buf, SysError> {
+        self.clock += 1;
+        if let Some(e) = self.blocks.get_mut(&(dev, block)) {
+            e.last_used = self.clock;
+            let inner = e.block.clone();
+            wait_while(dev, &inner, |b| b.loading)?;
+            if inner.borrow().valid {
+                return Ok(Buf { dev, block, inner });
+            }
+            // Reading it ahead didn't work, try again the slow way.
+            self.blocks.remove(&(dev, block));
+        }
+        let (sector, len) = sectors(dev, block)?;
+        // Make room. If every block is in use, the cache grows past its
+        // capacity for now.
+        while self.blocks.len() >= self.capacity && self.evict() {}
+        let mut b = Block::new(true);
+        block::get(dev)
+            .ok_or(Errno(EIO))?
+            .read_sectors(sector, &mut b.data[..len])?;
+        let inner = Rc::new(RefCell::new(b));
+        self.blocks.insert(
+            (dev, block),
+            Entry {
+                block: inner.clone(),
+                last_used: self.clock,
+            },
+        );
+        Ok(Buf { dev, block, inner })
+    }
+}
+
+/// Wait for a bio of `dev` that has block `b` to finish, while `busy(b)`
+/// says it hasn't. A caller that can sleep gets Block instead.
+fn wait_while(
+    dev: usize,
+    b: &Rc<RefCell<Block>>,
+    busy: fn(&Block) -> bool,
+) -> Result<(), SysError> {
+    while busy(&b.borrow()) {
+        if block::may_sleep() {
+            return Err(SysError::Block);
+        }
+        block::poll(dev);
+    }
+    Ok(())
+}
+
+/// Run `f` on the cache, which is marked busy meanwhile. This nests: the
+/// requests of a loop device come back here for the disk behind it.
+fn with_cache<T>(f: impl FnOnce(&mut Cache) -> T) -> T {
+    let c = cache();
+    let before = c.busy;
+    c.busy = true;
+    let ret = f(c);
+    c.busy = before;
+    ret
+}
+
+/// Take the size of the cache from the kernel command line.
+pub fn init() {
+    let capacity = fdt::get()
+        .and_then(|f| f.find("/chosen"))
+        .and_then(|c| c.str_property("bootargs"))
+        .and_then(|args| {
+            args.split_ascii_whitespace()
+                .find_map(|arg| arg.strip_prefix("bcache="))
+        })
+        .and_then(|n| n.parse().ok());
+    if let Some(n) = capacity {
+        set_capacity(n);
+    }
+}
+
+/// Keep at most `blocks` blocks, throwing away what's too much right away.
+pub fn set_capacity(blocks: usize) {
+    with_cache(|c| {
+        c.capacity = blocks.max(1);
+        while c.blocks.len() > c.capacity && c.evict() {}
+    });
+}
+
+/// Get block `block` of disk `dev`, reading it if it isn't cached.
+pub fn bread(dev: usize, block: u64) -> Result<Buf, SysError> {
+    with_cache(|c| c.get(dev, block))
+}
+
+/// Start reading the `count` blocks of disk `dev` from `block` on, those
+/// that aren't cached, without waiting for them. This stops at the end of
+/// the disk, and when a block would only fit by growing the cache.
+pub fn readahead(dev: usize, block: u64, count: u64) {
+    with_cache(|c| {
+        for n in block..block.saturating_add(count) {
+            if c.blocks.contains_key(&(dev, n)) {
+                continue;
+            }
+            let Ok((sector, len)) = sectors(dev, n) else {
+                break;
+            };
+            while c.blocks.len() >= c.capacity && c.evict() {}
+            if c.blocks.len() >= c.capacity {
+                break;
+            }
+            let inner = Rc::new(RefCell::new(Block::new(false)));
+            c.clock += 1;
+            c.blocks.insert(
+                (dev, n),
+                Entry {
+                    block: inner.clone(),
+                    last_used: c.clock,
+                },
+            );
+            block::submit(
+                dev,
+                Bio {
+                    op: Op::Read,
+                    sector,
+                    data: vec![0; len],
+                    done: read_done,
+                    private: Rc::into_raw(inner) as usize,
+                },
+            );
+        }
+    });
+}
+
+/// How an opened file or disk has been read, to know how far ahead of the
+/// next read to read.
+pub struct Readahead {
+    // The last read, and the window after it.
+    start: Cell<usize>,
+    end: Cell<usize>,
+    window: Cell<usize>,
+}
+
+impl Readahead {
+    pub const fn new() -> Self {
+        Readahead {
+            start: Cell::new(0),
+            end: Cell::new(0),
+            window: Cell::new(0),
+        }
+    }
+
+    /// `len` bytes are about to be read from `offset`. Returns how many
+    /// bytes after them should be read ahead.
+    pub fn window(&self, offset: usize, len: usize) -> usize {
+        let window = if offset == self.start.get() && self.end.get() != 0 {
+            // The same read again, restarted after it blocked.
+            self.window.get()
+        } else if offset == self.end.get() {
+            (self.window.get() * 2).clamp(READAHEAD_MIN, READAHEAD_MAX)
+        } else {
+            0
+        };
+        self.start.set(offset);
+        self.end.set(offset.saturating_add(len));
+        self.window.set(window);
+        window
+    }
+}
+
+/// The block a bio of ours was for. The bio had a reference to it.
+fn bio_block(bio: &Bio) -> Rc<RefCell<Block>> {
+    unsafe { Rc::from_raw(bio.private as *const RefCell<Block>) }
+}
+
+fn read_done(_dev: usize, bio: Bio, ret: Result<(), SysError>) {
+    let inner = bio_block(&bio);
+    let mut b = inner.borrow_mut();
+    b.loading = false;
+    if ret.is_ok() {
+        b.data[..bio.data.len()].copy_from_slice(&bio.data);
+        b.valid = true;
+    }
+}
+
+fn write_done(dev: usize, bio: Bio, ret: Result<(), SysError>) {
+    let inner = bio_block(&bio);
+    let mut b = inner.borrow_mut();
+    b.writing = false;
+    if ret.is_err() {
+        println!(
+            "bcache: writing block {} of disk {} failed",
+            bio.sector / SECTORS_PER_BLOCK,
+            dev
+        );
+        b.set_dirty();
+        b.failed = true;
+    }
+}
+
+/// Start writing a block out if it has been changed, without waiting for
+/// the disk. bwrite() waits.
+pub fn bwrite_start(buf: &Buf) -> Result<(), SysError> {
+    let mut b = buf.inner.borrow_mut();
+    // A block changed again while it's being written goes out once that's
+    // done.
+    if !b.dirty || b.writing {
+        return Ok(());
+    }
+    let (sector, len) = sectors(buf.dev, buf.block)?;
+    b.dirty = false;
+    b.writing = true;
+    b.failed = false;
+    let data = b.data[..len].to_vec();
+    // Drivers that can't go on without waiting finish the bio right away.
+    drop(b);
+    block::submit(
+        buf.dev,
+        Bio {
+            op: Op::Write,
+            sector,
+            data,
+            done: write_done,
+            private: Rc::into_raw(buf.inner.clone()) as usize,
+        },
+    );
+    Ok(())
+}
+
+/// Write a block out if it has been changed, and wait until it's on the
+/// disk.
+pub fn bwrite(buf: &Buf) -> Result<(), SysError> {
+    loop {
+        bwrite_start(buf)?;
+        wait_while(buf.dev, &buf.inner, |b| b.writing)?;
+        let mut b = buf.inner.borrow_mut();
+        if b.failed {
+            b.failed = false;
+            return Err(Errno(EIO));
+        }
+        if !b.dirty {
+            return Ok(());
+        }
+    }
+}
+
+/// Say a block has been changed, and can be written out whenever: by
+/// sync(), or when it's thrown away.
+pub fn bdwrite(buf: &Buf) {
+    buf.inner.borrow_mut().set_dirty();
+}
+
+/// Write out the changed blocks of disk `dev`, or of every disk if it's
+/// None, and wait until they're on it. Returns EIO if one of them couldn't
+/// be written; it stays dirty. A caller that can sleep may get Block, and
+/// asking again goes on from there.
+///
+/// The cache doesn't know which file a block is of, so a filesystem asked
+/// to sync one file syncs its whole disk.
+pub fn sync(dev: Option<usize>) -> Result<(), SysError> {
+    let bufs =
+        with_cache(|c| c.bufs(|d, b| dev.is_none_or(|dev| d == dev) && (b.dirty || b.writing)));
+    // Start them all first, so that the disks have all of them to work on.
+    for buf in &bufs {
+        bwrite_start(buf)?;
+    }
+    let mut ret = Ok(());
+    for buf in &bufs {
+        match bwrite(buf) {
+            Err(SysError::Block) => return Err(SysError::Block),
+            Err(e) => ret = Err(e),
+            Ok(()) => {}
+        }
+    }
+    ret
+}
+
+/// Write out the changed blocks of disk `dev` and forget all of its blocks,
+/// for when what's behind the disk changes. Whoever still has a Buf of one
+/// keeps it, but it isn't in the cache any more.
+pub fn invalidate(dev: usize) {
+    with_cache(|c| {
+        let keys: Vec<u64> = c
+            .blocks
+            .range((dev, 0)..=(dev, u64::MAX))
+            .map(|(&(_, block), _)| block)
+            .collect();
+        for block in keys {
+            let entry = c.blocks.remove(&(dev, block)).unwrap();
+            block::with_sleep(false, || {
+                // Let the bios that have it finish first.
+                let _ = wait_while(dev, &entry.block, |b| b.loading || b.writing);
+                let mut b = entry.block.borrow_mut();
+                if b.dirty && write_out(dev, block, &mut b).is_err() {
+                    println!("bcache: lost a write to block {} of disk {}", block, dev);
+                }
+            });
+        }
+    });
+}
+
+/// Say we're done with a block.
+pub fn brelse(buf: Buf) {
+    drop(buf);
+}
+
+/// Memory is short: throw away every block that nobody is using. Returns
+/// how many went. This is called by the allocator, so it can't do anything
+/// while the cache itself is allocating.
+pub fn reclaim() -> usize {
+    unsafe {
+        SHORT_OF_MEMORY = true;
+    }
+    let c = cache();
+    if c.busy {
+        return 0;
+    }
+    with_cache(|c| {
+        let mut n = 0;
+        while c.evict() {
+            n += 1;
+        }
+        n
+    })
+}
+
+// ///////////////////////////////////
+// / WRITEBACK
+// ///////////////////////////////////
+
+/// How long a block stays dirty before the flusher writes it out.
+const DIRTY_EXPIRE: u64 = 5 * NANOS_PER_SEC;
+/// How often the flusher looks, and how often while memory is short.
+const FLUSH_INTERVAL: TimeSpec = TimeSpec {
+    tv_sec: 1,
+    tv_nsec: 0,
+};
+const SHORT_FLUSH_INTERVAL: TimeSpec = TimeSpec {
+    tv_sec: 0,
+    tv_nsec: 100_000_000,
+};
+
+// Set by reclaim(), for the flusher to find.
+static mut SHORT_OF_MEMORY: bool = false;
+
+/// Start writing out the blocks that have been dirty for `age` nanoseconds
+/// or more, without waiting for the disk. Returns how many there were.
+pub fn writeback(age: u64) -> usize {
+    let now = timer::monotonic_ns();
+    let bufs = with_cache(|c| {
+        c.bufs(|_, b| b.dirty && !b.writing && now.saturating_sub(b.dirtied) >= age)
+    });
+    for buf in &bufs {
+        // One that can't be written stays dirty, for the next time.
+        let _ = bwrite_start(buf);
+    }
+    bufs.len()
+}
+
+/// Whether the flusher should write out everything: memory has run out
+/// since it last looked, less than a sixteenth of it is free, or half of
+/// the cache is dirty.
+fn flush_everything() -> bool {
+    let ran_out = unsafe { core::mem::take(&mut *addr_of_mut!(SHORT_OF_MEMORY)) };
+    let (pages, taken) = page::stats();
+    let c = cache();
+    let dirty = c.blocks.values().filter(|e| e.block.borrow().dirty).count();
+    ran_out || pages - taken < pages / 16 || dirty * 2 > c.capacity
+}
+
+/// The flusher, a kernel process that writes out what has been dirty for
+/// long, so that a crash doesn't lose much and dirty blocks don't pile up.
+pub fn flusher() {
+    loop {
+        // Nothing else may get at the cache halfway through.
+        let everything = cpu::without_interrupts(|| {
+            let everything = flush_everything();
+            writeback(if everything { 0 } else { DIRTY_EXPIRE });
+            everything
+        });
+        // Kernel processes sleep the way user processes do.
+        user::nanosleep(if everything {
+            &SHORT_FLUSH_INTERVAL
+        } else {
+            &FLUSH_INTERVAL
+        });
+    }
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::check;
+    use alloc::{format, string::String};
+
+    /// Sequential reads widen the window, a seek closes it, and a read at
+    /// the very end of the address space doesn't overflow.
+    #[test_case]
+    fn readahead_test() -> Result<(), String> {
+        let ra = Readahead::new();
+        check(ra.window(0, BLOCK_SIZE) == 0, || "first read".into())?;
+        let w = ra.window(BLOCK_SIZE, BLOCK_SIZE);
+        check(w == READAHEAD_MIN, || format!("second read: {}", w))?;
+        let w = ra.window(BLOCK_SIZE, BLOCK_SIZE);
+        check(w == READAHEAD_MIN, || format!("restarted read: {}", w))?;
+        let w = ra.window(2 * BLOCK_SIZE, BLOCK_SIZE);
+        check(w == 2 * READAHEAD_MIN, || format!("third read: {}", w))?;
+        check(ra.window(100 * BLOCK_SIZE, 1) == 0, || "seek".into())?;
+        check(ra.window(usize::MAX, usize::MAX) == 0, || {
+            "at the end".into()
+        })?;
+        let w = ra.window(usize::MAX, 1);
+        check(w == 0, || format!("again at the end: {}", w))
+    }
+}
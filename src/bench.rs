@@ -0,0 +1,356 @@
+// Microbenchmarks, so that a change to the allocators or the scheduler
+// can be measured instead of guessed at. Each one does something small
+// over and over, times every round with the cycle counter (mcycle), and
+// says how many cycles the fastest, the average and the slowest round
+// took. The slowest is where an interrupt, a cache miss or the allocator
+// having to search shows up; the fastest is what it costs when nothing
+// gets in the way. The ones that move data also say how much they got
+// through in a second, by the timer, since the cycle counter's frequency
+// isn't known.
+//
+// They run from the shell with interrupts off, like any command, apart
+// from the context switch one: that starts a kernel process that does
+// nothing but give the hart back, and then gives it that process with
+// sched_yield itself, so each round is two switches (and whatever else is
+// runnable then gets a turn too). The UART one writes lines of spaces to
+// the console UART, which need a terminal there to be read at the speed
+// of the line, and the disk one reads single sectors from all over vda,
+// or the disk -d names, without the block cache.
+
+use crate::{
+    block::{self, SECTOR_SIZE},
+    console, cpu, kmem, page, process,
+    shell::{self, Builtin},
+    syscall::{
+        SysError, EINTR, EINVAL, ENODEV, ENOENT, ENOMEM, SYS_EXIT, SYS_GETPID, SYS_SCHED_YIELD,
+    },
+    timer::{self, NANOS_PER_SEC},
+    uart,
+};
+use alloc::vec::Vec;
+use core::{arch::asm, ptr::addr_of_mut};
+
+use SysError::Errno;
+
+/// How many rounds a benchmark runs, unless -n says otherwise.
+const ROUNDS: usize = 1000;
+/// The length of the lines the UART benchmark writes.
+const LINE: usize = 64;
+
+/// The cycles the rounds of a benchmark took, and how fast it went.
+struct Stats {
+    min: u64,
+    max: u64,
+    total: u64,
+    rounds: u64,
+    /// How many of what a second, if it moves data.
+    rate: Option<(u64, &'static str)>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            min: u64::MAX,
+            max: 0,
+            total: 0,
+            rounds: 0,
+            rate: None,
+        }
+    }
+
+    fn add(&mut self, cycles: u64) {
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.total += cycles;
+        self.rounds += 1;
+    }
+
+    /// Say `count` of `unit` were done in `ns`.
+    fn set_rate(&mut self, count: u64, ns: u64, unit: &'static str) {
+        self.rate = Some((count * NANOS_PER_SEC / ns.max(1), unit));
+    }
+}
+
+/// How many cycles `f` takes.
+fn time(f: impl FnOnce()) -> u64 {
+    let start = cpu::cycles();
+    f();
+    cpu::cycles() - start
+}
+
+/// A system call without arguments.
+fn syscall(number: usize) -> isize {
+    let ret;
+    unsafe {
+        asm!("ecall", inlateout("a0") 0isize => ret, in("a7") number);
+    }
+    ret
+}
+
+/// What the benchmarks are given: how many rounds, and the disk.
+struct Options<'a> {
+    rounds: usize,
+    disk: &'a str,
+}
+
+/// A benchmark: what it's called, what it measures, and the function that
+/// runs it.
+struct Bench {
+    name: &'static str,
+    what: &'static str,
+    run: fn(&Options) -> Result<Stats, SysError>,
+}
+
+// ///////////////////////////////////
+// / THE BENCHMARKS
+// ///////////////////////////////////
+
+/// Allocate a page and free it.
+fn page_bench(opts: &Options) -> Result<Stats, SysError> {
+    let mut stats = Stats::new();
+    for _ in 0..opts.rounds {
+        let mut p = core::ptr::null_mut();
+        stats.add(time(|| {
+            p = page::alloc(1);
+            if !p.is_null() {
+                page::dealloc(p);
+            }
+        }));
+        if p.is_null() {
+            return Err(Errno(ENOMEM));
+        }
+    }
+    Ok(stats)
+}
+
+/// Allocate 64 bytes from the kernel heap and free them.
+fn kmem_bench(opts: &Options) -> Result<Stats, SysError> {
+    let mut stats = Stats::new();
+    for _ in 0..opts.rounds {
+        let mut p = core::ptr::null_mut();
+        stats.add(time(|| {
+            p = kmem::kmalloc(64);
+            if !p.is_null() {
+                kmem::kfree(p);
+            }
+        }));
+        if p.is_null() {
+            return Err(Errno(ENOMEM));
+        }
+    }
+    Ok(stats)
+}
+
+/// Call getpid, through the trap handler and back.
+fn syscall_bench(opts: &Options) -> Result<Stats, SysError> {
+    let mut stats = Stats::new();
+    for _ in 0..opts.rounds {
+        stats.add(time(|| {
+            syscall(SYS_GETPID);
+        }));
+    }
+    Ok(stats)
+}
+
+/// Whether the process switch_bench() switches to is to keep going.
+static mut PARTNER: bool = false;
+
+/// What switch_bench() switches to: give the hart back until told to stop.
+fn partner() {
+    while unsafe { *addr_of_mut!(PARTNER) } {
+        syscall(SYS_SCHED_YIELD);
+    }
+    syscall(SYS_EXIT);
+}
+
+/// Switch to another process and back, and count half of it.
+fn switch_bench(opts: &Options) -> Result<Stats, SysError> {
+    unsafe {
+        PARTNER = true;
+    }
+    let pid = process::add_kernel_process(partner);
+    // Let it get going.
+    syscall(SYS_SCHED_YIELD);
+    let mut stats = Stats::new();
+    for _ in 0..opts.rounds {
+        let took = time(|| {
+            syscall(SYS_SCHED_YIELD);
+        });
+        stats.add(took / 2);
+    }
+    unsafe {
+        PARTNER = false;
+    }
+    while process::get_by_pid(pid).is_some() {
+        syscall(SYS_SCHED_YIELD);
+    }
+    Ok(stats)
+}
+
+/// Write lines to the console UART, and wait for them to go out.
+fn uart_bench(opts: &Options) -> Result<Stats, SysError> {
+    if console::backend() != console::Backend::Uart {
+        println!("uart: the console isn't on a UART");
+        return Err(Errno(ENODEV));
+    }
+    let uart = uart::console();
+    uart.flush();
+    let line = [b' '; LINE];
+    let mut stats = Stats::new();
+    let start = timer::monotonic_ns();
+    for _ in 0..opts.rounds {
+        stats.add(time(|| {
+            for &c in &line[..LINE - 1] {
+                uart.put(c);
+            }
+            uart.put(b'\r');
+        }));
+    }
+    uart.flush();
+    let ns = timer::monotonic_ns() - start;
+    stats.set_rate((opts.rounds * LINE) as u64, ns, "bytes");
+    Ok(stats)
+}
+
+/// Read a sector from somewhere on the disk, a different place each time.
+fn disk_bench(opts: &Options) -> Result<Stats, SysError> {
+    let Some(dev) = block::find(opts.disk.as_bytes()) else {
+        println!("disk: there's no disk called {}", opts.disk);
+        return Err(Errno(ENOENT));
+    };
+    let dev = block::get(dev).unwrap();
+    let sectors = dev.num_sectors();
+    if sectors == 0 {
+        return Err(Errno(ENODEV));
+    }
+    let mut buf = [0u8; SECTOR_SIZE];
+    let mut sector = 0u64;
+    let mut stats = Stats::new();
+    let start = timer::monotonic_ns();
+    for i in 0..opts.rounds {
+        // Far enough from the last one that the disk can't read ahead.
+        sector = (sector + 7919) % sectors;
+        let mut ret = Ok(());
+        stats.add(time(|| {
+            ret = block::with_sleep(false, || dev.read_sectors(sector, &mut buf));
+        }));
+        ret?;
+        if i % 64 == 63 && shell::cancelled() {
+            return Err(Errno(EINTR));
+        }
+    }
+    let ns = timer::monotonic_ns() - start;
+    stats.set_rate(opts.rounds as u64, ns, "reads");
+    Ok(stats)
+}
+
+const BENCHES: [Bench; 6] = [
+    Bench {
+        name: "page",
+        what: "page alloc and free",
+        run: page_bench,
+    },
+    Bench {
+        name: "kmem",
+        what: "64-byte kmalloc and kfree",
+        run: kmem_bench,
+    },
+    Bench {
+        name: "syscall",
+        what: "getpid round trip",
+        run: syscall_bench,
+    },
+    Bench {
+        name: "uart",
+        what: "64 bytes to the console UART",
+        run: uart_bench,
+    },
+    Bench {
+        name: "disk",
+        what: "one-sector read",
+        run: disk_bench,
+    },
+    // Last, since once another process has had the hart, interrupts are on
+    // until the command is done.
+    Bench {
+        name: "switch",
+        what: "context switch",
+        run: switch_bench,
+    },
+];
+
+// ///////////////////////////////////
+// / SHELL COMMAND
+// ///////////////////////////////////
+
+/// Run the benchmarks named in `args`, or all of them, -n ROUNDS times
+/// each, with -d naming the disk to read; -l lists them instead.
+fn bench_command(args: &[&str]) -> Result<(), SysError> {
+    let mut opts = Options {
+        rounds: ROUNDS,
+        disk: "vda",
+    };
+    let mut run = Vec::new();
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "-l" => {
+                for b in &BENCHES {
+                    println!("{:<8} {}", b.name, b.what);
+                }
+                return Ok(());
+            }
+            "-n" => opts.rounds = shell::number(args.next().ok_or(Errno(EINVAL))?)?,
+            "-d" => opts.disk = args.next().ok_or(Errno(EINVAL))?,
+            name => match BENCHES.iter().find(|b| b.name == name) {
+                Some(b) => run.push(b),
+                None => {
+                    println!("bench: there's no benchmark {}", name);
+                    return Err(Errno(ENOENT));
+                }
+            },
+        }
+    }
+    if opts.rounds == 0 {
+        return Err(Errno(EINVAL));
+    }
+    if run.is_empty() {
+        run = BENCHES.iter().collect();
+    }
+    println!(
+        "{:<8} {:>10} {:>10} {:>10}  cycles, {} rounds",
+        "", "min", "avg", "max", opts.rounds
+    );
+    for b in run {
+        if shell::cancelled() {
+            return Err(Errno(EINTR));
+        }
+        // A benchmark that can't run here says why, and the rest go on.
+        let stats = match (b.run)(&opts) {
+            Ok(stats) => stats,
+            Err(Errno(EINTR)) => return Err(Errno(EINTR)),
+            Err(_) => continue,
+        };
+        print!(
+            "{:<8} {:>10} {:>10} {:>10}",
+            b.name,
+            stats.min,
+            stats.total / stats.rounds,
+            stats.max
+        );
+        match stats.rate {
+            Some((rate, unit)) => println!("  {} {}/s", rate, unit),
+            None => println!(),
+        }
+    }
+    Ok(())
+}
+
+/// Register the bench command with the shell.
+pub fn init() {
+    shell::register(&Builtin {
+        name: "bench",
+        help: "bench [-l] [-n ROUNDS] [-d DISK] [NAME]... - time the kernel's hot paths",
+        run: bench_command,
+    });
+}
@@ -0,0 +1,931 @@
+// Block devices: disks that are read and written in sectors. Their drivers
+// implement BlockDevice and register them here, and they show up as
+// /dev/<name>: virtio-blk disks (a -drive attached to a virtio-blk-device in
+// QEMU) as /dev/vda, /dev/vdb, ..., in the order of their slots, SD cards
+// as /dev/mmcblk0, ..., NVMe namespaces as /dev/nvme0n1, ..., the ramdisk as /dev/ram0, and loop devices as
+// /dev/loop0, ... The partitions on a disk are block devices too, /dev/vda1
+// and so on (see partition.rs). Filesystems (and the device files) don't
+// come here directly, they go through the block cache.
+//
+// A driver may hand a request to its device and let whoever asked sleep
+// until the device interrupts to say it's done. Only a syscall can sleep
+// like that, by answering Block, waiting in the device's queue, and being
+// restarted once it's woken up. On the restart the same request is asked
+// for again, and the driver hands back its result. A syscall says it can
+// take this with with_sleep(true, ...). Everyone else (like the block
+// cache throwing out a dirty block to make room) has the driver wait for
+// the device the way it used to: by polling.
+//
+// Reads and writes can also go on without anyone waiting for them, as bios
+// handed to submit(), with a function to call when they're done. That's
+// how the block cache reads ahead and writes out several blocks at once.
+// The bios of a disk wait in a queue, in the order the I/O scheduler (the
+// elevator= of the kernel command line) puts them in, and go to the driver
+// as soon as the device has room, bios for sectors next to each other
+// merged into one request.
+
+use crate::{
+    bcache::{self, Readahead, BLOCK_SIZE},
+    cpu::TrapFrame,
+    dma,
+    dma::DmaBuffer,
+    fdt,
+    file::File,
+    partition,
+    process::WaitQueue,
+    syscall::{
+        write_user, Stat, SysError, SysResult, EINVAL, EIO, ENOMEM, ENOSPC, ENOTTY, EOPNOTSUPP,
+        EROFS,
+    },
+    virtio::{self, Buffer, Device, Queue},
+};
+use alloc::{
+    boxed::Box, collections::vec_deque::VecDeque, format, rc::Rc, string::String, vec, vec::Vec,
+};
+use core::{mem::size_of, ptr::addr_of_mut};
+
+use SysError::{Block, Errno};
+
+/// The unit disks are read and written in, whatever their real sector size.
+pub const SECTOR_SIZE: usize = 512;
+
+/// The file type of a block device, for st_mode.
+pub const S_IFBLK: u32 = 0o060000;
+
+// ioctls
+pub const BLKSSZGET: usize = 0x1268;
+pub const BLKGETSIZE64: usize = 0x8008_1272;
+
+/// What a block device driver provides.
+pub trait BlockDevice {
+    /// Its name in /dev, like vda.
+    fn name(&self) -> &str;
+
+    /// Its device number on Linux, for stat.
+    fn rdev(&self) -> u64;
+
+    /// The size of the disk in sectors.
+    fn num_sectors(&self) -> u64;
+
+    fn read_only(&self) -> bool;
+
+    /// Read whole sectors into `buf`, starting at `sector`. This can
+    /// answer Block if may_sleep().
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), SysError>;
+
+    /// Write whole sectors from `buf`, starting at `sector`. This can
+    /// answer Block if may_sleep().
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), SysError>;
+
+    /// The device interrupted us.
+    fn handle_interrupt(&mut self) {}
+
+    /// Hand `rq` to the device without waiting for it to finish. A driver
+    /// that can't get its device to go on without it doesn't have to: by
+    /// default, the request is carried out right away.
+    fn queue(&mut self, mut rq: IoRequest) -> Queued {
+        let mut data = gather(&rq);
+        let ret = with_sleep(false, || match rq.op {
+            Op::Read => self.read_sectors(rq.sector, &mut data),
+            Op::Write => self.write_sectors(rq.sector, &data),
+        });
+        if ret.is_ok() && rq.op == Op::Read {
+            scatter(&mut rq, &data);
+        }
+        Queued::Done(rq, ret.map_err(errno))
+    }
+
+    /// The requests queue() started that have finished since this was last
+    /// asked, with how they went.
+    fn finished(&mut self) -> Vec<(IoRequest, Result<(), isize>)> {
+        Vec::new()
+    }
+
+    /// Look for what the device has finished, without an interrupt.
+    fn poll(&mut self) {}
+
+    /// Put process `pid` to sleep until a request that answered Block may
+    /// go on. Returns false if the driver leaves that to us, and the
+    /// process waits until the device has finished something.
+    fn wait(&mut self, _pid: usize) -> bool {
+        false
+    }
+
+    /// The ioctls that are the driver's own, the ones that aren't the same
+    /// for every disk.
+    fn ioctl(&mut self, _frame: &TrapFrame, _cmd: usize, _arg: usize) -> SysResult {
+        Err(Errno(ENOTTY))
+    }
+}
+
+// Whether requests may answer Block instead of waiting for the device,
+// and how many with_sleep(false, ...) we're in. Inside one, nothing may
+// sleep, not even what a with_sleep(true, ...) in it does: a loop device
+// reads its file that way.
+static mut MAY_SLEEP: bool = false;
+static mut FORBIDDEN: usize = 0;
+
+/// Run `f` with requests allowed to answer Block or not.
+pub fn with_sleep<T>(allowed: bool, f: impl FnOnce() -> T) -> T {
+    let before = unsafe { MAY_SLEEP };
+    unsafe {
+        MAY_SLEEP = allowed;
+        if !allowed {
+            FORBIDDEN += 1;
+        }
+    }
+    let ret = f();
+    unsafe {
+        MAY_SLEEP = before;
+        if !allowed {
+            FORBIDDEN -= 1;
+        }
+    }
+    ret
+}
+
+/// May a request that hasn't finished answer Block?
+pub fn may_sleep() -> bool {
+    unsafe { MAY_SLEEP && FORBIDDEN == 0 }
+}
+
+static mut DEVICES: Vec<Box<dyn BlockDevice>> = Vec::new();
+
+fn devices() -> &'static mut Vec<Box<dyn BlockDevice>> {
+    unsafe { &mut *addr_of_mut!(DEVICES) }
+}
+
+/// Add a block device, and the partitions on it. Returns its number.
+pub fn register(dev: Box<dyn BlockDevice>) -> usize {
+    let n = add(dev);
+    partition::scan(n);
+    n
+}
+
+/// Add a partition of a disk, which isn't looked at for partitions of its
+/// own. Returns its number.
+pub fn register_partition(dev: Box<dyn BlockDevice>) -> usize {
+    add(dev)
+}
+
+fn add(dev: Box<dyn BlockDevice>) -> usize {
+    // Loop devices start out empty, there's nothing to say about them.
+    if dev.num_sectors() > 0 {
+        println!(
+            "{}: {} MiB{}",
+            dev.name(),
+            (dev.num_sectors() * SECTOR_SIZE as u64) >> 20,
+            if dev.read_only() { ", read-only" } else { "" }
+        );
+    }
+    devices().push(dev);
+    queues().push(IoQueue {
+        pending: VecDeque::new(),
+        waiters: WaitQueue::new("disk"),
+    });
+    devices().len() - 1
+}
+
+/// Get block device number `n`.
+pub fn get(n: usize) -> Option<&'static mut dyn BlockDevice> {
+    Some(devices().get_mut(n)?.as_mut())
+}
+
+/// The number of the block device whose device number is `rdev`.
+pub fn find_rdev(rdev: u64) -> Option<usize> {
+    devices().iter().position(|d| d.rdev() == rdev)
+}
+
+/// The number of the block device called `name`.
+pub fn find(name: &[u8]) -> Option<usize> {
+    devices().iter().position(|d| d.name().as_bytes() == name)
+}
+
+/// The errno of an error, for requests that finish without anyone there
+/// to answer Block to.
+fn errno(e: SysError) -> isize {
+    match e {
+        Errno(e) => e,
+        Block => EIO,
+    }
+}
+
+// ///////////////////////////////////
+// / ASYNCHRONOUS I/O
+// ///////////////////////////////////
+
+/// How big a request merging bios can make.
+const MAX_MERGE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+/// A read or write that goes on without whoever started it. Once it's
+/// finished, `done(dev, bio, result)` is called with it: from the disk's
+/// interrupt, from poll(), or right away for drivers that can only wait
+/// for their device. `private` is for whoever started it.
+pub struct Bio {
+    pub op: Op,
+    pub sector: u64,
+    /// The bytes to write, or room for the ones read, in whole sectors.
+    pub data: Vec<u8>,
+    pub done: fn(usize, Bio, Result<(), SysError>),
+    pub private: usize,
+}
+
+/// What a driver is handed: one or more bios merged into one request.
+/// They're for sectors right after each other, in order, and all read or
+/// all write.
+pub struct IoRequest {
+    pub op: Op,
+    pub sector: u64,
+    pub bios: Vec<Bio>,
+}
+
+impl IoRequest {
+    /// How many bytes it reads or writes.
+    pub fn bytes(&self) -> usize {
+        self.bios.iter().map(|b| b.data.len()).sum()
+    }
+}
+
+/// The data of all the bios of a request, one after the other.
+pub fn gather(rq: &IoRequest) -> Vec<u8> {
+    let mut data = Vec::with_capacity(rq.bytes());
+    for bio in &rq.bios {
+        data.extend_from_slice(&bio.data);
+    }
+    data
+}
+
+/// Hand out what a request read to its bios.
+pub fn scatter(rq: &mut IoRequest, data: &[u8]) {
+    let mut at = 0;
+    for bio in &mut rq.bios {
+        let n = bio.data.len();
+        bio.data.copy_from_slice(&data[at..at + n]);
+        at += n;
+    }
+}
+
+/// What became of a request handed to BlockDevice::queue().
+pub enum Queued {
+    /// The device has it, it comes back from finished().
+    Started,
+    /// The device has no room for it now.
+    Full(IoRequest),
+    /// It's done already, with this errno if it went wrong.
+    Done(IoRequest, Result<(), isize>),
+}
+
+/// The I/O scheduler: where a new bio goes among the ones that wait for
+/// the disk. They go to the disk from the front, and bios next to each
+/// other there are merged.
+pub type Scheduler = fn(&mut VecDeque<Bio>, Bio);
+
+/// In the order they come.
+fn noop(pending: &mut VecDeque<Bio>, bio: Bio) {
+    pending.push_back(bio);
+}
+
+/// Sorted by sector, so that what's close on the disk goes together. Bios
+/// for the same sector stay in the order they came in. The block cache
+/// makes sure there's never a read and a write of the same block at once.
+fn sorted(pending: &mut VecDeque<Bio>, bio: Bio) {
+    let i = pending.partition_point(|b| b.sector <= bio.sector);
+    pending.insert(i, bio);
+}
+
+/// The schedulers elevator= can pick.
+const SCHEDULERS: [(&str, Scheduler); 2] = [("noop", noop), ("sorted", sorted)];
+
+static mut SCHEDULER: Scheduler = sorted;
+
+/// The bios of a disk that wait for it to have room, and the processes
+/// that wait for it to finish something.
+struct IoQueue {
+    pending: VecDeque<Bio>,
+    waiters: WaitQueue,
+}
+
+// By block device number.
+static mut QUEUES: Vec<IoQueue> = Vec::new();
+
+fn queues() -> &'static mut Vec<IoQueue> {
+    unsafe { &mut *addr_of_mut!(QUEUES) }
+}
+
+/// Take the I/O scheduler from the kernel command line.
+pub fn init() {
+    let name = fdt::get()
+        .and_then(|f| f.find("/chosen"))
+        .and_then(|c| c.str_property("bootargs"))
+        .and_then(|args| {
+            args.split_ascii_whitespace()
+                .find_map(|arg| arg.strip_prefix("elevator="))
+        });
+    let Some(name) = name else {
+        return;
+    };
+    match SCHEDULERS.iter().find(|(n, _)| *n == name) {
+        Some(&(_, s)) => unsafe { SCHEDULER = s },
+        None => println!("block: no I/O scheduler called {}", name),
+    }
+}
+
+/// Start `bio` on block device `dev`.
+pub fn submit(dev: usize, bio: Bio) {
+    let Some(d) = get(dev) else {
+        (bio.done)(dev, bio, Err(Errno(EIO)));
+        return;
+    };
+    let len = bio.data.len();
+    let end = bio.sector.checked_add((len / SECTOR_SIZE) as u64);
+    if !len.is_multiple_of(SECTOR_SIZE) || end.is_none_or(|end| end > d.num_sectors()) {
+        (bio.done)(dev, bio, Err(Errno(EINVAL)));
+        return;
+    }
+    if bio.op == Op::Write && d.read_only() {
+        (bio.done)(dev, bio, Err(Errno(EROFS)));
+        return;
+    }
+    let scheduler = unsafe { SCHEDULER };
+    scheduler(&mut queues()[dev].pending, bio);
+    dispatch(dev);
+}
+
+/// Hand the bios waiting for `dev` to it, for as long as it has room.
+fn dispatch(dev: usize) {
+    while let Some(first) = queues()[dev].pending.pop_front() {
+        let pending = &mut queues()[dev].pending;
+        let mut rq = IoRequest {
+            op: first.op,
+            sector: first.sector,
+            bios: vec![first],
+        };
+        let mut len = rq.bios[0].data.len();
+        while let Some(next) = pending.front() {
+            let end = rq.sector + (len / SECTOR_SIZE) as u64;
+            if next.op != rq.op || next.sector != end || len + next.data.len() > MAX_MERGE {
+                break;
+            }
+            len += next.data.len();
+            rq.bios.push(pending.pop_front().unwrap());
+        }
+        match get(dev).unwrap().queue(rq) {
+            Queued::Started => {}
+            Queued::Done(rq, ret) => finish(dev, rq, ret),
+            Queued::Full(rq) => {
+                let pending = &mut queues()[dev].pending;
+                for bio in rq.bios.into_iter().rev() {
+                    pending.push_front(bio);
+                }
+                break;
+            }
+        }
+    }
+}
+
+fn finish(dev: usize, rq: IoRequest, ret: Result<(), isize>) {
+    for bio in rq.bios {
+        (bio.done)(dev, bio, ret.map_err(Errno));
+    }
+}
+
+/// Finish what `dev` has done, give it more to do, and wake up everyone
+/// waiting for it.
+fn complete(dev: usize) {
+    for (rq, ret) in get(dev).unwrap().finished() {
+        finish(dev, rq, ret);
+    }
+    dispatch(dev);
+    queues()[dev].waiters.wake_all();
+}
+
+/// Finish what `dev` has done, without waiting for its interrupt.
+pub fn poll(dev: usize) {
+    get(dev).unwrap().poll();
+    complete(dev);
+}
+
+/// Put the process `pid` to sleep until `dev` has finished something, or
+/// until its driver says so.
+pub fn wait(dev: usize, pid: usize) {
+    if !get(dev).unwrap().wait(pid) {
+        queues()[dev].waiters.wait(pid);
+    }
+}
+
+// ///////////////////////////////////
+// / VIRTIO-BLK
+// ///////////////////////////////////
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+// Request status, written by the device
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+// Features
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+/// The device number major of virtio-blk disks on Linux.
+const VIRTBLK_MAJOR: u64 = 254;
+
+/// How many finished requests we keep for whoever asked for them to pick
+/// up. A process that was killed while it slept never comes back for its
+/// request, so the oldest ones are thrown away.
+const MAX_FINISHED: usize = 16;
+
+/// The header that starts every request.
+#[repr(C)]
+struct Header {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// Where the parts of a request are in its buffer: the header, the status
+// byte the device writes, and the data.
+const STATUS_OFFSET: usize = size_of::<Header>();
+const DATA_OFFSET: usize = 64;
+/// How many descriptors a request takes: header, data and status.
+const DESCRIPTORS: usize = 3;
+
+/// A request we've handed to the device. It was either asked for by
+/// read_sectors() or write_sectors(), which come back for it, or it has
+/// the bios queue() was given.
+struct Request {
+    kind: u32,
+    sector: u64,
+    len: usize,
+    // The header, the status and the data, where the device can get at
+    // them whoever's memory they came from.
+    mem: DmaBuffer,
+    // The head of its descriptor chain.
+    head: u16,
+    // The status, once the device is done with it.
+    status: Option<u8>,
+    io: Option<IoRequest>,
+}
+
+impl Request {
+    fn data(&mut self) -> &mut [u8] {
+        &mut self.mem.as_mut_slice()[DATA_OFFSET..DATA_OFFSET + self.len]
+    }
+
+    /// Is this the request to read `len` bytes from `sector`, or to write
+    /// `data` there?
+    fn is(&mut self, kind: u32, sector: u64, len: usize, data: &[u8]) -> bool {
+        self.io.is_none()
+            && self.kind == kind
+            && self.sector == sector
+            && self.len == len
+            && (kind != VIRTIO_BLK_T_OUT || self.data() == data)
+    }
+
+    fn overlaps(&self, sector: u64, len: usize) -> bool {
+        let end = sector + (len / SECTOR_SIZE) as u64;
+        self.sector < end && sector < self.sector + (self.len / SECTOR_SIZE) as u64
+    }
+}
+
+struct VirtioBlk {
+    dev: Device,
+    queue: Queue,
+    name: String,
+    // Which virtio-blk disk it is, counting from 0.
+    index: usize,
+    sectors: u64,
+    read_only: bool,
+    // The requests the device is working on, and the finished ones nobody
+    // has picked up yet, oldest first.
+    requests: Vec<Request>,
+}
+
+// The slots of the virtio-blk disks and their block device numbers.
+static mut SLOTS: Vec<(usize, usize)> = Vec::new();
+
+fn slots() -> &'static mut Vec<(usize, usize)> {
+    unsafe { &mut *addr_of_mut!(SLOTS) }
+}
+
+/// Set up a virtio-blk device. Returns false if it didn't work out.
+pub fn setup(mut dev: Device) -> bool {
+    let Some(features) = dev.begin_init(|_| VIRTIO_BLK_F_RO) else {
+        return false;
+    };
+    let Some(queue) = dev.setup_queue(0, virtio::MAX_QUEUE_SIZE) else {
+        dev.fail();
+        return false;
+    };
+    dev.driver_ok();
+    // The capacity, in sectors, is the first field of the configuration.
+    let sectors: u64 = dev.config(0);
+    let index = slots().len();
+    let slot = dev.slot;
+    let n = register(Box::new(VirtioBlk {
+        dev,
+        queue,
+        name: format!("vd{}", (b'a' + index as u8) as char),
+        index,
+        sectors,
+        read_only: features & VIRTIO_BLK_F_RO != 0,
+        requests: Vec::new(),
+    }));
+    slots().push((slot, n));
+    true
+}
+
+/// The device in `slot` interrupted us.
+pub fn handle_interrupt(slot: usize) {
+    if let Some(&(_, n)) = slots().iter().find(|&&(s, _)| s == slot) {
+        get(n).unwrap().handle_interrupt();
+        complete(n);
+    }
+}
+
+impl VirtioBlk {
+    /// Carry out a request to read `len` bytes from `sector`, or to write
+    /// `data` there, and return it once it's finished. If it isn't right
+    /// away and we may sleep, this answers Block instead, and the request
+    /// goes on without us. Asking for it again picks it up.
+    fn request(
+        &mut self,
+        kind: u32,
+        sector: u64,
+        len: usize,
+        data: &[u8],
+    ) -> Result<Request, SysError> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err(Errno(EINVAL));
+        }
+        let end = sector.checked_add((len / SECTOR_SIZE) as u64);
+        if end.is_none_or(|end| end > self.sectors) {
+            return Err(Errno(EINVAL));
+        }
+        self.reap();
+        let i = match self
+            .requests
+            .iter_mut()
+            .position(|r| r.is(kind, sector, len, data))
+        {
+            Some(i) => i,
+            None => self.submit(kind, sector, len, data)?,
+        };
+        while self.requests[i].status.is_none() {
+            if may_sleep() {
+                return Err(Block);
+            }
+            self.reap();
+        }
+        Ok(self.requests.remove(i))
+    }
+
+    /// Hand a new request to the device. Returns where it is in the list.
+    fn submit(
+        &mut self,
+        kind: u32,
+        sector: u64,
+        len: usize,
+        data: &[u8],
+    ) -> Result<usize, SysError> {
+        // Forget the oldest answers nobody came for, and reads that a write
+        // is about to make stale.
+        let unclaimed = |r: &Request| r.io.is_none() && r.status.is_some();
+        while self.requests.iter().filter(|r| unclaimed(r)).count() >= MAX_FINISHED {
+            let i = self.requests.iter().position(unclaimed).unwrap();
+            self.requests.remove(i);
+        }
+        if kind == VIRTIO_BLK_T_OUT {
+            self.requests
+                .retain(|r| r.kind != VIRTIO_BLK_T_IN || !unclaimed(r) || !r.overlaps(sector, len));
+        }
+        let mut mem = buffer(kind, sector, len).ok_or(Errno(ENOMEM))?;
+        if kind == VIRTIO_BLK_T_OUT {
+            mem.as_mut_slice()[DATA_OFFSET..DATA_OFFSET + len].copy_from_slice(data);
+        }
+        // With the queue full, wait for the device to finish something.
+        while self.queue.num_free() < DESCRIPTORS {
+            if may_sleep() {
+                return Err(Block);
+            }
+            self.reap();
+        }
+        Ok(self.push(kind, sector, len, mem, None))
+    }
+
+    /// Put a request on the queue, which must have room for it. Returns
+    /// where it is in the list.
+    fn push(
+        &mut self,
+        kind: u32,
+        sector: u64,
+        len: usize,
+        mem: DmaBuffer,
+        io: Option<IoRequest>,
+    ) -> usize {
+        let buffers = [
+            Buffer {
+                addr: mem.bus_addr(),
+                len: size_of::<Header>(),
+                writable: false,
+            },
+            Buffer {
+                addr: mem.bus_addr() + DATA_OFFSET,
+                len,
+                writable: kind == VIRTIO_BLK_T_IN,
+            },
+            Buffer {
+                addr: mem.bus_addr() + STATUS_OFFSET,
+                len: 1,
+                writable: true,
+            },
+        ];
+        let head = self.queue.add_chain(&buffers).unwrap();
+        self.queue.submit(head);
+        self.requests.push(Request {
+            kind,
+            sector,
+            len,
+            mem,
+            head,
+            status: None,
+            io,
+        });
+        self.requests.len() - 1
+    }
+
+    /// Take the requests the device has finished off the used ring.
+    fn reap(&mut self) {
+        while let Some((id, _)) = self.queue.pop_used() {
+            self.queue.free_chain(id);
+            // The requests read_sectors() and write_sectors() wait for stay
+            // where they are: they know them by where they are in the list.
+            // The finished IoRequests are taken out by finished().
+            let Some(i) = self
+                .requests
+                .iter()
+                .position(|r| r.status.is_none() && r.head == id)
+            else {
+                continue;
+            };
+            let r = &mut self.requests[i];
+            r.status = Some(unsafe { r.mem.as_ptr().add(STATUS_OFFSET).read_volatile() });
+        }
+    }
+}
+
+/// A buffer for a request: the header filled in, and the status set to
+/// something the device never writes.
+fn buffer(kind: u32, sector: u64, len: usize) -> Option<DmaBuffer> {
+    let mut mem = dma::alloc_coherent(DATA_OFFSET + len)?;
+    unsafe {
+        (mem.as_ptr() as *mut Header).write(Header {
+            kind,
+            reserved: 0,
+            sector,
+        });
+    }
+    mem.as_mut_slice()[STATUS_OFFSET] = 0xff;
+    Some(mem)
+}
+
+/// What the device said about a request.
+fn result(status: u8) -> Result<(), SysError> {
+    match status {
+        VIRTIO_BLK_S_OK => Ok(()),
+        VIRTIO_BLK_S_UNSUPP => Err(Errno(EOPNOTSUPP)),
+        _ => Err(Errno(EIO)),
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rdev(&self) -> u64 {
+        VIRTBLK_MAJOR << 8 | (self.index as u64 * 16)
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), SysError> {
+        let mut req = self.request(VIRTIO_BLK_T_IN, sector, buf.len(), &[])?;
+        result(req.status.unwrap())?;
+        buf.copy_from_slice(req.data());
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), SysError> {
+        if self.read_only {
+            return Err(Errno(EROFS));
+        }
+        let req = self.request(VIRTIO_BLK_T_OUT, sector, buf.len(), buf)?;
+        result(req.status.unwrap())
+    }
+
+    /// Pick up what the device has finished. The block layer wakes up
+    /// whoever is waiting for it.
+    fn handle_interrupt(&mut self) {
+        self.dev.ack_interrupt();
+        self.reap();
+    }
+
+    fn queue(&mut self, rq: IoRequest) -> Queued {
+        self.reap();
+        if self.queue.num_free() < DESCRIPTORS {
+            return Queued::Full(rq);
+        }
+        let kind = match rq.op {
+            Op::Read => VIRTIO_BLK_T_IN,
+            Op::Write => VIRTIO_BLK_T_OUT,
+        };
+        let len = rq.bytes();
+        let Some(mut mem) = buffer(kind, rq.sector, len) else {
+            return Queued::Done(rq, Err(ENOMEM));
+        };
+        if rq.op == Op::Write {
+            let mut at = DATA_OFFSET;
+            for bio in &rq.bios {
+                let n = bio.data.len();
+                mem.as_mut_slice()[at..at + n].copy_from_slice(&bio.data);
+                at += n;
+            }
+        }
+        self.push(kind, rq.sector, len, mem, Some(rq));
+        Queued::Started
+    }
+
+    fn finished(&mut self) -> Vec<(IoRequest, Result<(), isize>)> {
+        let mut done = Vec::new();
+        let mut i = 0;
+        while i < self.requests.len() {
+            let r = &self.requests[i];
+            if r.io.is_none() || r.status.is_none() {
+                i += 1;
+                continue;
+            }
+            let mut r = self.requests.remove(i);
+            let mut rq = r.io.take().unwrap();
+            let ret = result(r.status.unwrap()).map_err(errno);
+            if ret.is_ok() && rq.op == Op::Read {
+                scatter(&mut rq, r.data());
+            }
+            done.push((rq, ret));
+        }
+        done
+    }
+
+    fn poll(&mut self) {
+        self.reap();
+    }
+}
+
+// ///////////////////////////////////
+// / DEVICE FILES
+// ///////////////////////////////////
+
+/// How many blocks of a write go to the disk at once.
+const WRITE_BATCH: usize = 32;
+
+/// A block device as a file, which reads and writes bytes at any offset.
+pub struct Disk(usize, Readahead);
+
+/// Open /dev/`name`.
+pub fn open(name: &[u8]) -> Option<Rc<dyn File>> {
+    Some(Rc::new(Disk(find(name)?, Readahead::new())))
+}
+
+impl Disk {
+    fn dev(&self) -> &'static mut dyn BlockDevice {
+        get(self.0).unwrap()
+    }
+
+    fn len(&self) -> usize {
+        self.dev().num_sectors() as usize * SECTOR_SIZE
+    }
+
+    fn read_blocks(&self, offset: usize, buf: &mut [u8]) -> Result<(), SysError> {
+        // Start reading everything we need, and what comes after it if
+        // we're being read from start to end, so that the disk has all of
+        // it to work on at once.
+        let ahead = self.1.window(offset, buf.len());
+        let first = (offset / BLOCK_SIZE) as u64;
+        let last = ((offset + buf.len() + ahead - 1) / BLOCK_SIZE) as u64;
+        bcache::readahead(self.0, first, last - first + 1);
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let b = bcache::bread(self.0, (pos / BLOCK_SIZE) as u64)?;
+            let start = pos % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - start).min(buf.len() - done);
+            buf[done..done + len].copy_from_slice(&b.data()[start..start + len]);
+            bcache::brelse(b);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Change the blocks and write them out, WRITE_BATCH of them at once.
+    /// Blocks that already have the bytes aren't changed, so a restarted
+    /// write only waits for the ones it wrote before.
+    fn write_blocks(&self, offset: usize, buf: &[u8]) -> Result<(), SysError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let mut batch = Vec::new();
+            while done < buf.len() && batch.len() < WRITE_BATCH {
+                let pos = offset + done;
+                let b = bcache::bread(self.0, (pos / BLOCK_SIZE) as u64)?;
+                let start = pos % BLOCK_SIZE;
+                let len = (BLOCK_SIZE - start).min(buf.len() - done);
+                let new = &buf[done..done + len];
+                if b.data()[start..start + len] != *new {
+                    b.data_mut()[start..start + len].copy_from_slice(new);
+                }
+                bcache::bwrite_start(&b)?;
+                batch.push(b);
+                done += len;
+            }
+            for b in batch {
+                bcache::bwrite(&b)?;
+                bcache::brelse(b);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl File for Disk {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let n = buf.len().min(self.len().saturating_sub(offset));
+        if n == 0 {
+            return Ok(0);
+        }
+        // Through the cache, so that we see what a filesystem on the
+        // disk has written. What's been read before a request answers
+        // Block stays in the cache, so the restarted read gets further.
+        with_sleep(true, || self.read_blocks(offset, &mut buf[..n]))?;
+        Ok(n)
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        let n = buf.len().min(self.len().saturating_sub(offset));
+        if n == 0 && !buf.is_empty() {
+            return Err(Errno(ENOSPC));
+        }
+        if n == 0 {
+            return Ok(0);
+        }
+        if self.dev().read_only() {
+            return Err(Errno(EROFS));
+        }
+        // A restarted write leaves the blocks it already changed alone, and
+        // waits for the ones still being written out.
+        with_sleep(true, || self.write_blocks(offset, &buf[..n]))?;
+        Ok(n)
+    }
+
+    fn wait(&self, pid: usize) {
+        wait(self.0, pid);
+    }
+
+    /// Writes have been waited for already, but a filesystem on the disk
+    /// may have changed blocks that haven't.
+    fn sync(&self) -> Result<(), SysError> {
+        with_sleep(true, || bcache::sync(Some(self.0)))
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            st_mode: S_IFBLK | 0o660,
+            st_nlink: 1,
+            st_rdev: self.dev().rdev(),
+            st_blksize: SECTOR_SIZE as i32,
+            ..Default::default()
+        }
+    }
+
+    fn ioctl(&self, frame: &TrapFrame, cmd: usize, arg: usize) -> SysResult {
+        match cmd {
+            BLKSSZGET => write_user(frame, arg, &(SECTOR_SIZE as i32))?,
+            BLKGETSIZE64 => write_user(frame, arg, &(self.len() as u64))?,
+            _ => return self.dev().ioctl(frame, cmd, arg),
+        }
+        Ok(0)
+    }
+}
@@ -0,0 +1,302 @@
+// The boards we run on. Which one a kernel is for is picked when it's built,
+// with a cargo feature: qemu-virt (the default), unmatched, spike or k210,
+// as in
+//     cargo build --no-default-features --features unmatched
+// Most of what we know about a machine comes from its device tree. A board
+// says what we need before we've read it (where the kernel log goes, which
+// hart boots, how fast the timer ticks), what we fall back to without one,
+// and what no device tree says, like how the PLIC numbers its contexts.
+//
+// Every board has its RAM at 0x8000_0000, which is where the linker script
+// puts the kernel. It uses the first 128 MiB of it at most, and less on
+// boards that don't have that much (RAM_SIZE).
+//
+// The kernel is built for RV64 (riscv64gc) unless cargo is told otherwise:
+//     cargo build --target riscv32imac-unknown-none-elf
+// builds it for RV32, with Sv32 paging, which runs on QEMU's 32-bit virt
+// machine (qemu-system-riscv32). The Unmatched's harts are all RV64.
+
+#[cfg(not(any(
+    feature = "qemu-virt",
+    feature = "unmatched",
+    feature = "spike",
+    feature = "k210"
+)))]
+compile_error!("pick a board: --features qemu-virt, unmatched, spike or k210");
+const _: () = assert!(
+    cfg!(feature = "qemu-virt") as u8
+        + cfg!(feature = "unmatched") as u8
+        + cfg!(feature = "spike") as u8
+        + cfg!(feature = "k210") as u8
+        <= 1,
+    "only one of the qemu-virt, unmatched, spike and k210 features can be on"
+);
+
+#[cfg(feature = "k210")]
+pub use k210::*;
+#[cfg(feature = "qemu-virt")]
+pub use qemu_virt::*;
+#[cfg(feature = "spike")]
+pub use spike::*;
+#[cfg(feature = "unmatched")]
+pub use unmatched::*;
+
+// ///////////////////////////////////
+// / QEMU VIRT
+// ///////////////////////////////////
+
+// QEMU's virt machine, started with -bios none: every hart starts at
+// 0x8000_0000 in machine mode, with the address of the device tree QEMU
+// made up in a1.
+#[cfg(feature = "qemu-virt")]
+mod qemu_virt {
+    use crate::{device::Device, uart};
+    use alloc::{vec, vec::Vec};
+
+    pub const NAME: &str = "QEMU virt";
+    /// The hart that runs the kernel. The others wait.
+    pub const BOOT_HART: usize = 0;
+    /// How fast mtime counts.
+    pub const TIMEBASE_HZ: u64 = 10_000_000;
+
+    /// How much of the RAM at 0x8000_0000 we use.
+    pub const RAM_SIZE: usize = 128 << 20;
+    /// Whether whoever starts us hands us a device tree in a1.
+    pub const BOOT_DTB: bool = true;
+    /// Whether the harts follow version 1.9.1 of the privileged spec,
+    /// from before satp and PMP.
+    pub const OLD_PRIV_SPEC: bool = false;
+
+    pub const CLINT_BASE: usize = 0x0200_0000;
+    pub const PLIC_BASE: usize = 0x0c00_0000;
+    /// The PLIC context of the boot hart's machine mode. QEMU gives every
+    /// hart a machine and a supervisor context, in that order.
+    pub const PLIC_CONTEXT: usize = 2 * BOOT_HART;
+    /// The Goldfish RTC and the test finisher, if the board has them.
+    pub const RTC_BASE: Option<usize> = Some(0x0010_1000);
+    pub const TEST_BASE: Option<usize> = Some(0x0010_0000);
+
+    /// The UART the kernel log goes to until we've looked at the device
+    /// tree, its interrupt, and the clock it's fed.
+    pub const UART0_BASE: usize = 0x1000_0000;
+    pub const UART0_IRQ: u32 = 10;
+    pub const UART0_CLOCK_HZ: u32 = 3_686_400;
+    pub const UART0_KIND: uart::Kind = uart::Kind::Ns16550;
+
+    /// The devices QEMU gives the machine, for when there's no device
+    /// tree.
+    pub fn devices() -> Vec<Device> {
+        let mut devs = vec![
+            Device::new("sifive,test0", &[(TEST_BASE.unwrap(), 0x1000)], &[]),
+            Device::new("google,goldfish-rtc", &[(RTC_BASE.unwrap(), 0x1000)], &[11]),
+            Device::new("riscv,clint0", &[(CLINT_BASE, 0x1_0000)], &[]),
+            Device::new("riscv,plic0", &[(PLIC_BASE, 0x60_0000)], &[]),
+            Device::new("ns16550a", &[(UART0_BASE, 0x100)], &[UART0_IRQ]),
+            Device::new("pci-host-ecam-generic", &[(0x3000_0000, 0x1000_0000)], &[]),
+        ];
+        // The eight virtio-mmio slots, a page apart, with interrupts 1 to 8.
+        for i in 0..8 {
+            let base = 0x1000_1000 + i * 0x1000;
+            devs.push(Device::new(
+                "virtio,mmio",
+                &[(base, 0x1000)],
+                &[i as u32 + 1],
+            ));
+        }
+        devs
+    }
+}
+
+// ///////////////////////////////////
+// / HIFIVE UNMATCHED
+// ///////////////////////////////////
+
+// SiFive's HiFive Unmatched, with the FU740. Its boot ROM loads U-Boot SPL
+// off the SD card, which then loads a FIT image with OpenSBI and U-Boot
+// proper in it. We run in machine mode, so the kernel takes OpenSBI's place
+// in that image: SPL loads it at 0x8000_0000 and jumps to it on every hart
+// in machine mode, with the hart's ID in a0 and the device tree in a1.
+// Hart 0 is the S7, a small core without an MMU or an FPU, so the kernel
+// runs on hart 1, the first of the four U74s. The UART is SiFive's, and
+// it's left set up by SPL.
+#[cfg(feature = "unmatched")]
+mod unmatched {
+    use crate::{device::Device, uart};
+    use alloc::{vec, vec::Vec};
+
+    pub const NAME: &str = "HiFive Unmatched";
+    /// The hart that runs the kernel. The others wait.
+    pub const BOOT_HART: usize = 1;
+    /// How fast mtime counts: the FU740's RTCCLK.
+    pub const TIMEBASE_HZ: u64 = 1_000_000;
+
+    /// How much of the RAM at 0x8000_0000 we use.
+    pub const RAM_SIZE: usize = 128 << 20;
+    /// Whether whoever starts us hands us a device tree in a1.
+    pub const BOOT_DTB: bool = true;
+    /// Whether the harts follow version 1.9.1 of the privileged spec,
+    /// from before satp and PMP.
+    pub const OLD_PRIV_SPEC: bool = false;
+
+    pub const CLINT_BASE: usize = 0x0200_0000;
+    pub const PLIC_BASE: usize = 0x0c00_0000;
+    /// The PLIC context of the boot hart's machine mode. The S7 only has a
+    /// machine context, every U74 has a machine and a supervisor one.
+    pub const PLIC_CONTEXT: usize = 2 * BOOT_HART - 1;
+    /// The Goldfish RTC and the test finisher, if the board has them.
+    pub const RTC_BASE: Option<usize> = None;
+    pub const TEST_BASE: Option<usize> = None;
+
+    /// The UART the kernel log goes to until we've looked at the device
+    /// tree, and its interrupt. Its clock is the chip's peripheral clock,
+    /// which we don't know; 0 keeps the baud rate SPL set up.
+    pub const UART0_BASE: usize = 0x1001_0000;
+    pub const UART0_IRQ: u32 = 39;
+    pub const UART0_CLOCK_HZ: u32 = 0;
+    pub const UART0_KIND: uart::Kind = uart::Kind::Sifive;
+
+    /// The devices of the FU740 we drive, for when there's no device tree.
+    /// What's on the SPI buses is only in the device tree, so they're left
+    /// out.
+    pub fn devices() -> Vec<Device> {
+        let gpio_irqs: Vec<u32> = (23..39).collect();
+        vec![
+            Device::new("sifive,clint0", &[(CLINT_BASE, 0x1_0000)], &[]),
+            Device::new("sifive,plic-1.0.0", &[(PLIC_BASE, 0x400_0000)], &[]),
+            Device::new("sifive,uart0", &[(UART0_BASE, 0x1000)], &[UART0_IRQ]),
+            Device::new("sifive,uart0", &[(0x1001_1000, 0x1000)], &[40]),
+            Device::new("sifive,gpio0", &[(0x1006_0000, 0x1000)], &gpio_irqs),
+        ]
+    }
+}
+
+// ///////////////////////////////////
+// / SPIKE
+// ///////////////////////////////////
+
+// Spike, the RISC-V ISA simulator, run as
+//     spike -m128 target/riscv64gc-unknown-none-elf/debug/rust-riscv-os
+// Its boot ROM jumps to 0x8000_0000 on every hart in machine mode, with the
+// hart's ID in a0 and the device tree in a1. Newer versions have a CLINT, a
+// PLIC and a 16550 where QEMU's virt machine has them, but no test device
+// and no RTC. Its console is HTIF, and so is the way to stop it, so this
+// board turns on the htif feature: the kernel log goes there, not to the
+// 16550.
+#[cfg(feature = "spike")]
+mod spike {
+    use crate::{device::Device, uart};
+    use alloc::{vec, vec::Vec};
+
+    pub const NAME: &str = "Spike";
+    /// The hart that runs the kernel. The others wait.
+    pub const BOOT_HART: usize = 0;
+    /// How fast mtime counts: Spike's CPU runs at a simulated 1 GHz, and
+    /// the timer ticks every 100 instructions.
+    pub const TIMEBASE_HZ: u64 = 10_000_000;
+
+    /// How much of the RAM at 0x8000_0000 we use.
+    pub const RAM_SIZE: usize = 128 << 20;
+    /// Whether whoever starts us hands us a device tree in a1.
+    pub const BOOT_DTB: bool = true;
+    /// Whether the harts follow version 1.9.1 of the privileged spec,
+    /// from before satp and PMP.
+    pub const OLD_PRIV_SPEC: bool = false;
+
+    pub const CLINT_BASE: usize = 0x0200_0000;
+    pub const PLIC_BASE: usize = 0x0c00_0000;
+    /// The PLIC context of the boot hart's machine mode. Spike gives every
+    /// hart a machine and a supervisor context, in that order.
+    pub const PLIC_CONTEXT: usize = 2 * BOOT_HART;
+    /// The Goldfish RTC and the test finisher, if the board has them.
+    pub const RTC_BASE: Option<usize> = None;
+    pub const TEST_BASE: Option<usize> = None;
+
+    /// The 16550, which is the console UART until we've looked at the
+    /// device tree, its interrupt, and the clock it's fed; 0 keeps the
+    /// baud rate it has.
+    pub const UART0_BASE: usize = 0x1000_0000;
+    pub const UART0_IRQ: u32 = 1;
+    pub const UART0_CLOCK_HZ: u32 = 0;
+    pub const UART0_KIND: uart::Kind = uart::Kind::Ns16550;
+
+    /// The devices Spike gives the machine, for when there's no device
+    /// tree.
+    pub fn devices() -> Vec<Device> {
+        vec![
+            Device::new("riscv,clint0", &[(CLINT_BASE, 0x1_0000)], &[]),
+            Device::new("riscv,plic0", &[(PLIC_BASE, 0x100_0000)], &[]),
+            Device::new("ns16550a", &[(UART0_BASE, 0x100)], &[UART0_IRQ]),
+        ]
+    }
+}
+
+// ///////////////////////////////////
+// / KENDRYTE K210
+// ///////////////////////////////////
+
+// Kendryte's (now Canaan's) K210, on boards like the Sipeed Maix ones: two
+// RV64GC harts and 8 MiB of SRAM, of which the last 2 MiB are the AI
+// accelerator's, which is ours as long as we don't use it. kflash writes
+// the kernel to the flash, and the boot ROM copies it to 0x8000_0000 and
+// starts both harts there in machine mode. There's no device tree, and a1
+// is whatever the boot ROM left in it.
+//
+// Only hart 0 runs the kernel. Hart 1 waits in boot.S's wfi loop, like the
+// extra harts of every board, and nothing ever wakes it: the scheduler, the
+// allocators and the locks all assume one hart, and there's no trap frame,
+// stack or IPI handoff to start a second one with. Bringing it up is still
+// to do, here and on the other boards.
+//
+// The harts are older than the rest: they follow version 1.9.1 of the
+// privileged spec. Paging is turned on with mstatus.VM; where satp would
+// be, there's sptbr, with nothing but the root table in it; the fence after
+// changing the page tables is sfence.vm; and there's no PMP.
+//
+// The CLINT and the PLIC are the usual ones; the PLIC has a machine and a
+// supervisor context for each hart, like QEMU's. The timer ticks at a
+// fiftieth of the CPU clock, which the boot ROM sets to 390 MHz. The UART
+// the boot ROM talks on, UARTHS, is SiFive's, and it's left at 115200
+// baud.
+#[cfg(feature = "k210")]
+mod k210 {
+    use crate::{device::Device, uart};
+    use alloc::{vec, vec::Vec};
+
+    pub const NAME: &str = "Kendryte K210";
+    /// The hart that runs the kernel. The other one waits for good.
+    pub const BOOT_HART: usize = 0;
+    /// How fast mtime counts.
+    pub const TIMEBASE_HZ: u64 = 7_800_000;
+
+    /// How much of the RAM at 0x8000_0000 we use: all of it.
+    pub const RAM_SIZE: usize = 8 << 20;
+    /// Whether whoever starts us hands us a device tree in a1.
+    pub const BOOT_DTB: bool = false;
+    /// Whether the harts follow version 1.9.1 of the privileged spec,
+    /// from before satp and PMP.
+    pub const OLD_PRIV_SPEC: bool = true;
+
+    pub const CLINT_BASE: usize = 0x0200_0000;
+    pub const PLIC_BASE: usize = 0x0c00_0000;
+    /// The PLIC context of the boot hart's machine mode.
+    pub const PLIC_CONTEXT: usize = 2 * BOOT_HART;
+    /// The Goldfish RTC and the test finisher, if the board has them.
+    pub const RTC_BASE: Option<usize> = None;
+    pub const TEST_BASE: Option<usize> = None;
+
+    /// UARTHS, its interrupt, and the clock it's fed; 0 keeps the baud
+    /// rate the boot ROM set up.
+    pub const UART0_BASE: usize = 0x3800_0000;
+    pub const UART0_IRQ: u32 = 33;
+    pub const UART0_CLOCK_HZ: u32 = 0;
+    pub const UART0_KIND: uart::Kind = uart::Kind::Sifive;
+
+    /// The devices of the K210 we drive. There's never a device tree.
+    pub fn devices() -> Vec<Device> {
+        vec![
+            Device::new("riscv,clint0", &[(CLINT_BASE, 0xc000)], &[]),
+            Device::new("riscv,plic0", &[(PLIC_BASE, 0x400_0000)], &[]),
+            Device::new("sifive,uart0", &[(UART0_BASE, 0x1000)], &[UART0_IRQ]),
+        ]
+    }
+}
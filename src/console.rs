@@ -0,0 +1,757 @@
+// The console sits between the UART and whoever wants to talk to the user.
+// Its output can also go to a virtio console, for machines that don't have
+// a UART, and goes to HTIF instead when that's built in (see htif.rs), or
+// to the host through semihosting if asked to (see semihosting.rs). Its
+// input comes from all of the input sources at once: the console UART, the
+// virtio console, a virtio keyboard (see keymap.rs) and HTIF, unless the
+// kernel command line picks some of them with console_input=, like
+// console_input=uart,kbd. Bytes received by the interrupt handlers are
+// queued in a ring buffer, and readers either pull raw bytes out of it or
+// go through the line discipline, which collects (and echoes) a whole line
+// before handing it over. Which of the two a read() gets, and whether
+// input is echoed, is controlled through the terminal settings (struct
+// termios), like on any Unix.
+//
+// With ISIG on, ^C (VINTR) never makes it into the input: it throws away
+// what's waiting to be read and ends the foreground process with SIGINT.
+// There are no sessions or process groups, so the foreground process is
+// the user process that last waited for input here, or the one TIOCSPGRP
+// named, while it's alive. Without one, ^C is the kernel shell's, which
+// gives up on the line being typed or the command that's running.
+//
+// What's written to the console, and the kernel log, are also drawn on the
+// display if there is one (see fbcon.rs). The kernel log is kept without
+// escape sequences, and sent to the log UART without them too if its
+// terminal doesn't understand them. What's printed can also be captured
+// instead, which is how the shell's pager gets what commands print.
+
+use crate::{
+    ansi::{Parser, Sequence},
+    cpu, fbcon, fdt, htif, hvc, klog, panic,
+    process::{self, ProcessState, WaitQueue},
+    semihosting,
+    syscall::{SysError, EINVAL},
+    uart, xmodem,
+};
+use alloc::{string::String, vec::Vec};
+use core::{
+    fmt::{Error, Write},
+    ptr::addr_of_mut,
+};
+
+use SysError::Errno;
+
+const INPUT_BUFFER_SIZE: usize = 256;
+const LINE_BUFFER_SIZE: usize = 256;
+// With flow control on, the other side is asked to stop sending once the
+// input buffer is this full, and to go on once it has drained to here.
+const HIGH_WATER: usize = INPUT_BUFFER_SIZE * 3 / 4;
+const LOW_WATER: usize = INPUT_BUFFER_SIZE / 4;
+
+/// A fixed-size FIFO of bytes. When it is full, new bytes are dropped.
+pub struct RingBuffer<const N: usize> {
+    buffer: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            buffer: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Add a byte to the back. Returns false if there was no room for it.
+    pub fn push(&mut self, c: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buffer[(self.head + self.len) % N] = c;
+        self.len += 1;
+        true
+    }
+
+    /// Take a byte from the front.
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let c = self.buffer[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(c)
+    }
+}
+
+// Control mode flags (c_cflag). CBAUD is the speed, one of the Bxxx rates
+// in SPEEDS, or BOTHER for the one in c_ospeed.
+const CBAUD: u32 = 0o10017;
+const BOTHER: u32 = 0o10000;
+const CSIZE: u32 = 0o60;
+const CSTOPB: u32 = 0o100;
+const PARENB: u32 = 0o400;
+const PARODD: u32 = 0o1000;
+pub const CRTSCTS: u32 = 0o20000000000;
+// Local mode flags (c_lflag)
+pub const ISIG: u32 = 0o1;
+pub const ICANON: u32 = 0o2;
+pub const ECHO: u32 = 0o10;
+/// Where in c_cc the character is that sends SIGINT.
+const VINTR: usize = 0;
+
+/// The rates of B0 to B38400, and after them those of B57600 to B4000000,
+/// which are BOTHER | 1 to BOTHER | 15.
+const SPEEDS: [u32; 31] = [
+    0, 50, 75, 110, 134, 150, 200, 300, 600, 1200, 1800, 2400, 4800, 9600, 19200, 38400, 57600,
+    115200, 230400, 460800, 500000, 576000, 921600, 1000000, 1152000, 1500000, 2000000, 2500000,
+    3000000, 3500000, 4000000,
+];
+
+/// struct termios, as the TCGETS/TCSETS ioctls see it. We only act on
+/// ISIG, ICANON, ECHO, VINTR and the line settings of c_cflag (the speed, CSIZE,
+/// CSTOPB, PARENB, PARODD and CRTSCTS), everything else is just stored for
+/// whoever asks.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; 19],
+}
+
+/// struct termios2, for TCGETS2/TCSETS2, which can give any speed.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Termios2 {
+    pub termios: Termios,
+    pub c_ispeed: u32,
+    pub c_ospeed: u32,
+}
+
+/// struct winsize
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct WinSize {
+    pub ws_row: u16,
+    pub ws_col: u16,
+    pub ws_xpixel: u16,
+    pub ws_ypixel: u16,
+}
+
+/// struct serial_icounter_struct, for TIOCGICOUNT.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SerialICounter {
+    pub cts: i32,
+    pub dsr: i32,
+    pub rng: i32,
+    pub dcd: i32,
+    pub rx: i32,
+    pub tx: i32,
+    pub frame: i32,
+    pub overrun: i32,
+    pub parity: i32,
+    pub brk: i32,
+    pub buf_overrun: i32,
+    pub reserved: [i32; 9],
+}
+
+/// What the console is on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// The console UART, see uart::console().
+    Uart,
+    /// hvc0, the virtio console.
+    Virtio,
+    /// The host's console, through HTIF.
+    Htif,
+    /// The host's console, through semihosting. Output only.
+    Semihosting,
+}
+
+/// Where console input comes from.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The console UART.
+    Uart,
+    /// hvc0, the virtio console.
+    Virtio,
+    /// A virtio keyboard, through the keymap.
+    Keyboard,
+    /// The host's console, through HTIF.
+    Htif,
+}
+
+impl Source {
+    const ALL: [Source; 4] = [Source::Uart, Source::Virtio, Source::Keyboard, Source::Htif];
+
+    /// Its name for console_input=.
+    fn name(self) -> &'static str {
+        match self {
+            Source::Uart => "uart",
+            Source::Virtio => "hvc",
+            Source::Keyboard => "kbd",
+            Source::Htif => "htif",
+        }
+    }
+}
+
+/// The line that is currently being typed. Once a newline (or ^D) comes in,
+/// the line is `ready` and reads are served from it until it's drained.
+struct LineBuffer {
+    buffer: [u8; LINE_BUFFER_SIZE],
+    len: usize,
+    ready: bool,
+}
+
+static mut INPUT: RingBuffer<INPUT_BUFFER_SIZE> = RingBuffer::new();
+static mut LINE: LineBuffer = LineBuffer {
+    buffer: [0; LINE_BUFFER_SIZE],
+    len: 0,
+    ready: false,
+};
+// What Linux gives a freshly opened serial console: ICRNL, OPOST | ONLCR,
+// B115200 | CS8 | CREAD and ISIG | ICANON | ECHO | ECHOE | ECHOK | IEXTEN,
+// with ^C, ^\, DEL, ^U, ^D, VTIME 0 and VMIN 1 as the control characters.
+static mut TERMIOS: Termios = Termios {
+    c_iflag: 0o400,
+    c_oflag: 0o5,
+    c_cflag: 0o10262,
+    c_lflag: 0o100073,
+    c_line: 0,
+    c_cc: [3, 28, 127, 21, 4, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+};
+// The speed of the line, in bits per second.
+static mut SPEED: u32 = uart::DEFAULT_BAUD;
+// There's no way to know how big the terminal on the other side of the
+// UART is, so assume the classic size until someone tells us otherwise.
+static mut WINSIZE: WinSize = WinSize {
+    ws_row: 24,
+    ws_col: 80,
+    ws_xpixel: 0,
+    ws_ypixel: 0,
+};
+static mut BACKEND: Backend = if cfg!(feature = "htif") {
+    Backend::Htif
+} else {
+    Backend::Uart
+};
+// Which input sources we listen to, by Source.
+static mut ENABLED: [bool; Source::ALL.len()] = [true; Source::ALL.len()];
+// Processes blocked until more input arrives.
+static mut WAITERS: WaitQueue = WaitQueue::new("console readers");
+// Bytes we received but had no room for.
+static mut DROPPED: usize = 0;
+// Who ^C goes to, 0 for the kernel shell, and whether it has a ^C it
+// hasn't seen yet.
+static mut FOREGROUND: usize = 0;
+static mut INTERRUPTED: bool = false;
+
+/// Pick what the console is on, once the drivers are set up. That's the
+/// virtio console if the kernel command line says console=hvc0, or if
+/// there is one and the device tree doesn't list any UART. Otherwise, it's
+/// HTIF if that's built in, and the console UART if it isn't, unless the
+/// command line says console=semihosting and there's a host that answers.
+/// Then pick the input sources, if the command line says which.
+pub fn init() {
+    let fdt = fdt::get();
+    let bootargs = fdt
+        .and_then(|f| f.find("/chosen"))
+        .and_then(|c| c.str_property("bootargs"))
+        .unwrap_or("");
+    let asked = bootargs
+        .split_ascii_whitespace()
+        .any(|arg| arg == "console=hvc0");
+    let no_uart = fdt.is_some_and(|f| {
+        f.compatible("ns16550a").next().is_none() && f.compatible("sifive,uart0").next().is_none()
+    });
+    if hvc::present() && (asked || no_uart && backend() == Backend::Uart) {
+        unsafe {
+            BACKEND = Backend::Virtio;
+        }
+        println!("console: on hvc0");
+    }
+    let semihosted = bootargs
+        .split_ascii_whitespace()
+        .any(|arg| arg == "console=semihosting");
+    if semihosted && semihosting::present() {
+        unsafe {
+            BACKEND = Backend::Semihosting;
+        }
+        println!("console: on semihosting");
+    }
+    if let Some(names) = bootargs
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix("console_input="))
+    {
+        let names: Vec<&str> = names.split(',').collect();
+        for name in &names {
+            if !Source::ALL.iter().any(|s| s.name() == *name) {
+                println!("console: no input source called {}", name);
+            }
+        }
+        for s in Source::ALL {
+            set_enabled(s, names.contains(&s.name()));
+        }
+    }
+    // HTIF has to be asked for input, nothing tells us it's there.
+    if cfg!(feature = "htif") && is_enabled(Source::Htif) {
+        htif::init();
+    }
+}
+
+pub fn backend() -> Backend {
+    unsafe { BACKEND }
+}
+
+/// Are we listening to `source`?
+fn is_enabled(source: Source) -> bool {
+    unsafe { ENABLED[source as usize] }
+}
+
+/// Start or stop listening to `source`. What it sends while we don't is
+/// thrown away.
+fn set_enabled(source: Source, on: bool) {
+    unsafe {
+        ENABLED[source as usize] = on;
+    }
+    // A UART we stop listening to mustn't stay throttled.
+    if !on && source == Source::Uart {
+        uart::console().unthrottle_rx();
+    }
+}
+
+/// Queue a byte received from `source` and wake up everyone waiting for
+/// input. This is called from the interrupt handlers.
+pub fn push(source: Source, c: u8) {
+    if xmodem::push(source, c) || !is_enabled(source) {
+        return;
+    }
+    let t = termios();
+    if t.c_lflag & ISIG != 0 && c == t.c_cc[VINTR] && c != 0 {
+        interrupt();
+        return;
+    }
+    unsafe {
+        let input = &mut *addr_of_mut!(INPUT);
+        if !input.push(c) {
+            DROPPED += 1;
+        }
+        // Only the UART can be throttled. The virtio console just has to
+        // wait for us to give it buffers, and the others can't be stopped.
+        if input.len() >= HIGH_WATER && is_enabled(Source::Uart) {
+            uart::console().throttle_rx();
+        }
+        (*addr_of_mut!(WAITERS)).wake_all();
+    }
+}
+
+/// Take the next raw byte of input, if there is any.
+pub fn get() -> Option<u8> {
+    cpu::without_interrupts(|| {
+        let input = unsafe { &mut *addr_of_mut!(INPUT) };
+        let c = input.pop();
+        if input.len() <= LOW_WATER && is_enabled(Source::Uart) {
+            uart::console().unthrottle_rx();
+        }
+        c
+    })
+}
+
+/// Block the given process until more input arrives. A user process that
+/// waits for it has the console from then on.
+pub fn wait(pid: usize) {
+    if process::get_by_pid(pid).is_some_and(|p| p.is_user()) {
+        set_foreground(pid);
+    }
+    unsafe {
+        (*addr_of_mut!(WAITERS)).wait(pid);
+    }
+}
+
+/// The process ^C goes to, or 0 if that's the kernel shell.
+pub fn foreground() -> usize {
+    let pid = unsafe { FOREGROUND };
+    match process::get_by_pid(pid) {
+        Some(p) if p.state() != ProcessState::Zombie => pid,
+        _ => 0,
+    }
+}
+
+pub fn set_foreground(pid: usize) {
+    unsafe {
+        FOREGROUND = pid;
+    }
+}
+
+/// ^C was typed: throw away the input, and end the foreground process, or
+/// tell the shell.
+fn interrupt() {
+    flush_input();
+    if echo_on() {
+        write(b"^C\r\n");
+    }
+    let pid = foreground();
+    if pid == 0 || !process::signal(pid, process::SIGINT) {
+        unsafe {
+            INTERRUPTED = true;
+        }
+    }
+}
+
+/// Whether ^C was typed for the kernel shell since clear_interrupt().
+pub fn interrupted() -> bool {
+    unsafe { *addr_of_mut!(INTERRUPTED) }
+}
+
+pub fn clear_interrupt() {
+    unsafe {
+        INTERRUPTED = false;
+    }
+}
+
+pub fn termios() -> Termios {
+    unsafe { TERMIOS }
+}
+
+pub fn termios2() -> Termios2 {
+    let speed = unsafe { SPEED };
+    Termios2 {
+        termios: termios(),
+        c_ispeed: speed,
+        c_ospeed: speed,
+    }
+}
+
+/// What TCSETS, which can only give a speed as one of the Bxxx rates,
+/// means by `t`: BOTHER keeps the speed there is.
+pub fn with_speed(t: Termios) -> Termios2 {
+    Termios2 {
+        termios: t,
+        ..termios2()
+    }
+}
+
+/// Change the terminal settings. On a UART, with new line settings, it's
+/// reprogrammed, and the speed it gets as close as it can to is the speed
+/// from then on. There's only the one speed, for both directions, and B0
+/// (hang up) changes nothing.
+pub fn set_termios(mut t: Termios2) -> Result<(), SysError> {
+    let cflag = t.termios.c_cflag;
+    let speed = match cflag & CBAUD {
+        BOTHER => t.c_ospeed,
+        b if b & BOTHER != 0 => SPEEDS[15 + (b & !BOTHER) as usize],
+        b => SPEEDS[b as usize],
+    };
+    let mut speed = if speed == 0 { unsafe { SPEED } } else { speed };
+    let line = CSIZE | CSTOPB | PARENB | PARODD;
+    let old = termios().c_cflag;
+    if backend() == Backend::Uart && (cflag & line != old & line || speed != unsafe { SPEED }) {
+        let parity = match cflag & (PARENB | PARODD) {
+            PARENB => uart::Parity::Even,
+            p if p & PARENB != 0 => uart::Parity::Odd,
+            _ => uart::Parity::None,
+        };
+        let stop_bits = if cflag & CSTOPB != 0 { 2 } else { 1 };
+        speed = uart::console()
+            .configure(speed, 5 + (cflag & CSIZE) / 0o20, parity, stop_bits)
+            .map_err(|_| Errno(EINVAL))?;
+    }
+    // Say what we got, like Linux does: the Bxxx if it's one of them.
+    let code = match SPEEDS.iter().position(|&s| s == speed) {
+        Some(i) if i < 16 => i as u32,
+        Some(i) => BOTHER | (i - 15) as u32,
+        None => BOTHER,
+    };
+    t.termios.c_cflag = cflag & !CBAUD | code;
+    let flow = if cflag & CRTSCTS != 0 {
+        uart::CRTSCTS_FLOW_CONTROL
+    } else {
+        uart::FlowControl::None
+    };
+    uart::console().set_flow_control(flow);
+    unsafe {
+        TERMIOS = t.termios;
+        SPEED = speed;
+    }
+    Ok(())
+}
+
+pub fn winsize() -> WinSize {
+    unsafe { WINSIZE }
+}
+
+pub fn set_winsize(w: WinSize) {
+    unsafe {
+        WINSIZE = w;
+    }
+}
+
+fn echo_on() -> bool {
+    termios().c_lflag & ECHO != 0
+}
+
+/// Throw away all input that hasn't been read yet.
+pub fn flush_input() {
+    // Including what the UART has received but not handed to us yet.
+    if is_enabled(Source::Uart) {
+        uart::console().reset_fifos(true, false);
+    }
+    while get().is_some() {}
+    unsafe {
+        let line = &mut *addr_of_mut!(LINE);
+        line.len = 0;
+        line.ready = false;
+    }
+}
+
+/// Read input the way the terminal settings say: a line at a time in
+/// canonical mode, whatever has arrived otherwise. Returns None if there
+/// isn't anything to hand out yet.
+pub fn read(buf: &mut [u8]) -> Option<usize> {
+    if termios().c_lflag & ICANON != 0 {
+        return read_line(buf);
+    }
+    // Raw mode, wait for at least one byte (VMIN is always 1 for us).
+    let mut n = 0;
+    while n < buf.len() {
+        let Some(c) = get() else {
+            break;
+        };
+        buf[n] = c;
+        n += 1;
+    }
+    if n == 0 && !buf.is_empty() {
+        return None;
+    }
+    if echo_on() {
+        write(&buf[..n]);
+    }
+    Some(n)
+}
+
+/// Read from the line discipline. This consumes queued input, echoing it
+/// back (unless ECHO is off) and handling backspace, until a full line is available. Returns the
+/// number of bytes copied into `buf` (0 at end of file, i.e. ^D on an empty
+/// line), or None if the line isn't complete yet and the caller has to wait.
+fn read_line(buf: &mut [u8]) -> Option<usize> {
+    let line = unsafe { &mut *addr_of_mut!(LINE) };
+    let echo = echo_on();
+    while !line.ready {
+        let c = get()?;
+        match c {
+            8 | 127 => {
+                // Backspace (or delete, which is what most terminals
+                // send for the backspace key). Erase the character on
+                // screen by backing up, writing a space and backing up
+                // again.
+                if line.len > 0 {
+                    line.len -= 1;
+                    if echo {
+                        write(b"\x08 \x08");
+                    }
+                }
+            }
+            10 | 13 => {
+                // Newline or carriage-return ends the line. Always keep
+                // room for the newline, even if the line is full.
+                if line.len == LINE_BUFFER_SIZE {
+                    line.len -= 1;
+                }
+                line.buffer[line.len] = b'\n';
+                line.len += 1;
+                line.ready = true;
+                if echo {
+                    write(b"\r\n");
+                }
+            }
+            4 => {
+                // ^D hands over whatever we have without a newline. On an
+                // empty line, this makes the read return 0 (end of file).
+                line.ready = true;
+            }
+            _ => {
+                if line.len < LINE_BUFFER_SIZE - 1 {
+                    line.buffer[line.len] = c;
+                    line.len += 1;
+                    if echo {
+                        write(&[c]);
+                    }
+                }
+            }
+        }
+    }
+    // Hand out as much of the line as fits, the rest is kept for the next
+    // read.
+    let n = buf.len().min(line.len);
+    buf[..n].copy_from_slice(&line.buffer[..n]);
+    line.buffer.copy_within(n..line.len, 0);
+    line.len -= n;
+    if line.len == 0 {
+        line.ready = false;
+    }
+    Some(n)
+}
+
+/// The counters of the UART under the console. The virtio console only has
+/// ours.
+pub fn icount() -> SerialICounter {
+    if backend() == Backend::Virtio {
+        return SerialICounter {
+            buf_overrun: unsafe { DROPPED } as i32,
+            ..Default::default()
+        };
+    }
+    let stats = uart::console().stats();
+    SerialICounter {
+        rx: stats.rx as i32,
+        tx: stats.tx as i32,
+        frame: stats.framing as i32,
+        overrun: stats.overrun as i32,
+        parity: stats.parity as i32,
+        brk: stats.brk as i32,
+        buf_overrun: unsafe { DROPPED } as i32,
+        ..Default::default()
+    }
+}
+
+/// Wait until everything that was written has been sent.
+pub fn flush_output() {
+    match backend() {
+        Backend::Uart => uart::console().flush(),
+        Backend::Virtio => hvc::flush(),
+        // HTIF and semihosting output is gone once it's written.
+        Backend::Htif | Backend::Semihosting => {}
+    }
+}
+
+/// Write bytes to the console.
+pub fn write(buf: &[u8]) {
+    fbcon::write(buf);
+    match backend() {
+        Backend::Virtio => return hvc::write(buf),
+        Backend::Htif => return htif::write(buf),
+        Backend::Semihosting => return semihosting::write(buf),
+        Backend::Uart => {}
+    }
+    let uart = uart::console();
+    for &c in buf {
+        uart.put(c);
+    }
+}
+
+// Whether the terminal on the log UART (or HTIF) understands escape
+// sequences, and what takes them out of the log when they aren't wanted.
+static mut LOG_ANSI: bool = true;
+static mut SERIAL_PLAIN: Parser = Parser::output();
+
+/// Whether escape sequences in the kernel log get to the log UART.
+pub fn log_ansi() -> bool {
+    unsafe { LOG_ANSI }
+}
+
+/// Send the kernel log to the log UART with its escape sequences, or
+/// without them.
+pub fn set_log_ansi(on: bool) {
+    unsafe {
+        LOG_ANSI = on;
+    }
+}
+
+/// Feed `b` to `parser`, and return it if it isn't part of an escape
+/// sequence.
+fn plain(parser: *mut Parser, b: u8) -> Option<u8> {
+    match unsafe { (*parser).feed(b) } {
+        Some(Sequence::Byte(b)) => Some(b),
+        _ => None,
+    }
+}
+
+/// What print! writes while someone's capturing it, instead of writing it.
+static mut CAPTURE: Option<String> = None;
+
+/// Run `f`, and return what it printed instead of printing it, along with
+/// what it returned.
+pub fn capture<R>(f: impl FnOnce() -> R) -> (R, String) {
+    let outer = unsafe { (*addr_of_mut!(CAPTURE)).replace(String::new()) };
+    let ret = f();
+    let text = unsafe { core::mem::replace(&mut *addr_of_mut!(CAPTURE), outer) };
+    (ret, text.unwrap_or_default())
+}
+
+/// How many bytes can go to the log UART without waiting for it.
+pub fn log_room() -> usize {
+    if cfg!(feature = "htif") {
+        return usize::MAX;
+    }
+    uart::log().room()
+}
+
+/// Write `s` to the kernel log: to the log UART (or HTIF, if it's built
+/// in) if `serial`, and to the display if `display`.
+pub fn write_log(s: &str, serial: bool, display: bool) {
+    // A panic's report goes out whoever is capturing.
+    if let Some(text) = unsafe { &mut *addr_of_mut!(CAPTURE) }
+        .as_mut()
+        .filter(|_| !panic::panicking())
+    {
+        text.push_str(s);
+        return;
+    }
+    if display {
+        fbcon::write(s.as_bytes());
+    }
+    if !serial {
+        return;
+    }
+    if !log_ansi() {
+        for b in s.bytes() {
+            let Some(b) = plain(addr_of_mut!(SERIAL_PLAIN), b) else {
+                continue;
+            };
+            if cfg!(feature = "htif") {
+                htif::write(&[b]);
+            } else {
+                uart::log().put(b);
+            }
+        }
+        return;
+    }
+    if cfg!(feature = "htif") {
+        htif::write(s.as_bytes());
+        return;
+    }
+    let _ = uart::log().write_str(s);
+}
+
+/// Where print! writes the kernel log: the log UART (or HTIF, if it's built
+/// in), and the display.
+pub struct Log;
+
+impl Write for Log {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        if panic::panicking() {
+            klog::panic_print(s);
+        }
+        write_log(s, true, true);
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+// Core dumps: when a user process is killed by a fault, what it was doing
+// is written to a file called core in its current directory, so that the
+// crash can be looked at afterwards with
+//
+//   gdb target/riscv64gc-unknown-none-elf/debug/rust-riscv-os core
+//
+// It's an ELF file of the kind Linux writes (ET_CORE), which gdb knows how
+// to read: a PT_NOTE segment with an NT_PRSTATUS note in it, the signal,
+// the pid and the registers, and a PT_LOAD segment for each range of the
+// address space, with what's in it. The kernel's text is left out, the user
+// programs are linked into the kernel, so gdb has it from the kernel's ELF
+// file already. Pages that were never touched are written as zeros, and
+// ranges that can't be read are there without any data, as on Linux.
+//
+// The floating point registers aren't written yet.
+
+use crate::{
+    block, cpu,
+    file::{O_CREAT, O_TRUNC, O_WRONLY},
+    page::{self, EntryBits, Table, PAGE_SIZE},
+    process::{Cred, Process},
+    syscall::SysError,
+    vfs,
+};
+use alloc::vec::Vec;
+use core::{mem::size_of, slice};
+
+const EM_RISCV: u16 = 243;
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+
+/// How big a word is, 8 bytes on RV64 and 4 on RV32.
+const WORD: usize = size_of::<usize>();
+
+#[cfg(target_pointer_width = "64")]
+const ELFCLASS: u8 = 2;
+#[cfg(target_pointer_width = "32")]
+const ELFCLASS: u8 = 1;
+const EHDR_SIZE: usize = 16 + 4 + 4 + 3 * WORD + 4 + 6 * 2;
+#[cfg(target_pointer_width = "64")]
+const PHDR_SIZE: usize = 56;
+#[cfg(target_pointer_width = "32")]
+const PHDR_SIZE: usize = 32;
+
+// struct elf_prstatus: the signal info, the signal, the pending and held
+// signals, four pids, four times, and then the registers, pc first in
+// place of x0.
+const PR_CURSIG: usize = 12;
+const PR_PID: usize = 16 + 2 * WORD;
+const PR_REG: usize = 32 + 10 * WORD;
+const PRSTATUS_SIZE: usize = (PR_REG + 32 * WORD + 4).next_multiple_of(WORD);
+
+/// The bytes of a core file, little-endian as RISC-V is.
+struct Out(Vec<u8>);
+
+impl Out {
+    fn u16(&mut self, v: u16) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn word(&mut self, v: usize) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn phdr(
+        &mut self,
+        kind: u32,
+        flags: u32,
+        offset: usize,
+        vaddr: usize,
+        size: usize,
+        memsz: usize,
+    ) {
+        let align = if kind == PT_LOAD { PAGE_SIZE } else { 4 };
+        self.u32(kind);
+        #[cfg(target_pointer_width = "64")]
+        self.u32(flags);
+        self.word(offset);
+        self.word(vaddr);
+        self.word(0);
+        self.word(size);
+        self.word(memsz);
+        #[cfg(target_pointer_width = "32")]
+        self.u32(flags);
+        self.word(align);
+    }
+}
+
+/// A range of the address space that goes into the core file.
+struct Segment {
+    start: usize,
+    end: usize,
+    flags: u32,
+    /// Where its data starts in the file, if it has any.
+    offset: Option<usize>,
+}
+
+/// The NT_PRSTATUS note, for the process killed by `signal`, whose
+/// registers are in `frame`.
+fn prstatus(p: &Process, frame: &cpu::TrapFrame, signal: usize) -> Vec<u8> {
+    let mut desc = Vec::from([0; PRSTATUS_SIZE]);
+    desc[..4].copy_from_slice(&(signal as u32).to_le_bytes());
+    desc[PR_CURSIG..][..2].copy_from_slice(&(signal as u16).to_le_bytes());
+    desc[PR_PID..][..4].copy_from_slice(&(p.pid() as u32).to_le_bytes());
+    desc[PR_PID + 4..][..4].copy_from_slice(&(p.ppid() as u32).to_le_bytes());
+    for (i, reg) in frame.regs.iter().enumerate() {
+        let reg = if i == 0 { frame.pc } else { *reg };
+        desc[PR_REG + i * WORD..][..WORD].copy_from_slice(&reg.to_le_bytes());
+    }
+    let mut note = Out(Vec::new());
+    note.u32(5);
+    note.u32(PRSTATUS_SIZE as u32);
+    note.u32(NT_PRSTATUS);
+    note.0.extend_from_slice(b"CORE\0\0\0\0");
+    note.0.extend_from_slice(&desc);
+    note.0
+}
+
+/// Write a core file for the user process `p`, killed by `signal` with its
+/// registers in `frame`, into its current directory. Returns whether it
+/// could be.
+pub fn dump(p: &Process, frame: &cpu::TrapFrame, signal: usize) -> bool {
+    if !p.is_user() {
+        return false;
+    }
+    let root = cpu::satp_root(frame.satp) as *mut Table;
+    let mut segments: Vec<Segment> = p
+        .maps()
+        .into_iter()
+        .filter(|m| m.name != "[kernel]")
+        .map(|m| {
+            let bit = |b, f| if m.bits.contains(b) { f } else { 0 };
+            Segment {
+                start: m.start,
+                end: m.end,
+                flags: bit(EntryBits::READ, PF_R)
+                    | bit(EntryBits::WRITE, PF_W)
+                    | bit(EntryBits::EXECUTE, PF_X),
+                offset: None,
+            }
+        })
+        .collect();
+    let note = prstatus(p, frame, signal);
+    let headers = EHDR_SIZE + (1 + segments.len()) * PHDR_SIZE;
+    let mut offset = (headers + note.len()).next_multiple_of(PAGE_SIZE);
+    for s in segments.iter_mut().filter(|s| s.flags & PF_R != 0) {
+        s.offset = Some(offset);
+        offset += s.end - s.start;
+    }
+
+    let mut out = Out(Vec::with_capacity(headers + note.len()));
+    out.0
+        .extend_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS, 1, 1, 0]);
+    out.0.extend_from_slice(&[0; 8]);
+    out.u16(ET_CORE);
+    out.u16(EM_RISCV);
+    out.u32(1);
+    out.word(0);
+    out.word(EHDR_SIZE);
+    out.word(0);
+    out.u32(0);
+    out.u16(EHDR_SIZE as u16);
+    out.u16(PHDR_SIZE as u16);
+    out.u16(1 + segments.len() as u16);
+    out.u16(0);
+    out.u16(0);
+    out.u16(0);
+    out.phdr(PT_NOTE, 0, headers, 0, note.len(), 0);
+    for s in &segments {
+        let size = s.end - s.start;
+        let filesz = if s.offset.is_some() { size } else { 0 };
+        let offset = s.offset.unwrap_or(offset);
+        out.phdr(PT_LOAD, s.flags, offset, s.start, filesz, size);
+    }
+    out.0.extend_from_slice(&note);
+
+    let path = [p.cwd.as_slice(), b"/core"].concat();
+    vfs::set_cred(p.cred);
+    // Traps can't sleep, the disk is waited for.
+    let ret = block::with_sleep(false, || -> Result<(), SysError> {
+        let file = vfs::open(&path, O_WRONLY | O_CREAT | O_TRUNC, 0o600)?;
+        let mut done = 0;
+        while done < out.0.len() {
+            done += file.write(done, &out.0[done..])?;
+        }
+        let zeros = [0; PAGE_SIZE];
+        for s in &segments {
+            let Some(offset) = s.offset else {
+                continue;
+            };
+            for vaddr in (s.start..s.end).step_by(PAGE_SIZE) {
+                // Identity mapped, we're in machine mode.
+                let data = match page::walk(unsafe { &mut *root }, vaddr) {
+                    Some(entry) => unsafe {
+                        slice::from_raw_parts(entry.addr() as *const u8, PAGE_SIZE)
+                    },
+                    None => &zeros,
+                };
+                let at = offset + vaddr - s.start;
+                let mut done = 0;
+                while done < PAGE_SIZE {
+                    done += file.write(at + done, &data[done..])?;
+                }
+            }
+        }
+        Ok(())
+    });
+    vfs::set_cred(Cred::ROOT);
+    ret.is_ok()
+}
@@ -0,0 +1,376 @@
+use crate::{
+    board,
+    device::{self, Device},
+    fdt, lockdep,
+};
+use core::{arch::asm, panic::Location};
+
+// ///////////////////////////////////
+// / TRAP FRAME
+// ///////////////////////////////////
+
+/// The trap frame is where we store the registers of whatever was running
+/// when a trap happened. asm/trap.S writes into it directly, at the offsets
+/// of regs, fregs, satp, trap_stack, pc and mode that assembly.rs hands it.
+/// The floating point registers are 64 bits, even on RV32, and they're
+/// left alone on harts without an FPU.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],
+    pub fregs: [u64; 32],
+    pub satp: usize,
+    pub trap_stack: *mut u8,
+    pub hartid: usize,
+    pub pc: usize,
+    pub mode: usize,
+    pub pid: usize,
+}
+
+impl TrapFrame {
+    pub const fn zero() -> Self {
+        TrapFrame {
+            regs: [0; 32],
+            fregs: [0; 32],
+            satp: 0,
+            trap_stack: core::ptr::null_mut(),
+            hartid: 0,
+            pc: 0,
+            mode: 0,
+            pid: 0,
+        }
+    }
+}
+
+/// The CPU modes a process can run in. The value is what ends up in
+/// mstatus.MPP when we switch to the process.
+#[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CpuMode {
+    User = 0,
+    Machine = 3,
+}
+
+/// Register indices into TrapFrame::regs, named after the ABI.
+#[allow(dead_code)]
+pub enum Registers {
+    Zero = 0,
+    Ra,
+    Sp,
+    Gp,
+    Tp,
+    T0,
+    T1,
+    T2,
+    S0,
+    S1,
+    A0, // 10
+    A1,
+    A2,
+    A3,
+    A4,
+    A5,
+    A6,
+    A7,
+    S2,
+    S3,
+    S4, // 20
+    S5,
+    S6,
+    S7,
+    S8,
+    S9,
+    S10,
+    S11,
+    T3,
+    T4,
+    T5, // 30
+    T6,
+}
+
+pub const fn gp(r: Registers) -> usize {
+    r as usize
+}
+
+// There is one kernel trap frame per hart. It is used while the kernel
+// itself is running (kinit) and before any process has been scheduled.
+pub const MAX_HARTS: usize = 8;
+pub static mut KERNEL_TRAP_FRAME: [TrapFrame; MAX_HARTS] = [TrapFrame::zero(); MAX_HARTS];
+
+// ///////////////////////////////////
+// / CONTROL AND STATUS REGISTERS
+// ///////////////////////////////////
+
+pub fn mscratch_write(val: usize) {
+    unsafe {
+        asm!("csrw mscratch, {}", in(reg) val);
+    }
+}
+
+pub fn mscratch_read() -> usize {
+    let rval;
+    unsafe {
+        asm!("csrr {}, mscratch", out(reg) rval);
+    }
+    rval
+}
+
+pub fn mstatus_read() -> usize {
+    let rval;
+    unsafe {
+        asm!("csrr {}, mstatus", out(reg) rval);
+    }
+    rval
+}
+
+pub fn mhartid_read() -> usize {
+    let rval;
+    unsafe {
+        asm!("csrr {}, mhartid", out(reg) rval);
+    }
+    rval
+}
+
+pub fn sp_read() -> usize {
+    let rval;
+    unsafe {
+        asm!("mv {}, sp", out(reg) rval);
+    }
+    rval
+}
+
+// The MODE field of satp for the paging we use (Sv39 on RV64, Sv32 on
+// RV32), and where its ASID and PPN fields are.
+#[cfg(target_pointer_width = "64")]
+mod satp {
+    pub const MODE: usize = 8 << 60;
+    pub const ASID_SHIFT: usize = 44;
+    pub const ASID_MASK: usize = 0xffff;
+    pub const PPN_MASK: usize = 0xfff_ffff_ffff;
+}
+#[cfg(target_pointer_width = "32")]
+mod satp {
+    pub const MODE: usize = 1 << 31;
+    pub const ASID_SHIFT: usize = 22;
+    pub const ASID_MASK: usize = 0x1ff;
+    pub const PPN_MASK: usize = 0x3f_ffff;
+}
+
+/// Build a satp value for the given address space id and root page table.
+pub const fn build_satp(asid: usize, root: usize) -> usize {
+    satp::MODE | (asid & satp::ASID_MASK) << satp::ASID_SHIFT | (root >> 12) & satp::PPN_MASK
+}
+
+/// Get the root page table out of a satp value, or 0 if paging is off.
+pub const fn satp_root(satp: usize) -> usize {
+    if satp & satp::MODE == 0 {
+        0
+    } else {
+        (satp & satp::PPN_MASK) << 12
+    }
+}
+
+/// Make changes to the page tables visible to the hart.
+pub fn sfence_vma() {
+    unsafe {
+        if board::OLD_PRIV_SPEC {
+            // sfence.vm, which the assembler doesn't know any more.
+            asm!(".word 0x10400073");
+        } else {
+            asm!("sfence.vma");
+        }
+    }
+}
+
+/// The machine interrupt enable bit of mstatus.
+const MSTATUS_MIE: usize = 1 << 3;
+
+/// Whether this hart takes interrupts.
+pub fn interrupts_on() -> bool {
+    mstatus_read() & MSTATUS_MIE != 0
+}
+
+/// Run `f` with interrupts disabled on this hart. Kernel processes run with
+/// interrupts on, so this is how they keep an interrupt handler from seeing
+/// something half done.
+///
+/// Debug builds keep where each hart's sections were opened, for lockdep's
+/// reports, and check that they aren't nested without end, and that one
+/// inside another leaves interrupts off, as the one around it expects: if
+/// they're on when it ends, something in it gave the hart to another
+/// process, which runs with them on, or turned them on itself.
+#[track_caller]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let status: usize;
+    unsafe {
+        asm!("csrrc {}, mstatus, {}", out(reg) status, in(reg) MSTATUS_MIE);
+    }
+    if cfg!(debug_assertions) {
+        let depth = lockdep::section_opened(Location::caller());
+        assert!(
+            depth <= lockdep::MAX_SECTIONS,
+            "interrupts-off sections nested {} deep",
+            depth
+        );
+    }
+    let ret = f();
+    if cfg!(debug_assertions) {
+        lockdep::section_closed();
+        assert!(
+            status & MSTATUS_MIE != 0 || !interrupts_on(),
+            "interrupts came on in an interrupts-off section inside another"
+        );
+    }
+    if status & MSTATUS_MIE != 0 {
+        unsafe {
+            asm!("csrs mstatus, {}", in(reg) MSTATUS_MIE);
+        }
+    }
+    ret
+}
+
+/// Take the interrupts that are pending, from code that runs with them
+/// disabled, and disable them again.
+pub fn allow_interrupts() {
+    unsafe {
+        asm!("csrs mstatus, {0}", "csrc mstatus, {0}", in(reg) MSTATUS_MIE);
+    }
+}
+
+// ///////////////////////////////////
+// / CORE LOCAL INTERRUPTOR (CLINT)
+// ///////////////////////////////////
+
+// Where its registers are in it.
+const CLINT_MSIP: usize = 0;
+const CLINT_MTIMECMP: usize = 0x4000;
+const CLINT_MTIME: usize = 0xbff8;
+
+/// The frequency of the mtime register on the board we're built for.
+pub const FREQ: u64 = board::TIMEBASE_HZ;
+
+static mut CLINT: usize = board::CLINT_BASE;
+
+/// The driver for the CLINT. The timer frequency is baked into a lot of
+/// constants, so all it can do about a different one is complain.
+pub struct Clint;
+
+impl device::Driver for Clint {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["riscv,clint0", "sifive,clint0"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let Some(base) = dev.base() else {
+            return false;
+        };
+        unsafe {
+            CLINT = base;
+        }
+        let freq = fdt::get()
+            .and_then(|f| f.find("/cpus"))
+            .and_then(|cpus| cpus.u32_property("timebase-frequency"));
+        if let Some(freq) = freq.filter(|&f| f as u64 != FREQ) {
+            println!("clint: the timer runs at {} Hz, not {} Hz", freq, FREQ);
+        }
+        true
+    }
+}
+
+/// Read the machine timer.
+#[cfg(target_pointer_width = "64")]
+pub fn get_mtime() -> u64 {
+    unsafe { ((CLINT + CLINT_MTIME) as *const u64).read_volatile() }
+}
+
+/// Read the machine timer. RV32 reads it in two halves, so read the high
+/// one again to see whether the low one wrapped in between.
+#[cfg(target_pointer_width = "32")]
+pub fn get_mtime() -> u64 {
+    let mtime = unsafe { (CLINT + CLINT_MTIME) as *const u32 };
+    loop {
+        unsafe {
+            let high = mtime.add(1).read_volatile();
+            let low = mtime.read_volatile();
+            if mtime.add(1).read_volatile() == high {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
+
+/// The number of cycles the hart has executed.
+#[cfg(target_pointer_width = "64")]
+pub fn cycles() -> u64 {
+    let c: u64;
+    unsafe {
+        asm!("csrr {}, mcycle", out(reg) c);
+    }
+    c
+}
+
+/// The number of cycles the hart has executed, read in two halves like
+/// the machine timer on RV32.
+#[cfg(target_pointer_width = "32")]
+pub fn cycles() -> u64 {
+    loop {
+        let (high, low, again): (u32, u32, u32);
+        unsafe {
+            asm!("csrr {}, mcycleh", "csrr {}, mcycle", "csrr {}, mcycleh",
+                out(reg) high, out(reg) low, out(reg) again);
+        }
+        if high == again {
+            return (high as u64) << 32 | low as u64;
+        }
+    }
+}
+
+/// Send the given hart a software interrupt.
+pub fn send_ipi(hart: usize) {
+    unsafe {
+        ((CLINT + CLINT_MSIP) as *mut u32)
+            .add(hart)
+            .write_volatile(1);
+    }
+}
+
+/// Schedule the next timer interrupt of the given hart at the absolute time
+/// `when` (in mtime ticks).
+#[cfg(target_pointer_width = "64")]
+pub fn set_mtimecmp(hart: usize, when: u64) {
+    unsafe {
+        ((CLINT + CLINT_MTIMECMP) as *mut u64)
+            .add(hart)
+            .write_volatile(when);
+    }
+}
+
+/// Schedule the next timer interrupt of the given hart at the absolute time
+/// `when` (in mtime ticks). RV32 writes it in two halves; the low one is
+/// set to its maximum first, so that the compare value is never smaller
+/// than both the old and the new one halfway through.
+#[cfg(target_pointer_width = "32")]
+pub fn set_mtimecmp(hart: usize, when: u64) {
+    unsafe {
+        let cmp = ((CLINT + CLINT_MTIMECMP) as *mut u32).add(2 * hart);
+        cmp.write_volatile(u32::MAX);
+        cmp.add(1).write_volatile((when >> 32) as u32);
+        cmp.write_volatile(when as u32);
+    }
+}
+
+// ///////////////////////////////////
+// / CONTEXT SWITCHING
+// ///////////////////////////////////
+
+extern "C" {
+    /// Load the given trap frame into the hart and mret into its pc.
+    /// Defined in asm/trap.S
+    fn switch_to_user(frame: usize) -> !;
+}
+
+/// Start running whatever the given trap frame describes. This never
+/// returns, the next time the kernel runs is through a trap.
+pub fn switch_to(frame: *mut TrapFrame) -> ! {
+    unsafe { switch_to_user(frame as usize) }
+}
@@ -0,0 +1,338 @@
+// Crash dumps: when the kernel panics, what led up to it is written to a
+// partition kept for that, so it isn't lost when the panic has scrolled off
+// the serial console, or there wasn't anyone watching it. Which partition
+// is up to the kernel command line, crashdump=vda2 say, and whatever is on
+// it is written over by the first panic.
+//
+// A dump is the end of the kernel log as text, the panic's report last
+// (see klog.rs), the trap frame of what was running, and the rest of the
+// page of the stack the panic is on. Each is written from the start of a
+// sector, one after the other from sector 1, and sector 0, written last,
+// so that a dump that was cut short isn't taken for one, says where they
+// are:
+//
+//    0  "RVCRASH1"
+//    8  when it was, in nanoseconds since the Unix epoch, 64-bit
+//   16  the hart, 32-bit
+//   20  how many sections there are, 32-bit
+//   24  the sections, 24 bytes each: the kind (1 the log, 2 the trap
+//       frame, 3 memory), the first sector, 32-bit, the address it was at
+//       and the length, 64-bit
+//  508  the CRC32 of all of the above
+//
+// At boot, a dump on the partition is reported, read into memory, where
+// /proc/crash shows it as text, and wiped, so the next boot doesn't report
+// it again.
+
+use crate::{
+    block::{self, BlockDevice, SECTOR_SIZE},
+    board,
+    cpu::{self, TrapFrame},
+    fdt, klog,
+    page::PAGE_SIZE,
+    partition::crc32,
+    timer,
+};
+use alloc::{format, string::String, vec, vec::Vec};
+use core::{
+    fmt::Write,
+    mem::{offset_of, size_of},
+    ptr::addr_of_mut,
+    slice,
+};
+
+const MAGIC: &[u8] = b"RVCRASH1";
+const SECTIONS: usize = 24;
+const SECTION_SIZE: usize = 24;
+const MAX_SECTIONS: usize = (SECTOR_SIZE - 4 - SECTIONS) / SECTION_SIZE;
+const CRC: usize = SECTOR_SIZE - 4;
+
+/// How much of the kernel log a dump keeps.
+const LOG_SIZE: usize = 16 * 1024;
+
+const LOG: u32 = 1;
+const FRAME: u32 = 2;
+const MEMORY: u32 = 3;
+
+/// The sectors a dump can take: the header, and each section as big as it
+/// can be.
+const DUMP_SECTORS: usize = 1
+    + LOG_SIZE.div_ceil(SECTOR_SIZE)
+    + size_of::<TrapFrame>().div_ceil(SECTOR_SIZE)
+    + PAGE_SIZE / SECTOR_SIZE;
+
+const REG_NAMES: [&str; 32] = [
+    "pc", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5",
+    "t6",
+];
+
+/// The block device number of the partition dumps go to, if there is one.
+static mut DEV: Option<usize> = None;
+/// Whether we're dumping already, and panicked while at it.
+static mut DUMPING: bool = false;
+/// The dump found at boot.
+static mut PREVIOUS: Option<Crash> = None;
+
+/// A dump, as read back.
+struct Crash {
+    time_ns: u64,
+    hart: u32,
+    /// The kind of each section, where it was, and what was in it.
+    sections: Vec<(u32, u64, Vec<u8>)>,
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+// ///////////////////////////////////
+// / WRITING
+// ///////////////////////////////////
+
+/// A section of a dump: its kind, first sector, address and length.
+type Section = (u32, u64, usize, usize);
+
+/// Writes sectors of a dump from a buffer of one sector, so that what's
+/// written doesn't have to be copied anywhere.
+struct Writer<'a> {
+    dev: &'a mut dyn BlockDevice,
+    sector: u64,
+    buf: [u8; SECTOR_SIZE],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, mut data: &[u8]) -> Result<(), ()> {
+        while !data.is_empty() {
+            let n = data.len().min(SECTOR_SIZE - self.len);
+            self.buf[self.len..][..n].copy_from_slice(&data[..n]);
+            (self.len, data) = (self.len + n, &data[n..]);
+            if self.len == SECTOR_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out what's left, padded to the end of the sector.
+    fn flush(&mut self) -> Result<(), ()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        self.buf[self.len..].fill(0);
+        let (dev, sector, buf) = (&mut *self.dev, self.sector, &self.buf);
+        block::with_sleep(false, || dev.write_sectors(sector, buf)).map_err(|_| ())?;
+        self.sector += 1;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Write a section of `kind` that was at `addr`, made of `parts`.
+    fn section(&mut self, kind: u32, addr: usize, parts: &[&[u8]]) -> Result<Section, ()> {
+        let (sector, mut len) = (self.sector, 0);
+        for part in parts {
+            self.put(part)?;
+            len += part.len();
+        }
+        self.flush()?;
+        Ok((kind, sector, addr, len))
+    }
+}
+
+/// Write a dump of the kernel, which has panicked, to the crash partition,
+/// if there is one.
+pub fn save() {
+    let Some(dev) = (unsafe { DEV }) else {
+        return;
+    };
+    if unsafe { DUMPING } {
+        return;
+    }
+    unsafe {
+        DUMPING = true;
+    }
+    let disk = block::get(dev).unwrap();
+    let name = String::from(disk.name());
+    let mut w = Writer {
+        dev: disk,
+        sector: 1,
+        buf: [0; SECTOR_SIZE],
+        len: 0,
+    };
+    match write(&mut w) {
+        Ok(()) => println!("crashdump: written to {}", name),
+        Err(()) => println!("crashdump: couldn't write to {}", name),
+    }
+}
+
+fn write(w: &mut Writer) -> Result<(), ()> {
+    let mut sections = Vec::with_capacity(3);
+    let log = klog::text(LOG_SIZE);
+    sections.push(w.section(LOG, 0, &[log.as_bytes()])?);
+    let frame = cpu::mscratch_read();
+    if frame != 0 {
+        let bytes = unsafe { slice::from_raw_parts(frame as *const u8, size_of::<TrapFrame>()) };
+        sections.push(w.section(FRAME, frame, &[bytes])?);
+    }
+    let sp = cpu::sp_read();
+    let stack_end = (sp | (PAGE_SIZE - 1)) + 1;
+    let stack = unsafe { slice::from_raw_parts(sp as *const u8, stack_end - sp) };
+    sections.push(w.section(MEMORY, sp, &[stack])?);
+
+    let mut header = [0; SECTOR_SIZE];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..16].copy_from_slice(&timer::realtime_ns().to_le_bytes());
+    header[16..20].copy_from_slice(&(board::BOOT_HART as u32).to_le_bytes());
+    header[20..24].copy_from_slice(&(sections.len() as u32).to_le_bytes());
+    for (i, &(kind, sector, addr, len)) in sections.iter().enumerate() {
+        let s = &mut header[SECTIONS + i * SECTION_SIZE..][..SECTION_SIZE];
+        s[..4].copy_from_slice(&kind.to_le_bytes());
+        s[4..8].copy_from_slice(&(sector as u32).to_le_bytes());
+        s[8..16].copy_from_slice(&(addr as u64).to_le_bytes());
+        s[16..24].copy_from_slice(&(len as u64).to_le_bytes());
+    }
+    let crc = crc32(&header[..CRC]);
+    header[CRC..].copy_from_slice(&crc.to_le_bytes());
+    w.sector = 0;
+    w.put(&header)
+}
+
+// ///////////////////////////////////
+// / READING
+// ///////////////////////////////////
+
+/// Read `len` bytes of disk `dev` from `sector`, waiting for them.
+fn read(dev: &mut dyn BlockDevice, sector: u64, len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0; len.div_ceil(SECTOR_SIZE) * SECTOR_SIZE];
+    block::with_sleep(false, || dev.read_sectors(sector, &mut buf)).ok()?;
+    buf.truncate(len);
+    Some(buf)
+}
+
+/// The dump on `dev`, if there's one.
+fn load(dev: &mut dyn BlockDevice) -> Option<Crash> {
+    let header = read(dev, 0, SECTOR_SIZE)?;
+    if &header[..8] != MAGIC || crc32(&header[..CRC]) != u32_at(&header, CRC) {
+        return None;
+    }
+    let count = (u32_at(&header, 20) as usize).min(MAX_SECTIONS);
+    let mut sections = Vec::new();
+    for i in 0..count {
+        let s = &header[SECTIONS + i * SECTION_SIZE..][..SECTION_SIZE];
+        let (kind, sector, addr, len) = (
+            u32_at(s, 0),
+            u32_at(s, 4) as u64,
+            u64_at(s, 8),
+            u64_at(s, 16) as usize,
+        );
+        if sector + len.div_ceil(SECTOR_SIZE) as u64 > dev.num_sectors() {
+            return None;
+        }
+        sections.push((kind, addr, read(dev, sector, len)?));
+    }
+    Some(Crash {
+        time_ns: u64_at(&header, 8),
+        hart: u32_at(&header, 16),
+        sections,
+    })
+}
+
+/// The dump found at boot, as text, for /proc/crash. It's empty if there
+/// wasn't one.
+pub fn text() -> String {
+    let Some(crash) = (unsafe { &*addr_of_mut!(PREVIOUS) }) else {
+        return String::new();
+    };
+    let mut s = format!(
+        "Crashed at {}.{:09} (Unix time) on hart {}\n",
+        crash.time_ns / 1_000_000_000,
+        crash.time_ns % 1_000_000_000,
+        crash.hart
+    );
+    for (kind, addr, data) in &crash.sections {
+        match *kind {
+            FRAME if data.len() >= size_of::<TrapFrame>() => {
+                let word = |off| {
+                    let mut w = [0; size_of::<usize>()];
+                    w.copy_from_slice(&data[off..][..size_of::<usize>()]);
+                    usize::from_le_bytes(w)
+                };
+                let _ = writeln!(s, "\nTrap frame at 0x{:08x}:", addr);
+                for (i, name) in REG_NAMES.iter().enumerate() {
+                    let reg = match i {
+                        0 => word(offset_of!(TrapFrame, pc)),
+                        _ => word(i * size_of::<usize>()),
+                    };
+                    let end = if i % 4 == 3 { "\n" } else { "  " };
+                    let _ = write!(s, "{:>4} 0x{:016x}{}", name, reg, end);
+                }
+            }
+            MEMORY => {
+                let _ = writeln!(s, "\nMemory at 0x{:08x}:", addr);
+                for (i, line) in data.chunks(16).enumerate() {
+                    let _ = write!(s, "{:08x}:", *addr as usize + i * 16);
+                    for b in line {
+                        let _ = write!(s, " {:02x}", b);
+                    }
+                    s.push('\n');
+                }
+            }
+            LOG => {
+                s.push_str("\nLog:\n");
+                s.push_str(&String::from_utf8_lossy(data));
+            }
+            _ => {}
+        }
+    }
+    s
+}
+
+/// Find the crash partition the kernel command line asks for, if it does,
+/// and report the dump on it from the boot before, if there's one.
+pub fn init() {
+    let name = fdt::get()
+        .and_then(|f| f.find("/chosen"))
+        .and_then(|c| c.str_property("bootargs"))
+        .and_then(|args| {
+            args.split_ascii_whitespace()
+                .find_map(|arg| arg.strip_prefix("crashdump="))
+        });
+    let Some(name) = name else {
+        return;
+    };
+    let name = name.strip_prefix("/dev/").unwrap_or(name);
+    let Some(dev) = block::find(name.as_bytes()) else {
+        println!("crashdump: there's no disk called {}", name);
+        return;
+    };
+    let disk = block::get(dev).unwrap();
+    if disk.read_only() || disk.num_sectors() < DUMP_SECTORS as u64 {
+        println!(
+            "crashdump: {} can't be written to, or is smaller than {} KiB",
+            name,
+            DUMP_SECTORS * SECTOR_SIZE / 1024
+        );
+        return;
+    }
+    if let Some(crash) = load(disk) {
+        println!(
+            "crashdump: previous crash found on {}, cat /proc/crash for it",
+            name
+        );
+        unsafe {
+            *addr_of_mut!(PREVIOUS) = Some(crash);
+        }
+        let zero = [0; SECTOR_SIZE];
+        if block::with_sleep(false, || disk.write_sectors(0, &zero)).is_err() {
+            println!("crashdump: couldn't wipe it off {}", name);
+        }
+    }
+    unsafe {
+        DEV = Some(dev);
+    }
+}
@@ -0,0 +1,242 @@
+// devfs, the filesystem of devices, mounted at /dev. There's nothing to it
+// but names: the console, and ttyS<n> for the UART it's on if it's on one,
+// the display and the input events if there are those, null, zero, random
+// and urandom, the XMODEM receiver, and every block device. Looking one up
+// makes the device's File, which opening it hands out, so reading, writing
+// and ioctls go straight to its driver. The devices come and go (loop
+// devices are added at run time), so listing a directory lists what there
+// is right then.
+
+use crate::{
+    block, console,
+    cpu::TrapFrame,
+    entropy,
+    file::{Console, File, S_IFCHR, S_IFDIR},
+    gpu, input,
+    syscall::{Stat, SysError, SysResult, ENOENT},
+    uart,
+    vfs::{self, dirent_type, Entry, Inode, Listing},
+    xmodem,
+};
+use alloc::{format, rc::Rc, string::String, vec, vec::Vec};
+use core::ptr::addr_of_mut;
+
+use SysError::{Block, Errno};
+
+static mut DEV: u64 = 0;
+
+fn dev() -> u64 {
+    unsafe { *addr_of_mut!(DEV) }
+}
+
+/// The device at `path`, relative to /dev.
+fn open_device(path: &[u8]) -> Option<Rc<dyn File>> {
+    match path {
+        b"console" => Some(Rc::new(Console)),
+        b"fb0" => gpu::open(),
+        b"input/event0" => input::open(),
+        b"null" => Some(Rc::new(Mem::Null)),
+        b"random" => Some(Rc::new(Mem::Random)),
+        b"urandom" => Some(Rc::new(Mem::Urandom)),
+        b"xmodem" => Some(xmodem::open()),
+        b"zero" => Some(Rc::new(Mem::Zero)),
+        _ if tty().is_some_and(|n| path == tty_name(n).as_bytes()) => Some(Rc::new(Tty(tty()?))),
+        _ => block::open(path),
+    }
+}
+
+/// The names in the directory at `path`, relative to /dev, which is either
+/// /dev or /dev/input.
+fn names(path: &[u8]) -> Vec<Vec<u8>> {
+    if path == b"input/" {
+        return vec![b"event0".to_vec()];
+    }
+    let mut names = vec![b"console".to_vec()];
+    if gpu::open().is_some() {
+        names.push(b"fb0".to_vec());
+    }
+    if input::open().is_some() {
+        names.push(b"input".to_vec());
+    }
+    names.extend([b"null".to_vec(), b"random".to_vec()]);
+    if let Some(n) = tty() {
+        names.push(tty_name(n).into_bytes());
+    }
+    names.extend([b"urandom".to_vec(), b"xmodem".to_vec(), b"zero".to_vec()]);
+    let disks = (0..).map_while(block::get);
+    names.extend(disks.map(|d| d.name().as_bytes().to_vec()));
+    names
+}
+
+/// A directory of devfs, by its path relative to /dev: empty for /dev
+/// itself, "input/" for /dev/input.
+struct DevDir(&'static [u8]);
+
+impl Inode for DevDir {
+    fn lookup(&self, name: &[u8]) -> Result<Rc<dyn Inode>, SysError> {
+        if self.0.is_empty() && name == b"input" && input::open().is_some() {
+            return Ok(Rc::new(DevDir(b"input/")));
+        }
+        let path = [self.0, name].concat();
+        let file = open_device(&path).ok_or(Errno(ENOENT))?;
+        // Devices are numbered in the order they're listed, after the two
+        // directories, and those in /dev/input after the ones in /dev.
+        let i = names(self.0).iter().position(|n| n == name).unwrap_or(0);
+        let first = if self.0.is_empty() { 3 } else { 1 << 16 };
+        Ok(Rc::new(DevNode {
+            ino: first + i as u64,
+            file,
+        }))
+    }
+
+    fn open(&self, flags: usize) -> Result<Rc<dyn File>, SysError> {
+        let entries = names(self.0).into_iter().filter_map(|name| {
+            let st = self.lookup(&name).ok()?.metadata();
+            Some(Entry {
+                name,
+                ino: st.st_ino,
+                kind: dirent_type(st.st_mode),
+            })
+        });
+        Listing::open(self.metadata(), entries.collect(), flags)
+    }
+
+    fn metadata(&self) -> Stat {
+        Stat {
+            st_dev: dev(),
+            st_ino: if self.0.is_empty() { 1 } else { 2 },
+            st_mode: S_IFDIR | 0o755,
+            st_nlink: 2,
+            ..Default::default()
+        }
+    }
+}
+
+/// A device of devfs.
+struct DevNode {
+    ino: u64,
+    file: Rc<dyn File>,
+}
+
+impl Inode for DevNode {
+    fn open(&self, _flags: usize) -> Result<Rc<dyn File>, SysError> {
+        Ok(self.file.clone())
+    }
+
+    fn metadata(&self) -> Stat {
+        Stat {
+            st_dev: dev(),
+            st_ino: self.ino,
+            ..self.file.stat()
+        }
+    }
+}
+
+// ///////////////////////////////////
+// / MEMORY DEVICES
+// ///////////////////////////////////
+
+/// The memory devices, by their minor numbers on Linux, where they're
+/// major 1.
+#[derive(Clone, Copy)]
+enum Mem {
+    /// Reads as nothing, takes anything.
+    Null = 3,
+    /// Reads as zeros, takes anything.
+    Zero = 5,
+    /// Reads as random bytes, once the entropy pool is seeded.
+    Random = 8,
+    /// Reads as random bytes right away.
+    Urandom = 9,
+}
+
+impl File for Mem {
+    fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        match self {
+            Mem::Null => return Ok(0),
+            Mem::Zero => buf.fill(0),
+            Mem::Random if !entropy::is_seeded() => return Err(Block),
+            Mem::Random | Mem::Urandom => entropy::fill(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        // What's written to the random devices is mixed into the pool, but
+        // there's no telling how random it is, so it isn't credited.
+        if matches!(self, Mem::Random | Mem::Urandom) {
+            entropy::add(buf, 0);
+        }
+        Ok(buf.len())
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            st_mode: S_IFCHR | 0o666,
+            st_nlink: 1,
+            st_rdev: 1 << 8 | *self as u64,
+            ..Default::default()
+        }
+    }
+
+    fn wait(&self, pid: usize) {
+        entropy::wait(pid);
+    }
+}
+
+// ///////////////////////////////////
+// / SERIAL PORTS
+// ///////////////////////////////////
+
+/// The number of the UART the console is on, if it's on one.
+fn tty() -> Option<usize> {
+    (console::backend() == console::Backend::Uart).then(uart::console_number)
+}
+
+fn tty_name(n: usize) -> String {
+    format!("ttyS{}", n)
+}
+
+/// /dev/ttyS<n>, the UART the console is on, which is the console under
+/// the name of its UART: another name for /dev/console.
+struct Tty(usize);
+
+impl File for Tty {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        Console.read(offset, buf)
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        Console.write(offset, buf)
+    }
+
+    fn stat(&self) -> Stat {
+        // Major 4, from minor 64 on, as on Linux.
+        Stat {
+            st_rdev: 4 << 8 | (64 + self.0 as u64),
+            ..Console.stat()
+        }
+    }
+
+    fn ioctl(&self, frame: &TrapFrame, cmd: usize, arg: usize) -> SysResult {
+        Console.ioctl(frame, cmd, arg)
+    }
+
+    fn wait(&self, pid: usize) {
+        Console.wait(pid);
+    }
+}
+
+/// The root of devfs. There's only the one devfs, wherever it's mounted.
+pub fn new() -> Rc<dyn Inode> {
+    Rc::new(DevDir(b""))
+}
+
+/// Mount devfs at /dev.
+pub fn init() {
+    unsafe {
+        DEV = vfs::new_dev();
+    }
+    // The rootfs is empty yet, there is nothing in the way.
+    let _ = vfs::mount(b"/dev", new(), "devfs", "devfs", false);
+}
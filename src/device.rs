@@ -0,0 +1,209 @@
+// Devices and the drivers that bind to them. Scanners describe what they
+// find as a Device: the device tree scanner turns every node with a
+// compatible property into one, and bus drivers (like the PCI host bridge's)
+// register the devices they find on their bus. Every driver in DRIVERS says
+// which devices it handles, and the first one that matches and manages to
+// set a device up gets it. So adding a driver means adding it to the table,
+// not to kinit.
+
+use crate::{board, cpu, fdt, gpio, nvme, pci, plic, power, rtc, spi, uart, virtio};
+use alloc::{rc::Rc, vec, vec::Vec};
+use core::ptr::addr_of_mut;
+
+/// A device, as whoever found it describes it.
+pub struct Device {
+    /// What it is compatible with, most specific first.
+    pub compatible: Vec<&'static str>,
+    /// Its register blocks: where they are and how big.
+    pub regs: Vec<(usize, usize)>,
+    /// The PLIC interrupts it raises.
+    pub irqs: Vec<u32>,
+    /// Its device tree node, for drivers that want more properties.
+    pub node: Option<fdt::Node>,
+    /// Its function, for devices on the PCI bus.
+    pub pci: Option<pci::Function>,
+}
+
+impl Device {
+    /// A device that is compatible with `compat` and has `regs` and
+    /// `irqs`, and nothing else.
+    pub fn new(compat: &'static str, regs: &[(usize, usize)], irqs: &[u32]) -> Self {
+        Device {
+            compatible: vec![compat],
+            regs: regs.to_vec(),
+            irqs: irqs.to_vec(),
+            node: None,
+            pci: None,
+        }
+    }
+
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.compatible.contains(&compat)
+    }
+
+    /// Where its first register block is.
+    pub fn base(&self) -> Option<usize> {
+        self.regs.first().map(|&(base, _)| base)
+    }
+
+    /// Its first interrupt.
+    pub fn irq(&self) -> Option<u32> {
+        self.irqs.first().copied()
+    }
+}
+
+/// A driver for some kind of device.
+pub trait Driver: Sync {
+    /// The compatible strings of the devices it handles.
+    fn compatible(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Does it handle `dev`? By default, that's if the device is compatible
+    /// with one of the driver's strings.
+    fn matches(&self, dev: &Device) -> bool {
+        self.compatible().iter().any(|c| dev.is_compatible(c))
+    }
+
+    /// Set `dev` up. Returns false if the device turned out not to be
+    /// there or setting it up failed, and the device is left for the other
+    /// drivers.
+    fn probe(&self, dev: &Device) -> bool;
+
+    /// Stop using `dev`, the machine is about to go away.
+    fn remove(&self, _dev: &Device) {}
+}
+
+/// Every driver, in the order they get to look at the devices. Drivers
+/// that others depend on, like the interrupt controller's, go first.
+static DRIVERS: [&dyn Driver; 14] = [
+    &plic::Plic,
+    &cpu::Clint,
+    &rtc::Rtc,
+    &power::TestFinisher,
+    &uart::Ns16550,
+    &uart::SifiveUart,
+    &spi::SifiveSpi,
+    &gpio::SifiveGpio,
+    &gpio::Leds,
+    &gpio::Keys,
+    &virtio::VirtioMmio,
+    &pci::EcamHost,
+    &virtio::VirtioPci,
+    &nvme::NvmePci,
+];
+
+struct Entry {
+    // Shared, so that a driver can look at its device while other devices
+    // are registered.
+    dev: Rc<Device>,
+    driver: Option<&'static dyn Driver>,
+    // Whether every driver has had a look at it already.
+    offered: bool,
+}
+
+static mut DEVICES: Vec<Entry> = Vec::new();
+
+fn devices() -> &'static mut Vec<Entry> {
+    unsafe { &mut *addr_of_mut!(DEVICES) }
+}
+
+/// Hand the device at `i` to `driver`. Returns false if the driver didn't
+/// take it.
+fn bind(i: usize, driver: &'static dyn Driver) -> bool {
+    let dev = devices()[i].dev.clone();
+    if !driver.matches(&dev) || !driver.probe(&dev) {
+        return false;
+    }
+    devices()[i].driver = Some(driver);
+    true
+}
+
+/// Add a device and give it to the first driver that takes it. Bus
+/// drivers call this for what they find on their bus.
+pub fn register(dev: Device) {
+    devices().push(Entry {
+        dev: Rc::new(dev),
+        driver: None,
+        offered: true,
+    });
+    let i = devices().len() - 1;
+    for driver in DRIVERS {
+        if bind(i, driver) {
+            break;
+        }
+    }
+}
+
+/// The devices of the device tree: every node that says what it's
+/// compatible with.
+fn scan_fdt(fdt: fdt::Fdt) -> Vec<Device> {
+    fdt.nodes()
+        .filter(|node| node.compatibles().next().is_some())
+        .map(|node| Device {
+            compatible: node.compatibles().collect(),
+            regs: node.regs().collect(),
+            irqs: node.interrupts().collect(),
+            node: Some(node),
+            pci: None,
+        })
+        .collect()
+}
+
+/// Find the devices of the machine and bind drivers to them. Every driver
+/// gets to look at all devices before the next one does, and a driver sees
+/// the devices in address order.
+pub fn init() {
+    let mut found = match fdt::get() {
+        Some(fdt) => scan_fdt(fdt),
+        None => board::devices(),
+    };
+    // QEMU lists the virtio-mmio slots backwards. Going by address keeps
+    // disk names the same with and without a device tree.
+    found.sort_by_key(|d| d.base().unwrap_or(usize::MAX));
+    for dev in found {
+        devices().push(Entry {
+            dev: Rc::new(dev),
+            driver: None,
+            offered: false,
+        });
+    }
+    for driver in DRIVERS {
+        // Probing can register more devices, so the list can grow under
+        // us.
+        let mut i = 0;
+        while i < devices().len() {
+            let entry = &devices()[i];
+            if entry.driver.is_none() && !entry.offered {
+                bind(i, driver);
+            }
+            i += 1;
+        }
+    }
+    for entry in devices().iter_mut() {
+        entry.offered = true;
+    }
+}
+
+/// The register blocks of every device: what it's compatible with first,
+/// where the block is and how big.
+pub fn register_blocks() -> Vec<(&'static str, usize, usize)> {
+    let mut blocks = Vec::new();
+    for entry in devices().iter() {
+        let name = entry.dev.compatible.first().copied().unwrap_or("?");
+        for &(base, size) in &entry.dev.regs {
+            blocks.push((name, base, size));
+        }
+    }
+    blocks
+}
+
+/// Let every driver stop using its devices, last bound first. This is
+/// done right before the machine is turned off or reset.
+pub fn remove_all() {
+    for entry in devices().iter_mut().rev() {
+        if let Some(driver) = entry.driver.take() {
+            driver.remove(&entry.dev);
+        }
+    }
+}
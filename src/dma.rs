@@ -0,0 +1,90 @@
+// Memory that devices read and write themselves (DMA). The kernel runs in
+// machine mode without paging and the virt machine has no IOMMU, so the
+// address a device is given for a buffer (its bus address) is just the
+// physical address we use for it. Drivers still go through here, so that
+// this is written down in one place, and so their buffers come page aligned,
+// zeroed, where the device can reach them and are given back when dropped.
+
+use crate::page::{self, align_val, PAGE_ORDER, PAGE_SIZE};
+use core::{arch::asm, slice};
+
+/// Memory any device can reach.
+pub const NO_LIMIT: usize = usize::MAX;
+
+/// A buffer that both we and a device access.
+pub struct DmaBuffer {
+    cpu: *mut u8,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// Where the buffer is for us.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.cpu
+    }
+
+    /// Where the buffer is for the device.
+    pub fn bus_addr(&self) -> usize {
+        self.cpu as usize
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.cpu, self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        page::dealloc(self.cpu);
+    }
+}
+
+/// Allocate `len` bytes for a device to use. They are zeroed and start on
+/// a page boundary. Returns None if we're out of memory.
+pub fn alloc_coherent(len: usize) -> Option<DmaBuffer> {
+    alloc_coherent_below(len, NO_LIMIT)
+}
+
+/// Allocate `len` bytes like alloc_coherent(), for a device that can only
+/// reach bus addresses below `limit`. The page allocator can't be asked for
+/// low memory, so this fails if what it gives us is too high up.
+pub fn alloc_coherent_below(len: usize, limit: usize) -> Option<DmaBuffer> {
+    let size = align_val(len.max(1), PAGE_ORDER);
+    let cpu = page::zalloc(size / PAGE_SIZE);
+    if cpu.is_null() {
+        return None;
+    }
+    let buf = DmaBuffer { cpu, size };
+    if buf
+        .bus_addr()
+        .checked_add(size)
+        .is_none_or(|end| end > limit)
+    {
+        return None;
+    }
+    Some(buf)
+}
+
+/// The bus address of memory that doesn't come from alloc_coherent(), like
+/// a request on the kernel stack that the device answers before we return.
+pub fn bus_addr<T>(p: *const T) -> usize {
+    p as usize
+}
+
+/// Order our writes to memory: a device that sees the later ones also
+/// sees the earlier ones.
+pub fn wmb() {
+    unsafe { asm!("fence w, w") }
+}
+
+/// Order our reads of memory: the later ones can't see older data than the
+/// earlier ones did.
+pub fn rmb() {
+    unsafe { asm!("fence r, r") }
+}
+
+/// Order everything, our memory accesses and our device register accesses,
+/// like before telling a device to go look at memory we've written.
+pub fn mb() {
+    unsafe { asm!("fence iorw, iorw") }
+}
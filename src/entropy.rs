@@ -0,0 +1,211 @@
+// The kernel entropy pool. Anything unpredictable (the timing of
+// interrupts, jitter in how long the CPU takes to run the same code,
+// hardware random number generators) is mixed into the pool, and random
+// bytes are drawn from a ChaCha20 generator that is keyed from it.
+// Sources are credited with a conservative estimate of how much entropy they
+// add, and until the pool has been credited with enough of it, it is not
+// seeded and readers who want good randomness have to wait.
+
+use crate::{cpu, process::WaitQueue, rtc};
+use core::{hint::black_box, ptr::addr_of_mut};
+
+/// How much entropy the pool must have been credited with (in bits) before
+/// we hand out random numbers.
+pub const SEED_BITS: usize = 256;
+
+// How many jitter samples init() takes, and how many of them make a bit.
+const JITTER_SAMPLES: usize = 2048;
+const SAMPLES_PER_BIT: usize = 4;
+// How many interrupts make a bit.
+const INTERRUPTS_PER_BIT: usize = 16;
+
+struct Pool {
+    // Input is folded into this, then stirred with the ChaCha permutation.
+    state: [u32; 16],
+    // The key and position of the output generator.
+    key: [u32; 8],
+    counter: u64,
+    // Where the next word of input goes, and whether there has been any
+    // input since the key was last derived from the state.
+    pos: usize,
+    fresh: bool,
+    // Entropy credited since boot, in bits, and the number of interrupts
+    // we've seen.
+    credited: usize,
+    interrupts: usize,
+}
+
+static mut POOL: Pool = Pool {
+    state: [0; 16],
+    key: [0; 8],
+    counter: 0,
+    pos: 0,
+    fresh: false,
+    credited: 0,
+    interrupts: 0,
+};
+// Processes waiting for the pool to be seeded.
+static mut WAITERS: WaitQueue = WaitQueue::new("entropy");
+
+fn pool() -> &'static mut Pool {
+    unsafe { &mut *addr_of_mut!(POOL) }
+}
+
+// ///////////////////////////////////
+// / CHACHA20
+// ///////////////////////////////////
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block function: 20 rounds, then add the input back in.
+fn chacha20(input: &[u32; 16]) -> [u32; 16] {
+    let mut x = *input;
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 1, 5, 9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7, 8, 13);
+        quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    for (o, i) in x.iter_mut().zip(input) {
+        *o = o.wrapping_add(*i);
+    }
+    x
+}
+
+/// "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+impl Pool {
+    /// Produce the next 64 bytes of output.
+    fn block(&mut self) -> [u32; 16] {
+        let mut input = [0; 16];
+        input[..4].copy_from_slice(&SIGMA);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter as u32;
+        input[13] = (self.counter >> 32) as u32;
+        self.counter += 1;
+        chacha20(&input)
+    }
+
+    fn mix(&mut self, data: &[u8]) {
+        for chunk in data.chunks(4) {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            // Spread the input over the whole state before stirring.
+            let i = self.pos;
+            self.state[i] = self.state[i].rotate_left(7) ^ u32::from_le_bytes(word);
+            self.pos = (self.pos + 1) % 16;
+        }
+        self.state = chacha20(&self.state);
+        self.fresh = true;
+    }
+
+    /// Derive a new output key from the pool, so that everything mixed in
+    /// so far affects future output.
+    fn reseed(&mut self) {
+        let out = chacha20(&self.state);
+        for (k, (o, s)) in self.key.iter_mut().zip(out.iter().zip(self.state.iter())) {
+            *k ^= o ^ s;
+        }
+        self.counter = 0;
+        self.state = chacha20(&out);
+        self.fresh = false;
+    }
+}
+
+// ///////////////////////////////////
+// / SOURCES
+// ///////////////////////////////////
+
+/// Mix `data` into the pool and credit it with `bits` bits of entropy.
+pub fn add(data: &[u8], bits: usize) {
+    let pool = pool();
+    let was_seeded = is_seeded();
+    pool.mix(data);
+    pool.credited = pool.credited.saturating_add(bits);
+    if !was_seeded && is_seeded() {
+        pool.reseed();
+        unsafe {
+            (*addr_of_mut!(WAITERS)).wake_all();
+        }
+    }
+}
+
+/// Mix in the time of an interrupt. This is called for every interrupt;
+/// each one is only worth a fraction of a bit.
+pub fn add_interrupt() {
+    let pool = pool();
+    pool.interrupts = pool.interrupts.wrapping_add(1);
+    let jitter = cpu::cycles() ^ cpu::get_mtime().rotate_left(32);
+    let bits = usize::from(pool.interrupts.is_multiple_of(INTERRUPTS_PER_BIT));
+    add(&jitter.to_le_bytes(), bits);
+}
+
+/// Seed the pool from the CPU: time how long the same small piece of work
+/// takes over and over. Caches, the pipeline and (under QEMU) the host all
+/// make that vary a little. The RTC goes in too, it isn't secret but it
+/// makes every boot different.
+pub fn init() {
+    add(&rtc::read_ns().to_le_bytes(), 0);
+    let mut scratch = [0u64; 64];
+    let mut last = cpu::cycles();
+    for i in 0..JITTER_SAMPLES {
+        // Something for the memory system to do.
+        let j = (last as usize ^ i) % scratch.len();
+        scratch[j] = black_box(scratch[j].wrapping_mul(31).wrapping_add(last));
+        let now = cpu::cycles();
+        let delta = now.wrapping_sub(last);
+        last = now;
+        let bits = usize::from(i.is_multiple_of(SAMPLES_PER_BIT));
+        add(&delta.to_le_bytes(), bits);
+    }
+}
+
+// ///////////////////////////////////
+// / OUTPUT
+// ///////////////////////////////////
+
+/// Has the pool been credited with enough entropy to hand any out?
+pub fn is_seeded() -> bool {
+    pool().credited >= SEED_BITS
+}
+
+/// Fill `buf` with random bytes. This works whether the pool is seeded or
+/// not, callers who care must check is_seeded() first.
+pub fn fill(buf: &mut [u8]) {
+    let pool = pool();
+    if pool.fresh {
+        pool.reseed();
+    }
+    for chunk in buf.chunks_mut(64) {
+        let block = pool.block();
+        for (i, b) in chunk.iter_mut().enumerate() {
+            *b = block[i / 4].to_le_bytes()[i % 4];
+        }
+    }
+    // Move on to a new key right away, so that the output we just handed
+    // out can't be recomputed later (forward secrecy).
+    let next = pool.block();
+    pool.key.copy_from_slice(&next[..8]);
+    pool.counter = 0;
+}
+
+/// Block the process `pid` until the pool is seeded.
+pub fn wait(pid: usize) {
+    unsafe {
+        (*addr_of_mut!(WAITERS)).wait(pid);
+    }
+}
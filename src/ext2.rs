@@ -0,0 +1,532 @@
+// ext2, the filesystem of Linux's mke2fs, read-only, through the block
+// cache. Every disk that has one is mounted at /mnt/<disk> at boot, so the
+// images Linux makes can be looked at as they are.
+//
+// The superblock is 1024 bytes into the disk, and says how big a block is
+// (1024 << s_log_block_size bytes) and how the disk is split into block
+// groups. After it, in the block after the one it's in, come the group
+// descriptors, which say where each group's bitmaps and inode table are.
+// Inode n is entry (n - 1) % inodes_per_group of the table of group
+// (n - 1) / inodes_per_group; inodes count from 1, and the root directory
+// is inode 2.
+//
+// An inode has twelve block numbers of its file, then one of a block of
+// block numbers, one of a block of those, and one of a block of those in
+// turn. Block 0 is a hole. ext4's extents, which replace these, aren't
+// understood, and neither are filesystems that need them. A directory is
+// a file of entries that each say how long they are: an inode number (0
+// if the entry is unused), the entry's length, the name's length, the
+// file's type and the name. Entries don't cross blocks.
+//
+// Symbolic links are files whose contents are where they point, kept in the
+// block numbers themselves if that's short enough: a "fast" symlink, one
+// with no blocks. The VFS follows them, so opening one itself fails with
+// ELOOP.
+
+use crate::{
+    bcache::{self, Readahead, BLOCK_SIZE},
+    block,
+    file::{File, O_ACCMODE, O_RDONLY, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG},
+    syscall::{
+        Stat, SysError, EINVAL, EIO, EISDIR, ELOOP, ENAMETOOLONG, ENOENT, ENOTDIR, EROFS, PATH_MAX,
+    },
+    vfs::{self, Inode},
+};
+use alloc::{rc::Rc, vec, vec::Vec};
+use core::ptr::addr_of_mut;
+
+use SysError::Errno;
+
+/// Where the superblock is on the disk.
+const SUPERBLOCK: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+/// The size of inodes on filesystems of revision 0.
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GROUP_DESC_SIZE: usize = 32;
+const ROOT_INO: u32 = 2;
+const NAME_LEN: usize = 255;
+
+// Features that change how the disk is laid out: a file type in directory
+// entries, which is all we can do without, and block groups whose tables
+// are kept together, which changes nothing when all we do is read.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+/// How many block numbers an inode has itself.
+const DIRECT_BLOCKS: u64 = 12;
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+/// What we use of an inode.
+#[derive(Clone, Copy)]
+struct DiskInode {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    atime: u32,
+    ctime: u32,
+    mtime: u32,
+    links: u16,
+    // In 512-byte units.
+    blocks: u32,
+    block: [u32; 15],
+}
+
+impl DiskInode {
+    fn parse(b: &[u8]) -> Self {
+        let mut block = [0; 15];
+        for (i, n) in block.iter_mut().enumerate() {
+            *n = u32_at(b, 40 + 4 * i);
+        }
+        let mode = u16_at(b, 0);
+        // The high half of the size of a regular file; it's something else
+        // for the rest.
+        let size_high = if mode as u32 & S_IFMT == S_IFREG {
+            u32_at(b, 108) as u64
+        } else {
+            0
+        };
+        DiskInode {
+            mode,
+            uid: u16_at(b, 2) as u32 | (u16_at(b, 120) as u32) << 16,
+            gid: u16_at(b, 24) as u32 | (u16_at(b, 122) as u32) << 16,
+            size: u32_at(b, 4) as u64 | size_high << 32,
+            atime: u32_at(b, 8),
+            ctime: u32_at(b, 12),
+            mtime: u32_at(b, 16),
+            links: u16_at(b, 26),
+            blocks: u32_at(b, 28),
+            block,
+        }
+    }
+
+    fn kind(&self) -> u32 {
+        self.mode as u32 & S_IFMT
+    }
+}
+
+/// A mounted filesystem: what its superblock says.
+struct Fs {
+    dev: usize,
+    block_size: usize,
+    inodes: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    // The first block of the group descriptors.
+    descriptors: u32,
+    // Whether directory entries have the file's type.
+    filetype: bool,
+}
+
+static mut FILESYSTEMS: Vec<Fs> = Vec::new();
+
+fn filesystems() -> &'static mut Vec<Fs> {
+    unsafe { &mut *addr_of_mut!(FILESYSTEMS) }
+}
+
+impl Fs {
+    /// Read `buf` from `pos` on the disk.
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), SysError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let start = (at % BLOCK_SIZE as u64) as usize;
+            let len = (BLOCK_SIZE - start).min(buf.len() - done);
+            let b = bcache::bread(self.dev, at / BLOCK_SIZE as u64)?;
+            buf[done..done + len].copy_from_slice(&b.data()[start..start + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn block_pos(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    fn read_u32(&self, pos: u64) -> Result<u32, SysError> {
+        let mut b = [0; 4];
+        self.read_at(pos, &mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<DiskInode, SysError> {
+        if ino == 0 || ino > self.inodes {
+            return Err(Errno(EIO));
+        }
+        let (group, index) = (
+            (ino - 1) / self.inodes_per_group,
+            (ino - 1) % self.inodes_per_group,
+        );
+        let desc = self.block_pos(self.descriptors) + (group as usize * GROUP_DESC_SIZE) as u64;
+        let table = self.read_u32(desc + 8)?;
+        let mut b = [0; GOOD_OLD_INODE_SIZE];
+        self.read_at(
+            self.block_pos(table) + index as u64 * self.inode_size as u64,
+            &mut b,
+        )?;
+        Ok(DiskInode::parse(&b))
+    }
+
+    /// The block that has block `n` of the file of `inode`, 0 if it's a
+    /// hole.
+    fn bmap(&self, inode: &DiskInode, n: u64) -> Result<u32, SysError> {
+        if n < DIRECT_BLOCKS {
+            return Ok(inode.block[n as usize]);
+        }
+        let per_block = (self.block_size / 4) as u64;
+        let (mut n, mut levels, mut span) = (n - DIRECT_BLOCKS, 1, per_block);
+        while n >= span {
+            n -= span;
+            levels += 1;
+            span *= per_block;
+            if levels > 3 {
+                return Err(Errno(EINVAL));
+            }
+        }
+        let mut block = inode.block[DIRECT_BLOCKS as usize - 1 + levels];
+        for level in (0..levels).rev() {
+            if block == 0 {
+                break;
+            }
+            let i = n / per_block.pow(level as u32) % per_block;
+            block = self.read_u32(self.block_pos(block) + i * 4)?;
+        }
+        Ok(block)
+    }
+
+    fn read_data(&self, inode: &DiskInode, offset: u64, buf: &mut [u8]) -> Result<usize, SysError> {
+        let n = (buf.len() as u64).min(inode.size.saturating_sub(offset)) as usize;
+        let bs = self.block_size as u64;
+        let mut done = 0;
+        while done < n {
+            let pos = offset + done as u64;
+            let start = pos % bs;
+            let len = ((bs - start) as usize).min(n - done);
+            let out = &mut buf[done..done + len];
+            match self.bmap(inode, pos / bs)? {
+                0 => out.fill(0),
+                block => self.read_at(self.block_pos(block) + start, out)?,
+            }
+            done += len;
+        }
+        Ok(n)
+    }
+
+    /// Start reading the blocks of the file of `inode` that the `len` bytes
+    /// from `offset` are in, without waiting for them.
+    fn readahead(&self, inode: &DiskInode, offset: u64, len: u64) {
+        let bs = self.block_size as u64;
+        let end = (offset + len).min(inode.size);
+        for n in offset / bs..end.div_ceil(bs) {
+            match self.bmap(inode, n) {
+                Ok(0) => {}
+                Ok(block) => {
+                    let first = self.block_pos(block) / BLOCK_SIZE as u64;
+                    bcache::readahead(self.dev, first, bs / BLOCK_SIZE as u64);
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// The entry at `offset` in the directory of `dir`: its inode number,
+    /// 0 if it's unused, its name and type, and where the next one is.
+    fn dir_entry(&self, dir: &DiskInode, offset: u64) -> Result<(u32, Vec<u8>, u8, u64), SysError> {
+        let mut h = [0; 8];
+        if self.read_data(dir, offset, &mut h)? < h.len() {
+            return Err(Errno(EIO));
+        }
+        let (ino, rec_len) = (u32_at(&h, 0), u16_at(&h, 4) as u64);
+        // Without the file's type, its byte is the high byte of the name's
+        // length.
+        let (name_len, kind) = if self.filetype {
+            (h[6] as usize, h[7])
+        } else {
+            (u16_at(&h, 6) as usize, 0)
+        };
+        if rec_len < 8 + name_len as u64 || rec_len % 4 != 0 {
+            return Err(Errno(EIO));
+        }
+        let mut name = vec![0; name_len];
+        self.read_data(dir, offset + 8, &mut name)?;
+        Ok((ino, name, kind, offset + rec_len))
+    }
+
+    fn find_entry(&self, dir: &DiskInode, name: &[u8]) -> Result<u32, SysError> {
+        let mut offset = 0;
+        while offset < dir.size {
+            let (ino, n, _, next) = self.dir_entry(dir, offset)?;
+            if ino != 0 && n == name {
+                return Ok(ino);
+            }
+            offset = next;
+        }
+        Err(Errno(ENOENT))
+    }
+}
+
+/// Mount the ext2 filesystem on disk `dev`, and return its root. It's
+/// read-only whatever `read_only` says.
+pub fn mount(dev: usize, _read_only: bool) -> Result<Rc<dyn Inode>, SysError> {
+    let mut sb = [0; 1024];
+    let probe = Fs {
+        dev,
+        block_size: 1024,
+        inodes: 0,
+        inodes_per_group: 1,
+        inode_size: GOOD_OLD_INODE_SIZE,
+        descriptors: 0,
+        filetype: false,
+    };
+    probe.read_at(SUPERBLOCK, &mut sb)?;
+    if u16_at(&sb, 56) != MAGIC {
+        return Err(Errno(EINVAL));
+    }
+    let incompat = u32_at(&sb, 96);
+    if incompat & !INCOMPAT_SUPPORTED != 0 {
+        println!(
+            "ext2: disk {} needs features we don't have ({:#x})",
+            dev,
+            incompat & !INCOMPAT_SUPPORTED
+        );
+        return Err(Errno(EINVAL));
+    }
+    let log = u32_at(&sb, 24);
+    if log > 6 {
+        return Err(Errno(EINVAL));
+    }
+    let inode_size = match u32_at(&sb, 76) {
+        0 => GOOD_OLD_INODE_SIZE,
+        _ => u16_at(&sb, 88) as usize,
+    };
+    let fs = Fs {
+        dev,
+        block_size: 1024 << log,
+        inodes: u32_at(&sb, 0),
+        inodes_per_group: u32_at(&sb, 40),
+        inode_size,
+        descriptors: u32_at(&sb, 20) + 1,
+        filetype: incompat & INCOMPAT_FILETYPE != 0,
+    };
+    if fs.inodes_per_group == 0 || fs.inode_size < GOOD_OLD_INODE_SIZE {
+        return Err(Errno(EINVAL));
+    }
+    if fs.read_inode(ROOT_INO)?.kind() != S_IFDIR {
+        return Err(Errno(EINVAL));
+    }
+    filesystems().push(fs);
+    Ok(Rc::new(Node {
+        fs: filesystems().len() - 1,
+        ino: ROOT_INO,
+        ra: Readahead::new(),
+    }))
+}
+
+/// Mount every disk that has an ext2 filesystem, read-only.
+pub fn init() {
+    vfs::mount_disks("ext2", mount, true);
+}
+
+/// A file of an ext2 filesystem, both as an inode and opened.
+struct Node {
+    fs: usize,
+    ino: u32,
+    ra: Readahead,
+}
+
+impl Node {
+    fn fs(&self) -> &'static Fs {
+        &filesystems()[self.fs]
+    }
+
+    fn inode(&self) -> Result<DiskInode, SysError> {
+        self.fs().read_inode(self.ino)
+    }
+}
+
+impl Inode for Node {
+    fn lookup(&self, name: &[u8]) -> Result<Rc<dyn Inode>, SysError> {
+        let dir = self.inode()?;
+        if dir.kind() != S_IFDIR {
+            return Err(Errno(ENOTDIR));
+        }
+        if name.len() > NAME_LEN {
+            return Err(Errno(ENAMETOOLONG));
+        }
+        let ino = self.fs().find_entry(&dir, name)?;
+        Ok(Rc::new(Node {
+            fs: self.fs,
+            ino,
+            ra: Readahead::new(),
+        }))
+    }
+
+    fn create(&self, _name: &[u8], _flags: usize, _mode: usize) -> Result<Rc<dyn File>, SysError> {
+        Err(Errno(EROFS))
+    }
+
+    fn mkdir(&self, _name: &[u8], _mode: u32) -> Result<Rc<dyn Inode>, SysError> {
+        Err(Errno(EROFS))
+    }
+
+    fn unlink(&self, _name: &[u8], _dir: bool) -> Result<(), SysError> {
+        Err(Errno(EROFS))
+    }
+
+    fn link(&self, _name: &[u8], _inode: &dyn Inode) -> Result<(), SysError> {
+        Err(Errno(EROFS))
+    }
+
+    fn rename(&self, _old: &[u8], _dir: &dyn Inode, _new: &[u8]) -> Result<(), SysError> {
+        Err(Errno(EROFS))
+    }
+
+    fn symlink(&self, _name: &[u8], _target: &[u8]) -> Result<(), SysError> {
+        Err(Errno(EROFS))
+    }
+
+    fn chmod(&self, _mode: u32) -> Result<(), SysError> {
+        Err(Errno(EROFS))
+    }
+
+    fn chown(&self, _uid: u32, _gid: u32) -> Result<(), SysError> {
+        Err(Errno(EROFS))
+    }
+
+    fn readlink(&self) -> Result<Vec<u8>, SysError> {
+        let inode = self.inode()?;
+        if inode.kind() != S_IFLNK {
+            return Err(Errno(EINVAL));
+        }
+        if inode.blocks == 0 {
+            let bytes: Vec<u8> = inode.block.iter().flat_map(|n| n.to_le_bytes()).collect();
+            let len = (inode.size as usize).min(bytes.len());
+            return Ok(bytes[..len].to_vec());
+        }
+        let mut target = vec![0; inode.size.min(PATH_MAX as u64) as usize];
+        let n = self.fs().read_data(&inode, 0, &mut target)?;
+        target.truncate(n);
+        Ok(target)
+    }
+
+    fn open(&self, flags: usize) -> Result<Rc<dyn File>, SysError> {
+        let inode = self.inode()?;
+        if inode.kind() == S_IFLNK {
+            return Err(Errno(ELOOP));
+        }
+        if flags & O_ACCMODE != O_RDONLY {
+            return Err(Errno(if inode.kind() == S_IFDIR {
+                EISDIR
+            } else {
+                EROFS
+            }));
+        }
+        Ok(Rc::new(Node {
+            fs: self.fs,
+            ino: self.ino,
+            ra: Readahead::new(),
+        }))
+    }
+
+    fn metadata(&self) -> Stat {
+        let Ok(i) = self.inode() else {
+            return Stat::default();
+        };
+        let fs = self.fs();
+        Stat {
+            st_dev: block::get(fs.dev).unwrap().rdev(),
+            st_ino: self.ino as u64,
+            st_mode: i.mode as u32,
+            st_nlink: i.links as u32,
+            st_uid: i.uid,
+            st_gid: i.gid,
+            st_size: i.size as i64,
+            st_blksize: fs.block_size as i32,
+            st_blocks: i.blocks as i64,
+            st_atime: i.atime as i64,
+            st_mtime: i.mtime as i64,
+            st_ctime: i.ctime as i64,
+            ..Default::default()
+        }
+    }
+}
+
+impl File for Node {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let inode = self.inode()?;
+        if inode.kind() == S_IFDIR {
+            return Err(Errno(EISDIR));
+        }
+        let n = (buf.len() as u64).min(inode.size.saturating_sub(offset as u64)) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        let ahead = self.ra.window(offset, n);
+        // Reading can be restarted, what's read is cached by then.
+        block::with_sleep(true, || {
+            let fs = self.fs();
+            fs.readahead(&inode, offset as u64, (n + ahead) as u64);
+            fs.read_data(&inode, offset as u64, &mut buf[..n])
+        })
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, SysError> {
+        Err(Errno(EROFS))
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.inode().ok()?.size as usize)
+    }
+
+    fn stat(&self) -> Stat {
+        self.metadata()
+    }
+
+    fn wait(&self, pid: usize) {
+        block::wait(self.fs().dev, pid);
+    }
+
+    /// Offsets are where the entries are in the directory.
+    fn getdents(&self, offset: usize, buf: &mut [u8]) -> Result<(usize, usize), SysError> {
+        let fs = self.fs();
+        let dir = self.inode()?;
+        if dir.kind() != S_IFDIR {
+            return Err(Errno(ENOTDIR));
+        }
+        let (mut len, mut pos) = (0, offset as u64);
+        while pos < dir.size {
+            let (ino, name, kind, next) = fs.dir_entry(&dir, pos)?;
+            if ino != 0 {
+                // Without types in the entries, the inode has it.
+                let kind = match kind {
+                    0 => vfs::dirent_type(fs.read_inode(ino)?.mode as u32),
+                    // The entries' types are 1 for regular files, 2 for
+                    // directories, and so on, which isn't what d_type is.
+                    k => [0, 8, 4, 2, 6, 1, 12, 10]
+                        .get(k as usize)
+                        .copied()
+                        .unwrap_or(0),
+                };
+                let Some(n) = vfs::put_dirent(&mut buf[len..], ino as u64, next, kind, &name)
+                else {
+                    break;
+                };
+                len += n;
+            }
+            pos = next;
+        }
+        if len == 0 && pos < dir.size {
+            // Not even one entry fits.
+            return Err(Errno(EINVAL));
+        }
+        Ok((len, pos as usize))
+    }
+}
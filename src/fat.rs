@@ -0,0 +1,1128 @@
+// FAT32, the filesystem of SD cards and of the images mkfs.fat -F 32 makes
+// anywhere, read and written through the block cache. Every disk that has
+// one is mounted at /mnt/<disk> at boot. Changes stay in the cache until
+// they're synced.
+//
+// The disk starts with the boot sector, whose BIOS parameter block says
+// how big everything is: after some reserved sectors come the FATs (there
+// are usually two copies, which we keep the same), then the data, in
+// clusters of a few sectors numbered from 2. The FAT has an entry for every
+// cluster: 0 if it's free, otherwise the next cluster of the file it's
+// part of, or an end-of-chain mark. Only the low 28 bits count. A file is
+// a chain of clusters; its directory entry has the first one and its size.
+//
+// A directory is a file too, even the root (whose first cluster the boot
+// sector has), of 32-byte entries: an 8.3 name, attributes, times, the
+// first cluster and the size. Longer names, and names with lower case in
+// them, come in entries of their own before that one: a long file name
+// (LFN) entry has 13 UTF-16 characters of the name, numbered from the end,
+// and a checksum of the 8.3 name it goes with. Files we make get a long
+// name unless theirs is a plain upper case 8.3 one, and an 8.3 alias like
+// LONGNA~1.TXT. Names are compared without case, like FAT does.
+//
+// FAT has no inodes, so a file is known by where its directory entry is
+// on the disk, which is also its inode number for stat. That's why a file
+// has only the one name, and why a file that's open can't be unlinked or
+// renamed, or be what's renamed over: its entry would be gone from under
+// it, EBUSY. There are no owners or permissions either: everything is
+// root's, and read-only files can't be written.
+
+use crate::{
+    bcache::{self, Readahead, BLOCK_SIZE},
+    block,
+    file::{File, O_ACCMODE, O_RDONLY, O_TRUNC, S_IFDIR, S_IFREG},
+    rtc::DateTime,
+    syscall::{
+        Stat, SysError, EBUSY, EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC,
+        ENOTDIR, ENOTEMPTY, EROFS, EXDEV,
+    },
+    vfs::{self, Inode},
+};
+use alloc::{collections::BTreeMap, format, rc::Rc, string::String, vec, vec::Vec};
+use core::ptr::addr_of_mut;
+
+use SysError::Errno;
+
+// Attributes
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// What a long file name entry has for attributes.
+const ATTR_LFN: u8 = 0x0f;
+
+// The first byte of a directory entry
+const FREE: u8 = 0x00;
+const DELETED: u8 = 0xe5;
+/// Stands for a first byte of 0xe5 in a name, which would mean deleted.
+const KANJI_E5: u8 = 0x05;
+/// The first LFN entry of a name, which is its last part.
+const LFN_LAST: u8 = 0x40;
+
+// Which parts of an 8.3 name Windows shows in lower case
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+const ENTRY_SIZE: usize = 32;
+const LFN_CHARS: usize = 13;
+/// Where the characters of an LFN entry are.
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const NAME_MAX: usize = 255;
+
+/// The bits of a FAT entry that count. Entries that aren't a cluster
+/// number, like the end-of-chain marks from 0x0ffffff8 up, end a chain.
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+
+// The FSInfo sector's signatures, and where its count of free clusters is
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FSINFO_FREE: usize = 488;
+
+/// The inode number of the root directory, which has no entry.
+const ROOT_INO: u64 = 1;
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+/// The date and time of a FAT timestamp.
+fn fat_to_unix(date: u16, time: u16) -> i64 {
+    if date == 0 {
+        return 0;
+    }
+    let dt = DateTime {
+        year: 1980 + (date >> 9) as i64,
+        month: (date >> 5 & 0xf) as u8,
+        day: (date & 0x1f) as u8,
+        hour: (time >> 11) as u8,
+        minute: (time >> 5 & 0x3f) as u8,
+        second: (time & 0x1f) as u8 * 2,
+    };
+    dt.to_unix() as i64
+}
+
+/// Now, as a FAT date and time. Times go in steps of 2 seconds.
+fn fat_now() -> (u16, u16) {
+    let dt = DateTime::now();
+    let year = dt.year.clamp(1980, 2107) as u16 - 1980;
+    let date = year << 9 | (dt.month as u16) << 5 | dt.day as u16;
+    let time = (dt.hour as u16) << 11 | (dt.minute as u16) << 5 | (dt.second / 2) as u16;
+    (date, time)
+}
+
+/// The checksum of an 8.3 name that its LFN entries have.
+fn lfn_checksum(short: &[u8]) -> u8 {
+    short[..11]
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// The 8.3 name of a directory entry as a file name, like README.TXT.
+fn short_name(e: &[u8]) -> Vec<u8> {
+    let trim = |s: &[u8]| s.len() - s.iter().rev().take_while(|&&c| c == b' ').count();
+    let mut base = e[..trim(&e[..8])].to_vec();
+    let mut ext = e[8..8 + trim(&e[8..11])].to_vec();
+    if base.first() == Some(&KANJI_E5) {
+        base[0] = DELETED;
+    }
+    if e[12] & LOWER_BASE != 0 {
+        base.make_ascii_lowercase();
+    }
+    if e[12] & LOWER_EXT != 0 {
+        ext.make_ascii_lowercase();
+    }
+    if !ext.is_empty() {
+        base.push(b'.');
+        base.extend_from_slice(&ext);
+    }
+    base
+}
+
+/// Whether `c` may be in an 8.3 name.
+fn short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c) || c >= 0x80
+}
+
+/// `name` as an 8.3 entry's 11 bytes, if it's a plain upper case 8.3 name.
+fn as_short(name: &[u8]) -> Option<[u8; 11]> {
+    let (base, ext) = match name.iter().position(|&c| c == b'.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, &b""[..]),
+    };
+    let ok = |s: &[u8], max| s.len() <= max && s.iter().all(|&c| short_char(c) && c < 0x80);
+    if base.is_empty() || !ok(base, 8) || !ok(ext, 3) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base);
+    short[8..8 + ext.len()].copy_from_slice(ext);
+    Some(short)
+}
+
+/// Find an 8.3 alias for `name` that isn't in `taken` yet: as much of the
+/// name as fits, in upper case without what can't be in one, then ~1, ~2,
+/// and so on.
+fn make_alias(name: &[u8], taken: &[[u8; 11]]) -> Result<[u8; 11], SysError> {
+    let clean = |s: &[u8]| -> Vec<u8> {
+        s.iter()
+            .map(|c| c.to_ascii_uppercase())
+            .filter(|&c| c != b' ' && c != b'.')
+            .map(|c| if short_char(c) && c < 0x80 { c } else { b'_' })
+            .collect()
+    };
+    let (base, ext) = match name.iter().rposition(|&c| c == b'.') {
+        Some(i) if i > 0 => (clean(&name[..i]), clean(&name[i + 1..])),
+        _ => (clean(name), Vec::new()),
+    };
+    let base = if base.is_empty() { b"_".to_vec() } else { base };
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        let ext = &ext[..ext.len().min(3)];
+        short[8..8 + ext.len()].copy_from_slice(ext);
+        if !taken.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(Errno(EEXIST))
+}
+
+/// Whether FAT allows `name` for a file.
+fn valid_name(name: &[u8]) -> bool {
+    !name
+        .iter()
+        .any(|&c| c < 0x20 || b"\"*/:<>?\\|".contains(&c))
+}
+
+/// A directory entry, and where it is.
+struct DirEntry {
+    name: Vec<u8>,
+    raw: [u8; ENTRY_SIZE],
+    // Where its 8.3 entry is on the disk, and which entry of the directory
+    // it is.
+    pos: u64,
+    index: usize,
+}
+
+/// The first cluster of the file of directory entry `e`.
+fn cluster_of(e: &[u8]) -> u32 {
+    (u16_at(e, 20) as u32) << 16 | u16_at(e, 26) as u32
+}
+
+fn set_cluster(e: &mut [u8], cluster: u32) {
+    e[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    e[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// A new directory entry, with the times set to now.
+fn new_entry(short: &[u8; 11], attr: u8, cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut e = [0; ENTRY_SIZE];
+    e[..11].copy_from_slice(short);
+    e[11] = attr;
+    let (date, time) = fat_now();
+    for (off, v) in [(14, time), (16, date), (18, date), (22, time), (24, date)] {
+        e[off..off + 2].copy_from_slice(&v.to_le_bytes());
+    }
+    set_cluster(&mut e, cluster);
+    e
+}
+
+/// A mounted filesystem: what its boot sector says.
+struct Fs {
+    dev: usize,
+    read_only: bool,
+    cluster_size: usize,
+    // Where the first FAT, and the data, start on the disk, in bytes.
+    fat_start: u64,
+    fat_size: u64,
+    num_fats: u64,
+    data_start: u64,
+    // How many clusters there are, and the root directory's first one.
+    clusters: u32,
+    root: u32,
+    // Where to look for a free cluster first.
+    next_free: u32,
+    // How many times each file that's open is, by where its entry is.
+    open: BTreeMap<u64, usize>,
+}
+
+static mut FILESYSTEMS: Vec<Fs> = Vec::new();
+
+fn filesystems() -> &'static mut Vec<Fs> {
+    unsafe { &mut *addr_of_mut!(FILESYSTEMS) }
+}
+
+impl Fs {
+    /// Read `buf` from `pos` on the disk.
+    fn read_at(&self, pos: u64, buf: &mut [u8]) -> Result<(), SysError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let start = (at % BLOCK_SIZE as u64) as usize;
+            let len = (BLOCK_SIZE - start).min(buf.len() - done);
+            let b = bcache::bread(self.dev, at / BLOCK_SIZE as u64)?;
+            buf[done..done + len].copy_from_slice(&b.data()[start..start + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write `buf` at `pos` on the disk.
+    fn write_at(&self, pos: u64, buf: &[u8]) -> Result<(), SysError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let at = pos + done as u64;
+            let start = (at % BLOCK_SIZE as u64) as usize;
+            let len = (BLOCK_SIZE - start).min(buf.len() - done);
+            let b = bcache::bread(self.dev, at / BLOCK_SIZE as u64)?;
+            b.data_mut()[start..start + len].copy_from_slice(&buf[done..done + len]);
+            bcache::bdwrite(&b);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Start reading the clusters of the file whose clusters are `chain`
+    /// that the `len` bytes from `offset` are in, without waiting for them.
+    fn readahead(&self, chain: &[u32], offset: usize, len: usize) {
+        let clusters = offset / self.cluster_size..(offset + len).div_ceil(self.cluster_size);
+        for &c in chain.get(clusters).unwrap_or(&[]) {
+            let pos = self.cluster_pos(c);
+            let first = pos / BLOCK_SIZE as u64;
+            let last = (pos + self.cluster_size as u64 - 1) / BLOCK_SIZE as u64;
+            bcache::readahead(self.dev, first, last - first + 1);
+        }
+    }
+
+    fn valid(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster - 2) as u64 * self.cluster_size as u64
+    }
+
+    fn fat_get(&self, cluster: u32) -> Result<u32, SysError> {
+        let mut e = [0; 4];
+        self.read_at(self.fat_start + cluster as u64 * 4, &mut e)?;
+        Ok(u32::from_le_bytes(e) & CLUSTER_MASK)
+    }
+
+    /// Set the FAT entry of `cluster`, in every copy of the FAT. The top
+    /// bits of an entry aren't ours to change.
+    fn fat_set(&self, cluster: u32, value: u32) -> Result<(), SysError> {
+        for n in 0..self.num_fats {
+            let pos = self.fat_start + n * self.fat_size + cluster as u64 * 4;
+            let mut e = [0; 4];
+            self.read_at(pos, &mut e)?;
+            let old = u32::from_le_bytes(e);
+            let new = old & !CLUSTER_MASK | value & CLUSTER_MASK;
+            self.write_at(pos, &new.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain that starts at `first`.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, SysError> {
+        let mut chain = Vec::new();
+        let mut c = first;
+        while self.valid(c) {
+            if chain.len() > self.clusters as usize {
+                // It goes round in circles.
+                return Err(Errno(EIO));
+            }
+            chain.push(c);
+            c = self.fat_get(c)?;
+        }
+        Ok(chain)
+    }
+
+    /// Take a free cluster, full of zeros, and put it at the end of the
+    /// chain that ends with `last`, if there is one.
+    fn alloc_cluster(&mut self, last: Option<u32>) -> Result<u32, SysError> {
+        let start = self.next_free.clamp(2, self.clusters + 1);
+        let candidates = (start..self.clusters + 2).chain(2..start);
+        for c in candidates {
+            if self.fat_get(c)? != 0 {
+                continue;
+            }
+            self.write_at(self.cluster_pos(c), &vec![0; self.cluster_size])?;
+            self.fat_set(c, CLUSTER_MASK)?;
+            if let Some(last) = last {
+                self.fat_set(last, c)?;
+            }
+            self.next_free = c + 1;
+            return Ok(c);
+        }
+        Err(Errno(ENOSPC))
+    }
+
+    fn free_chain(&mut self, first: u32) -> Result<(), SysError> {
+        for c in self.chain(first)? {
+            self.fat_set(c, 0)?;
+        }
+        self.next_free = self.next_free.min(first);
+        Ok(())
+    }
+
+    /// Call `f` with every entry slot of the directory that starts at
+    /// cluster `dir`, with where it is, until it returns false.
+    fn scan(
+        &self,
+        dir: u32,
+        mut f: impl FnMut(u64, &[u8]) -> Result<bool, SysError>,
+    ) -> Result<(), SysError> {
+        let mut buf = vec![0; self.cluster_size];
+        for c in self.chain(dir)? {
+            self.read_at(self.cluster_pos(c), &mut buf)?;
+            for (i, e) in buf.chunks(ENTRY_SIZE).enumerate() {
+                if !f(self.cluster_pos(c) + (i * ENTRY_SIZE) as u64, e)? {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The entries of the directory that starts at cluster `dir`, with
+    /// their long names put together, from its `from`th entry slot on.
+    /// There are no volume labels, . or .. among them.
+    fn entries(&self, dir: u32, from: usize) -> Result<Vec<DirEntry>, SysError> {
+        let mut entries = Vec::new();
+        // The long name being put together, and its checksum.
+        let mut lfn: Vec<u16> = Vec::new();
+        let mut checksum = 0;
+        let mut index = 0;
+        self.scan(dir, |pos, e| {
+            index += 1;
+            if e[0] == FREE {
+                return Ok(false);
+            }
+            if index <= from || e[0] == DELETED {
+                lfn.clear();
+                return Ok(true);
+            }
+            if e[11] == ATTR_LFN {
+                let n = (e[0] & 0x1f) as usize;
+                if e[0] & LFN_LAST != 0 {
+                    lfn = vec![0xffff; n * LFN_CHARS];
+                    checksum = e[13];
+                }
+                if n >= 1 && n * LFN_CHARS <= lfn.len() && e[13] == checksum {
+                    for (i, off) in LFN_OFFSETS.iter().enumerate() {
+                        lfn[(n - 1) * LFN_CHARS + i] = u16_at(e, *off);
+                    }
+                }
+                return Ok(true);
+            }
+            let long = core::mem::take(&mut lfn);
+            if e[11] & ATTR_VOLUME_ID != 0 || e[0] == b'.' {
+                return Ok(true);
+            }
+            let name = if !long.is_empty() && checksum == lfn_checksum(e) {
+                let units = long.into_iter().take_while(|&u| u != 0 && u != 0xffff);
+                let name: String = char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                name.into_bytes()
+            } else {
+                short_name(e)
+            };
+            entries.push(DirEntry {
+                name,
+                raw: e.try_into().unwrap(),
+                pos,
+                index: index - 1,
+            });
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    fn find(&self, dir: u32, name: &[u8]) -> Result<DirEntry, SysError> {
+        let entries = self.entries(dir, 0)?;
+        let e = entries
+            .into_iter()
+            .find(|e| e.name.eq_ignore_ascii_case(name));
+        e.ok_or(Errno(ENOENT))
+    }
+
+    /// Put an entry for `name` in the directory that starts at cluster
+    /// `dir`, with `attr` and the file starting at `cluster`. Returns where
+    /// its 8.3 entry is.
+    fn add_entry(
+        &mut self,
+        dir: u32,
+        name: &[u8],
+        attr: u8,
+        cluster: u32,
+    ) -> Result<u64, SysError> {
+        if self.read_only {
+            return Err(Errno(EROFS));
+        }
+        let chars: Vec<u16> = core::str::from_utf8(name)
+            .map_err(|_| Errno(EINVAL))?
+            .encode_utf16()
+            .collect();
+        if chars.len() > NAME_MAX {
+            return Err(Errno(ENAMETOOLONG));
+        }
+        if !valid_name(name) {
+            return Err(Errno(EINVAL));
+        }
+        match self.find(dir, name) {
+            Ok(_) => return Err(Errno(EEXIST)),
+            Err(Errno(ENOENT)) => {}
+            Err(e) => return Err(e),
+        }
+        // The slots there are, whether they're free, and the 8.3 names.
+        let mut slots = Vec::new();
+        let mut taken = Vec::new();
+        let mut end = false;
+        self.scan(dir, |pos, e| {
+            end |= e[0] == FREE;
+            slots.push((pos, end || e[0] == DELETED));
+            if !end && e[0] != DELETED && e[11] != ATTR_LFN {
+                taken.push(e[..11].try_into().unwrap());
+            }
+            Ok(true)
+        })?;
+        let (short, long) = match as_short(name) {
+            Some(short) => (short, None),
+            None => (make_alias(name, &taken)?, Some(chars)),
+        };
+        let count = long.as_ref().map_or(0, |l| l.len().div_ceil(LFN_CHARS)) + 1;
+        // The first run of free slots that's long enough, or the end of the
+        // directory, made longer.
+        let mut start = slots.len();
+        let mut run = 0;
+        for (i, &(_, free)) in slots.iter().enumerate() {
+            run = if free { run + 1 } else { 0 };
+            if run == count {
+                start = i + 1 - count;
+                break;
+            }
+        }
+        if start == slots.len() {
+            start -= run;
+            let mut last = *self.chain(dir)?.last().ok_or(Errno(EIO))?;
+            while slots.len() < start + count {
+                last = self.alloc_cluster(Some(last))?;
+                let pos = self.cluster_pos(last);
+                for i in 0..self.cluster_size / ENTRY_SIZE {
+                    slots.push((pos + (i * ENTRY_SIZE) as u64, true));
+                }
+            }
+        }
+        if let Some(long) = long {
+            let checksum = lfn_checksum(&short);
+            let n = count - 1;
+            for (i, &(pos, _)) in slots[start..start + n].iter().enumerate() {
+                // The entries go from the end of the name to its start.
+                let ord = n - i;
+                let mut e = [0u8; ENTRY_SIZE];
+                e[0] = ord as u8 | if i == 0 { LFN_LAST } else { 0 };
+                e[11] = ATTR_LFN;
+                e[13] = checksum;
+                for (j, off) in LFN_OFFSETS.iter().enumerate() {
+                    // A NUL ends a name that doesn't fill its last entry,
+                    // and the rest is 0xffff.
+                    let k = (ord - 1) * LFN_CHARS + j;
+                    let u = match k.cmp(&long.len()) {
+                        core::cmp::Ordering::Less => long[k],
+                        core::cmp::Ordering::Equal => 0,
+                        core::cmp::Ordering::Greater => 0xffff,
+                    };
+                    e[*off..*off + 2].copy_from_slice(&u.to_le_bytes());
+                }
+                self.write_at(pos, &e)?;
+            }
+        }
+        let pos = slots[start + count - 1].0;
+        self.write_at(pos, &new_entry(&short, attr, cluster))?;
+        Ok(pos)
+    }
+
+    /// Take the entry `e` out of the directory that starts at cluster
+    /// `dir`, with the LFN entries of its name before it.
+    fn remove_entry(&self, dir: u32, e: &DirEntry) -> Result<(), SysError> {
+        if self.read_only {
+            return Err(Errno(EROFS));
+        }
+        let mut slots = Vec::new();
+        self.scan(dir, |pos, raw| {
+            slots.push((pos, raw[11] == ATTR_LFN && raw[0] != DELETED, raw[13]));
+            Ok(pos != e.pos)
+        })?;
+        let checksum = lfn_checksum(&e.raw);
+        let before = slots.iter().rev().skip(1);
+        let lfn = before.take_while(|&&(_, lfn, sum)| lfn && sum == checksum);
+        for &(pos, _, _) in lfn.chain(slots.last()) {
+            self.write_at(pos, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// The entry `name` of the directory that starts at `dir`, which is of
+    /// a directory if `dir_entry`, to be unlinked or renamed over.
+    fn victim(&self, dir: u32, name: &[u8], dir_entry: bool) -> Result<DirEntry, SysError> {
+        let e = self.find(dir, name)?;
+        match (e.raw[11] & ATTR_DIRECTORY != 0, dir_entry) {
+            (true, false) => return Err(Errno(EISDIR)),
+            (false, true) => return Err(Errno(ENOTDIR)),
+            (true, true) if !self.entries(cluster_of(&e.raw), 0)?.is_empty() => {
+                return Err(Errno(ENOTEMPTY))
+            }
+            _ => {}
+        }
+        if self.open.contains_key(&e.pos) {
+            return Err(Errno(EBUSY));
+        }
+        Ok(e)
+    }
+}
+
+/// Mount the FAT32 filesystem on disk `dev`, read-only if `read_only` or
+/// if the disk is, and return its root.
+pub fn mount(dev: usize, read_only: bool) -> Result<Rc<dyn Inode>, SysError> {
+    let disk = block::get(dev).ok_or(Errno(EIO))?;
+    let read_only = read_only || disk.read_only();
+    let mut bs = [0; 512];
+    let b = bcache::bread(dev, 0)?;
+    bs.copy_from_slice(&b.data()[..512]);
+    drop(b);
+    if bs[510..512] != [0x55, 0xaa] {
+        return Err(Errno(EINVAL));
+    }
+    let sector_size = u16_at(&bs, 11) as u64;
+    let sectors_per_cluster = bs[13] as u64;
+    let reserved = u16_at(&bs, 14) as u64;
+    let num_fats = bs[16] as u64;
+    let total = match u16_at(&bs, 19) {
+        0 => u32_at(&bs, 32) as u64,
+        n => n as u64,
+    };
+    let fat_sectors = u32_at(&bs, 36) as u64;
+    // FAT12 and FAT16 have a root directory of their own, with a size, and
+    // FATs whose size is in the 16-bit field.
+    let fat32 = u16_at(&bs, 17) == 0 && u16_at(&bs, 22) == 0 && fat_sectors != 0;
+    if !fat32
+        || !matches!(sector_size, 512 | 1024 | 2048 | 4096)
+        || !sectors_per_cluster.is_power_of_two()
+        || num_fats == 0
+    {
+        return Err(Errno(EINVAL));
+    }
+    let data = reserved + num_fats * fat_sectors;
+    let clusters = (total.saturating_sub(data) / sectors_per_cluster)
+        .min(fat_sectors * sector_size / 4 - 2)
+        .min(CLUSTER_MASK as u64 - 0x10) as u32;
+    let mut fs = Fs {
+        dev,
+        read_only,
+        cluster_size: (sectors_per_cluster * sector_size) as usize,
+        fat_start: reserved * sector_size,
+        fat_size: fat_sectors * sector_size,
+        num_fats,
+        data_start: data * sector_size,
+        clusters,
+        root: u32_at(&bs, 44),
+        next_free: 2,
+        open: BTreeMap::new(),
+    };
+    if !fs.valid(fs.root) {
+        return Err(Errno(EINVAL));
+    }
+    // The FSInfo sector keeps a count of free clusters, which we'd get
+    // wrong: say it's unknown, and the next system to mount it counts.
+    let fsinfo = u16_at(&bs, 48) as u64 * sector_size;
+    if !read_only && fsinfo != 0 {
+        let mut info = [0; 512];
+        fs.read_at(fsinfo, &mut info)?;
+        if u32_at(&info, 0) == FSINFO_LEAD && u32_at(&info, 484) == FSINFO_STRUCT {
+            fs.write_at(fsinfo + FSINFO_FREE as u64, &u32::MAX.to_le_bytes())?;
+            fs.next_free = u32_at(&info, FSINFO_FREE + 4);
+        }
+    }
+    filesystems().push(fs);
+    Ok(Rc::new(Node {
+        fs: filesystems().len() - 1,
+        pos: None,
+        open: false,
+        ra: Readahead::new(),
+    }))
+}
+
+/// Mount every disk that has a FAT32 filesystem.
+pub fn init() {
+    vfs::mount_disks("vfat", mount, false);
+}
+
+/// A file of a FAT filesystem, both as an inode and, if `open`, opened:
+/// where its directory entry is, None for the root.
+struct Node {
+    fs: usize,
+    pos: Option<u64>,
+    open: bool,
+    ra: Readahead,
+}
+
+impl Node {
+    fn fs(&self) -> &'static mut Fs {
+        &mut filesystems()[self.fs]
+    }
+
+    /// The directory entry. The root has none, so it gets one made up.
+    fn entry(&self) -> Result<[u8; ENTRY_SIZE], SysError> {
+        let mut e = [0; ENTRY_SIZE];
+        match self.pos {
+            Some(pos) => self.fs().read_at(pos, &mut e)?,
+            None => {
+                e[11] = ATTR_DIRECTORY;
+                set_cluster(&mut e, self.fs().root);
+            }
+        }
+        Ok(e)
+    }
+
+    fn set_entry(&self, e: &[u8; ENTRY_SIZE]) -> Result<(), SysError> {
+        match self.pos {
+            Some(pos) => self.fs().write_at(pos, e),
+            None => Ok(()),
+        }
+    }
+
+    /// The first cluster of the directory, which this has to be.
+    fn dir(&self) -> Result<u32, SysError> {
+        let e = self.entry()?;
+        if e[11] & ATTR_DIRECTORY == 0 {
+            return Err(Errno(ENOTDIR));
+        }
+        Ok(cluster_of(&e))
+    }
+
+    fn node(&self, pos: u64) -> Rc<Node> {
+        Rc::new(Node {
+            fs: self.fs,
+            pos: Some(pos),
+            open: false,
+            ra: Readahead::new(),
+        })
+    }
+
+    /// The file whose entry is at `pos`, or the root, opened.
+    fn opened(&self, pos: Option<u64>) -> Rc<Node> {
+        if let Some(pos) = pos {
+            *self.fs().open.entry(pos).or_insert(0) += 1;
+        }
+        Rc::new(Node {
+            fs: self.fs,
+            pos,
+            open: true,
+            ra: Readahead::new(),
+        })
+    }
+
+    /// Write `buf` at `offset` in the file, whose clusters are `chain`.
+    fn write_chain(&self, chain: &[u32], offset: usize, buf: &[u8]) -> Result<(), SysError> {
+        let fs = self.fs();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let start = pos % fs.cluster_size;
+            let len = (fs.cluster_size - start).min(buf.len() - done);
+            let c = chain[pos / fs.cluster_size];
+            fs.write_at(fs.cluster_pos(c) + start as u64, &buf[done..done + len])?;
+            done += len;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let (true, Some(pos)) = (self.open, self.pos) else {
+            return;
+        };
+        let open = &mut self.fs().open;
+        let count = open.get_mut(&pos).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            open.remove(&pos);
+        }
+    }
+}
+
+impl Inode for Node {
+    fn lookup(&self, name: &[u8]) -> Result<Rc<dyn Inode>, SysError> {
+        let e = self.fs().find(self.dir()?, name)?;
+        Ok(self.node(e.pos))
+    }
+
+    fn create(&self, name: &[u8], _flags: usize, mode: usize) -> Result<Rc<dyn File>, SysError> {
+        let attr = if mode & 0o222 == 0 {
+            ATTR_ARCHIVE | ATTR_READ_ONLY
+        } else {
+            ATTR_ARCHIVE
+        };
+        let pos = self.fs().add_entry(self.dir()?, name, attr, 0)?;
+        Ok(self.opened(Some(pos)))
+    }
+
+    /// A new directory has entries for . and .., whose cluster is 0 if
+    /// it's the root. There's nowhere to keep its mode.
+    fn mkdir(&self, name: &[u8], _mode: u32) -> Result<Rc<dyn Inode>, SysError> {
+        let fs = self.fs();
+        let parent = self.dir()?;
+        if fs.read_only {
+            return Err(Errno(EROFS));
+        }
+        let c = fs.alloc_cluster(None)?;
+        let up = if self.pos.is_none() { 0 } else { parent };
+        let mut dots = [0; 2 * ENTRY_SIZE];
+        dots[..ENTRY_SIZE].copy_from_slice(&new_entry(b".          ", ATTR_DIRECTORY, c));
+        dots[ENTRY_SIZE..].copy_from_slice(&new_entry(b"..         ", ATTR_DIRECTORY, up));
+        fs.write_at(fs.cluster_pos(c), &dots)?;
+        match fs.add_entry(parent, name, ATTR_DIRECTORY, c) {
+            Ok(pos) => Ok(self.node(pos)),
+            Err(e) => {
+                let _ = fs.free_chain(c);
+                Err(e)
+            }
+        }
+    }
+
+    fn unlink(&self, name: &[u8], dir: bool) -> Result<(), SysError> {
+        let fs = self.fs();
+        let parent = self.dir()?;
+        if fs.read_only {
+            return Err(Errno(EROFS));
+        }
+        let e = fs.victim(parent, name, dir)?;
+        fs.remove_entry(parent, &e)?;
+        fs.free_chain(cluster_of(&e.raw))
+    }
+
+    /// The entry gets the new name, keeping its attributes, times, cluster
+    /// and size. A directory that moves to another directory has its ..
+    /// changed to that one.
+    fn rename(&self, old: &[u8], dir: &dyn Inode, new: &[u8]) -> Result<(), SysError> {
+        let to = vfs::downcast::<Node>(dir)?;
+        if to.fs != self.fs {
+            return Err(Errno(EXDEV));
+        }
+        let fs = self.fs();
+        let (from_dir, to_dir) = (self.dir()?, to.dir()?);
+        if fs.read_only {
+            return Err(Errno(EROFS));
+        }
+        let e = fs.find(from_dir, old)?;
+        let is_dir = e.raw[11] & ATTR_DIRECTORY != 0;
+        if fs.open.contains_key(&e.pos) {
+            return Err(Errno(EBUSY));
+        }
+        match fs.victim(to_dir, new, is_dir) {
+            Ok(there) if there.pos == e.pos => return Ok(()),
+            Ok(there) => {
+                fs.remove_entry(to_dir, &there)?;
+                fs.free_chain(cluster_of(&there.raw))?;
+            }
+            Err(Errno(ENOENT)) => {}
+            Err(err) => return Err(err),
+        }
+        let pos = fs.add_entry(to_dir, new, 0, 0)?;
+        let mut moved = [0; ENTRY_SIZE];
+        fs.read_at(pos, &mut moved)?;
+        moved[11] = e.raw[11];
+        moved[13..].copy_from_slice(&e.raw[13..]);
+        fs.write_at(pos, &moved)?;
+        fs.remove_entry(from_dir, &e)?;
+        if is_dir && from_dir != to_dir {
+            let mut dots = [0; ENTRY_SIZE];
+            let at = fs.cluster_pos(cluster_of(&e.raw)) + ENTRY_SIZE as u64;
+            fs.read_at(at, &mut dots)?;
+            set_cluster(&mut dots, if to.pos.is_none() { 0 } else { to_dir });
+            fs.write_at(at, &dots)?;
+        }
+        Ok(())
+    }
+
+    fn open(&self, flags: usize) -> Result<Rc<dyn File>, SysError> {
+        let mut e = self.entry()?;
+        if flags & O_ACCMODE != O_RDONLY {
+            if e[11] & ATTR_DIRECTORY != 0 {
+                return Err(Errno(EISDIR));
+            }
+            if self.fs().read_only || e[11] & ATTR_READ_ONLY != 0 {
+                return Err(Errno(EROFS));
+            }
+            if flags & O_TRUNC != 0 && cluster_of(&e) != 0 {
+                self.fs().free_chain(cluster_of(&e))?;
+                set_cluster(&mut e, 0);
+                e[28..32].fill(0);
+                let (date, time) = fat_now();
+                e[22..24].copy_from_slice(&time.to_le_bytes());
+                e[24..26].copy_from_slice(&date.to_le_bytes());
+                self.set_entry(&e)?;
+            }
+        }
+        Ok(self.opened(self.pos))
+    }
+
+    fn metadata(&self) -> Stat {
+        let Ok(e) = self.entry() else {
+            return Stat::default();
+        };
+        let fs = self.fs();
+        let (mode, size) = if e[11] & ATTR_DIRECTORY != 0 {
+            let clusters = fs.chain(cluster_of(&e)).map_or(0, |c| c.len());
+            (S_IFDIR | 0o755, clusters * fs.cluster_size)
+        } else {
+            (S_IFREG | 0o644, u32_at(&e, 28) as usize)
+        };
+        let mode = if e[11] & ATTR_READ_ONLY != 0 {
+            mode & !0o222
+        } else {
+            mode
+        };
+        Stat {
+            st_dev: block::get(fs.dev).unwrap().rdev(),
+            st_ino: self.pos.map_or(ROOT_INO, |pos| pos / ENTRY_SIZE as u64),
+            st_mode: mode,
+            st_nlink: 1,
+            st_size: size as i64,
+            st_blksize: fs.cluster_size as i32,
+            st_blocks: (size.div_ceil(fs.cluster_size) * fs.cluster_size / 512) as i64,
+            st_atime: fat_to_unix(u16_at(&e, 18), 0),
+            st_mtime: fat_to_unix(u16_at(&e, 24), u16_at(&e, 22)),
+            st_ctime: fat_to_unix(u16_at(&e, 16), u16_at(&e, 14)),
+            ..Default::default()
+        }
+    }
+}
+
+impl File for Node {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let fs = self.fs();
+        let e = self.entry()?;
+        if e[11] & ATTR_DIRECTORY != 0 {
+            return Err(Errno(EISDIR));
+        }
+        let n = buf
+            .len()
+            .min((u32_at(&e, 28) as usize).saturating_sub(offset));
+        if n == 0 {
+            return Ok(0);
+        }
+        let ahead = self.ra.window(offset, n);
+        let size = u32_at(&e, 28) as usize;
+        // Reading can be restarted, what's read is cached by then.
+        block::with_sleep(true, || {
+            let chain = fs.chain(cluster_of(&e))?;
+            fs.readahead(&chain, offset, (n + ahead).min(size - offset));
+            let mut done = 0;
+            while done < n {
+                let pos = offset + done;
+                let start = pos % fs.cluster_size;
+                let len = (fs.cluster_size - start).min(n - done);
+                let c = *chain.get(pos / fs.cluster_size).ok_or(Errno(EIO))?;
+                fs.read_at(fs.cluster_pos(c) + start as u64, &mut buf[done..done + len])?;
+                done += len;
+            }
+            Ok(n)
+        })
+    }
+
+    /// The file gets the clusters it needs first, as many as there are if
+    /// the disk fills up, which are zeros until written. So is what's
+    /// between its old end and `offset`. A file can't be bigger than 4 GiB,
+    /// which is checked before anything else.
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        let want = offset
+            .checked_add(buf.len())
+            .filter(|&end| end <= u32::MAX as usize)
+            .ok_or(Errno(EFBIG))?;
+        let fs = self.fs();
+        let mut e = self.entry()?;
+        let size = u32_at(&e, 28) as usize;
+        let mut chain = fs.chain(cluster_of(&e))?;
+        let need = want.div_ceil(fs.cluster_size);
+        while chain.len() < need {
+            match fs.alloc_cluster(chain.last().copied()) {
+                Ok(c) => {
+                    if chain.is_empty() {
+                        set_cluster(&mut e, c);
+                    }
+                    chain.push(c);
+                }
+                Err(Errno(ENOSPC)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        let end = want.min(chain.len() * fs.cluster_size);
+        if end <= offset && !buf.is_empty() {
+            self.set_entry(&e)?;
+            return Err(Errno(ENOSPC));
+        }
+        let n = end.saturating_sub(offset);
+        if offset > size {
+            // The old last cluster may have anything after the old end.
+            let gap = vec![0; (offset - size).min(fs.cluster_size)];
+            self.write_chain(&chain, size, &gap)?;
+        }
+        self.write_chain(&chain, offset, &buf[..n])?;
+        e[28..32].copy_from_slice(&(size.max(offset + n) as u32).to_le_bytes());
+        e[11] |= ATTR_ARCHIVE;
+        let (date, time) = fat_now();
+        e[22..24].copy_from_slice(&time.to_le_bytes());
+        e[24..26].copy_from_slice(&date.to_le_bytes());
+        self.set_entry(&e)?;
+        Ok(n)
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.metadata().st_size as usize)
+    }
+
+    fn stat(&self) -> Stat {
+        self.metadata()
+    }
+
+    fn wait(&self, pid: usize) {
+        block::wait(self.fs().dev, pid);
+    }
+
+    /// All of the disk, see bcache::sync().
+    fn sync(&self) -> Result<(), SysError> {
+        block::with_sleep(true, || bcache::sync(Some(self.fs().dev)))
+    }
+
+    /// Offsets count entry slots of the directory.
+    fn getdents(&self, offset: usize, buf: &mut [u8]) -> Result<(usize, usize), SysError> {
+        let fs = self.fs();
+        let entries = fs.entries(self.dir()?, offset)?;
+        let (mut len, mut next) = (0, offset);
+        for e in &entries {
+            let kind = if e.raw[11] & ATTR_DIRECTORY != 0 {
+                S_IFDIR
+            } else {
+                S_IFREG
+            };
+            let ino = e.pos / ENTRY_SIZE as u64;
+            let after = e.index as u64 + 1;
+            let kind = vfs::dirent_type(kind);
+            let Some(n) = vfs::put_dirent(&mut buf[len..], ino, after, kind, &e.name) else {
+                break;
+            };
+            len += n;
+            next = e.index + 1;
+        }
+        if len == 0 && !entries.is_empty() {
+            // Not even one entry fits.
+            return Err(Errno(EINVAL));
+        }
+        Ok((len, next))
+    }
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest::check, testing::shown};
+
+    #[test_case]
+    fn lfn_checksum_test() -> Result<(), String> {
+        for (short, want) in [(b"README  TXT", 0x73), (b"MYDOCU~1DOC", 0x9b)] {
+            let sum = lfn_checksum(short);
+            check(sum == want, || {
+                format!("{} sums to {:#x}", shown(short), sum)
+            })?;
+        }
+        Ok(())
+    }
+
+    #[test_case]
+    fn short_name_test() -> Result<(), String> {
+        let short = as_short(b"README.TXT");
+        check(short == Some(*b"README  TXT"), || {
+            "README.TXT isn't 8.3".into()
+        })?;
+        for name in [&b"readme.txt"[..], b"TOOLONGNAME.TXT", b"A.B.C", b".TXT"] {
+            check(as_short(name).is_none(), || {
+                format!("{} is 8.3", shown(name))
+            })?;
+        }
+        // With the base in lower case, which is how Windows keeps readme.TXT.
+        let mut e = [0u8; ENTRY_SIZE];
+        e[..11].copy_from_slice(b"README  TXT");
+        e[12] = LOWER_BASE;
+        let name = short_name(&e);
+        check(name == b"readme.TXT", || {
+            format!("the entry is {}", shown(&name))
+        })?;
+        e[..11].copy_from_slice(b"\x05BC        ");
+        let name = short_name(&e);
+        check(name == b"\xe5bc", || "a leading 0x05 isn't 0xe5".into())
+    }
+
+    #[test_case]
+    fn make_alias_test() -> Result<(), String> {
+        let cases: [(&[u8], &[u8; 11]); 5] = [
+            (b"My Document.docx", b"MYDOCU~1DOC"),
+            (b".bashrc", b"BASHRC~1   "),
+            (b"a+b.c", b"A_B~1   C  "),
+            (b"...", b"_~1        "),
+            (b"naive.tar.gz", b"NAIVET~1GZ "),
+        ];
+        for (name, want) in cases {
+            let alias = make_alias(name, &[]).map_err(|_| "no alias")?;
+            check(&alias == want, || {
+                format!("{}'s alias is {}", shown(name), shown(&alias))
+            })?;
+        }
+        // The ones taken are skipped, and a longer number takes more room.
+        let mut taken = vec![*b"MYDOCU~1DOC"];
+        let alias = make_alias(b"My Document.docx", &taken).map_err(|_| "no alias")?;
+        check(&alias == b"MYDOCU~2DOC", || {
+            format!("the second is {}", shown(&alias))
+        })?;
+        for n in 2..10 {
+            taken.push(*format!("MYDOCU~{}DOC", n).as_bytes().first_chunk().unwrap());
+        }
+        let alias = make_alias(b"My Document.docx", &taken).map_err(|_| "no alias")?;
+        check(&alias == b"MYDOC~10DOC", || {
+            format!("the tenth is {}", shown(&alias))
+        })
+    }
+
+    /// A write that would make the file bigger than 4 GiB, or go past the
+    /// end of the address space, is EFBIG before the filesystem is looked
+    /// at, so the node here has none.
+    #[test_case]
+    fn write_bounds_test() -> Result<(), String> {
+        let node = Node {
+            fs: usize::MAX,
+            pos: None,
+            open: false,
+            ra: Readahead::new(),
+        };
+        for offset in [u32::MAX as usize, usize::MAX] {
+            let ret = node.write(offset, b"x");
+            check(matches!(ret, Err(Errno(EFBIG))), || {
+                format!("a byte at {} isn't EFBIG", offset)
+            })?;
+        }
+        Ok(())
+    }
+}
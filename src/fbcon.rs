@@ -0,0 +1,449 @@
+// The framebuffer console: a terminal drawn on the display, so that what
+// the kernel prints and what's written to the console shows up in QEMU's
+// window as well as on the UART. Characters are drawn with a bitmap font in
+// PSF2 format (the one Linux's console uses), which is built into the
+// kernel: font.psf, DejaVu Sans Mono Bold rasterized to 8x16 for Latin-1.
+// The terminal understands enough of the ANSI escape sequences (see
+// ansi.rs) for colored text, clearing the screen or a line, inserting and
+// deleting characters, and moving the cursor around. What
+// was printed before the display was set up is kept and drawn once it is.
+
+use crate::{
+    ansi::{Csi, Parser, Sequence},
+    console::RingBuffer,
+    gpu::{self, Framebuffer, Rect},
+    pointer,
+};
+use alloc::{vec, vec::Vec};
+use core::ptr::addr_of_mut;
+
+static FONT: &[u8] = include_bytes!("font.psf");
+const PSF2_MAGIC: u32 = 0x864a_b572;
+
+/// How much of the kernel log we keep for until there's a display.
+const EARLY_LOG_SIZE: usize = 8192;
+
+/// The 16 colors of the VGA text mode, as 0xRRGGBB: the 8 normal ones, then
+/// their bright versions.
+const PALETTE: [u32; 16] = [
+    0x000000, 0xaa0000, 0x00aa00, 0xaa5500, 0x0000aa, 0xaa00aa, 0x00aaaa, 0xaaaaaa, 0x555555,
+    0xff5555, 0x55ff55, 0xffff55, 0x5555ff, 0xff55ff, 0x55ffff, 0xffffff,
+];
+const DEFAULT_FG: u8 = 7;
+const DEFAULT_BG: u8 = 0;
+
+struct Font {
+    glyphs: &'static [u8],
+    count: usize,
+    bytes_per_glyph: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Font {
+    /// Read the header of a PSF2 font.
+    fn parse(psf: &'static [u8]) -> Option<Font> {
+        let field = |i: usize| {
+            let bytes = psf.get(i * 4..i * 4 + 4)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+        if field(0)? != PSF2_MAGIC as usize {
+            return None;
+        }
+        let (header_size, count, bytes_per_glyph) = (field(2)?, field(4)?, field(5)?);
+        let (height, width) = (field(6)?, field(7)?);
+        let glyphs = psf.get(header_size..header_size + count * bytes_per_glyph)?;
+        if width == 0 || height == 0 || bytes_per_glyph < width.div_ceil(8) * height {
+            return None;
+        }
+        Some(Font {
+            glyphs,
+            count,
+            bytes_per_glyph,
+            width,
+            height,
+        })
+    }
+
+    /// The bitmap of character `c`: `height` rows, each a whole number of
+    /// bytes with the leftmost pixel in the top bit. Glyph 0 stands in for
+    /// what the font doesn't have.
+    fn glyph(&self, c: u32) -> &'static [u8] {
+        let i = if (c as usize) < self.count {
+            c as usize
+        } else {
+            0
+        };
+        &self.glyphs[i * self.bytes_per_glyph..(i + 1) * self.bytes_per_glyph]
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Cell {
+    c: u32,
+    fg: u8,
+    bg: u8,
+}
+
+const BLANK: Cell = Cell {
+    c: b' ' as u32,
+    fg: DEFAULT_FG,
+    bg: DEFAULT_BG,
+};
+
+struct Console {
+    font: Font,
+    cols: usize,
+    rows: usize,
+    // What's on the screen, to redraw a cell the cursor leaves.
+    cells: Vec<Cell>,
+    x: usize,
+    y: usize,
+    fg: u8,
+    bg: u8,
+    bold: bool,
+    cursor_visible: bool,
+    parser: Parser,
+    // The character being decoded from UTF-8, and how many more bytes of
+    // it are to come.
+    utf8: u32,
+    utf8_left: u32,
+    // Where a cell's pixels are put together before they're drawn.
+    pixels: Vec<u32>,
+}
+
+static mut CONSOLE: Option<Console> = None;
+static mut EARLY_LOG: RingBuffer<EARLY_LOG_SIZE> = RingBuffer::new();
+// Set while we're drawing. Whatever is printed meanwhile (by the display
+// driver, say) is dropped, drawing it would get in the way.
+static mut BUSY: bool = false;
+
+fn console() -> Option<&'static mut Console> {
+    unsafe { (*addr_of_mut!(CONSOLE)).as_mut() }
+}
+
+fn fb() -> &'static mut Framebuffer {
+    gpu::framebuffer().unwrap()
+}
+
+/// A color of the palette as a pixel of the framebuffer.
+fn pixel(fb: &Framebuffer, color: u8) -> u32 {
+    fb.rgb(PALETTE[color as usize & 15])
+}
+
+impl Console {
+    fn new(font: Font, fb: &Framebuffer) -> Console {
+        let cols = fb.width as usize / font.width;
+        let rows = fb.height as usize / font.height;
+        let pixels = vec![0; font.width * font.height];
+        Console {
+            font,
+            cols,
+            rows,
+            cells: vec![BLANK; cols * rows],
+            x: 0,
+            y: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            bold: false,
+            cursor_visible: true,
+            parser: Parser::output(),
+            utf8: 0,
+            utf8_left: 0,
+            pixels,
+        }
+    }
+
+    /// Draw the cell at `x`, `y`, with its colors swapped for the cursor.
+    fn draw(&mut self, x: usize, y: usize, inverted: bool) {
+        let cell = self.cells[y * self.cols + x];
+        let fb = fb();
+        let (mut fg, mut bg) = (pixel(fb, cell.fg), pixel(fb, cell.bg));
+        if inverted {
+            (fg, bg) = (bg, fg);
+        }
+        let (width, height) = (self.font.width, self.font.height);
+        let glyph = self.font.glyph(cell.c);
+        let row_bytes = width.div_ceil(8);
+        for row in 0..height {
+            let bits = &glyph[row * row_bytes..(row + 1) * row_bytes];
+            for col in 0..width {
+                let on = bits[col / 8] & (0x80 >> (col % 8)) != 0;
+                self.pixels[row * width + col] = if on { fg } else { bg };
+            }
+        }
+        fb.blit(
+            (x * width) as u32,
+            (y * height) as u32,
+            width as u32,
+            &self.pixels,
+        );
+    }
+
+    fn draw_cursor(&mut self, shown: bool) {
+        if self.cursor_visible && self.x < self.cols {
+            self.draw(self.x, self.y, shown);
+        }
+    }
+
+    /// Blank the cells from `from` to `to` (not included), counting cells
+    /// across the rows.
+    fn erase(&mut self, from: usize, to: usize) {
+        let blank = Cell {
+            bg: self.bg,
+            ..BLANK
+        };
+        self.cells[from..to].fill(blank);
+        let fb = fb();
+        let color = pixel(fb, self.bg);
+        let (width, height) = (self.font.width, self.font.height);
+        // A row at a time, what of it is in the range.
+        let mut i = from;
+        while i < to {
+            let end = to.min((i / self.cols + 1) * self.cols);
+            let rect = Rect {
+                x: (i % self.cols * width) as u32,
+                y: (i / self.cols * height) as u32,
+                width: ((end - i) * width) as u32,
+                height: height as u32,
+            };
+            fb.fill_rect(rect, color);
+            i = end;
+        }
+    }
+
+    /// Move the cells of the cursor's line from column `from` on to column
+    /// `to`, as many as fit, and blank the ones that are left behind. This
+    /// inserts or deletes characters.
+    fn shift_line(&mut self, from: usize, to: usize) {
+        let line = self.y * self.cols;
+        let len = self.cols - from.max(to);
+        self.cells
+            .copy_within(line + from..line + from + len, line + to);
+        let (width, height) = (self.font.width, self.font.height);
+        let y = (self.y * height) as u32;
+        let src = Rect {
+            x: (from * width) as u32,
+            y,
+            width: (len * width) as u32,
+            height: height as u32,
+        };
+        fb().copy_region(src, (to * width) as u32, y);
+        if to > from {
+            self.erase(line + from, line + to);
+        } else {
+            self.erase(line + to + len, line + self.cols);
+        }
+    }
+
+    /// Move everything up a row and blank the bottom one.
+    fn scroll(&mut self) {
+        let fb = fb();
+        let color = pixel(fb, self.bg);
+        fb.scroll_up(self.font.height as u32, color);
+        self.cells.copy_within(self.cols.., 0);
+        let last = (self.rows - 1) * self.cols;
+        self.erase(last, last + self.cols);
+    }
+
+    fn newline(&mut self) {
+        self.x = 0;
+        if self.y + 1 == self.rows {
+            self.scroll();
+        } else {
+            self.y += 1;
+        }
+    }
+
+    /// Put a character where the cursor is and move it on, to the next
+    /// line at the end of one.
+    fn put(&mut self, c: u32) {
+        if self.x == self.cols {
+            self.newline();
+        }
+        let fg = if self.bold && self.fg < 8 {
+            self.fg + 8
+        } else {
+            self.fg
+        };
+        self.cells[self.y * self.cols + self.x] = Cell { c, fg, bg: self.bg };
+        self.draw(self.x, self.y, false);
+        self.x += 1;
+    }
+
+    /// Select Graphic Rendition: colors and boldness.
+    fn sgr(&mut self, csi: &Csi) {
+        for i in 0..csi.nparams.max(1) {
+            match csi.params[i] {
+                0 => {
+                    (self.fg, self.bg, self.bold) = (DEFAULT_FG, DEFAULT_BG, false);
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                p @ 30..=37 => self.fg = (p - 30) as u8,
+                39 => self.fg = DEFAULT_FG,
+                p @ 40..=47 => self.bg = (p - 40) as u8,
+                49 => self.bg = DEFAULT_BG,
+                p @ 90..=97 => self.fg = (p - 90) as u8 + 8,
+                p @ 100..=107 => self.bg = (p - 100) as u8 + 8,
+                _ => {}
+            }
+        }
+    }
+
+    /// Carry out the control sequence `csi`.
+    fn csi(&mut self, csi: &Csi) {
+        let cursor = self.y * self.cols + self.x.min(self.cols - 1);
+        let end = self.rows * self.cols;
+        let n = csi.param(0, 1) as usize;
+        let c = csi.final_byte;
+        match c {
+            b'A' => self.y = self.y.saturating_sub(n),
+            b'B' => self.y = (self.y + n).min(self.rows - 1),
+            b'C' => self.x = (self.x + n).min(self.cols - 1),
+            b'D' => self.x = self.x.min(self.cols - 1).saturating_sub(n),
+            b'H' | b'f' => {
+                self.y = (csi.param(0, 1) as usize - 1).min(self.rows - 1);
+                self.x = (csi.param(1, 1) as usize - 1).min(self.cols - 1);
+            }
+            // Erase in display: to the end, to the cursor, or all of it.
+            b'J' => match csi.param(0, 0) {
+                0 => self.erase(cursor, end),
+                1 => self.erase(0, cursor + 1),
+                _ => self.erase(0, end),
+            },
+            // Erase in line, the same way.
+            b'K' => {
+                let line = self.y * self.cols;
+                match csi.param(0, 0) {
+                    0 => self.erase(cursor, line + self.cols),
+                    1 => self.erase(line, cursor + 1),
+                    _ => self.erase(line, line + self.cols),
+                }
+            }
+            // Insert and delete characters, moving the rest of the line.
+            b'@' | b'P' => {
+                let x = self.x.min(self.cols - 1);
+                let n = n.min(self.cols - x);
+                if c == b'@' {
+                    self.shift_line(x, x + n);
+                } else {
+                    self.shift_line(x + n, x);
+                }
+            }
+            b'm' => self.sgr(csi),
+            // Show and hide the cursor.
+            b'h' | b'l' if csi.private == Some(b'?') && csi.param(0, 0) == 25 => {
+                self.cursor_visible = c == b'h';
+            }
+            _ => {}
+        }
+    }
+
+    /// Take a byte of output.
+    fn byte(&mut self, b: u8) {
+        let b = match self.parser.feed(b) {
+            Some(Sequence::Byte(b)) => b,
+            Some(Sequence::Csi(csi)) => return self.csi(&csi),
+            // Nothing else is drawn.
+            _ => return,
+        };
+        // UTF-8: what comes after the first byte of a character.
+        if self.utf8_left > 0 && b & 0xc0 == 0x80 {
+            self.utf8 = self.utf8 << 6 | (b & 0x3f) as u32;
+            self.utf8_left -= 1;
+            if self.utf8_left == 0 {
+                self.put(self.utf8);
+            }
+            return;
+        }
+        self.utf8_left = 0;
+        match b {
+            b'\r' => self.x = 0,
+            // Nobody turns \n into \r\n for us (there's no output
+            // processing), so a line feed goes back to the start of the
+            // line as well.
+            b'\n' => self.newline(),
+            8 => self.x = self.x.min(self.cols - 1).saturating_sub(1),
+            b'\t' => {
+                let next = (self.x / 8 + 1) * 8;
+                while self.x < next.min(self.cols) {
+                    self.put(b' ' as u32);
+                }
+            }
+            0xc0..=0xdf => (self.utf8, self.utf8_left) = ((b & 0x1f) as u32, 1),
+            0xe0..=0xef => (self.utf8, self.utf8_left) = ((b & 0x0f) as u32, 2),
+            0xf0..=0xf7 => (self.utf8, self.utf8_left) = ((b & 0x07) as u32, 3),
+            0x20..=0x7e => self.put(b as u32),
+            // Other control characters, and bytes that aren't UTF-8.
+            _ => {}
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        pointer::hide();
+        self.draw_cursor(false);
+        for &b in buf {
+            self.byte(b);
+        }
+        self.draw_cursor(true);
+        pointer::show();
+        gpu::present();
+    }
+}
+
+/// Start the console, if there's a display, and draw what was printed
+/// until now.
+pub fn init() {
+    let Some(fb) = gpu::framebuffer() else {
+        return;
+    };
+    let Some(font) = Font::parse(FONT) else {
+        println!("fbcon: the font is broken");
+        return;
+    };
+    // Only 32-bit pixels, and room for at least a character.
+    if fb.format.bytes_per_pixel != 4
+        || (fb.width as usize) < font.width
+        || (fb.height as usize) < font.height
+    {
+        return;
+    }
+    let mut con = Console::new(font, fb);
+    println!(
+        "fbcon: {}x{} characters of {}x{}",
+        con.cols, con.rows, con.font.width, con.font.height
+    );
+    con.erase(0, con.cols * con.rows);
+    let early = unsafe { &mut *addr_of_mut!(EARLY_LOG) };
+    let mut buf = Vec::with_capacity(early.len());
+    while let Some(c) = early.pop() {
+        buf.push(c);
+    }
+    unsafe {
+        *addr_of_mut!(CONSOLE) = Some(con);
+    }
+    write(&buf);
+}
+
+/// Show output on the screen. Until there is one, it's kept for later.
+pub fn write(buf: &[u8]) {
+    unsafe {
+        if BUSY {
+            return;
+        }
+        BUSY = true;
+    }
+    match console() {
+        Some(con) => con.write(buf),
+        None => {
+            let early = unsafe { &mut *addr_of_mut!(EARLY_LOG) };
+            for &c in buf {
+                early.push(c);
+            }
+        }
+    }
+    unsafe {
+        BUSY = false;
+    }
+}
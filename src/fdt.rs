@@ -0,0 +1,546 @@
+// The flattened device tree (FDT). Whoever starts the kernel (QEMU itself,
+// with -bios none) passes the address of one in a1: a description of the
+// machine's memory and devices. The blob is a header, a structure block of
+// tokens that describes the tree of nodes and their properties, and a block
+// of strings that property names point into. Everything in it is big
+// endian.
+
+use alloc::vec::Vec;
+use core::ptr::addr_of_mut;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+// Tokens of the structure block
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+/// How deep nodes can be nested.
+const MAX_DEPTH: usize = 16;
+
+fn be32(b: &[u8], offset: usize) -> Option<u32> {
+    let bytes = b.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Read a number made of `cells` 32-bit cells.
+fn cells(b: &[u8], cells: u32) -> Option<usize> {
+    let mut val: u64 = 0;
+    for i in 0..cells as usize {
+        val = val << 32 | be32(b, i * 4)? as u64;
+    }
+    Some(val as usize)
+}
+
+/// The NUL terminated string at the start of `b`.
+fn c_str(b: &[u8]) -> Option<&str> {
+    let len = b.iter().position(|&c| c == 0)?;
+    core::str::from_utf8(&b[..len]).ok()
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt {
+    structure: &'static [u8],
+    strings: &'static [u8],
+}
+
+static mut FDT: Option<Fdt> = None;
+
+/// Take a copy of the device tree at `addr`, if there is a valid one
+/// there. The memory it is in isn't reserved, so the page allocator could
+/// hand it out at any time. The heap must be up. Returns false if there is
+/// no device tree.
+pub fn init(addr: usize) -> bool {
+    if addr == 0 || !addr.is_multiple_of(8) {
+        return false;
+    }
+    let header = unsafe { core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE) };
+    if be32(header, 0) != Some(FDT_MAGIC) {
+        return false;
+    }
+    let size = be32(header, 4).unwrap() as usize;
+    let blob = unsafe { core::slice::from_raw_parts(addr as *const u8, size) };
+    let Some(fdt) = parse(Vec::from(blob).leak()) else {
+        return false;
+    };
+    unsafe {
+        *addr_of_mut!(FDT) = Some(fdt);
+    }
+    true
+}
+
+/// The device tree in `blob`, if the blocks its header says it has are
+/// in it.
+fn parse(blob: &'static [u8]) -> Option<Fdt> {
+    let block = |offset, size| {
+        let start = be32(blob, offset)? as usize;
+        let len = be32(blob, size)? as usize;
+        blob.get(start..start + len)
+    };
+    Some(Fdt {
+        structure: block(8, 36)?,
+        strings: block(12, 32)?,
+    })
+}
+
+/// The device tree we booted with, if there was one.
+pub fn get() -> Option<Fdt> {
+    unsafe { *addr_of_mut!(FDT) }
+}
+
+impl Fdt {
+    /// Every node, parents before their children.
+    pub fn nodes(&self) -> Nodes {
+        Nodes {
+            fdt: *self,
+            pos: 0,
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH],
+        }
+    }
+
+    /// The nodes that are compatible with `compat`.
+    pub fn compatible<'a>(&self, compat: &'a str) -> impl Iterator<Item = Node> + 'a {
+        self.nodes().filter(move |n| n.is_compatible(compat))
+    }
+
+    /// The nodes right below `parent`.
+    pub fn children(&self, parent: Node) -> impl Iterator<Item = Node> {
+        self.nodes()
+            .skip_while(move |n| n.props.as_ptr() != parent.props.as_ptr())
+            .skip(1)
+            .take_while(move |n| n.depth > parent.depth)
+            .filter(move |n| n.depth == parent.depth + 1)
+    }
+
+    /// Find a node by its path, like "/soc/serial@10000000". The unit
+    /// address (after the @) can be left out, or an alias from /aliases
+    /// used instead of a path.
+    pub fn find(&self, path: &str) -> Option<Node> {
+        if !path.starts_with('/') {
+            let aliases = self.find("/aliases")?;
+            return self.find(aliases.str_property(path)?);
+        }
+        let parts: Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+        // How many parts of the path the branch we're in matches.
+        let mut matched = 0;
+        for node in self.nodes() {
+            if node.depth == 0 {
+                if parts.is_empty() {
+                    return Some(node);
+                }
+                continue;
+            }
+            if node.depth > matched + 1 {
+                // Inside a node that isn't on the path.
+                continue;
+            }
+            matched = node.depth - 1;
+            let part = parts[matched];
+            let name = if part.contains('@') {
+                node.name
+            } else {
+                node.name.split('@').next().unwrap()
+            };
+            if name == part {
+                matched += 1;
+                if matched == parts.len() {
+                    return Some(node);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// A node of the device tree.
+#[derive(Clone, Copy)]
+pub struct Node {
+    pub name: &'static str,
+    pub depth: usize,
+    // The structure block from the node's first property on.
+    props: &'static [u8],
+    strings: &'static [u8],
+    // The #address-cells and #size-cells of the parent, which is what the
+    // node's reg is made of.
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl Node {
+    /// The names and values of the node's properties.
+    pub fn properties(&self) -> Properties {
+        Properties {
+            node: *self,
+            pos: 0,
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'static [u8]> {
+        self.properties().find(|&(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    pub fn u32_property(&self, name: &str) -> Option<u32> {
+        be32(self.property(name)?, 0)
+    }
+
+    /// A property that's a number of one cell or two, like the
+    /// linux,initrd-start of /chosen.
+    pub fn num_property(&self, name: &str) -> Option<usize> {
+        let p = self.property(name)?;
+        cells(p, (p.len() / 4).min(2) as u32)
+    }
+
+    pub fn str_property(&self, name: &str) -> Option<&'static str> {
+        c_str(self.property(name)?)
+    }
+
+    /// The strings of the node's compatible list, most specific first.
+    pub fn compatibles(&self) -> impl Iterator<Item = &'static str> {
+        self.property("compatible")
+            .unwrap_or(&[])
+            .split(|&c| c == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    /// Is `compat` one of the strings in the node's compatible list?
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.compatibles().any(|c| c == compat)
+    }
+
+    /// The node's interrupts. All the interrupt controllers we know use
+    /// one cell per interrupt.
+    pub fn interrupts(&self) -> impl Iterator<Item = u32> {
+        let list = self.property("interrupts").unwrap_or(&[]);
+        (0..list.len() / 4).filter_map(move |i| be32(list, i * 4))
+    }
+
+    /// The address and size of the node's first register block.
+    pub fn reg(&self) -> Option<(usize, usize)> {
+        self.regs().next()
+    }
+
+    /// The addresses and sizes of all of the node's register blocks.
+    pub fn regs(&self) -> impl Iterator<Item = (usize, usize)> {
+        let reg = self.property("reg").unwrap_or(&[]);
+        let (a, s) = (self.address_cells, self.size_cells);
+        let len = (a + s) as usize * 4;
+        // A node without any cells has nothing to say.
+        let count = reg.len().checked_div(len).unwrap_or(0);
+        (0..count).filter_map(move |i| {
+            let entry = &reg[i * len..];
+            Some((cells(entry, a)?, cells(&entry[a as usize * 4..], s)?))
+        })
+    }
+}
+
+/// An iterator over the nodes of a device tree.
+pub struct Nodes {
+    fdt: Fdt,
+    pos: usize,
+    depth: usize,
+    // The #address-cells and #size-cells of the nodes we're in.
+    cells: [(u32, u32); MAX_DEPTH],
+}
+
+impl Iterator for Nodes {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        let s = self.fdt.structure;
+        loop {
+            let token = be32(s, self.pos)?;
+            self.pos += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(&s[self.pos..])?;
+                    self.pos = align4(self.pos + name.len() + 1);
+                    if self.depth == MAX_DEPTH {
+                        return None;
+                    }
+                    let (address_cells, size_cells) = match self.depth {
+                        0 => (2, 1),
+                        d => self.cells[d - 1],
+                    };
+                    let node = Node {
+                        name,
+                        depth: self.depth,
+                        props: s.get(self.pos..)?,
+                        strings: self.fdt.strings,
+                        address_cells,
+                        size_cells,
+                    };
+                    // The node's own cells are what its children use.
+                    self.cells[self.depth] = (
+                        node.u32_property("#address-cells").unwrap_or(2),
+                        node.u32_property("#size-cells").unwrap_or(1),
+                    );
+                    self.depth += 1;
+                    return Some(node);
+                }
+                FDT_END_NODE => self.depth = self.depth.checked_sub(1)?,
+                FDT_PROP => {
+                    let len = be32(s, self.pos)? as usize;
+                    self.pos = align4(self.pos + 8 + len);
+                }
+                FDT_NOP => {}
+                // FDT_END, or garbage.
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// An iterator over the properties of a node.
+pub struct Properties {
+    node: Node,
+    pos: usize,
+}
+
+impl Iterator for Properties {
+    type Item = (&'static str, &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.node.props;
+        loop {
+            match be32(s, self.pos)? {
+                FDT_NOP => self.pos += 4,
+                FDT_PROP => break,
+                // The properties end where the children (or the end of the
+                // node) begin.
+                _ => return None,
+            }
+        }
+        let len = be32(s, self.pos + 4)? as usize;
+        let name_offset = be32(s, self.pos + 8)? as usize;
+        let value = s.get(self.pos + 12..self.pos + 12 + len)?;
+        self.pos = align4(self.pos + 12 + len);
+        let name = c_str(self.node.strings.get(name_offset..)?)?;
+        Some((name, value))
+    }
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::check;
+    use alloc::{format, string::String};
+
+    const FDT_END: u32 = 9;
+
+    /// A device tree being put together, the way dtc would.
+    struct Builder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        fn token(&mut self, token: u32) -> &mut Self {
+            self.structure.extend_from_slice(&token.to_be_bytes());
+            self
+        }
+
+        fn pad(&mut self) {
+            self.structure.resize(align4(self.structure.len()), 0);
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.token(FDT_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.token(FDT_END_NODE)
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(FDT_PROP).token(value.len() as u32).token(offset);
+            self.structure.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        fn str(&mut self, name: &str, value: &str) -> &mut Self {
+            self.prop(name, &[value.as_bytes(), b"\0"].concat())
+        }
+
+        /// The blob: the header, the structure block and the strings.
+        fn finish(&mut self) -> Option<Fdt> {
+            self.token(FDT_END);
+            let (structure, strings) = (self.structure.len(), self.strings.len());
+            let mut header = [0u32; HEADER_SIZE / 4];
+            header[0] = FDT_MAGIC;
+            header[1] = (HEADER_SIZE + structure + strings) as u32;
+            header[2] = HEADER_SIZE as u32;
+            header[3] = (HEADER_SIZE + structure) as u32;
+            header[5] = 17;
+            header[8] = strings as u32;
+            header[9] = structure as u32;
+            let mut blob: Vec<u8> = header.iter().flat_map(|w| w.to_be_bytes()).collect();
+            blob.extend_from_slice(&self.structure);
+            blob.extend_from_slice(&self.strings);
+            parse(blob.leak())
+        }
+    }
+
+    /// Something like what QEMU's virt machine has.
+    fn tree() -> Option<Fdt> {
+        let mut b = Builder {
+            structure: Vec::new(),
+            strings: Vec::new(),
+        };
+        b.begin("")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .str("compatible", "riscv-virtio");
+        b.begin("chosen")
+            .str("bootargs", "console=ttyS0")
+            .cells("linux,initrd-start", &[0, 0x8400_0000])
+            .cells("linux,initrd-end", &[0x8410_0000])
+            .end();
+        b.begin("aliases")
+            .str("serial0", "/soc/serial@10000000")
+            .end();
+        b.begin("soc")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2]);
+        b.begin("serial@10000000")
+            .token(FDT_NOP)
+            .prop("compatible", b"ns16550a\0ns16550\0")
+            .cells("reg", &[0, 0x1000_0000, 0, 0x100])
+            .cells("interrupts", &[10])
+            .end();
+        for (addr, irq) in [(0x1000_1000, 1), (0x1000_2000, 2)] {
+            b.begin(&format!("virtio_mmio@{:x}", addr))
+                .str("compatible", "virtio,mmio")
+                .cells("reg", &[0, addr, 0, 0x1000])
+                .cells("interrupts", &[irq])
+                .end();
+        }
+        b.end();
+        b.begin("memory@80000000")
+            .cells("reg", &[0, 0x8000_0000, 0, 0x800_0000])
+            .end();
+        b.begin("cpus")
+            .cells("#address-cells", &[1])
+            .cells("#size-cells", &[0]);
+        b.begin("cpu@0").cells("reg", &[0]).end();
+        b.end().end();
+        b.finish()
+    }
+
+    #[test_case]
+    fn find_test() -> Result<(), String> {
+        let fdt = tree().ok_or("the tree isn't one")?;
+        let root = fdt.find("/").ok_or("there's no /")?;
+        check(root.is_compatible("riscv-virtio"), || {
+            "/ isn't riscv-virtio".into()
+        })?;
+        let chosen = fdt.find("/chosen").ok_or("there's no /chosen")?;
+        let bootargs = chosen.str_property("bootargs");
+        check(bootargs == Some("console=ttyS0"), || {
+            format!("the bootargs are {:?}", bootargs)
+        })?;
+        // By path, with and without the unit address, and by alias.
+        for path in ["/soc/serial", "/soc/serial@10000000", "serial0"] {
+            let node = fdt
+                .find(path)
+                .ok_or_else(|| format!("there's no {}", path))?;
+            check(node.name == "serial@10000000" && node.depth == 2, || {
+                format!("{} is {}", path, node.name)
+            })?;
+        }
+        for path in ["/soc/serial@10001000", "/serial", "/soc/serial/x", "nope"] {
+            check(fdt.find(path).is_none(), || format!("there's a {}", path))?;
+        }
+        Ok(())
+    }
+
+    #[test_case]
+    fn reg_test() -> Result<(), String> {
+        let fdt = tree().ok_or("the tree isn't one")?;
+        let serial = fdt.find("/soc/serial").ok_or("there's no serial")?;
+        check(serial.reg() == Some((0x1000_0000, 0x100)), || {
+            format!("the serial's reg is {:x?}", serial.reg())
+        })?;
+        let memory = fdt.find("/memory").ok_or("there's no memory")?;
+        check(memory.reg() == Some((0x8000_0000, 0x800_0000)), || {
+            format!("the memory is {:x?}", memory.reg())
+        })?;
+        // One address cell and no size cells.
+        let cpu = fdt.find("/cpus/cpu@0").ok_or("there's no cpu")?;
+        check(cpu.reg() == Some((0, 0)), || {
+            format!("the cpu is {:x?}", cpu.reg())
+        })?;
+        let virtio: Vec<usize> = fdt
+            .compatible("virtio,mmio")
+            .filter_map(|n| n.reg())
+            .map(|(addr, _)| addr)
+            .collect();
+        check(virtio == [0x1000_1000, 0x1000_2000], || {
+            format!("virtio is at {:x?}", virtio)
+        })
+    }
+
+    #[test_case]
+    fn properties_test() -> Result<(), String> {
+        let fdt = tree().ok_or("the tree isn't one")?;
+        let serial = fdt.find("/soc/serial").ok_or("there's no serial")?;
+        let compatibles: Vec<&str> = serial.compatibles().collect();
+        check(compatibles == ["ns16550a", "ns16550"], || {
+            format!("the serial is compatible with {:?}", compatibles)
+        })?;
+        let irqs: Vec<u32> = serial.interrupts().collect();
+        check(irqs == [10], || {
+            format!("the serial's interrupts are {:?}", irqs)
+        })
+    }
+
+    #[test_case]
+    fn children_test() -> Result<(), String> {
+        let fdt = tree().ok_or("the tree isn't one")?;
+        let soc = fdt.find("/soc").ok_or("there's no soc")?;
+        let children: Vec<&str> = fdt.children(soc).map(|n| n.name).collect();
+        check(
+            children.len() == 3 && children[0] == "serial@10000000",
+            || format!("soc has {:?}", children),
+        )?;
+        let root = fdt.find("/").ok_or("there's no /")?;
+        let count = fdt.children(root).count();
+        check(count == 5, || format!("/ has {} children", count))
+    }
+
+    /// Where the boot loader left the initramfs, as one or two cells.
+    #[test_case]
+    fn initrd_test() -> Result<(), String> {
+        let fdt = tree().ok_or("the tree isn't one")?;
+        let chosen = fdt.find("/chosen").ok_or("there's no /chosen")?;
+        let initrd = (
+            chosen.num_property("linux,initrd-start"),
+            chosen.num_property("linux,initrd-end"),
+        );
+        check(initrd == (Some(0x8400_0000), Some(0x8410_0000)), || {
+            format!("the initrd is at {:x?}", initrd)
+        })
+    }
+}
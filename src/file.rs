@@ -0,0 +1,493 @@
+// Open files and file descriptors. Everything a process can read from or
+// write to through a file descriptor implements the File trait. Opening a
+// file creates an OpenFile, which holds the file offset and the flags it
+// was opened with, and the process's file descriptor table maps small
+// integers to open files.
+
+use crate::{
+    console,
+    cpu::TrapFrame,
+    locks::{self, Owner},
+    net::UdpSocket,
+    process,
+    syscall::{
+        read_user, write_user, Stat, SysError, SysResult, EAGAIN, EBADF, EINVAL, EMFILE, ENOTDIR,
+        ENOTTY, ENXIO, ESPIPE, ESRCH,
+    },
+    vfs,
+};
+use alloc::{rc::Rc, vec::Vec};
+use core::cell::Cell;
+
+use SysError::{Block, Errno};
+
+/// How many files a process can have open at once.
+pub const MAX_FILES: usize = 64;
+
+// Flags for open
+pub const O_RDONLY: usize = 0o0;
+pub const O_WRONLY: usize = 0o1;
+pub const O_RDWR: usize = 0o2;
+pub const O_ACCMODE: usize = 0o3;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_APPEND: usize = 0o2000;
+pub const O_NONBLOCK: usize = 0o4000;
+pub const O_DIRECTORY: usize = 0o200000;
+pub const O_NOFOLLOW: usize = 0o400000;
+// There's no exec yet, so close-on-exec is accepted but does nothing.
+pub const O_CLOEXEC: usize = 0o2000000;
+
+// File types, in the st_mode field of struct stat
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+// and the bits besides the permissions
+pub const S_ISUID: u32 = 0o4000;
+pub const S_ISGID: u32 = 0o2000;
+pub const S_ISVTX: u32 = 0o1000;
+
+// Terminal ioctls
+pub const TCGETS: usize = 0x5401;
+pub const TCSETS: usize = 0x5402;
+pub const TCSETSW: usize = 0x5403;
+pub const TCSETSF: usize = 0x5404;
+pub const TCGETS2: usize = 0x802c_542a;
+pub const TCSETS2: usize = 0x402c_542b;
+pub const TCSETSW2: usize = 0x402c_542c;
+pub const TCSETSF2: usize = 0x402c_542d;
+pub const TIOCGPGRP: usize = 0x540f;
+pub const TIOCSPGRP: usize = 0x5410;
+pub const TIOCGWINSZ: usize = 0x5413;
+pub const TIOCSWINSZ: usize = 0x5414;
+pub const TIOCGICOUNT: usize = 0x545d;
+
+// Where lseek counts from
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+pub const SEEK_DATA: usize = 3;
+pub const SEEK_HOLE: usize = 4;
+
+/// Something that can be read and written through a file descriptor.
+pub trait File {
+    /// Read into `buf` from `offset`. Returns the number of bytes read, 0
+    /// at end of file. If there is nothing to read yet, this returns Block
+    /// and the caller uses wait() to sleep until there might be.
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError>;
+
+    /// Write `buf` at `offset`. Returns the number of bytes written.
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, SysError>;
+
+    /// The size of the file. Streams, like the console, don't have one,
+    /// and can't be seeked.
+    fn size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Describe the file for stat.
+    fn stat(&self) -> Stat;
+
+    /// Carry out a device specific request for the process whose trap
+    /// frame is `frame`. `arg` is usually a pointer into its memory.
+    fn ioctl(&self, _frame: &TrapFrame, _cmd: usize, _arg: usize) -> SysResult {
+        Err(Errno(ENOTTY))
+    }
+
+    /// Put the process `pid` to sleep until the operation that returned
+    /// Block can make progress.
+    fn wait(&self, _pid: usize) {}
+
+    /// Read directory entries into `buf` as struct linux_dirent64s,
+    /// starting from `offset`, which is whatever the directory makes of it.
+    /// Returns how many bytes were filled in and the offset to go on from.
+    fn getdents(&self, _offset: usize, _buf: &mut [u8]) -> Result<(usize, usize), SysError> {
+        Err(Errno(ENOTDIR))
+    }
+
+    /// Get what's been written to the file onto the disk it's on, and
+    /// wait until it's there. This can answer Block.
+    fn sync(&self) -> Result<(), SysError> {
+        Ok(())
+    }
+
+    /// Cut the file down, or make it longer with zeros, to `len` bytes.
+    fn truncate(&self, _len: usize) -> Result<(), SysError> {
+        Err(Errno(EINVAL))
+    }
+
+    /// Where the first data at or after `offset` is, or the first hole if
+    /// `hole`, for SEEK_DATA and SEEK_HOLE. There's a hole at the end of
+    /// every file. By default, all of a file is data.
+    fn seek_data(&self, offset: usize, hole: bool) -> Result<usize, SysError> {
+        let size = self.size().ok_or(Errno(ESPIPE))?;
+        match (offset < size, hole) {
+            (false, _) => Err(Errno(ENXIO)),
+            (true, false) => Ok(offset),
+            (true, true) => Ok(size),
+        }
+    }
+
+    /// Get at the socket behind the file, if it is one.
+    fn as_udp(&self) -> Option<&UdpSocket> {
+        None
+    }
+}
+
+/// A file that has been opened. This is shared by every file descriptor
+/// that refers to it, so they share the offset as well.
+pub struct OpenFile {
+    file: Rc<dyn File>,
+    flags: usize,
+    offset: Cell<usize>,
+    // Where it was opened, if it's a directory.
+    dir: Option<Vec<u8>>,
+}
+
+impl OpenFile {
+    pub fn new(file: Rc<dyn File>, flags: usize) -> Self {
+        OpenFile {
+            file,
+            flags,
+            offset: Cell::new(0),
+            dir: None,
+        }
+    }
+
+    /// The path of the directory, as it was when it was opened, or None if
+    /// it isn't one.
+    pub fn dir(&self) -> Option<&[u8]> {
+        self.dir.as_deref()
+    }
+
+    pub fn file(&self) -> &Rc<dyn File> {
+        &self.file
+    }
+
+    pub fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    pub fn nonblocking(&self) -> bool {
+        self.flags & O_NONBLOCK != 0
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset.get()
+    }
+
+    /// Calls that would block fail with EAGAIN instead if the file was
+    /// opened with O_NONBLOCK.
+    fn check_block<T>(&self, ret: Result<T, SysError>) -> Result<T, SysError> {
+        match ret {
+            Err(Block) if self.nonblocking() => Err(Errno(EAGAIN)),
+            ret => ret,
+        }
+    }
+
+    /// Read at the current offset and move past what was read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, SysError> {
+        if !self.readable() {
+            return Err(Errno(EBADF));
+        }
+        let n = self.check_block(self.file.read(self.offset.get(), buf))?;
+        self.offset.set(self.offset.get() + n);
+        Ok(n)
+    }
+
+    /// Write at the current offset (or at the end, for files opened with
+    /// O_APPEND) and move past what was written.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, SysError> {
+        if !self.writable() {
+            return Err(Errno(EBADF));
+        }
+        if self.flags & O_APPEND != 0 {
+            if let Some(size) = self.file.size() {
+                self.offset.set(size);
+            }
+        }
+        let n = self.check_block(self.file.write(self.offset.get(), buf))?;
+        self.offset.set(self.offset.get() + n);
+        Ok(n)
+    }
+
+    /// Read at `offset` (pread), leaving the file offset alone. Only files
+    /// with a size can do this.
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        if !self.readable() {
+            return Err(Errno(EBADF));
+        }
+        self.file.size().ok_or(Errno(ESPIPE))?;
+        self.file.read(offset, buf)
+    }
+
+    /// Write at `offset` (pwrite), leaving the file offset alone.
+    pub fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        if !self.writable() {
+            return Err(Errno(EBADF));
+        }
+        self.file.size().ok_or(Errno(ESPIPE))?;
+        self.file.write(offset, buf)
+    }
+
+    /// Read directory entries from the current offset, and move past
+    /// them.
+    pub fn getdents(&self, buf: &mut [u8]) -> Result<usize, SysError> {
+        if !self.readable() {
+            return Err(Errno(EBADF));
+        }
+        let (n, next) = self.file.getdents(self.offset.get(), buf)?;
+        self.offset.set(next);
+        Ok(n)
+    }
+
+    /// Move the offset and return the new one, which has to fit in an
+    /// off_t.
+    pub fn seek(&self, offset: isize, whence: usize) -> Result<usize, SysError> {
+        let size = self.file.size().ok_or(Errno(ESPIPE))?;
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => self.offset.get(),
+            SEEK_END => size,
+            SEEK_DATA | SEEK_HOLE => {
+                let offset = usize::try_from(offset).map_err(|_| Errno(ENXIO))?;
+                let new = self.file.seek_data(offset, whence == SEEK_HOLE)?;
+                self.offset.set(new);
+                return Ok(new);
+            }
+            _ => return Err(Errno(EINVAL)),
+        };
+        let new = base
+            .checked_add_signed(offset)
+            .filter(|&new| new <= isize::MAX as usize)
+            .ok_or(Errno(EINVAL))?;
+        // Seeking past the end is fine, the gap reads as zeros once
+        // something is written there.
+        self.offset.set(new);
+        Ok(new)
+    }
+}
+
+impl Drop for OpenFile {
+    /// The last file descriptor of it is closed: its flock() lock goes.
+    fn drop(&mut self) {
+        locks::release(Owner::File(self as *const OpenFile as usize));
+    }
+}
+
+/// A process's file descriptors.
+pub struct FdTable {
+    files: Vec<Option<Rc<OpenFile>>>,
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        FdTable { files: Vec::new() }
+    }
+
+    /// A table with stdin, stdout and stderr connected to the console.
+    pub fn with_console() -> Self {
+        let mut table = Self::new();
+        let console: Rc<dyn File> = Rc::new(Console);
+        for flags in [O_RDONLY, O_WRONLY, O_WRONLY] {
+            let _ = table.insert(Rc::new(OpenFile::new(console.clone(), flags)));
+        }
+        table
+    }
+
+    pub fn get(&self, fd: usize) -> Result<&Rc<OpenFile>, SysError> {
+        match self.files.get(fd) {
+            Some(Some(file)) => Ok(file),
+            _ => Err(Errno(EBADF)),
+        }
+    }
+
+    /// Install an open file in the lowest free descriptor and return it.
+    pub fn insert(&mut self, file: Rc<OpenFile>) -> Result<usize, SysError> {
+        if let Some(fd) = self.files.iter().position(|f| f.is_none()) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() == MAX_FILES {
+            return Err(Errno(EMFILE));
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    /// Install an open file as descriptor `fd`, closing whatever `fd` was.
+    pub fn insert_at(&mut self, fd: usize, file: Rc<OpenFile>) -> Result<(), SysError> {
+        if fd >= MAX_FILES {
+            return Err(Errno(EBADF));
+        }
+        if fd >= self.files.len() {
+            self.files.resize(fd + 1, None);
+        }
+        self.files[fd] = Some(file);
+        Ok(())
+    }
+
+    /// The open files, whichever descriptors they're in.
+    pub fn iter(&self) -> impl Iterator<Item = &Rc<OpenFile>> {
+        self.files.iter().flatten()
+    }
+
+    /// Close a descriptor. The file itself is closed along with the last
+    /// descriptor that refers to it.
+    pub fn close(&mut self, fd: usize) -> Result<(), SysError> {
+        match self.files.get_mut(fd) {
+            Some(file @ Some(_)) => {
+                *file = None;
+                Ok(())
+            }
+            _ => Err(Errno(EBADF)),
+        }
+    }
+}
+
+/// Open the file at the absolute `path`, which the VFS finds, or with
+/// O_CREAT makes, with `mode`.
+pub fn open(path: &[u8], flags: usize, mode: usize) -> Result<Rc<OpenFile>, SysError> {
+    let (file, dir) = vfs::open_dir(path, flags, mode)?;
+    let mut open = OpenFile::new(file, flags);
+    open.dir = dir;
+    Ok(Rc::new(open))
+}
+
+// ///////////////////////////////////
+// / CONSOLE
+// ///////////////////////////////////
+
+/// The console as a file. Reads go through the line discipline.
+pub struct Console;
+
+/// The device number of /dev/console on Linux, major 5 and minor 1.
+const CONSOLE_RDEV: u64 = 5 << 8 | 1;
+
+impl File for Console {
+    fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        console::read(buf).ok_or(Block)
+    }
+
+    fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        console::write(buf);
+        Ok(buf.len())
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            st_mode: S_IFCHR | 0o620,
+            st_nlink: 1,
+            st_rdev: CONSOLE_RDEV,
+            st_blksize: 1024,
+            ..Default::default()
+        }
+    }
+
+    fn ioctl(&self, frame: &TrapFrame, cmd: usize, arg: usize) -> SysResult {
+        match cmd {
+            TCGETS => write_user(frame, arg, &console::termios())?,
+            TCGETS2 => write_user(frame, arg, &console::termios2())?,
+            TCSETS | TCSETSW | TCSETSF | TCSETS2 | TCSETSW2 | TCSETSF2 => {
+                let t = match cmd {
+                    TCSETS2 | TCSETSW2 | TCSETSF2 => read_user(frame, arg)?,
+                    _ => console::with_speed(read_user(frame, arg)?),
+                };
+                // Both of these wait for pending output to go out first.
+                if cmd != TCSETS && cmd != TCSETS2 {
+                    console::flush_output();
+                }
+                if cmd == TCSETSF || cmd == TCSETSF2 {
+                    console::flush_input();
+                }
+                console::set_termios(t)?;
+            }
+            // There are no process groups, a process is its own.
+            TIOCGPGRP => write_user(frame, arg, &(console::foreground() as i32))?,
+            TIOCSPGRP => {
+                let pid = read_user::<i32>(frame, arg)? as usize;
+                if !process::get_by_pid(pid).is_some_and(|p| p.is_user()) {
+                    return Err(Errno(ESRCH));
+                }
+                console::set_foreground(pid);
+            }
+            TIOCGWINSZ => write_user(frame, arg, &console::winsize())?,
+            TIOCSWINSZ => console::set_winsize(read_user(frame, arg)?),
+            TIOCGICOUNT => write_user(frame, arg, &console::icount())?,
+            _ => return Err(Errno(ENOTTY)),
+        }
+        Ok(0)
+    }
+
+    fn wait(&self, pid: usize) {
+        console::wait(pid);
+    }
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::check;
+    use alloc::{format, string::String};
+
+    /// A file of 100 bytes with nothing in them.
+    struct Empty;
+
+    impl File for Empty {
+        fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, SysError> {
+            Ok(0)
+        }
+
+        fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+            Ok(buf.len())
+        }
+
+        fn size(&self) -> Option<usize> {
+            Some(100)
+        }
+
+        fn stat(&self) -> Stat {
+            Stat::default()
+        }
+    }
+
+    /// Seeks that end up before the start, or past what an off_t can say,
+    /// are EINVAL and leave the offset where it was.
+    #[test_case]
+    fn seek_test() -> Result<(), String> {
+        let file = OpenFile::new(Rc::new(Empty), 0);
+        let cases = [
+            (10, SEEK_SET, Some(10)),
+            (5, SEEK_CUR, Some(15)),
+            (-200, SEEK_END, None),
+            (-100, SEEK_END, Some(0)),
+            (isize::MAX, SEEK_SET, Some(isize::MAX as usize)),
+            (1, SEEK_CUR, None),
+            (isize::MAX, SEEK_END, None),
+            (0, 99, None),
+        ];
+        for (offset, whence, want) in cases {
+            let before = file.offset();
+            let got = file.seek(offset, whence).ok();
+            check(got == want, || {
+                format!("seeking {} from {} went to {:?}", offset, whence, got)
+            })?;
+            let now = file.offset();
+            check(want.unwrap_or(before) == now, || {
+                format!("the offset is {} after seeking {}", now, offset)
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,373 @@
+// The SiFive GPIO controller, as on the FU540 and FU740 (HiFive Unleashed and
+// Unmatched). It has up to 32 pins, and every register has a bit per pin:
+// whether the pin is an input or an output, the value it drives, the value
+// it reads, and which edges of it interrupt. Every pin has an interrupt of
+// its own at the PLIC, listed in order in the device tree. What the board
+// has on the pins is in the device tree too: LEDs under a gpio-leds node and
+// buttons under a gpio-keys node, which point at their pin with a gpios
+// property.
+
+use crate::{
+    cpu,
+    device::{self, Device},
+    fdt,
+    input::{self, Event, EV_KEY, EV_SYN},
+    plic, timer,
+};
+use alloc::vec::Vec;
+use core::ptr::addr_of_mut;
+
+// Register offsets
+const INPUT_VAL: usize = 0x00;
+const INPUT_EN: usize = 0x04;
+const OUTPUT_EN: usize = 0x08;
+const OUTPUT_VAL: usize = 0x0c;
+const RISE_IE: usize = 0x18;
+const RISE_IP: usize = 0x1c;
+const FALL_IE: usize = 0x20;
+const FALL_IP: usize = 0x24;
+const HIGH_IE: usize = 0x28;
+const LOW_IE: usize = 0x30;
+const IOF_EN: usize = 0x38;
+const OUT_XOR: usize = 0x40;
+
+/// How many pins a controller has at most.
+const MAX_PINS: u32 = 32;
+/// The flag of a gpios property entry that says the pin is active low.
+const GPIO_ACTIVE_LOW: u32 = 1;
+
+struct Handler {
+    callback: fn(Pin, usize),
+    data: usize,
+}
+
+struct Controller {
+    base: usize,
+    // What the device tree calls the controller in gpios properties.
+    phandle: Option<u32>,
+    pins: u32,
+    // The interrupt of each pin, if it has one.
+    irqs: Vec<u32>,
+    handlers: Vec<Option<Handler>>,
+}
+
+static mut CONTROLLERS: Vec<Controller> = Vec::new();
+
+fn controllers() -> &'static mut Vec<Controller> {
+    unsafe { &mut *addr_of_mut!(CONTROLLERS) }
+}
+
+impl Controller {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, val: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(val) }
+    }
+
+    /// Set or clear the bit of `pin` in a register.
+    fn set_bit(&self, offset: usize, pin: u32, on: bool) {
+        let val = self.read(offset);
+        if on {
+            self.write(offset, val | 1 << pin);
+        } else {
+            self.write(offset, val & !(1 << pin));
+        }
+    }
+}
+
+/// A pin of a GPIO controller.
+#[derive(Clone, Copy)]
+pub struct Pin {
+    ctrl: usize,
+    pin: u32,
+}
+
+impl Pin {
+    fn controller(&self) -> &'static mut Controller {
+        &mut controllers()[self.ctrl]
+    }
+
+    /// Take the pin away from whatever hardware function it has, to use it
+    /// as a plain GPIO.
+    fn claim(&self) {
+        let c = self.controller();
+        c.set_bit(IOF_EN, self.pin, false);
+        c.set_bit(OUT_XOR, self.pin, false);
+    }
+
+    /// Make the pin an input.
+    pub fn input(&self) {
+        self.claim();
+        let c = self.controller();
+        c.set_bit(OUTPUT_EN, self.pin, false);
+        c.set_bit(INPUT_EN, self.pin, true);
+    }
+
+    /// Make the pin an output, driving `high` to begin with.
+    pub fn output(&self, high: bool) {
+        self.claim();
+        let c = self.controller();
+        c.set_bit(INPUT_EN, self.pin, false);
+        c.set_bit(OUTPUT_VAL, self.pin, high);
+        c.set_bit(OUTPUT_EN, self.pin, true);
+    }
+
+    /// Drive the pin, which must be an output.
+    pub fn set(&self, high: bool) {
+        self.controller().set_bit(OUTPUT_VAL, self.pin, high);
+    }
+
+    /// Read the pin, which must be an input.
+    pub fn get(&self) -> bool {
+        self.controller().read(INPUT_VAL) & 1 << self.pin != 0
+    }
+
+    /// Call `callback(pin, data)` when the pin rises, falls, or both. The
+    /// callback runs in the interrupt handler. Returns false if the pin
+    /// can't interrupt.
+    pub fn on_edge(
+        &self,
+        rising: bool,
+        falling: bool,
+        callback: fn(Pin, usize),
+        data: usize,
+    ) -> bool {
+        let c = self.controller();
+        let Some(&irq) = c.irqs.get(self.pin as usize) else {
+            return false;
+        };
+        c.handlers[self.pin as usize] = Some(Handler { callback, data });
+        // Throw away edges from before.
+        c.write(RISE_IP, 1 << self.pin);
+        c.write(FALL_IP, 1 << self.pin);
+        c.set_bit(RISE_IE, self.pin, rising);
+        c.set_bit(FALL_IE, self.pin, falling);
+        plic::enable(irq);
+        plic::set_priority(irq, 1);
+        true
+    }
+}
+
+/// The pin a property like gpios = <&gpio 22 GPIO_ACTIVE_LOW> of `node`
+/// points at, and whether it's active low.
+pub fn from_property(node: fdt::Node, name: &str) -> Option<(Pin, bool)> {
+    let prop = node.property(name)?;
+    let cell = |i: usize| {
+        let bytes = prop.get(i * 4..i * 4 + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let (phandle, pin, flags) = (cell(0)?, cell(1)?, cell(2).unwrap_or(0));
+    let ctrl = controllers()
+        .iter()
+        .position(|c| c.phandle == Some(phandle))?;
+    if pin >= controllers()[ctrl].pins {
+        return None;
+    }
+    Some((Pin { ctrl, pin }, flags & GPIO_ACTIVE_LOW != 0))
+}
+
+/// The driver for the controller.
+pub struct SifiveGpio;
+
+impl device::Driver for SifiveGpio {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["sifive,gpio0"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let Some(base) = dev.base() else {
+            return false;
+        };
+        let node = dev.node;
+        let pins = node
+            .and_then(|n| n.u32_property("ngpios"))
+            .unwrap_or(dev.irqs.len() as u32)
+            .clamp(1, MAX_PINS);
+        let c = Controller {
+            base,
+            phandle: node.and_then(|n| n.u32_property("phandle")),
+            pins,
+            irqs: dev.irqs.iter().copied().take(pins as usize).collect(),
+            handlers: (0..pins).map(|_| None).collect(),
+        };
+        // Nothing interrupts until someone asks for it.
+        for ie in [RISE_IE, FALL_IE, HIGH_IE, LOW_IE] {
+            c.write(ie, 0);
+        }
+        println!("gpio{}: {} pins at 0x{:x}", controllers().len(), pins, base);
+        controllers().push(c);
+        true
+    }
+}
+
+/// Is `irq` the interrupt of a GPIO pin?
+pub fn has_irq(irq: u32) -> bool {
+    controllers().iter().any(|c| c.irqs.contains(&irq))
+}
+
+/// Clear the edges of the pin that raised `irq` and run its handler. This
+/// is called by the PLIC handler.
+pub fn handle_interrupt(irq: u32) {
+    for (ctrl, c) in controllers().iter().enumerate() {
+        let Some(pin) = c.irqs.iter().position(|&i| i == irq) else {
+            continue;
+        };
+        let bit = 1 << pin;
+        let pending = (c.read(RISE_IP) | c.read(FALL_IP)) & bit;
+        // Writing a one clears the bit.
+        c.write(RISE_IP, bit);
+        c.write(FALL_IP, bit);
+        if pending == 0 {
+            continue;
+        }
+        if let Some(h) = &c.handlers[pin] {
+            let pin = Pin {
+                ctrl,
+                pin: pin as u32,
+            };
+            (h.callback)(pin, h.data);
+        }
+    }
+}
+
+// ///////////////////////////////////
+// / GPIO-LEDS
+// ///////////////////////////////////
+
+// What a heartbeat LED does every second: two short blinks, like a pulse.
+// Milliseconds on, off, on, off.
+const HEARTBEAT: [u64; 4] = [70, 180, 70, 680];
+
+struct Led {
+    pin: Pin,
+    active_low: bool,
+    // Where in HEARTBEAT the LED is.
+    phase: usize,
+}
+
+static mut LEDS: Vec<Led> = Vec::new();
+
+fn leds() -> &'static mut Vec<Led> {
+    unsafe { &mut *addr_of_mut!(LEDS) }
+}
+
+/// Go on to the next phase of the heartbeat of LED `n`, from a timer.
+fn heartbeat(n: usize) {
+    let led = &mut leds()[n];
+    // On in the even phases.
+    led.pin.set(led.phase.is_multiple_of(2) != led.active_low);
+    let ms = HEARTBEAT[led.phase];
+    led.phase = (led.phase + 1) % HEARTBEAT.len();
+    let deadline = cpu::get_mtime() + timer::duration_to_ticks(0, ms * 1_000_000);
+    timer::add(deadline, heartbeat, n);
+}
+
+/// The driver for the LEDs of a gpio-leds node. An LED is on if the
+/// device tree says so (default-state = "on"), blinks if its trigger is
+/// "heartbeat", and is off otherwise.
+pub struct Leds;
+
+impl device::Driver for Leds {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["gpio-leds"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let (Some(fdt), Some(node)) = (fdt::get(), dev.node) else {
+            return false;
+        };
+        for child in fdt.children(node) {
+            let Some((pin, active_low)) = from_property(child, "gpios") else {
+                continue;
+            };
+            let on = child.str_property("default-state") == Some("on");
+            pin.output(on != active_low);
+            if child.str_property("linux,default-trigger") == Some("heartbeat") {
+                leds().push(Led {
+                    pin,
+                    active_low,
+                    phase: 0,
+                });
+                heartbeat(leds().len() - 1);
+            }
+        }
+        true
+    }
+}
+
+// ///////////////////////////////////
+// / GPIO-KEYS
+// ///////////////////////////////////
+
+struct Key {
+    pin: Pin,
+    active_low: bool,
+    // The evdev key code it reports.
+    code: u16,
+    pressed: bool,
+}
+
+static mut KEYS: Vec<Key> = Vec::new();
+
+fn keys() -> &'static mut Vec<Key> {
+    unsafe { &mut *addr_of_mut!(KEYS) }
+}
+
+/// Report key `n` going down or up, from its pin's interrupt.
+fn key_changed(_pin: Pin, n: usize) {
+    let key = &mut keys()[n];
+    let pressed = key.pin.get() != key.active_low;
+    // Buttons bounce, only say something when it makes a difference.
+    if pressed == key.pressed {
+        return;
+    }
+    key.pressed = pressed;
+    input::report(Event {
+        kind: EV_KEY,
+        code: key.code,
+        value: pressed as i32,
+    });
+    input::report(Event {
+        kind: EV_SYN,
+        code: 0,
+        value: 0,
+    });
+}
+
+/// The driver for the buttons of a gpio-keys node. They report the key
+/// code the device tree gives them (linux,code) as input events.
+pub struct Keys;
+
+impl device::Driver for Keys {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["gpio-keys"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let (Some(fdt), Some(node)) = (fdt::get(), dev.node) else {
+            return false;
+        };
+        for child in fdt.children(node) {
+            let (Some((pin, active_low)), Some(code)) = (
+                from_property(child, "gpios"),
+                child.u32_property("linux,code"),
+            ) else {
+                continue;
+            };
+            pin.input();
+            keys().push(Key {
+                pin,
+                active_low,
+                code: code as u16,
+                pressed: pin.get() != active_low,
+            });
+            if !pin.on_edge(true, true, key_changed, keys().len() - 1) {
+                println!("gpio-keys: {} has no interrupt", child.name);
+                keys().pop();
+            }
+        }
+        true
+    }
+}
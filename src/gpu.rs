@@ -0,0 +1,771 @@
+// The display: a virtio-gpu device (-device virtio-gpu-device in QEMU).
+// We only use its 2D commands. The picture is a resource on the host, which
+// we create with the size of the display and back with our own memory: the
+// framebuffer. Drawing is writing pixels into the framebuffer, then telling
+// the device to transfer the changed rectangle to the resource and flush it
+// to the screen. So the framebuffer is a back buffer: the drawing functions
+// (fill_rect, blit, copy_region) only add to the rectangle that changed,
+// and present() sends all of it in one transfer.
+//
+// The 2D commands can't copy on the host, so moving the whole picture
+// would mean sending the whole screen again. Instead, the resource is
+// twice as tall as the screen if we have the memory, and scrolling shows
+// another part of it: only the rows that come in at the bottom have to be
+// sent. Once we get to the end of the resource, the picture moves back to
+// the top, which does send everything, but only every so often.
+// virtio-gpu has no legacy interface, so QEMU needs
+// -global virtio-mmio.force-legacy=false for this device to show up, or
+// -device virtio-gpu-pci instead.
+
+use crate::{
+    cpu::TrapFrame,
+    dma::{self, DmaBuffer},
+    file::{File, S_IFCHR},
+    pointer,
+    syscall::{write_user, Stat, SysError, SysResult, ENOSPC, ENOTTY},
+    virtio::{self, Buffer, Device, Queue},
+};
+use alloc::rc::Rc;
+use core::{mem::size_of, ptr::addr_of_mut};
+use log::{error, info, warn};
+
+use SysError::Errno;
+
+// Commands
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+// Responses
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+const CONTROL_QUEUE: u32 = 0;
+/// How many scanouts (displays) a device can have.
+const MAX_SCANOUTS: usize = 16;
+/// The pixel format of our resource: 32 bits, blue in the lowest byte.
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+/// The resource that is our framebuffer. 0 means no resource.
+const RESOURCE_ID: u32 = 1;
+/// The resolution to use if the device doesn't say.
+const DEFAULT_SIZE: (u32, u32) = (1024, 768);
+
+/// The header of every command and response.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CtrlHeader {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+impl CtrlHeader {
+    fn new(kind: u32) -> Self {
+        CtrlHeader {
+            kind,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct DisplayOne {
+    rect: Rect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct DisplayInfo {
+    hdr: CtrlHeader,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    hdr: CtrlHeader,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Attach backing with our one contiguous memory entry.
+#[repr(C)]
+struct AttachBacking {
+    hdr: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct SetScanout {
+    hdr: CtrlHeader,
+    rect: Rect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+struct TransferToHost2d {
+    hdr: CtrlHeader,
+    rect: Rect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct ResourceFlush {
+    hdr: CtrlHeader,
+    rect: Rect,
+    resource_id: u32,
+    padding: u32,
+}
+
+/// Where the color components are in a pixel, like Linux describes them.
+#[derive(Clone, Copy)]
+pub struct PixelFormat {
+    pub bytes_per_pixel: usize,
+    pub red_shift: u32,
+    pub green_shift: u32,
+    pub blue_shift: u32,
+}
+
+/// A linear framebuffer: `height` rows of `width` pixels, which start
+/// `pitch` bytes apart.
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pitch: usize,
+    pub format: PixelFormat,
+    mem: DmaBuffer,
+    // How many rows the resource has, and the first of them that's on the
+    // screen.
+    virtual_height: u32,
+    yoffset: u32,
+    // Whether yoffset changed, and what changed on the screen, since the
+    // last present().
+    panned: bool,
+    damage: Option<Rect>,
+}
+
+impl Framebuffer {
+    pub fn len(&self) -> usize {
+        self.pitch * self.height as usize
+    }
+
+    /// The memory of what's on the screen. Changes only show up after
+    /// they're marked with damage() and present() is called.
+    pub fn bytes(&mut self) -> &mut [u8] {
+        let start = self.yoffset as usize * self.pitch;
+        let len = self.len();
+        &mut self.mem.as_mut_slice()[start..start + len]
+    }
+
+    /// A color, given as 0xRRGGBB, as a pixel.
+    pub fn rgb(&self, rgb: u32) -> u32 {
+        let f = self.format;
+        (rgb >> 16 & 0xff) << f.red_shift
+            | (rgb >> 8 & 0xff) << f.green_shift
+            | (rgb & 0xff) << f.blue_shift
+    }
+
+    /// The part of `rect` that's on the screen.
+    fn clip(&self, rect: Rect) -> Rect {
+        let x = rect.x.min(self.width);
+        let y = rect.y.min(self.height);
+        Rect {
+            x,
+            y,
+            width: rect.width.min(self.width - x),
+            height: rect.height.min(self.height - y),
+        }
+    }
+
+    /// Mark `rect` as changed, so that the next present() shows it. The
+    /// drawing functions do that themselves, writing to bytes() doesn't.
+    pub fn damage(&mut self, rect: Rect) {
+        let rect = self.clip(rect);
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        self.damage = Some(match self.damage {
+            Some(d) => {
+                let (x, y) = (d.x.min(rect.x), d.y.min(rect.y));
+                Rect {
+                    x,
+                    y,
+                    width: (d.x + d.width).max(rect.x + rect.width) - x,
+                    height: (d.y + d.height).max(rect.y + rect.height) - y,
+                }
+            }
+            None => rect,
+        });
+    }
+
+    /// Where pixel `x` of row `y` is in bytes().
+    fn offset(&self, x: u32, y: u32) -> usize {
+        y as usize * self.pitch + x as usize * self.format.bytes_per_pixel
+    }
+
+    /// Fill `rect` with the pixel `color`.
+    pub fn fill_rect(&mut self, rect: Rect, color: u32) {
+        let rect = self.clip(rect);
+        let px = color.to_le_bytes();
+        for y in rect.y..rect.y + rect.height {
+            let start = self.offset(rect.x, y);
+            let end = self.offset(rect.x + rect.width, y);
+            for p in self.bytes()[start..end].chunks_exact_mut(4) {
+                p.copy_from_slice(&px);
+            }
+        }
+        self.damage(rect);
+    }
+
+    /// Draw the image `pixels`, whose rows are `width` pixels long, with
+    /// its top left corner at `x`, `y`. What doesn't fit on the screen is
+    /// cut off.
+    pub fn blit(&mut self, x: u32, y: u32, width: u32, pixels: &[u32]) {
+        if width == 0 {
+            return;
+        }
+        let height = (pixels.len() / width as usize) as u32;
+        let rect = self.clip(Rect {
+            x,
+            y,
+            width,
+            height,
+        });
+        for row in 0..rect.height {
+            let src = &pixels[(row * width) as usize..][..rect.width as usize];
+            let start = self.offset(rect.x, rect.y + row);
+            let bytes = self.bytes();
+            for (i, p) in src.iter().enumerate() {
+                bytes[start + i * 4..start + i * 4 + 4].copy_from_slice(&p.to_le_bytes());
+            }
+        }
+        self.damage(rect);
+    }
+
+    /// The other way around from blit(): read the pixels of the image
+    /// `pixels`, whose rows are `width` pixels long, from the screen at `x`,
+    /// `y`. Those of it that are off the screen are left alone.
+    pub fn read_region(&mut self, x: u32, y: u32, width: u32, pixels: &mut [u32]) {
+        if width == 0 {
+            return;
+        }
+        let height = (pixels.len() / width as usize) as u32;
+        let rect = self.clip(Rect {
+            x,
+            y,
+            width,
+            height,
+        });
+        for row in 0..rect.height {
+            let dst = &mut pixels[(row * width) as usize..][..rect.width as usize];
+            let start = self.offset(rect.x, rect.y + row);
+            let bytes = self.bytes();
+            for (i, p) in dst.iter_mut().enumerate() {
+                let at = start + i * 4;
+                *p = u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+            }
+        }
+    }
+
+    /// Copy the pixels of `src` to where its top left corner is at `x`,
+    /// `y`. The two may overlap.
+    pub fn copy_region(&mut self, src: Rect, x: u32, y: u32) {
+        let src = self.clip(src);
+        let dst = self.clip(Rect {
+            x,
+            y,
+            width: src.width,
+            height: src.height,
+        });
+        let len = dst.width as usize * self.format.bytes_per_pixel;
+        // Rows that move down are copied from the bottom up, so that none
+        // is overwritten before it's copied.
+        let copy = |fb: &mut Framebuffer, row: u32| {
+            let from = fb.offset(src.x, src.y + row);
+            let to = fb.offset(dst.x, dst.y + row);
+            fb.bytes().copy_within(from..from + len, to);
+        };
+        if dst.y > src.y {
+            (0..dst.height).rev().for_each(|row| copy(self, row));
+        } else {
+            (0..dst.height).for_each(|row| copy(self, row));
+        }
+        self.damage(dst);
+    }
+
+    /// Move the picture up `rows` rows, and fill the rows that come in at
+    /// the bottom with `color`. While the resource has rows below the
+    /// screen, those are just shown instead, so that only the new rows have
+    /// to be sent to the device.
+    pub fn scroll_up(&mut self, rows: u32, color: u32) {
+        let rows = rows.min(self.height);
+        if self.yoffset + rows + self.height <= self.virtual_height {
+            self.yoffset += rows;
+        } else {
+            // Back to the top of the resource, or there's no other way.
+            let from = (self.yoffset + rows) as usize * self.pitch;
+            let len = (self.height - rows) as usize * self.pitch;
+            self.mem.as_mut_slice().copy_within(from..from + len, 0);
+            self.yoffset = 0;
+            self.damage(Rect {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+            });
+        }
+        self.panned = self.virtual_height > self.height;
+        self.fill_rect(
+            Rect {
+                x: 0,
+                y: self.height - rows,
+                width: self.width,
+                height: rows,
+            },
+            color,
+        );
+    }
+}
+
+struct Gpu {
+    dev: Device,
+    control: Queue,
+    scanout: u32,
+    fb: Framebuffer,
+}
+
+// Like the network interface, there's only ever one display.
+static mut GPU: Option<Gpu> = None;
+
+fn gpu() -> Option<&'static mut Gpu> {
+    unsafe { (*addr_of_mut!(GPU)).as_mut() }
+}
+
+/// The framebuffer of the display, if there is one.
+pub fn framebuffer() -> Option<&'static mut Framebuffer> {
+    gpu().map(|g| &mut g.fb)
+}
+
+/// Show what was drawn on the framebuffer since the last time.
+pub fn present() {
+    if let Some(g) = gpu() {
+        g.present();
+    }
+}
+
+impl Gpu {
+    /// Send a command and wait for the response. Returns the type of the
+    /// response.
+    fn command<Req, Resp>(&mut self, req: &Req, resp: &mut Resp) -> u32 {
+        command(&mut self.control, req, resp)
+    }
+
+    fn present(&mut self) {
+        let fb = &mut self.fb;
+        let damage = fb.damage.take();
+        let panned = fb.panned;
+        fb.panned = false;
+        // The resource counts rows from its top, not the screen's.
+        let yoffset = fb.yoffset;
+        let visible = Rect {
+            x: 0,
+            y: yoffset,
+            width: fb.width,
+            height: fb.height,
+        };
+        let pitch = fb.pitch;
+        let bpp = fb.format.bytes_per_pixel;
+        let mut resp = CtrlHeader::default();
+        let mut flushed = visible;
+        if let Some(rect) = damage {
+            let rect = Rect {
+                y: rect.y + yoffset,
+                ..rect
+            };
+            let transfer = TransferToHost2d {
+                hdr: CtrlHeader::new(CMD_TRANSFER_TO_HOST_2D),
+                rect,
+                offset: (rect.y as usize * pitch + rect.x as usize * bpp) as u64,
+                resource_id: RESOURCE_ID,
+                padding: 0,
+            };
+            self.command(&transfer, &mut resp);
+            flushed = rect;
+        } else if !panned {
+            return;
+        }
+        if panned {
+            let set_scanout = SetScanout {
+                hdr: CtrlHeader::new(CMD_SET_SCANOUT),
+                rect: visible,
+                scanout_id: self.scanout,
+                resource_id: RESOURCE_ID,
+            };
+            self.command(&set_scanout, &mut resp);
+            flushed = visible;
+        }
+        let flush = ResourceFlush {
+            hdr: CtrlHeader::new(CMD_RESOURCE_FLUSH),
+            rect: flushed,
+            resource_id: RESOURCE_ID,
+            padding: 0,
+        };
+        self.command(&flush, &mut resp);
+    }
+}
+
+/// Put a command and the buffer for its response on `queue`, and poll
+/// until the device has answered. Returns the type of the response.
+fn command<Req, Resp>(queue: &mut Queue, req: &Req, resp: &mut Resp) -> u32 {
+    let buffers = [
+        Buffer {
+            addr: dma::bus_addr(req),
+            len: size_of::<Req>(),
+            writable: false,
+        },
+        Buffer {
+            addr: dma::bus_addr(resp),
+            len: size_of::<Resp>(),
+            writable: true,
+        },
+    ];
+    // Only one command is ever in flight, so there's always room.
+    let head = queue.add_chain(&buffers).unwrap();
+    queue.submit(head);
+    let id = loop {
+        if let Some((id, _)) = queue.pop_used() {
+            break id;
+        }
+    };
+    queue.free_chain(id);
+    // Every response starts with a header.
+    unsafe { (resp as *mut Resp as *const u32).read_volatile() }
+}
+
+/// Set up a virtio-gpu device, with a framebuffer the size of its first
+/// display. Returns false if it didn't work out.
+pub fn setup(mut dev: Device) -> bool {
+    if gpu().is_some() {
+        warn!("only one display is supported");
+        return false;
+    }
+    if dev.begin_init(|_| 0).is_none() {
+        return false;
+    }
+    let Some(mut control) = dev.setup_queue(CONTROL_QUEUE, virtio::MAX_QUEUE_SIZE) else {
+        dev.fail();
+        return false;
+    };
+    dev.driver_ok();
+
+    // Use the first display that is on.
+    let mut info = DisplayInfo::default();
+    let (scanout, (width, height)) = if command(
+        &mut control,
+        &CtrlHeader::new(CMD_GET_DISPLAY_INFO),
+        &mut info,
+    ) == RESP_OK_DISPLAY_INFO
+    {
+        info.pmodes
+            .iter()
+            .position(|m| m.enabled != 0)
+            .map_or((0, DEFAULT_SIZE), |i| {
+                let r = info.pmodes[i].rect;
+                (i as u32, (r.width, r.height))
+            })
+    } else {
+        (0, DEFAULT_SIZE)
+    };
+
+    let format = PixelFormat {
+        bytes_per_pixel: 4,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+    let pitch = width as usize * format.bytes_per_pixel;
+    let len = pitch * height as usize;
+    // Twice the rows of the screen to scroll in, if we can.
+    let (mem, virtual_height) = match dma::alloc_coherent(len * 2) {
+        Some(mem) => (mem, height * 2),
+        None => match dma::alloc_coherent(len) {
+            Some(mem) => (mem, height),
+            None => {
+                error!("no memory for a {}x{} framebuffer", width, height);
+                return false;
+            }
+        },
+    };
+
+    let mut resp = CtrlHeader::default();
+    let create = ResourceCreate2d {
+        hdr: CtrlHeader::new(CMD_RESOURCE_CREATE_2D),
+        resource_id: RESOURCE_ID,
+        format: FORMAT_B8G8R8X8_UNORM,
+        width,
+        height: virtual_height,
+    };
+    let attach = AttachBacking {
+        hdr: CtrlHeader::new(CMD_RESOURCE_ATTACH_BACKING),
+        resource_id: RESOURCE_ID,
+        nr_entries: 1,
+        addr: mem.bus_addr() as u64,
+        length: (pitch * virtual_height as usize) as u32,
+        padding: 0,
+    };
+    let set_scanout = SetScanout {
+        hdr: CtrlHeader::new(CMD_SET_SCANOUT),
+        rect: Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        },
+        scanout_id: scanout,
+        resource_id: RESOURCE_ID,
+    };
+    if command(&mut control, &create, &mut resp) != RESP_OK_NODATA
+        || command(&mut control, &attach, &mut resp) != RESP_OK_NODATA
+        || command(&mut control, &set_scanout, &mut resp) != RESP_OK_NODATA
+    {
+        error!("the device didn't take the framebuffer");
+        dev.fail();
+        return false;
+    }
+
+    info!("fb0: {}x{}, 32 bits per pixel", width, height);
+    let mut gpu = Gpu {
+        dev,
+        control,
+        scanout,
+        fb: Framebuffer {
+            width,
+            height,
+            pitch,
+            format,
+            mem,
+            virtual_height,
+            yoffset: 0,
+            panned: false,
+            damage: None,
+        },
+    };
+    // The memory is zeroed, so this shows a black screen.
+    gpu.fb.damage(set_scanout.rect);
+    gpu.present();
+    unsafe {
+        *addr_of_mut!(GPU) = Some(gpu);
+    }
+    true
+}
+
+/// The device in `slot` interrupted us. Commands are polled for, so
+/// there's nothing to do but acknowledge it.
+pub fn handle_interrupt(_slot: usize) {
+    if let Some(g) = gpu() {
+        g.dev.ack_interrupt();
+    }
+}
+
+// ///////////////////////////////////
+// / DEVICE FILE
+// ///////////////////////////////////
+
+// ioctls, with Linux's structures
+pub const FBIOGET_VSCREENINFO: usize = 0x4600;
+pub const FBIOGET_FSCREENINFO: usize = 0x4602;
+const FB_TYPE_PACKED_PIXELS: u32 = 0;
+const FB_VISUAL_TRUECOLOR: u32 = 2;
+
+/// The major of framebuffer devices on Linux.
+const FB_MAJOR: u64 = 29;
+
+/// Where a color component is in a pixel.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FbBitfield {
+    offset: u32,
+    length: u32,
+    msb_right: u32,
+}
+
+/// struct fb_var_screeninfo, the parts of it that aren't timings.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FbVarScreeninfo {
+    xres: u32,
+    yres: u32,
+    xres_virtual: u32,
+    yres_virtual: u32,
+    xoffset: u32,
+    yoffset: u32,
+    bits_per_pixel: u32,
+    grayscale: u32,
+    red: FbBitfield,
+    green: FbBitfield,
+    blue: FbBitfield,
+    transp: FbBitfield,
+    nonstd: u32,
+    activate: u32,
+    height: u32,
+    width: u32,
+    accel_flags: u32,
+    // Timings, which a virtual display doesn't have.
+    timings: [u32; 11],
+    reserved: [u32; 4],
+}
+
+/// struct fb_fix_screeninfo
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FbFixScreeninfo {
+    id: [u8; 16],
+    smem_start: u64,
+    smem_len: u32,
+    kind: u32,
+    type_aux: u32,
+    visual: u32,
+    xpanstep: u16,
+    ypanstep: u16,
+    ywrapstep: u16,
+    line_length: u32,
+    mmio_start: u64,
+    mmio_len: u32,
+    accel: u32,
+    capabilities: u16,
+    reserved: [u16; 2],
+}
+
+/// The framebuffer as a file, /dev/fb0. Writes show up on the screen
+/// right away.
+pub struct FbDevice;
+
+/// Open /dev/fb0.
+pub fn open() -> Option<Rc<dyn File>> {
+    framebuffer()?;
+    Some(Rc::new(FbDevice))
+}
+
+impl File for FbDevice {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let fb = framebuffer().unwrap();
+        let bytes = fb.bytes();
+        let n = buf.len().min(bytes.len().saturating_sub(offset));
+        buf[..n].copy_from_slice(&bytes[offset..offset + n]);
+        Ok(n)
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        let fb = framebuffer().unwrap();
+        let pitch = fb.pitch;
+        let width = fb.width;
+        let n = buf.len().min(fb.len().saturating_sub(offset));
+        if n == 0 {
+            return if buf.is_empty() {
+                Ok(0)
+            } else {
+                Err(Errno(ENOSPC))
+            };
+        }
+        // The pointer's arrow would be written over, and then come back
+        // with what was under it before.
+        pointer::hide();
+        let fb = framebuffer().unwrap();
+        fb.bytes()[offset..offset + n].copy_from_slice(&buf[..n]);
+        // Show the whole rows that were written to.
+        let first = offset / pitch;
+        let last = (offset + n - 1) / pitch;
+        fb.damage(Rect {
+            x: 0,
+            y: first as u32,
+            width,
+            height: (last - first + 1) as u32,
+        });
+        pointer::show();
+        present();
+        Ok(n)
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(framebuffer().unwrap().len())
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            st_mode: S_IFCHR | 0o660,
+            st_nlink: 1,
+            st_rdev: FB_MAJOR << 8,
+            ..Default::default()
+        }
+    }
+
+    fn ioctl(&self, frame: &TrapFrame, cmd: usize, arg: usize) -> SysResult {
+        let fb = framebuffer().unwrap();
+        let bits = |offset| FbBitfield {
+            offset,
+            length: 8,
+            msb_right: 0,
+        };
+        match cmd {
+            FBIOGET_VSCREENINFO => {
+                let var = FbVarScreeninfo {
+                    xres: fb.width,
+                    yres: fb.height,
+                    xres_virtual: fb.width,
+                    yres_virtual: fb.height,
+                    bits_per_pixel: fb.format.bytes_per_pixel as u32 * 8,
+                    red: bits(fb.format.red_shift),
+                    green: bits(fb.format.green_shift),
+                    blue: bits(fb.format.blue_shift),
+                    // The size in millimeters is unknown.
+                    height: u32::MAX,
+                    width: u32::MAX,
+                    ..Default::default()
+                };
+                write_user(frame, arg, &var)?;
+            }
+            FBIOGET_FSCREENINFO => {
+                let mut id = [0; 16];
+                id[..10].copy_from_slice(b"virtio-gpu");
+                let fix = FbFixScreeninfo {
+                    id,
+                    // Where the screen is now: it moves when the console
+                    // scrolls.
+                    smem_start: (fb.mem.bus_addr() + fb.yoffset as usize * fb.pitch) as u64,
+                    smem_len: fb.len() as u32,
+                    kind: FB_TYPE_PACKED_PIXELS,
+                    visual: FB_VISUAL_TRUECOLOR,
+                    line_length: fb.pitch as u32,
+                    ..Default::default()
+                };
+                write_user(frame, arg, &fix)?;
+            }
+            _ => return Err(Errno(ENOTTY)),
+        }
+        Ok(0)
+    }
+}
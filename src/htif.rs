@@ -0,0 +1,118 @@
+// HTIF, the host-target interface of the Spike simulator (and of FPGA cores
+// that took it over, like those of Rocket Chip). There are no registers: the
+// kernel has two 64-bit words, tohost and fromhost, that the host finds by
+// their symbols in our ELF file and looks at every so often. We write a
+// command to tohost, and the host clears it once it has taken it; what it
+// has to say back, it writes to fromhost, which we clear once we've read
+// it. A command is a device in the top 8 bits, a command for it in the
+// next 8, and 48 bits of data:
+//
+//   device 0, the "syscall proxy": data with bit 0 set stops the simulator,
+//     with the rest of it as the exit code
+//   device 1, the console: command 1 writes the character in the data,
+//     command 0 asks for one, which comes back in fromhost once it's typed
+//
+// It's built in with the htif feature, which the spike board turns on, and
+// the kernel log and the console go here then. Nothing interrupts us when
+// a key is pressed, so input is polled with a timer.
+
+use crate::{console, cpu, timer};
+use core::ptr::{addr_of, addr_of_mut};
+
+// Devices and their commands
+const DEV_SYSCALL: u64 = 0;
+const DEV_CONSOLE: u64 = 1;
+const CMD_GETCHAR: u64 = 0;
+const CMD_PUTCHAR: u64 = 1;
+
+/// How often we look for input, in milliseconds.
+const POLL_INTERVAL_MS: u64 = 10;
+
+#[export_name = "tohost"]
+static mut TOHOST: u64 = 0;
+#[export_name = "fromhost"]
+static mut FROMHOST: u64 = 0;
+
+// Whether we've asked the console for a character and it hasn't come yet,
+// and the one that came, if nobody has taken it.
+static mut READING: bool = false;
+static mut INPUT: Option<u8> = None;
+
+fn command(dev: u64, cmd: u64, data: u64) -> u64 {
+    dev << 56 | cmd << 48 | data & 0xffff_ffff_ffff
+}
+
+/// Look at what the host has said, if anything.
+fn check_fromhost() {
+    let fh = unsafe { addr_of!(FROMHOST).read_volatile() };
+    if fh == 0 {
+        return;
+    }
+    unsafe {
+        addr_of_mut!(FROMHOST).write_volatile(0);
+    }
+    // The only answer we care about is a character we asked for; the
+    // console also answers every character written.
+    if fh >> 56 == DEV_CONSOLE && (fh >> 48) & 0xff == CMD_GETCHAR {
+        unsafe {
+            READING = false;
+            INPUT = Some(fh as u8);
+        }
+    }
+}
+
+/// Give the host a command, once it has taken the last one.
+fn send(dev: u64, cmd: u64, data: u64) {
+    while unsafe { addr_of!(TOHOST).read_volatile() } != 0 {
+        check_fromhost();
+    }
+    unsafe {
+        addr_of_mut!(TOHOST).write_volatile(command(dev, cmd, data));
+    }
+}
+
+/// Write bytes to the host's console.
+pub fn write(buf: &[u8]) {
+    cpu::without_interrupts(|| {
+        for &c in buf {
+            send(DEV_CONSOLE, CMD_PUTCHAR, c as u64);
+        }
+    });
+}
+
+/// The next character typed on the host's console, if one has been.
+fn get() -> Option<u8> {
+    check_fromhost();
+    unsafe {
+        if let Some(c) = (*addr_of_mut!(INPUT)).take() {
+            return Some(c);
+        }
+        if !READING {
+            READING = true;
+            send(DEV_CONSOLE, CMD_GETCHAR, 0);
+        }
+    }
+    None
+}
+
+/// Stop the simulator, which exits with `code`.
+pub fn exit(code: u16) -> ! {
+    send(DEV_SYSCALL, 0, (code as u64) << 1 | 1);
+    // The host takes its time to look.
+    loop {
+        check_fromhost();
+    }
+}
+
+/// Start looking for input for the console.
+pub fn init() {
+    poll(0);
+}
+
+fn poll(_: usize) {
+    while let Some(c) = get() {
+        console::push(console::Source::Htif, c);
+    }
+    let deadline = cpu::get_mtime() + timer::duration_to_ticks(0, POLL_INTERVAL_MS * 1_000_000);
+    timer::add(deadline, poll, 0);
+}
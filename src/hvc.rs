@@ -0,0 +1,175 @@
+// The virtio console (-device virtio-serial-device with a virtconsole on it
+// in QEMU), hvc0 on Linux. It is a plain byte stream in both directions: a
+// receive queue we keep stocked with buffers for the device to fill, and a
+// transmit queue we put output on. Unlike with a UART, output never gets
+// lost, since the device hands a buffer back only once it has taken all of
+// it. We only use the first port, so we don't ask for the multiport
+// feature.
+
+use crate::{
+    console, cpu,
+    dma::{self, DmaBuffer},
+    virtio::{self, Device, Queue},
+};
+use core::ptr::addr_of_mut;
+
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+/// The size of each of our buffers.
+const BUFFER_SIZE: usize = 256;
+/// How many buffers of output can be in flight.
+const TX_BUFFERS: u16 = 16;
+
+struct Hvc {
+    dev: Device,
+    rx: Queue,
+    tx: Queue,
+    // A buffer for every descriptor of each queue, indexed by descriptor.
+    rx_buffers: DmaBuffer,
+    tx_buffers: DmaBuffer,
+}
+
+static mut HVC: Option<Hvc> = None;
+
+fn hvc() -> Option<&'static mut Hvc> {
+    unsafe { (*addr_of_mut!(HVC)).as_mut() }
+}
+
+/// Is there a virtio console?
+pub fn present() -> bool {
+    hvc().is_some()
+}
+
+/// Set up a virtio console device. Returns false if it didn't work out.
+pub fn setup(mut dev: Device) -> bool {
+    if present() {
+        println!("virtio-console: only one console is supported");
+        return false;
+    }
+    if dev.begin_init(|_| 0).is_none() {
+        return false;
+    }
+    let (Some(rx), Some(tx)) = (
+        dev.setup_queue(RX_QUEUE, virtio::MAX_QUEUE_SIZE),
+        dev.setup_queue(TX_QUEUE, TX_BUFFERS),
+    ) else {
+        dev.fail();
+        return false;
+    };
+    let (Some(rx_buffers), Some(tx_buffers)) = (
+        dma::alloc_coherent(rx.size() as usize * BUFFER_SIZE),
+        dma::alloc_coherent(tx.size() as usize * BUFFER_SIZE),
+    ) else {
+        dev.fail();
+        return false;
+    };
+    let mut hvc = Hvc {
+        rx_buffers,
+        tx_buffers,
+        dev,
+        rx,
+        tx,
+    };
+    while hvc.rx.num_free() > 0 {
+        let i = hvc.rx.alloc_desc().unwrap();
+        hvc.give_rx(i);
+    }
+    hvc.dev.driver_ok();
+    println!("hvc0: virtio console");
+    unsafe {
+        *addr_of_mut!(HVC) = Some(hvc);
+    }
+    true
+}
+
+impl Hvc {
+    fn buffer(buffers: &mut DmaBuffer, i: u16) -> &mut [u8] {
+        let start = i as usize * BUFFER_SIZE;
+        &mut buffers.as_mut_slice()[start..start + BUFFER_SIZE]
+    }
+
+    fn buffer_addr(buffers: &DmaBuffer, i: u16) -> u64 {
+        (buffers.bus_addr() + i as usize * BUFFER_SIZE) as u64
+    }
+
+    /// Put the receive buffer of descriptor `i` (back) on the queue.
+    fn give_rx(&mut self, i: u16) {
+        *self.rx.desc(i) = virtio::Descriptor {
+            addr: Self::buffer_addr(&self.rx_buffers, i),
+            len: BUFFER_SIZE as u32,
+            flags: virtio::DESC_F_WRITE,
+            next: 0,
+        };
+        self.rx.submit(i);
+    }
+
+    /// Free the transmit descriptors the device is done with.
+    fn reclaim_tx(&mut self) {
+        while let Some((i, _)) = self.tx.pop_used() {
+            self.tx.free_desc(i);
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        for chunk in buf.chunks(BUFFER_SIZE) {
+            // If everything is in flight, wait for the device to catch up.
+            let i = loop {
+                self.reclaim_tx();
+                if let Some(i) = self.tx.alloc_desc() {
+                    break i;
+                }
+            };
+            let b = Self::buffer(&mut self.tx_buffers, i);
+            b[..chunk.len()].copy_from_slice(chunk);
+            *self.tx.desc(i) = virtio::Descriptor {
+                addr: Self::buffer_addr(&self.tx_buffers, i),
+                len: chunk.len() as u32,
+                flags: 0,
+                next: 0,
+            };
+            self.tx.submit(i);
+        }
+    }
+
+    fn flush(&mut self) {
+        while self.tx.num_free() < self.tx.size() as usize {
+            self.reclaim_tx();
+        }
+    }
+}
+
+/// Write bytes to the virtio console.
+pub fn write(buf: &[u8]) {
+    // The interrupt handler uses the queues too.
+    cpu::without_interrupts(|| {
+        if let Some(h) = hvc() {
+            h.write(buf);
+        }
+    });
+}
+
+/// Wait until the device has taken everything that was written.
+pub fn flush() {
+    cpu::without_interrupts(|| {
+        if let Some(h) = hvc() {
+            h.flush();
+        }
+    });
+}
+
+/// The device interrupted us: there's input, or output it's done with.
+/// Input goes to the console, which may not be listening.
+pub fn handle_interrupt(_slot: usize) {
+    let Some(h) = hvc() else {
+        return;
+    };
+    h.dev.ack_interrupt();
+    h.reclaim_tx();
+    while let Some((i, len)) = h.rx.pop_used() {
+        let len = (len as usize).min(BUFFER_SIZE);
+        for &c in &Hvc::buffer(&mut h.rx_buffers, i)[..len] {
+            console::push(console::Source::Virtio, c);
+        }
+        h.give_rx(i);
+    }
+}
@@ -0,0 +1,421 @@
+// Inflate: decompressing deflate (RFC 1951), the compression of gzip and
+// zlib, and the gzip format it comes in (RFC 1952), for compressed
+// initramfs archives.
+//
+// Deflated data is a row of blocks, each stored as it is or compressed
+// with Huffman codes: literal bytes and lengths share one code, and every
+// length is followed by the distance back to where to copy that many bytes
+// from, in a code of its own. A block either uses the fixed codes the RFC
+// gives, or has its own, sent as code lengths, which are compressed with a
+// third code. Codes are canonical, so the number of codes of each length
+// and the symbols in order are all it takes to decode them, which is what
+// a Huffman is. Bits come from the bytes low bit first, but Huffman codes
+// are sent from their top bit.
+//
+// Everything is decompressed into a buffer that's big enough for all of it
+// in one go, which is what distances point back into. Data that's corrupt
+// gives None, never a panic.
+//
+// A gzip file is a header, the deflated data, and the CRC32 and length of
+// what that decompresses to. Only one member is read, and what comes after
+// it is ignored.
+
+use crate::partition::crc32;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_DEFLATE: u8 = 8;
+// Header flags
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+const MAX_BITS: usize = 15;
+const MAX_LITERALS: usize = 288;
+const MAX_DISTANCES: usize = 30;
+const END_OF_BLOCK: u16 = 256;
+
+// The lengths of length codes 257-285, from their base and extra bits
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// The same for distance codes 0-29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order the code lengths of the code length code come in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+/// A canonical Huffman code: how many codes there are of each length, and
+/// the symbols, ordered by their code.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: [u16; MAX_LITERALS],
+}
+
+impl Huffman {
+    /// The code whose symbols have code lengths `lengths`, 0 for a symbol
+    /// that isn't in it. None if there are more codes than fit in the
+    /// lengths; a code with fewer is all right, until a missing one turns
+    /// up.
+    fn new(lengths: &[u8]) -> Option<Huffman> {
+        let mut h = Huffman {
+            counts: [0; MAX_BITS + 1],
+            symbols: [0; MAX_LITERALS],
+        };
+        for &len in lengths {
+            h.counts[len as usize] += 1;
+        }
+        let mut left = 1i32;
+        for len in 1..=MAX_BITS {
+            left = (left << 1) - h.counts[len] as i32;
+            if left < 0 {
+                return None;
+            }
+        }
+        // Where the symbols of each length start.
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offsets[len + 1] = offsets[len] + h.counts[len];
+        }
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                h.symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Some(h)
+    }
+}
+
+/// The deflated data being read, bit by bit.
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl Input<'_> {
+    /// The next `n` bits, at most 16, the first in the low bit.
+    fn bits(&mut self, n: u32) -> Option<u32> {
+        let mut val = self.bit_buf;
+        while self.bit_count < n {
+            val |= (*self.data.get(self.pos)? as u32) << self.bit_count;
+            self.pos += 1;
+            self.bit_count += 8;
+        }
+        self.bit_buf = val >> n;
+        self.bit_count -= n;
+        Some(val & ((1 << n) - 1))
+    }
+
+    /// The next symbol in the code `h`.
+    fn decode(&mut self, h: &Huffman) -> Option<u16> {
+        // The code so far, the first code of its length, and where that
+        // one's symbol is.
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_BITS {
+            code |= self.bits(1)? as i32;
+            let count = h.counts[len] as i32;
+            if code - first < count {
+                return Some(h.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+/// What's been decompressed so far.
+struct Output<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Output<'_> {
+    fn push(&mut self, b: u8) -> Option<()> {
+        *self.buf.get_mut(self.pos)? = b;
+        self.pos += 1;
+        Some(())
+    }
+
+    /// Copy `len` bytes from `distance` back. They can overlap with what
+    /// they're copied to, which repeats them.
+    fn copy(&mut self, distance: usize, len: usize) -> Option<()> {
+        if distance > self.pos || self.pos + len > self.buf.len() {
+            return None;
+        }
+        for _ in 0..len {
+            self.buf[self.pos] = self.buf[self.pos - distance];
+            self.pos += 1;
+        }
+        Some(())
+    }
+}
+
+/// A block that's stored as it is: its length, the length's complement,
+/// and the bytes, from the next whole byte on.
+fn stored(input: &mut Input, out: &mut Output) -> Option<()> {
+    input.bit_buf = 0;
+    input.bit_count = 0;
+    let header = input.data.get(input.pos..input.pos + 4)?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    if len != !u16::from_le_bytes([header[2], header[3]]) {
+        return None;
+    }
+    input.pos += 4;
+    let bytes = input.data.get(input.pos..input.pos + len as usize)?;
+    let to = out.buf.get_mut(out.pos..out.pos + len as usize)?;
+    to.copy_from_slice(bytes);
+    input.pos += len as usize;
+    out.pos += len as usize;
+    Some(())
+}
+
+/// A compressed block's symbols, up to the end of the block.
+fn codes(input: &mut Input, out: &mut Output, lit: &Huffman, dist: &Huffman) -> Option<()> {
+    loop {
+        let symbol = input.decode(lit)?;
+        if symbol < END_OF_BLOCK {
+            out.push(symbol as u8)?;
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Some(());
+        }
+        let i = (symbol - 257) as usize;
+        let len = *LENGTH_BASE.get(i)? as u32 + input.bits(LENGTH_EXTRA[i] as u32)?;
+        let i = input.decode(dist)? as usize;
+        let distance = *DISTANCE_BASE.get(i)? as u32 + input.bits(DISTANCE_EXTRA[i] as u32)?;
+        out.copy(distance as usize, len as usize)?;
+    }
+}
+
+/// A block compressed with the fixed codes.
+fn fixed(input: &mut Input, out: &mut Output) -> Option<()> {
+    let mut lengths = [0u8; MAX_LITERALS];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let lit = Huffman::new(&lengths)?;
+    let dist = Huffman::new(&[5; MAX_DISTANCES])?;
+    codes(input, out, &lit, &dist)
+}
+
+/// A block compressed with codes of its own, which come first.
+fn dynamic(input: &mut Input, out: &mut Output) -> Option<()> {
+    let literals = input.bits(5)? as usize + 257;
+    let distances = input.bits(5)? as usize + 1;
+    let code_lengths = input.bits(4)? as usize + 4;
+    if literals > 286 || distances > MAX_DISTANCES {
+        return None;
+    }
+    let mut lengths = [0u8; MAX_LITERALS + MAX_DISTANCES];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = input.bits(3)? as u8;
+    }
+    let lencode = Huffman::new(&lengths[..19])?;
+    // Lengths 16 to 18 repeat the last length, or 0, a few times.
+    let mut i = 0;
+    while i < literals + distances {
+        let (len, repeat) = match input.decode(&lencode)? {
+            len @ 0..16 => (len as u8, 1),
+            16 if i > 0 => (lengths[i - 1], 3 + input.bits(2)?),
+            17 => (0, 3 + input.bits(3)?),
+            18 => (0, 11 + input.bits(7)?),
+            _ => return None,
+        };
+        let run = lengths.get_mut(i..i + repeat as usize)?;
+        run.fill(len);
+        i += repeat as usize;
+    }
+    // There has to be a way to end the block.
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return None;
+    }
+    let lit = Huffman::new(&lengths[..literals])?;
+    let dist = Huffman::new(&lengths[literals..literals + distances])?;
+    codes(input, out, &lit, &dist)
+}
+
+/// Decompress the deflated `data` into `out`. Returns how much of `data`
+/// that took and how much of `out` it filled.
+pub fn inflate(data: &[u8], out: &mut [u8]) -> Option<(usize, usize)> {
+    let mut input = Input {
+        data,
+        pos: 0,
+        bit_buf: 0,
+        bit_count: 0,
+    };
+    let mut out = Output { buf: out, pos: 0 };
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => stored(&mut input, &mut out)?,
+            1 => fixed(&mut input, &mut out)?,
+            2 => dynamic(&mut input, &mut out)?,
+            _ => return None,
+        }
+        if last {
+            return Some((input.pos, out.pos));
+        }
+    }
+}
+
+/// Whether `data` is gzipped.
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+/// How big the gzipped `data` is decompressed, as its trailer says. That's
+/// modulo 4 GiB, but nothing that big would fit anyway.
+pub fn gunzip_size(data: &[u8]) -> Option<usize> {
+    if !is_gzip(data) || data.len() < 18 {
+        return None;
+    }
+    Some(u32_at(data, data.len() - 4) as usize)
+}
+
+/// Decompress the gzipped `data` into `out`, which has to be big enough.
+/// Returns how much of `out` it filled, or None if the data is corrupt or
+/// its CRC or size is wrong.
+pub fn gunzip(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    if !is_gzip(data) || *data.get(2)? != GZIP_DEFLATE {
+        return None;
+    }
+    let flags = *data.get(3)?;
+    // The magic, method and flags, the time, the extra flags and the OS.
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = data.get(pos..pos + 2)?;
+        pos += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            // A NUL-terminated string.
+            pos += data.get(pos..)?.iter().position(|&c| c == 0)? + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    let (used, n) = inflate(data.get(pos..)?, out)?;
+    let trailer = data.get(pos + used..pos + used + 8)?;
+    if crc32(&out[..n]) != u32_at(trailer, 0) || n as u32 != u32_at(trailer, 4) {
+        return None;
+    }
+    Some(n)
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest::check, testing::shown};
+    use alloc::{format, string::String, vec};
+
+    /// Inflate `data` into a buffer of `room` bytes, check that it took all
+    /// of `data` and gave `want`.
+    fn inflates_to(data: &[u8], room: usize, want: &[u8]) -> Result<(), String> {
+        let mut out = vec![0; room];
+        let got = inflate(data, &mut out);
+        check(got == Some((data.len(), want.len())), || {
+            format!("inflate gave {:?} for {} bytes", got, want.len())
+        })?;
+        check(&out[..want.len()] == want, || {
+            format!("inflated to {:?}", shown(&out[..want.len()]))
+        })
+    }
+
+    #[test_case]
+    fn stored_test() -> Result<(), String> {
+        let data = [
+            0x01, 0x0c, 0x00, 0xf3, 0xff, b'h', b'e', b'l', b'l', b'o', b',', b' ', b'w', b'o',
+            b'r', b'l', b'd',
+        ];
+        inflates_to(&data, 64, b"hello, world")
+    }
+
+    /// With the fixed codes, and a copy that overlaps what it copies.
+    #[test_case]
+    fn fixed_test() -> Result<(), String> {
+        let data = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01];
+        inflates_to(&data, 64, b"hello hello hello hello")
+    }
+
+    #[test_case]
+    fn dynamic_test() -> Result<(), String> {
+        let data = [
+            0x25, 0x8a, 0x81, 0x09, 0x00, 0x20, 0x00, 0xc2, 0x5e, 0xf1, 0xb5, 0xa9, 0xff, 0xdf,
+            0x90, 0x95, 0x22, 0x0c, 0x19, 0xd0, 0x57, 0x90, 0xb8, 0x71, 0x9c, 0xd4, 0xfb, 0x4b,
+            0x8a, 0x09, 0x1a, 0x0d, 0x7e, 0xdc, 0x4c, 0xd7, 0x15, 0xb6, 0x03,
+        ];
+        let want = b"aaadadadaaa  aaaaabcbccdbaaddacdabaca dacabaaaaaaabdcada baadbaa";
+        inflates_to(&data, 128, want)
+    }
+
+    /// Data that's cut short, or doesn't fit, is None.
+    #[test_case]
+    fn corrupt_test() -> Result<(), String> {
+        let data = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01];
+        let mut out = [0; 64];
+        for len in 0..data.len() {
+            check(inflate(&data[..len], &mut out).is_none(), || {
+                format!("{} bytes of it inflated", len)
+            })?;
+        }
+        check(inflate(&data, &mut out[..22]).is_none(), || {
+            "it inflated into too little room".into()
+        })?;
+        // Block type 3 doesn't exist.
+        check(inflate(&[0x07], &mut out).is_none(), || {
+            "block type 3 inflated".into()
+        })
+    }
+
+    /// A gzip file with a name in its header.
+    #[test_case]
+    fn gunzip_test() -> Result<(), String> {
+        let mut data = [
+            0x1f, 0x8b, 0x08, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, b'h', b'i', b'.', b't',
+            b'x', b't', 0x00, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01, 0xe3,
+            0x51, 0x3d, 0x8d, 0x17, 0x00, 0x00, 0x00,
+        ];
+        let want = b"hello hello hello hello";
+        check(gunzip_size(&data) == Some(want.len()), || {
+            format!("gunzip_size says {:?}", gunzip_size(&data))
+        })?;
+        let mut out = [0; 64];
+        let n = gunzip(&data, &mut out);
+        check(n == Some(want.len()) && &out[..want.len()] == want, || {
+            format!("gunzip gave {:?}", n)
+        })?;
+        // The CRC in the trailer.
+        data[27] ^= 1;
+        check(gunzip(&data, &mut out).is_none(), || {
+            "a wrong CRC went unnoticed".into()
+        })
+    }
+}
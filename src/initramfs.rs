@@ -0,0 +1,202 @@
+// The initramfs: files unpacked into the rootfs at boot, so there's
+// something there without any disk. They come in a CPIO archive in the
+// "newc" format, the one Linux takes (find . | cpio -o -H newc), either
+// linked into the kernel (build with the initramfs feature and INITRAMFS
+// set to the archive) or loaded by the boot loader, as QEMU's -initrd
+// does, which tells us where it put it in /chosen of the device tree.
+// When both are there, the loaded one is unpacked second, so its files
+// win.
+//
+// Either can be gzipped (find . | cpio -o -H newc | gzip), which keeps big
+// ones, with a toolchain or test programs in them, from making the kernel
+// big and slow to load. A gzipped archive is decompressed into pages of
+// its own first, as many as its trailer says it needs, and they're given
+// back once it's unpacked (see inflate.rs).
+//
+// An archive is a row of entries, each a 110-byte header of hex numbers,
+// the path, NUL-terminated, and the file's data, both padded to 4 bytes.
+// It ends with an entry called TRAILER!!!. Directories, regular files and
+// symlinks, whose data is where they point, are made. There are no device
+// files in a tmpfs yet, so those are skipped.
+//
+// The loaded archive sits in RAM the page allocator would otherwise hand
+// out, so reserve() takes its pages first thing, and they're given back
+// once it's unpacked.
+//
+// Nothing runs /init. There's no ELF loader and no exec, the processes
+// that run are the ones compiled into the kernel (see user.rs), so an
+// archive can bring files along but not programs. We only say so when
+// there's an /init.
+
+use crate::{
+    fdt,
+    file::{O_CREAT, O_TRUNC, O_WRONLY, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG},
+    inflate,
+    page::{self, PAGE_SIZE},
+    syscall::{SysError, EEXIST},
+    vfs,
+};
+use alloc::vec::Vec;
+use core::{
+    ptr::{addr_of_mut, null_mut},
+    slice,
+};
+use log::{error, info, warn};
+
+/// The archive linked into the kernel.
+#[cfg(feature = "initramfs")]
+static EMBEDDED: &[u8] = include_bytes!(env!("INITRAMFS"));
+#[cfg(not(feature = "initramfs"))]
+static EMBEDDED: &[u8] = &[];
+
+const HEADER_SIZE: usize = 110;
+const TRAILER: &[u8] = b"TRAILER!!!";
+const CORRUPT: &str = "the archive is corrupt, stopped unpacking it";
+
+/// The archive the boot loader loaded, and the pages reserved for it, if
+/// they're in the heap.
+static mut LOADED: Option<(&'static [u8], *mut u8)> = None;
+
+/// Keep the page allocator away from the archive the boot loader loaded,
+/// if it did. This has to come before anything allocates much.
+pub fn reserve() {
+    let Some(chosen) = fdt::get().and_then(|fdt| fdt.find("/chosen")) else {
+        return;
+    };
+    let (Some(start), Some(end)) = (
+        chosen.num_property("linux,initrd-start"),
+        chosen.num_property("linux,initrd-end"),
+    ) else {
+        return;
+    };
+    if end <= start {
+        return;
+    }
+    // If it isn't in the heap, there's nothing to reserve or give back.
+    let pages = page::reserve(start, end - start);
+    let archive = unsafe { slice::from_raw_parts(start as *const u8, end - start) };
+    unsafe {
+        *addr_of_mut!(LOADED) = Some((archive, pages));
+    }
+}
+
+/// The number in the `n`th field of the header `h`.
+fn field(h: &[u8], n: usize) -> Option<usize> {
+    let hex = core::str::from_utf8(&h[6 + n * 8..][..8]).ok()?;
+    usize::from_str_radix(hex, 16).ok()
+}
+
+/// Unpack `archive` into the rootfs. Returns how many files and
+/// directories were made, or None if the archive is corrupt.
+fn unpack(archive: &[u8]) -> Option<usize> {
+    let (mut pos, mut made) = (0, 0);
+    loop {
+        let h = archive.get(pos..pos + HEADER_SIZE)?;
+        if &h[..6] != b"070701" && &h[..6] != b"070702" {
+            return None;
+        }
+        let (mode, size, namesize) = (field(h, 1)? as u32, field(h, 6)?, field(h, 11)?);
+        let name_end = pos + HEADER_SIZE + namesize;
+        let name = archive.get(pos + HEADER_SIZE..name_end.checked_sub(1)?)?;
+        let data_start = name_end.next_multiple_of(4);
+        let data = archive.get(data_start..data_start + size)?;
+        pos = (data_start + size).next_multiple_of(4);
+        if name == TRAILER {
+            return Some(made);
+        }
+        // Paths are relative, "./etc" or "etc", and "." is the root.
+        let name = name.strip_prefix(b".").unwrap_or(name);
+        let name = name.strip_prefix(b"/").unwrap_or(name);
+        if name.is_empty() {
+            continue;
+        }
+        let mut path = Vec::from(*b"/");
+        path.extend_from_slice(name);
+        let ret = match mode & S_IFMT {
+            S_IFDIR => match vfs::mkdir(&path, mode) {
+                // It may have been made for a mount point, or by an archive
+                // before this one.
+                Err(SysError::Errno(EEXIST)) => Ok(()),
+                ret => ret,
+            },
+            S_IFREG => write_file(&path, mode, data),
+            S_IFLNK => vfs::symlink(data, &path),
+            _ => {
+                warn!(
+                    "skipping {}, of a type there's no file for",
+                    core::str::from_utf8(&path).unwrap_or("?")
+                );
+                continue;
+            }
+        };
+        match ret {
+            Ok(()) => made += 1,
+            Err(_) => warn!(
+                "couldn't make {}",
+                core::str::from_utf8(&path).unwrap_or("?")
+            ),
+        }
+    }
+}
+
+/// Make the file at `path`, of `mode`, with `data` in it.
+fn write_file(path: &[u8], mode: u32, data: &[u8]) -> Result<(), SysError> {
+    let flags = O_WRONLY | O_CREAT | O_TRUNC;
+    let file = vfs::open(path, flags, (mode & 0o7777) as usize)?;
+    let mut done = 0;
+    while done < data.len() {
+        done += file.write(done, &data[done..])?;
+    }
+    Ok(())
+}
+
+/// Decompress the gzipped `archive` and unpack it. Returns how many files
+/// and directories were made, or why it couldn't be.
+fn unpack_gzip(archive: &[u8]) -> Result<usize, &'static str> {
+    let size = inflate::gunzip_size(archive).ok_or(CORRUPT)?;
+    let count = size.div_ceil(PAGE_SIZE).max(1);
+    let (pages, taken) = page::stats();
+    // Asking the page allocator for more than there is would panic.
+    let buf = if count <= pages - taken {
+        page::alloc(count)
+    } else {
+        null_mut()
+    };
+    if buf.is_null() {
+        return Err("there's no room to decompress the archive into");
+    }
+    let out = unsafe { slice::from_raw_parts_mut(buf, size) };
+    let ret = match inflate::gunzip(archive, out) {
+        Some(n) if n == size => unpack(out).ok_or(CORRUPT),
+        _ => Err("the archive doesn't decompress, it's corrupt"),
+    };
+    page::dealloc(buf);
+    ret
+}
+
+/// Unpack the archives there are into the rootfs, and give the memory the
+/// loaded one was in back.
+pub fn init() {
+    let loaded = unsafe { (*addr_of_mut!(LOADED)).take() };
+    let archives = [Some((EMBEDDED, null_mut())), loaded];
+    for (archive, pages) in archives.into_iter().flatten() {
+        if archive.is_empty() {
+            continue;
+        }
+        let ret = if inflate::is_gzip(archive) {
+            unpack_gzip(archive)
+        } else {
+            unpack(archive).ok_or(CORRUPT)
+        };
+        match ret {
+            Ok(n) => info!("unpacked {} files", n),
+            Err(why) => error!("{}", why),
+        }
+        if !pages.is_null() {
+            page::dealloc(pages);
+        }
+    }
+    if vfs::resolve(b"/init", true).is_ok() {
+        warn!("/init isn't run, there's no way to load a program");
+    }
+}
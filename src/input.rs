@@ -0,0 +1,329 @@
+// Input devices: keyboards, mice and tablets. Devices report what happens as
+// evdev events, the way Linux does: a key going down or up, relative or
+// absolute motion along an axis, and a SYN_REPORT after every batch of
+// events that belong together. For now, the devices are virtio-input ones
+// (-device virtio-keyboard-device and -device virtio-tablet-device in QEMU),
+// and the events of all of them end up in one queue, which user programs
+// read from /dev/input/event0. Keys pressed on a virtio keyboard are also
+// typed on the console (see keymap.rs), and tablets and mice move the
+// pointer (see pointer.rs). Since the queue mixes devices up, the absolute
+// positions of tablets are given in the pointer's pixels, not each
+// tablet's own units, the same for every device: EVIOCGABS says how big the
+// screen is and where the pointer is on it. Clicks come in like keys
+// (BTN_LEFT and so on), in the same report as the position they were made
+// at.
+
+use crate::{
+    cpu::TrapFrame,
+    dma::{self, DmaBuffer},
+    file::{File, S_IFCHR},
+    keymap, pointer,
+    process::WaitQueue,
+    syscall::{write_user, Stat, SysError, SysResult, EINVAL, ENOTTY},
+    timer,
+    virtio::{self, Device, Queue},
+};
+use alloc::{collections::vec_deque::VecDeque, rc::Rc, vec::Vec};
+use core::{mem::size_of, ptr::addr_of_mut};
+
+use SysError::{Block, Errno};
+
+// Event types
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+// Codes of EV_SYN
+const SYN_REPORT: u16 = 0;
+const SYN_DROPPED: u16 = 3;
+// Codes of EV_REL and EV_ABS
+const REL_X: u16 = 0;
+const REL_Y: u16 = 1;
+const ABS_X: u16 = 0;
+const ABS_Y: u16 = 1;
+
+// ioctls: EVIOCGABS(axis) is this plus the axis.
+const EVIOCGABS: usize = 0x8018_4540;
+
+/// How many events are queued before the queue overflows.
+const QUEUE_LEN: usize = 256;
+
+/// An event, as a device reports it.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Event {
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+/// struct input_event, an event with the time it happened.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TimedEvent {
+    sec: u64,
+    usec: u64,
+    kind: u16,
+    code: u16,
+    value: i32,
+}
+
+/// struct input_absinfo, what EVIOCGABS says about an axis.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct AbsInfo {
+    value: i32,
+    minimum: i32,
+    maximum: i32,
+    fuzz: i32,
+    flat: i32,
+    resolution: i32,
+}
+
+struct Events {
+    queue: VecDeque<TimedEvent>,
+    readers: WaitQueue,
+}
+
+static mut EVENTS: Events = Events {
+    queue: VecDeque::new(),
+    readers: WaitQueue::new("input readers"),
+};
+
+fn events() -> &'static mut Events {
+    unsafe { &mut *addr_of_mut!(EVENTS) }
+}
+
+/// Report an event from a device.
+pub fn report(ev: Event) {
+    let ns = timer::realtime_ns();
+    let events = events();
+    let mut ev = ev;
+    if events.queue.len() == QUEUE_LEN {
+        // Nobody is reading. Like Linux, throw away what's queued and say
+        // so, events that are cut in half would make no sense anyway.
+        events.queue.clear();
+        ev = Event {
+            kind: EV_SYN,
+            code: SYN_DROPPED,
+            value: 0,
+        };
+    }
+    events.queue.push_back(TimedEvent {
+        sec: ns / timer::NANOS_PER_SEC,
+        usec: ns % timer::NANOS_PER_SEC / 1000,
+        kind: ev.kind,
+        code: ev.code,
+        value: ev.value,
+    });
+    events.readers.wake_all();
+}
+
+// ///////////////////////////////////
+// / VIRTIO-INPUT
+// ///////////////////////////////////
+
+// The configuration space: the driver writes what it wants to know to
+// select (and subsel), and the device puts the answer in data.
+const CFG_SELECT: usize = 0;
+const CFG_SUBSEL: usize = 1;
+const CFG_SIZE: usize = 2;
+const CFG_DATA: usize = 8;
+const CFG_ID_NAME: u8 = 0x01;
+const CFG_ABS_INFO: u8 = 0x12;
+
+const EVENT_QUEUE: u32 = 0;
+
+struct InputDevice {
+    dev: Device,
+    queue: Queue,
+    // A buffer for every descriptor of the event queue, for the device to
+    // write an event into.
+    buffers: DmaBuffer,
+    // The minimum and maximum of the ABS_X and ABS_Y axes, for a tablet.
+    abs: [Option<(i32, i32)>; 2],
+}
+
+static mut DEVICES: Vec<InputDevice> = Vec::new();
+
+fn devices() -> &'static mut Vec<InputDevice> {
+    unsafe { &mut *addr_of_mut!(DEVICES) }
+}
+
+/// Set up a virtio-input device. Returns false if it didn't work out.
+pub fn setup(mut dev: Device) -> bool {
+    if dev.begin_init(|_| 0).is_none() {
+        return false;
+    }
+    // The status queue, for the keyboard LEDs, isn't used.
+    let Some(queue) = dev.setup_queue(EVENT_QUEUE, virtio::MAX_QUEUE_SIZE) else {
+        dev.fail();
+        return false;
+    };
+    let Some(buffers) = dma::alloc_coherent(queue.size() as usize * size_of::<Event>()) else {
+        dev.fail();
+        return false;
+    };
+    let mut input = InputDevice {
+        buffers,
+        dev,
+        queue,
+        abs: [None; 2],
+    };
+    input.abs = [input.abs_range(ABS_X), input.abs_range(ABS_Y)];
+    while input.queue.num_free() > 0 {
+        let i = input.queue.alloc_desc().unwrap();
+        input.give_buffer(i);
+    }
+    input.dev.driver_ok();
+    print!("input{}: ", devices().len());
+    input.print_name();
+    println!();
+    devices().push(input);
+    true
+}
+
+impl InputDevice {
+    /// Put the buffer of descriptor `i` (back) on the event queue.
+    fn give_buffer(&mut self, i: u16) {
+        *self.queue.desc(i) = virtio::Descriptor {
+            addr: (self.buffers.bus_addr() + i as usize * size_of::<Event>()) as u64,
+            len: size_of::<Event>() as u32,
+            flags: virtio::DESC_F_WRITE,
+            next: 0,
+        };
+        self.queue.submit(i);
+    }
+
+    /// The minimum and maximum that the device reports for the absolute
+    /// axis `axis`, if it has that axis.
+    fn abs_range(&self, axis: u16) -> Option<(i32, i32)> {
+        self.dev.set_config(CFG_SELECT, CFG_ABS_INFO);
+        self.dev.set_config(CFG_SUBSEL, axis as u8);
+        let size: u8 = self.dev.config(CFG_SIZE);
+        if size == 0 {
+            return None;
+        }
+        let min: u32 = self.dev.config(CFG_DATA);
+        let max: u32 = self.dev.config(CFG_DATA + 4);
+        Some((min as i32, max as i32))
+    }
+
+    fn print_name(&self) {
+        self.dev.set_config(CFG_SELECT, CFG_ID_NAME);
+        self.dev.set_config(CFG_SUBSEL, 0u8);
+        let size: u8 = self.dev.config(CFG_SIZE);
+        for i in 0..size as usize {
+            print!("{}", self.dev.config::<u8>(CFG_DATA + i) as char);
+        }
+    }
+}
+
+/// The device in `slot` interrupted us: it has written events.
+pub fn handle_interrupt(slot: usize) {
+    let Some(input) = devices().iter_mut().find(|d| d.dev.slot == slot) else {
+        return;
+    };
+    input.dev.ack_interrupt();
+    while let Some((i, _)) = input.queue.pop_used() {
+        let buffers = input.buffers.as_ptr() as *const Event;
+        let mut ev = unsafe { buffers.add(i as usize).read_volatile() };
+        input.give_buffer(i);
+        match (ev.kind, ev.code) {
+            (EV_ABS, ABS_X | ABS_Y) => {
+                if let Some((min, max)) = input.abs[ev.code as usize] {
+                    ev.value = pointer::move_to(ev.code == ABS_Y, ev.value, min, max) as i32;
+                }
+            }
+            (EV_REL, REL_X | REL_Y) => pointer::move_by(ev.code == REL_Y, ev.value),
+            (EV_SYN, SYN_REPORT) => pointer::sync(),
+            (EV_KEY, _) => keymap::key(ev.code, ev.value),
+            _ => {}
+        }
+        // Keys, motion and the reports that group them are all we know
+        // about. LEDs and the like are left out.
+        if let EV_SYN | EV_KEY | EV_REL | EV_ABS = ev.kind {
+            report(ev);
+        }
+    }
+}
+
+// ///////////////////////////////////
+// / DEVICE FILE
+// ///////////////////////////////////
+
+/// The device number of /dev/input/event0 on Linux, major 13 and minor 64.
+const EVENT0_RDEV: u64 = 13 << 8 | 64;
+
+/// The event queue as a file. Reads return whole struct input_events.
+pub struct EventDevice;
+
+/// Open /dev/input/event0.
+pub fn open() -> Option<Rc<dyn File>> {
+    if devices().is_empty() {
+        return None;
+    }
+    Some(Rc::new(EventDevice))
+}
+
+impl File for EventDevice {
+    fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        const SIZE: usize = size_of::<TimedEvent>();
+        if buf.len() < SIZE {
+            return Err(Errno(EINVAL));
+        }
+        let queue = &mut events().queue;
+        if queue.is_empty() {
+            return Err(Block);
+        }
+        let mut n = 0;
+        while n + SIZE <= buf.len() {
+            let Some(ev) = queue.pop_front() else {
+                break;
+            };
+            let bytes = unsafe { core::slice::from_raw_parts(&ev as *const _ as *const u8, SIZE) };
+            buf[n..n + SIZE].copy_from_slice(bytes);
+            n += SIZE;
+        }
+        Ok(n)
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, SysError> {
+        Err(Errno(EINVAL))
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            st_mode: S_IFCHR | 0o660,
+            st_nlink: 1,
+            st_rdev: EVENT0_RDEV,
+            ..Default::default()
+        }
+    }
+
+    fn ioctl(&self, frame: &TrapFrame, cmd: usize, arg: usize) -> SysResult {
+        let axis = cmd.wrapping_sub(EVIOCGABS);
+        if axis != ABS_X as usize && axis != ABS_Y as usize {
+            return Err(Errno(ENOTTY));
+        }
+        let (x, y) = pointer::position();
+        let (width, height) = pointer::screen_size();
+        let (value, size) = if axis == ABS_Y as usize {
+            (y, height)
+        } else {
+            (x, width)
+        };
+        let info = AbsInfo {
+            value: value as i32,
+            minimum: 0,
+            maximum: size as i32 - 1,
+            ..Default::default()
+        };
+        write_user(frame, arg, &info)?;
+        Ok(0)
+    }
+
+    fn wait(&self, pid: usize) {
+        events().readers.wait(pid);
+    }
+}
@@ -0,0 +1,302 @@
+// The keymap turns the key presses of a keyboard into the characters a
+// terminal would send, and feeds them to the console, so typing on the
+// keyboard in QEMU's window works the same as typing on the UART. Keys come
+// in as evdev key codes, which say where a key is, not what's printed on
+// it; the layout says what it's printed with, with and without shift and
+// AltGr. The layout is picked with keymap= on the kernel command line, US
+// if there's none. Keys that move the cursor send the escape sequences of
+// a VT100, and a key that's held down repeats.
+//
+// Dead keys aren't supported, the accents they're for are typed as they
+// are.
+
+use crate::{console, cpu, fdt, timer};
+use core::ptr::addr_of_mut;
+
+// Key codes
+const KEY_ESC: u16 = 1;
+const KEY_BACKSPACE: u16 = 14;
+const KEY_TAB: u16 = 15;
+const KEY_ENTER: u16 = 28;
+const KEY_LEFTCTRL: u16 = 29;
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+const KEY_KPASTERISK: u16 = 55;
+const KEY_LEFTALT: u16 = 56;
+const KEY_SPACE: u16 = 57;
+const KEY_CAPSLOCK: u16 = 58;
+const KEY_KP7: u16 = 71;
+const KEY_KPDOT: u16 = 83;
+const KEY_102ND: u16 = 86;
+const KEY_KPENTER: u16 = 96;
+const KEY_RIGHTCTRL: u16 = 97;
+const KEY_KPSLASH: u16 = 98;
+const KEY_RIGHTALT: u16 = 100;
+const KEY_HOME: u16 = 102;
+const KEY_UP: u16 = 103;
+const KEY_PAGEUP: u16 = 104;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_END: u16 = 107;
+const KEY_DOWN: u16 = 108;
+const KEY_PAGEDOWN: u16 = 109;
+const KEY_INSERT: u16 = 110;
+const KEY_DELETE: u16 = 111;
+/// We only keep track of keys below this.
+const KEY_COUNT: usize = 128;
+
+/// The first key of each row of letters, in the order of Layout's rows.
+/// The rows are 12, 12, 12 and 11 keys long; the fifth "row" is the key
+/// ISO keyboards have next to the left shift.
+const ROW_START: [u16; 5] = [2, 16, 30, 43, KEY_102ND];
+
+/// The keypad, from KEY_KP7 to KEY_KPDOT, with num lock on.
+const KEYPAD: &[u8; 13] = b"789-456+1230.";
+
+/// How long a key is held before it starts repeating, and how often it
+/// repeats then, in milliseconds.
+const REPEAT_DELAY_MS: u64 = 250;
+const REPEAT_INTERVAL_MS: u64 = 33;
+
+/// A keyboard layout: what each key of the rows of letters and digits
+/// types, without a modifier, with shift, and with AltGr. A row is a string
+/// with a character for every key in it, \0 for keys that type nothing.
+struct Layout {
+    name: &'static str,
+    normal: [&'static str; 5],
+    shift: [&'static str; 5],
+    // None if right alt is just alt.
+    altgr: Option<[&'static str; 5]>,
+}
+
+static US: Layout = Layout {
+    name: "us",
+    normal: [
+        "1234567890-=",
+        "qwertyuiop[]",
+        "asdfghjkl;'`",
+        "\\zxcvbnm,./",
+        "<",
+    ],
+    shift: [
+        "!@#$%^&*()_+",
+        "QWERTYUIOP{}",
+        "ASDFGHJKL:\"~",
+        "|ZXCVBNM<>?",
+        ">",
+    ],
+    altgr: None,
+};
+
+static DE: Layout = Layout {
+    name: "de",
+    normal: [
+        "1234567890ß´",
+        "qwertzuiopü+",
+        "asdfghjklöä^",
+        "#yxcvbnm,.-",
+        "<",
+    ],
+    shift: [
+        "!\"§$%&/()=?`",
+        "QWERTZUIOPÜ*",
+        "ASDFGHJKLÖÄ°",
+        "'YXCVBNM;:_",
+        ">",
+    ],
+    altgr: Some([
+        "\0²³\0\0\0{[]}\\\0",
+        "@\0€\0\0\0\0\0\0\0\0~",
+        "\0\0\0\0\0\0\0\0\0\0\0\0",
+        "\0\0\0\0\0\0\0µ\0\0\0",
+        "|",
+    ]),
+};
+
+/// The layouts keymap= can pick.
+static LAYOUTS: [&Layout; 2] = [&US, &DE];
+
+/// The character of key `code` in `plane`, if it has one.
+fn lookup(plane: &[&'static str; 5], code: u16) -> Option<char> {
+    let row = ROW_START.iter().rposition(|&start| start <= code)?;
+    let c = plane[row].chars().nth((code - ROW_START[row]) as usize)?;
+    (c != '\0').then_some(c)
+}
+
+struct Keyboard {
+    layout: &'static Layout,
+    down: [bool; KEY_COUNT],
+    caps_lock: bool,
+    // The key that's repeating, and the timer that repeats it next.
+    repeat: Option<(u16, usize)>,
+}
+
+static mut KEYBOARD: Keyboard = Keyboard {
+    layout: &US,
+    down: [false; KEY_COUNT],
+    caps_lock: false,
+    repeat: None,
+};
+
+fn keyboard() -> &'static mut Keyboard {
+    unsafe { &mut *addr_of_mut!(KEYBOARD) }
+}
+
+/// Pick the layout the kernel command line asks for.
+pub fn init() {
+    let Some(bootargs) = fdt::get()
+        .and_then(|f| f.find("/chosen"))
+        .and_then(|c| c.str_property("bootargs"))
+    else {
+        return;
+    };
+    let Some(name) = bootargs
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix("keymap="))
+    else {
+        return;
+    };
+    match LAYOUTS.iter().find(|l| l.name == name) {
+        Some(layout) => {
+            keyboard().layout = layout;
+            println!("keymap: {}", name);
+        }
+        None => println!("keymap: no layout called {}, using us", name),
+    }
+}
+
+impl Keyboard {
+    fn held(&self, code: u16) -> bool {
+        self.down[code as usize]
+    }
+
+    /// Queue what key `code` types with the modifiers that are held now.
+    /// Returns false if it doesn't type anything.
+    fn type_key(&self, code: u16) -> bool {
+        let seq: &[u8] = match code {
+            KEY_ESC => b"\x1b",
+            // What terminals send for backspace is DEL.
+            KEY_BACKSPACE => b"\x7f",
+            KEY_TAB => b"\t",
+            KEY_ENTER | KEY_KPENTER => b"\r",
+            KEY_UP => b"\x1b[A",
+            KEY_DOWN => b"\x1b[B",
+            KEY_RIGHT => b"\x1b[C",
+            KEY_LEFT => b"\x1b[D",
+            KEY_HOME => b"\x1b[H",
+            KEY_END => b"\x1b[F",
+            KEY_INSERT => b"\x1b[2~",
+            KEY_DELETE => b"\x1b[3~",
+            KEY_PAGEUP => b"\x1b[5~",
+            KEY_PAGEDOWN => b"\x1b[6~",
+            _ => &[],
+        };
+        if !seq.is_empty() {
+            push(seq);
+            return true;
+        }
+        let Some(mut c) = self.char_of(code) else {
+            return false;
+        };
+        let ctrl = self.held(KEY_LEFTCTRL) || self.held(KEY_RIGHTCTRL);
+        if ctrl {
+            // ^A is 1, and so on; ^@ and ^Space are NUL.
+            match c.to_ascii_uppercase() {
+                '@'..='_' => c = (c.to_ascii_uppercase() as u8 & 0x1f) as char,
+                ' ' => c = '\0',
+                _ => {}
+            }
+        }
+        // Alt puts an ESC in front, like xterm's metaSendsEscape.
+        let alt = self.held(KEY_LEFTALT) || self.layout.altgr.is_none() && self.held(KEY_RIGHTALT);
+        if alt {
+            push(b"\x1b");
+        }
+        let mut utf8 = [0; 4];
+        push(c.encode_utf8(&mut utf8).as_bytes());
+        true
+    }
+
+    /// The character key `code` stands for, before ctrl is applied.
+    fn char_of(&self, code: u16) -> Option<char> {
+        match code {
+            KEY_SPACE => return Some(' '),
+            KEY_KPASTERISK => return Some('*'),
+            KEY_KPSLASH => return Some('/'),
+            KEY_KP7..=KEY_KPDOT => return Some(KEYPAD[(code - KEY_KP7) as usize] as char),
+            _ => {}
+        }
+        let layout = self.layout;
+        if let Some(altgr) = &layout.altgr {
+            if self.held(KEY_RIGHTALT) {
+                return lookup(altgr, code);
+            }
+        }
+        let mut shift = self.held(KEY_LEFTSHIFT) || self.held(KEY_RIGHTSHIFT);
+        // Caps lock is shift for letters only.
+        if self.caps_lock && lookup(&layout.normal, code).is_some_and(|c| c.is_alphabetic()) {
+            shift = !shift;
+        }
+        lookup(if shift { &layout.shift } else { &layout.normal }, code)
+    }
+
+    fn stop_repeat(&mut self) {
+        if let Some((_, timer)) = self.repeat.take() {
+            timer::cancel(timer);
+        }
+    }
+
+    fn start_repeat(&mut self, code: u16, ms: u64) {
+        let deadline = cpu::get_mtime() + timer::duration_to_ticks(0, ms * 1_000_000);
+        let timer = timer::add(deadline, repeat, code as usize);
+        self.repeat = Some((code, timer));
+    }
+}
+
+/// Give input to the console, as if it had been typed on a terminal.
+fn push(bytes: &[u8]) {
+    for &b in bytes {
+        console::push(console::Source::Keyboard, b);
+    }
+}
+
+/// Type key `code` again, from a timer, while it's held down.
+fn repeat(code: usize) {
+    let kbd = keyboard();
+    kbd.repeat = None;
+    if kbd.held(code as u16) {
+        kbd.type_key(code as u16);
+        kbd.start_repeat(code as u16, REPEAT_INTERVAL_MS);
+    }
+}
+
+/// A key went down (`value` 1), up (0), or the keyboard repeated it (2).
+/// This is called from the interrupt handler of the keyboard.
+pub fn key(code: u16, value: i32) {
+    let kbd = keyboard();
+    if code as usize >= KEY_COUNT {
+        return;
+    }
+    let was_down = kbd.held(code);
+    kbd.down[code as usize] = value != 0;
+    if value == 0 {
+        if kbd.repeat.is_some_and(|(key, _)| key == code) {
+            kbd.stop_repeat();
+        }
+        return;
+    }
+    // We repeat keys ourselves, the keyboard's repeats (and presses of a
+    // key that's already down) are ignored.
+    if was_down {
+        return;
+    }
+    if code == KEY_CAPSLOCK {
+        kbd.caps_lock = !kbd.caps_lock;
+        return;
+    }
+    if kbd.type_key(code) {
+        // Only the last key pressed repeats.
+        kbd.stop_repeat();
+        kbd.start_repeat(code, REPEAT_DELAY_MS);
+    }
+}
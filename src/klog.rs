@@ -0,0 +1,630 @@
+// The kernel log as the log crate sees it, so that the kernel can say what
+// it's doing with error!, warn!, info!, debug! and trace! instead of
+// println!. Every message has a level, and only those at the level asked
+// for or above are printed, each on a line like
+//
+//   [    1.234567] h0 INFO  virtio::blk  the message
+//
+// with the time since boot, from the timer's tick counter, the hart it was
+// logged on, its level and the module it's from, without the crate's name.
+// The modules are padded to the longest one seen so far, so that the
+// messages of drivers coming up at the same time line up, and a message of
+// several lines has the rest of them start where its first did.
+//
+// The level is in a color of its own, red for errors down to gray for
+// trace, and the time and the module are set off from the message too.
+// logcolor=never on the command line turns that off, logcolor=always
+// keeps it even on a serial line with TERM=dumb, whose terminal otherwise
+// gets the log without the colors (see console.rs). The display always
+// draws them.
+//
+// Info and up is printed until the kernel command line says otherwise with
+// loglevel=, which takes a level, modules with levels of their own, or
+// both, separated by commas: loglevel=warn,virtio=trace,block=debug. A
+// module's level goes for the modules in it too, unless they have their
+// own. The shell's log command changes the levels later, the same way.
+//
+// Records go to the serial line, the display and the ring below, which
+// each have a level of their own as well (see THE SINKS): logsink= on the
+// command line sets them, logsink=display:warn,serial:trace:drop, and so
+// does log sink.
+//
+// Whatever the levels, every record is also kept in a ring buffer in
+// memory, until newer ones push it out, so that what was logged before
+// anyone was looking, or below the level that's printed, can still be
+// seen. The shell's dmesg command prints what's there, and /proc/kmsg has
+// it in the format Linux has, <priority>[time] message, one line each.
+// A panic's report is printed rather than logged, and goes in the ring
+// too, as errors of the module panic, a line each, so that the crash dump
+// has it along with what led up to it (see crashdump.rs).
+
+use crate::{
+    console,
+    cpu::{self, TrapFrame},
+    fdt, semihosting,
+    shell::{self, Builtin},
+    syscall::{SysError, EINVAL},
+    timer::{self, NANOS_PER_SEC},
+};
+use alloc::{format, string::String, vec::Vec};
+use core::{fmt::Write, ptr::addr_of_mut, str::FromStr};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use SysError::Errno;
+
+/// The crate's name, as it starts the modules' paths.
+const CRATE: &str = concat!(env!("CARGO_CRATE_NAME"), "::");
+
+/// The level of the modules that don't have one of their own.
+static mut LEVEL: LevelFilter = LevelFilter::Info;
+/// The modules that do, by path without the crate's name.
+static mut MODULES: Vec<(String, LevelFilter)> = Vec::new();
+
+/// Whether records are colored.
+static mut COLOR: bool = true;
+/// How wide the module column is, the longest module seen so far.
+static mut WIDTH: usize = 0;
+
+fn modules() -> &'static mut Vec<(String, LevelFilter)> {
+    unsafe { &mut *addr_of_mut!(MODULES) }
+}
+
+/// The module `target` is, without the crate's name.
+fn module(target: &str) -> &str {
+    target.strip_prefix(CRATE).unwrap_or(target)
+}
+
+/// The level of the module `module`: its own, or that of the closest module
+/// it's in that has one.
+fn level_of(module: &str) -> LevelFilter {
+    let within = |name: &str| {
+        module == name
+            || module
+                .strip_prefix(name)
+                .is_some_and(|m| m.starts_with("::"))
+    };
+    modules()
+        .iter()
+        .filter(|(name, _)| within(name))
+        .max_by_key(|(name, _)| name.len())
+        .map_or(unsafe { LEVEL }, |&(_, level)| level)
+}
+
+/// Change the levels the way `spec` says: a level for the modules that
+/// don't have one of their own, or a module's with module=level, several
+/// separated by commas. A module's level is taken away with module=.
+pub fn set_levels(spec: &str) -> Result<(), SysError> {
+    for part in spec.split(',').filter(|p| !p.is_empty()) {
+        let Some((name, level)) = part.split_once('=') else {
+            let level = LevelFilter::from_str(part).map_err(|_| Errno(EINVAL))?;
+            unsafe {
+                LEVEL = level;
+            }
+            continue;
+        };
+        let name = module(name);
+        modules().retain(|(n, _)| n != name);
+        if !level.is_empty() {
+            let level = LevelFilter::from_str(level).map_err(|_| Errno(EINVAL))?;
+            modules().push((name.into(), level));
+        }
+    }
+    Ok(())
+}
+
+/// The hart we're on.
+fn hart() -> usize {
+    match cpu::mscratch_read() {
+        0 => 0,
+        frame => unsafe { (*(frame as *const TrapFrame)).hartid },
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const MODULE: &str = "\x1b[36m";
+
+/// The color of `level`, and of the messages at that level.
+fn color(level: Level) -> (&'static str, &'static str) {
+    match level {
+        Level::Error => ("\x1b[1;31m", "\x1b[31m"),
+        Level::Warn => ("\x1b[1;33m", "\x1b[33m"),
+        Level::Info => ("\x1b[32m", ""),
+        Level::Debug => ("\x1b[34m", ""),
+        Level::Trace => ("\x1b[90m", "\x1b[90m"),
+    }
+}
+
+/// A record as it's printed, a line with the end of line, in colour if
+/// `in_color` and the log is.
+fn format(
+    ns: u64,
+    hart: usize,
+    level: Level,
+    module: &str,
+    message: &str,
+    in_color: bool,
+) -> String {
+    let width = unsafe {
+        WIDTH = WIDTH.max(module.len());
+        WIDTH
+    };
+    let (dim, module_color, (level_color, text), reset) = if in_color && unsafe { COLOR } {
+        (DIM, MODULE, color(level), RESET)
+    } else {
+        ("", "", ("", ""), "")
+    };
+    let time = format!(
+        "[{:5}.{:06}]",
+        ns / NANOS_PER_SEC,
+        ns % NANOS_PER_SEC / 1000
+    );
+    let hart = format!("h{}", hart);
+    // The lines after the first go under it.
+    let indent = format!("\r\n{:1$}", "", time.len() + hart.len() + width + 10);
+    let message = message.replace('\n', &indent);
+    format!(
+        "{}{}{} {} {}{:<5}{} {}{:<width$}{}  {}{}{}\r\n",
+        dim,
+        time,
+        reset,
+        hart,
+        level_color,
+        level,
+        reset,
+        module_color,
+        module,
+        reset,
+        text,
+        message,
+        reset,
+        width = width,
+    )
+}
+
+// ///////////////////////////////////
+// / THE SINKS
+// ///////////////////////////////////
+// Where records go, each at a level of its own, so that the display can
+// show only the warnings while the serial line gets everything, say. A
+// record is printed on the serial line and the display if it's at their
+// level and at its module's; the ring keeps it if it's at the ring's,
+// whatever its module's.
+//
+// Writing to a serial line waits when the UART's buffer is full, and at
+// 115200 baud a lot of trace goes out slower than it's made. A sink set to
+// drop throws away the records there isn't room for instead, and counts
+// them.
+//
+// With semihosting built in, there's a semihosting sink too, off until
+// it's turned on, which writes to the host's console without colour;
+// every record stops the hart while the host sees to it (see
+// semihosting.rs).
+
+/// A place records go.
+struct Sink {
+    name: &'static str,
+    level: LevelFilter,
+    /// How many bytes it takes without waiting, if it can make us wait.
+    room: Option<fn() -> usize>,
+    /// Whether records it hasn't room for are thrown away.
+    drop: bool,
+    dropped: usize,
+}
+
+const SERIAL: usize = 0;
+const DISPLAY: usize = 1;
+const RING_SINK: usize = 2;
+const SEMIHOSTING: usize = 3;
+
+static mut SINKS: [Sink; 4] = [
+    Sink {
+        name: "serial",
+        level: LevelFilter::Trace,
+        room: Some(console::log_room),
+        drop: false,
+        dropped: 0,
+    },
+    Sink {
+        name: "display",
+        level: LevelFilter::Trace,
+        room: None,
+        drop: false,
+        dropped: 0,
+    },
+    Sink {
+        name: "ring",
+        level: LevelFilter::Trace,
+        room: None,
+        drop: false,
+        dropped: 0,
+    },
+    Sink {
+        name: "semihosting",
+        level: LevelFilter::Off,
+        room: None,
+        drop: false,
+        dropped: 0,
+    },
+];
+
+fn sinks() -> &'static mut [Sink] {
+    let sinks = unsafe { &mut *addr_of_mut!(SINKS) };
+    if semihosting::present() {
+        sinks
+    } else {
+        &mut sinks[..SEMIHOSTING]
+    }
+}
+
+/// Whether the sink `sink` takes `line`, at `level`. Counts it as dropped
+/// if it's at the level but there isn't room for it.
+fn takes(sink: usize, level: Level, line: &str) -> bool {
+    let sink = &mut sinks()[sink];
+    if level > sink.level {
+        return false;
+    }
+    let room = sink.room.map_or(usize::MAX, |room| room());
+    if sink.drop && line.len() > room {
+        sink.dropped += 1;
+        return false;
+    }
+    true
+}
+
+/// Change a sink the way `spec` says: NAME:LEVEL, or NAME:off, and then
+/// :drop or :block for whether it throws away what it hasn't room for.
+fn set_sink(spec: &str) -> Result<(), SysError> {
+    let mut parts = spec.split(':');
+    let name = parts.next().unwrap_or("");
+    let sink = sinks()
+        .iter_mut()
+        .find(|s| s.name == name)
+        .ok_or(Errno(EINVAL))?;
+    let level = parts.next().ok_or(Errno(EINVAL))?;
+    let level = LevelFilter::from_str(level).map_err(|_| Errno(EINVAL))?;
+    let drop = match parts.next() {
+        None => sink.drop,
+        Some("drop") if sink.room.is_some() => true,
+        Some("block") => false,
+        Some(_) => return Err(Errno(EINVAL)),
+    };
+    if parts.next().is_some() {
+        return Err(Errno(EINVAL));
+    }
+    sink.level = level;
+    sink.drop = drop;
+    Ok(())
+}
+
+fn print_sinks() {
+    for s in sinks().iter() {
+        let how = match (s.room, s.drop) {
+            (None, _) => "",
+            (Some(_), false) => " block",
+            (Some(_), true) => " drop",
+        };
+        println!("{}: {}{}, {} dropped", s.name, s.level, how, s.dropped);
+    }
+}
+
+// ///////////////////////////////////
+// / THE RING
+// ///////////////////////////////////
+// A record takes HEADER bytes, its level, hart, the lengths of its module
+// and message (the latter in two bytes) and the time in eight, then the
+// module and the message. Where it starts is counted in bytes since boot,
+// and taken mod RING_SIZE to find it.
+
+const RING_SIZE: usize = 64 * 1024;
+const HEADER: usize = 13;
+/// The most of a message that's kept.
+const MAX_MESSAGE: usize = 1024;
+
+static mut RING: [u8; RING_SIZE] = [0; RING_SIZE];
+/// Where the oldest record starts, and where the next one goes.
+static mut HEAD: usize = 0;
+static mut TAIL: usize = 0;
+
+/// A record from the ring.
+pub struct Kept {
+    pub ns: u64,
+    pub level: Level,
+    pub hart: usize,
+    pub module: String,
+    pub message: String,
+}
+
+fn ring() -> &'static mut [u8; RING_SIZE] {
+    unsafe { &mut *addr_of_mut!(RING) }
+}
+
+fn ring_read(at: usize, buf: &mut [u8]) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = ring()[(at + i) % RING_SIZE];
+    }
+}
+
+fn ring_write(at: usize, buf: &[u8]) {
+    for (i, &b) in buf.iter().enumerate() {
+        ring()[(at + i) % RING_SIZE] = b;
+    }
+}
+
+/// The header of the record at `at`, and how long the record is.
+fn header(at: usize) -> ([u8; HEADER], usize) {
+    let mut h = [0; HEADER];
+    ring_read(at, &mut h);
+    let len = HEADER + h[2] as usize + u16::from_le_bytes([h[3], h[4]]) as usize;
+    (h, len)
+}
+
+/// Put a record in the ring, pushing out the oldest ones to make room.
+fn keep(ns: u64, level: Level, hart: usize, module: &str, message: &str) {
+    let module = &module.as_bytes()[..module.len().min(u8::MAX as usize)];
+    let message = &message.as_bytes()[..message.len().min(MAX_MESSAGE)];
+    let len = HEADER + module.len() + message.len();
+    let mut h = [0; HEADER];
+    h[0] = level as u8;
+    h[1] = hart as u8;
+    h[2] = module.len() as u8;
+    h[3..5].copy_from_slice(&(message.len() as u16).to_le_bytes());
+    h[5..].copy_from_slice(&ns.to_le_bytes());
+    cpu::without_interrupts(|| unsafe {
+        while TAIL + len - HEAD > RING_SIZE {
+            HEAD += header(HEAD).1;
+        }
+        ring_write(TAIL, &h);
+        ring_write(TAIL + HEADER, module);
+        ring_write(TAIL + HEADER + module.len(), message);
+        TAIL += len;
+    });
+}
+
+/// The records in the ring, oldest first.
+pub fn kept() -> Vec<Kept> {
+    cpu::without_interrupts(|| {
+        let mut records = Vec::new();
+        let (mut at, tail) = unsafe { (HEAD, TAIL) };
+        while at < tail {
+            let (h, len) = header(at);
+            let mut text = alloc::vec![0; len - HEADER];
+            ring_read(at + HEADER, &mut text);
+            let (module, message) = text.split_at(h[2] as usize);
+            let mut ns = [0; 8];
+            ns.copy_from_slice(&h[5..]);
+            records.push(Kept {
+                ns: u64::from_le_bytes(ns),
+                level: Level::iter()
+                    .find(|&l| l as u8 == h[0])
+                    .unwrap_or(Level::Trace),
+                hart: h[1] as usize,
+                module: String::from_utf8_lossy(module).into(),
+                message: String::from_utf8_lossy(message).into(),
+            });
+            at += len;
+        }
+        records
+    })
+}
+
+/// The last of the ring as text, the way dmesg prints it without colors,
+/// as many of the newest records as fit in `max` bytes.
+pub fn text(max: usize) -> String {
+    let mut lines = Vec::new();
+    let mut len = 0;
+    for r in kept().iter().rev() {
+        let line = format(r.ns, r.hart, r.level, &r.module, &r.message, false);
+        len += line.len();
+        if len > max {
+            break;
+        }
+        lines.push(line);
+    }
+    lines.reverse();
+    lines.concat()
+}
+
+/// The line of a panic's report being printed, and how much of it there is.
+static mut REPORT: [u8; MAX_MESSAGE] = [0; MAX_MESSAGE];
+static mut REPORT_LEN: usize = 0;
+
+/// Keep `s`, which a panic's report printed, in the ring, a line at a
+/// time. This doesn't allocate, since the heap may be what broke.
+pub fn panic_print(s: &str) {
+    let (line, len) = unsafe { (&mut *addr_of_mut!(REPORT), &mut *addr_of_mut!(REPORT_LEN)) };
+    for b in s.bytes() {
+        match b {
+            b'\r' => {}
+            b'\n' => {
+                let text = core::str::from_utf8(&line[..*len]).unwrap_or("?");
+                keep(timer::monotonic_ns(), Level::Error, hart(), "panic", text);
+                *len = 0;
+            }
+            _ if *len < MAX_MESSAGE => {
+                line[*len] = b;
+                *len += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Throw away what's in the ring.
+fn clear() {
+    cpu::without_interrupts(|| unsafe {
+        HEAD = TAIL;
+    });
+}
+
+/// The ring as /proc/kmsg has it.
+pub fn kmsg() -> String {
+    let mut s = String::new();
+    for r in kept() {
+        // syslog's priorities, which have nothing below debug.
+        let priority = match r.level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        };
+        for line in r.message.split('\n') {
+            let _ = writeln!(
+                s,
+                "<{}>[{:5}.{:06}] {}: {}",
+                priority,
+                r.ns / NANOS_PER_SEC,
+                r.ns % NANOS_PER_SEC / 1000,
+                r.module,
+                line
+            );
+        }
+    }
+    s
+}
+
+// ///////////////////////////////////
+// / THE LOGGER
+// ///////////////////////////////////
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= level_of(module(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        let ns = timer::monotonic_ns();
+        let module = module(record.target());
+        let message = format!("{}", record.args());
+        let hart = hart();
+        let level = record.level();
+        if level <= sinks()[RING_SINK].level {
+            keep(ns, level, hart, module, &message);
+        }
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format(ns, hart, level, module, &message, true);
+        let serial = takes(SERIAL, level, &line);
+        let display = takes(DISPLAY, level, &line);
+        if serial || display {
+            console::write_log(&line, serial, display);
+        }
+        if semihosting::present() && takes(SEMIHOSTING, level, &line) {
+            semihosting::write(format(ns, hart, level, module, &message, false).as_bytes());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Without arguments, say what's printed. `log sinks` says where, and
+/// `log sink NAME LEVEL [drop|block]` changes a sink.
+fn log_command(args: &[&str]) -> Result<(), SysError> {
+    match args {
+        ["sinks"] => {
+            print_sinks();
+            return Ok(());
+        }
+        ["sink", name, level] => return set_sink(&format!("{}:{}", name, level)),
+        ["sink", name, level, how] => {
+            return set_sink(&format!("{}:{}:{}", name, level, how));
+        }
+        _ => {}
+    }
+    if args.is_empty() {
+        println!("everything: {}", unsafe { LEVEL });
+        for (name, level) in modules().iter() {
+            println!("{}: {}", name, level);
+        }
+        return Ok(());
+    }
+    for arg in args {
+        set_levels(arg)?;
+    }
+    Ok(())
+}
+
+/// Print what's in the ring, the records at LEVEL and up with -l, and
+/// then throw it away with -c.
+fn dmesg_command(args: &[&str]) -> Result<(), SysError> {
+    let mut clear_after = false;
+    let mut least = LevelFilter::Trace;
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "-c" => clear_after = true,
+            "-l" => {
+                let level = args.next().ok_or(Errno(EINVAL))?;
+                least = LevelFilter::from_str(level).map_err(|_| Errno(EINVAL))?;
+            }
+            _ => return Err(Errno(EINVAL)),
+        }
+    }
+    shell::paged(|| {
+        for r in kept().iter().filter(|r| r.level <= least) {
+            print!(
+                "{}",
+                format(r.ns, r.hart, r.level, &r.module, &r.message, true)
+            );
+        }
+    });
+    if clear_after {
+        clear();
+    }
+    Ok(())
+}
+
+/// Start logging, at the levels the kernel command line asks for. This has
+/// to come after the heap and the device tree are set up.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+    // Everything is kept, whatever's printed.
+    log::set_max_level(LevelFilter::Trace);
+    let bootargs = fdt::get()
+        .and_then(|f| f.find("/chosen"))
+        .and_then(|c| c.str_property("bootargs"))
+        .unwrap_or("");
+    let arg = |key: &str| {
+        bootargs
+            .split_ascii_whitespace()
+            .find_map(|arg| arg.strip_prefix(key))
+    };
+    if let Some(spec) = arg("loglevel=") {
+        if set_levels(spec).is_err() {
+            println!("klog: loglevel={} isn't levels", spec);
+        }
+    }
+    for spec in arg("logsink=")
+        .unwrap_or("")
+        .split(',')
+        .filter(|s| !s.is_empty())
+    {
+        if set_sink(spec).is_err() {
+            println!("klog: logsink={} isn't NAME:LEVEL[:drop|:block]", spec);
+        }
+    }
+    let dumb = arg("TERM=") == Some("dumb");
+    match arg("logcolor=") {
+        None | Some("auto") => console::set_log_ansi(!dumb),
+        Some("always") => console::set_log_ansi(true),
+        Some("never") => unsafe { COLOR = false },
+        Some(other) => println!("klog: logcolor={} isn't auto, always or never", other),
+    }
+    shell::register(&Builtin {
+        name: "log",
+        help: "log [LEVEL|MODULE=[LEVEL]]... | sinks | sink NAME LEVEL [drop|block] - say or change what's logged, and where",
+        run: log_command,
+    });
+    shell::register(&Builtin {
+        name: "dmesg",
+        help: "dmesg [-c] [-l LEVEL] - print the kernel log kept in memory",
+        run: dmesg_command,
+    });
+}
@@ -0,0 +1,254 @@
+// Sub-page level allocation for the kernel. The page allocator only hands
+// out whole pages, which is a lot of waste for things like a process
+// structure or a small Vec. Instead, we grab a chunk of pages once and then
+// split it up with a simple linked list of allocations.
+
+use crate::{
+    bcache,
+    ktrace::{self, Kind},
+    page::{self, align_val, PAGE_ORDER, PAGE_SIZE},
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
+    ptr::null_mut,
+};
+
+// The top bit of AllocList::flags_size marks a chunk as taken.
+const TAKEN: usize = 1 << (usize::BITS - 1);
+
+// Every allocation is prefixed by one of these. The top bit tells us if the
+// chunk is taken, the rest is the size of the chunk including this header.
+struct AllocList {
+    pub flags_size: usize,
+}
+
+impl AllocList {
+    pub fn is_taken(&self) -> bool {
+        self.flags_size & TAKEN != 0
+    }
+
+    pub fn is_free(&self) -> bool {
+        !self.is_taken()
+    }
+
+    pub fn set_taken(&mut self) {
+        self.flags_size |= TAKEN;
+    }
+
+    pub fn set_free(&mut self) {
+        self.flags_size &= !TAKEN;
+    }
+
+    pub fn set_size(&mut self, sz: usize) {
+        let k = self.is_taken();
+        self.flags_size = sz & !TAKEN;
+        if k {
+            self.flags_size |= TAKEN;
+        }
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.flags_size & !TAKEN
+    }
+}
+
+// This is the head of the allocation. We start here when
+// we search for a free memory location.
+static mut KMEM_HEAD: *mut AllocList = null_mut();
+// In the future, we will have on-demand pages
+// so, we need to keep track of our memory footprint to
+// see if we actually need to allocate more.
+static mut KMEM_ALLOC: usize = 0;
+
+/// The number of pages we reserve for the kernel heap.
+const KMEM_PAGES: usize = 512;
+
+/// Initialize kernel's memory
+/// This is not to be used to allocate memory
+/// for user processes. If that's the case, use
+/// alloc/dealloc from the page crate.
+pub fn init() {
+    unsafe {
+        // Allocate kernel pages (KMEM_ALLOC)
+        KMEM_ALLOC = KMEM_PAGES;
+        let k_alloc = page::zalloc(KMEM_ALLOC);
+        assert!(!k_alloc.is_null());
+        KMEM_HEAD = k_alloc as *mut AllocList;
+        (*KMEM_HEAD).set_free();
+        (*KMEM_HEAD).set_size(KMEM_ALLOC * PAGE_SIZE);
+    }
+}
+
+/// Allocate sub-page level allocation based on bytes and zero the memory
+pub fn kzmalloc(sz: usize) -> *mut u8 {
+    let size = align_val(sz, 3);
+    let ret = kmalloc(size);
+
+    if !ret.is_null() {
+        for i in 0..size {
+            unsafe {
+                (*ret.add(i)) = 0;
+            }
+        }
+    }
+    ret
+}
+
+/// Allocate sub-page level allocation based on bytes
+pub fn kmalloc(sz: usize) -> *mut u8 {
+    unsafe {
+        let size = align_val(sz, 3) + size_of::<AllocList>();
+        let mut head = KMEM_HEAD;
+        // .add() uses pointer arithmetic, so we type-cast into a u8
+        // so that we multiply by an absolute size (KMEM_ALLOC *
+        // PAGE_SIZE).
+        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
+
+        while head < tail {
+            if (*head).is_free() && size <= (*head).get_size() {
+                let chunk_size = (*head).get_size();
+                let rem = chunk_size - size;
+                (*head).set_taken();
+                if rem > size_of::<AllocList>() {
+                    let next = (head as *mut u8).add(size) as *mut AllocList;
+                    // There is space remaining here.
+                    (*next).set_free();
+                    (*next).set_size(rem);
+                    (*head).set_size(size);
+                } else {
+                    // If we get here, take the entire chunk
+                    (*head).set_size(chunk_size);
+                }
+                ktrace::record(Kind::Kmalloc, head.add(1) as usize, sz);
+                return head.add(1) as *mut u8;
+            } else {
+                // If we get here, what we saw wasn't a free
+                // chunk, move on to the next.
+                head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+            }
+        }
+    }
+    // If we get here, we didn't find any free chunks--i.e. there isn't
+    // enough memory for this. TODO: Add on-demand page allocation.
+    null_mut()
+}
+
+/// Free a sub-page level allocation
+pub fn kfree(ptr: *mut u8) {
+    unsafe {
+        if !ptr.is_null() {
+            ktrace::record(Kind::Kfree, ptr as usize, 0);
+            let p = (ptr as *mut AllocList).offset(-1);
+            if (*p).is_taken() {
+                (*p).set_free();
+            }
+            // After we free, see if we can combine adjacent free
+            // spots to see if we can reduce fragmentation.
+            coalesce();
+        }
+    }
+}
+
+/// Merge smaller chunks into a bigger chunk
+pub fn coalesce() {
+    unsafe {
+        let mut head = KMEM_HEAD;
+        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
+
+        while head < tail {
+            let next = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+            if (*head).get_size() == 0 {
+                // If this happens, then we have a bad heap
+                // (double free or something). However, that
+                // will cause an infinite loop since the next
+                // pointer will never move beyond the current
+                // location.
+                break;
+            } else if next >= tail {
+                // We calculated the next by using the size
+                // given as get_size(), however this could push
+                // us past the tail. In that case, the size is
+                // wrong, hence we break and stop doing what we
+                // need to do.
+                break;
+            } else if (*head).is_free() && (*next).is_free() {
+                // This means we have adjacent blocks needing to
+                // be freed. So, we combine them into one
+                // allocation.
+                (*head).set_size((*head).get_size() + (*next).get_size());
+            } else {
+                // If we get here, we might've moved. Recalculate new
+                // head.
+                head = next;
+            }
+        }
+    }
+}
+
+/// How big the kernel heap is, and how much of it is taken, in bytes.
+pub fn stats() -> (usize, usize) {
+    let mut taken = 0;
+    unsafe {
+        let mut head = KMEM_HEAD;
+        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
+        while head < tail && (*head).get_size() != 0 {
+            if (*head).is_taken() {
+                taken += (*head).get_size();
+            }
+            head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+        }
+        (KMEM_ALLOC * PAGE_SIZE, taken)
+    }
+}
+
+// ///////////////////////////////////
+// / GLOBAL ALLOCATOR
+// ///////////////////////////////////
+
+// The global allocator allows us to use the data structures
+// in the core library, such as a linked list or B-tree.
+// We want to use these sparingly since we have a coarse-grained
+// allocator.
+
+// The global allocator is a static constant to a global allocator
+// structure. We don't need any members because we're using this
+// structure just to implement alloc and dealloc.
+struct OsGlobalAlloc;
+
+fn alloc_layout(layout: Layout) -> *mut u8 {
+    // kmalloc only guarantees 8-byte alignment. Anything that needs
+    // more than that gets whole pages, which are aligned to
+    // PAGE_SIZE.
+    if layout.align() > 8 {
+        assert!(layout.align() <= PAGE_SIZE);
+        page::zalloc(align_val(layout.size().max(1), PAGE_ORDER) / PAGE_SIZE)
+    } else {
+        kzmalloc(layout.size())
+    }
+}
+
+unsafe impl GlobalAlloc for OsGlobalAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = alloc_layout(layout);
+        // Out of memory. The block cache can give some back.
+        if ptr.is_null() && bcache::reclaim() > 0 {
+            return alloc_layout(layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if layout.align() > 8 {
+            page::dealloc(ptr);
+        } else {
+            kfree(ptr);
+        }
+    }
+}
+
+#[global_allocator]
+/// Technically, we don't need the {} at the end, but it
+/// reveals that we're creating a new structure and not just
+/// copying a value.
+static GA: OsGlobalAlloc = OsGlobalAlloc {};
@@ -0,0 +1,36 @@
+// The kernel's own symbol table, so that a backtrace can say which
+// functions it went through instead of just where (see panic.rs). It's a
+// text file, one function a line, sorted by address:
+//
+//   0000000080001234 rust_riscv_os::page::zalloc
+//
+// linked into its own section after the code (build with the ksyms feature
+// and KSYMS set to the file). The table can only be made from a kernel
+// that's already linked, so ksyms.sh builds twice: once with an empty
+// table, then again with the functions nm finds in that one. Only what
+// comes after the code changes size in between, so the functions stay
+// where they were, and the table is right for the second kernel too.
+//
+// Without the feature the table is empty, and backtraces are addresses.
+
+/// The table linked into the kernel.
+#[cfg(feature = "ksyms")]
+#[link_section = ".ksyms"]
+static TABLE: [u8; include_bytes!(env!("KSYMS")).len()] = *include_bytes!(env!("KSYMS"));
+#[cfg(not(feature = "ksyms"))]
+static TABLE: [u8; 0] = [];
+
+/// The lines of the table, as addresses and names.
+fn symbols() -> impl Iterator<Item = (usize, &'static str)> {
+    let text = core::str::from_utf8(&TABLE).unwrap_or("");
+    text.lines().filter_map(|line| {
+        let (addr, name) = line.split_once(' ')?;
+        Some((usize::from_str_radix(addr, 16).ok()?, name))
+    })
+}
+
+/// The function `addr` is in, and how far into it, if the table has it.
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    let (start, name) = symbols().take_while(|&(a, _)| a <= addr).last()?;
+    Some((name, addr - start))
+}
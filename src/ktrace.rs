@@ -0,0 +1,321 @@
+// Tracepoints: a record of what the kernel did and when, for problems of
+// ordering and latency that printing would change, since printing a line
+// takes longer than most of what there is to see. The places worth
+// watching call record() with a kind of event and two words that go with
+// it; that's one check of the mask of categories being traced when
+// nothing is, and writing a few words to this hart's ring when something
+// is. The rings keep the last EVENTS events of each hart, binary, with
+// the time by mtime, so that they can be put back in order across harts,
+// and the pid of what was running.
+//
+// What's traced, by category:
+//
+//   sched    the scheduler picking the next process to switch to
+//   syscall  system calls going in and coming out, or blocking
+//   irq      traps for interrupts going in and coming out, and each PLIC
+//            interrupt handled in between; a timer interrupt that ends a
+//            time slice switches away without coming out
+//   alloc    pages and kernel heap memory being taken and given back
+//
+// Nothing is traced until ktrace= on the kernel command line names the
+// categories, ktrace=sched,irq or ktrace=all, or the shell's ktrace
+// command turns some on. ktrace dump decodes what's in the rings, a line
+// an event, with how long after the one before it came, and tracing stops
+// while it does, so that it doesn't see itself.
+
+use crate::{
+    cpu::{self, TrapFrame, MAX_HARTS},
+    fdt,
+    shell::{self, Builtin},
+    strace,
+    syscall::{SysError, EINVAL},
+    timer::{self, NANOS_PER_SEC},
+};
+use alloc::vec::Vec;
+use core::ptr::addr_of_mut;
+
+use SysError::Errno;
+
+/// How many events a hart's ring keeps.
+const EVENTS: usize = 512;
+
+// Categories, as bits of MASK.
+const SCHED: u8 = 1 << 0;
+const SYSCALL: u8 = 1 << 1;
+const IRQ: u8 = 1 << 2;
+const ALLOC: u8 = 1 << 3;
+
+const CATEGORIES: [(&str, u8); 4] = [
+    ("sched", SCHED),
+    ("syscall", SYSCALL),
+    ("irq", IRQ),
+    ("alloc", ALLOC),
+];
+
+/// What happened, and what the two words recorded with it are.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    /// Switching to the process a (0 is idling).
+    Switch,
+    /// System call a, with b as its first argument.
+    SyscallEnter,
+    /// System call a came back with b.
+    SyscallExit,
+    /// System call a has to wait.
+    SyscallBlock,
+    /// A trap for interrupt a, mcause's code.
+    IrqEnter,
+    IrqExit,
+    /// PLIC interrupt a, being handled.
+    Irq,
+    /// b pages at a.
+    PageAlloc,
+    /// The pages at a.
+    PageFree,
+    /// b bytes at a.
+    Kmalloc,
+    /// The bytes at a.
+    Kfree,
+}
+
+impl Kind {
+    fn category(self) -> u8 {
+        match self {
+            Kind::Switch => SCHED,
+            Kind::SyscallEnter | Kind::SyscallExit | Kind::SyscallBlock => SYSCALL,
+            Kind::IrqEnter | Kind::IrqExit | Kind::Irq => IRQ,
+            Kind::PageAlloc | Kind::PageFree | Kind::Kmalloc | Kind::Kfree => ALLOC,
+        }
+    }
+}
+
+/// An event as it's kept.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Event {
+    /// mtime when it happened.
+    time: u64,
+    /// What was running.
+    pid: u32,
+    kind: Kind,
+    hart: u8,
+    a: usize,
+    b: usize,
+}
+
+/// A hart's events, the last EVENTS of the `written` there have been.
+struct Ring {
+    events: [Event; EVENTS],
+    written: usize,
+}
+
+const EMPTY: Ring = Ring {
+    events: [Event {
+        time: 0,
+        pid: 0,
+        kind: Kind::Switch,
+        hart: 0,
+        a: 0,
+        b: 0,
+    }; EVENTS],
+    written: 0,
+};
+
+static mut RINGS: [Ring; MAX_HARTS] = [EMPTY; MAX_HARTS];
+/// The categories being traced.
+static mut MASK: u8 = 0;
+
+fn rings() -> &'static mut [Ring; MAX_HARTS] {
+    unsafe { &mut *addr_of_mut!(RINGS) }
+}
+
+/// Record that `kind` happened, with `a` and `b`, if it's being traced.
+#[inline]
+pub fn record(kind: Kind, a: usize, b: usize) {
+    if unsafe { MASK } & kind.category() == 0 {
+        return;
+    }
+    let (pid, hart) = match cpu::mscratch_read() {
+        0 => (0, 0),
+        frame => unsafe {
+            let frame = &*(frame as *const TrapFrame);
+            (frame.pid, frame.hartid)
+        },
+    };
+    let Some(ring) = rings().get_mut(hart) else {
+        return;
+    };
+    cpu::without_interrupts(|| {
+        ring.events[ring.written % EVENTS] = Event {
+            time: cpu::get_mtime(),
+            pid: pid as u32,
+            kind,
+            hart: hart as u8,
+            a,
+            b,
+        };
+        ring.written += 1;
+    });
+}
+
+/// Which categories `spec` names, separated by commas, or None if it
+/// names one there isn't.
+fn parse_mask(spec: &str) -> Option<u8> {
+    let mut mask = 0;
+    for name in spec.split(',').filter(|n| !n.is_empty()) {
+        mask |= match name {
+            "all" => SCHED | SYSCALL | IRQ | ALLOC,
+            _ => CATEGORIES.iter().find(|(n, _)| *n == name)?.1,
+        };
+    }
+    Some(mask)
+}
+
+// ///////////////////////////////////
+// / DECODING
+// ///////////////////////////////////
+
+/// A copy of the events in the rings, oldest first.
+fn events() -> Vec<Event> {
+    let mut all = Vec::new();
+    for ring in rings().iter() {
+        let kept = ring.written.min(EVENTS);
+        for i in ring.written - kept..ring.written {
+            all.push(ring.events[i % EVENTS]);
+        }
+    }
+    // Stable, so events of one hart at the same tick stay in order.
+    all.sort_by_key(|e| e.time);
+    all
+}
+
+/// What mcause calls interrupt `cause`.
+fn irq_name(cause: usize) -> &'static str {
+    match cause {
+        3 => "software",
+        7 => "timer",
+        11 => "external",
+        _ => "?",
+    }
+}
+
+fn syscall_name(number: usize) -> &'static str {
+    strace::name(number).unwrap_or("?")
+}
+
+/// Print `e`, which came `delta` nanoseconds after the event before it.
+fn print_event(e: &Event, delta: u64) {
+    let (secs, nanos) = timer::ticks_to_duration(e.time);
+    print!(
+        "{:5}.{:06} +{:>9} h{} p{:<3} ",
+        secs,
+        nanos / 1000,
+        delta,
+        e.hart,
+        e.pid
+    );
+    let (a, b) = (e.a, e.b);
+    match e.kind {
+        Kind::Switch => println!("switch -> {}", a),
+        Kind::SyscallEnter => println!("syscall {}(0x{:x})", syscall_name(a), b),
+        Kind::SyscallExit => println!("syscall {} = {}", syscall_name(a), b as isize),
+        Kind::SyscallBlock => println!("syscall {} blocks", syscall_name(a)),
+        Kind::IrqEnter => println!("irq {} in", irq_name(a)),
+        Kind::IrqExit => println!("irq {} out", irq_name(a)),
+        Kind::Irq => println!("plic {}", a),
+        Kind::PageAlloc => println!("page alloc 0x{:x} {}", a, b),
+        Kind::PageFree => println!("page free 0x{:x}", a),
+        Kind::Kmalloc => println!("kmalloc 0x{:x} {}", a, b),
+        Kind::Kfree => println!("kfree 0x{:x}", a),
+    }
+}
+
+// ///////////////////////////////////
+// / SHELL COMMAND
+// ///////////////////////////////////
+
+fn print_status() {
+    let mask = unsafe { MASK };
+    let on: Vec<&str> = CATEGORIES
+        .iter()
+        .filter(|(_, bit)| mask & bit != 0)
+        .map(|(name, _)| *name)
+        .collect();
+    if on.is_empty() {
+        println!("tracing nothing");
+    } else {
+        println!("tracing {}", on.join(","));
+    }
+    for (hart, ring) in rings().iter().enumerate().filter(|(_, r)| r.written > 0) {
+        println!(
+            "hart {}: {} events, {} kept",
+            hart,
+            ring.written,
+            ring.written.min(EVENTS)
+        );
+    }
+}
+
+/// `ktrace` says what's traced, `ktrace on [CATEGORIES]` and `ktrace off
+/// [CATEGORIES]` change it, `ktrace clear` empties the rings, and `ktrace
+/// dump [COUNT]` prints the last COUNT events, or all of them.
+fn ktrace_command(args: &[&str]) -> Result<(), SysError> {
+    let mask = unsafe { &mut *addr_of_mut!(MASK) };
+    match *args {
+        [] => print_status(),
+        ["on"] => *mask = parse_mask("all").unwrap(),
+        ["on", spec] => *mask |= parse_mask(spec).ok_or(Errno(EINVAL))?,
+        ["off"] => *mask = 0,
+        ["off", spec] => *mask &= !parse_mask(spec).ok_or(Errno(EINVAL))?,
+        ["clear"] => {
+            for ring in rings().iter_mut() {
+                ring.written = 0;
+            }
+        }
+        ["dump"] | ["dump", _] => {
+            let was = *mask;
+            *mask = 0;
+            let events = events();
+            let count = match args.get(1) {
+                Some(count) => shell::number(count)?,
+                None => events.len(),
+            };
+            let from = events.len().saturating_sub(count);
+            shell::paged(|| {
+                let mut last = events.get(from).map_or(0, |e| e.time);
+                for e in &events[from..] {
+                    let (secs, nanos) = timer::ticks_to_duration(e.time - last);
+                    print_event(e, secs * NANOS_PER_SEC + nanos);
+                    last = e.time;
+                }
+            });
+            *mask = was;
+        }
+        _ => return Err(Errno(EINVAL)),
+    }
+    Ok(())
+}
+
+/// Start tracing what the kernel command line says, and register the
+/// ktrace command.
+pub fn init() {
+    let spec = fdt::get()
+        .and_then(|f| f.find("/chosen"))
+        .and_then(|c| c.str_property("bootargs"))
+        .and_then(|b| {
+            b.split_ascii_whitespace()
+                .find_map(|arg| arg.strip_prefix("ktrace="))
+        });
+    if let Some(spec) = spec {
+        match parse_mask(spec) {
+            Some(mask) => unsafe { MASK = mask },
+            None => println!("ktrace: ktrace={} isn't categories", spec),
+        }
+    }
+    shell::register(&Builtin {
+        name: "ktrace",
+        help: "ktrace [on|off [CATEGORIES]|clear|dump [COUNT]] - trace events in the kernel",
+        run: ktrace_command,
+    });
+}
@@ -0,0 +1,383 @@
+// Lock dependency checking in debug builds: lockdep, as Linux calls it,
+// but a lot less of it. A deadlock is two processes that each wait for
+// what the other holds, and the order things are taken in tells
+// beforehand whether it can happen: if one waits for B while holding A,
+// and another ever waited for A while holding B, they can end up waiting
+// for each other, even if they haven't run at the same time yet. So every
+// time a process waits for something while holding something else, the
+// pair goes into the order, and one that goes against it is reported with
+// both chains: what this process does, and what was seen before that it
+// goes the other way round from.
+//
+// The kernel itself holds nothing while a process waits: a syscall that
+// has to wait answers Block and starts over when it's woken. What's held
+// across waits is what processes hold, and what they wait for. The kinds
+// of it, the classes, are:
+//
+//   file locks   write locks taken with flock() or fcntl() (see locks.rs),
+//                a class for each file and kind. Read locks go together,
+//                so they're left out.
+//   wait queues  a class for each name queues are made with, so all the
+//                pipes' readers are one. Waiting on one while holding a
+//                lock puts the queue after the lock. Waking it from a
+//                syscall while holding one puts the lock after the queue,
+//                since whoever waits there may have to wait until the
+//                waker got the lock.
+//
+// Interrupts-off sections keep interrupt handlers, and the switch to
+// another process, out of something half done. They aren't in the order,
+// a hart can't wait for itself, but each hart's are kept like held locks,
+// with where they were opened, and go into the reports. The wait queues
+// and the file locks are shared with the interrupt handlers and the
+// syscalls of other processes, so touching them with interrupts on is
+// reported too.
+//
+// The first report turns the checking off, since what comes after it may
+// only be because of it, and so does running out of room for pairs.
+
+use crate::{
+    cpu::{self, MAX_HARTS},
+    locks::{self, Key, Owner},
+};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::{fmt, panic::Location, ptr::addr_of_mut};
+use log::error;
+
+/// A kind of thing that's waited for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Class {
+    /// The wait queues made with this name.
+    Queue(&'static str),
+    /// A write lock taken with flock() on the file.
+    Flock(Key),
+    /// A write lock taken with fcntl() on some of the file.
+    Fcntl(Key),
+}
+
+impl Class {
+    /// The class of a write lock of `owner` on the file `key`.
+    pub fn file_lock(key: Key, owner: Owner) -> Class {
+        match owner {
+            Owner::File(_) => Class::Flock(key),
+            Owner::Process(_) => Class::Fcntl(key),
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Class::Queue(name) => write!(f, "the {} queue", name),
+            Class::Flock((dev, ino)) => write!(f, "flock() of inode {} on {:x}", ino, dev),
+            Class::Fcntl((dev, ino)) => write!(f, "fcntl() lock of inode {} on {:x}", ino, dev),
+        }
+    }
+}
+
+/// How a pair of classes got into the order: `pid` waited for the second
+/// while holding the first, or, if `woke`, woke the first, a queue, while
+/// holding the second.
+#[derive(Debug)]
+struct Edge {
+    pid: usize,
+    woke: bool,
+    at: &'static Location<'static>,
+}
+
+/// A pair of the order, to report.
+struct Step<'a>(Class, Class, &'a Edge);
+
+impl fmt::Display for Step<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Step(from, to, e) = self;
+        if e.woke {
+            write!(f, "pid {} woke {} holding {}, at {}", e.pid, from, to, e.at)
+        } else {
+            write!(
+                f,
+                "pid {} waited for {} holding {}, at {}",
+                e.pid, to, from, e.at
+            )
+        }
+    }
+}
+
+/// How many pairs there's room for.
+const MAX_EDGES: usize = 512;
+
+static mut ORDER: BTreeMap<(Class, Class), Edge> = BTreeMap::new();
+static mut OFF: bool = false;
+/// The process whose syscall is running, if one is.
+static mut CURRENT: Option<usize> = None;
+
+/// How deep the interrupts-off sections of a hart are kept track of.
+pub const MAX_SECTIONS: usize = 32;
+/// Where each hart opened the interrupts-off sections it's in, and how
+/// many it's in, which can be more than are kept.
+static mut SECTIONS: [[Option<&'static Location<'static>>; MAX_SECTIONS]; MAX_HARTS] =
+    [[None; MAX_SECTIONS]; MAX_HARTS];
+static mut DEPTH: [usize; MAX_HARTS] = [0; MAX_HARTS];
+
+fn order() -> &'static mut BTreeMap<(Class, Class), Edge> {
+    unsafe { &mut *addr_of_mut!(ORDER) }
+}
+
+fn checking() -> bool {
+    cfg!(debug_assertions) && unsafe { !*addr_of_mut!(OFF) }
+}
+
+/// A syscall of `pid` is running from now on, or with None, none is.
+pub fn set_current(pid: Option<usize>) {
+    unsafe {
+        *addr_of_mut!(CURRENT) = pid;
+    }
+}
+
+/// This hart opened an interrupts-off section at `at`. Returns how many
+/// it's in now.
+pub fn section_opened(at: &'static Location<'static>) -> usize {
+    let hart = cpu::mhartid_read() % MAX_HARTS;
+    unsafe {
+        let depth = &mut (*addr_of_mut!(DEPTH))[hart];
+        if let Some(s) = (*addr_of_mut!(SECTIONS))[hart].get_mut(*depth) {
+            *s = Some(at);
+        }
+        *depth += 1;
+        *depth
+    }
+}
+
+/// This hart left the innermost interrupts-off section it was in.
+pub fn section_closed() {
+    let hart = cpu::mhartid_read() % MAX_HARTS;
+    unsafe {
+        let depth = &mut (*addr_of_mut!(DEPTH))[hart];
+        *depth -= 1;
+        if let Some(s) = (*addr_of_mut!(SECTIONS))[hart].get_mut(*depth) {
+            *s = None;
+        }
+    }
+}
+
+/// Where this hart opened the interrupts-off sections it's in that are
+/// kept, the outermost first.
+fn sections() -> impl Iterator<Item = &'static Location<'static>> {
+    let hart = cpu::mhartid_read() % MAX_HARTS;
+    unsafe { (*addr_of_mut!(SECTIONS))[hart].iter().map_while(|s| *s) }
+}
+
+/// The classes `pid` holds.
+fn held(pid: usize) -> Vec<Class> {
+    let mut held: Vec<Class> = locks::held(pid)
+        .map(|(key, owner)| Class::file_lock(key, owner))
+        .collect();
+    held.sort();
+    held.dedup();
+    held
+}
+
+/// `pid` waits for `class`, or takes it, a lock, where it might have had
+/// to wait.
+#[track_caller]
+pub fn wait(pid: usize, class: Class) {
+    if !checking() {
+        return;
+    }
+    let at = Location::caller();
+    if !interrupts_off(Some(pid), "waiting for", class, at) {
+        return;
+    }
+    for from in held(pid).into_iter().filter(|&c| c != class) {
+        if !note(
+            from,
+            class,
+            Edge {
+                pid,
+                woke: false,
+                at,
+            },
+        ) {
+            return;
+        }
+    }
+}
+
+/// The queues called `name` are woken. Whether from an interrupt handler
+/// or a syscall, it's with interrupts off, and in a syscall, what the
+/// process holds goes after the queue.
+#[track_caller]
+pub fn woke(name: &'static str) {
+    if !checking() {
+        return;
+    }
+    let at = Location::caller();
+    let pid = unsafe { *addr_of_mut!(CURRENT) };
+    let class = Class::Queue(name);
+    if !interrupts_off(pid, "waking", class, at) {
+        return;
+    }
+    let Some(pid) = pid else {
+        return;
+    };
+    for to in held(pid) {
+        if !note(
+            class,
+            to,
+            Edge {
+                pid,
+                woke: true,
+                at,
+            },
+        ) {
+            return;
+        }
+    }
+}
+
+/// Report `class` being waited for or woken with interrupts on. Returns
+/// whether they're off.
+fn interrupts_off(pid: Option<usize>, doing: &str, class: Class, at: &Location) -> bool {
+    if !cpu::interrupts_on() {
+        return true;
+    }
+    error!("lockdep: {} {} with interrupts on, at {}", doing, class, at);
+    match pid {
+        Some(pid) => report_held(pid),
+        None => error!("  outside of any syscall"),
+    }
+    stop();
+    false
+}
+
+/// Put `from` before `to` in the order, or report that it goes against
+/// it. Returns whether to go on checking.
+fn note(from: Class, to: Class, edge: Edge) -> bool {
+    if order().len() >= MAX_EDGES && !order().contains_key(&(from, to)) {
+        error!("lockdep: no room for more than {} pairs", MAX_EDGES);
+        stop();
+        return false;
+    }
+    let Err((edge, chain)) = put(from, to, edge) else {
+        return true;
+    };
+    error!("lockdep: possible deadlock");
+    error!("  {}", Step(from, to, &edge));
+    report_held(edge.pid);
+    error!("but before, the other way round:");
+    for (f, t) in chain {
+        error!("  {}", Step(f, t, &order()[&(f, t)]));
+    }
+    stop();
+    false
+}
+
+/// Put `from` before `to` in the order, unless there's a chain of pairs
+/// of it that goes from `to` to `from`, which is given back instead.
+fn put(from: Class, to: Class, edge: Edge) -> Result<(), (Edge, Vec<(Class, Class)>)> {
+    if order().contains_key(&(from, to)) {
+        return Ok(());
+    }
+    if let Some(chain) = chain(to, from) {
+        return Err((edge, chain));
+    }
+    order().insert((from, to), edge);
+    Ok(())
+}
+
+/// A chain of pairs of the order that goes from `start` to `end`.
+fn chain(start: Class, end: Class) -> Option<Vec<(Class, Class)>> {
+    // Where each class was first got to from.
+    let mut from = BTreeMap::new();
+    let mut todo = vec![start];
+    while let Some(c) = todo.pop() {
+        if c == end {
+            let mut chain = Vec::new();
+            let mut at = end;
+            while at != start {
+                let prev = from[&at];
+                chain.push((prev, at));
+                at = prev;
+            }
+            chain.reverse();
+            return Some(chain);
+        }
+        for &(_, next) in order().keys().filter(|(f, _)| *f == c) {
+            if next != start && !from.contains_key(&next) {
+                from.insert(next, c);
+                todo.push(next);
+            }
+        }
+    }
+    None
+}
+
+/// Say what `pid` holds, and which interrupts-off sections this hart is
+/// in.
+fn report_held(pid: usize) {
+    for c in held(pid) {
+        error!("  pid {} holds {}", pid, c);
+    }
+    for at in sections() {
+        error!("  in the interrupts-off section opened at {}", at);
+    }
+}
+
+fn stop() {
+    error!("lockdep: that's all, it's off now");
+    unsafe {
+        *addr_of_mut!(OFF) = true;
+    }
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::check;
+    use alloc::{format, string::String};
+
+    /// Waiting for two locks the other way round from before is caught,
+    /// with the chain it goes against, here through a queue. The classes
+    /// are made up, and the pairs are put in directly, so that nothing is
+    /// reported.
+    #[test_case]
+    fn order_test() -> Result<(), String> {
+        let (a, b) = (Class::Fcntl((u64::MAX, 1)), Class::Flock((u64::MAX, 2)));
+        let q = Class::Queue("lockdep test");
+        let edge = |woke| Edge {
+            pid: 1_000_001,
+            woke,
+            at: Location::caller(),
+        };
+        check(put(a, q, edge(false)).is_ok(), || "a before q".into())?;
+        check(put(q, b, edge(true)).is_ok(), || "q before b".into())?;
+        let got = put(b, a, edge(false));
+        check(
+            matches!(&got, Err((_, chain)) if chain[..] == [(a, q), (q, b)]),
+            || format!("b before a gave {:?}", got),
+        )?;
+        order().retain(|&(f, t), _| ![f, t].iter().any(|c| [a, b, q].contains(c)));
+        Ok(())
+    }
+
+    /// The sections a hart is in are kept, the innermost last.
+    #[test_case]
+    fn sections_test() -> Result<(), String> {
+        let before = sections().count();
+        let (inner, count) = cpu::without_interrupts(|| {
+            cpu::without_interrupts(|| (sections().last(), sections().count()))
+        });
+        check(count == before + 2, || {
+            format!("{} sections kept in 2 more than {}", count, before)
+        })?;
+        check(inner.is_some_and(|at| at.file() == file!()), || {
+            format!("the inner section is from {:?}", inner)
+        })?;
+        check(sections().count() == before, || {
+            "the sections are still kept once left".into()
+        })
+    }
+}
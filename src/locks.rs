@@ -0,0 +1,306 @@
+// Advisory file locks, which only keep out whoever asks for a lock too, so
+// that programs working on the same file can take turns. There are the two
+// kinds Linux has, which don't see each other:
+//
+//   flock()   a shared or exclusive lock on the whole file, held by the
+//             open file: by every file descriptor dup()ed from the one that
+//             took it. It goes when it's unlocked or the last of them is
+//             closed.
+//   fcntl()   read and write locks on ranges of bytes (F_SETLK), held by
+//             the process, which can lock and unlock parts of what it
+//             holds, and whose locks on a file all go when it closes any
+//             file descriptor of the file, or exits, as POSIX has it.
+//
+// A file is known by its device and inode numbers, so only files that have
+// an inode, the ones on filesystems, can be locked: pipes, sockets and
+// devices have none to tell one from another by. Read locks go together,
+// a write lock goes with nothing of someone else's. Waiting for a lock
+// (flock() without LOCK_NB, F_SETLKW) is waiting until a lock goes, and
+// then trying again. One that would never come, because whoever has the
+// lock is waiting for one of ours, fails with EDEADLK instead.
+
+use crate::{
+    lockdep::{self, Class},
+    process::WaitQueue,
+    syscall::{Stat, SysError, EAGAIN, EDEADLK, EINVAL},
+};
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ptr::addr_of_mut;
+
+use SysError::{Block, Errno};
+
+// struct flock's l_type
+pub const F_RDLCK: i16 = 0;
+pub const F_WRLCK: i16 = 1;
+pub const F_UNLCK: i16 = 2;
+
+// flock() operations
+pub const LOCK_SH: usize = 1;
+pub const LOCK_EX: usize = 2;
+pub const LOCK_NB: usize = 4;
+pub const LOCK_UN: usize = 8;
+
+/// Who holds a lock.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    /// An open file, by its address, for flock().
+    File(usize),
+    /// A process, for the locks of fcntl().
+    Process(usize),
+}
+
+impl Owner {
+    fn same_kind(self, other: Owner) -> bool {
+        matches!(
+            (self, other),
+            (Owner::File(_), Owner::File(_)) | (Owner::Process(_), Owner::Process(_))
+        )
+    }
+}
+
+/// A file, by its device and inode numbers.
+pub type Key = (u64, u64);
+
+/// The file `st` describes, if it's one that can be locked.
+pub fn key(st: &Stat) -> Result<Key, SysError> {
+    if st.st_ino == 0 {
+        return Err(Errno(EINVAL));
+    }
+    Ok((st.st_dev, st.st_ino))
+}
+
+/// A lock on the bytes from `start` up to `end`, u64::MAX for up to
+/// wherever the file ends.
+#[derive(Clone, Copy)]
+pub struct Lock {
+    pub owner: Owner,
+    /// The process that took it.
+    pub pid: usize,
+    pub write: bool,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Lock {
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.owner.same_kind(other.owner)
+            && (self.write || other.write)
+            && self.start < other.end
+            && other.start < self.end
+    }
+}
+
+static mut LOCKS: BTreeMap<Key, Vec<Lock>> = BTreeMap::new();
+/// Who's waiting for a lock, and for which process to let go of it.
+static mut WAITING: BTreeMap<usize, usize> = BTreeMap::new();
+// Lockdep has the locks that are waited for, the queue would only muddle
+// them.
+static mut WAITERS: WaitQueue = WaitQueue::untracked();
+
+fn locks() -> &'static mut BTreeMap<Key, Vec<Lock>> {
+    unsafe { &mut *addr_of_mut!(LOCKS) }
+}
+
+fn waiting() -> &'static mut BTreeMap<usize, usize> {
+    unsafe { &mut *addr_of_mut!(WAITING) }
+}
+
+/// A lock has gone: let everyone waiting try again. They'll say again who
+/// they're waiting for if they still have to.
+fn wake() {
+    waiting().clear();
+    unsafe {
+        (*addr_of_mut!(WAITERS)).wake_all();
+    }
+}
+
+/// Whether `pid` waiting for `holder` would be waiting forever: `holder`
+/// is waiting for a lock of `pid`'s, or for someone else who is, and so on.
+fn deadlock(pid: usize, mut holder: usize) -> bool {
+    let waiting = waiting();
+    for _ in 0..=waiting.len() {
+        if holder == pid {
+            return true;
+        }
+        match waiting.get(&holder) {
+            Some(&next) => holder = next,
+            None => return false,
+        }
+    }
+    false
+}
+
+/// The first lock of someone else's on the file `key` that `lock` can't go
+/// with.
+pub fn conflict(key: Key, lock: &Lock) -> Option<Lock> {
+    let held = locks().get(&key)?;
+    held.iter().find(|l| l.conflicts(lock)).copied()
+}
+
+/// Take away what `owner` has locked of the file `key` from `start` up to
+/// `end`, splitting the locks that go past it.
+fn remove(key: Key, owner: Owner, start: u64, end: u64) {
+    let Some(held) = locks().get_mut(&key) else {
+        return;
+    };
+    let mut kept = Vec::with_capacity(held.len());
+    for l in held.drain(..) {
+        if l.owner != owner || l.end <= start || end <= l.start {
+            kept.push(l);
+            continue;
+        }
+        if l.start < start {
+            kept.push(Lock { end: start, ..l });
+        }
+        if end < l.end {
+            kept.push(Lock { start: end, ..l });
+        }
+    }
+    if kept.is_empty() {
+        locks().remove(&key);
+    } else {
+        *held = kept;
+    }
+}
+
+/// Take `lock` on the file `key`, in place of whatever its owner had there
+/// before. If someone else's lock is in the way, this fails with EAGAIN,
+/// or with `wait` answers Block until it might not be any more.
+#[track_caller]
+pub fn set(key: Key, lock: Lock, wait: bool) -> Result<(), SysError> {
+    if wait && lock.write {
+        lockdep::wait(lock.pid, Class::file_lock(key, lock.owner));
+    }
+    if let Some(holder) = conflict(key, &lock) {
+        if !wait {
+            return Err(Errno(EAGAIN));
+        }
+        if deadlock(lock.pid, holder.pid) {
+            return Err(Errno(EDEADLK));
+        }
+        waiting().insert(lock.pid, holder.pid);
+        unsafe {
+            (*addr_of_mut!(WAITERS)).wait(lock.pid);
+        }
+        return Err(Block);
+    }
+    remove(key, lock.owner, lock.start, lock.end);
+    locks().entry(key).or_default().push(lock);
+    // Going from a write lock to a read lock lets readers in.
+    wake();
+    Ok(())
+}
+
+/// Unlock what `owner` has locked of the file `key` from `start` up to
+/// `end`.
+pub fn unlock(key: Key, owner: Owner, start: u64, end: u64) {
+    remove(key, owner, start, end);
+    wake();
+}
+
+/// Let go of every lock `owner` has: an open file that's been closed, or a
+/// process that's gone.
+pub fn release(owner: Owner) {
+    let keys: Vec<Key> = locks()
+        .iter()
+        .filter(|(_, held)| held.iter().any(|l| l.owner == owner))
+        .map(|(&key, _)| key)
+        .collect();
+    if keys.is_empty() {
+        return;
+    }
+    for key in keys {
+        remove(key, owner, 0, u64::MAX);
+    }
+    wake();
+}
+
+/// The process `pid` closed a file descriptor of the file `key`: its
+/// fcntl() locks on the file go.
+pub fn closed(pid: usize, key: Key) {
+    let owner = Owner::Process(pid);
+    let held = locks().get(&key);
+    if held.is_some_and(|held| held.iter().any(|l| l.owner == owner)) {
+        unlock(key, owner, 0, u64::MAX);
+    }
+}
+
+/// The files `pid` holds write locks on, and who for, for lockdep.
+pub fn held(pid: usize) -> impl Iterator<Item = (Key, Owner)> {
+    locks().iter().flat_map(move |(&key, held)| {
+        held.iter()
+            .filter(move |l| l.pid == pid && l.write)
+            .map(move |l| (key, l.owner))
+    })
+}
+
+/// The process `pid` is gone, and isn't waiting for anything.
+pub fn exited(pid: usize) {
+    waiting().remove(&pid);
+    release(Owner::Process(pid));
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::check;
+    use alloc::string::String;
+
+    /// Files without an inode, like two different pipes, don't share a key.
+    #[test_case]
+    fn key_test() -> Result<(), String> {
+        let pipe = Stat::default();
+        check(matches!(key(&pipe), Err(Errno(EINVAL))), || {
+            "a file without an inode has a key".into()
+        })?;
+        let file = Stat {
+            st_dev: 5,
+            st_ino: 7,
+            ..Default::default()
+        };
+        check(matches!(key(&file), Ok((5, 7))), || {
+            "a file's key isn't its device and inode".into()
+        })
+    }
+
+    /// Locks of different processes on the same file, and on different
+    /// files. The pids are made up, nobody waits.
+    #[test_case]
+    fn conflict_test() -> Result<(), String> {
+        let (a, b) = (1_000_001, 1_000_002);
+        let lock = |pid, write, start, end| Lock {
+            owner: Owner::Process(pid),
+            pid,
+            write,
+            start,
+            end,
+        };
+        let (file, other) = ((u64::MAX, 1), (u64::MAX, 2));
+        set(file, lock(a, true, 0, 100), false).map_err(|_| "a's lock failed")?;
+        let ret = set(file, lock(b, false, 50, 60), false);
+        check(matches!(ret, Err(Errno(EAGAIN))), || {
+            "b got into a's write lock".into()
+        })?;
+        set(file, lock(b, true, 100, 200), false).map_err(|_| "b's lock next to it failed")?;
+        set(other, lock(b, true, 0, 100), false).map_err(|_| "b's lock on another file failed")?;
+        // a closing another file keeps its lock on this one.
+        closed(a, other);
+        check(conflict(file, &lock(b, false, 0, 1)).is_some(), || {
+            "a's lock went when it closed another file".into()
+        })?;
+        // Going down to a read lock lets b's read in.
+        set(file, lock(a, false, 0, 100), false).map_err(|_| "a's read lock failed")?;
+        set(file, lock(b, false, 50, 60), false).map_err(|_| "b's read lock failed")?;
+        closed(a, file);
+        exited(b);
+        check(
+            !locks().contains_key(&file) && !locks().contains_key(&other),
+            || "locks were left behind".into(),
+        )
+    }
+}
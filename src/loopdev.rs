@@ -0,0 +1,272 @@
+// Loop devices: block devices whose sectors are the bytes of a file, so a
+// filesystem image can be made, mounted and checked inside the running
+// kernel instead of restarting QEMU with another -drive. /dev/loop0 to
+// /dev/loop3 are there from the start, empty, and are set up the way
+// losetup does it on Linux: LOOP_SET_FD attaches the file open as a file
+// descriptor (read-only if it isn't open for writing), LOOP_SET_STATUS64
+// can make the device a part of it, from lo_offset on and lo_sizelimit
+// bytes long, and LOOP_CLR_FD lets go of it again.
+//
+// Any file with a size will do: a file on a share of the host, or a disk
+// itself, which makes a part of a disk a disk of its own.
+// Loop devices on loop devices aren't allowed, so that a loop can't read
+// itself forever.
+//
+// A request reads or writes the file like read() and write() would, so it
+// goes through the cache of the disk behind it. If the file answers Block,
+// so does the request, and whoever asked waits for the file.
+
+use crate::{
+    bcache,
+    block::{self, BlockDevice, SECTOR_SIZE, S_IFBLK},
+    cpu::TrapFrame,
+    file::OpenFile,
+    syscall::{
+        self, read_user, write_user, SysError, SysResult, EBUSY, EINVAL, EIO, ENOSPC, ENXIO, EROFS,
+    },
+};
+use alloc::{boxed::Box, format, rc::Rc, string::String};
+
+use SysError::Errno;
+
+const LOOP_MAJOR: u64 = 7;
+/// How many loop devices there are.
+const MAX_LOOPS: usize = 4;
+
+// ioctls
+const LOOP_SET_FD: usize = 0x4c00;
+const LOOP_CLR_FD: usize = 0x4c01;
+const LOOP_SET_STATUS64: usize = 0x4c04;
+const LOOP_GET_STATUS64: usize = 0x4c05;
+const LOOP_SET_CAPACITY: usize = 0x4c07;
+
+/// lo_flags
+const LO_FLAGS_READ_ONLY: u32 = 1;
+
+/// struct loop_info64, which LOOP_GET_STATUS64 and LOOP_SET_STATUS64 take.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+struct Loop {
+    name: String,
+    index: usize,
+    // The file we're attached to.
+    backing: Option<Rc<OpenFile>>,
+    // Where in it the device starts, and how much of it it may have at
+    // most, 0 for all of it.
+    offset: u64,
+    size_limit: u64,
+    // The size of the device, as it was when it was last looked at.
+    sectors: u64,
+    read_only: bool,
+}
+
+/// Make the loop devices, all of them empty.
+pub fn init() {
+    for index in 0..MAX_LOOPS {
+        block::register(Box::new(Loop {
+            name: format!("loop{}", index),
+            index,
+            backing: None,
+            offset: 0,
+            size_limit: 0,
+            sectors: 0,
+            read_only: false,
+        }));
+    }
+    println!("loop: /dev/loop0 to /dev/loop{}", MAX_LOOPS - 1);
+}
+
+impl Loop {
+    fn backing(&self) -> Result<&Rc<OpenFile>, SysError> {
+        self.backing.as_ref().ok_or(Errno(ENXIO))
+    }
+
+    /// Work out how big the device is from the size of the file.
+    fn set_capacity(&mut self) -> Result<(), SysError> {
+        let size = self.backing()?.file().size().ok_or(Errno(EINVAL))? as u64;
+        let mut bytes = size.saturating_sub(self.offset);
+        if self.size_limit != 0 {
+            bytes = bytes.min(self.size_limit);
+        }
+        self.sectors = bytes / SECTOR_SIZE as u64;
+        Ok(())
+    }
+
+    /// Where the sectors from `sector` on that fit in `len` bytes are in
+    /// the file.
+    fn position(&self, sector: u64, len: usize) -> Result<usize, SysError> {
+        let count = (len / SECTOR_SIZE) as u64;
+        if sector
+            .checked_add(count)
+            .is_none_or(|end| end > self.sectors)
+        {
+            return Err(Errno(EIO));
+        }
+        Ok((self.offset + sector * SECTOR_SIZE as u64) as usize)
+    }
+
+    fn attach(&mut self, frame: &TrapFrame, fd: usize) -> Result<(), SysError> {
+        if self.backing.is_some() {
+            return Err(Errno(EBUSY));
+        }
+        let file = syscall::current(frame).files.get(fd)?.clone();
+        let stat = file.file().stat();
+        if stat.st_mode & S_IFBLK == S_IFBLK && stat.st_rdev >> 8 == LOOP_MAJOR {
+            return Err(Errno(EINVAL));
+        }
+        self.read_only = !file.writable();
+        self.backing = Some(file);
+        self.offset = 0;
+        self.size_limit = 0;
+        if let Err(e) = self.set_capacity() {
+            self.backing = None;
+            return Err(e);
+        }
+        println!(
+            "{}: {} MiB{}",
+            self.name,
+            (self.sectors * SECTOR_SIZE as u64) >> 20,
+            if self.read_only { ", read-only" } else { "" }
+        );
+        Ok(())
+    }
+
+    /// The blocks of ours the cache has are about to change under it.
+    fn invalidate(&self) {
+        if let Some(dev) = block::find(self.name.as_bytes()) {
+            bcache::invalidate(dev);
+        }
+    }
+
+    fn status(&self) -> Result<LoopInfo64, SysError> {
+        let stat = self.backing()?.file().stat();
+        Ok(LoopInfo64 {
+            lo_device: stat.st_dev,
+            lo_inode: stat.st_ino,
+            lo_rdevice: stat.st_rdev,
+            lo_offset: self.offset,
+            lo_sizelimit: self.size_limit,
+            lo_number: self.index as u32,
+            lo_encrypt_type: 0,
+            lo_encrypt_key_size: 0,
+            lo_flags: if self.read_only {
+                LO_FLAGS_READ_ONLY
+            } else {
+                0
+            },
+            lo_file_name: [0; 64],
+            lo_crypt_name: [0; 64],
+            lo_encrypt_key: [0; 32],
+            lo_init: [0; 2],
+        })
+    }
+}
+
+impl BlockDevice for Loop {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rdev(&self) -> u64 {
+        LOOP_MAJOR << 8 | self.index as u64
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), SysError> {
+        let pos = self.position(sector, buf.len())?;
+        let file = self.backing()?.file();
+        let mut done = 0;
+        while done < buf.len() {
+            let n = file.read(pos + done, &mut buf[done..])?;
+            if n == 0 {
+                // The file got shorter. What isn't there any more reads as
+                // zeros.
+                buf[done..].fill(0);
+                break;
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), SysError> {
+        if self.read_only {
+            return Err(Errno(EROFS));
+        }
+        let pos = self.position(sector, buf.len())?;
+        let file = self.backing()?.file();
+        let mut done = 0;
+        while done < buf.len() {
+            let n = file.write(pos + done, &buf[done..])?;
+            if n == 0 {
+                return Err(Errno(ENOSPC));
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// A request answers Block when the file does, so it's the file we wait
+    /// for.
+    fn wait(&mut self, pid: usize) -> bool {
+        match &self.backing {
+            Some(file) => {
+                file.file().wait(pid);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn ioctl(&mut self, frame: &TrapFrame, cmd: usize, arg: usize) -> SysResult {
+        match cmd {
+            LOOP_SET_FD => self.attach(frame, arg)?,
+            LOOP_CLR_FD => {
+                self.backing()?;
+                self.invalidate();
+                self.backing = None;
+                self.sectors = 0;
+                println!("{}: detached", self.name);
+            }
+            LOOP_SET_STATUS64 => {
+                self.backing()?;
+                let info: LoopInfo64 = read_user(frame, arg)?;
+                self.invalidate();
+                self.offset = info.lo_offset;
+                self.size_limit = info.lo_sizelimit;
+                self.set_capacity()?;
+            }
+            LOOP_GET_STATUS64 => write_user(frame, arg, &self.status()?)?,
+            LOOP_SET_CAPACITY => {
+                self.backing()?;
+                self.invalidate();
+                self.set_capacity()?;
+            }
+            _ => return Err(Errno(EINVAL)),
+        }
+        Ok(0)
+    }
+}
@@ -1,7 +1,13 @@
 #![no_main]
 #![no_std]
+// cargo test builds a test kernel, see testing.rs.
+#![cfg_attr(test, feature(custom_test_frameworks))]
+#![cfg_attr(test, test_runner(crate::testing::run))]
+#![cfg_attr(test, reexport_test_harness_main = "test_main")]
 
-use core::arch::asm;
+extern crate alloc;
+
+use core::{arch::asm, ptr::addr_of_mut};
 
 // ///////////////////////////////////
 // / RUST MACROS
@@ -11,7 +17,7 @@ use core::arch::asm;
 macro_rules! print {
     ($($args:tt)+) => ({
         use core::fmt::Write;
-        let _ = write!($crate::uart::Uart::new(0x1000_0000), $($args)+);
+        let _ = write!($crate::console::Log, $($args)+);
     });
 }
 
@@ -29,9 +35,78 @@ macro_rules! println
 	});
 }
 
+mod ansi;
 mod assembly;
+mod bcache;
+mod bench;
+mod block;
+mod board;
+mod console;
+mod coredump;
+mod cpu;
+mod crashdump;
+mod devfs;
+mod device;
+mod dma;
+mod entropy;
+mod ext2;
+mod fat;
+mod fbcon;
+mod fdt;
+mod file;
+mod gpio;
+mod gpu;
+mod htif;
+mod hvc;
+mod inflate;
+mod initramfs;
+mod input;
+mod keymap;
+mod klog;
+mod kmem;
+mod ksyms;
+mod ktrace;
+mod lockdep;
+mod locks;
+mod loopdev;
+mod memmap;
+mod minix;
+mod net;
+mod nic;
+mod nvme;
 mod page;
+mod panic;
+mod partition;
+mod pci;
+mod pipe;
+mod plic;
+mod pointer;
+mod power;
+mod process;
+mod procfs;
+mod ramdisk;
+mod rng;
+mod rtc;
+mod sched;
+mod sd;
+mod selftest;
+mod semihosting;
+mod shell;
+mod shm;
+mod spi;
+mod strace;
+mod syscall;
+#[cfg(test)]
+mod testing;
+mod timer;
+mod tmpfs;
+mod trap;
 mod uart;
+mod user;
+mod v9fs;
+mod vfs;
+mod virtio;
+mod xmodem;
 
 // ///////////////////////////////////
 // / LANGUAGE STRUCTURES / FUNCTIONS
@@ -42,8 +117,7 @@ extern "C" fn eh_personality() {}
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    println!("Aborting: {}", info);
-    abort();
+    panic::report(info)
 }
 
 #[no_mangle]
@@ -59,77 +133,73 @@ extern "C" fn abort() -> ! {
 // / CONSTANTS
 // ///////////////////////////////////
 
+/// The number of pages the hart uses as its stack while handling traps.
+const TRAP_STACK_PAGES: usize = 4;
+
 // ///////////////////////////////////
 // / ENTRY POINT
 // ///////////////////////////////////
 
 #[no_mangle]
-extern "C" fn kmain() {
-    // Main should initialize all sub-systems and get
-    // ready to start scheduling. The last thing this
-    // should do is start the timer.
-
-    page::init();
-    page::alloc(64);
-    page::alloc(1);
-    page::alloc(1);
-    page::alloc(1);
+extern "C" fn kinit(dtb: usize) -> ! {
+    // kinit runs in machine mode with interrupts disabled. It should
+    // initialize all sub-systems and get ready to start scheduling. The
+    // last thing this does is start the timer and switch to the first
+    // process.
+    unsafe {
+        let frame = &mut *addr_of_mut!(cpu::KERNEL_TRAP_FRAME[board::BOOT_HART]);
+        cpu::mscratch_write(frame as *mut cpu::TrapFrame as usize);
+
+        page::init();
+        kmem::init();
+
+        let trap_stack = page::zalloc(TRAP_STACK_PAGES);
+        frame.trap_stack = trap_stack.add(TRAP_STACK_PAGES * page::PAGE_SIZE);
+    }
+    semihosting::init();
     page::print_page_allocations();
 
-    let mut my_uart = uart::Uart::new(0x1000_0000);
-    my_uart.init();
-
-    println!("This is my operating system!");
-    println!("I'm so awesome. If you start typing something, I'll show you what you typed!");
-
-    loop {
-        if let Some(c) = my_uart.get() {
-            match c {
-                8 => {
-                    // This is a backspace, so we essentially have
-                    // to write a space and backup again:
-                    print!("{}{}{}", 8 as char, ' ', 8 as char);
-                }
-                10 | 13 => {
-                    // Newline or carriage-return
-                    println!();
-                }
-                0x1b => {
-                    // Those familiar with ANSI escape sequences
-                    // knows that this is one of them. The next
-                    // thing we should get is the left bracket [
-                    // These are multi-byte sequences, so we can take
-                    // a chance and get from UART ourselves.
-                    // Later, we'll button this up.
-                    if let Some(91) = my_uart.get() {
-                        // This is a right bracket! We're on our way!
-                        if let Some(b) = my_uart.get() {
-                            match b as char {
-                                'A' => {
-                                    println!("That's the up arrow!");
-                                }
-                                'B' => {
-                                    println!("That's the down arrow!");
-                                }
-                                'C' => {
-                                    println!("That's the right arrow!");
-                                }
-                                'D' => {
-                                    println!("That's the left arrow!");
-                                }
-                                _ => {
-                                    println!("That's something else.....");
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    print!("{}", c as char);
-                }
-            }
-        }
+    println!("Booting on {}, hart {}", board::NAME, board::BOOT_HART);
+    if !board::BOOT_DTB {
+        println!("No device tree on this board");
+    } else if !fdt::init(dtb) {
+        println!("No device tree at 0x{:x}", dtb);
     }
+    klog::init();
+    ktrace::init();
+    initramfs::reserve();
+    vfs::init();
+    initramfs::init();
+    device::init();
+    console::init();
+    fbcon::init();
+    keymap::init();
+    timer::init();
+    entropy::init();
+    block::init();
+    bcache::init();
+    ramdisk::init();
+    loopdev::init();
+    minix::init();
+    fat::init();
+    ext2::init();
+    crashdump::init();
+    memmap::init();
+    bench::init();
+    selftest::init();
+    shell::init();
+
+    process::init();
+    // The test kernel runs its tests instead of the shell and init.
+    #[cfg(test)]
+    process::add_kernel_process(test_main);
+    #[cfg(not(test))]
+    process::add_kernel_process(shell::run);
+    process::add_kernel_process(bcache::flusher);
+    #[cfg(not(test))]
+    process::add_user_process(user::init);
+
+    sched::start();
 }
 
 // ///////////////////////////////////
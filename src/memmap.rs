@@ -0,0 +1,315 @@
+// The physical address space as the kernel knows it: RAM, with the parts
+// of it the linker laid the kernel out in (see lds/virt.lds) and the page
+// heap after them, and the register blocks of the devices (see device.rs).
+// The shell's memory commands check addresses against it, so that a typo
+// gets an error instead of an access fault: reading where nothing answers
+// traps, and reading a device's registers can change it (a UART gives up
+// the byte it holds when its receive register is read), so only RAM is
+// dumped.
+//
+// peek and poke read and write one value at a time, of 1, 2, 4 or 8 bytes
+// (-w), with accesses of that size, which is what registers want. They
+// take device registers too, that being what they're for, and --force
+// lets them at addresses that aren't in the map at all, for a device the
+// kernel doesn't know about yet. Nothing answering there is an access
+// fault, which the kernel panics on. Values have to be aligned to their
+// size either way.
+//
+// hexdump() formats memory the way hexdump -C does, 16 bytes a line with
+// what's printable of them next to it, for whoever wants to show some.
+
+use crate::{
+    board, console, device,
+    shell::{self, Builtin},
+    syscall::{SysError, EFAULT, EINTR, EINVAL},
+};
+use alloc::vec::Vec;
+use core::{
+    fmt::{self, Write},
+    slice,
+};
+
+use SysError::Errno;
+
+extern "C" {
+    static TEXT_START: usize;
+    static TEXT_END: usize;
+    static RODATA_START: usize;
+    static RODATA_END: usize;
+    static DATA_START: usize;
+    static DATA_END: usize;
+    static BSS_START: usize;
+    static BSS_END: usize;
+    static KERNEL_STACK_START: usize;
+    static KERNEL_STACK_END: usize;
+    static HEAP_START: usize;
+}
+
+/// Where RAM starts, on every board we know.
+pub const RAM_START: usize = 0x8000_0000;
+/// How many bytes a line of hexdump() shows.
+const LINE: usize = 16;
+/// How many bytes the hexdump command dumps between looking for ^C.
+const CHUNK: usize = 64 * LINE;
+
+/// What's at some addresses.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Ram,
+    Device,
+}
+
+/// A range of addresses, from `start` up to `end`.
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub name: &'static str,
+    pub kind: Kind,
+}
+
+/// The regions of RAM, the whole of it first and then its parts.
+fn ram() -> Vec<Region> {
+    let part = |name, start, end| Region {
+        start,
+        end,
+        name,
+        kind: Kind::Ram,
+    };
+    let end = RAM_START + board::RAM_SIZE;
+    unsafe {
+        alloc::vec![
+            part("ram", RAM_START, end),
+            part("kernel text", TEXT_START, TEXT_END),
+            part("kernel rodata", RODATA_START, RODATA_END),
+            part("kernel data", DATA_START, DATA_END),
+            part("kernel bss", BSS_START, BSS_END),
+            part("kernel stack", KERNEL_STACK_START, KERNEL_STACK_END),
+            part("page heap", HEAP_START, end),
+        ]
+    }
+}
+
+/// Every region we know of, by address. Parts of RAM come after RAM.
+pub fn regions() -> Vec<Region> {
+    let mut regions = ram();
+    for (name, base, size) in device::register_blocks() {
+        regions.push(Region {
+            start: base,
+            end: base.saturating_add(size),
+            name,
+            kind: Kind::Device,
+        });
+    }
+    // Stable, so RAM stays before its first part.
+    regions.sort_by_key(|r| r.start);
+    regions
+}
+
+/// The region that all of `addr` up to `addr + len` is in: RAM, or the
+/// registers of one device. Fails with EFAULT if that's nowhere, or more
+/// than one place.
+pub fn check(addr: usize, len: usize) -> Result<Region, SysError> {
+    let end = addr.checked_add(len).ok_or(Errno(EFAULT))?;
+    regions()
+        .into_iter()
+        .filter(|r| r.name == "ram" || r.kind == Kind::Device)
+        .find(|r| r.start <= addr && end <= r.end)
+        .ok_or(Errno(EFAULT))
+}
+
+/// Write `data` to `w` as a hex dump, with `addr` as the address of its
+/// first byte.
+pub fn hexdump(w: &mut impl Write, addr: usize, data: &[u8]) -> fmt::Result {
+    for (i, line) in data.chunks(LINE).enumerate() {
+        write!(w, "{:08x} ", addr + i * LINE)?;
+        for j in 0..LINE {
+            if j % 8 == 0 {
+                w.write_char(' ')?;
+            }
+            match line.get(j) {
+                Some(b) => write!(w, "{:02x} ", b)?,
+                None => w.write_str("   ")?,
+            }
+        }
+        w.write_str(" |")?;
+        for &b in line {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            };
+            w.write_char(c)?;
+        }
+        w.write_str("|\r\n")?;
+    }
+    Ok(())
+}
+
+// ///////////////////////////////////
+// / SHELL COMMANDS
+// ///////////////////////////////////
+
+fn memmap_command(_: &[&str]) -> Result<(), SysError> {
+    for r in regions() {
+        let indent = if r.kind == Kind::Ram && r.name != "ram" {
+            "  "
+        } else {
+            ""
+        };
+        println!("{:08x}-{:08x} {}{}", r.start, r.end, indent, r.name);
+    }
+    Ok(())
+}
+
+fn hexdump_command(args: &[&str]) -> Result<(), SysError> {
+    let [addr, len] = args else {
+        return Err(Errno(EINVAL));
+    };
+    let (addr, len) = (shell::number(addr)?, shell::number(len)?);
+    match check(addr, len) {
+        Ok(r) if r.kind == Kind::Ram => {}
+        Ok(r) => {
+            println!("that's the registers of {}, which aren't dumped", r.name);
+            return Err(Errno(EFAULT));
+        }
+        Err(e) => {
+            println!("there's no RAM there, see memmap");
+            return Err(e);
+        }
+    }
+    let data = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+    shell::paged(|| {
+        for (i, chunk) in data.chunks(CHUNK).enumerate() {
+            if shell::cancelled() {
+                return Err(Errno(EINTR));
+            }
+            hexdump(&mut console::Log, addr + i * CHUNK, chunk).ok();
+        }
+        Ok(())
+    })
+}
+
+/// The flags peek and poke take, -w WIDTH and --force, and the arguments
+/// after them.
+fn access_args<'a>(args: &[&'a str]) -> Result<(usize, bool, Vec<&'a str>), SysError> {
+    let (mut width, mut force, mut rest) = (4, false, Vec::new());
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "-w" => {
+                width = shell::number(args.next().ok_or(Errno(EINVAL))?)?;
+                if !matches!(width, 1 | 2 | 4 | 8) {
+                    return Err(Errno(EINVAL));
+                }
+            }
+            "--force" => force = true,
+            _ => rest.push(arg),
+        }
+    }
+    Ok((width, force, rest))
+}
+
+/// Check that `count` values of `width` bytes from `addr` can be got at,
+/// unless `force`.
+fn check_access(addr: usize, width: usize, count: usize, force: bool) -> Result<(), SysError> {
+    if !addr.is_multiple_of(width) {
+        println!("0x{:x} isn't aligned to {} bytes", addr, width);
+        return Err(Errno(EFAULT));
+    }
+    let len = width.checked_mul(count).ok_or(Errno(EINVAL))?;
+    if force {
+        return Ok(());
+    }
+    check(addr, len).map(|_| ()).inspect_err(|_| {
+        println!("there's nothing there that we know of, see memmap, or use --force");
+    })
+}
+
+/// Read the value of `width` bytes at `addr`.
+fn read(addr: usize, width: usize) -> u64 {
+    unsafe {
+        match width {
+            1 => (addr as *const u8).read_volatile() as u64,
+            2 => (addr as *const u16).read_volatile() as u64,
+            4 => (addr as *const u32).read_volatile() as u64,
+            _ => (addr as *const u64).read_volatile(),
+        }
+    }
+}
+
+/// Write `value` to the `width` bytes at `addr`.
+fn write(addr: usize, width: usize, value: u64) {
+    unsafe {
+        match width {
+            1 => (addr as *mut u8).write_volatile(value as u8),
+            2 => (addr as *mut u16).write_volatile(value as u16),
+            4 => (addr as *mut u32).write_volatile(value as u32),
+            _ => (addr as *mut u64).write_volatile(value),
+        }
+    }
+}
+
+fn peek_command(args: &[&str]) -> Result<(), SysError> {
+    let (width, force, rest) = access_args(args)?;
+    let (addr, count) = match rest[..] {
+        [addr] => (shell::number(addr)?, 1),
+        [addr, count] => (shell::number(addr)?, shell::number(count)?),
+        _ => return Err(Errno(EINVAL)),
+    };
+    check_access(addr, width, count, force)?;
+    shell::paged(|| {
+        for i in 0..count {
+            let at = addr + i * width;
+            println!("0x{:08x}: 0x{:0w$x}", at, read(at, width), w = 2 * width);
+        }
+    });
+    Ok(())
+}
+
+fn poke_command(args: &[&str]) -> Result<(), SysError> {
+    let (width, force, rest) = access_args(args)?;
+    let [addr, ref values @ ..] = rest[..] else {
+        return Err(Errno(EINVAL));
+    };
+    if values.is_empty() {
+        return Err(Errno(EINVAL));
+    }
+    let addr = shell::number(addr)?;
+    let values = values
+        .iter()
+        .map(|v| shell::number(v).map(|v| v as u64))
+        .collect::<Result<Vec<_>, _>>()?;
+    if width < 8 && values.iter().any(|&v| v >> (8 * width) != 0) {
+        println!("that doesn't fit in {} bytes", width);
+        return Err(Errno(EINVAL));
+    }
+    check_access(addr, width, values.len(), force)?;
+    for (i, &value) in values.iter().enumerate() {
+        write(addr + i * width, width, value);
+    }
+    Ok(())
+}
+
+/// Register the memory commands with the shell.
+pub fn init() {
+    shell::register(&Builtin {
+        name: "memmap",
+        help: "memmap - list what's where in the physical address space",
+        run: memmap_command,
+    });
+    shell::register(&Builtin {
+        name: "hexdump",
+        help: "hexdump ADDR LEN - dump LEN bytes of RAM from ADDR",
+        run: hexdump_command,
+    });
+    shell::register(&Builtin {
+        name: "peek",
+        help: "peek [-w 1|2|4|8] [--force] ADDR [COUNT] - read COUNT values from ADDR",
+        run: peek_command,
+    });
+    shell::register(&Builtin {
+        name: "poke",
+        help: "poke [-w 1|2|4|8] [--force] ADDR VALUE... - write the values from ADDR on",
+        run: poke_command,
+    });
+}
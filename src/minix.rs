@@ -0,0 +1,1313 @@
+// The Minix 3 filesystem, the way Linux's mkfs.minix -3 makes it, read and
+// written through the block cache. Every disk that has one is mounted at
+// /mnt/<disk> at boot.
+//
+// The disk is a row of 1024-byte blocks, which are what the cache holds:
+//
+//   block 0         the boot block, which we leave alone
+//   block 1         the superblock, which says how big everything is
+//   then            the inode bitmap, a bit for every inode
+//   then            the zone bitmap, a bit for every data zone
+//   then            the inodes, 64 bytes each (16 to a block)
+//   first_data on   the data zones
+//
+// A zone is a block here: the filesystems we take have a log_zone_size of
+// 0. Bit 0 of both bitmaps is always set, so inode n is bit n and zone z
+// is bit z - first_data + 1. Inodes count from 1, the root directory.
+//
+// An inode has its file's type and permissions, its owner and group, its
+// size, its times and ten zone numbers: seven for the first zones of the file, then one of a
+// block of zone numbers, one of a block of those and one of a block of
+// those in turn. Zone 0 means there's none, a hole that reads as zeros. A
+// directory is a file of 64-byte entries, an inode number and a name of up
+// to 60 bytes, NUL-padded; an inode number of 0 is a free entry.
+//
+// A symlink is a file with where it points in it.
+//
+// A file can have several names, in one directory or several, as many as
+// its inode's link count; a directory's . and the .. of the directories
+// in it count too. A file whose last name goes is freed, its zones and its
+// inode, once nobody has it open any more: until then, the ones who do can
+// go on reading and writing it.
+//
+// Nothing is kept besides the cache: inodes and files are just inode
+// numbers. Changes stay in the cache until sync() or fsync() writes them
+// out, or the cache needs the room.
+//
+// fsck() checks a filesystem is what all that says it should be, and can
+// put right what isn't (see the end of the file).
+
+use crate::{
+    bcache::{self, Readahead, BLOCK_SIZE},
+    block::{self, SECTOR_SIZE},
+    cpu,
+    file::{File, O_ACCMODE, O_RDONLY, O_TRUNC, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG},
+    shell::{self, Builtin},
+    syscall::{
+        Stat, SysError, EEXIST, EFBIG, EINVAL, EIO, EISDIR, EMLINK, ENAMETOOLONG, ENODEV, ENOENT,
+        ENOSPC, ENOTDIR, ENOTEMPTY, EROFS, EXDEV, PATH_MAX,
+    },
+    timer,
+    vfs::{self, Inode},
+};
+use alloc::{collections::BTreeMap, format, rc::Rc, string::String, vec, vec::Vec};
+use core::{cell::RefCell, ptr::addr_of_mut};
+use log::warn;
+
+use SysError::Errno;
+
+const MAGIC: u16 = 0x4d5a;
+const SUPER_BLOCK: u64 = 1;
+/// Where the bitmaps start.
+const IMAP_BLOCK: u64 = 2;
+const INODE_SIZE: usize = 64;
+const INODES_PER_BLOCK: u32 = (BLOCK_SIZE / INODE_SIZE) as u32;
+const BITS_PER_BLOCK: u32 = BLOCK_SIZE as u32 * 8;
+const ROOT_INO: u32 = 1;
+
+const DIRENT_SIZE: usize = 64;
+const NAME_LEN: usize = 60;
+/// The most links a file can have, as on Linux.
+const LINK_MAX: u16 = 65530;
+
+/// How many zones an inode has the numbers of itself, and how many numbers
+/// there are in an indirect block.
+const DIRECT_ZONES: usize = 7;
+const ZONES_PER_BLOCK: u64 = (BLOCK_SIZE / 4) as u64;
+
+fn u16_at(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn now() -> u32 {
+    (timer::realtime_ns() / 1_000_000_000) as u32
+}
+
+/// An inode as it is on the disk.
+#[derive(Clone, Copy)]
+struct DiskInode {
+    mode: u16,
+    nlinks: u16,
+    uid: u16,
+    gid: u16,
+    size: u32,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+    zones: [u32; 10],
+}
+
+impl DiskInode {
+    /// A new inode, of whoever the VFS acts for.
+    fn new(mode: u16, nlinks: u16) -> Self {
+        let (now, cred) = (now(), vfs::cred());
+        DiskInode {
+            mode,
+            nlinks,
+            uid: cred.uid as u16,
+            gid: cred.gid as u16,
+            size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            zones: [0; 10],
+        }
+    }
+
+    fn parse(b: &[u8]) -> Self {
+        let mut zones = [0; 10];
+        for (i, z) in zones.iter_mut().enumerate() {
+            *z = u32_at(b, 24 + 4 * i);
+        }
+        DiskInode {
+            mode: u16_at(b, 0),
+            nlinks: u16_at(b, 2),
+            uid: u16_at(b, 4),
+            gid: u16_at(b, 6),
+            size: u32_at(b, 8),
+            atime: u32_at(b, 12),
+            mtime: u32_at(b, 16),
+            ctime: u32_at(b, 20),
+            zones,
+        }
+    }
+
+    fn store(&self, b: &mut [u8]) {
+        b[0..2].copy_from_slice(&self.mode.to_le_bytes());
+        b[2..4].copy_from_slice(&self.nlinks.to_le_bytes());
+        b[4..6].copy_from_slice(&self.uid.to_le_bytes());
+        b[6..8].copy_from_slice(&self.gid.to_le_bytes());
+        b[8..12].copy_from_slice(&self.size.to_le_bytes());
+        b[12..16].copy_from_slice(&self.atime.to_le_bytes());
+        b[16..20].copy_from_slice(&self.mtime.to_le_bytes());
+        b[20..24].copy_from_slice(&self.ctime.to_le_bytes());
+        for (i, z) in self.zones.iter().enumerate() {
+            b[24 + 4 * i..28 + 4 * i].copy_from_slice(&z.to_le_bytes());
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode as u32 & S_IFMT == S_IFDIR
+    }
+}
+
+/// A mounted filesystem: what its superblock says.
+struct Fs {
+    dev: usize,
+    read_only: bool,
+    ninodes: u32,
+    zones: u32,
+    first_data: u32,
+    max_size: u32,
+    imap_blocks: u64,
+    zmap_blocks: u64,
+    // How many times each inode that's open is.
+    open: RefCell<BTreeMap<u32, usize>>,
+}
+
+static mut FILESYSTEMS: Vec<Fs> = Vec::new();
+
+fn filesystems() -> &'static mut Vec<Fs> {
+    unsafe { &mut *addr_of_mut!(FILESYSTEMS) }
+}
+
+impl Fs {
+    fn zmap(&self) -> u64 {
+        IMAP_BLOCK + self.imap_blocks
+    }
+
+    fn itable(&self) -> u64 {
+        self.zmap() + self.zmap_blocks
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<DiskInode, SysError> {
+        if ino == 0 || ino > self.ninodes {
+            return Err(Errno(EIO));
+        }
+        let block = self.itable() + ((ino - 1) / INODES_PER_BLOCK) as u64;
+        let off = ((ino - 1) % INODES_PER_BLOCK) as usize * INODE_SIZE;
+        let b = bcache::bread(self.dev, block)?;
+        let inode = DiskInode::parse(&b.data()[off..off + INODE_SIZE]);
+        Ok(inode)
+    }
+
+    fn write_inode(&self, ino: u32, inode: &DiskInode) -> Result<(), SysError> {
+        let block = self.itable() + ((ino - 1) / INODES_PER_BLOCK) as u64;
+        let off = ((ino - 1) % INODES_PER_BLOCK) as usize * INODE_SIZE;
+        let b = bcache::bread(self.dev, block)?;
+        inode.store(&mut b.data_mut()[off..off + INODE_SIZE]);
+        bcache::bdwrite(&b);
+        Ok(())
+    }
+
+    /// Set the first clear bit below `bits` in the bitmap that starts at
+    /// block `start` and is `blocks` long, and return it.
+    fn alloc_bit(&self, start: u64, blocks: u64, bits: u32) -> Result<u32, SysError> {
+        for n in 0..blocks {
+            let b = bcache::bread(self.dev, start + n)?;
+            let Some(byte) = b.data().iter().position(|&c| c != 0xff) else {
+                continue;
+            };
+            let bit = (!b.data()[byte]).trailing_zeros();
+            let found = n as u32 * BITS_PER_BLOCK + byte as u32 * 8 + bit;
+            if found >= bits {
+                break;
+            }
+            b.data_mut()[byte] |= 1 << bit;
+            bcache::bdwrite(&b);
+            return Ok(found);
+        }
+        Err(Errno(ENOSPC))
+    }
+
+    /// Set or clear `bit` in the bitmap that starts at block `start`.
+    fn set_bit(&self, start: u64, bit: u32, set: bool) -> Result<(), SysError> {
+        let b = bcache::bread(self.dev, start + (bit / BITS_PER_BLOCK) as u64)?;
+        let byte = (bit % BITS_PER_BLOCK) as usize / 8;
+        if set {
+            b.data_mut()[byte] |= 1 << (bit % 8);
+        } else {
+            b.data_mut()[byte] &= !(1 << (bit % 8));
+        }
+        bcache::bdwrite(&b);
+        Ok(())
+    }
+
+    fn alloc_inode(&self) -> Result<u32, SysError> {
+        self.alloc_bit(IMAP_BLOCK, self.imap_blocks, self.ninodes + 1)
+    }
+
+    fn free_inode(&self, ino: u32) -> Result<(), SysError> {
+        self.set_bit(IMAP_BLOCK, ino, false)
+    }
+
+    /// A new zone, full of zeros.
+    fn alloc_zone(&self) -> Result<u32, SysError> {
+        let bits = self.zones - self.first_data + 1;
+        let zone = self.first_data + self.alloc_bit(self.zmap(), self.zmap_blocks, bits)? - 1;
+        let b = bcache::bread(self.dev, zone as u64)?;
+        b.data_mut().fill(0);
+        bcache::bdwrite(&b);
+        Ok(zone)
+    }
+
+    fn free_zone(&self, zone: u32) -> Result<(), SysError> {
+        if zone < self.first_data || zone >= self.zones {
+            return Err(Errno(EIO));
+        }
+        self.set_bit(self.zmap(), zone - self.first_data + 1, false)
+    }
+
+    /// The zone that has block `n` of the file of `inode`, 0 if it's a
+    /// hole. With `alloc`, holes get zones, which can change `inode`.
+    fn bmap(&self, inode: &mut DiskInode, n: u64, alloc: bool) -> Result<u32, SysError> {
+        if n < DIRECT_ZONES as u64 {
+            if inode.zones[n as usize] == 0 && alloc {
+                inode.zones[n as usize] = self.alloc_zone()?;
+            }
+            return Ok(inode.zones[n as usize]);
+        }
+        // Which indirect zone it's under, and where under it.
+        let (mut n, mut levels, mut span) = (n - DIRECT_ZONES as u64, 1, ZONES_PER_BLOCK);
+        while n >= span {
+            n -= span;
+            levels += 1;
+            span *= ZONES_PER_BLOCK;
+            if levels > 3 {
+                return Err(Errno(EFBIG));
+            }
+        }
+        let slot = &mut inode.zones[DIRECT_ZONES - 1 + levels];
+        if *slot == 0 {
+            if !alloc {
+                return Ok(0);
+            }
+            *slot = self.alloc_zone()?;
+        }
+        let mut zone = *slot;
+        for level in (0..levels).rev() {
+            let i = (n / ZONES_PER_BLOCK.pow(level as u32) % ZONES_PER_BLOCK) as usize * 4;
+            let b = bcache::bread(self.dev, zone as u64)?;
+            let mut next = u32_at(&b.data(), i);
+            if next == 0 {
+                if !alloc {
+                    return Ok(0);
+                }
+                next = self.alloc_zone()?;
+                b.data_mut()[i..i + 4].copy_from_slice(&next.to_le_bytes());
+                bcache::bdwrite(&b);
+            }
+            zone = next;
+        }
+        Ok(zone)
+    }
+
+    /// Free `zone`, which is `levels` levels of indirect blocks above the
+    /// file's data, and everything under it.
+    fn free_tree(&self, zone: u32, levels: u32) -> Result<(), SysError> {
+        if zone == 0 {
+            return Ok(());
+        }
+        if levels > 0 {
+            let b = bcache::bread(self.dev, zone as u64)?;
+            let children: Vec<u32> = (0..ZONES_PER_BLOCK as usize)
+                .map(|i| u32_at(&b.data(), i * 4))
+                .collect();
+            drop(b);
+            for child in children {
+                self.free_tree(child, levels - 1)?;
+            }
+        }
+        self.free_zone(zone)
+    }
+
+    /// Throw away all of the file's data.
+    fn truncate(&self, inode: &mut DiskInode) -> Result<(), SysError> {
+        for (i, zone) in inode.zones.iter_mut().enumerate() {
+            self.free_tree(*zone, i.saturating_sub(DIRECT_ZONES - 1) as u32)?;
+            *zone = 0;
+        }
+        inode.size = 0;
+        Ok(())
+    }
+
+    fn read_data(
+        &self,
+        inode: &DiskInode,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, SysError> {
+        let n = buf.len().min((inode.size as usize).saturating_sub(offset));
+        let mut inode = *inode;
+        let mut done = 0;
+        while done < n {
+            let pos = offset + done;
+            let start = pos % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - start).min(n - done);
+            let out = &mut buf[done..done + len];
+            match self.bmap(&mut inode, (pos / BLOCK_SIZE) as u64, false)? {
+                0 => out.fill(0),
+                zone => {
+                    let b = bcache::bread(self.dev, zone as u64)?;
+                    out.copy_from_slice(&b.data()[start..start + len]);
+                }
+            }
+            done += len;
+        }
+        Ok(n)
+    }
+
+    /// Start reading the blocks of the file of `inode` that the `len` bytes
+    /// from `offset` are in, without waiting for them.
+    fn readahead(&self, inode: &DiskInode, offset: usize, len: usize) {
+        let end = (offset + len).min(inode.size as usize);
+        let mut inode = *inode;
+        for n in offset / BLOCK_SIZE..end.div_ceil(BLOCK_SIZE) {
+            match self.bmap(&mut inode, n as u64, false) {
+                Ok(0) => {}
+                Ok(zone) => bcache::readahead(self.dev, zone as u64, 1),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Write `buf` at `offset` in the file of `inode`, which grows if it
+    /// has to. The caller writes the inode out. If the disk fills up, this
+    /// returns what was written up to there.
+    fn write_data(
+        &self,
+        inode: &mut DiskInode,
+        offset: usize,
+        buf: &[u8],
+    ) -> Result<usize, SysError> {
+        if offset
+            .checked_add(buf.len())
+            .is_none_or(|end| end > self.max_size as usize)
+        {
+            return Err(Errno(EFBIG));
+        }
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let start = pos % BLOCK_SIZE;
+            let len = (BLOCK_SIZE - start).min(buf.len() - done);
+            let zone = match self.bmap(inode, (pos / BLOCK_SIZE) as u64, true) {
+                Ok(zone) => zone,
+                Err(Errno(ENOSPC)) if done > 0 => break,
+                Err(e) => return Err(e),
+            };
+            let b = bcache::bread(self.dev, zone as u64)?;
+            b.data_mut()[start..start + len].copy_from_slice(&buf[done..done + len]);
+            bcache::bdwrite(&b);
+            done += len;
+            inode.size = inode.size.max((pos + len) as u32);
+        }
+        inode.mtime = now();
+        inode.ctime = inode.mtime;
+        Ok(done)
+    }
+
+    /// The entry at `offset` in the directory of `dir`: its inode number,
+    /// 0 if it's free, and its name.
+    fn dir_entry(&self, dir: &DiskInode, offset: usize) -> Result<(u32, Vec<u8>), SysError> {
+        let mut e = [0; DIRENT_SIZE];
+        self.read_data(dir, offset, &mut e)?;
+        let name = &e[4..];
+        let len = name.iter().position(|&c| c == 0).unwrap_or(NAME_LEN);
+        Ok((u32_at(&e, 0), name[..len].to_vec()))
+    }
+
+    /// Where the entry `name` is in the directory of `dir`, and its inode
+    /// number.
+    fn entry_at(&self, dir: &DiskInode, name: &[u8]) -> Result<(usize, u32), SysError> {
+        for offset in (0..dir.size as usize).step_by(DIRENT_SIZE) {
+            match self.dir_entry(dir, offset)? {
+                (ino, n) if ino != 0 && n == name => return Ok((offset, ino)),
+                _ => {}
+            }
+        }
+        Err(Errno(ENOENT))
+    }
+
+    fn find_entry(&self, dir: &DiskInode, name: &[u8]) -> Result<u32, SysError> {
+        Ok(self.entry_at(dir, name)?.1)
+    }
+
+    /// Make the entry `name` of the directory `dir_ino` the name of inode
+    /// `ino`, or free it if that's 0.
+    fn set_entry(&self, dir_ino: u32, name: &[u8], ino: u32) -> Result<(), SysError> {
+        let (offset, _) = self.entry_at(&self.read_inode(dir_ino)?, name)?;
+        self.set_entry_at(dir_ino, offset, ino)
+    }
+
+    /// Make the entry at `offset` in the directory `dir_ino` the name of
+    /// inode `ino`, or free it if that's 0.
+    fn set_entry_at(&self, dir_ino: u32, offset: usize, ino: u32) -> Result<(), SysError> {
+        let mut dir = self.read_inode(dir_ino)?;
+        self.write_data(&mut dir, offset, &ino.to_le_bytes())?;
+        self.write_inode(dir_ino, &dir)
+    }
+
+    /// Whether the directory of `dir` has nothing but . and .. in it.
+    fn is_empty(&self, dir: &DiskInode) -> Result<bool, SysError> {
+        for offset in (0..dir.size as usize).step_by(DIRENT_SIZE) {
+            match self.dir_entry(dir, offset)? {
+                (0, _) => {}
+                (_, name) if name == b"." || name == b".." => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Change inode `ino` with `f`, and write it back.
+    fn change(&self, ino: u32, f: impl FnOnce(&mut DiskInode)) -> Result<(), SysError> {
+        if self.read_only {
+            return Err(Errno(EROFS));
+        }
+        let mut inode = self.read_inode(ino)?;
+        f(&mut inode);
+        inode.ctime = now();
+        self.write_inode(ino, &inode)
+    }
+
+    /// Count a link less of inode `ino`, whose name in the directory
+    /// `dir_ino` is gone. A directory loses its . with it, and the
+    /// directory its .. was a link to.
+    fn unlinked(&self, dir_ino: u32, ino: u32) -> Result<(), SysError> {
+        let inode = self.read_inode(ino)?;
+        if inode.is_dir() {
+            self.change(dir_ino, |dir| dir.nlinks -= 1)?;
+            self.change(ino, |inode| inode.nlinks = 0)?;
+        } else {
+            self.change(ino, |inode| inode.nlinks = inode.nlinks.saturating_sub(1))?;
+        }
+        self.release(ino)
+    }
+
+    /// Free inode `ino` and its zones if it has no links, and nobody has
+    /// it open.
+    fn release(&self, ino: u32) -> Result<(), SysError> {
+        let mut inode = self.read_inode(ino)?;
+        if inode.nlinks > 0 || self.open.borrow().contains_key(&ino) {
+            return Ok(());
+        }
+        self.truncate(&mut inode)?;
+        inode.mode = 0;
+        self.write_inode(ino, &inode)?;
+        self.free_inode(ino)
+    }
+
+    /// Put the entry `name` for inode `ino` in the directory `dir_ino`, in
+    /// the first free entry, or at the end.
+    fn add_entry(&self, dir_ino: u32, name: &[u8], ino: u32) -> Result<(), SysError> {
+        let mut dir = self.read_inode(dir_ino)?;
+        let mut offset = dir.size as usize;
+        for off in (0..dir.size as usize).step_by(DIRENT_SIZE) {
+            if self.dir_entry(&dir, off)?.0 == 0 {
+                offset = off;
+                break;
+            }
+        }
+        let mut e = [0; DIRENT_SIZE];
+        e[0..4].copy_from_slice(&ino.to_le_bytes());
+        e[4..4 + name.len()].copy_from_slice(name);
+        if self.write_data(&mut dir, offset, &e)? < DIRENT_SIZE {
+            return Err(Errno(ENOSPC));
+        }
+        self.write_inode(dir_ino, &dir)
+    }
+
+    /// Make a file of `mode` called `name` in the directory `dir_ino`, and
+    /// return its inode number.
+    fn make(&self, dir_ino: u32, name: &[u8], mode: u16, nlinks: u16) -> Result<u32, SysError> {
+        if self.read_only {
+            return Err(Errno(EROFS));
+        }
+        if name.len() > NAME_LEN {
+            return Err(Errno(ENAMETOOLONG));
+        }
+        let dir = self.read_inode(dir_ino)?;
+        match self.find_entry(&dir, name) {
+            Ok(_) => return Err(Errno(EEXIST)),
+            Err(Errno(ENOENT)) => {}
+            Err(e) => return Err(e),
+        }
+        let ino = self.alloc_inode()?;
+        let made = self
+            .write_inode(ino, &DiskInode::new(mode, nlinks))
+            .and_then(|_| self.add_entry(dir_ino, name, ino));
+        if let Err(e) = made {
+            let _ = self.free_inode(ino);
+            return Err(e);
+        }
+        Ok(ino)
+    }
+}
+
+/// Read the superblock of the Minix 3 filesystem on disk `dev`, which is
+/// read-only if `read_only` or if the disk is.
+fn read_super(dev: usize, read_only: bool) -> Result<Fs, SysError> {
+    let read_only = read_only || block::get(dev).ok_or(Errno(EIO))?.read_only();
+    let b = bcache::bread(dev, SUPER_BLOCK)?;
+    let sb = b.data();
+    if u16_at(&sb, 24) != MAGIC {
+        return Err(Errno(EINVAL));
+    }
+    // Zones bigger than blocks, and blocks that aren't the cache's, we
+    // don't do.
+    if u16_at(&sb, 12) != 0 || u16_at(&sb, 28) as usize != BLOCK_SIZE {
+        warn!("disk {} has blocks or zones we can't handle", dev);
+        return Err(Errno(EINVAL));
+    }
+    let fs = Fs {
+        dev,
+        read_only,
+        ninodes: u32_at(&sb, 0),
+        imap_blocks: u16_at(&sb, 6) as u64,
+        zmap_blocks: u16_at(&sb, 8) as u64,
+        first_data: u16_at(&sb, 10) as u32,
+        max_size: u32_at(&sb, 16),
+        zones: u32_at(&sb, 20),
+        open: RefCell::new(BTreeMap::new()),
+    };
+    if fs.first_data as u64 <= fs.itable() || fs.zones <= fs.first_data || fs.ninodes == 0 {
+        return Err(Errno(EINVAL));
+    }
+    Ok(fs)
+}
+
+/// Mount the Minix 3 filesystem on disk `dev`, read-only if `read_only`
+/// or if the disk is, and return its root.
+pub fn mount(dev: usize, read_only: bool) -> Result<Rc<dyn Inode>, SysError> {
+    let fs = read_super(dev, read_only)?;
+    filesystems().push(fs);
+    Ok(Rc::new(Node {
+        fs: filesystems().len() - 1,
+        ino: ROOT_INO,
+        open: false,
+        ra: Readahead::new(),
+    }))
+}
+
+/// Mount every disk that has a Minix 3 filesystem.
+pub fn init() {
+    vfs::mount_disks("minix", mount, false);
+    shell::register(&Builtin {
+        name: "fsck",
+        help: "fsck DISK [-r] - check the Minix filesystem on a disk, and repair it",
+        run: fsck_command,
+    });
+}
+
+/// A file of a Minix filesystem, both as an inode and, if `open`, opened.
+struct Node {
+    fs: usize,
+    ino: u32,
+    open: bool,
+    ra: Readahead,
+}
+
+impl Node {
+    fn fs(&self) -> &'static Fs {
+        &filesystems()[self.fs]
+    }
+
+    fn inode(&self) -> Result<DiskInode, SysError> {
+        self.fs().read_inode(self.ino)
+    }
+
+    /// Inode `ino` of the same filesystem.
+    fn node(&self, ino: u32) -> Rc<Node> {
+        Rc::new(Node {
+            fs: self.fs,
+            ino,
+            open: false,
+            ra: Readahead::new(),
+        })
+    }
+
+    /// Inode `ino` of the same filesystem, opened.
+    fn opened(&self, ino: u32) -> Rc<Node> {
+        *self.fs().open.borrow_mut().entry(ino).or_insert(0) += 1;
+        Rc::new(Node {
+            fs: self.fs,
+            ino,
+            open: true,
+            ra: Readahead::new(),
+        })
+    }
+
+    /// The other node, if it's of the same filesystem.
+    fn same_fs<'a>(&self, other: &'a dyn Inode) -> Result<&'a Node, SysError> {
+        let node = vfs::downcast::<Node>(other)?;
+        if node.fs != self.fs {
+            return Err(Errno(EXDEV));
+        }
+        Ok(node)
+    }
+}
+
+/// Closing a file for the last time frees it, if it has no names left.
+impl Drop for Node {
+    fn drop(&mut self) {
+        if !self.open {
+            return;
+        }
+        let fs = self.fs();
+        let mut open = fs.open.borrow_mut();
+        let count = open.get_mut(&self.ino).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            open.remove(&self.ino);
+            drop(open);
+            let _ = fs.release(self.ino);
+        }
+    }
+}
+
+impl Inode for Node {
+    fn lookup(&self, name: &[u8]) -> Result<Rc<dyn Inode>, SysError> {
+        let dir = self.inode()?;
+        if !dir.is_dir() {
+            return Err(Errno(ENOTDIR));
+        }
+        if name.len() > NAME_LEN {
+            return Err(Errno(ENAMETOOLONG));
+        }
+        let ino = self.fs().find_entry(&dir, name)?;
+        Ok(self.node(ino))
+    }
+
+    fn create(&self, name: &[u8], _flags: usize, mode: usize) -> Result<Rc<dyn File>, SysError> {
+        let mode = S_IFREG as u16 | (mode & 0o7777) as u16;
+        let ino = self.fs().make(self.ino, name, mode, 1)?;
+        Ok(self.opened(ino))
+    }
+
+    /// A new directory has entries for . and .., which count as links to
+    /// it and to its parent.
+    fn mkdir(&self, name: &[u8], mode: u32) -> Result<Rc<dyn Inode>, SysError> {
+        let fs = self.fs();
+        let ino = fs.make(self.ino, name, (S_IFDIR | mode) as u16, 2)?;
+        fs.add_entry(ino, b".", ino)?;
+        fs.add_entry(ino, b"..", self.ino)?;
+        let mut parent = self.inode()?;
+        parent.nlinks += 1;
+        fs.write_inode(self.ino, &parent)?;
+        Ok(self.node(ino))
+    }
+
+    fn unlink(&self, name: &[u8], dir: bool) -> Result<(), SysError> {
+        let fs = self.fs();
+        if fs.read_only {
+            return Err(Errno(EROFS));
+        }
+        let ino = fs.find_entry(&self.inode()?, name)?;
+        let inode = fs.read_inode(ino)?;
+        match (inode.is_dir(), dir) {
+            (true, false) => return Err(Errno(EISDIR)),
+            (false, true) => return Err(Errno(ENOTDIR)),
+            (true, true) if !fs.is_empty(&inode)? => return Err(Errno(ENOTEMPTY)),
+            _ => {}
+        }
+        fs.set_entry(self.ino, name, 0)?;
+        fs.unlinked(self.ino, ino)
+    }
+
+    fn link(&self, name: &[u8], inode: &dyn Inode) -> Result<(), SysError> {
+        let target = self.same_fs(inode)?;
+        let fs = self.fs();
+        if fs.read_only {
+            return Err(Errno(EROFS));
+        }
+        if name.len() > NAME_LEN {
+            return Err(Errno(ENAMETOOLONG));
+        }
+        match fs.find_entry(&self.inode()?, name) {
+            Ok(_) => return Err(Errno(EEXIST)),
+            Err(Errno(ENOENT)) => {}
+            Err(e) => return Err(e),
+        }
+        if target.inode()?.nlinks >= LINK_MAX {
+            return Err(Errno(EMLINK));
+        }
+        fs.add_entry(self.ino, name, target.ino)?;
+        fs.change(target.ino, |inode| inode.nlinks += 1)
+    }
+
+    /// A directory that moves to another directory has its .. changed to
+    /// that one, which the link counts of both follow.
+    fn rename(&self, old: &[u8], dir: &dyn Inode, new: &[u8]) -> Result<(), SysError> {
+        let to = self.same_fs(dir)?;
+        let fs = self.fs();
+        if fs.read_only {
+            return Err(Errno(EROFS));
+        }
+        if new.len() > NAME_LEN {
+            return Err(Errno(ENAMETOOLONG));
+        }
+        let ino = fs.find_entry(&self.inode()?, old)?;
+        let is_dir = fs.read_inode(ino)?.is_dir();
+        match fs.find_entry(&to.inode()?, new) {
+            Ok(there) if there == ino => return Ok(()),
+            Ok(there) => {
+                if is_dir && !fs.is_empty(&fs.read_inode(there)?)? {
+                    return Err(Errno(ENOTEMPTY));
+                }
+                fs.set_entry(to.ino, new, ino)?;
+                fs.unlinked(to.ino, there)?;
+            }
+            Err(Errno(ENOENT)) => fs.add_entry(to.ino, new, ino)?,
+            Err(e) => return Err(e),
+        }
+        fs.set_entry(self.ino, old, 0)?;
+        if is_dir && to.ino != self.ino {
+            fs.set_entry(ino, b"..", to.ino)?;
+            fs.change(self.ino, |dir| dir.nlinks -= 1)?;
+            fs.change(to.ino, |dir| dir.nlinks += 1)?;
+        }
+        fs.change(ino, |_| {})
+    }
+
+    fn symlink(&self, name: &[u8], target: &[u8]) -> Result<(), SysError> {
+        let fs = self.fs();
+        let ino = fs.make(self.ino, name, (S_IFLNK | 0o777) as u16, 1)?;
+        let mut inode = fs.read_inode(ino)?;
+        let n = fs.write_data(&mut inode, 0, target)?;
+        fs.write_inode(ino, &inode)?;
+        if n < target.len() {
+            return Err(Errno(ENOSPC));
+        }
+        Ok(())
+    }
+
+    fn readlink(&self) -> Result<Vec<u8>, SysError> {
+        let inode = self.inode()?;
+        if inode.mode as u32 & S_IFMT != S_IFLNK {
+            return Err(Errno(EINVAL));
+        }
+        let mut target = vec![0; (inode.size as usize).min(PATH_MAX)];
+        let n = self.fs().read_data(&inode, 0, &mut target)?;
+        target.truncate(n);
+        Ok(target)
+    }
+
+    fn chmod(&self, mode: u32) -> Result<(), SysError> {
+        let mode = |inode: &mut DiskInode| inode.mode = (inode.mode as u32 & S_IFMT | mode) as u16;
+        self.fs().change(self.ino, mode)
+    }
+
+    /// Ids that don't fit in 16 bits are cut short, as Linux does.
+    fn chown(&self, uid: u32, gid: u32) -> Result<(), SysError> {
+        let owner = |inode: &mut DiskInode| (inode.uid, inode.gid) = (uid as u16, gid as u16);
+        self.fs().change(self.ino, owner)
+    }
+
+    fn open(&self, flags: usize) -> Result<Rc<dyn File>, SysError> {
+        let mut inode = self.inode()?;
+        if flags & O_ACCMODE != O_RDONLY {
+            if inode.is_dir() {
+                return Err(Errno(EISDIR));
+            }
+            if self.fs().read_only {
+                return Err(Errno(EROFS));
+            }
+            if flags & O_TRUNC != 0 && inode.size > 0 {
+                self.fs().truncate(&mut inode)?;
+                inode.mtime = now();
+                inode.ctime = inode.mtime;
+                self.fs().write_inode(self.ino, &inode)?;
+            }
+        }
+        Ok(self.opened(self.ino))
+    }
+
+    fn metadata(&self) -> Stat {
+        let Ok(i) = self.inode() else {
+            return Stat::default();
+        };
+        let disk = block::get(self.fs().dev).unwrap();
+        Stat {
+            st_dev: disk.rdev(),
+            st_ino: self.ino as u64,
+            st_mode: i.mode as u32,
+            st_nlink: i.nlinks as u32,
+            st_uid: i.uid as u32,
+            st_gid: i.gid as u32,
+            st_size: i.size as i64,
+            st_blksize: BLOCK_SIZE as i32,
+            // In 512-byte units, as if there were no holes.
+            st_blocks: (i.size as usize).div_ceil(BLOCK_SIZE) as i64 * 2,
+            st_atime: i.atime as i64,
+            st_mtime: i.mtime as i64,
+            st_ctime: i.ctime as i64,
+            ..Default::default()
+        }
+    }
+}
+
+impl File for Node {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let inode = self.inode()?;
+        if inode.is_dir() {
+            return Err(Errno(EISDIR));
+        }
+        let n = buf.len().min((inode.size as usize).saturating_sub(offset));
+        if n == 0 {
+            return Ok(0);
+        }
+        let ahead = self.ra.window(offset, n);
+        // Reading can be restarted, what's read is cached by then.
+        block::with_sleep(true, || {
+            self.fs().readahead(&inode, offset, n + ahead);
+            self.fs().read_data(&inode, offset, &mut buf[..n])
+        })
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        let fs = self.fs();
+        let mut inode = self.inode()?;
+        let n = fs.write_data(&mut inode, offset, buf)?;
+        fs.write_inode(self.ino, &inode)?;
+        Ok(n)
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.inode().ok()?.size as usize)
+    }
+
+    fn stat(&self) -> Stat {
+        self.metadata()
+    }
+
+    fn wait(&self, pid: usize) {
+        block::wait(self.fs().dev, pid);
+    }
+
+    /// All of the disk, see bcache::sync().
+    fn sync(&self) -> Result<(), SysError> {
+        block::with_sleep(true, || bcache::sync(Some(self.fs().dev)))
+    }
+
+    /// Offsets are where the entries are in the directory.
+    fn getdents(&self, offset: usize, buf: &mut [u8]) -> Result<(usize, usize), SysError> {
+        let fs = self.fs();
+        let dir = self.inode()?;
+        if !dir.is_dir() {
+            return Err(Errno(ENOTDIR));
+        }
+        let (mut len, mut next) = (0, offset);
+        while next < dir.size as usize {
+            let (ino, name) = fs.dir_entry(&dir, next)?;
+            if ino != 0 {
+                let kind = vfs::dirent_type(fs.read_inode(ino)?.mode as u32);
+                let end = (next + DIRENT_SIZE) as u64;
+                let Some(n) = vfs::put_dirent(&mut buf[len..], ino as u64, end, kind, &name) else {
+                    break;
+                };
+                len += n;
+            }
+            next += DIRENT_SIZE;
+        }
+        if len == 0 && next < dir.size as usize {
+            // Not even one entry fits.
+            return Err(Errno(EINVAL));
+        }
+        Ok((len, next))
+    }
+}
+
+// Checking a filesystem, as fsck.minix does, for when something wrote it
+// wrong: most likely us, while the code that writes is young. Every
+// directory is walked from the root, which says which inodes are in use
+// and how many names each has, and the zones of every file are followed,
+// which says which zones are. The superblock, the inodes' link counts and
+// both bitmaps are checked against that.
+//
+// Repairing puts things right the simple way. Entries for inodes that
+// aren't there are freed, and so are second names of directories. A zone
+// outside the data zones, or that another file had first, is taken away
+// from the file. Link counts and bitmaps are set to what was counted, so
+// an inode in use without a name is freed with its zones: there's no
+// lost+found to put it in. A file that's open with no names is in use
+// until it's closed. What's wrong with the superblock can't be fixed.
+
+/// What checking a filesystem has found so far.
+struct Check<'a> {
+    fs: &'a Fs,
+    repair: bool,
+    problems: usize,
+    /// Which inodes were found, and how many names each has.
+    seen: Vec<bool>,
+    links: Vec<u32>,
+    /// Which zones are used, by their bit in the zone bitmap.
+    zones: Vec<bool>,
+}
+
+impl Check<'_> {
+    fn problem(&mut self, what: String) {
+        self.problems += 1;
+        let fixed = if self.repair { ", fixed" } else { "" };
+        println!("fsck: disk {}: {}{}", self.fs.dev, what, fixed);
+    }
+
+    /// Whether the superblock makes sense, so the rest can be checked.
+    fn superblock(&mut self) -> bool {
+        let fs = self.fs;
+        let disk = block::get(fs.dev).unwrap();
+        let blocks = disk.num_sectors() * SECTOR_SIZE as u64 / BLOCK_SIZE as u64;
+        let itable_blocks = fs.ninodes.div_ceil(INODES_PER_BLOCK) as u64;
+        let wrong = if fs.imap_blocks * (BITS_PER_BLOCK as u64) <= fs.ninodes as u64 {
+            "the inode bitmap is too small for the inodes"
+        } else if fs.zmap_blocks * (BITS_PER_BLOCK as u64) <= (fs.zones - fs.first_data) as u64 {
+            "the zone bitmap is too small for the zones"
+        } else if (fs.first_data as u64) < fs.itable() + itable_blocks {
+            "the inodes go past where the data zones start"
+        } else if fs.zones as u64 > blocks {
+            "the filesystem is bigger than the disk"
+        } else {
+            return true;
+        };
+        self.problems += 1;
+        println!("fsck: disk {}: {}, which can't be fixed", fs.dev, wrong);
+        false
+    }
+
+    /// Count `zone` of inode `ino`, `levels` levels of indirect blocks
+    /// above the file's data, and everything under it as used. Returns
+    /// false if the file can't have it, and the caller is to take it away.
+    fn zone(&mut self, ino: u32, zone: u32, levels: u32) -> Result<bool, SysError> {
+        let fs = self.fs;
+        if zone == 0 {
+            return Ok(true);
+        }
+        if zone < fs.first_data || zone >= fs.zones {
+            self.problem(format!(
+                "inode {} has zone {}, which isn't a data zone",
+                ino, zone
+            ));
+            return Ok(false);
+        }
+        let bit = (zone - fs.first_data + 1) as usize;
+        if self.zones[bit] {
+            self.problem(format!(
+                "inode {} has zone {}, which another file has too",
+                ino, zone
+            ));
+            return Ok(false);
+        }
+        self.zones[bit] = true;
+        if levels == 0 {
+            return Ok(true);
+        }
+        let b = bcache::bread(fs.dev, zone as u64)?;
+        let children: Vec<u32> = (0..ZONES_PER_BLOCK as usize)
+            .map(|i| u32_at(&b.data(), i * 4))
+            .collect();
+        drop(b);
+        for (i, child) in children.into_iter().enumerate() {
+            if !self.zone(ino, child, levels - 1)? && self.repair {
+                let b = bcache::bread(fs.dev, zone as u64)?;
+                b.data_mut()[i * 4..i * 4 + 4].fill(0);
+                bcache::bdwrite(&b);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Count inode `ino` as found, and its zones as used.
+    fn found(&mut self, ino: u32) -> Result<(), SysError> {
+        self.seen[ino as usize] = true;
+        let mut inode = self.fs.read_inode(ino)?;
+        // Device files keep their device number where the zones go.
+        if !matches!(inode.mode as u32 & S_IFMT, S_IFREG | S_IFDIR | S_IFLNK) {
+            return Ok(());
+        }
+        let mut changed = false;
+        for i in 0..inode.zones.len() {
+            let levels = i.saturating_sub(DIRECT_ZONES - 1) as u32;
+            if !self.zone(ino, inode.zones[i], levels)? && self.repair {
+                inode.zones[i] = 0;
+                changed = true;
+            }
+        }
+        if changed {
+            self.fs.write_inode(ino, &inode)?;
+        }
+        Ok(())
+    }
+
+    /// Check the entry `name` for inode `ino`, at `offset` in the directory
+    /// `dir`, whose parent is `parent`. Returns the inode it's for once
+    /// it's right, None if it's for none.
+    fn entry(
+        &mut self,
+        dir: u32,
+        parent: u32,
+        offset: usize,
+        ino: u32,
+        name: &[u8],
+    ) -> Result<Option<u32>, SysError> {
+        let fs = self.fs;
+        let shown = core::str::from_utf8(name).unwrap_or("?");
+        let right = match name {
+            b"." => Some(dir),
+            b".." => Some(parent),
+            _ => None,
+        };
+        let wrong = if ino > fs.ninodes {
+            Some("an inode there isn't")
+        } else if fs.read_inode(ino)?.mode == 0 {
+            Some("a free inode")
+        } else if right.is_some_and(|right| right != ino) {
+            Some("the wrong directory")
+        } else if right.is_none() && fs.read_inode(ino)?.is_dir() && self.seen[ino as usize] {
+            Some("a directory with a name already")
+        } else {
+            None
+        };
+        let Some(wrong) = wrong else {
+            return Ok(Some(ino));
+        };
+        self.problem(format!(
+            "{} in directory {} is for {}, inode {}",
+            shown, dir, wrong, ino
+        ));
+        if !self.repair {
+            return Ok(None);
+        }
+        fs.set_entry_at(dir, offset, right.unwrap_or(0))?;
+        Ok(right)
+    }
+
+    /// Walk every directory from the root, counting names and zones.
+    fn walk(&mut self) -> Result<(), SysError> {
+        let fs = self.fs;
+        if !fs.read_inode(ROOT_INO)?.is_dir() {
+            self.problems += 1;
+            println!(
+                "fsck: disk {}: the root isn't a directory, which can't be fixed",
+                fs.dev
+            );
+            return Err(Errno(EINVAL));
+        }
+        self.found(ROOT_INO)?;
+        let mut dirs = vec![(ROOT_INO, ROOT_INO)];
+        while let Some((dir_ino, parent)) = dirs.pop() {
+            let (mut dot, mut dotdot) = (false, false);
+            let dir = fs.read_inode(dir_ino)?;
+            for offset in (0..dir.size as usize).step_by(DIRENT_SIZE) {
+                let (ino, name) = fs.dir_entry(&dir, offset)?;
+                if ino == 0 {
+                    continue;
+                }
+                dot |= name == b".";
+                dotdot |= name == b"..";
+                let Some(ino) = self.entry(dir_ino, parent, offset, ino, &name)? else {
+                    continue;
+                };
+                self.links[ino as usize] += 1;
+                if self.seen[ino as usize] {
+                    continue;
+                }
+                self.found(ino)?;
+                if fs.read_inode(ino)?.is_dir() {
+                    dirs.push((ino, dir_ino));
+                }
+            }
+            for (there, name, ino) in [(dot, ".", dir_ino), (dotdot, "..", parent)] {
+                if there {
+                    continue;
+                }
+                self.problem(format!("directory {} has no {}", dir_ino, name));
+                if self.repair {
+                    fs.add_entry(dir_ino, name.as_bytes(), ino)?;
+                    self.links[ino as usize] += 1;
+                }
+            }
+        }
+        // Files that are open keep their inode and zones without a name.
+        for &ino in fs.open.borrow().keys() {
+            if !self.seen[ino as usize] {
+                self.found(ino)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the link count of every inode that was found.
+    fn link_counts(&mut self) -> Result<(), SysError> {
+        let fs = self.fs;
+        for ino in 1..=fs.ninodes {
+            if !self.seen[ino as usize] {
+                continue;
+            }
+            let mut inode = fs.read_inode(ino)?;
+            let links = self.links[ino as usize];
+            if inode.nlinks as u32 == links {
+                continue;
+            }
+            self.problem(format!(
+                "inode {} has a link count of {}, but {} names",
+                ino, inode.nlinks, links
+            ));
+            if self.repair {
+                inode.nlinks = links.min(LINK_MAX as u32) as u16;
+                fs.write_inode(ino, &inode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the bitmap at block `start` has the bits of `used` set, and
+    /// no others. Returns how many it has set that it shouldn't, and how
+    /// many it doesn't that it should.
+    fn bitmap(&self, start: u64, used: &[bool]) -> Result<(usize, usize), SysError> {
+        let fs = self.fs;
+        let (mut extra, mut missing) = (0, 0);
+        for (n, bits) in used.chunks(BITS_PER_BLOCK as usize).enumerate() {
+            let b = bcache::bread(fs.dev, start + n as u64)?;
+            let mut changed = false;
+            for (i, &used) in bits.iter().enumerate() {
+                let (byte, mask) = (i / 8, 1 << (i % 8));
+                let set = b.data()[byte] & mask != 0;
+                match (set, used) {
+                    (true, false) => extra += 1,
+                    (false, true) => missing += 1,
+                    _ => continue,
+                }
+                if self.repair {
+                    b.data_mut()[byte] ^= mask;
+                    changed = true;
+                }
+            }
+            if changed {
+                bcache::bdwrite(&b);
+            }
+        }
+        Ok((extra, missing))
+    }
+
+    /// Check both bitmaps say what's used.
+    fn bitmaps(&mut self) -> Result<(), SysError> {
+        let fs = self.fs;
+        // Bit 0 of both is always set.
+        let mut inodes = core::mem::take(&mut self.seen);
+        let mut zones = core::mem::take(&mut self.zones);
+        inodes[0] = true;
+        zones[0] = true;
+        let (extra, missing) = self.bitmap(IMAP_BLOCK, &inodes)?;
+        if extra > 0 {
+            self.problem(format!("{} inodes in use have no name", extra));
+        }
+        if missing > 0 {
+            self.problem(format!("{} inodes in use are free in the bitmap", missing));
+        }
+        let (extra, missing) = self.bitmap(fs.zmap(), &zones)?;
+        if extra > 0 {
+            self.problem(format!("{} zones in use aren't of any file", extra));
+        }
+        if missing > 0 {
+            self.problem(format!("{} zones of files are free in the bitmap", missing));
+        }
+        Ok(())
+    }
+}
+
+/// Check the Minix 3 filesystem on disk `dev`, mounted or not, and with
+/// `repair`, put right what's wrong with it. Returns how many problems
+/// were found. It waits for the disk with interrupts off, so nothing
+/// changes the filesystem while it's being checked.
+pub fn fsck(dev: usize, repair: bool) -> Result<usize, SysError> {
+    let mut fs = read_super(dev, !repair)?;
+    if repair && fs.read_only {
+        return Err(Errno(EROFS));
+    }
+    // The files that are open, in any mount of the disk.
+    for other in filesystems().iter().filter(|other| other.dev == dev) {
+        for (&ino, &count) in other.open.borrow().iter() {
+            *fs.open.get_mut().entry(ino).or_insert(0) += count;
+        }
+    }
+    let problems = cpu::without_interrupts(|| {
+        block::with_sleep(false, || -> Result<usize, SysError> {
+            let mut check = Check {
+                fs: &fs,
+                repair,
+                problems: 0,
+                seen: vec![false; fs.ninodes as usize + 1],
+                links: vec![0; fs.ninodes as usize + 1],
+                zones: vec![false; (fs.zones - fs.first_data + 1) as usize],
+            };
+            if !check.superblock() {
+                return Err(Errno(EINVAL));
+            }
+            check.walk()?;
+            check.link_counts()?;
+            check.bitmaps()?;
+            if repair && check.problems > 0 {
+                bcache::sync(Some(dev))?;
+            }
+            Ok(check.problems)
+        })
+    })?;
+    match (problems, repair) {
+        (0, _) => println!("fsck: disk {} is clean", dev),
+        (n, false) => println!("fsck: disk {}: {} problems", dev, n),
+        (n, true) => println!("fsck: disk {}: {} problems, all fixed", dev, n),
+    }
+    Ok(problems)
+}
+
+fn fsck_command(args: &[&str]) -> Result<(), SysError> {
+    let (disk, repair) = match args {
+        [disk] => (disk, false),
+        [disk, "-r"] => (disk, true),
+        _ => return Err(Errno(EINVAL)),
+    };
+    let name = disk.strip_prefix("/dev/").unwrap_or(disk);
+    let dev = block::find(name.as_bytes()).ok_or(Errno(ENODEV))?;
+    fsck(dev, repair)?;
+    Ok(())
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::check;
+
+    /// Writes that would end past the biggest file there can be, or past
+    /// the end of the address space, are EFBIG before the disk is touched,
+    /// so the made-up filesystem here has no disk.
+    #[test_case]
+    fn write_bounds_test() -> Result<(), String> {
+        let fs = Fs {
+            dev: usize::MAX,
+            read_only: false,
+            ninodes: 1,
+            zones: 0,
+            first_data: 0,
+            max_size: 1 << 20,
+            imap_blocks: 0,
+            zmap_blocks: 0,
+            open: RefCell::new(BTreeMap::new()),
+        };
+        let mut inode = DiskInode::new(S_IFREG as u16, 1);
+        for (offset, len) in [(1 << 20, 1), ((1 << 20) - 1, 2), (usize::MAX, 1)] {
+            let ret = fs.write_data(&mut inode, offset, &vec![0; len]);
+            check(matches!(ret, Err(Errno(EFBIG))), || {
+                format!("{} bytes at {} aren't EFBIG", len, offset)
+            })?;
+        }
+        Ok(())
+    }
+}
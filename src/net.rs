@@ -0,0 +1,477 @@
+// The network stack. This is UDP over IPv4: sockets bind to ports, and
+// datagrams sent to a bound port are queued on its socket until someone
+// receives them. Datagrams for 127.0.0.0/8 never leave the machine. If there
+// is a network interface, datagrams for other addresses go out through it
+// as Ethernet frames, and ones that come in through it are delivered the
+// same way; without one, sending them fails with ENETUNREACH.
+
+use crate::{
+    file::File,
+    nic,
+    process::WaitQueue,
+    syscall::{Stat, SysError, EADDRINUSE, EDESTADDRREQ, EINVAL, EMSGSIZE, ENETUNREACH},
+};
+use alloc::{
+    collections::{btree_map::BTreeMap, vec_deque::VecDeque},
+    rc::{Rc, Weak},
+    vec::Vec,
+};
+use core::{cell::RefCell, ptr::addr_of_mut};
+
+use SysError::{Block, Errno};
+
+// Address families, socket types and protocols for socket()
+pub const AF_INET: usize = 2;
+pub const SOCK_STREAM: usize = 1;
+pub const SOCK_DGRAM: usize = 2;
+pub const SOCK_NONBLOCK: usize = 0o4000;
+pub const SOCK_CLOEXEC: usize = 0o2000000;
+pub const IPPROTO_UDP: usize = 17;
+
+/// The loopback address, 127.0.0.1.
+pub const INADDR_LOOPBACK: u32 = 0x7f00_0001;
+/// The largest UDP payload that fits in an IPv4 packet.
+pub const MAX_DATAGRAM: usize = 65507;
+/// How many datagrams a socket queues before new ones are dropped.
+const QUEUE_LEN: usize = 64;
+// Ports we pick for sockets that send without binding first.
+const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// The file type of a socket, for st_mode.
+pub const S_IFSOCK: u32 = 0o140000;
+
+/// struct sockaddr_in. The port and the address are in network byte order.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SockAddrIn {
+    pub sin_family: u16,
+    pub sin_port: u16,
+    pub sin_addr: u32,
+    pub sin_zero: [u8; 8],
+}
+
+/// An IPv4 address and port, in host byte order.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Endpoint {
+    pub addr: u32,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn from_sockaddr(sa: &SockAddrIn) -> Self {
+        Endpoint {
+            addr: u32::from_be(sa.sin_addr),
+            port: u16::from_be(sa.sin_port),
+        }
+    }
+
+    pub fn to_sockaddr(self) -> SockAddrIn {
+        SockAddrIn {
+            sin_family: AF_INET as u16,
+            sin_port: self.port.to_be(),
+            sin_addr: self.addr.to_be(),
+            sin_zero: [0; 8],
+        }
+    }
+
+    /// Is this one of our own addresses? 0.0.0.0 and all of 127.0.0.0/8
+    /// are, and so is the interface's address if there is one.
+    fn is_local(&self) -> bool {
+        self.addr == 0 || self.addr >> 24 == 127 || (self.addr == LOCAL_ADDR && nic::present())
+    }
+
+    /// Can we send to this address?
+    fn is_reachable(&self) -> bool {
+        self.is_local() || nic::present()
+    }
+}
+
+struct Udp {
+    local: Option<Endpoint>,
+    peer: Option<Endpoint>,
+    queue: VecDeque<(Endpoint, Vec<u8>)>,
+    readers: WaitQueue,
+}
+
+/// A UDP socket.
+pub struct UdpSocket(Rc<RefCell<Udp>>);
+
+// Bound sockets, by port.
+static mut PORTS: BTreeMap<u16, Weak<RefCell<Udp>>> = BTreeMap::new();
+static mut NEXT_EPHEMERAL: u16 = *EPHEMERAL_PORTS.start();
+
+fn ports() -> &'static mut BTreeMap<u16, Weak<RefCell<Udp>>> {
+    unsafe { &mut *addr_of_mut!(PORTS) }
+}
+
+impl UdpSocket {
+    pub fn new() -> Self {
+        UdpSocket(Rc::new(RefCell::new(Udp {
+            local: None,
+            peer: None,
+            queue: VecDeque::new(),
+            readers: WaitQueue::new("socket readers"),
+        })))
+    }
+
+    /// Bind to a local address. Port 0 picks a free port.
+    pub fn bind(&self, mut ep: Endpoint) -> Result<(), SysError> {
+        if self.0.borrow().local.is_some() {
+            return Err(Errno(EINVAL));
+        }
+        if !ep.is_local() {
+            return Err(Errno(ENETUNREACH));
+        }
+        let ports = ports();
+        if ep.port == 0 {
+            ep.port = free_port().ok_or(Errno(EADDRINUSE))?;
+        } else if ports.get(&ep.port).is_some_and(|s| s.strong_count() > 0) {
+            return Err(Errno(EADDRINUSE));
+        }
+        ports.insert(ep.port, Rc::downgrade(&self.0));
+        self.0.borrow_mut().local = Some(ep);
+        Ok(())
+    }
+
+    /// Set the default destination, and only take datagrams from there.
+    pub fn connect(&self, ep: Endpoint) -> Result<(), SysError> {
+        if !ep.is_reachable() {
+            return Err(Errno(ENETUNREACH));
+        }
+        self.0.borrow_mut().peer = Some(ep);
+        Ok(())
+    }
+
+    pub fn local(&self) -> Option<Endpoint> {
+        self.0.borrow().local
+    }
+
+    pub fn peer(&self) -> Option<Endpoint> {
+        self.0.borrow().peer
+    }
+
+    /// Send a datagram to `to`, or to the peer if it is None.
+    pub fn send_to(&self, buf: &[u8], to: Option<Endpoint>) -> Result<usize, SysError> {
+        let to = to.or(self.peer()).ok_or(Errno(EDESTADDRREQ))?;
+        if buf.len() > MAX_DATAGRAM {
+            return Err(Errno(EMSGSIZE));
+        }
+        if !to.is_reachable() {
+            return Err(Errno(ENETUNREACH));
+        }
+        if !to.is_local() && buf.len() > MAX_NET_DATAGRAM {
+            // We don't fragment.
+            return Err(Errno(EMSGSIZE));
+        }
+        // Sending binds the socket if it isn't already, so there is a
+        // port to reply to.
+        if self.local().is_none() {
+            self.bind(Endpoint::default())?;
+        }
+        let mut from = self.local().unwrap();
+        if !to.is_local() {
+            from.addr = LOCAL_ADDR;
+            send_udp(from, to, buf)?;
+        } else {
+            if from.addr == 0 {
+                from.addr = if to.addr == LOCAL_ADDR {
+                    LOCAL_ADDR
+                } else {
+                    INADDR_LOOPBACK
+                };
+            }
+            deliver(from, to, buf);
+        }
+        Ok(buf.len())
+    }
+
+    /// Take the next datagram. It is cut short if it doesn't fit in `buf`,
+    /// and the full length is returned along with the sender.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Endpoint), SysError> {
+        let mut udp = self.0.borrow_mut();
+        let (from, data) = udp.queue.pop_front().ok_or(Block)?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok((data.len(), from))
+    }
+}
+
+impl File for UdpSocket {
+    fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let (n, _) = self.recv_from(buf)?;
+        Ok(n.min(buf.len()))
+    }
+
+    fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        self.send_to(buf, None)
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            st_mode: S_IFSOCK | 0o777,
+            st_nlink: 1,
+            ..Default::default()
+        }
+    }
+
+    fn wait(&self, pid: usize) {
+        self.0.borrow_mut().readers.wait(pid);
+    }
+
+    fn as_udp(&self) -> Option<&UdpSocket> {
+        Some(self)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        if let Some(ep) = self.local() {
+            ports().remove(&ep.port);
+        }
+    }
+}
+
+/// Queue a datagram from `from` on the socket bound to `to`. Like on a real
+/// network, a datagram for a port nobody listens on (or whose queue is
+/// full) is silently dropped.
+fn deliver(from: Endpoint, to: Endpoint, data: &[u8]) {
+    let Some(dest) = ports().get(&to.port).and_then(|s| s.upgrade()) else {
+        return;
+    };
+    let mut dest = dest.borrow_mut();
+    let bound_to = dest.local.map_or(0, |ep| ep.addr);
+    let accepts = dest.peer.is_none_or(|p| p == from) && (bound_to == 0 || bound_to == to.addr);
+    if accepts && dest.queue.len() < QUEUE_LEN {
+        dest.queue.push_back((from, data.to_vec()));
+        dest.readers.wake_all();
+    }
+}
+
+/// Find an ephemeral port nobody is bound to.
+fn free_port() -> Option<u16> {
+    let ports = ports();
+    ports.retain(|_, s| s.strong_count() > 0);
+    let count = EPHEMERAL_PORTS.len();
+    for _ in 0..count {
+        let port = unsafe {
+            let port = NEXT_EPHEMERAL;
+            NEXT_EPHEMERAL = if port == *EPHEMERAL_PORTS.end() {
+                *EPHEMERAL_PORTS.start()
+            } else {
+                port + 1
+            };
+            port
+        };
+        if !ports.contains_key(&port) {
+            return Some(port);
+        }
+    }
+    None
+}
+
+// ///////////////////////////////////
+// / ETHERNET, ARP AND IPV4
+// ///////////////////////////////////
+
+// There is no DHCP: these are the addresses QEMU's user mode network always
+// hands out.
+/// Our address on the network, 10.0.2.15.
+pub const LOCAL_ADDR: u32 = 0x0a00_020f;
+const NETMASK: u32 = 0xffff_ff00;
+/// The host, which routes everything else, 10.0.2.2.
+const GATEWAY: u32 = 0x0a00_0202;
+
+/// The largest UDP payload that fits in one Ethernet frame.
+const MAX_NET_DATAGRAM: usize = nic::MAX_FRAME - ETH_HEADER - IPV4_HEADER - UDP_HEADER;
+
+const ETH_HEADER: usize = 14;
+const IPV4_HEADER: usize = 20;
+const UDP_HEADER: usize = 8;
+const ARP_PACKET: usize = 28;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const BROADCAST: [u8; 6] = [0xff; 6];
+
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+const IP_PROTO_UDP: u8 = 17;
+const DEFAULT_TTL: u8 = 64;
+
+// The MAC addresses of the hosts we've heard from, by IPv4 address.
+static mut ARP_CACHE: BTreeMap<u32, [u8; 6]> = BTreeMap::new();
+
+fn arp_cache() -> &'static mut BTreeMap<u32, [u8; 6]> {
+    unsafe { &mut *addr_of_mut!(ARP_CACHE) }
+}
+
+fn be16(b: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([b[offset], b[offset + 1]])
+}
+
+fn be32(b: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(b[offset..offset + 4].try_into().unwrap())
+}
+
+/// The internet checksum: the ones' complement of the ones' complement sum
+/// of the 16-bit words.
+fn checksum(b: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for word in b.chunks(2) {
+        let hi = word[0] as u32;
+        let lo = word.get(1).copied().unwrap_or(0) as u32;
+        sum += hi << 8 | lo;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Build an Ethernet frame around `payload` and send it.
+fn send_frame(dest: [u8; 6], ethertype: u16, payload: &[u8]) -> Result<(), SysError> {
+    let mac = nic::mac().ok_or(Errno(ENETUNREACH))?;
+    let mut frame = Vec::with_capacity(ETH_HEADER + payload.len());
+    frame.extend_from_slice(&dest);
+    frame.extend_from_slice(&mac);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    nic::send(&frame)
+}
+
+fn send_arp(op: u16, target_mac: [u8; 6], target_addr: u32) -> Result<(), SysError> {
+    let mac = nic::mac().ok_or(Errno(ENETUNREACH))?;
+    let mut arp = Vec::with_capacity(ARP_PACKET);
+    // Ethernet hardware, IPv4 protocol, with their address lengths.
+    arp.extend_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+    arp.extend_from_slice(&op.to_be_bytes());
+    arp.extend_from_slice(&mac);
+    arp.extend_from_slice(&LOCAL_ADDR.to_be_bytes());
+    arp.extend_from_slice(&target_mac);
+    arp.extend_from_slice(&target_addr.to_be_bytes());
+    let dest = if op == ARP_REQUEST {
+        BROADCAST
+    } else {
+        target_mac
+    };
+    send_frame(dest, ETHERTYPE_ARP, &arp)
+}
+
+/// Send a UDP datagram out of the network interface.
+fn send_udp(from: Endpoint, to: Endpoint, data: &[u8]) -> Result<(), SysError> {
+    // Hosts outside of our subnet are reached through the gateway.
+    let next_hop = if to.addr & NETMASK == LOCAL_ADDR & NETMASK {
+        to.addr
+    } else {
+        GATEWAY
+    };
+    let dest = if to.addr == u32::MAX {
+        BROADCAST
+    } else if let Some(&mac) = arp_cache().get(&next_hop) {
+        mac
+    } else {
+        // We don't queue datagrams while waiting for the reply. UDP
+        // doesn't promise delivery anyway, and by the time the
+        // application retries we'll know where to send it.
+        return send_arp(ARP_REQUEST, [0; 6], next_hop);
+    };
+
+    let len = IPV4_HEADER + UDP_HEADER + data.len();
+    let mut packet = Vec::with_capacity(len);
+    // Version 4, 5 words of header, no type of service.
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&(len as u16).to_be_bytes());
+    // Identification, and the don't fragment flag.
+    packet.extend_from_slice(&[0, 0, 0x40, 0]);
+    packet.extend_from_slice(&[DEFAULT_TTL, IP_PROTO_UDP, 0, 0]);
+    packet.extend_from_slice(&from.addr.to_be_bytes());
+    packet.extend_from_slice(&to.addr.to_be_bytes());
+    let sum = checksum(&packet);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+
+    packet.extend_from_slice(&from.port.to_be_bytes());
+    packet.extend_from_slice(&to.port.to_be_bytes());
+    packet.extend_from_slice(&((UDP_HEADER + data.len()) as u16).to_be_bytes());
+    // The UDP checksum is optional over IPv4, 0 means there isn't one.
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(data);
+    send_frame(dest, ETHERTYPE_IPV4, &packet)
+}
+
+/// Handle an Ethernet frame the network interface received. Anything we
+/// don't understand is dropped.
+pub fn receive(frame: &[u8]) {
+    if frame.len() < ETH_HEADER {
+        return;
+    }
+    let payload = &frame[ETH_HEADER..];
+    match be16(frame, 12) {
+        ETHERTYPE_ARP => receive_arp(payload),
+        ETHERTYPE_IPV4 => receive_ipv4(payload),
+        _ => {}
+    }
+}
+
+fn receive_arp(arp: &[u8]) {
+    // Only Ethernet and IPv4.
+    if arp.len() < ARP_PACKET || arp[..6] != [0, 1, 0x08, 0x00, 6, 4] {
+        return;
+    }
+    let op = be16(arp, 6);
+    let sender_mac: [u8; 6] = arp[8..14].try_into().unwrap();
+    let sender_addr = be32(arp, 14);
+    let target_addr = be32(arp, 24);
+    // Whoever talks to us, we might want to talk back to.
+    if sender_addr != 0 {
+        arp_cache().insert(sender_addr, sender_mac);
+    }
+    if op == ARP_REQUEST && target_addr == LOCAL_ADDR {
+        let _ = send_arp(ARP_REPLY, sender_mac, sender_addr);
+    }
+}
+
+fn receive_ipv4(packet: &[u8]) {
+    if packet.len() < IPV4_HEADER || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    let total_len = be16(packet, 2) as usize;
+    if header_len < IPV4_HEADER || total_len < header_len || total_len > packet.len() {
+        return;
+    }
+    if checksum(&packet[..header_len]) != 0 {
+        return;
+    }
+    // A fragment, either more are coming or it isn't the first. We don't
+    // reassemble.
+    let fragment = be16(packet, 6);
+    if fragment & 0x2000 != 0 || fragment & 0x1fff != 0 {
+        return;
+    }
+    let src = be32(packet, 12);
+    let dst = be32(packet, 16);
+    if dst != LOCAL_ADDR && dst != u32::MAX {
+        return;
+    }
+    if packet[9] != IP_PROTO_UDP {
+        return;
+    }
+    let udp = &packet[header_len..total_len];
+    if udp.len() < UDP_HEADER {
+        return;
+    }
+    let udp_len = be16(udp, 4) as usize;
+    if udp_len < UDP_HEADER || udp_len > udp.len() {
+        return;
+    }
+    let from = Endpoint {
+        addr: src,
+        port: be16(udp, 0),
+    };
+    let to = Endpoint {
+        addr: LOCAL_ADDR,
+        port: be16(udp, 2),
+    };
+    deliver(from, to, &udp[UDP_HEADER..udp_len]);
+}
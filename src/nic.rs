@@ -0,0 +1,207 @@
+// The network interface: a virtio-net device (-device virtio-net-device in
+// QEMU, which by default gets the user mode network behind it). The device
+// has a receive queue, which we keep stocked with empty buffers for it to
+// fill with incoming frames, and a transmit queue for outgoing ones. Every
+// frame is preceded by a virtio-net header, which we leave zeroed: we don't
+// ask for checksum offloading or segmentation.
+
+use crate::{
+    cpu,
+    dma::{self, DmaBuffer},
+    net,
+    syscall::{SysError, EAGAIN, EMSGSIZE},
+    virtio::{self, Device, Queue},
+};
+use alloc::vec::Vec;
+use core::ptr::addr_of_mut;
+
+use SysError::Errno;
+
+// Features
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+// The queues
+const RX_QUEUE: u32 = 0;
+const TX_QUEUE: u32 = 1;
+
+/// The largest Ethernet frame (without the FCS, which the device handles).
+pub const MAX_FRAME: usize = 1514;
+/// The room for a frame and its header in each of our buffers.
+const BUFFER_SIZE: usize = 2048;
+
+/// A locally administered address, for devices that don't have one.
+const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+struct Nic {
+    dev: Device,
+    rx: Queue,
+    tx: Queue,
+    mac: [u8; 6],
+    // The header is 10 bytes on legacy devices and 12 (with num_buffers)
+    // on modern ones.
+    header_len: usize,
+    // A buffer for every descriptor of each queue, indexed by descriptor.
+    rx_buffers: DmaBuffer,
+    tx_buffers: DmaBuffer,
+}
+
+// There's only ever one network interface.
+static mut NIC: Option<Nic> = None;
+
+fn nic() -> Option<&'static mut Nic> {
+    unsafe { (*addr_of_mut!(NIC)).as_mut() }
+}
+
+/// Is there a network interface?
+pub fn present() -> bool {
+    nic().is_some()
+}
+
+/// The MAC address of the interface.
+pub fn mac() -> Option<[u8; 6]> {
+    nic().map(|n| n.mac)
+}
+
+/// Set up a virtio-net device. Returns false if it didn't work out.
+pub fn setup(mut dev: Device) -> bool {
+    if present() {
+        println!("virtio-net: only one network interface is supported");
+        return false;
+    }
+    let Some(features) = dev.begin_init(|_| VIRTIO_NET_F_MAC) else {
+        return false;
+    };
+    let (Some(rx), Some(tx)) = (
+        dev.setup_queue(RX_QUEUE, virtio::MAX_QUEUE_SIZE),
+        dev.setup_queue(TX_QUEUE, virtio::MAX_QUEUE_SIZE),
+    ) else {
+        dev.fail();
+        return false;
+    };
+    let mac = if features & VIRTIO_NET_F_MAC != 0 {
+        let mut mac = [0; 6];
+        for (i, b) in mac.iter_mut().enumerate() {
+            *b = dev.config(i);
+        }
+        mac
+    } else {
+        DEFAULT_MAC
+    };
+    let header_len = if features & virtio::F_VERSION_1 != 0 {
+        12
+    } else {
+        10
+    };
+    let (Some(rx_buffers), Some(tx_buffers)) = (
+        dma::alloc_coherent(rx.size() as usize * BUFFER_SIZE),
+        dma::alloc_coherent(tx.size() as usize * BUFFER_SIZE),
+    ) else {
+        dev.fail();
+        return false;
+    };
+    let mut nic = Nic {
+        rx_buffers,
+        tx_buffers,
+        dev,
+        rx,
+        tx,
+        mac,
+        header_len,
+    };
+    // Hand the device all the receive buffers it can take.
+    while nic.rx.num_free() > 0 {
+        let i = nic.rx.alloc_desc().unwrap();
+        nic.give_rx(i);
+    }
+    nic.dev.driver_ok();
+    println!(
+        "eth0: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    );
+    unsafe {
+        *addr_of_mut!(NIC) = Some(nic);
+    }
+    true
+}
+
+impl Nic {
+    fn buffer(buffers: &mut DmaBuffer, i: u16) -> &mut [u8] {
+        let start = i as usize * BUFFER_SIZE;
+        &mut buffers.as_mut_slice()[start..start + BUFFER_SIZE]
+    }
+
+    fn buffer_addr(buffers: &DmaBuffer, i: u16) -> u64 {
+        (buffers.bus_addr() + i as usize * BUFFER_SIZE) as u64
+    }
+
+    /// Put the receive buffer of descriptor `i` (back) on the queue.
+    fn give_rx(&mut self, i: u16) {
+        *self.rx.desc(i) = virtio::Descriptor {
+            addr: Self::buffer_addr(&self.rx_buffers, i),
+            len: BUFFER_SIZE as u32,
+            flags: virtio::DESC_F_WRITE,
+            next: 0,
+        };
+        self.rx.submit(i);
+    }
+
+    /// Free the transmit descriptors the device is done with.
+    fn reclaim_tx(&mut self) {
+        while let Some((i, _)) = self.tx.pop_used() {
+            self.tx.free_chain(i);
+        }
+    }
+
+    /// Take the next received frame off the queue, and give its buffer back
+    /// to the device.
+    fn next_rx(&mut self) -> Option<Vec<u8>> {
+        let (i, len) = self.rx.pop_used()?;
+        let len = (len as usize).clamp(self.header_len, BUFFER_SIZE);
+        let frame = Self::buffer(&mut self.rx_buffers, i)[self.header_len..len].to_vec();
+        self.give_rx(i);
+        Some(frame)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> Result<(), SysError> {
+        if frame.len() > MAX_FRAME {
+            return Err(Errno(EMSGSIZE));
+        }
+        self.reclaim_tx();
+        // The transmit queue is only full if the device is way behind,
+        // drop the frame like a real network would.
+        let i = self.tx.alloc_desc().ok_or(Errno(EAGAIN))?;
+        let header_len = self.header_len;
+        let buf = Self::buffer(&mut self.tx_buffers, i);
+        buf[..header_len].fill(0);
+        buf[header_len..header_len + frame.len()].copy_from_slice(frame);
+        *self.tx.desc(i) = virtio::Descriptor {
+            addr: Self::buffer_addr(&self.tx_buffers, i),
+            len: (header_len + frame.len()) as u32,
+            flags: 0,
+            next: 0,
+        };
+        self.tx.submit(i);
+        Ok(())
+    }
+}
+
+/// Send an Ethernet frame (without the FCS).
+pub fn send(frame: &[u8]) -> Result<(), SysError> {
+    // The interrupt handler uses the queues too.
+    cpu::without_interrupts(|| nic().ok_or(Errno(EAGAIN))?.send(frame))
+}
+
+/// The device interrupted us: there are received frames, or sent ones to
+/// clean up after.
+pub fn handle_interrupt(_slot: usize) {
+    let Some(n) = nic() else {
+        return;
+    };
+    n.dev.ack_interrupt();
+    n.reclaim_tx();
+    // The network stack may send replies, don't hold on to the interface
+    // while it runs.
+    while let Some(frame) = nic().and_then(|n| n.next_rx()) {
+        net::receive(&frame);
+    }
+}
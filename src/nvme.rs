@@ -0,0 +1,719 @@
+// NVMe: SSDs on the PCI bus, like QEMU's -device nvme. The controller's
+// registers are in BAR0, and everything else goes through queues in memory:
+// we write 64-byte commands to a submission queue and ring its doorbell,
+// and the controller writes 16-byte completions to a completion queue, with
+// a phase bit it flips every time it goes around, so we can tell the new
+// ones from the old. There's the admin queue pair, for setting things up,
+// and as many I/O queue pairs as we make with it; one is enough for us.
+// Where the data of a command is, is given as a list of pages (PRPs).
+//
+// Only namespace 1 of a controller is used, as /dev/nvme0n1, /dev/nvme1n1,
+// ... Its requests are handled like those of virtio-blk: the controller
+// interrupts (INTx, the way every PCI device here does) once it has
+// completed something, and whoever waits for it is woken up.
+
+use crate::{
+    block::{self, gather, scatter, BlockDevice, IoRequest, Op, Queued, SECTOR_SIZE},
+    cpu, device,
+    dma::{self, DmaBuffer},
+    page::PAGE_SIZE,
+    plic,
+    syscall::{SysError, EINVAL, EIO, ENOMEM, EROFS},
+    timer,
+};
+use alloc::{boxed::Box, format, string::String, vec::Vec};
+use core::{mem::size_of, ptr::addr_of_mut};
+use log::{error, warn};
+
+use SysError::{Block, Errno};
+
+/// Mass storage, non-volatile memory, NVM Express: the class code NVMe
+/// controllers have.
+const PCI_CLASS_NVME: u32 = 0x01_08_02;
+
+/// The device number major of NVMe namespaces on Linux.
+const NVME_MAJOR: u64 = 259;
+
+// Register offsets
+const CAP: usize = 0x00;
+const CC: usize = 0x14;
+const CSTS: usize = 0x1c;
+const AQA: usize = 0x24;
+const ASQ: usize = 0x28;
+const ACQ: usize = 0x30;
+/// Where the doorbells start. They are CAP.DSTRD apart: the submission
+/// queue tail of queue pair y, then its completion queue head.
+const DOORBELLS: usize = 0x1000;
+
+// CC bits
+const CC_EN: u32 = 1 << 0;
+const CC_SHN_NORMAL: u32 = 1 << 14;
+/// Submission and completion queue entries of 2^6 and 2^4 bytes.
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+// CSTS bits
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_SHST_MASK: u32 = 3 << 2;
+const CSTS_SHST_COMPLETE: u32 = 2 << 2;
+
+// Admin commands
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+// What Identify identifies
+const CNS_NAMESPACE: u32 = 0;
+const CNS_CONTROLLER: u32 = 1;
+// I/O commands
+const CMD_WRITE: u8 = 0x01;
+const CMD_READ: u8 = 0x02;
+
+// Queue sizes, if the controller has room for that many.
+const ADMIN_QUEUE_SIZE: usize = 16;
+const IO_QUEUE_SIZE: usize = 64;
+/// The namespace we use.
+const NSID: u32 = 1;
+/// The most a command transfers: what one page of PRPs can list.
+const MAX_TRANSFER: usize = PAGE_SIZE / size_of::<u64>() * PAGE_SIZE;
+
+/// How long admin commands and shutting down may take.
+const ADMIN_TIMEOUT_MS: u64 = 2000;
+const SHUTDOWN_TIMEOUT_MS: u64 = 2000;
+
+/// How many finished requests we keep for whoever asked for them to pick
+/// up, like virtio-blk does.
+const MAX_FINISHED: usize = 16;
+
+/// A submission queue entry.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Command {
+    opcode: u8,
+    flags: u8,
+    cid: u16,
+    nsid: u32,
+    reserved: u64,
+    mptr: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: [u32; 6],
+}
+
+/// A completion queue entry. Bit 0 of the status is the phase bit, the
+/// rest is 0 if the command went well.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Completion {
+    result: u32,
+    reserved: u32,
+    sq_head: u16,
+    sq_id: u16,
+    cid: u16,
+    status: u16,
+}
+
+fn read32(addr: usize) -> u32 {
+    unsafe { (addr as *const u32).read_volatile() }
+}
+
+fn write32(addr: usize, val: u32) {
+    unsafe { (addr as *mut u32).write_volatile(val) }
+}
+
+// The 64-bit registers are read and written in halves, so that it also
+// works on RV32.
+fn read64(addr: usize) -> u64 {
+    read32(addr) as u64 | (read32(addr + 4) as u64) << 32
+}
+
+fn write64(addr: usize, val: u64) {
+    write32(addr, val as u32);
+    write32(addr + 4, (val >> 32) as u32);
+}
+
+/// A deadline `ms` milliseconds from now, in mtime ticks.
+fn deadline(ms: u64) -> u64 {
+    cpu::get_mtime() + timer::duration_to_ticks(0, ms * 1_000_000)
+}
+
+/// A submission queue and the completion queue it completes to.
+struct QueuePair {
+    size: usize,
+    sq: DmaBuffer,
+    cq: DmaBuffer,
+    sq_tail: usize,
+    cq_head: usize,
+    // The phase bit the new completions have.
+    phase: bool,
+    sq_doorbell: usize,
+    cq_doorbell: usize,
+    // How many of our commands the controller hasn't completed yet.
+    in_flight: usize,
+}
+
+impl QueuePair {
+    /// Queue pair `id` of the controller at `regs` with `size` entries,
+    /// whose doorbells are `stride` bytes apart.
+    fn new(regs: usize, id: usize, size: usize, stride: usize) -> Option<QueuePair> {
+        Some(QueuePair {
+            size,
+            sq: dma::alloc_coherent(size * size_of::<Command>())?,
+            cq: dma::alloc_coherent(size * size_of::<Completion>())?,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            sq_doorbell: regs + DOORBELLS + 2 * id * stride,
+            cq_doorbell: regs + DOORBELLS + (2 * id + 1) * stride,
+            in_flight: 0,
+        })
+    }
+
+    /// Is there no room for another command? One entry always stays empty,
+    /// a full queue would look like an empty one to the controller.
+    fn full(&self) -> bool {
+        self.in_flight + 1 >= self.size
+    }
+
+    /// Hand a command to the controller. Its ID is where it went in the
+    /// queue, which is returned.
+    fn submit(&mut self, mut cmd: Command) -> u16 {
+        let cid = self.sq_tail as u16;
+        cmd.cid = cid;
+        unsafe {
+            (self.sq.as_ptr() as *mut Command)
+                .add(self.sq_tail)
+                .write_volatile(cmd);
+        }
+        self.sq_tail = (self.sq_tail + 1) % self.size;
+        self.in_flight += 1;
+        // The command has to be in memory before the controller looks.
+        dma::mb();
+        write32(self.sq_doorbell, self.sq_tail as u32);
+        cid
+    }
+
+    /// The next completion, if the controller has written one.
+    fn pop(&mut self) -> Option<Completion> {
+        let entry = unsafe { (self.cq.as_ptr() as *const Completion).add(self.cq_head) };
+        let status = unsafe { (&raw const (*entry).status).read_volatile() };
+        if (status & 1 != 0) != self.phase {
+            return None;
+        }
+        // Don't read the rest of it before we know it's new.
+        dma::rmb();
+        let c = unsafe { entry.read_volatile() };
+        self.cq_head += 1;
+        if self.cq_head == self.size {
+            self.cq_head = 0;
+            self.phase = !self.phase;
+        }
+        self.in_flight -= 1;
+        // Telling the controller we've taken it also lets it lower its
+        // interrupt once there's nothing left.
+        write32(self.cq_doorbell, self.cq_head as u32);
+        Some(c)
+    }
+
+    /// Carry out an admin command, waiting for it to complete. Returns
+    /// false if it failed or took too long.
+    fn run(&mut self, cmd: Command) -> bool {
+        let cid = self.submit(cmd);
+        let end = deadline(ADMIN_TIMEOUT_MS);
+        while cpu::get_mtime() < end {
+            if let Some(c) = self.pop() {
+                if c.cid == cid {
+                    return c.status >> 1 == 0;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// A read or write we've handed to the controller, kept the way virtio-blk
+/// keeps its requests.
+struct Request {
+    op: Op,
+    sector: u64,
+    len: usize,
+    mem: DmaBuffer,
+    // The rest of the PRPs, for data over two pages.
+    _prp_list: Option<DmaBuffer>,
+    cid: u16,
+    // The status, once the command has completed.
+    status: Option<u16>,
+    io: Option<IoRequest>,
+}
+
+impl Request {
+    fn data(&mut self) -> &mut [u8] {
+        &mut self.mem.as_mut_slice()[..self.len]
+    }
+
+    /// Is this the request to read `len` bytes from `sector`, or to write
+    /// `data` there?
+    fn is(&mut self, op: Op, sector: u64, len: usize, data: &[u8]) -> bool {
+        self.io.is_none()
+            && self.op == op
+            && self.sector == sector
+            && self.len == len
+            && (op != Op::Write || self.data() == data)
+    }
+
+    fn overlaps(&self, sector: u64, len: usize) -> bool {
+        let end = sector + (len / SECTOR_SIZE) as u64;
+        self.sector < end && sector < self.sector + (self.len / SECTOR_SIZE) as u64
+    }
+}
+
+struct Nvme {
+    name: String,
+    // Which controller it is, counting from 0.
+    index: usize,
+    // The admin queue pair stays the controller's for as long as it's on.
+    _admin: QueuePair,
+    io: QueuePair,
+    sectors: u64,
+    // The namespace's logical blocks are 2^lba_shift bytes.
+    lba_shift: u32,
+    max_transfer: usize,
+    read_only: bool,
+    requests: Vec<Request>,
+}
+
+/// A controller we've set up: its registers, its interrupt and its block
+/// device number.
+struct Controller {
+    regs: usize,
+    irq: u32,
+    dev: usize,
+}
+
+static mut CONTROLLERS: Vec<Controller> = Vec::new();
+
+fn controllers() -> &'static mut Vec<Controller> {
+    unsafe { &mut *addr_of_mut!(CONTROLLERS) }
+}
+
+/// Wait for CSTS.RDY to become `ready`, for at most `ms` milliseconds.
+fn wait_ready(regs: usize, ready: bool, ms: u64) -> bool {
+    let end = deadline(ms);
+    while (read32(regs + CSTS) & CSTS_RDY != 0) != ready {
+        if cpu::get_mtime() >= end {
+            return false;
+        }
+    }
+    true
+}
+
+/// An Identify command, answered in `buf`.
+fn identify(buf: &DmaBuffer, cns: u32, nsid: u32) -> Command {
+    let mut cmd = Command {
+        opcode: ADMIN_IDENTIFY,
+        nsid,
+        prp1: buf.bus_addr() as u64,
+        ..Default::default()
+    };
+    cmd.cdw10[0] = cns;
+    cmd
+}
+
+/// Reset the controller at `regs`, bring it up with an I/O queue pair and
+/// find out about its namespace.
+fn setup(regs: usize) -> Option<Nvme> {
+    let cap = read64(regs + CAP);
+    let max_entries = (cap & 0xffff) as usize + 1;
+    let stride = 4 << ((cap >> 32) & 0xf);
+    // CAP.TO is in 500 ms units.
+    let timeout = ((cap >> 24) & 0xff) * 500;
+    if (cap >> 48) & 0xf != 0 {
+        error!("the controller needs pages bigger than ours");
+        return None;
+    }
+
+    write32(regs + CC, 0);
+    if !wait_ready(regs, false, timeout) {
+        error!("the controller didn't reset");
+        return None;
+    }
+    let size = ADMIN_QUEUE_SIZE.min(max_entries);
+    let mut admin = QueuePair::new(regs, 0, size, stride)?;
+    write32(regs + AQA, ((size - 1) << 16 | (size - 1)) as u32);
+    write64(regs + ASQ, admin.sq.bus_addr() as u64);
+    write64(regs + ACQ, admin.cq.bus_addr() as u64);
+    // The NVM command set, 4 KiB pages, round robin.
+    write32(regs + CC, CC_EN | CC_IOSQES | CC_IOCQES);
+    if !wait_ready(regs, true, timeout) {
+        error!("the controller didn't come up");
+        return None;
+    }
+
+    let mut id = dma::alloc_coherent(PAGE_SIZE)?;
+    if !admin.run(identify(&id, CNS_CONTROLLER, 0)) {
+        error!("identifying the controller failed");
+        return None;
+    }
+    let info = id.as_mut_slice();
+    let model: String = String::from_utf8_lossy(&info[24..64]).trim().into();
+    // MDTS is a power of two of pages, 0 if there's no limit.
+    let max_transfer = match info[77] {
+        0 => MAX_TRANSFER,
+        mdts => (PAGE_SIZE << mdts).min(MAX_TRANSFER),
+    };
+    if !admin.run(identify(&id, CNS_NAMESPACE, NSID)) {
+        error!("identifying namespace {} failed", NSID);
+        return None;
+    }
+    let info = id.as_mut_slice();
+    let nsze = u64::from_le_bytes(info[0..8].try_into().unwrap());
+    // FLBAS picks one of the LBA formats, whose third byte is the size of
+    // a block as a power of two.
+    let format = (info[26] & 0xf) as usize;
+    let lba_shift = info[128 + format * 4 + 2] as u32;
+    // NSATTR bit 0: write protected.
+    let read_only = info[99] & 1 != 0;
+    if !(9..=12).contains(&lba_shift) || nsze == 0 {
+        error!("namespace {} isn't something we can use", NSID);
+        return None;
+    }
+
+    // The completion queue first, the submission queue goes to it.
+    let io = QueuePair::new(regs, 1, IO_QUEUE_SIZE.min(max_entries), stride)?;
+    let qsize = ((io.size - 1) << 16 | 1) as u32;
+    let mut cq = Command {
+        opcode: ADMIN_CREATE_CQ,
+        prp1: io.cq.bus_addr() as u64,
+        ..Default::default()
+    };
+    // Physically contiguous, interrupts on, interrupt vector 0.
+    cq.cdw10[0] = qsize;
+    cq.cdw10[1] = 0b11;
+    let mut sq = Command {
+        opcode: ADMIN_CREATE_SQ,
+        prp1: io.sq.bus_addr() as u64,
+        ..Default::default()
+    };
+    // Physically contiguous, completing to queue 1.
+    sq.cdw10[0] = qsize;
+    sq.cdw10[1] = 1 << 16 | 1;
+    if !admin.run(cq) || !admin.run(sq) {
+        error!("creating the I/O queues failed");
+        return None;
+    }
+
+    let index = controllers().len();
+    println!("nvme{}: {}", index, model);
+    Some(Nvme {
+        name: format!("nvme{}n{}", index, NSID),
+        index,
+        _admin: admin,
+        io,
+        sectors: nsze << (lba_shift - 9),
+        lba_shift,
+        max_transfer,
+        read_only,
+        requests: Vec::new(),
+    })
+}
+
+/// PRP2 of a transfer of `len` bytes from `mem`, and the list of pages it
+/// points to if it needs one. The first page goes in PRP1. PRP2 is the
+/// second page if that's the last one, or else a list of all the pages
+/// after the first.
+fn prps(mem: &DmaBuffer, len: usize) -> Option<(u64, Option<DmaBuffer>)> {
+    let pages = len.div_ceil(PAGE_SIZE);
+    let bus = mem.bus_addr() as u64;
+    Some(match pages {
+        1 => (0, None),
+        2 => (bus + PAGE_SIZE as u64, None),
+        _ => {
+            let list = dma::alloc_coherent((pages - 1) * size_of::<u64>())?;
+            let entries = list.as_ptr() as *mut u64;
+            for i in 1..pages {
+                unsafe {
+                    entries.add(i - 1).write(bus + (i * PAGE_SIZE) as u64);
+                }
+            }
+            (list.bus_addr() as u64, Some(list))
+        }
+    })
+}
+
+/// What the controller said about a command.
+fn result(status: u16) -> Result<(), SysError> {
+    match status {
+        0 => Ok(()),
+        _ => Err(Errno(EIO)),
+    }
+}
+
+impl Nvme {
+    /// Can the controller take a transfer of `len` bytes from `sector`?
+    /// It has to be in whole logical blocks of the namespace.
+    fn check(&self, sector: u64, len: usize) -> Result<(), SysError> {
+        let mask = (1 << (self.lba_shift - 9)) - 1;
+        let end = sector.checked_add((len / SECTOR_SIZE) as u64);
+        if len == 0
+            || len > self.max_transfer
+            || !len.is_multiple_of(1 << self.lba_shift)
+            || sector & mask != 0
+            || end.is_none_or(|end| end > self.sectors)
+        {
+            return Err(Errno(EINVAL));
+        }
+        Ok(())
+    }
+
+    /// Carry out a read into a buffer of `len` bytes, or a write of
+    /// `data`, and return it once it's finished. If it isn't right away
+    /// and we may sleep, this answers Block instead, and asking for it
+    /// again picks it up.
+    fn request(
+        &mut self,
+        op: Op,
+        sector: u64,
+        len: usize,
+        data: &[u8],
+    ) -> Result<Request, SysError> {
+        self.check(sector, len)?;
+        self.reap();
+        let i = match self
+            .requests
+            .iter_mut()
+            .position(|r| r.is(op, sector, len, data))
+        {
+            Some(i) => i,
+            None => self.submit(op, sector, len, data)?,
+        };
+        while self.requests[i].status.is_none() {
+            if block::may_sleep() {
+                return Err(Block);
+            }
+            self.reap();
+        }
+        Ok(self.requests.remove(i))
+    }
+
+    /// Hand a new request to the controller. Returns where it is in the
+    /// list.
+    fn submit(&mut self, op: Op, sector: u64, len: usize, data: &[u8]) -> Result<usize, SysError> {
+        let unclaimed = |r: &Request| r.io.is_none() && r.status.is_some();
+        while self.requests.iter().filter(|r| unclaimed(r)).count() >= MAX_FINISHED {
+            let i = self.requests.iter().position(unclaimed).unwrap();
+            self.requests.remove(i);
+        }
+        if op == Op::Write {
+            self.requests
+                .retain(|r| r.op != Op::Read || !unclaimed(r) || !r.overlaps(sector, len));
+        }
+        let mut mem = dma::alloc_coherent(len).ok_or(Errno(ENOMEM))?;
+        if op == Op::Write {
+            mem.as_mut_slice()[..len].copy_from_slice(data);
+        }
+        let prps = prps(&mem, len).ok_or(Errno(ENOMEM))?;
+        while self.io.full() {
+            if block::may_sleep() {
+                return Err(Block);
+            }
+            self.reap();
+        }
+        Ok(self.push(op, sector, len, mem, prps, None))
+    }
+
+    /// Put a request on the submission queue, which must have room for
+    /// it. Returns where it is in the list.
+    fn push(
+        &mut self,
+        op: Op,
+        sector: u64,
+        len: usize,
+        mem: DmaBuffer,
+        (prp2, prp_list): (u64, Option<DmaBuffer>),
+        io: Option<IoRequest>,
+    ) -> usize {
+        let lba = sector >> (self.lba_shift - 9);
+        let mut cmd = Command {
+            opcode: match op {
+                Op::Read => CMD_READ,
+                Op::Write => CMD_WRITE,
+            },
+            nsid: NSID,
+            prp1: mem.bus_addr() as u64,
+            prp2,
+            ..Default::default()
+        };
+        // The first block and how many there are, less one.
+        cmd.cdw10[0] = lba as u32;
+        cmd.cdw10[1] = (lba >> 32) as u32;
+        cmd.cdw10[2] = (len >> self.lba_shift) as u32 - 1;
+        let cid = self.io.submit(cmd);
+        self.requests.push(Request {
+            op,
+            sector,
+            len,
+            mem,
+            _prp_list: prp_list,
+            cid,
+            status: None,
+            io,
+        });
+        self.requests.len() - 1
+    }
+
+    /// Take what the controller has completed off the completion queue.
+    fn reap(&mut self) {
+        while let Some(c) = self.io.pop() {
+            if let Some(r) = self
+                .requests
+                .iter_mut()
+                .find(|r| r.status.is_none() && r.cid == c.cid)
+            {
+                r.status = Some(c.status >> 1);
+            }
+        }
+    }
+}
+
+impl BlockDevice for Nvme {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rdev(&self) -> u64 {
+        NVME_MAJOR << 8 | self.index as u64
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), SysError> {
+        let mut req = self.request(Op::Read, sector, buf.len(), &[])?;
+        result(req.status.unwrap())?;
+        buf.copy_from_slice(req.data());
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), SysError> {
+        if self.read_only {
+            return Err(Errno(EROFS));
+        }
+        let req = self.request(Op::Write, sector, buf.len(), buf)?;
+        result(req.status.unwrap())
+    }
+
+    fn queue(&mut self, rq: IoRequest) -> Queued {
+        self.reap();
+        let len = rq.bytes();
+        if let Err(Errno(e)) = self.check(rq.sector, len) {
+            return Queued::Done(rq, Err(e));
+        }
+        if self.io.full() {
+            return Queued::Full(rq);
+        }
+        let Some(mut mem) = dma::alloc_coherent(len) else {
+            return Queued::Done(rq, Err(ENOMEM));
+        };
+        if rq.op == Op::Write {
+            mem.as_mut_slice()[..len].copy_from_slice(&gather(&rq));
+        }
+        let Some(prps) = prps(&mem, len) else {
+            return Queued::Done(rq, Err(ENOMEM));
+        };
+        self.push(rq.op, rq.sector, len, mem, prps, Some(rq));
+        Queued::Started
+    }
+
+    fn finished(&mut self) -> Vec<(IoRequest, Result<(), isize>)> {
+        let mut done = Vec::new();
+        let mut i = 0;
+        while i < self.requests.len() {
+            let r = &self.requests[i];
+            if r.io.is_none() || r.status.is_none() {
+                i += 1;
+                continue;
+            }
+            let mut r = self.requests.remove(i);
+            let mut rq = r.io.take().unwrap();
+            let ret = result(r.status.unwrap()).map_err(|_| EIO);
+            if ret.is_ok() && rq.op == Op::Read {
+                scatter(&mut rq, r.data());
+            }
+            done.push((rq, ret));
+        }
+        done
+    }
+
+    fn poll(&mut self) {
+        self.reap();
+    }
+}
+
+/// The driver for NVMe controllers on the PCI bus.
+pub struct NvmePci;
+
+impl device::Driver for NvmePci {
+    fn matches(&self, dev: &device::Device) -> bool {
+        dev.pci
+            .as_ref()
+            .is_some_and(|f| f.class() == PCI_CLASS_NVME)
+    }
+
+    fn probe(&self, dev: &device::Device) -> bool {
+        let Some(f) = &dev.pci else {
+            return false;
+        };
+        let (Some(regs), Some(irq)) = (f.bar(0), dev.irq()) else {
+            warn!("the controller has no registers or no interrupt");
+            return false;
+        };
+        let Some(nvme) = setup(regs) else {
+            return false;
+        };
+        let n = block::register(Box::new(nvme));
+        controllers().push(Controller { regs, irq, dev: n });
+        plic::enable(irq);
+        plic::set_priority(irq, 1);
+        true
+    }
+
+    /// Shut the controller down properly, so that it has written out what
+    /// it has cached before the power goes.
+    fn remove(&self, dev: &device::Device) {
+        let Some(regs) = dev.pci.as_ref().and_then(|f| f.bar(0)) else {
+            return;
+        };
+        if !controllers().iter().any(|c| c.regs == regs) {
+            return;
+        }
+        write32(regs + CC, read32(regs + CC) | CC_SHN_NORMAL);
+        let end = deadline(SHUTDOWN_TIMEOUT_MS);
+        while read32(regs + CSTS) & CSTS_SHST_MASK != CSTS_SHST_COMPLETE {
+            if cpu::get_mtime() >= end {
+                warn!("the controller didn't shut down");
+                return;
+            }
+        }
+    }
+}
+
+/// Is `irq` the interrupt of an NVMe controller?
+pub fn has_irq(irq: u32) -> bool {
+    controllers().iter().any(|c| c.irq == irq)
+}
+
+/// Handle an interrupt from the controllers that raise `irq`. Taking their
+/// completions is all it takes.
+pub fn handle_interrupt(irq: u32) {
+    let devs: Vec<usize> = controllers()
+        .iter()
+        .filter(|c| c.irq == irq)
+        .map(|c| c.dev)
+        .collect();
+    for dev in devs {
+        block::poll(dev);
+    }
+}
@@ -1,3 +1,7 @@
+use crate::{
+    board,
+    ktrace::{self, Kind},
+};
 use bitflags::bitflags;
 use core::{mem::size_of, ptr::null_mut};
 
@@ -6,10 +10,17 @@ extern "C" {
     static HEAP_SIZE: usize;
 }
 
+/// The size of the heap. The linker script gives it all the RAM of the
+/// biggest board, boards with less have less of it.
+fn heap_size() -> usize {
+    let end = 0x8000_0000 + board::RAM_SIZE;
+    unsafe { HEAP_SIZE.min(end.saturating_sub(HEAP_START)) }
+}
+
 // We will use ALLOC_START to mark the start of the actual
 // memory we can dish out.
 static mut ALLOC_START: usize = 0;
-const PAGE_ORDER: usize = 12;
+pub const PAGE_ORDER: usize = 12;
 pub const PAGE_SIZE: usize = 1 << 12;
 
 bitflags! {
@@ -57,12 +68,13 @@ impl Page {
 /// Initialize the allocation system. There are several ways that we can
 /// implement the page allocator:
 /// 1. Free list (singly linked list where it starts at the first free
-/// allocation) 2. Bookkeeping list (structure contains a taken and length)
+///    allocation)
+/// 2. Bookkeeping list (structure contains a taken and length)
 /// 3. Allocate one Page structure per 4096 bytes (this is what I chose)
 /// 4. Others
 pub fn init() {
     unsafe {
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
+        let num_pages = heap_size() / PAGE_SIZE;
         let ptr = HEAP_START as *mut Page;
         // Clear all pages to make sure that they aren't accidentally
         // taken
@@ -92,7 +104,7 @@ pub fn alloc(pages: usize) -> *mut u8 {
         // We create a Page structure for each page on the heap. We
         // actually might have more since HEAP_SIZE moves and so does
         // the size of our structure, but we'll only waste a few bytes.
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
+        let num_pages = heap_size() / PAGE_SIZE;
         let ptr = HEAP_START as *mut Page;
         for i in 0..num_pages - pages {
             let mut found = false;
@@ -128,7 +140,9 @@ pub fn alloc(pages: usize) -> *mut u8 {
                 // useful memory. Instead, there is 1 Page
                 // structure per 4096 bytes starting at
                 // ALLOC_START.
-                return (ALLOC_START + PAGE_SIZE * i) as *mut u8;
+                let addr = ALLOC_START + PAGE_SIZE * i;
+                ktrace::record(Kind::PageAlloc, addr, pages);
+                return addr as *mut u8;
             }
         }
     }
@@ -164,17 +178,41 @@ pub fn zalloc(pages: usize) -> *mut u8 {
     ret
 }
 
+/// Take the pages that [addr, addr + len) is in out of the allocator, for
+/// memory something else put there before we booted. Returns the first of
+/// them, which dealloc() gives back, or null if they aren't all in the
+/// heap and free.
+pub fn reserve(addr: usize, len: usize) -> *mut u8 {
+    unsafe {
+        let num_pages = heap_size() / PAGE_SIZE;
+        let ptr = HEAP_START as *mut Page;
+        let Some(first) = addr.checked_sub(ALLOC_START).map(|a| a / PAGE_SIZE) else {
+            return null_mut();
+        };
+        let last = (addr + len.max(1) - 1 - ALLOC_START) / PAGE_SIZE;
+        if last >= num_pages || (first..=last).any(|i| (*ptr.add(i)).is_taken()) {
+            return null_mut();
+        }
+        for i in first..last {
+            (*ptr.add(i)).alloc();
+        }
+        (*ptr.add(last)).alloc_last();
+        (ALLOC_START + first * PAGE_SIZE) as *mut u8
+    }
+}
+
 /// Deallocate a page by its pointer
 /// The way we've structured this, it will automatically coalesce
 /// contiguous pages.
 pub fn dealloc(ptr: *mut u8) {
     // Make sure we don't try to free a null pointer.
     assert!(!ptr.is_null());
+    ktrace::record(Kind::PageFree, ptr as usize, 0);
     unsafe {
         let addr = HEAP_START + (ptr as usize - ALLOC_START) / PAGE_SIZE;
         // Make sure that the address makes sense. The address we
         // calculate here is the page structure, not the HEAP address!
-        assert!(addr >= HEAP_START && addr < HEAP_START + HEAP_SIZE);
+        assert!(addr >= HEAP_START && addr < HEAP_START + heap_size());
         let mut p = addr as *mut Page;
         // Keep clearing pages until we hit the last page.
         while (*p).is_taken() && !(*p).is_last() {
@@ -194,11 +232,21 @@ pub fn dealloc(ptr: *mut u8) {
     }
 }
 
+/// How many pages there are to hand out, and how many of them are taken.
+pub fn stats() -> (usize, usize) {
+    unsafe {
+        let num_pages = heap_size() / PAGE_SIZE;
+        let ptr = HEAP_START as *const Page;
+        let taken = (0..num_pages).filter(|&i| (*ptr.add(i)).is_taken());
+        (num_pages, taken.count())
+    }
+}
+
 /// Print all page allocations
 /// This is mainly used for debugging.
 pub fn print_page_allocations() {
     unsafe {
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
+        let num_pages = heap_size() / PAGE_SIZE;
         let mut beg = HEAP_START as *const Page;
         let end = beg.add(num_pages);
         let alloc_beg = ALLOC_START;
@@ -244,3 +292,237 @@ pub fn print_page_allocations() {
         println!();
     }
 }
+
+// ///////////////////////////////////
+// / MMU Routines
+// ///////////////////////////////////
+
+// The paging scheme: Sv39 on RV64, three levels of tables with 512 entries
+// of 64 bits, and Sv32 on RV32, two levels of 1024 entries of 32 bits. Both
+// have 4 KiB pages and the same bits in an entry, so the rest of the
+// kernel doesn't have to care which one it is.
+#[cfg(target_pointer_width = "64")]
+const LEVELS: usize = 3;
+#[cfg(target_pointer_width = "32")]
+const LEVELS: usize = 2;
+/// How many bits of a virtual address each level of tables takes.
+const VPN_BITS: usize = if LEVELS == 3 { 9 } else { 10 };
+const ENTRIES: usize = 1 << VPN_BITS;
+
+/// The index into the table at level `level` for `vaddr`: VPN[level].
+const fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (PAGE_ORDER + level * VPN_BITS)) & (ENTRIES - 1)
+}
+
+// The bits of a page table entry.
+bitflags! {
+    pub struct EntryBits: usize {
+        const VALID = 1 << 0;
+        const READ = 1 << 1;
+        const WRITE = 1 << 2;
+        const EXECUTE = 1 << 3;
+        const USER = 1 << 4;
+        const GLOBAL = 1 << 5;
+        const ACCESS = 1 << 6;
+        const DIRTY = 1 << 7;
+
+        // Convenience combinations
+        const READ_WRITE = Self::READ.bits | Self::WRITE.bits;
+        const READ_EXECUTE = Self::READ.bits | Self::EXECUTE.bits;
+        const READ_WRITE_EXECUTE = Self::READ.bits | Self::WRITE.bits | Self::EXECUTE.bits;
+
+        // User Convenience Combinations
+        const USER_READ_WRITE = Self::READ_WRITE.bits | Self::USER.bits;
+        const USER_READ_EXECUTE = Self::READ_EXECUTE.bits | Self::USER.bits;
+    }
+}
+
+// A single entry.
+pub struct Entry {
+    pub entry: usize,
+}
+
+// The Entry structure describes one of the entries of a table, which is
+// described in the RISC-V privileged spec Figures 4.18 (Sv32) and 4.21
+// (Sv39).
+impl Entry {
+    pub fn is_valid(&self) -> bool {
+        self.entry & EntryBits::VALID.bits() != 0
+    }
+
+    // The first bit (bit index #0) is the V bit for
+    // valid.
+    pub fn is_invalid(&self) -> bool {
+        !self.is_valid()
+    }
+
+    // A leaf has one or more RWX bits set
+    pub fn is_leaf(&self) -> bool {
+        self.entry & EntryBits::READ_WRITE_EXECUTE.bits() != 0
+    }
+
+    pub fn is_branch(&self) -> bool {
+        !self.is_leaf()
+    }
+
+    pub fn bits(&self) -> EntryBits {
+        EntryBits::from_bits_truncate(self.entry)
+    }
+
+    pub fn set_entry(&mut self, entry: usize) {
+        self.entry = entry;
+    }
+
+    /// The physical address this entry points to.
+    pub fn addr(&self) -> usize {
+        (self.entry & !0x3ff) << 2
+    }
+}
+
+// Table represents a single table, which contains 512 (2^9) entries of 64
+// bits for Sv39, or 1024 (2^10) entries of 32 bits for Sv32. Either way,
+// it fills a page.
+pub struct Table {
+    pub entries: [Entry; ENTRIES],
+}
+
+const _: () = assert!(size_of::<Table>() == PAGE_SIZE);
+
+/// Map a virtual address to a physical address using 4096-byte page
+/// size.
+/// root: a mutable reference to the root Table
+/// vaddr: The virtual address to map
+/// paddr: The physical address to map
+/// bits: An OR'd bitset containing the bits the leaf should have.
+///       The bits should contain only the following:
+///          Read, Write, Execute, User, and/or Global
+///       The bits MUST include one or more of the following:
+///          Read, Write, Execute
+///       The valid bit automatically gets added.
+/// Returns false, with nothing mapped, if there are no pages left for the
+/// tables on the way. The ones it did get stay, for unmap() to free.
+#[must_use]
+pub fn map(root: &mut Table, vaddr: usize, paddr: usize, bits: EntryBits, level: usize) -> bool {
+    // Make sure that Read, Write, or Execute have been provided
+    // otherwise, we'll leak memory and always create a page fault.
+    assert!(bits.intersects(EntryBits::READ_WRITE_EXECUTE));
+
+    // We will use this as a floating reference so that we can set
+    // individual entries as we walk the table, starting with the root's
+    // entry for the top VPN.
+    let mut v = &mut root.entries[vpn(vaddr, LEVELS - 1)];
+    // Now, we're going to traverse the page table and set the bits
+    // properly. We expect the root to be valid, however we're required to
+    // create anything beyond the root.
+    // In Rust, we create a range iterator using the .. operator.
+    // The .rev() will reverse the iteration since we need to start with
+    // the top VPN. The .. operator is inclusive on start but exclusive on
+    // end, so with three levels, (0..2) will iterate 0 and 1.
+    for i in (level..LEVELS - 1).rev() {
+        if !v.is_valid() {
+            // Allocate a page
+            let page = zalloc(1);
+            if page.is_null() {
+                return false;
+            }
+            // The page is already aligned by 4,096, so store it
+            // directly The page is stored in the entry shifted
+            // right by 2 places.
+            v.set_entry((page as usize >> 2) | EntryBits::VALID.bits());
+        }
+        let entry = v.addr() as *mut Entry;
+        v = unsafe { &mut *entry.add(vpn(vaddr, i)) };
+    }
+    // When we get here, we should be at VPN[0] and v should be pointing to
+    // our entry.
+    // The entry structure is Figure 4.18 (Sv32) or 4.21 (Sv39) in the
+    // RISC-V Privileged Specification. The physical page number starts at
+    // bit 10, whichever it is.
+    let entry = (paddr >> PAGE_ORDER) << 10 |
+        bits.bits() |                    // Specified bits, such as User, Read, Write, etc
+        EntryBits::VALID.bits() |          // Valid bit
+        EntryBits::DIRTY.bits() |          // Some machines require this to =1
+        EntryBits::ACCESS.bits(); // Just like dirty, some machines require this
+                                  // Set the entry. V should be set to the correct pointer by the loop
+                                  // above.
+    v.set_entry(entry);
+    true
+}
+
+/// Unmaps and frees all memory associated with a table.
+/// root: The root table to start freeing.
+/// NOTE: This does NOT free root directly. This must be
+/// freed manually.
+/// The reason we don't free the root is because it is
+/// usually embedded into the Process structure.
+pub fn unmap(root: &mut Table) {
+    // Start with the root's level
+    unmap_level(root, LEVELS - 1);
+}
+
+/// Free the tables below `table`, which is at level `level`.
+fn unmap_level(table: &mut Table, level: usize) {
+    for entry in table.entries.iter() {
+        if entry.is_valid() && entry.is_branch() {
+            // This is a valid entry, so drill down and free.
+            let memaddr = entry.addr();
+            // The tables at level 0 cannot have branches, so there's
+            // nothing below them to free.
+            if level > 1 {
+                // Make the table a mutable reference instead of a
+                // pointer.
+                unmap_level(unsafe { &mut *(memaddr as *mut Table) }, level - 1);
+            }
+            dealloc(memaddr as *mut u8);
+        }
+    }
+}
+
+/// Find the leaf entry that maps `vaddr`, if there is one.
+pub fn walk(root: &mut Table, vaddr: usize) -> Option<&mut Entry> {
+    // Walk the page table pointed to by root
+    let mut v = &mut root.entries[vpn(vaddr, LEVELS - 1)];
+    for i in (0..LEVELS).rev() {
+        if v.is_invalid() {
+            // This is an invalid entry, page fault.
+            break;
+        } else if v.is_leaf() {
+            // According to RISC-V, a leaf can be at any level.
+            // We only ever map 4 KiB pages, so anything else isn't one
+            // of ours.
+            return if i == 0 { Some(v) } else { None };
+        }
+        if i == 0 {
+            break;
+        }
+        // Set v to the next entry which is pointed to by this
+        // entry. However, the address was shifted right by 2 places
+        // when stored in the page table entry, so we shift it left
+        // to get it back into place.
+        let entry = v.addr() as *mut Entry;
+        // We do i - 1 here, however we should get None or Some() above
+        // before we do 0 - 1 = -1.
+        v = unsafe { &mut *entry.add(vpn(vaddr, i - 1)) };
+    }
+
+    // If we get here, we've exhausted all valid tables and haven't
+    // found a leaf.
+    None
+}
+
+/// Walk the page table to convert a virtual address to a
+/// physical address.
+/// If a page fault would occur, this returns None
+/// Otherwise, it returns Some with the physical address.
+pub fn virt_to_phys(root: &mut Table, vaddr: usize) -> Option<usize> {
+    walk(root, vaddr).map(|v| v.addr() | (vaddr & (PAGE_SIZE - 1)))
+}
+
+/// Remove the mapping of the 4 KiB page containing `vaddr` and return the
+/// physical address it was mapped to. The page itself is not freed.
+pub fn unmap_page(root: &mut Table, vaddr: usize) -> Option<usize> {
+    let v = walk(root, vaddr)?;
+    let paddr = v.addr();
+    v.set_entry(0);
+    Some(paddr)
+}
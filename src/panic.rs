@@ -0,0 +1,289 @@
+// What the kernel says when it panics, so that there's more to go on than
+// the message. After it comes the hart it happened on and, if that was in
+// the middle of handling a trap, the trap (mcause, mepc, mtval, mstatus)
+// and the registers of whatever the trap interrupted. Then the top of the
+// stack, the trapped one if there is one, a backtrace, and how much the
+// page and the heap allocators have given out.
+//
+// The kernel is built with frame pointers, so the backtrace follows them:
+// s0 points just past a function's frame, where it saved its return
+// address and its caller's s0 last. Each return address is named from the
+// kernel's symbol table, if it has one (see ksyms.rs). core isn't built
+// with frame pointers, so the frames of the code panic!() goes through
+// there are missing, along with the place of the panic!() itself, which
+// the message has anyway, and whatever core keeps in s0 can end the walk
+// early.
+//
+// Before any of that, the other harts are sent a software interrupt to
+// stop them where they are, so that they don't go on changing what's
+// being looked at, or print over it. A hart that's in the kernel parks in
+// its trap handler (see trap.rs); the ones that never left boot.S's wfi
+// loop stay in it. Then the crash dump is written (see crashdump.rs) and
+// the machine stopped.
+//
+// A panic while all that is going on only prints its message, since
+// whatever it was doing is what broke.
+
+use crate::{
+    board,
+    cpu::{self, Registers, TrapFrame, MAX_HARTS},
+    crashdump, fdt, kmem, ksyms,
+    memmap::RAM_START,
+    page, power, uart, TRAP_STACK_PAGES,
+};
+use core::{arch::asm, mem::size_of, panic::PanicInfo, ptr::addr_of_mut};
+
+/// How many words of the stack are printed.
+const STACK_WORDS: usize = 16;
+/// How many frames a backtrace goes back at most.
+const MAX_FRAMES: usize = 32;
+const WORD: usize = size_of::<usize>();
+
+/// Set once a hart has started panicking.
+static mut PANICKING: bool = false;
+
+/// Whether the kernel is panicking; the other harts stop when it is.
+pub fn panicking() -> bool {
+    unsafe { *addr_of_mut!(PANICKING) }
+}
+
+/// Stop this hart for good.
+pub fn park() -> ! {
+    loop {
+        unsafe {
+            asm!("csrci mstatus, 8", "wfi");
+        }
+    }
+}
+
+/// The ABI names of the registers, in the order of TrapFrame::regs.
+const NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// What mcause says, for the causes we know.
+fn cause_name(cause: usize) -> &'static str {
+    let interrupt = cause >> (usize::BITS - 1) & 1 == 1;
+    match (interrupt, cause & 0xfff) {
+        (true, 3) => "machine software interrupt",
+        (true, 7) => "machine timer interrupt",
+        (true, 11) => "machine external interrupt",
+        (false, 0) => "instruction address misaligned",
+        (false, 1) => "instruction access fault",
+        (false, 2) => "illegal instruction",
+        (false, 3) => "breakpoint",
+        (false, 4) => "load address misaligned",
+        (false, 5) => "load access fault",
+        (false, 6) => "store address misaligned",
+        (false, 7) => "store access fault",
+        (false, 8) => "ecall from user mode",
+        (false, 11) => "ecall from machine mode",
+        (false, 12) => "instruction page fault",
+        (false, 13) => "load page fault",
+        (false, 15) => "store page fault",
+        _ => "unknown",
+    }
+}
+
+fn csr_mcause() -> usize {
+    let v;
+    unsafe { asm!("csrr {}, mcause", out(reg) v) };
+    v
+}
+
+fn csr_mepc() -> usize {
+    let v;
+    unsafe { asm!("csrr {}, mepc", out(reg) v) };
+    v
+}
+
+fn csr_mtval() -> usize {
+    let v;
+    unsafe { asm!("csrr {}, mtval", out(reg) v) };
+    v
+}
+
+fn csr_mstatus() -> usize {
+    let v;
+    unsafe { asm!("csrr {}, mstatus", out(reg) v) };
+    v
+}
+
+fn hart() -> usize {
+    let v;
+    unsafe { asm!("csrr {}, mhartid", out(reg) v) };
+    v
+}
+
+/// The frame of what's running, if there is one.
+fn frame() -> Option<&'static TrapFrame> {
+    match cpu::mscratch_read() {
+        0 => None,
+        frame => Some(unsafe { &*(frame as *const TrapFrame) }),
+    }
+}
+
+/// Whether we're in the trap handler: its stack is the one we're on.
+fn in_trap(frame: &TrapFrame) -> bool {
+    let top = frame.trap_stack as usize;
+    let bottom = top.wrapping_sub(TRAP_STACK_PAGES * page::PAGE_SIZE);
+    (bottom..top).contains(&cpu::sp_read())
+}
+
+/// The harts the device tree lists, or just the one we booted on.
+fn harts() -> impl Iterator<Item = usize> {
+    let mut harts = [false; MAX_HARTS];
+    if let Some((f, cpus)) = fdt::get().and_then(|f| Some((f, f.find("/cpus")?))) {
+        for c in f.children(cpus) {
+            let hart = c.reg().map(|(hart, _)| hart);
+            if c.str_property("device_type") == Some("cpu") {
+                if let Some(hart) = hart.filter(|&h| h < MAX_HARTS) {
+                    harts[hart] = true;
+                }
+            }
+        }
+    }
+    if !harts.contains(&true) {
+        harts[board::BOOT_HART] = true;
+    }
+    (0..MAX_HARTS).filter(move |&h| harts[h])
+}
+
+/// Interrupt every hart but this one, to have it stop.
+fn stop_others() {
+    let me = hart();
+    for hart in harts().filter(|&h| h != me) {
+        cpu::send_ipi(hart);
+    }
+}
+
+fn print_registers(frame: &TrapFrame) {
+    println!("Registers (pid {}, pc 0x{:08x}):", frame.pid, frame.pc);
+    for (i, chunk) in frame.regs.chunks(4).enumerate() {
+        for (j, r) in chunk.iter().enumerate() {
+            print!("  {:>4} 0x{:0w$x}", NAMES[i * 4 + j], r, w = 2 * WORD);
+        }
+        println!();
+    }
+}
+
+/// Print the stack from `sp` up, as far as it's RAM.
+fn print_stack(sp: usize) {
+    if !readable(sp) {
+        println!("Stack at 0x{:08x}, which isn't somewhere we can look", sp);
+        return;
+    }
+    let end = RAM_START + board::RAM_SIZE;
+    let words = STACK_WORDS.min((end - sp) / WORD);
+    println!("Stack at 0x{:08x}:", sp);
+    for line in (0..words).step_by(4) {
+        print!("  0x{:08x}:", sp + line * WORD);
+        for i in line..(line + 4).min(words) {
+            let value = unsafe { *((sp + i * WORD) as *const usize) };
+            print!(" 0x{:0w$x}", value, w = 2 * WORD);
+        }
+        println!();
+    }
+}
+
+/// Whether `addr` is in RAM and aligned to a word, so it can be read.
+fn readable(addr: usize) -> bool {
+    let ram = RAM_START..RAM_START + board::RAM_SIZE;
+    addr.is_multiple_of(WORD) && ram.contains(&addr)
+}
+
+/// Print `addr` and the function it's in.
+fn print_address(addr: usize) {
+    // A return address is just past the call, which may be the last
+    // instruction of the function.
+    match ksyms::lookup(addr - 1) {
+        Some((name, offset)) => println!("  0x{:08x} {}+0x{:x}", addr, name, offset + 1),
+        None => println!("  0x{:08x}", addr),
+    }
+}
+
+/// Print the return addresses of the frames from the one `fp` points to.
+fn print_backtrace(mut fp: usize) {
+    println!("Backtrace:");
+    for _ in 0..MAX_FRAMES {
+        if fp < 2 * WORD || !readable(fp - 2 * WORD) {
+            break;
+        }
+        let (ra, caller) = unsafe {
+            (
+                *((fp - WORD) as *const usize),
+                *((fp - 2 * WORD) as *const usize),
+            )
+        };
+        if ra == 0 {
+            break;
+        }
+        print_address(ra);
+        // Callers' frames are higher up the stack.
+        if caller <= fp {
+            break;
+        }
+        fp = caller;
+    }
+}
+
+/// This function's frame pointer.
+#[inline(always)]
+fn fp_read() -> usize {
+    let fp;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+    fp
+}
+
+fn print_memory() {
+    let (pages, taken) = page::stats();
+    let (heap, heap_taken) = kmem::stats();
+    println!(
+        "Memory: {} of {} pages taken, {} of {} bytes of the kernel heap",
+        taken, pages, heap_taken, heap
+    );
+}
+
+/// Say all we can about the panic described by `info`, and stop.
+pub fn report(info: &PanicInfo) -> ! {
+    uart::make_synchronous();
+    if panicking() {
+        println!("Panic while panicking: {}", info);
+        power::exit(1);
+    }
+    unsafe {
+        PANICKING = true;
+    }
+    stop_others();
+    println!("Aborting: {}", info);
+    println!("Hart {}", hart());
+    let frame = frame();
+    match frame.filter(|f| in_trap(f)) {
+        Some(frame) => {
+            let cause = csr_mcause();
+            println!(
+                "In a trap: mcause 0x{:x} ({}), mepc 0x{:08x}, mtval 0x{:08x}, mstatus 0x{:x}",
+                cause,
+                cause_name(cause),
+                csr_mepc(),
+                csr_mtval(),
+                csr_mstatus()
+            );
+            print_registers(frame);
+            print_stack(frame.regs[cpu::gp(Registers::Sp)]);
+        }
+        None => {
+            if let Some(frame) = frame {
+                println!("Running pid {}", frame.pid);
+            }
+            print_stack(cpu::sp_read());
+        }
+    }
+    print_backtrace(fp_read());
+    print_memory();
+    crashdump::save();
+    // Under QEMU, this makes it exit with a failure instead of hanging.
+    power::exit(1);
+}
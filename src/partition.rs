@@ -0,0 +1,367 @@
+// Partition tables: a disk can be split into parts that are disks of their
+// own, so that one image has, say, a boot partition and a root filesystem.
+// Every disk is looked at when it's registered, and each partition it has
+// is registered after it as a block device named after the disk and its
+// number, /dev/vda1, /dev/vda2, ..., with a p in between if the disk's
+// name ends in a digit, as in /dev/mmcblk0p1 and /dev/nvme0n1p1. It's
+// mounted at boot like any other disk would be.
+//
+// Two kinds of table are understood, in 512-byte sectors:
+//
+//   MBR   sector 0 ends in 55 aa and has four entries at 446, 16 bytes
+//         each: a status (0 or 0x80), the type (0 is unused), and the
+//         first sector and number of sectors, 32-bit. Extended partitions,
+//         the ones with logical partitions in them, are skipped.
+//   GPT   the MBR has one partition of type 0xee, and sector 1 is the GPT
+//         header ("EFI PART"), which says where the entries are, how many
+//         and how big. An entry whose type GUID is all zeros is unused.
+//         The header and the entries have a CRC32 each, and a table whose
+//         CRCs are wrong is left alone. The backup at the end of the disk
+//         isn't looked at.
+//
+// A FAT filesystem with no partition table starts with a sector that ends
+// in 55 aa too, so one whose statuses aren't 0 or 0x80, or which has the
+// jump and "FAT" of a FAT boot sector, isn't taken for an MBR.
+//
+// A partition that goes past the end of its disk is cut short there, and
+// one that starts past it is skipped. Reading and writing a partition is
+// reading and writing its disk, from where the partition starts.
+
+use crate::{
+    block::{self, BlockDevice, SECTOR_SIZE},
+    syscall::{SysError, EIO},
+};
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use log::{info, warn};
+
+use SysError::Errno;
+
+/// Partitions are numbered from 1, and a disk's have the device numbers
+/// after its own, which leaves room for 15 of them.
+const MAX_PARTITIONS: usize = 15;
+
+const MBR_ENTRIES: usize = 446;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const MBR_GPT: u8 = 0xee;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// The most GPT entries we read, which is also how many there usually are.
+const GPT_MAX_ENTRIES: usize = 128;
+
+fn u32_at(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(b[off..off + 8].try_into().unwrap())
+}
+
+/// The CRC32 of `data`, the one of zlib, gzip and GPT.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A part of a disk, as a disk.
+struct Partition {
+    name: String,
+    // The block device number of the disk it's on.
+    disk: usize,
+    number: usize,
+    start: u64,
+    sectors: u64,
+}
+
+impl Partition {
+    fn disk(&self) -> &'static mut dyn BlockDevice {
+        block::get(self.disk).unwrap()
+    }
+
+    /// Where on the disk the sectors from `sector` on that fit in `len`
+    /// bytes are.
+    fn position(&self, sector: u64, len: usize) -> Result<u64, SysError> {
+        let count = (len / SECTOR_SIZE) as u64;
+        if sector
+            .checked_add(count)
+            .is_none_or(|end| end > self.sectors)
+        {
+            return Err(Errno(EIO));
+        }
+        Ok(self.start + sector)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rdev(&self) -> u64 {
+        self.disk().rdev() + self.number as u64
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk().read_only()
+    }
+
+    /// If the disk answers Block, so do we, and the restarted request asks
+    /// the disk for the same sectors again.
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), SysError> {
+        let at = self.position(sector, buf.len())?;
+        self.disk().read_sectors(at, buf)
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), SysError> {
+        let at = self.position(sector, buf.len())?;
+        self.disk().write_sectors(at, buf)
+    }
+
+    /// What we wait for is the disk.
+    fn wait(&mut self, pid: usize) -> bool {
+        block::wait(self.disk, pid);
+        true
+    }
+}
+
+/// Read `count` sectors of disk `dev` from `sector`, waiting for them.
+fn read(dev: usize, sector: u64, count: usize) -> Option<Vec<u8>> {
+    let disk = block::get(dev)?;
+    let end = sector.checked_add(count as u64)?;
+    if end > disk.num_sectors() {
+        return None;
+    }
+    let mut buf = vec![0; count * SECTOR_SIZE];
+    block::with_sleep(false, || disk.read_sectors(sector, &mut buf)).ok()?;
+    Some(buf)
+}
+
+/// Whether `mbr` is a partition table, and not a FAT boot sector or
+/// something else that ends in 55 aa.
+fn is_mbr(mbr: &[u8]) -> bool {
+    if mbr[510..512] != [0x55, 0xaa] {
+        return false;
+    }
+    let fat = matches!(mbr[0], 0xeb | 0xe9) && (&mbr[54..57] == b"FAT" || &mbr[82..85] == b"FAT");
+    let statuses = (0..4).all(|i| matches!(mbr[MBR_ENTRIES + i * 16], 0 | 0x80));
+    statuses && !fat
+}
+
+/// A partition in a table: its number, first sector and size.
+type Part = (usize, u64, u64);
+
+/// The partitions in the MBR `mbr`. They're numbered by their entry.
+fn mbr_partitions(mbr: &[u8]) -> Vec<Part> {
+    let entries = (1..).zip(mbr[MBR_ENTRIES..MBR_ENTRIES + 64].chunks(16));
+    entries
+        .filter(|(_, e)| e[4] != 0 && !MBR_EXTENDED.contains(&e[4]))
+        .map(|(n, e)| (n, u32_at(e, 8) as u64, u32_at(e, 12) as u64))
+        .collect()
+}
+
+/// The partitions in the GPT of disk `dev`, or None if it doesn't have one
+/// that's all right. They're numbered by their entry too.
+fn gpt_partitions(dev: usize) -> Option<Vec<Part>> {
+    let header = read(dev, 1, 1)?;
+    let (at, bytes) = gpt_entries_at(&header)?;
+    let entries = read(dev, at, bytes.div_ceil(SECTOR_SIZE))?;
+    gpt_entries(&header, &entries[..bytes])
+}
+
+/// Where the entries of the GPT with the header `header` are, and how many
+/// bytes of them, if the header is all right.
+fn gpt_entries_at(header: &[u8]) -> Option<(u64, usize)> {
+    let size = u32_at(header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE || !(92..=SECTOR_SIZE).contains(&size) {
+        return None;
+    }
+    let mut h = header[..size].to_vec();
+    h[16..20].fill(0);
+    if crc32(&h) != u32_at(header, 16) {
+        return None;
+    }
+    let (count, entry_size) = (u32_at(header, 80) as usize, u32_at(header, 84) as usize);
+    if count > GPT_MAX_ENTRIES || entry_size < 128 || !entry_size.is_multiple_of(8) {
+        return None;
+    }
+    Some((u64_at(header, 72), count * entry_size))
+}
+
+/// The partitions in `entries`, the entries of the GPT with the header
+/// `header`, if their CRC is right.
+fn gpt_entries(header: &[u8], entries: &[u8]) -> Option<Vec<Part>> {
+    if crc32(entries) != u32_at(header, 88) {
+        return None;
+    }
+    let entry_size = u32_at(header, 84) as usize;
+    let entries = (1..).zip(entries.chunks(entry_size));
+    let used = entries.filter(|(_, e)| e[..16].iter().any(|&b| b != 0));
+    // The last sector is in the partition.
+    let parts = used
+        .map(|(n, e)| (n, u64_at(e, 32), u64_at(e, 40)))
+        .filter(|&(_, first, last)| first <= last)
+        .map(|(n, first, last)| (n, first, last - first + 1));
+    Some(parts.collect())
+}
+
+/// Look for a partition table on disk `dev`, and register the partitions
+/// it has.
+pub fn scan(dev: usize) {
+    let Some(mbr) = read(dev, 0, 1) else {
+        return;
+    };
+    if !is_mbr(&mbr) {
+        return;
+    }
+    let mut parts = mbr_partitions(&mbr);
+    let mut kind = "MBR";
+    if (0..4).any(|i| mbr[MBR_ENTRIES + i * 16 + 4] == MBR_GPT) {
+        match gpt_partitions(dev) {
+            Some(gpt) => (parts, kind) = (gpt, "GPT"),
+            None => {
+                warn!("the GPT of disk {} is corrupt", dev);
+                return;
+            }
+        }
+    }
+    let disk = block::get(dev).unwrap();
+    let (disk_name, disk_sectors) = (String::from(disk.name()), disk.num_sectors());
+    let p = if disk_name.ends_with(|c: char| c.is_ascii_digit()) {
+        "p"
+    } else {
+        ""
+    };
+    let mut names = Vec::new();
+    for (number, start, sectors) in parts {
+        if number > MAX_PARTITIONS {
+            warn!("{} has more than {} partitions", disk_name, MAX_PARTITIONS);
+            break;
+        }
+        if sectors == 0 || start >= disk_sectors {
+            continue;
+        }
+        let name = format!("{}{}{}", disk_name, p, number);
+        names.push(name.clone());
+        block::register_partition(Box::new(Partition {
+            name,
+            disk: dev,
+            number,
+            start,
+            sectors: sectors.min(disk_sectors - start),
+        }));
+    }
+    if !names.is_empty() {
+        info!("{}: {} ({})", disk_name, names.join(" "), kind);
+    }
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::check;
+    use alloc::format;
+
+    fn put32(b: &mut [u8], off: usize, v: u32) {
+        b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn put64(b: &mut [u8], off: usize, v: u64) {
+        b[off..off + 8].copy_from_slice(&v.to_le_bytes());
+    }
+
+    #[test_case]
+    fn crc32_test() -> Result<(), String> {
+        check(crc32(b"") == 0, || {
+            format!("crc32 of nothing is {:x}", crc32(b""))
+        })?;
+        let crc = crc32(b"123456789");
+        check(crc == 0xcbf4_3926, || {
+            format!("crc32 of 123456789 is {:x}", crc)
+        })
+    }
+
+    /// An MBR with a partition, an extended one, and one more.
+    #[test_case]
+    fn mbr_test() -> Result<(), String> {
+        let mut mbr = [0u8; SECTOR_SIZE];
+        mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+        let mut entry = |i: usize, status, kind, start, sectors| {
+            let e = &mut mbr[MBR_ENTRIES + i * 16..MBR_ENTRIES + i * 16 + 16];
+            (e[0], e[4]) = (status, kind);
+            put32(e, 8, start);
+            put32(e, 12, sectors);
+        };
+        entry(0, 0x80, 0x83, 2048, 1000);
+        entry(1, 0, 0x05, 3048, 500);
+        entry(2, 0, 0x0c, 5000, 10);
+        check(is_mbr(&mbr), || "the MBR isn't one".into())?;
+        let parts = mbr_partitions(&mbr);
+        check(parts == [(1, 2048, 1000), (3, 5000, 10)], || {
+            format!("the MBR has {:?}", parts)
+        })?;
+        // A FAT boot sector isn't an MBR, and neither is a bad status.
+        let mut fat = mbr;
+        fat[0] = 0xeb;
+        fat[82..85].copy_from_slice(b"FAT");
+        check(!is_mbr(&fat), || "a FAT boot sector is an MBR".into())?;
+        mbr[MBR_ENTRIES] = 0x12;
+        check(!is_mbr(&mbr), || "status 0x12 is an MBR".into())
+    }
+
+    /// A GPT with a partition, an unused entry, one that ends before it
+    /// starts, and one of a sector.
+    #[test_case]
+    fn gpt_test() -> Result<(), String> {
+        let mut entries = [0u8; 4 * 128];
+        for (i, first, last) in [(0, 34, 2081), (2, 100, 99), (3, 3000, 3000)] {
+            let e = &mut entries[i * 128..(i + 1) * 128];
+            e[..16].fill(0xaf);
+            put64(e, 32, first);
+            put64(e, 40, last);
+        }
+        let mut header = [0u8; SECTOR_SIZE];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        put32(&mut header, 12, 92);
+        put64(&mut header, 72, 2);
+        put32(&mut header, 80, 4);
+        put32(&mut header, 84, 128);
+        put32(&mut header, 88, crc32(&entries));
+        let crc = crc32(&header[..92]);
+        put32(&mut header, 16, crc);
+
+        let at = gpt_entries_at(&header);
+        check(at == Some((2, entries.len())), || {
+            format!("the header says the entries are at {:?}", at)
+        })?;
+        let parts = gpt_entries(&header, &entries);
+        check(
+            parts.as_deref() == Some(&[(1, 34, 2048), (4, 3000, 1)]),
+            || format!("the GPT has {:?}", parts),
+        )?;
+        // Either CRC being wrong is the table being left alone.
+        entries[40] ^= 1;
+        check(gpt_entries(&header, &entries).is_none(), || {
+            "a wrong entries CRC went unnoticed".into()
+        })?;
+        header[72] ^= 1;
+        check(gpt_entries_at(&header).is_none(), || {
+            "a wrong header CRC went unnoticed".into()
+        })
+    }
+}
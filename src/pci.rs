@@ -0,0 +1,313 @@
+// PCI Express. QEMU's virt machine has a generic PCIe host bridge, whose
+// configuration space is memory mapped the ECAM way: every function of
+// every device on every bus gets 4 KiB of it, at an address made of its bus,
+// device and function numbers. There's no firmware to set anything up for
+// us (we run with -bios none), so we size the BARs of the functions
+// ourselves and hand them addresses from the bridge's memory window.
+// Only bus 0 is scanned: without bridges on it, that's where everything is.
+
+use crate::device::{self, Device};
+use alloc::vec::Vec;
+use log::{info, warn};
+
+// Where QEMU's virt machine has the bridge's 32-bit memory window.
+const MMIO_BASE: usize = 0x4000_0000;
+const MMIO_SIZE: usize = 0x4000_0000;
+/// The PLIC interrupt of INTA of device 0. The others rotate through the
+/// next three, see route_interrupt().
+const INTX_BASE: u32 = 0x20;
+
+// Configuration space registers
+const VENDOR_ID: usize = 0x00;
+const DEVICE_ID: usize = 0x02;
+const COMMAND: usize = 0x04;
+const STATUS: usize = 0x06;
+const CLASS_REVISION: usize = 0x08;
+const HEADER_TYPE: usize = 0x0e;
+const BAR0: usize = 0x10;
+const SUBSYSTEM_ID: usize = 0x2e;
+const CAPABILITIES: usize = 0x34;
+const INTERRUPT_PIN: usize = 0x3d;
+
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+// BAR bits
+const BAR_IO: u32 = 1;
+const BAR_64BIT: u32 = 0b100;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+const NUM_BARS: usize = 6;
+
+/// A function of a device on the bus.
+pub struct Function {
+    cfg: usize,
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// The PLIC interrupt its INTx pin is routed to, if it has one.
+    pub irq: Option<u32>,
+    // Where the BARs are, as CPU addresses.
+    bars: [Option<usize>; NUM_BARS],
+}
+
+impl Function {
+    pub fn read8(&self, offset: usize) -> u8 {
+        unsafe { ((self.cfg + offset) as *const u8).read_volatile() }
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        unsafe { ((self.cfg + offset) as *const u16).read_volatile() }
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.cfg + offset) as *const u32).read_volatile() }
+    }
+
+    fn write16(&self, offset: usize, val: u16) {
+        unsafe { ((self.cfg + offset) as *mut u16).write_volatile(val) }
+    }
+
+    fn write32(&self, offset: usize, val: u32) {
+        unsafe { ((self.cfg + offset) as *mut u32).write_volatile(val) }
+    }
+
+    /// Its class code: class, subclass and programming interface.
+    pub fn class(&self) -> u32 {
+        self.read32(CLASS_REVISION) >> 8
+    }
+
+    pub fn subsystem_id(&self) -> u16 {
+        self.read16(SUBSYSTEM_ID)
+    }
+
+    /// The CPU address of memory BAR `n`, if it has one.
+    pub fn bar(&self, n: usize) -> Option<usize> {
+        *self.bars.get(n)?
+    }
+
+    /// The offsets of the function's capabilities in its configuration
+    /// space, with their IDs.
+    pub fn capabilities(&self) -> Vec<(u8, usize)> {
+        let mut caps = Vec::new();
+        if self.read16(STATUS) & STATUS_CAPABILITIES == 0 {
+            return caps;
+        }
+        let mut offset = self.read8(CAPABILITIES) as usize & !3;
+        // The list can't be longer than what fits in the header, stop if
+        // it loops.
+        while offset != 0 && caps.len() < 48 {
+            caps.push((self.read8(offset), offset));
+            offset = self.read8(offset + 1) as usize & !3;
+        }
+        caps
+    }
+}
+
+/// The host bridge, as the device tree describes it.
+struct Bridge {
+    ecam: usize,
+    // The 32-bit memory window: where it is for the CPU and on the bus,
+    // and how big it is.
+    cpu_base: usize,
+    pci_base: usize,
+    size: usize,
+    // Where the next BAR goes, as an offset into the window.
+    next: usize,
+    // The interrupt-map and its mask: every entry is the function's
+    // address (3 cells) and pin, then the controller's phandle and the
+    // interrupt.
+    interrupt_map: Option<&'static [u8]>,
+    map_mask: [u32; 4],
+}
+
+fn be32(b: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes(b[i * 4..i * 4 + 4].try_into().unwrap())
+}
+
+impl Bridge {
+    fn new(dev: &Device) -> Option<Bridge> {
+        let ecam = dev.base()?;
+        let Some(node) = dev.node else {
+            return Some(Bridge {
+                ecam,
+                cpu_base: MMIO_BASE,
+                pci_base: MMIO_BASE,
+                size: MMIO_SIZE,
+                next: 0,
+                interrupt_map: None,
+                map_mask: [0; 4],
+            });
+        };
+        // Every range is a PCI address (3 cells, the first says what kind
+        // of space it is), a CPU address (2 cells) and a size (2 cells).
+        let ranges = node.property("ranges")?;
+        let (cpu_base, pci_base, size) = ranges.chunks_exact(28).find_map(|r| {
+            let space = (be32(r, 0) >> 24) & 3;
+            let pci = ((be32(r, 1) as u64) << 32 | be32(r, 2) as u64) as usize;
+            let cpu = ((be32(r, 3) as u64) << 32 | be32(r, 4) as u64) as usize;
+            let size = ((be32(r, 5) as u64) << 32 | be32(r, 6) as u64) as usize;
+            // 32-bit memory space.
+            (space == 2).then_some((cpu, pci, size))
+        })?;
+        let mut map_mask = [0; 4];
+        if let Some(mask) = node.property("interrupt-map-mask") {
+            for (i, m) in map_mask.iter_mut().enumerate().take(mask.len() / 4) {
+                *m = be32(mask, i);
+            }
+        }
+        Some(Bridge {
+            ecam,
+            cpu_base,
+            pci_base,
+            size,
+            next: 0,
+            interrupt_map: node.property("interrupt-map"),
+            map_mask,
+        })
+    }
+
+    /// Find the PLIC interrupt that INTx `pin` (1 to 4) of a function is
+    /// wired to.
+    fn route_interrupt(&self, dev: u8, func: u8, pin: u8) -> Option<u32> {
+        let Some(map) = self.interrupt_map else {
+            // QEMU rotates the pins of each device through four
+            // interrupts.
+            return Some(INTX_BASE + (dev as u32 + pin as u32 - 1) % 4);
+        };
+        let addr = (dev as u32) << 11 | (func as u32) << 8;
+        let key = [addr, 0, 0, pin as u32];
+        // With the PLIC as the parent, whose interrupts are one cell and
+        // that has no address cells, an entry is 6 cells.
+        map.chunks_exact(24).find_map(|e| {
+            let matches = (0..4).all(|i| be32(e, i) == key[i] & self.map_mask[i]);
+            matches.then(|| be32(e, 5))
+        })
+    }
+
+    /// Size the BARs of a function and give them addresses.
+    fn assign_bars(&mut self, f: &mut Function) {
+        let mut n = 0;
+        while n < NUM_BARS {
+            let reg = BAR0 + n * 4;
+            let orig = f.read32(reg);
+            let is_64 = orig & BAR_IO == 0 && orig & 0b110 == BAR_64BIT;
+            // Writing all ones and reading back tells us which address bits
+            // the BAR has, and so how big it is.
+            f.write32(reg, u32::MAX);
+            let low = f.read32(reg);
+            let high = if is_64 {
+                f.write32(reg + 4, u32::MAX);
+                f.read32(reg + 4)
+            } else {
+                u32::MAX
+            };
+            let slots = if is_64 { 2 } else { 1 };
+            if orig & BAR_IO != 0 || low == 0 {
+                // I/O space, which the virt machine doesn't have a window
+                // for, or no BAR.
+                f.write32(reg, orig);
+                n += slots;
+                continue;
+            }
+            let mask = (high as u64) << 32 | (low & !0xf) as u64;
+            let size = (!mask).wrapping_add(1) as usize;
+            // BARs are aligned to their size.
+            let offset = self.next.next_multiple_of(size);
+            if offset + size > self.size {
+                warn!(
+                    "{:02x}:{:02x}.{}: no room for BAR{}",
+                    f.bus, f.dev, f.func, n
+                );
+                f.write32(reg, orig);
+                n += slots;
+                continue;
+            }
+            self.next = offset + size;
+            let pci = self.pci_base + offset;
+            f.write32(reg, pci as u32);
+            if is_64 {
+                f.write32(reg + 4, ((pci as u64) >> 32) as u32);
+            }
+            f.bars[n] = Some(self.cpu_base + offset);
+            n += slots;
+        }
+    }
+}
+
+/// The driver for the host bridge. Probing it finds every function on bus
+/// 0, gives them their BARs, turns them on and registers them as devices.
+pub struct EcamHost;
+
+impl device::Driver for EcamHost {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["pci-host-ecam-generic"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let Some(bridge) = Bridge::new(dev) else {
+            return false;
+        };
+        for f in enumerate(bridge) {
+            device::register(Device {
+                compatible: Vec::new(),
+                regs: Vec::new(),
+                irqs: f.irq.into_iter().collect(),
+                node: None,
+                pci: Some(f),
+            });
+        }
+        true
+    }
+}
+
+fn enumerate(mut bridge: Bridge) -> Vec<Function> {
+    let mut functions = Vec::new();
+    for dev in 0..DEVICES_PER_BUS {
+        for func in 0..FUNCTIONS_PER_DEVICE {
+            let cfg = bridge.ecam + ((dev as usize) << 15 | (func as usize) << 12);
+            let mut f = Function {
+                cfg,
+                bus: 0,
+                dev,
+                func,
+                vendor_id: 0,
+                device_id: 0,
+                irq: None,
+                bars: [None; NUM_BARS],
+            };
+            f.vendor_id = f.read16(VENDOR_ID);
+            if f.vendor_id == 0xffff {
+                // Nothing there. Without function 0, there are no others.
+                if func == 0 {
+                    break;
+                }
+                continue;
+            }
+            f.device_id = f.read16(DEVICE_ID);
+            let header = f.read8(HEADER_TYPE);
+            // Leave the host bridge itself alone.
+            if !(dev == 0 && func == 0) {
+                bridge.assign_bars(&mut f);
+                let pin = f.read8(INTERRUPT_PIN);
+                if (1..=4).contains(&pin) {
+                    f.irq = bridge.route_interrupt(dev, func, pin);
+                }
+                f.write16(COMMAND, COMMAND_MEMORY | COMMAND_BUS_MASTER);
+            }
+            info!(
+                "{:02x}:{:02x}.{}: {:04x}:{:04x}",
+                f.bus, f.dev, f.func, f.vendor_id, f.device_id
+            );
+            functions.push(f);
+            if func == 0 && header & HEADER_MULTIFUNCTION == 0 {
+                break;
+            }
+        }
+    }
+    functions
+}
@@ -0,0 +1,145 @@
+// Pipes. A pipe is a ring buffer with a read end and a write end, each of
+// which is a File. Readers block while the pipe is empty and writers while
+// it is full. Once the write end is closed, readers get end of file when the
+// buffer runs dry; once the read end is closed, writes fail with EPIPE.
+
+use crate::{
+    console::RingBuffer,
+    file::File,
+    process::WaitQueue,
+    syscall::{Stat, SysError, EBADF, EPIPE},
+};
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+use SysError::{Block, Errno};
+
+/// How much a pipe can hold.
+pub const PIPE_SIZE: usize = 4096;
+/// Writes of at most this many bytes are never split up: they wait until
+/// there is room for all of it.
+pub const PIPE_BUF: usize = 4096;
+
+/// The file type of a pipe, for st_mode.
+pub const S_IFIFO: u32 = 0o010000;
+
+struct Pipe {
+    buffer: RingBuffer<PIPE_SIZE>,
+    reader_open: bool,
+    writer_open: bool,
+    // Readers waiting for data, and writers waiting for room.
+    readers: WaitQueue,
+    writers: WaitQueue,
+}
+
+/// The read end of a pipe.
+pub struct PipeReader(Rc<RefCell<Pipe>>);
+
+/// The write end of a pipe.
+pub struct PipeWriter(Rc<RefCell<Pipe>>);
+
+/// Create a pipe and return its two ends.
+pub fn new() -> (PipeReader, PipeWriter) {
+    let pipe = Rc::new(RefCell::new(Pipe {
+        buffer: RingBuffer::new(),
+        reader_open: true,
+        writer_open: true,
+        readers: WaitQueue::new("pipe readers"),
+        writers: WaitQueue::new("pipe writers"),
+    }));
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+fn stat() -> Stat {
+    Stat {
+        st_mode: S_IFIFO | 0o600,
+        st_nlink: 1,
+        st_blksize: PIPE_SIZE as i32,
+        ..Default::default()
+    }
+}
+
+impl File for PipeReader {
+    fn read(&self, _offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let mut pipe = self.0.borrow_mut();
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut n = 0;
+        while n < buf.len() {
+            let Some(c) = pipe.buffer.pop() else {
+                break;
+            };
+            buf[n] = c;
+            n += 1;
+        }
+        if n == 0 {
+            // Empty. That's the end if nobody can write anymore.
+            return if pipe.writer_open { Err(Block) } else { Ok(0) };
+        }
+        pipe.writers.wake_all();
+        Ok(n)
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, SysError> {
+        // Read ends are only ever opened read-only.
+        Err(Errno(EBADF))
+    }
+
+    fn stat(&self) -> Stat {
+        stat()
+    }
+
+    fn wait(&self, pid: usize) {
+        self.0.borrow_mut().readers.wait(pid);
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut pipe = self.0.borrow_mut();
+        pipe.reader_open = false;
+        // Writers waiting for room will never get any, let them fail.
+        pipe.writers.wake_all();
+    }
+}
+
+impl File for PipeWriter {
+    fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, SysError> {
+        Err(Errno(EBADF))
+    }
+
+    fn write(&self, _offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        let mut pipe = self.0.borrow_mut();
+        if !pipe.reader_open {
+            return Err(Errno(EPIPE));
+        }
+        let room = PIPE_SIZE - pipe.buffer.len();
+        if room == 0 || (buf.len() <= PIPE_BUF && room < buf.len()) {
+            return Err(Block);
+        }
+        let n = buf.len().min(room);
+        for &c in &buf[..n] {
+            pipe.buffer.push(c);
+        }
+        pipe.readers.wake_all();
+        Ok(n)
+    }
+
+    fn stat(&self) -> Stat {
+        stat()
+    }
+
+    fn wait(&self, pid: usize) {
+        self.0.borrow_mut().writers.wait(pid);
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut pipe = self.0.borrow_mut();
+        pipe.writer_open = false;
+        // Readers waiting for data now get end of file.
+        pipe.readers.wake_all();
+    }
+}
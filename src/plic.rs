@@ -0,0 +1,176 @@
+// Platform-Level Interrupt Controller (PLIC)
+// The PLIC routes external interrupts (UART, virtio, ...) to the harts.
+// We only ever take them on the boot hart in machine mode. Which context
+// that is depends on the board: every hart has one per privilege mode it
+// has, and each context has its own enable bits, threshold and claim
+// register.
+
+use crate::{
+    board,
+    device::{self, Device},
+    ktrace::{self, Kind},
+};
+use alloc::collections::BTreeMap;
+use core::ptr::addr_of_mut;
+
+// Where its registers are in it. The enables and the threshold and claim
+// registers are repeated for every context.
+const PLIC_PRIORITY: usize = 0x00_0000;
+const PLIC_INT_ENABLE: usize = 0x00_2000 + 0x80 * board::PLIC_CONTEXT;
+const PLIC_THRESHOLD: usize = 0x20_0000 + 0x1000 * board::PLIC_CONTEXT;
+const PLIC_CLAIM: usize = PLIC_THRESHOLD + 4;
+
+static mut BASE: usize = board::PLIC_BASE;
+
+/// The driver for the PLIC. Probing it lets interrupts through: those at
+/// or below the threshold (0) are masked, and the drivers enable theirs
+/// with a priority of 1.
+pub struct Plic;
+
+impl device::Driver for Plic {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["riscv,plic0", "sifive,plic-1.0.0"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let Some(base) = dev.base() else {
+            return false;
+        };
+        unsafe {
+            BASE = base;
+        }
+        set_threshold(0);
+        true
+    }
+}
+
+fn reg(offset: usize) -> usize {
+    unsafe { BASE + offset }
+}
+
+/// Get the next available interrupt. This is the "claim" process.
+/// The plic will automatically sort by priority and hand us the
+/// ID of the interrupt. For example, if the UART is interrupting
+/// and it's next, we will get the value 10.
+pub fn next() -> Option<u32> {
+    let claim_reg = reg(PLIC_CLAIM) as *const u32;
+    let claim_no;
+    // The claim register is filled with the highest-priority, enabled
+    // interrupt.
+    unsafe {
+        claim_no = claim_reg.read_volatile();
+    }
+    if claim_no == 0 {
+        // The interrupt 0 is hardwired to 0, which tells us that there is
+        // no interrupt to claim, hence we return None.
+        None
+    } else {
+        // If we get here, we've gotten a non-0 interrupt.
+        Some(claim_no)
+    }
+}
+
+/// Complete a pending interrupt by id. The id should come
+/// from the next() function above.
+pub fn complete(id: u32) {
+    let complete_reg = reg(PLIC_CLAIM) as *mut u32;
+    unsafe {
+        // We actually write a u32 into the entire complete_register.
+        // This is the same register as the claim register, but it can
+        // differentiate based on whether we're reading or writing.
+        complete_reg.write_volatile(id);
+    }
+}
+
+/// Set the global threshold. The threshold can be a value [0..7].
+/// The PLIC will mask any interrupts at or below the given threshold.
+/// This means that a threshold of 7 will mask ALL interrupts and
+/// a threshold of 0 will allow ALL interrupts.
+pub fn set_threshold(tsh: u8) {
+    // We do tsh because we're using a u8, but our maximum number
+    // is a 3-bit 0b111. So, we and with 7 (0b111) to just get the
+    // last three bits.
+    let actual_tsh = tsh & 7;
+    let tsh_reg = reg(PLIC_THRESHOLD) as *mut u32;
+    unsafe {
+        tsh_reg.write_volatile(actual_tsh as u32);
+    }
+}
+
+/// Enable a given interrupt id
+pub fn enable(id: u32) {
+    let enables = (reg(PLIC_INT_ENABLE) as *mut u32).wrapping_add(id as usize / 32);
+    let actual_id = 1 << (id % 32);
+    unsafe {
+        // Unlike the complete and claim registers, the plic_int_enable
+        // register is a bitset where the id is the bit index. The register
+        // is a 32-bit register, so that gives us enables for interrupts
+        // 31 through 1 (0 is hardwired to 0).
+        enables.write_volatile(enables.read_volatile() | actual_id);
+    }
+}
+
+/// Set a given interrupt priority to the given priority.
+/// The priority must be [0..7]
+pub fn set_priority(id: u32, prio: u8) {
+    let actual_prio = prio as u32 & 7;
+    let prio_reg = reg(PLIC_PRIORITY) as *mut u32;
+    unsafe {
+        // The offset for the interrupt id is:
+        // BASE + PLIC_PRIORITY + 4 * id
+        // Since we're using pointer arithmetic on a u32 type,
+        // it will automatically multiply the id by 4.
+        prio_reg.add(id as usize).write_volatile(actual_prio);
+    }
+}
+
+/// How many times each interrupt has come, by id, for the ones that have.
+static mut COUNTS: BTreeMap<u32, u64> = BTreeMap::new();
+
+/// Every interrupt that has come, and how many times.
+pub fn counts() -> impl Iterator<Item = (u32, u64)> {
+    unsafe { (*addr_of_mut!(COUNTS)).iter().map(|(&id, &n)| (id, n)) }
+}
+
+/// Who handles interrupt `id`.
+pub fn owner(id: u32) -> &'static str {
+    match id {
+        id if crate::uart::has_irq(id) => "uart",
+        id if crate::virtio::has_irq(id) && crate::nvme::has_irq(id) => "virtio, nvme",
+        id if crate::virtio::has_irq(id) => "virtio",
+        id if crate::nvme::has_irq(id) => "nvme",
+        id if crate::gpio::has_irq(id) => "gpio",
+        _ => "none",
+    }
+}
+
+/// Claim and dispatch every pending external interrupt.
+pub fn handle_interrupt() {
+    while let Some(interrupt) = next() {
+        ktrace::record(Kind::Irq, interrupt as usize, 0);
+        unsafe {
+            *(*addr_of_mut!(COUNTS)).entry(interrupt).or_insert(0) += 1;
+        }
+        match interrupt {
+            id if crate::uart::has_irq(id) => {
+                crate::uart::handle_interrupt(id);
+            }
+            id if crate::virtio::has_irq(id) || crate::nvme::has_irq(id) => {
+                // PCI devices share their INTx interrupts, so everyone on
+                // it gets a look.
+                crate::virtio::handle_interrupt(id);
+                crate::nvme::handle_interrupt(id);
+            }
+            id if crate::gpio::has_irq(id) => {
+                crate::gpio::handle_interrupt(id);
+            }
+            _ => {
+                println!("Unknown external interrupt: {}", interrupt);
+            }
+        }
+        // We've claimed it, so now say that we've handled it. This resets
+        // the interrupt pending bit and allows the UART to interrupt
+        // again. Otherwise, the UART will get "stuck".
+        complete(interrupt);
+    }
+}
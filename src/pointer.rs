@@ -0,0 +1,178 @@
+// The mouse pointer: where it is on the screen, and the arrow that shows it
+// there. Tablets (like QEMU's virtio-tablet, which follows the host's
+// mouse) say where the pointer is along an axis from some minimum to some
+// maximum, which we scale to the screen's pixels; mice say how far it
+// moved, in pixels. Without a display, the pointer moves over a screen of
+// 0 to 32767 both ways, like the tablet's own.
+//
+// Nothing draws the arrow for us, so it's a sprite we draw over the
+// framebuffer, keeping what's under it to put back when it moves. Anything
+// else that draws on the screen hides the pointer first and shows it again
+// after (see hide() and show()), so that the arrow is never drawn over, and
+// what's under it never goes stale. The arrow only shows up once a pointing
+// device has been used.
+
+use crate::gpu;
+use alloc::{vec, vec::Vec};
+use core::ptr::addr_of_mut;
+
+/// The arrow: X is its outline, . is inside it, and the rest is what's
+/// under it. Its tip, the top left corner, is where the pointer is.
+const ARROW: [&[u8; ARROW_WIDTH]; 19] = [
+    b"X          ",
+    b"XX         ",
+    b"X.X        ",
+    b"X..X       ",
+    b"X...X      ",
+    b"X....X     ",
+    b"X.....X    ",
+    b"X......X   ",
+    b"X.......X  ",
+    b"X........X ",
+    b"X.....XXXXX",
+    b"X..X..X    ",
+    b"X.X X..X   ",
+    b"XX  X..X   ",
+    b"X    X..X  ",
+    b"     X..X  ",
+    b"      X..X ",
+    b"      X..X ",
+    b"       XX  ",
+];
+const ARROW_WIDTH: usize = 11;
+const OUTLINE: u32 = 0x000000;
+const INSIDE: u32 = 0xffffff;
+
+/// The size of the screen the pointer moves over if there's no display.
+const NO_DISPLAY_SIZE: u32 = 32768;
+
+struct Pointer {
+    x: u32,
+    y: u32,
+    // Whether a pointing device was used yet, and whether the pointer
+    // moved since the arrow was last drawn.
+    active: bool,
+    moved: bool,
+    // How many hide()s weren't followed by a show() yet.
+    hidden: usize,
+    // Where the arrow is drawn, if it is, and the pixels it covers.
+    drawn: Option<(u32, u32)>,
+    under: Vec<u32>,
+}
+
+static mut POINTER: Pointer = Pointer {
+    x: 0,
+    y: 0,
+    active: false,
+    moved: false,
+    hidden: 0,
+    drawn: None,
+    under: Vec::new(),
+};
+
+fn pointer() -> &'static mut Pointer {
+    unsafe { &mut *addr_of_mut!(POINTER) }
+}
+
+/// The size of the screen, in pixels.
+pub fn screen_size() -> (u32, u32) {
+    gpu::framebuffer().map_or((NO_DISPLAY_SIZE, NO_DISPLAY_SIZE), |fb| {
+        (fb.width, fb.height)
+    })
+}
+
+/// Where the pointer is.
+pub fn position() -> (u32, u32) {
+    let p = pointer();
+    (p.x, p.y)
+}
+
+/// A tablet says the pointer is at `value` along an axis that goes from
+/// `min` to `max`, the x axis or the y axis. Returns where that is on the
+/// screen.
+pub fn move_to(y_axis: bool, value: i32, min: i32, max: i32) -> u32 {
+    let (width, height) = screen_size();
+    let size = if y_axis { height } else { width };
+    let range = (max as i64 - min as i64).max(1);
+    let pos = (value as i64 - min as i64).clamp(0, range) * (size as i64 - 1) / range;
+    set(y_axis, pos as u32);
+    pos as u32
+}
+
+/// A mouse moved the pointer `delta` pixels along the x axis or the y
+/// axis.
+pub fn move_by(y_axis: bool, delta: i32) {
+    let (width, height) = screen_size();
+    let p = pointer();
+    let (pos, size) = if y_axis { (p.y, height) } else { (p.x, width) };
+    let pos = (pos as i64 + delta as i64).clamp(0, size as i64 - 1);
+    set(y_axis, pos as u32);
+}
+
+fn set(y_axis: bool, pos: u32) {
+    let p = pointer();
+    if y_axis {
+        p.y = pos;
+    } else {
+        p.x = pos;
+    }
+    p.active = true;
+    p.moved = true;
+}
+
+/// The device is done reporting a batch of events: draw the arrow where
+/// the pointer is now.
+pub fn sync() {
+    let p = pointer();
+    if !p.moved || p.hidden > 0 {
+        // Whoever hid it shows it again, where it is by then.
+        return;
+    }
+    hide();
+    show();
+    gpu::present();
+}
+
+/// Take the arrow off the screen, before drawing there.
+pub fn hide() {
+    let p = pointer();
+    p.hidden += 1;
+    let Some(fb) = gpu::framebuffer() else {
+        return;
+    };
+    if let Some((x, y)) = p.drawn.take() {
+        fb.blit(x, y, ARROW_WIDTH as u32, &p.under);
+    }
+}
+
+/// Put the arrow back on the screen once everything that hid it is done
+/// drawing. It isn't presented yet, the drawing isn't either.
+pub fn show() {
+    let p = pointer();
+    p.hidden = p.hidden.saturating_sub(1);
+    if p.hidden > 0 || !p.active || p.drawn.is_some() {
+        return;
+    }
+    let Some(fb) = gpu::framebuffer() else {
+        return;
+    };
+    let (x, y) = (p.x, p.y);
+    if p.under.is_empty() {
+        p.under = vec![0; ARROW_WIDTH * ARROW.len()];
+    }
+    fb.read_region(x, y, ARROW_WIDTH as u32, &mut p.under);
+    let mut sprite = p.under.clone();
+    for (row, line) in ARROW.iter().enumerate() {
+        for (col, &c) in line.iter().enumerate() {
+            let px = &mut sprite[row * ARROW_WIDTH + col];
+            match c {
+                b'X' => *px = fb.rgb(OUTLINE),
+                b'.' => *px = fb.rgb(INSIDE),
+                _ => {}
+            }
+        }
+    }
+    fb.blit(x, y, ARROW_WIDTH as u32, &sprite);
+    p.drawn = Some((x, y));
+    p.moved = false;
+}
@@ -0,0 +1,97 @@
+// Powering off and rebooting. QEMU's virt machine has a SiFive test device
+// (the "test finisher") at 0x10_0000 for this: writing a command to it stops
+// or resets the machine, and a failure command makes QEMU exit with a
+// status of our choosing, which is what scripts running the kernel want to
+// see. Spike has no test device, but with HTIF built in we can ask it to
+// stop the same way, only it can't reset, and so can a debugger through
+// semihosting.
+
+use crate::{
+    abort, bcache, block, board,
+    device::{self, Device},
+    htif, semihosting,
+};
+
+// Commands. A failure carries the exit code for QEMU in the upper 16 bits.
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+static mut BASE: Option<usize> = board::TEST_BASE;
+
+/// The driver for the test device. Until it's probed, the device is
+/// assumed to be where the board has one, if it does.
+pub struct TestFinisher;
+
+impl device::Driver for TestFinisher {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["sifive,test0"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let Some(base) = dev.base() else {
+            return false;
+        };
+        unsafe {
+            BASE = Some(base);
+        }
+        true
+    }
+}
+
+fn finish(cmd: u32) -> ! {
+    // Let the drivers get out whatever they still have buffered, it would
+    // be lost.
+    device::remove_all();
+    if let Some(base) = unsafe { BASE } {
+        unsafe {
+            (base as *mut u32).write_volatile(cmd);
+        }
+    }
+    // We're still here, so there is no test device.
+    if semihosting::present() && cmd != FINISHER_RESET {
+        semihosting::exit(if cmd == FINISHER_PASS {
+            0
+        } else {
+            (cmd >> 16) as u16
+        });
+    }
+    if cfg!(feature = "htif") && cmd != FINISHER_RESET {
+        htif::exit(if cmd == FINISHER_PASS {
+            0
+        } else {
+            (cmd >> 16) as u16
+        });
+    }
+    // There's nothing left to do but stop.
+    abort()
+}
+
+/// Get what the block cache has that the disks don't onto them, the way
+/// unmounting everything would.
+fn sync() {
+    if block::with_sleep(false, || bcache::sync(None)).is_err() {
+        println!("power: some changes didn't make it to the disks");
+    }
+}
+
+/// Turn the machine off.
+pub fn power_off() -> ! {
+    sync();
+    finish(FINISHER_PASS)
+}
+
+/// Reset the machine.
+pub fn reboot() -> ! {
+    sync();
+    finish(FINISHER_RESET)
+}
+
+/// Stop the machine and have QEMU exit with `code`. 0 powers off the same
+/// way power_off() does.
+pub fn exit(code: u16) -> ! {
+    if code == 0 {
+        power_off();
+    }
+    finish(FINISHER_FAIL | (code as u32) << 16)
+}
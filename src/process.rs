@@ -0,0 +1,1009 @@
+use crate::{
+    block, board,
+    cpu::{self, gp, CpuMode, Registers, TrapFrame, KERNEL_TRAP_FRAME},
+    file::{FdTable, File},
+    lockdep::{self, Class},
+    locks,
+    page::{self, align_val, EntryBits, Table, PAGE_ORDER, PAGE_SIZE},
+    shm,
+    syscall::{SysError, ENOMEM},
+    timer,
+};
+use alloc::{collections::vec_deque::VecDeque, rc::Rc, vec::Vec};
+use core::{arch::asm, mem::size_of, ptr::addr_of_mut, ptr::null_mut, slice};
+
+use SysError::Errno;
+
+extern "C" {
+    static TEXT_START: usize;
+    static RODATA_END: usize;
+}
+
+// How many pages are we going to give a process for their
+// stack?
+const STACK_PAGES: usize = 8;
+// The layout of a user address space. The kernel's text and read-only data
+// are identity mapped (that's where user programs live for now), the stack
+// sits at STACK_ADDR and the heap grows up from HEAP_START. Memory mappings
+// are placed between MMAP_START and MMAP_END: on Sv39, that's up to the end
+// of the lower half of the address space, and Sv32 has to fit all of it
+// below the kernel at 0x8000_0000.
+pub const HEAP_START: usize = 0x4000_0000;
+#[cfg(target_pointer_width = "64")]
+pub const STACK_ADDR: usize = 0x1_0000_0000;
+#[cfg(target_pointer_width = "64")]
+pub const MMAP_START: usize = 0x10_0000_0000;
+#[cfg(target_pointer_width = "64")]
+pub const MMAP_END: usize = 0x40_0000_0000;
+#[cfg(target_pointer_width = "32")]
+pub const STACK_ADDR: usize = 0x7f00_0000;
+#[cfg(target_pointer_width = "32")]
+pub const MMAP_START: usize = 0x1000_0000;
+#[cfg(target_pointer_width = "32")]
+pub const MMAP_END: usize = 0x4000_0000;
+/// How far the program break may move past HEAP_START, unless the process
+/// asks for something else with setrlimit(RLIMIT_DATA). It's the hard limit
+/// too: a process can ask for less, but only root can ask for more.
+pub const DEFAULT_BRK_LIMIT: usize = 16 * 1024 * 1024;
+
+// The process list holds every process in the system. The scheduler
+// rotates it, so the process at the front is the one currently running.
+static mut PROCESS_LIST: Option<VecDeque<Process>> = None;
+// Processes waiting for one of their children to exit.
+static mut CHILD_EXIT: WaitQueue = WaitQueue::new("child exit");
+// We will eventually move this to a global allocator, but for now, we just
+// hand out increasing process ids. 0 is reserved for the idle context.
+static mut NEXT_PID: usize = 1;
+
+// A trap frame is stored in a single page.
+const _: () = assert!(size_of::<TrapFrame>() <= PAGE_SIZE);
+
+/// A range of the address space created by mmap or shmat. The pages of
+/// anonymous memory are allocated lazily by the page fault handler, the
+/// first time they are touched, and so are those of a mapped file, which
+/// are read from it then. Shared memory is mapped right away, and its
+/// pages belong to the segment rather than to the process.
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub bits: EntryBits,
+    // The shared memory segment behind the region, and the page of the
+    // segment that `start` maps.
+    pub shm: Option<Rc<shm::Segment>>,
+    pub shm_page: usize,
+    pub file: Option<FileMap>,
+}
+
+/// The file behind a region, and where in it the region starts.
+///
+/// There's no page cache to share pages with, so every page is a copy of
+/// the file, our own, made when it's first touched. Writes to a private
+/// mapping only ever change the copy. A shared one's pages are mapped
+/// read-only until they're first written to, which is how we know which
+/// ones to write back to the file: those that are writable. That happens
+/// on msync, and when they're unmapped. Other processes that map the file
+/// see the changes once they're written back.
+#[derive(Clone)]
+pub struct FileMap {
+    pub file: Rc<dyn File>,
+    pub offset: usize,
+    pub shared: bool,
+}
+
+impl Region {
+    /// The part of the region in [start, end).
+    fn slice(&self, start: usize, end: usize) -> Region {
+        let file = self.file.clone().map(|f| FileMap {
+            offset: f.offset + (start - self.start),
+            ..f
+        });
+        Region {
+            start,
+            end,
+            bits: self.bits,
+            shm: self.shm.clone(),
+            shm_page: self.shm_page + (start - self.start) / PAGE_SIZE,
+            file,
+        }
+    }
+
+    /// Unmap [start, end) of the region, freeing the pages if they are
+    /// ours. A shared file mapping writes what was changed back first, or
+    /// tries to: there's nobody to tell if it fails.
+    fn unmap(&self, root: &mut Table, start: usize, end: usize) {
+        if self.shm.is_none() {
+            let _ = self.write_back(root, start, end);
+            free_pages(root, start, end);
+            return;
+        }
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            page::unmap_page(root, vaddr);
+        }
+    }
+
+    /// Write the pages of [start, end) that were written to back to the
+    /// file, if the region is a shared file mapping, and make them
+    /// read-only again. What's past the end of the file stays out of it.
+    fn write_back(&self, root: &mut Table, start: usize, end: usize) -> Result<(), SysError> {
+        let Some(f) = self.file.as_ref().filter(|f| f.shared) else {
+            return Ok(());
+        };
+        for vaddr in (start..end).step_by(PAGE_SIZE) {
+            let Some(entry) = page::walk(root, vaddr) else {
+                continue;
+            };
+            if !entry.bits().contains(EntryBits::WRITE) {
+                continue;
+            }
+            let offset = f.offset + (vaddr - self.start);
+            let size = f.file.size().unwrap_or(0);
+            let len = size.saturating_sub(offset).min(PAGE_SIZE);
+            let data = unsafe { slice::from_raw_parts(entry.addr() as *const u8, len) };
+            let mut done = 0;
+            while done < len {
+                // We can't be put to sleep here, it waits for the disk.
+                match block::with_sleep(false, || f.file.write(offset + done, &data[done..]))? {
+                    0 => break,
+                    n => done += n,
+                }
+            }
+            entry.set_entry(entry.entry & !EntryBits::WRITE.bits());
+        }
+        Ok(())
+    }
+
+    /// Fill the page at `page`, the one of the region at `vaddr`, from the
+    /// file. Returns false if it's past the end of the file, or the file
+    /// can't be read.
+    fn read_in(&self, vaddr: usize, page: *mut u8) -> bool {
+        let Some(f) = &self.file else {
+            return true;
+        };
+        let offset = f.offset + (vaddr - self.start);
+        let buf = unsafe { slice::from_raw_parts_mut(page, PAGE_SIZE) };
+        let mut done = 0;
+        while done < PAGE_SIZE {
+            match block::with_sleep(false, || f.file.read(offset + done, &mut buf[done..])) {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(_) => return false,
+            }
+        }
+        done > 0
+    }
+}
+
+/// A range of an address space, as /proc/<pid>/maps lists it.
+pub struct Map {
+    pub start: usize,
+    pub end: usize,
+    pub bits: EntryBits,
+    /// What's there, if it's anything but anonymous memory.
+    pub name: &'static str,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    Sleeping,
+    Waiting,
+    // Exited, but the parent hasn't collected the exit status yet.
+    Zombie,
+}
+
+// Signals that end a process. They can't be caught yet: the kernel uses
+// them to report why it killed a process, and sends SIGINT for ^C on the
+// console (see console.rs), which ends the process it's sent to.
+pub const SIGINT: usize = 2;
+pub const SIGILL: usize = 4;
+pub const SIGSEGV: usize = 11;
+
+/// A list of processes waiting for something to happen.
+pub struct WaitQueue {
+    pids: Vec<usize>,
+    // What lockdep calls it, unless it's left out.
+    name: Option<&'static str>,
+}
+
+impl WaitQueue {
+    /// A queue that's `name` in lockdep's reports, the same for every
+    /// queue made for the same thing.
+    pub const fn new(name: &'static str) -> Self {
+        WaitQueue {
+            pids: Vec::new(),
+            name: Some(name),
+        }
+    }
+
+    /// A queue lockdep leaves alone, since what's waited for on it is
+    /// tracked itself.
+    pub const fn untracked() -> Self {
+        WaitQueue {
+            pids: Vec::new(),
+            name: None,
+        }
+    }
+
+    /// Block the process `pid` until the queue is woken up.
+    #[track_caller]
+    pub fn wait(&mut self, pid: usize) {
+        if let Some(name) = self.name {
+            lockdep::wait(pid, Class::Queue(name));
+        }
+        set_state(pid, ProcessState::Waiting);
+        self.pids.push(pid);
+    }
+
+    /// Make every waiting process runnable again.
+    #[track_caller]
+    pub fn wake_all(&mut self) {
+        if let Some(name) = self.name {
+            lockdep::woke(name);
+        }
+        for pid in self.pids.drain(..) {
+            if get_by_pid(pid).is_some_and(|p| p.state == ProcessState::Waiting) {
+                set_state(pid, ProcessState::Running);
+            }
+        }
+    }
+}
+
+/// What a process finds when it looks for an exited child.
+pub enum WaitResult {
+    /// It doesn't have any children (that match).
+    NoChild,
+    /// None of them has exited yet.
+    Running,
+    /// This one exited with the given wait status and is gone now.
+    Exited { pid: usize, status: usize },
+}
+
+/// Who a process is, for deciding what it may do to files. There's only
+/// the one uid and gid, no real and effective ones or supplementary groups.
+#[derive(Clone, Copy)]
+pub struct Cred {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Cred {
+    /// What every process starts out as, and what the kernel acts as.
+    pub const ROOT: Cred = Cred { uid: 0, gid: 0 };
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+}
+
+pub struct Process {
+    frame: *mut TrapFrame,
+    stack: *mut u8,
+    // The root page table of user processes, null for kernel processes.
+    root: *mut Table,
+    pid: usize,
+    ppid: usize,
+    state: ProcessState,
+    // When a sleeping process should wake up (in mtime ticks), and the
+    // software timer that is going to wake it. 0 if it isn't sleeping.
+    pub sleep_until: u64,
+    pub sleep_timer: usize,
+    // The end of the heap (the program break), the limit on its size, and
+    // how far anyone but root may raise that.
+    brk: usize,
+    pub brk_limit: usize,
+    pub brk_limit_max: usize,
+    // Memory mappings, sorted by address.
+    regions: Vec<Region>,
+    pub files: FdTable,
+    pub cred: Cred,
+    // The current directory, an absolute path without . or .. or symlinks
+    // in it, empty for the root.
+    pub cwd: Vec<u8>,
+    // The wait status for the parent, once the process is a zombie.
+    exit_status: usize,
+    // Print the system calls the process makes.
+    pub trace: bool,
+    // A signal that was sent to the process, 0 if none was.
+    signal: usize,
+}
+
+impl Process {
+    pub fn frame(&self) -> *mut TrapFrame {
+        self.frame
+    }
+
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub fn ppid(&self) -> usize {
+        self.ppid
+    }
+
+    /// The end of the heap.
+    pub fn brk(&self) -> usize {
+        self.brk
+    }
+
+    /// What's mapped in the address space, by address. Kernel processes
+    /// share the kernel's, which isn't theirs to list.
+    pub fn maps(&self) -> Vec<Map> {
+        if !self.is_user() {
+            return Vec::new();
+        }
+        let map = |start, end, bits, name| Map {
+            start,
+            end,
+            bits,
+            name,
+        };
+        let (text, text_end) = unsafe { (TEXT_START, align_val(RODATA_END, PAGE_ORDER)) };
+        let mut maps = Vec::from([
+            map(text, text_end, EntryBits::USER_READ_EXECUTE, "[kernel]"),
+            map(
+                STACK_ADDR,
+                STACK_ADDR + STACK_PAGES * PAGE_SIZE,
+                EntryBits::USER_READ_WRITE,
+                "[stack]",
+            ),
+        ]);
+        let heap_end = align_val(self.brk, PAGE_ORDER);
+        if heap_end > HEAP_START {
+            maps.push(map(
+                HEAP_START,
+                heap_end,
+                EntryBits::USER_READ_WRITE,
+                "[heap]",
+            ));
+        }
+        for r in &self.regions {
+            let name = if r.shm.is_some() { "[shm]" } else { "" };
+            maps.push(map(r.start, r.end, r.bits, name));
+        }
+        maps.sort_by_key(|m| m.start);
+        maps
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: ProcessState) {
+        self.state = state;
+    }
+
+    pub fn is_user(&self) -> bool {
+        !self.root.is_null()
+    }
+
+    fn new(func: fn(), ppid: usize, mode: CpuMode) -> Self {
+        let pid = unsafe {
+            let pid = NEXT_PID;
+            NEXT_PID += 1;
+            pid
+        };
+        let frame = page::zalloc(1) as *mut TrapFrame;
+        let stack = page::zalloc(STACK_PAGES);
+        assert!(!frame.is_null() && !stack.is_null());
+        let mut ret = Process {
+            frame,
+            stack,
+            root: null_mut(),
+            pid,
+            ppid,
+            state: ProcessState::Running,
+            sleep_until: 0,
+            sleep_timer: 0,
+            brk: 0,
+            brk_limit: DEFAULT_BRK_LIMIT,
+            brk_limit_max: DEFAULT_BRK_LIMIT,
+            regions: Vec::new(),
+            files: FdTable::with_console(),
+            cred: Cred::ROOT,
+            cwd: Vec::new(),
+            exit_status: 0,
+            trace: false,
+            signal: 0,
+        };
+        let sp = match mode {
+            CpuMode::Machine => stack as usize + STACK_PAGES * PAGE_SIZE,
+            CpuMode::User => {
+                ret.root = page::zalloc(1) as *mut Table;
+                assert!(!ret.root.is_null());
+                let root = unsafe { &mut *ret.root };
+                // Map the stack.
+                let mut mapped = (0..STACK_PAGES).all(|i| {
+                    let addr = i * PAGE_SIZE;
+                    page::map(
+                        root,
+                        STACK_ADDR + addr,
+                        stack as usize + addr,
+                        EntryBits::USER_READ_WRITE,
+                        0,
+                    )
+                });
+                // Map the program, which is part of the kernel's text
+                // for now.
+                let (start, end) = unsafe { (TEXT_START, align_val(RODATA_END, PAGE_ORDER)) };
+                mapped = mapped
+                    && (start..end)
+                        .step_by(PAGE_SIZE)
+                        .all(|addr| page::map(root, addr, addr, EntryBits::USER_READ_EXECUTE, 0));
+                assert!(mapped, "no pages left for the page tables");
+                ret.brk = HEAP_START;
+                STACK_ADDR + STACK_PAGES * PAGE_SIZE
+            }
+        };
+        unsafe {
+            (*frame).regs[gp(Registers::Sp)] = sp;
+            (*frame).regs[gp(Registers::Gp)] = global_pointer();
+            (*frame).pc = func as usize;
+            (*frame).mode = mode as usize;
+            (*frame).pid = pid;
+            (*frame).hartid = board::BOOT_HART;
+            if !ret.root.is_null() {
+                (*frame).satp = cpu::build_satp(pid, ret.root as usize);
+            }
+            // All traps taken by processes are handled on the hart's
+            // trap stack.
+            (*frame).trap_stack = KERNEL_TRAP_FRAME[board::BOOT_HART].trap_stack;
+        }
+        ret
+    }
+
+    /// Create a process that runs `func` in machine mode. Kernel processes
+    /// share the kernel's (physical) address space and must not return
+    /// from `func`. Processes created by the kernel itself have 0 as their
+    /// parent.
+    pub fn new_kernel(func: fn(), ppid: usize) -> Self {
+        Self::new(func, ppid, CpuMode::Machine)
+    }
+
+    /// Create a process that runs `func` in user mode, in its own address
+    /// space. `func` must not touch any kernel data and must not return.
+    pub fn new_user(func: fn(), ppid: usize) -> Self {
+        Self::new(func, ppid, CpuMode::User)
+    }
+
+    /// Move the program break to `addr`, mapping zeroed pages into (or
+    /// unmapping them from) the address space as needed. Returns the new
+    /// break, or the old one if it can't be moved there. Whatever the
+    /// limit, the heap stops short of what's mapped above it: the kernel's
+    /// text on Sv39, the stack on Sv32.
+    pub fn set_brk(&mut self, addr: usize) -> usize {
+        let ceiling = unsafe { STACK_ADDR.min(TEXT_START) };
+        if !self.is_user()
+            || addr < HEAP_START
+            || addr > ceiling
+            || addr - HEAP_START > self.brk_limit
+        {
+            return self.brk;
+        }
+        let root = unsafe { &mut *self.root };
+        let old_top = align_val(self.brk, PAGE_ORDER);
+        let new_top = align_val(addr, PAGE_ORDER);
+        if new_top > old_top {
+            for vaddr in (old_top..new_top).step_by(PAGE_SIZE) {
+                let page = page::zalloc(1);
+                if page.is_null()
+                    || !page::map(root, vaddr, page as usize, EntryBits::USER_READ_WRITE, 0)
+                {
+                    // Out of memory, give back what we got so far.
+                    if !page.is_null() {
+                        page::dealloc(page);
+                    }
+                    free_pages(root, old_top, vaddr);
+                    return self.brk;
+                }
+            }
+        } else {
+            free_pages(root, new_top, old_top);
+        }
+        cpu::sfence_vma();
+        self.brk = addr;
+        self.brk
+    }
+
+    /// Create an anonymous mapping of `len` bytes with the given
+    /// permissions (a combination of READ, WRITE and EXECUTE, or nothing
+    /// to just reserve the range) and return its address. Unless `fixed`
+    /// is set, `addr` is ignored and we pick the lowest free range. With
+    /// `fixed`, whatever is mapped at [addr, addr + len) is replaced.
+    pub fn map_anonymous(
+        &mut self,
+        addr: usize,
+        len: usize,
+        bits: EntryBits,
+        fixed: bool,
+    ) -> Option<usize> {
+        self.map(addr, len, bits, fixed, None)
+    }
+
+    /// Map `len` bytes of a file like map_anonymous() maps memory, from the
+    /// offset in `file` on, which is a multiple of the page size.
+    pub fn map_file(
+        &mut self,
+        addr: usize,
+        len: usize,
+        bits: EntryBits,
+        fixed: bool,
+        file: FileMap,
+    ) -> Option<usize> {
+        self.map(addr, len, bits, fixed, Some(file))
+    }
+
+    fn map(
+        &mut self,
+        addr: usize,
+        len: usize,
+        bits: EntryBits,
+        fixed: bool,
+        file: Option<FileMap>,
+    ) -> Option<usize> {
+        if !self.is_user() || len == 0 || len > MMAP_END - MMAP_START {
+            return None;
+        }
+        let len = align_val(len, PAGE_ORDER);
+        let start = if fixed {
+            if !addr.is_multiple_of(PAGE_SIZE) || addr < MMAP_START || addr > MMAP_END - len {
+                return None;
+            }
+            self.unmap_range(addr, len);
+            addr
+        } else {
+            self.find_gap(len)?
+        };
+        // The hardware doesn't allow writable pages that aren't readable.
+        let bits = if bits.contains(EntryBits::WRITE) {
+            bits | EntryBits::READ
+        } else {
+            bits
+        };
+        self.insert_region(Region {
+            start,
+            end: start + len,
+            bits: bits | EntryBits::USER,
+            shm: None,
+            shm_page: 0,
+            file,
+        });
+        Some(start)
+    }
+
+    /// Find the lowest free range of `len` bytes (a multiple of the page
+    /// size) for a mapping.
+    fn find_gap(&self, len: usize) -> Option<usize> {
+        // First fit, the regions are sorted.
+        let mut start = MMAP_START;
+        for r in self.regions.iter() {
+            if r.start - start >= len {
+                break;
+            }
+            start = r.end;
+        }
+        if MMAP_END - start < len {
+            return None;
+        }
+        Some(start)
+    }
+
+    fn insert_region(&mut self, region: Region) {
+        let pos = self.regions.partition_point(|r| r.start < region.start);
+        self.regions.insert(pos, region);
+    }
+
+    /// Attach a shared memory segment at `addr`, or wherever there's room
+    /// if it is 0, and return where it went. The range must be free.
+    pub fn attach_shared(
+        &mut self,
+        seg: Rc<shm::Segment>,
+        addr: usize,
+        read_only: bool,
+    ) -> Option<usize> {
+        let root = unsafe { self.root.as_mut()? };
+        let len = seg.num_pages() * PAGE_SIZE;
+        let start = if addr == 0 {
+            self.find_gap(len)?
+        } else {
+            if !addr.is_multiple_of(PAGE_SIZE) || addr < MMAP_START || addr > MMAP_END - len {
+                return None;
+            }
+            let end = addr + len;
+            if self.regions.iter().any(|r| r.start < end && addr < r.end) {
+                return None;
+            }
+            addr
+        };
+        let bits = if read_only {
+            EntryBits::USER | EntryBits::READ
+        } else {
+            EntryBits::USER_READ_WRITE
+        };
+        for i in 0..seg.num_pages() {
+            if !page::map(root, start + i * PAGE_SIZE, seg.page(i), bits, 0) {
+                // Out of memory. The pages are the segment's, so only
+                // unmap them.
+                for vaddr in (start..start + i * PAGE_SIZE).step_by(PAGE_SIZE) {
+                    page::unmap_page(root, vaddr);
+                }
+                return None;
+            }
+        }
+        cpu::sfence_vma();
+        self.insert_region(Region {
+            start,
+            end: start + len,
+            bits,
+            shm: Some(seg),
+            shm_page: 0,
+            file: None,
+        });
+        Some(start)
+    }
+
+    /// Detach the shared memory segment that was attached at `addr`.
+    /// Returns false if there is none.
+    pub fn detach_shared(&mut self, addr: usize) -> bool {
+        let Some(r) = self
+            .regions
+            .iter()
+            .find(|r| r.start == addr && r.shm.is_some() && r.shm_page == 0)
+        else {
+            return false;
+        };
+        // munmap may have split the attachment up, take all of it.
+        let seg = r.shm.clone().unwrap();
+        let len = seg.num_pages() * PAGE_SIZE;
+        let end = self
+            .regions
+            .iter()
+            .filter(|r| r.start >= addr && r.start < addr + len)
+            .filter(|r| r.shm.as_ref().is_some_and(|s| Rc::ptr_eq(s, &seg)))
+            .map(|r| r.end)
+            .max()
+            .unwrap();
+        self.unmap_range(addr, end - addr);
+        true
+    }
+
+    /// Remove the mappings in [addr, addr + len), freeing the pages that
+    /// were populated. Regions that only partially overlap are split.
+    pub fn unmap_range(&mut self, addr: usize, len: usize) {
+        let Some(root) = (unsafe { self.root.as_mut() }) else {
+            return;
+        };
+        let start = addr;
+        let end = addr.saturating_add(align_val(len, PAGE_ORDER));
+        let mut kept = Vec::with_capacity(self.regions.len() + 1);
+        for r in self.regions.drain(..) {
+            if r.end <= start || r.start >= end {
+                kept.push(r);
+                continue;
+            }
+            r.unmap(root, r.start.max(start), r.end.min(end));
+            if r.start < start {
+                kept.push(r.slice(r.start, start));
+            }
+            if r.end > end {
+                kept.push(r.slice(end, r.end));
+            }
+        }
+        self.regions = kept;
+        cpu::sfence_vma();
+    }
+
+    /// Write what was changed in the shared file mappings in [addr, addr +
+    /// len) back to their files, and with `sync`, have the files write it
+    /// out. It's ENOMEM if some of the range isn't mapped.
+    pub fn msync(&mut self, addr: usize, len: usize, sync: bool) -> Result<(), SysError> {
+        let Some(root) = (unsafe { self.root.as_mut() }) else {
+            return Err(Errno(ENOMEM));
+        };
+        let end = addr.saturating_add(align_val(len, PAGE_ORDER));
+        let mut at = addr;
+        for r in self
+            .regions
+            .iter()
+            .filter(|r| r.end > addr && r.start < end)
+        {
+            if r.start > at {
+                return Err(Errno(ENOMEM));
+            }
+            at = r.end;
+        }
+        if at < end {
+            return Err(Errno(ENOMEM));
+        }
+        let mut ret = Ok(());
+        for r in self
+            .regions
+            .iter()
+            .filter(|r| r.end > addr && r.start < end)
+        {
+            ret = ret.and(r.write_back(root, r.start.max(addr), r.end.min(end)));
+            if let Some(f) = r.file.as_ref().filter(|f| sync && f.shared) {
+                ret = ret.and(f.file.sync());
+            }
+        }
+        cpu::sfence_vma();
+        ret
+    }
+
+    /// The files that are mapped into the address space.
+    pub fn mapped_files(&self) -> impl Iterator<Item = &Rc<dyn File>> {
+        self.regions
+            .iter()
+            .filter_map(|r| Some(&r.file.as_ref()?.file))
+    }
+
+    /// Try to resolve a page fault at `vaddr`. If it is in one of our
+    /// anonymous mappings and the access is allowed (`access` is READ, WRITE or
+    /// EXECUTE), a zeroed page is mapped there and we return true; the
+    /// faulting instruction can then simply be retried. In a file mapping,
+    /// the page is read from the file, and in a shared one, the first write
+    /// to a page makes it writable. With no memory left for the page, or
+    /// the tables to map it with, it's false too.
+    pub fn handle_page_fault(&mut self, vaddr: usize, access: EntryBits) -> bool {
+        let Some(root) = (unsafe { self.root.as_mut() }) else {
+            return false;
+        };
+        let Some(r) = self
+            .regions
+            .iter()
+            .find(|r| r.start <= vaddr && vaddr < r.end)
+        else {
+            return false;
+        };
+        if !r.bits.contains(access) || r.shm.is_some() {
+            return false;
+        }
+        let vaddr = vaddr & !(PAGE_SIZE - 1);
+        let shared = r.file.as_ref().is_some_and(|f| f.shared);
+        if let Some(entry) = page::walk(root, vaddr) {
+            if shared && access == EntryBits::WRITE {
+                entry.set_entry(entry.entry | EntryBits::WRITE.bits());
+                cpu::sfence_vma();
+                return true;
+            }
+            // Already populated, so this is a genuine permission
+            // problem.
+            return false;
+        }
+        let page = page::zalloc(1);
+        if page.is_null() {
+            return false;
+        }
+        if !r.read_in(vaddr, page) {
+            page::dealloc(page);
+            return false;
+        }
+        let bits = if shared && access != EntryBits::WRITE {
+            r.bits & !EntryBits::WRITE
+        } else {
+            r.bits
+        };
+        if !page::map(root, vaddr, page as usize, bits, 0) {
+            page::dealloc(page);
+            return false;
+        }
+        cpu::sfence_vma();
+        true
+    }
+}
+
+// Unmap and free the pages of [start, end) in the address space.
+fn free_pages(root: &mut Table, start: usize, end: usize) {
+    for vaddr in (start..end).step_by(PAGE_SIZE) {
+        if let Some(paddr) = page::unmap_page(root, vaddr) {
+            page::dealloc(paddr as *mut u8);
+        }
+    }
+}
+
+impl Process {
+    /// Give back everything the process owns except for its trap frame and
+    /// its entry in the process list: the address space, the stack and the
+    /// open files. This can be called more than once.
+    fn release(&mut self) {
+        if let Some(root) = unsafe { self.root.as_mut() } {
+            // The pages of the heap and of anonymous mappings are ours,
+            // everything else that is mapped is either the stack, shared
+            // memory or belongs to the kernel.
+            free_pages(root, HEAP_START, align_val(self.brk, PAGE_ORDER));
+            for r in self.regions.drain(..) {
+                r.unmap(root, r.start, r.end);
+            }
+            page::unmap(root);
+            page::dealloc(self.root as *mut u8);
+            self.root = null_mut();
+        }
+        if !self.stack.is_null() {
+            page::dealloc(self.stack);
+            self.stack = null_mut();
+        }
+        self.files = FdTable::new();
+    }
+}
+
+impl Drop for Process {
+    /// Since we're storing ownership of a Process in the linked list,
+    /// we can cause it to deallocate automatically when it is removed.
+    fn drop(&mut self) {
+        self.release();
+        page::dealloc(self.frame as *mut u8);
+    }
+}
+
+/// Get the process list. This may only be called from the kernel with
+/// interrupts disabled, and after init().
+pub fn list() -> &'static mut VecDeque<Process> {
+    unsafe {
+        (*addr_of_mut!(PROCESS_LIST))
+            .as_mut()
+            .expect("process list is not initialized")
+    }
+}
+
+/// Find a process by its process id.
+pub fn get_by_pid(pid: usize) -> Option<&'static mut Process> {
+    list().iter_mut().find(|p| p.pid == pid)
+}
+
+/// Change the state of the process with the given pid, if it exists.
+pub fn set_state(pid: usize, state: ProcessState) {
+    if let Some(p) = get_by_pid(pid) {
+        p.set_state(state);
+    }
+}
+
+/// Timer callback that wakes up the sleeping process `pid`.
+pub fn wake_sleeper(pid: usize) {
+    if let Some(p) = get_by_pid(pid) {
+        if p.state == ProcessState::Sleeping {
+            p.state = ProcessState::Running;
+        }
+    }
+}
+
+/// Send `signal` to the user process `pid`, which ends it when it next
+/// traps into the kernel (see trap.rs). If it's waiting or sleeping, it's
+/// woken up for that. Returns false if there's no such process.
+pub fn signal(pid: usize, signal: usize) -> bool {
+    let Some(p) = get_by_pid(pid).filter(|p| p.is_user() && p.state != ProcessState::Zombie) else {
+        return false;
+    };
+    p.signal = signal;
+    if p.sleep_until != 0 {
+        timer::cancel(p.sleep_timer);
+        p.sleep_until = 0;
+    }
+    p.state = ProcessState::Running;
+    true
+}
+
+/// The signal that was sent to `pid`, if there is one. It's taken.
+pub fn take_signal(pid: usize) -> Option<usize> {
+    let p = get_by_pid(pid)?;
+    match core::mem::take(&mut p.signal) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// End the process `pid` with the given wait status: (code & 0xff) << 8
+/// for a normal exit, or the number of the signal that killed it, with 0x80
+/// set if it dumped core. Its resources are freed right away, and it stays
+/// around as a zombie until its parent collects the status. The caller must not return to it, the
+/// next thing to do is to schedule something else.
+pub fn exit(pid: usize, status: usize) {
+    let Some(p) = get_by_pid(pid) else {
+        return;
+    };
+    p.release();
+    locks::exited(pid);
+    p.state = ProcessState::Zombie;
+    p.exit_status = status;
+    if p.sleep_until != 0 {
+        timer::cancel(p.sleep_timer);
+    }
+    // Orphans are adopted by the kernel, which never waits for anyone, so
+    // processes without a parent are dropped as soon as they're zombies.
+    let pl = list();
+    for child in pl.iter_mut().filter(|c| c.ppid == pid) {
+        child.ppid = 0;
+    }
+    pl.retain(|p| p.ppid != 0 || p.state != ProcessState::Zombie);
+    unsafe {
+        (*addr_of_mut!(CHILD_EXIT)).wake_all();
+    }
+}
+
+/// Look for an exited child of `ppid`, any child if `pid` is None. An
+/// exited child is removed from the process list for good.
+pub fn reap(ppid: usize, pid: Option<usize>) -> WaitResult {
+    let pl = list();
+    let mut children = pl
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| p.ppid == ppid && pid.is_none_or(|pid| p.pid == pid))
+        .peekable();
+    if children.peek().is_none() {
+        return WaitResult::NoChild;
+    }
+    let Some(idx) = children
+        .find(|(_, p)| p.state == ProcessState::Zombie)
+        .map(|(i, _)| i)
+    else {
+        return WaitResult::Running;
+    };
+    let child = pl.remove(idx).unwrap();
+    WaitResult::Exited {
+        pid: child.pid,
+        status: child.exit_status,
+    }
+}
+
+/// Block `pid` until one of the processes in the system exits.
+pub fn wait_for_child(pid: usize) {
+    unsafe {
+        (*addr_of_mut!(CHILD_EXIT)).wait(pid);
+    }
+}
+
+/// Add a kernel process to the process list and return its pid.
+pub fn add_kernel_process(func: fn()) -> usize {
+    let p = Process::new_kernel(func, 0);
+    let pid = p.pid;
+    list().push_back(p);
+    pid
+}
+
+/// Add a user process to the process list and return its pid. Only init
+/// is one, which the test kernel doesn't start.
+#[cfg_attr(test, allow(dead_code))]
+pub fn add_user_process(func: fn()) -> usize {
+    let p = Process::new_user(func, 0);
+    let pid = p.pid;
+    list().push_back(p);
+    pid
+}
+
+/// Set up the process list and the idle context. The idle context lives in
+/// the hart's kernel trap frame and is what the scheduler falls back to when
+/// no process can run.
+pub fn init() {
+    unsafe {
+        PROCESS_LIST = Some(VecDeque::with_capacity(15));
+        let idle_stack = page::zalloc(1);
+        let frame = &mut *addr_of_mut!(KERNEL_TRAP_FRAME[board::BOOT_HART]);
+        frame.regs[gp(Registers::Sp)] = idle_stack as usize + PAGE_SIZE;
+        frame.regs[gp(Registers::Gp)] = global_pointer();
+        frame.pc = idle as fn() as usize;
+        frame.mode = CpuMode::Machine as usize;
+        frame.pid = 0;
+    }
+}
+
+// Kernel code may address globals relative to gp, so everything that runs
+// in the kernel's address space needs the value boot.S loaded.
+fn global_pointer() -> usize {
+    let rval;
+    unsafe {
+        asm!("mv {}, gp", out(reg) rval);
+    }
+    rval
+}
+
+/// The idle context. There's nothing to do, so wait for an interrupt.
+fn idle() {
+    loop {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
+
+/// Get the idle context's trap frame of the given hart.
+pub fn idle_frame(hart: usize) -> *mut TrapFrame {
+    unsafe { addr_of_mut!(KERNEL_TRAP_FRAME[hart]) }
+}
@@ -0,0 +1,292 @@
+// procfs, mounted at /proc: what the kernel knows about itself and its
+// processes, as text files, in the formats Linux has for them, so that
+// the tools that read those can read these:
+//
+//   /proc/crash           the kernel's crash dump from the boot before
+//   /proc/interrupts      how many of each interrupt there have been
+//   /proc/kmsg            the kernel log kept in memory (see klog.rs)
+//   /proc/meminfo         how much memory there is and how much is free
+//   /proc/mounts          the mount table
+//   /proc/uptime          how long we've been up
+//   /proc/<pid>/maps      what's mapped in the address space of a process
+//   /proc/<pid>/status    the state of a process
+//
+// Nothing's kept: a file's text is made when it's opened, from the stats
+// the rest of the kernel keeps, so reading it from the start again after
+// opening it again gives what's true then. The directories are listed the
+// same way, so a process that's gone is gone from /proc right away.
+
+use crate::{
+    board, crashdump,
+    file::{File, O_ACCMODE, O_RDONLY, S_IFDIR, S_IFREG},
+    klog, kmem, page, plic,
+    process::{self, Process, ProcessState},
+    syscall::{Stat, SysError, EACCES, EBADF, ENOENT},
+    timer, trap,
+    vfs::{self, dirent_type, Entry, Inode, Listing},
+};
+use alloc::{format, rc::Rc, string::String, vec::Vec};
+use core::{fmt::Write, ptr::addr_of_mut};
+
+use SysError::Errno;
+
+static mut DEV: u64 = 0;
+
+fn dev() -> u64 {
+    unsafe { *addr_of_mut!(DEV) }
+}
+
+/// What makes the text of a file in /proc.
+type Make = fn() -> String;
+/// What makes the text of a file in /proc/<pid>, for the process.
+type MakeFor = fn(&Process) -> String;
+
+/// The files in /proc, and what makes their text.
+const FILES: &[(&[u8], Make)] = &[
+    (b"crash", crashdump::text),
+    (b"interrupts", interrupts),
+    (b"kmsg", klog::kmsg),
+    (b"meminfo", meminfo),
+    (b"mounts", mounts),
+    (b"uptime", uptime),
+];
+
+/// The files in /proc/<pid>, and what makes their text.
+const PID_FILES: &[(&[u8], MakeFor)] = &[(b"maps", maps), (b"status", status)];
+
+// ///////////////////////////////////
+// / FILES
+// ///////////////////////////////////
+
+fn interrupts() -> String {
+    let mut s = format!("{:>5}{:>11}\n", "", format!("CPU{}", board::BOOT_HART));
+    let _ = writeln!(s, "{:>4}:{:>11}  INTC  timer", 7, trap::timer_interrupts());
+    for (id, count) in plic::counts() {
+        let _ = writeln!(s, "{:>4}:{:>11}  PLIC  {}", id, count, plic::owner(id));
+    }
+    s
+}
+
+fn meminfo() -> String {
+    let kb = |pages: usize| pages * page::PAGE_SIZE / 1024;
+    let (pages, taken) = page::stats();
+    let (heap, heap_taken) = kmem::stats();
+    format!(
+        "MemTotal:       {:>8} kB\nMemFree:        {:>8} kB\n\
+         KernelHeap:     {:>8} kB\nKernelHeapFree: {:>8} kB\n",
+        kb(pages),
+        kb(pages - taken),
+        heap / 1024,
+        (heap - heap_taken) / 1024,
+    )
+}
+
+fn mounts() -> String {
+    let mut s = String::from("rootfs / tmpfs rw 0 0\n");
+    for m in vfs::mount_table() {
+        let path = core::str::from_utf8(&m.path).unwrap_or("?");
+        let rw = if m.read_only { "ro" } else { "rw" };
+        let _ = writeln!(s, "{} {} {} {} 0 0", m.source, path, m.fstype, rw);
+    }
+    s
+}
+
+fn uptime() -> String {
+    let cs = timer::monotonic_ns() / 10_000_000;
+    // Idle time isn't kept track of.
+    format!("{}.{:02} 0.00\n", cs / 100, cs % 100)
+}
+
+fn maps(p: &Process) -> String {
+    let mut s = String::new();
+    for m in p.maps() {
+        let bit = |b, c| if m.bits.contains(b) { c } else { '-' };
+        let _ = writeln!(
+            s,
+            "{:08x}-{:08x} {}{}{}{} 00000000 00:00 0 {}",
+            m.start,
+            m.end,
+            bit(page::EntryBits::READ, 'r'),
+            bit(page::EntryBits::WRITE, 'w'),
+            bit(page::EntryBits::EXECUTE, 'x'),
+            if m.name == "[shm]" { 's' } else { 'p' },
+            m.name,
+        );
+    }
+    s
+}
+
+fn status(p: &Process) -> String {
+    let state = match p.state() {
+        ProcessState::Running => "R (running)",
+        ProcessState::Sleeping | ProcessState::Waiting => "S (sleeping)",
+        ProcessState::Zombie => "Z (zombie)",
+    };
+    let mut s = format!(
+        "State:\t{}\nPid:\t{}\nPPid:\t{}\nUid:\t{u}\t{u}\t{u}\t{u}\nGid:\t{g}\t{g}\t{g}\t{g}\n",
+        state,
+        p.pid(),
+        p.ppid(),
+        u = p.cred.uid,
+        g = p.cred.gid,
+    );
+    if p.is_user() {
+        let brk = p.brk().saturating_sub(process::HEAP_START);
+        let _ = writeln!(s, "VmBrk:\t{:>8} kB", brk.div_ceil(1024));
+    }
+    let _ = writeln!(s, "Kthread:\t{}", u8::from(!p.is_user()));
+    s
+}
+
+// ///////////////////////////////////
+// / INODES
+// ///////////////////////////////////
+
+/// The inode number of /proc/<pid>; the files in it come right after.
+fn pid_ino(pid: usize) -> u64 {
+    (pid as u64 + 1) << 8
+}
+
+/// /proc, or /proc/<pid>.
+struct ProcDir(Option<usize>);
+
+impl Inode for ProcDir {
+    fn lookup(&self, name: &[u8]) -> Result<Rc<dyn Inode>, SysError> {
+        if let Some(pid) = self.0 {
+            let i = PID_FILES.iter().position(|(n, _)| *n == name);
+            let i = i.ok_or(Errno(ENOENT))?;
+            return Ok(Rc::new(ProcFile { pid: Some(pid), i }));
+        }
+        if let Some(i) = FILES.iter().position(|(n, _)| *n == name) {
+            return Ok(Rc::new(ProcFile { pid: None, i }));
+        }
+        let pid = core::str::from_utf8(name).ok().and_then(|n| n.parse().ok());
+        match pid.filter(|&pid| process::get_by_pid(pid).is_some()) {
+            Some(pid) => Ok(Rc::new(ProcDir(Some(pid)))),
+            None => Err(Errno(ENOENT)),
+        }
+    }
+
+    fn open(&self, flags: usize) -> Result<Rc<dyn File>, SysError> {
+        let entry = |name: &[u8], ino, mode| Entry {
+            name: name.to_vec(),
+            ino,
+            kind: dirent_type(mode),
+        };
+        let entries = match self.0 {
+            Some(pid) => {
+                process::get_by_pid(pid).ok_or(Errno(ENOENT))?;
+                let files = PID_FILES.iter().enumerate();
+                files
+                    .map(|(i, (name, _))| entry(name, pid_ino(pid) + 1 + i as u64, S_IFREG))
+                    .collect()
+            }
+            None => {
+                let files = FILES.iter().enumerate();
+                let mut entries: Vec<Entry> = files
+                    .map(|(i, (name, _))| entry(name, 2 + i as u64, S_IFREG))
+                    .collect();
+                let mut pids: Vec<usize> = process::list().iter().map(|p| p.pid()).collect();
+                pids.sort();
+                for pid in pids {
+                    entries.push(entry(format!("{}", pid).as_bytes(), pid_ino(pid), S_IFDIR));
+                }
+                entries
+            }
+        };
+        Listing::open(self.metadata(), entries, flags)
+    }
+
+    fn metadata(&self) -> Stat {
+        Stat {
+            st_dev: dev(),
+            st_ino: self.0.map_or(1, pid_ino),
+            st_mode: S_IFDIR | 0o555,
+            st_nlink: 2,
+            ..Default::default()
+        }
+    }
+}
+
+/// A file of /proc, the `i`th of /proc/<pid>, or of /proc if there's no
+/// `pid`.
+struct ProcFile {
+    pid: Option<usize>,
+    i: usize,
+}
+
+impl Inode for ProcFile {
+    fn open(&self, flags: usize) -> Result<Rc<dyn File>, SysError> {
+        if flags & O_ACCMODE != O_RDONLY {
+            return Err(Errno(EACCES));
+        }
+        let text = match self.pid {
+            Some(pid) => {
+                let p = process::get_by_pid(pid).ok_or(Errno(ENOENT))?;
+                (PID_FILES[self.i].1)(p)
+            }
+            None => (FILES[self.i].1)(),
+        };
+        Ok(Rc::new(Text {
+            stat: self.metadata(),
+            text: text.into_bytes(),
+        }))
+    }
+
+    fn metadata(&self) -> Stat {
+        let ino = match self.pid {
+            Some(pid) => pid_ino(pid) + 1 + self.i as u64,
+            None => 2 + self.i as u64,
+        };
+        // The size is 0, as on Linux: there's no knowing it without making
+        // the text.
+        Stat {
+            st_dev: dev(),
+            st_ino: ino,
+            st_mode: S_IFREG | 0o444,
+            st_nlink: 1,
+            ..Default::default()
+        }
+    }
+}
+
+/// An opened file of /proc, with the text that was made for it.
+struct Text {
+    stat: Stat,
+    text: Vec<u8>,
+}
+
+impl File for Text {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let text = self.text.get(offset..).unwrap_or(&[]);
+        let n = text.len().min(buf.len());
+        buf[..n].copy_from_slice(&text[..n]);
+        Ok(n)
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, SysError> {
+        // It can't be opened for writing.
+        Err(Errno(EBADF))
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.text.len())
+    }
+
+    fn stat(&self) -> Stat {
+        self.stat
+    }
+}
+
+/// The root of procfs. There's only the one, wherever it's mounted.
+pub fn new() -> Rc<dyn Inode> {
+    Rc::new(ProcDir(None))
+}
+
+/// Mount procfs at /proc.
+pub fn init() {
+    unsafe {
+        DEV = vfs::new_dev();
+    }
+    let _ = vfs::mount(b"/proc", new(), "proc", "proc", false);
+}
@@ -0,0 +1,134 @@
+// The ramdisk: a block device that's only memory, /dev/ram0. It's there on
+// every board, so there's always a disk to make and try out a filesystem
+// on, even one without a disk driver of its own yet. Its size is the
+// ramdisk_size= of the kernel command line, in KiB as on Linux; 0 means
+// there's none. What's on it is gone when the machine is.
+//
+// Its pages come from the page allocator one at a time, not all at once:
+// a page is only allocated when something is written to it, and a page
+// nobody wrote to reads as zeros. So a big ramdisk costs nothing until
+// it's used, and it doesn't need a lot of memory in one piece.
+
+use crate::{
+    block::{self, BlockDevice, SECTOR_SIZE},
+    fdt,
+    page::{self, PAGE_SIZE},
+    syscall::{SysError, EIO, ENOMEM},
+};
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::{ptr::null_mut, slice};
+
+use SysError::Errno;
+
+/// The device number major of ramdisks on Linux.
+const RAMDISK_MAJOR: u64 = 1;
+/// Its size in KiB, unless the kernel command line says otherwise.
+const DEFAULT_SIZE_KIB: u64 = 4096;
+
+struct Ramdisk {
+    // Every page of the disk, null for those that were never written.
+    pages: Vec<*mut u8>,
+    sectors: u64,
+}
+
+/// Make the ramdisk, as big as the kernel command line says.
+pub fn init() {
+    let size_kib = fdt::get()
+        .and_then(|f| f.find("/chosen"))
+        .and_then(|c| c.str_property("bootargs"))
+        .and_then(|args| {
+            args.split_ascii_whitespace()
+                .find_map(|arg| arg.strip_prefix("ramdisk_size="))
+        })
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_SIZE_KIB);
+    let sectors = size_kib * 1024 / SECTOR_SIZE as u64;
+    if sectors == 0 {
+        return;
+    }
+    let pages = (sectors as usize * SECTOR_SIZE).div_ceil(PAGE_SIZE);
+    block::register(Box::new(Ramdisk {
+        pages: vec![null_mut(); pages],
+        sectors,
+    }));
+}
+
+impl Ramdisk {
+    /// Check that the sectors from `sector` on that fit in `len` bytes are
+    /// on the disk, and return where they start, in bytes.
+    fn position(&self, sector: u64, len: usize) -> Result<usize, SysError> {
+        let count = (len / SECTOR_SIZE) as u64;
+        if sector
+            .checked_add(count)
+            .is_none_or(|end| end > self.sectors)
+        {
+            return Err(Errno(EIO));
+        }
+        Ok(sector as usize * SECTOR_SIZE)
+    }
+
+    /// Page `n`, allocating it if it hasn't been.
+    fn page_mut(&mut self, n: usize) -> Result<&mut [u8], SysError> {
+        if self.pages[n].is_null() {
+            let page = page::zalloc(1);
+            if page.is_null() {
+                return Err(Errno(ENOMEM));
+            }
+            self.pages[n] = page;
+        }
+        Ok(unsafe { slice::from_raw_parts_mut(self.pages[n], PAGE_SIZE) })
+    }
+}
+
+impl BlockDevice for Ramdisk {
+    fn name(&self) -> &str {
+        "ram0"
+    }
+
+    fn rdev(&self) -> u64 {
+        RAMDISK_MAJOR << 8
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), SysError> {
+        let mut pos = self.position(sector, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            // A page at a time, or what's left of one.
+            let start = pos % PAGE_SIZE;
+            let len = (buf.len() - done).min(PAGE_SIZE - start);
+            let page = self.pages[pos / PAGE_SIZE];
+            let chunk = &mut buf[done..done + len];
+            if page.is_null() {
+                chunk.fill(0);
+            } else {
+                let data = unsafe { slice::from_raw_parts(page, PAGE_SIZE) };
+                chunk.copy_from_slice(&data[start..start + len]);
+            }
+            pos += len;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), SysError> {
+        let mut pos = self.position(sector, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let start = pos % PAGE_SIZE;
+            let len = (buf.len() - done).min(PAGE_SIZE - start);
+            let page = self.page_mut(pos / PAGE_SIZE)?;
+            page[start..start + len].copy_from_slice(&buf[done..done + len]);
+            pos += len;
+            done += len;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+// The hardware random number generator: a virtio-rng device (-device
+// virtio-rng-device in QEMU, which reads the host's /dev/urandom). The
+// device has one queue, and fills whatever buffers we put on it with
+// random bytes. We only ask it once, at boot, for enough to seed the
+// entropy pool; the pool stretches that out from then on.
+
+use crate::{
+    dma, entropy,
+    virtio::{self, Device},
+};
+use alloc::vec::Vec;
+use core::ptr::addr_of_mut;
+
+const REQUEST_QUEUE: u32 = 0;
+/// How many bytes we ask for, twice what the pool needs to be seeded.
+const SEED_BYTES: usize = entropy::SEED_BITS / 4;
+
+// The devices stay set up, so their interrupts can be acknowledged.
+static mut DEVICES: Vec<Device> = Vec::new();
+
+/// Set up a virtio-rng device and seed the entropy pool from it. Returns
+/// false if it didn't work out.
+pub fn setup(mut dev: Device) -> bool {
+    if dev.begin_init(|_| 0).is_none() {
+        return false;
+    }
+    let Some(mut queue) = dev.setup_queue(REQUEST_QUEUE, 1) else {
+        dev.fail();
+        return false;
+    };
+    dev.driver_ok();
+
+    let mut buf = [0u8; SEED_BYTES];
+    let head = queue.alloc_desc().unwrap();
+    *queue.desc(head) = virtio::Descriptor {
+        addr: dma::bus_addr(buf.as_mut_ptr()) as u64,
+        len: buf.len() as u32,
+        flags: virtio::DESC_F_WRITE,
+        next: 0,
+    };
+    queue.submit(head);
+    let len = loop {
+        if let Some((_, len)) = queue.pop_used() {
+            break (len as usize).min(buf.len());
+        }
+    };
+    queue.free_desc(head);
+    // The host's generator is good, but it is someone else's. Only credit
+    // half of what we got.
+    entropy::add(&buf[..len], len * 8 / 2);
+    println!("virtio-rng: {} bytes of entropy", len);
+    unsafe {
+        (*addr_of_mut!(DEVICES)).push(dev);
+    }
+    true
+}
+
+/// The device in `slot` interrupted us. The one request is polled for, so
+/// there's nothing to do but acknowledge it.
+pub fn handle_interrupt(slot: usize) {
+    let devices = unsafe { &*addr_of_mut!(DEVICES) };
+    if let Some(dev) = devices.iter().find(|d| d.slot == slot) {
+        dev.ack_interrupt();
+    }
+}
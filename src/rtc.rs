@@ -0,0 +1,135 @@
+// Goldfish real-time clock. QEMU's virt machine has one at 0x10_1000. It
+// counts nanoseconds since the Unix epoch in a 64-bit register that has to
+// be read low half first, which latches the high half. Writing it works the
+// other way around: the high half is held until the low half is written.
+
+use crate::{
+    board,
+    device::{self, Device},
+};
+use core::fmt;
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+static mut BASE: Option<usize> = board::RTC_BASE;
+
+/// The driver for the RTC. Until it's probed, the RTC is assumed to be
+/// where the board has one, if it does. Without one, it's always 1970.
+pub struct Rtc;
+
+impl device::Driver for Rtc {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["google,goldfish-rtc"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let Some(base) = dev.base() else {
+            return false;
+        };
+        unsafe {
+            BASE = Some(base);
+        }
+        true
+    }
+}
+
+fn reg(base: usize, offset: usize) -> *mut u32 {
+    (base + offset) as *mut u32
+}
+
+/// Read the current wall-clock time in nanoseconds since the Unix epoch.
+pub fn read_ns() -> u64 {
+    let Some(base) = (unsafe { BASE }) else {
+        return 0;
+    };
+    unsafe {
+        let low = reg(base, TIME_LOW).read_volatile() as u64;
+        let high = reg(base, TIME_HIGH).read_volatile() as u64;
+        high << 32 | low
+    }
+}
+
+/// Set the clock to `ns` nanoseconds since the Unix epoch.
+pub fn write_ns(ns: u64) {
+    let Some(base) = (unsafe { BASE }) else {
+        return;
+    };
+    unsafe {
+        reg(base, TIME_HIGH).write_volatile((ns >> 32) as u32);
+        reg(base, TIME_LOW).write_volatile(ns as u32);
+    }
+}
+
+// ///////////////////////////////////
+// / DATES
+// ///////////////////////////////////
+
+/// A point in time as a UTC calendar date and time of day.
+#[derive(Clone, Copy)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The date and time `secs` seconds after the Unix epoch.
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        // Count in eras of 400 years (146097 days) from 0000-03-01, so that
+        // leap days come at the end of a year. See Howard Hinnant's
+        // civil_from_days.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        DateTime {
+            year,
+            month: month as u8,
+            day: day as u8,
+            hour: (rem / 3600) as u8,
+            minute: (rem / 60 % 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// How many seconds after the Unix epoch this is, the other way
+    /// around from from_unix(). Dates before it are the epoch.
+    pub fn to_unix(self) -> u64 {
+        let y = self.year - i64::from(self.month <= 2);
+        let era = y.div_euclid(400);
+        let yoe = y.rem_euclid(400);
+        let mp = (self.month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = 365 * yoe + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        let secs = self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        (days * 86400 + secs).max(0) as u64
+    }
+
+    /// The current date and time.
+    pub fn now() -> Self {
+        Self::from_unix(crate::timer::realtime_ns() / crate::timer::NANOS_PER_SEC)
+    }
+}
+
+/// ISO 8601, like 2024-01-31 23:59:59.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
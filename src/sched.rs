@@ -0,0 +1,57 @@
+use crate::{
+    board,
+    cpu::{self, TrapFrame},
+    ktrace::{self, Kind},
+    process::{self, ProcessState},
+    timer,
+};
+
+/// How long a process gets to run before the timer interrupts it,
+/// in mtime ticks (10 ms).
+pub const CONTEXT_SWITCH_TIME: u64 = cpu::FREQ / 100;
+
+// When the time slice of the running process ends, in mtime ticks.
+static mut SLICE_END: u64 = 0;
+
+/// Pick the next process to run in round-robin order and return its trap
+/// frame. If nothing can run, this returns the idle context. The chosen
+/// process gets a fresh time slice.
+pub fn schedule() -> *mut TrapFrame {
+    unsafe {
+        SLICE_END = cpu::get_mtime() + CONTEXT_SWITCH_TIME;
+    }
+    arm_timer();
+    let pl = process::list();
+    for _ in 0..pl.len() {
+        pl.rotate_left(1);
+        if let Some(prc) = pl.front() {
+            if prc.state() == ProcessState::Running {
+                ktrace::record(Kind::Switch, prc.pid(), 0);
+                return prc.frame();
+            }
+        }
+    }
+    ktrace::record(Kind::Switch, 0, 0);
+    process::idle_frame(0)
+}
+
+/// Has the running process used up its time slice?
+pub fn slice_expired(now: u64) -> bool {
+    now >= unsafe { SLICE_END }
+}
+
+/// Program the timer compare register for whatever comes first: the end
+/// of the time slice or the next software timer.
+pub fn arm_timer() {
+    let slice_end = unsafe { SLICE_END };
+    let next = match timer::next_deadline() {
+        Some(deadline) => deadline.min(slice_end),
+        None => slice_end,
+    };
+    cpu::set_mtimecmp(board::BOOT_HART, next);
+}
+
+/// Switch to the first process. This is the last thing kinit does.
+pub fn start() -> ! {
+    cpu::switch_to(schedule())
+}
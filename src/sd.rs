@@ -0,0 +1,342 @@
+// SD cards in SPI mode, as wired to the SPI controller on HiFive boards. A
+// card wakes up in its native mode and is switched to SPI by CMD0 with its
+// chip select held low. Every command is six bytes: the index, a 32-bit
+// argument and a CRC (which SPI mode only checks on CMD0 and CMD8), and the
+// card answers with a one-byte R1 status, some commands with a few more
+// bytes. Data comes in 512-byte blocks behind a start token. Cards up to
+// 2 GiB (SDSC) are addressed in bytes, bigger ones (SDHC and SDXC) in
+// blocks.
+
+use crate::{
+    block::{self, BlockDevice, SECTOR_SIZE},
+    spi::Spi,
+    syscall::{SysError, EINVAL, EIO},
+};
+use alloc::{boxed::Box, format, string::String};
+
+use SysError::Errno;
+
+/// The clock while the card is being identified, which may be no faster
+/// than 400 kHz.
+const INIT_HZ: u32 = 400_000;
+/// The clock afterwards, unless the device tree says less.
+const DEFAULT_HZ: u32 = 20_000_000;
+
+// Commands
+const GO_IDLE_STATE: u8 = 0;
+const SEND_IF_COND: u8 = 8;
+const SEND_CSD: u8 = 9;
+const SET_BLOCKLEN: u8 = 16;
+const READ_SINGLE_BLOCK: u8 = 17;
+const WRITE_BLOCK: u8 = 24;
+const APP_CMD: u8 = 55;
+const READ_OCR: u8 = 58;
+// Application commands, which follow an APP_CMD
+const SD_SEND_OP_COND: u8 = 41;
+
+// R1 bits
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+/// The start token in front of a data block.
+const START_BLOCK: u8 = 0xfe;
+/// A data response that says a written block was accepted.
+const DATA_ACCEPTED: u8 = 0x05;
+/// OCR: the card is addressed in blocks (SDHC or SDXC).
+const OCR_CCS: u32 = 1 << 30;
+/// SD_SEND_OP_COND: we do high capacity.
+const HCS: u32 = 1 << 30;
+/// SEND_IF_COND: 2.7-3.6 V and a check pattern to echo back.
+const IF_COND: u32 = 0x1aa;
+
+/// How many times we ask before giving up on a response, a token, or the
+/// card getting ready.
+const RETRIES: usize = 100_000;
+
+/// The device number major of MMC and SD cards on Linux.
+const MMC_BLOCK_MAJOR: u64 = 179;
+
+struct SdCard {
+    spi: Spi,
+    name: String,
+    index: usize,
+    // Whether the card is addressed in blocks instead of bytes.
+    high_capacity: bool,
+    sectors: u64,
+}
+
+// How many cards we found, for naming them.
+static mut COUNT: usize = 0;
+
+impl SdCard {
+    /// Clock a byte in, sending all ones.
+    fn recv(&self) -> u8 {
+        self.spi.transfer(0xff)
+    }
+
+    /// Send a command and return its R1. The card stays selected, so that
+    /// the rest of the response or the data can be read; end() deselects
+    /// it.
+    fn command(&self, cmd: u8, arg: u32) -> Result<u8, SysError> {
+        // Only these two have their CRC checked.
+        let crc = match cmd {
+            GO_IDLE_STATE => 0x95,
+            SEND_IF_COND => 0x87,
+            _ => 0x01,
+        };
+        self.spi.select();
+        self.recv();
+        self.spi.transfer(0x40 | cmd);
+        for b in arg.to_be_bytes() {
+            self.spi.transfer(b);
+        }
+        self.spi.transfer(crc);
+        // The R1 comes within eight bytes, before that the line stays high.
+        for _ in 0..8 {
+            let r1 = self.recv();
+            if r1 & 0x80 == 0 {
+                return Ok(r1);
+            }
+        }
+        self.end();
+        Err(Errno(EIO))
+    }
+
+    /// Deselect the card, with the extra byte of clocks it wants to finish.
+    fn end(&self) {
+        self.recv();
+        self.spi.deselect();
+    }
+
+    /// Send a command that has only an R1 for an answer.
+    fn simple(&self, cmd: u8, arg: u32) -> Result<u8, SysError> {
+        let r1 = self.command(cmd, arg)?;
+        self.end();
+        Ok(r1)
+    }
+
+    /// Read the 32 bits that follow the R1 of SEND_IF_COND and READ_OCR.
+    fn command_r7(&self, cmd: u8, arg: u32) -> Result<(u8, u32), SysError> {
+        let r1 = self.command(cmd, arg)?;
+        let mut val = 0;
+        if r1 & R1_ILLEGAL_COMMAND == 0 {
+            for _ in 0..4 {
+                val = val << 8 | self.recv() as u32;
+            }
+        }
+        self.end();
+        Ok((r1, val))
+    }
+
+    /// Wait for the start token and read a data block into `buf`.
+    fn read_data(&self, buf: &mut [u8]) -> Result<(), SysError> {
+        let mut token = 0xff;
+        for _ in 0..RETRIES {
+            token = self.recv();
+            if token != 0xff {
+                break;
+            }
+        }
+        if token != START_BLOCK {
+            return Err(Errno(EIO));
+        }
+        for b in buf.iter_mut() {
+            *b = self.recv();
+        }
+        // The CRC, which we don't check.
+        self.recv();
+        self.recv();
+        Ok(())
+    }
+
+    /// Switch the card to SPI mode and find out what it is.
+    fn init(&mut self, max_hz: u32) -> Result<(), SysError> {
+        self.spi.set_speed(INIT_HZ);
+        // At least 74 clocks with the card deselected to wake it up.
+        self.spi.without_select();
+        for _ in 0..10 {
+            self.recv();
+        }
+        self.spi.deselect();
+        if self.simple(GO_IDLE_STATE, 0)? != R1_IDLE {
+            return Err(Errno(EIO));
+        }
+        // Version 1 cards don't know SEND_IF_COND.
+        let (r1, echo) = self.command_r7(SEND_IF_COND, IF_COND)?;
+        let v2 = r1 & R1_ILLEGAL_COMMAND == 0;
+        if v2 && echo & 0xfff != IF_COND {
+            return Err(Errno(EIO));
+        }
+        // Tell the card to initialize until it's no longer idle.
+        let arg = if v2 { HCS } else { 0 };
+        let mut ready = false;
+        for _ in 0..RETRIES {
+            self.simple(APP_CMD, 0)?;
+            if self.simple(SD_SEND_OP_COND, arg)? == 0 {
+                ready = true;
+                break;
+            }
+        }
+        if !ready {
+            return Err(Errno(EIO));
+        }
+        if v2 {
+            let (_, ocr) = self.command_r7(READ_OCR, 0)?;
+            self.high_capacity = ocr & OCR_CCS != 0;
+        }
+        if !self.high_capacity {
+            // Byte addressed cards can have other block sizes.
+            self.simple(SET_BLOCKLEN, SECTOR_SIZE as u32)?;
+        }
+        self.spi.set_speed(max_hz);
+        self.sectors = self.read_capacity()?;
+        Ok(())
+    }
+
+    /// The size of the card in sectors, from its card-specific data.
+    fn read_capacity(&self) -> Result<u64, SysError> {
+        let mut csd = [0u8; 16];
+        if self.command(SEND_CSD, 0)? != 0 {
+            self.end();
+            return Err(Errno(EIO));
+        }
+        let ret = self.read_data(&mut csd);
+        self.end();
+        ret?;
+        // The bits of the CSD, counting from the end like the spec does.
+        let bits = |hi: usize, lo: usize| {
+            let mut val = 0u64;
+            for bit in (lo..=hi).rev() {
+                val = val << 1 | ((csd[15 - bit / 8] >> (bit % 8)) & 1) as u64;
+            }
+            val
+        };
+        match bits(127, 126) {
+            // CSD version 2: units of 512 KiB.
+            1 => Ok((bits(69, 48) + 1) * 1024),
+            // CSD version 1: blocks of 2^READ_BL_LEN bytes, (C_SIZE + 1)
+            // times 2^(C_SIZE_MULT + 2) of them.
+            0 => {
+                let block_len = 1 << bits(83, 80);
+                let mult = 1 << (bits(49, 47) + 2);
+                Ok((bits(73, 62) + 1) * mult * block_len / SECTOR_SIZE as u64)
+            }
+            _ => Err(Errno(EIO)),
+        }
+    }
+
+    /// What to send as the address of `sector`.
+    fn address(&self, sector: u64) -> u32 {
+        if self.high_capacity {
+            sector as u32
+        } else {
+            (sector * SECTOR_SIZE as u64) as u32
+        }
+    }
+
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), SysError> {
+        if self.command(READ_SINGLE_BLOCK, self.address(sector))? != 0 {
+            self.end();
+            return Err(Errno(EIO));
+        }
+        let ret = self.read_data(buf);
+        self.end();
+        ret
+    }
+
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), SysError> {
+        if self.command(WRITE_BLOCK, self.address(sector))? != 0 {
+            self.end();
+            return Err(Errno(EIO));
+        }
+        // A byte of gap, the token, the block and a CRC nobody checks.
+        self.recv();
+        self.spi.transfer(START_BLOCK);
+        for &b in buf {
+            self.spi.transfer(b);
+        }
+        self.recv();
+        self.recv();
+        let accepted = self.recv() & 0x1f == DATA_ACCEPTED;
+        // The card holds the line low while it's busy writing.
+        let mut idle = false;
+        for _ in 0..RETRIES {
+            if self.recv() == 0xff {
+                idle = true;
+                break;
+            }
+        }
+        self.end();
+        if accepted && idle {
+            Ok(())
+        } else {
+            Err(Errno(EIO))
+        }
+    }
+
+    /// Check that `len` bytes from `sector` are whole sectors on the card.
+    fn check(&self, sector: u64, len: usize) -> Result<(), SysError> {
+        let end = sector.checked_add((len / SECTOR_SIZE) as u64);
+        if !len.is_multiple_of(SECTOR_SIZE) || end.is_none_or(|end| end > self.sectors) {
+            return Err(Errno(EINVAL));
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for SdCard {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn rdev(&self) -> u64 {
+        MMC_BLOCK_MAJOR << 8 | (self.index as u64 * 8)
+    }
+
+    fn num_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), SysError> {
+        self.check(sector, buf.len())?;
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            self.read_sector(sector + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, buf: &[u8]) -> Result<(), SysError> {
+        self.check(sector, buf.len())?;
+        for (i, chunk) in buf.chunks(SECTOR_SIZE).enumerate() {
+            self.write_sector(sector + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Look for a card behind `spi` and register it as a block device if
+/// there is one. `max_hz` is how fast the slot can go.
+pub fn probe(spi: Spi, max_hz: Option<u32>) {
+    let index = unsafe { COUNT };
+    let mut card = SdCard {
+        spi,
+        name: format!("mmcblk{}", index),
+        index,
+        high_capacity: false,
+        sectors: 0,
+    };
+    if card
+        .init(max_hz.unwrap_or(DEFAULT_HZ).min(DEFAULT_HZ))
+        .is_err()
+    {
+        println!("{}: no card, or it didn't answer", card.name);
+        return;
+    }
+    unsafe {
+        COUNT += 1;
+    }
+    block::register(Box::new(card));
+}
@@ -0,0 +1,408 @@
+// Self-tests: checks the kernel runs on itself, from the shell, so that
+// whether the allocators, the page tables, traps and timers still work
+// after a change doesn't come down to reading what they print. Each test
+// is a function that says what went wrong, if anything. Subsystems
+// register theirs with register(), the way they do shell commands; the
+// core ones are registered here, and virtio's queue test with them since
+// it needs no device.
+//
+// selftest runs all of them, or the ones named, and says how many passed.
+// They run in the shell's process with interrupts off like any command,
+// so what they measure isn't changed under them, and they give back
+// whatever they take. A test that breaks something badly enough panics
+// instead of failing, which says as much.
+
+use crate::{
+    cpu::{self, TrapFrame},
+    kmem,
+    page::{self, EntryBits, Table, PAGE_SIZE},
+    rtc,
+    shell::{self, Builtin},
+    syscall::{SysError, EINTR, ENOENT, SYS_GETPID},
+    timer::{self, NANOS_PER_SEC},
+    virtio,
+};
+use alloc::{format, string::String, vec::Vec};
+use core::{arch::asm, ptr::addr_of_mut, ptr::null_mut, slice};
+
+use SysError::Errno;
+
+/// A self-test: what it's called, and the function that runs it.
+pub struct Test {
+    pub name: &'static str,
+    pub run: fn() -> Result<(), String>,
+}
+
+static mut TESTS: Vec<&Test> = Vec::new();
+
+fn tests() -> &'static mut Vec<&'static Test> {
+    unsafe { &mut *addr_of_mut!(TESTS) }
+}
+
+/// Add `test`, in place of any by that name.
+pub fn register(test: &'static Test) {
+    tests().retain(|t| t.name != test.name);
+    tests().push(test);
+}
+
+/// The tests, in the order they were registered.
+pub fn all() -> Vec<&'static Test> {
+    tests().clone()
+}
+
+/// Fail with what `what` says, unless `ok`.
+pub fn check(ok: bool, what: impl FnOnce() -> String) -> Result<(), String> {
+    if ok {
+        Ok(())
+    } else {
+        Err(what())
+    }
+}
+
+// ///////////////////////////////////
+// / THE TESTS
+// ///////////////////////////////////
+
+/// How many allocations the allocator tests make.
+const ROUNDS: usize = 64;
+
+/// Allocate runs of pages of different lengths, check that they're zeroed
+/// and don't overlap, and give them back.
+fn page_test() -> Result<(), String> {
+    let (_, before) = page::stats();
+    let mut taken = [(null_mut(), 0); ROUNDS];
+    let mut failed = None;
+    for (i, (p, pages)) in taken.iter_mut().enumerate() {
+        *pages = i % 4 + 1;
+        *p = page::zalloc(*pages);
+        if p.is_null() {
+            failed.get_or_insert(format!("out of pages after {} allocations", i));
+            break;
+        }
+        let mem = unsafe { slice::from_raw_parts_mut(*p, *pages * PAGE_SIZE) };
+        if mem.iter().any(|&b| b != 0) {
+            failed.get_or_insert(format!("allocation {} isn't zeroed", i));
+        }
+        mem.fill(i as u8);
+    }
+    for (i, &(p, pages)) in taken.iter().enumerate().filter(|(_, t)| !t.0.is_null()) {
+        let mem = unsafe { slice::from_raw_parts(p, pages * PAGE_SIZE) };
+        if mem.iter().any(|&b| b != i as u8) {
+            failed.get_or_insert(format!("allocation {} was written over", i));
+        }
+        page::dealloc(p);
+    }
+    if let Some(failed) = failed {
+        return Err(failed);
+    }
+    let (_, after) = page::stats();
+    check(after == before, || {
+        format!("{} pages taken before, {} after", before, after)
+    })
+}
+
+/// Allocate from the kernel heap in all sizes, free every other allocation
+/// and allocate again in the holes, then check nothing was written over
+/// and give it all back.
+fn kmem_test() -> Result<(), String> {
+    const SIZES: [usize; 8] = [1, 7, 8, 24, 100, 512, 4000, 9000];
+    let size = |i: usize| SIZES[i % SIZES.len()];
+    let (_, before) = kmem::stats();
+    let mut taken = [null_mut(); ROUNDS];
+    let fill =
+        |p: *mut u8, i: usize| unsafe { slice::from_raw_parts_mut(p, size(i)).fill(i as u8) };
+    for (i, p) in taken.iter_mut().enumerate() {
+        *p = kmem::kmalloc(size(i));
+        if !p.is_null() {
+            fill(*p, i);
+        }
+    }
+    for (i, p) in taken.iter_mut().enumerate().step_by(2) {
+        kmem::kfree(*p);
+        *p = kmem::kmalloc(size(i));
+        if !p.is_null() {
+            fill(*p, i);
+        }
+    }
+    let mut failed = None;
+    for (i, &p) in taken.iter().enumerate() {
+        if p.is_null() {
+            failed.get_or_insert(format!("allocation {} of {} bytes failed", i, size(i)));
+            continue;
+        }
+        let mem = unsafe { slice::from_raw_parts(p, size(i)) };
+        if mem.iter().any(|&b| b != i as u8) {
+            failed.get_or_insert(format!("allocation {} was written over", i));
+        }
+        kmem::kfree(p);
+    }
+    if let Some(failed) = failed {
+        return Err(failed);
+    }
+    let (_, after) = kmem::stats();
+    check(after == before, || {
+        format!("{} bytes taken before, {} after", before, after)
+    })
+}
+
+/// Map a page at addresses that need tables of their own, translate them
+/// back, unmap one, and free the tables.
+fn mmu_test() -> Result<(), String> {
+    let (_, before) = page::stats();
+    let root = page::zalloc(1);
+    let frame = page::zalloc(1);
+    if root.is_null() || frame.is_null() {
+        for p in [root, frame].into_iter().filter(|p| !p.is_null()) {
+            page::dealloc(p);
+        }
+        return Err("out of pages".into());
+    }
+    let table = unsafe { &mut *(root as *mut Table) };
+    let frame = frame as usize;
+    // Apart in every level of tables, and at the top of what Sv32 maps.
+    let vaddrs = [0x1000, 0x20_3000, 0x4000_5000, 0xffff_f000];
+    for &vaddr in &vaddrs {
+        if !page::map(table, vaddr, frame, EntryBits::READ_WRITE, 0) {
+            page::unmap(table);
+            page::dealloc(root);
+            page::dealloc(frame as *mut u8);
+            return Err("out of pages".into());
+        }
+    }
+    let result = (|| {
+        for &vaddr in &vaddrs {
+            let got = page::virt_to_phys(table, vaddr + 0x123);
+            check(got == Some(frame + 0x123), || {
+                format!("0x{:x} translates to {:x?}", vaddr + 0x123, got)
+            })?;
+            let bits = page::walk(table, vaddr).map(|e| e.bits());
+            check(
+                bits.is_some_and(|b| b.contains(EntryBits::READ_WRITE | EntryBits::VALID)),
+                || format!("0x{:x} isn't mapped readable and writable", vaddr),
+            )?;
+        }
+        check(page::virt_to_phys(table, 0x2000).is_none(), || {
+            "0x2000 is mapped, next to 0x1000".into()
+        })?;
+        let got = page::unmap_page(table, vaddrs[0]);
+        check(got == Some(frame), || {
+            format!("unmapping 0x{:x} gave {:x?}", vaddrs[0], got)
+        })?;
+        check(page::virt_to_phys(table, vaddrs[0]).is_none(), || {
+            format!("0x{:x} is still mapped", vaddrs[0])
+        })?;
+        check(page::virt_to_phys(table, vaddrs[1]) == Some(frame), || {
+            format!("unmapping 0x{:x} unmapped 0x{:x}", vaddrs[0], vaddrs[1])
+        })
+    })();
+    page::unmap(table);
+    page::dealloc(root);
+    page::dealloc(frame as *mut u8);
+    result?;
+    let (_, after) = page::stats();
+    check(after == before, || {
+        format!("{} pages taken before, {} after", before, after)
+    })
+}
+
+/// Map with every page taken, so there's none for the tables, and check
+/// that map() says so instead of writing through a null table.
+fn mmu_oom_test() -> Result<(), String> {
+    let (_, before) = page::stats();
+    let root = page::zalloc(1);
+    if root.is_null() {
+        return Err("out of pages".into());
+    }
+    let table = unsafe { &mut *(root as *mut Table) };
+    // Nothing else may run here while the pages are gone. They're chained
+    // through their first word, there's no memory to keep a list in.
+    let mapped = cpu::without_interrupts(|| {
+        let mut taken: *mut usize = null_mut();
+        loop {
+            let p = page::alloc(1) as *mut usize;
+            if p.is_null() {
+                break;
+            }
+            unsafe { p.write(taken as usize) };
+            taken = p;
+        }
+        let mapped = page::map(table, 0x4000_5000, root as usize, EntryBits::READ_WRITE, 0);
+        while !taken.is_null() {
+            let next = unsafe { taken.read() } as *mut usize;
+            page::dealloc(taken as *mut u8);
+            taken = next;
+        }
+        mapped
+    });
+    let result = check(!mapped, || "mapped with no pages left".into());
+    page::unmap(table);
+    page::dealloc(root);
+    result?;
+    let (_, after) = page::stats();
+    check(after == before, || {
+        format!("{} pages taken before, {} after", before, after)
+    })
+}
+
+/// Make a system call from here, which goes through the trap handler and
+/// back, and check that it answered and put every register back.
+fn trap_test() -> Result<(), String> {
+    let pid = match cpu::mscratch_read() {
+        0 => return Err("not in a process".into()),
+        frame => unsafe { (*(frame as *const TrapFrame)).pid },
+    };
+    let magic: [usize; 3] = [0x1234_5678, 0x0bad_cafe, 0x7777_0001];
+    let (mut a1, mut t0, mut s2) = (magic[0], magic[1], magic[2]);
+    let ret: usize;
+    unsafe {
+        asm!("ecall",
+            inlateout("a0") 0usize => ret,
+            inout("a1") a1,
+            inout("t0") t0,
+            inout("s2") s2,
+            in("a7") SYS_GETPID);
+    }
+    check(ret == pid, || format!("getpid said {}, not {}", ret, pid))?;
+    check([a1, t0, s2] == magic, || {
+        format!(
+            "a1, t0 and s2 came back as 0x{:x}, 0x{:x} and 0x{:x}",
+            a1, t0, s2
+        )
+    })
+}
+
+static mut FIRED: usize = 0;
+
+fn fire(data: usize) {
+    unsafe {
+        FIRED = data;
+    }
+}
+
+/// Check the conversions between ticks and time, that a software timer
+/// that's due runs and a cancelled one doesn't, and, if there's an RTC to
+/// go by, that a tenth of a second on the timer is one on it too.
+fn timer_test() -> Result<(), String> {
+    let tick = NANOS_PER_SEC / cpu::FREQ;
+    for ns in [
+        0,
+        1,
+        999,
+        NANOS_PER_SEC - 1,
+        NANOS_PER_SEC,
+        3 * NANOS_PER_SEC + 5,
+    ] {
+        let ticks = timer::duration_to_ticks(ns / NANOS_PER_SEC, ns % NANOS_PER_SEC);
+        let (secs, nanos) = timer::ticks_to_duration(ticks);
+        let back = secs * NANOS_PER_SEC + nanos;
+        // Rounded up, to the next tick.
+        check(back >= ns && back - ns < tick.max(1), || {
+            format!("{} ns is {} ticks, which is {} ns", ns, ticks, back)
+        })?;
+    }
+    unsafe {
+        FIRED = 0;
+    }
+    let cancelled = timer::add(u64::MAX, fire, 1);
+    timer::cancel(cancelled);
+    timer::add(cpu::get_mtime(), fire, 2);
+    timer::run_expired(cpu::get_mtime());
+    let fired = unsafe { *addr_of_mut!(FIRED) };
+    check(fired == 2, || match fired {
+        0 => "a timer that was due didn't run".into(),
+        _ => "a cancelled timer ran".into(),
+    })?;
+    check(timer::next_deadline() != Some(u64::MAX), || {
+        "a cancelled timer is still there".into()
+    })?;
+    if rtc::read_ns() == 0 {
+        return Ok(());
+    }
+    let wait = NANOS_PER_SEC / 10;
+    let (start, rtc_start) = (timer::monotonic_ns(), rtc::read_ns());
+    while timer::monotonic_ns() - start < wait {}
+    let (took, rtc_took) = (timer::monotonic_ns() - start, rtc::read_ns() - rtc_start);
+    // Within a hundredth of the time, and reading the RTC takes some.
+    check(rtc_took.abs_diff(took) <= wait / 100 + 1_000_000, || {
+        format!("{} ns on the timer are {} ns on the RTC", took, rtc_took)
+    })
+}
+
+// ///////////////////////////////////
+// / SHELL COMMAND
+// ///////////////////////////////////
+
+/// Run the tests named in `args`, or all of them; -l lists them instead.
+fn selftest_command(args: &[&str]) -> Result<(), SysError> {
+    if args == ["-l"] {
+        for t in tests().iter() {
+            println!("{}", t.name);
+        }
+        return Ok(());
+    }
+    let mut run = Vec::new();
+    for &name in args {
+        match tests().iter().find(|t| t.name == name) {
+            Some(&t) => run.push(t),
+            None => {
+                println!("selftest: there's no test {}", name);
+                return Err(Errno(ENOENT));
+            }
+        }
+    }
+    if run.is_empty() {
+        run = all();
+    }
+    let mut failed = 0;
+    for t in &run {
+        if shell::cancelled() {
+            return Err(Errno(EINTR));
+        }
+        match (t.run)() {
+            Ok(()) => println!("{:<12} ok", t.name),
+            Err(what) => {
+                println!("{:<12} FAILED: {}", t.name, what);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} passed, {} failed", run.len() - failed, failed);
+    Ok(())
+}
+
+/// Register the core tests, and the command that runs them.
+pub fn init() {
+    register(&Test {
+        name: "page",
+        run: page_test,
+    });
+    register(&Test {
+        name: "kmem",
+        run: kmem_test,
+    });
+    register(&Test {
+        name: "mmu",
+        run: mmu_test,
+    });
+    register(&Test {
+        name: "mmu-oom",
+        run: mmu_oom_test,
+    });
+    register(&Test {
+        name: "trap",
+        run: trap_test,
+    });
+    register(&Test {
+        name: "timer",
+        run: timer_test,
+    });
+    register(&Test {
+        name: "virtqueue",
+        run: virtio::loopback_test,
+    });
+    shell::register(&Builtin {
+        name: "selftest",
+        help: "selftest [-l] [NAME]... - run the kernel's self-tests, or list them",
+        run: selftest_command,
+    });
+}
@@ -0,0 +1,273 @@
+// Semihosting: calls into a debugger, or a simulator, that has stopped the
+// hart to see to them, so that a machine without a UART can still print,
+// read and write the host's files and say how it did when it stops. QEMU
+// answers them with -semihosting, OpenOCD with arm semihosting enable. A
+// call is an ebreak between two instructions that do nothing,
+//
+//   slli x0, x0, 0x1f
+//   ebreak
+//   srai x0, x0, 7
+//
+// which is how the other end tells it apart from a breakpoint, with the
+// operation in a0 and a pointer to its arguments in a1; the answer comes
+// back in a0.
+//
+// It's built in with the semihosting feature. Then console=semihosting on
+// the kernel command line puts the console here, for output only, the
+// semihosting log sink can be turned on (see klog.rs), the shell gets
+// hostget and hostput, which copy files from and to the host, and powering
+// off tells the host the exit code when there's no other way. If nothing
+// is on the other end, the ebreak is just a breakpoint trap: the first
+// call, at boot, finds that out (see trap.rs) and nothing is sent after
+// it.
+
+use crate::{
+    cpu::{gp, Registers, TrapFrame},
+    file::{O_CREAT, O_RDONLY, O_TRUNC, O_WRONLY},
+    shell::{self, Builtin},
+    syscall::{SysError, EINVAL, EIO},
+    vfs,
+};
+use alloc::vec::Vec;
+use core::{arch::asm, ptr::addr_of_mut};
+
+use SysError::Errno;
+
+// Operations
+const SYS_OPEN: usize = 0x01;
+const SYS_CLOSE: usize = 0x02;
+const SYS_WRITE0: usize = 0x04;
+const SYS_WRITE: usize = 0x05;
+const SYS_READ: usize = 0x06;
+const SYS_ERRNO: usize = 0x13;
+const SYS_EXIT: usize = 0x18;
+
+// fopen()'s modes, by number.
+const MODE_RB: usize = 1;
+const MODE_WB: usize = 5;
+
+// Why SYS_EXIT stops.
+const ADP_STOPPED_APPLICATION_EXIT: usize = 0x20026;
+const ADP_STOPPED_RUN_TIME_ERROR: usize = 0x20023;
+
+// The instructions around the ebreak.
+const SLLI_X0_X0_31: u32 = 0x01f0_1013;
+const EBREAK: u32 = 0x0010_0073;
+const SRAI_X0_X0_7: u32 = 0x4070_5013;
+
+/// How much is copied to or from the host in one call.
+const CHUNK: usize = 4096;
+
+/// Whether there's something on the other end. Until someone asks, there
+/// may be.
+static mut PRESENT: bool = true;
+
+/// Whether semihosting calls get answered.
+pub fn present() -> bool {
+    cfg!(feature = "semihosting") && unsafe { *addr_of_mut!(PRESENT) }
+}
+
+/// Make the call `op`, with the arguments `args` points to.
+#[inline(never)]
+fn call(op: usize, args: usize) -> isize {
+    if !present() {
+        return -1;
+    }
+    let ret;
+    unsafe {
+        // Uncompressed, and not across a page boundary.
+        asm!(
+            ".option push",
+            ".option norvc",
+            ".balign 16",
+            "slli x0, x0, 0x1f",
+            "ebreak",
+            "srai x0, x0, 7",
+            ".option pop",
+            inlateout("a0") op => ret,
+            in("a1") args,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Whether the ebreak at `pc` is a semihosting call.
+pub fn is_call(pc: usize) -> bool {
+    let word = |at: usize| unsafe { (at as *const u32).read_volatile() };
+    cfg!(feature = "semihosting")
+        && pc.is_multiple_of(4)
+        && word(pc - 4) == SLLI_X0_X0_31
+        && word(pc) == EBREAK
+        && word(pc + 4) == SRAI_X0_X0_7
+}
+
+/// The call at `pc` trapped, so there's nobody there: make it fail, and
+/// don't make any more. Returns where to go on.
+pub fn absent(frame: *mut TrapFrame, pc: usize) -> usize {
+    unsafe {
+        PRESENT = false;
+        (*frame).regs[gp(Registers::A0)] = usize::MAX;
+    }
+    pc + 8
+}
+
+/// Write `buf` to the host's console.
+pub fn write(buf: &[u8]) {
+    // SYS_WRITE0 takes a string that ends in a NUL, so there can't be one
+    // in it.
+    let mut chunk = [0u8; 128];
+    let mut len = 0;
+    for &b in buf.iter().filter(|&&b| b != 0) {
+        chunk[len] = b;
+        len += 1;
+        if len == chunk.len() - 1 {
+            chunk[len] = 0;
+            call(SYS_WRITE0, chunk.as_ptr() as usize);
+            len = 0;
+        }
+    }
+    if len > 0 {
+        chunk[len] = 0;
+        call(SYS_WRITE0, chunk.as_ptr() as usize);
+    }
+}
+
+/// Stop, with `code` as the exit code. 32-bit hosts only hear whether it
+/// was 0.
+pub fn exit(code: u16) {
+    if cfg!(target_pointer_width = "64") {
+        let args = [ADP_STOPPED_APPLICATION_EXIT, code as usize];
+        call(SYS_EXIT, args.as_ptr() as usize);
+    } else if code == 0 {
+        call(SYS_EXIT, ADP_STOPPED_APPLICATION_EXIT);
+    } else {
+        call(SYS_EXIT, ADP_STOPPED_RUN_TIME_ERROR);
+    }
+}
+
+// ///////////////////////////////////
+// / HOST FILES
+// ///////////////////////////////////
+
+/// Open the host's file at `path`, in the fopen() mode `mode`.
+fn open(path: &str, mode: usize) -> Result<usize, SysError> {
+    // The host reads the name up to a NUL, whatever the length says.
+    let mut name: Vec<u8> = path.bytes().filter(|&b| b != 0).collect();
+    let len = name.len();
+    name.push(0);
+    let args = [name.as_ptr() as usize, mode, len];
+    match call(SYS_OPEN, args.as_ptr() as usize) {
+        -1 => {
+            println!("semihosting: the host couldn't open {}", path);
+            Err(Errno(EIO))
+        }
+        handle => Ok(handle as usize),
+    }
+}
+
+fn close(handle: usize) {
+    let args = [handle];
+    call(SYS_CLOSE, args.as_ptr() as usize);
+}
+
+/// Read from `handle` into `buf`, and say how much was read: 0 at the end
+/// of the file.
+fn read(handle: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+    let args = [handle, buf.as_mut_ptr() as usize, buf.len()];
+    // What's left is what wasn't read.
+    match call(SYS_READ, args.as_ptr() as usize) {
+        left if left < 0 || left as usize > buf.len() => Err(Errno(EIO)),
+        left => Ok(buf.len() - left as usize),
+    }
+}
+
+/// Write all of `buf` to `handle`.
+fn write_host(handle: usize, buf: &[u8]) -> Result<(), SysError> {
+    let args = [handle, buf.as_ptr() as usize, buf.len()];
+    match call(SYS_WRITE, args.as_ptr() as usize) {
+        0 => Ok(()),
+        _ => Err(Errno(EIO)),
+    }
+}
+
+/// Copy the host's file HOST to PATH.
+fn hostget(args: &[&str]) -> Result<(), SysError> {
+    let [host, path] = args else {
+        return Err(Errno(EINVAL));
+    };
+    // The host's file first, so that PATH isn't emptied for nothing.
+    let handle = open(host, MODE_RB)?;
+    let file = match vfs::open(&shell::absolute(path), O_WRONLY | O_CREAT | O_TRUNC, 0o644) {
+        Ok(file) => file,
+        Err(e) => {
+            close(handle);
+            return Err(e);
+        }
+    };
+    let mut buf = alloc::vec![0; CHUNK];
+    let copied = (|| {
+        let mut offset = 0;
+        loop {
+            let n = read(handle, &mut buf)?;
+            if n == 0 {
+                return Ok(offset);
+            }
+            let mut done = 0;
+            while done < n {
+                done += file.write(offset + done, &buf[done..n])?;
+            }
+            offset += n;
+        }
+    })();
+    close(handle);
+    println!("{} bytes", copied?);
+    Ok(())
+}
+
+/// Copy PATH to the host's file HOST.
+fn hostput(args: &[&str]) -> Result<(), SysError> {
+    let [path, host] = args else {
+        return Err(Errno(EINVAL));
+    };
+    let file = vfs::open(&shell::absolute(path), O_RDONLY, 0)?;
+    let handle = open(host, MODE_WB)?;
+    let mut buf: Vec<u8> = alloc::vec![0; CHUNK];
+    let copied = (|| {
+        let mut offset = 0;
+        loop {
+            let n = file.read(offset, &mut buf)?;
+            if n == 0 {
+                return Ok(offset);
+            }
+            write_host(handle, &buf[..n])?;
+            offset += n;
+        }
+    })();
+    close(handle);
+    println!("{} bytes", copied?);
+    Ok(())
+}
+
+/// Find out whether there's anything on the other end, with a call that
+/// doesn't do anything, and give the shell the host's files if there is.
+/// This has to come after the heap and the trap stack are set up.
+pub fn init() {
+    if !cfg!(feature = "semihosting") {
+        return;
+    }
+    call(SYS_ERRNO, 0);
+    if !present() {
+        return;
+    }
+    shell::register(&Builtin {
+        name: "hostget",
+        help: "hostget HOST PATH - copy the host's file HOST to PATH",
+        run: hostget,
+    });
+    shell::register(&Builtin {
+        name: "hostput",
+        help: "hostput PATH HOST - copy PATH to the host's file HOST",
+        run: hostput,
+    });
+}
@@ -0,0 +1,1047 @@
+// The kernel shell: a kernel process that reads commands from the console
+// and runs them, for looking at and poking the kernel while it runs. A
+// line is split into words at spaces, quotes ('...' or "...") and a
+// backslash keeping what's in them together. The first word is the
+// command, the rest are its arguments.
+//
+// A line can have several commands, separated by ;, or by && to run the
+// next one only if the one before worked, or || only if it didn't, which
+// go from left to right the way sh has them. $NAME and ${NAME} are the
+// shell's variables, which export sets and unset takes away, and $? is
+// what the last command came to: 0 if it worked, the errno if it didn't,
+// 127 if there's no such command. They're replaced everywhere but in
+// '...', and a value stays one word whatever's in it.
+//
+// Commands are kept in a registry, by name. The shell has help, echo, mem,
+// pages, ps, reboot, cd, pwd, history, strace, export and unset itself, and
+// the other subsystems register theirs when they're set up, with
+// register(): the VFS has mount, umount and ls, the Minix filesystem has
+// fsck, the kernel log has log and dmesg, the tracepoints ktrace, the
+// memory map has memmap, hexdump, peek and poke, the self-tests have
+// selftest and the benchmarks bench. Many take paths, which are relative
+// to the shell's current directory, its own and not a process's, or
+// numbers, in decimal or in hex with 0x.
+//
+// Input is read raw from the console and echoed by the shell, so anything
+// else reading the console gets some of it too. The line can be edited as
+// it's typed (see LINE EDITING below). Commands run with interrupts off,
+// the way the rest of the kernel does, so that nothing gets at what they
+// change halfway through, and the disk is waited for. Those that can print
+// a lot print it through the pager (see PAGER below).
+//
+// ^C, when there's no user process in the foreground (see console.rs),
+// gives up on the line being typed and prompts again. A command can't be
+// stopped from outside, with interrupts off, so those that can take long
+// ask cancelled() now and then, where they can stop, and give up with
+// EINTR if ^C was pressed.
+
+use crate::{
+    ansi::{Input, Keys, Parser, Sequence},
+    console, cpu, kmem,
+    page::{self, PAGE_SIZE},
+    power,
+    process::{self, ProcessState},
+    strace,
+    syscall::{SysError, TimeSpec, EAGAIN, EINTR, EINVAL, ESRCH},
+    user, vfs,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::String,
+    vec::Vec,
+};
+use core::{iter::Peekable, ptr::addr_of_mut, str::Chars};
+
+use SysError::Errno;
+
+/// The longest line the shell takes.
+const LINE_MAX: usize = 256;
+/// How long to sleep for when there's no input.
+const POLL_INTERVAL: TimeSpec = TimeSpec {
+    tv_sec: 0,
+    tv_nsec: 10_000_000,
+};
+
+/// A command the shell can run.
+pub trait Command {
+    /// What it's called.
+    fn name(&self) -> &'static str;
+
+    /// How it's used and what it does, on a line, for help.
+    fn help(&self) -> &'static str;
+
+    /// Run it with `args`, the words after its name.
+    fn run(&self, args: &[&str]) -> Result<(), SysError>;
+}
+
+/// A command that's just a function, which is what most are.
+pub struct Builtin {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub run: fn(&[&str]) -> Result<(), SysError>,
+}
+
+impl Command for Builtin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn run(&self, args: &[&str]) -> Result<(), SysError> {
+        (self.run)(args)
+    }
+}
+
+static mut COMMANDS: BTreeMap<&str, &dyn Command> = BTreeMap::new();
+/// The shell's variables, and what the last command came to, for $?.
+static mut VARS: BTreeMap<String, String> = BTreeMap::new();
+static mut STATUS: isize = 0;
+/// The shell's current directory, without . or .. or symlinks in it, empty
+/// for the root.
+static mut CWD: Vec<u8> = Vec::new();
+
+fn commands() -> &'static mut BTreeMap<&'static str, &'static dyn Command> {
+    unsafe { &mut *addr_of_mut!(COMMANDS) }
+}
+
+fn vars() -> &'static mut BTreeMap<String, String> {
+    unsafe { &mut *addr_of_mut!(VARS) }
+}
+
+fn cwd() -> &'static mut Vec<u8> {
+    unsafe { &mut *addr_of_mut!(CWD) }
+}
+
+/// The current directory, to show.
+fn shown_cwd() -> String {
+    match cwd().as_slice() {
+        b"" => String::from("/"),
+        at => String::from_utf8_lossy(at).into_owned(),
+    }
+}
+
+/// Whether ^C was pressed since the command started. Interrupts are let in
+/// for a moment, so that it can get through.
+pub fn cancelled() -> bool {
+    cpu::allow_interrupts();
+    console::interrupted()
+}
+
+/// Add `command` to the shell, in place of any it has by that name.
+pub fn register(command: &'static dyn Command) {
+    commands().insert(command.name(), command);
+}
+
+/// The absolute path of `path`, which is relative to the shell's current
+/// directory if it doesn't start with a /.
+pub fn absolute(path: &str) -> Vec<u8> {
+    if path.starts_with('/') {
+        return path.into();
+    }
+    [cwd().as_slice(), b"/", path.as_bytes()].concat()
+}
+
+/// The number `arg` is, in hex if it starts with 0x.
+pub fn number(arg: &str) -> Result<usize, SysError> {
+    let n = match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    n.map_err(|_| Errno(EINVAL))
+}
+
+/// When a command on a line is run, after the one before it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Then {
+    Always,
+    IfOk,
+    IfFailed,
+}
+
+/// The value of what comes after a $ in `chars`: ?, NAME or {NAME}. A $
+/// that isn't followed by one of them is just a $.
+fn expand(chars: &mut Peekable<Chars>) -> String {
+    if chars.next_if_eq(&'?').is_some() {
+        return format!("{}", unsafe { STATUS });
+    }
+    let braced = chars.next_if_eq(&'{').is_some();
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+        name.push(c);
+    }
+    if name.is_empty() || braced && chars.next_if_eq(&'}').is_none() {
+        let open = if braced { "{" } else { "" };
+        return format!("${}{}", open, name);
+    }
+    vars().get(&name).cloned().unwrap_or_default()
+}
+
+/// Split `line` into commands, at ;, && and || that aren't quoted or
+/// after a backslash.
+fn commands_of(line: &str) -> Vec<(Then, &str)> {
+    let (mut commands, mut then, mut start) = (Vec::new(), Then::Always, 0);
+    let (mut quote, mut escaped) = (None, false);
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = match c {
+            _ if escaped => {
+                escaped = false;
+                None
+            }
+            '\\' if quote != Some('\'') => {
+                escaped = true;
+                None
+            }
+            '\'' | '"' if quote.is_none() => {
+                quote = Some(c);
+                None
+            }
+            _ if quote == Some(c) => {
+                quote = None;
+                None
+            }
+            _ if quote.is_some() => None,
+            ';' => Some(Then::Always),
+            '&' if chars.next_if(|&(_, c)| c == '&').is_some() => Some(Then::IfOk),
+            '|' if chars.next_if(|&(_, c)| c == '|').is_some() => Some(Then::IfFailed),
+            _ => None,
+        };
+        if let Some(next) = next {
+            commands.push((then, &line[start..i]));
+            start = i + if c == ';' { 1 } else { 2 };
+            then = next;
+        }
+    }
+    commands.push((then, &line[start..]));
+    commands
+}
+
+/// Split `command` into words, at spaces that aren't quoted or after a
+/// backslash, replacing the variables in them.
+fn split(command: &str) -> Vec<String> {
+    let (mut words, mut word, mut in_word) = (Vec::new(), String::new(), false);
+    let (mut quote, mut escaped) = (None, false);
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            _ if escaped => {
+                word.push(c);
+                escaped = false;
+            }
+            '\\' if quote != Some('\'') => escaped = true,
+            '\'' | '"' if quote.is_none() => quote = Some(c),
+            _ if quote == Some(c) => quote = None,
+            '$' if quote != Some('\'') => {
+                let value = expand(&mut chars);
+                // Like sh, an empty variable on its own isn't a word.
+                in_word |= !value.is_empty() || quote.is_some();
+                word.push_str(&value);
+                continue;
+            }
+            ' ' | '\t' if quote.is_none() => {
+                if in_word {
+                    words.push(core::mem::take(&mut word));
+                }
+                in_word = false;
+                continue;
+            }
+            _ => word.push(c),
+        }
+        in_word = true;
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+/// Run the command that's `words`, and say what it came to, for $?.
+fn execute(words: &[String]) -> isize {
+    let Some((name, args)) = words.split_first() else {
+        return unsafe { STATUS };
+    };
+    let Some(command) = commands().get(name.as_str()) else {
+        println!("{}: no such command, help lists them", name);
+        return 127;
+    };
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let result = cpu::without_interrupts(|| command.run(&args));
+    let text = core::mem::take(pending());
+    if !text.is_empty() {
+        more(&text);
+    }
+    match result {
+        Ok(()) => return 0,
+        Err(Errno(EINVAL)) => println!("{}: EINVAL, the usage is {}", name, command.help()),
+        Err(Errno(EINTR)) => println!("{}: interrupted", name),
+        Err(Errno(e)) => println!("{}: {}", name, strace::errno_name(e)),
+        Err(SysError::Block) => println!("{}: it would have to wait", name),
+    }
+    match result {
+        Err(Errno(e)) => e,
+        _ => EAGAIN,
+    }
+}
+
+/// Run the commands on `line`, until ^C.
+fn run_line(line: &str) {
+    console::clear_interrupt();
+    for (then, command) in commands_of(line) {
+        if console::interrupted() {
+            break;
+        }
+        let ok = unsafe { STATUS } == 0;
+        if then == Then::IfOk && !ok || then == Then::IfFailed && ok {
+            continue;
+        }
+        // Split only now, so that $? is what the one before came to.
+        let status = execute(&split(command));
+        unsafe {
+            STATUS = status;
+        }
+    }
+}
+
+/// Read a line from the console, letting it be edited as it's typed, and
+/// hand it out once Enter is pressed. It has to fit on the terminal's line
+/// after `prompt`, which has been printed.
+fn read_line(prompt: &str) -> String {
+    let cols = console::winsize().ws_col as usize;
+    let max = cols.saturating_sub(prompt.len() + 1).clamp(1, LINE_MAX);
+    let mut editor = Editor::new(prompt, max);
+    let mut keys = Keys::new();
+    console::clear_interrupt();
+    loop {
+        // ^C was echoed, the line is dropped for a new prompt.
+        if console::interrupted() {
+            return String::new();
+        }
+        let Some(c) = console::get() else {
+            user::nanosleep(&POLL_INTERVAL);
+            continue;
+        };
+        if let Some(key) = keys.feed(c).and_then(key_of) {
+            if editor.key(key) {
+                println!();
+                return editor.line;
+            }
+        }
+    }
+}
+
+/// The shell, as a kernel process. The test kernel doesn't start it.
+#[cfg_attr(test, allow(dead_code))]
+pub fn run() {
+    println!("Kernel shell, help lists the commands.");
+    loop {
+        let prompt = format!("{}# ", shown_cwd());
+        print!("{}", prompt);
+        let line = read_line(&prompt);
+        remember(&line);
+        run_line(&line);
+    }
+}
+
+// ///////////////////////////////////
+// / LINE EDITING
+// ///////////////////////////////////
+
+// The keys the line editor knows: the arrows, Home, End and Delete, as the
+// escape sequences terminals send for them (see ansi.rs), and the Emacs
+// keys of readline, ^A, ^E, ^B, ^F, ^D, ^K, ^P and ^N.
+// The line is redrawn from the cursor on after every change, and the
+// cursor is moved with ESC [ C and ESC [ D, which is why it has to stay on
+// one line of the terminal.
+//
+// The last lines that were run are kept in the history. Up and down go
+// through them, and the one that's recalled can be edited like a new one:
+// it's a copy, what's in the history stays as it was. ^R searches back
+// through them for a line with what's typed after it in it, as readline
+// does: ^R again finds the one before that, ^G gives up, and any other
+// key takes the line that was found, Enter runs it.
+
+/// A key, as far as the line editor cares.
+#[derive(Clone, Copy)]
+enum Key {
+    Char(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    KillToEnd,
+    Up,
+    Down,
+    Search,
+    Cancel,
+}
+
+/// The editor's key for `input`, if it's one.
+fn key_of(input: Input) -> Option<Key> {
+    Some(match input {
+        Input::Char(b'\r' | b'\n') => Key::Enter,
+        Input::Char(8 | 0x7f) => Key::Backspace,
+        Input::Char(0x01) | Input::Home => Key::Home,
+        Input::Char(0x02) | Input::ArrowLeft => Key::Left,
+        Input::Char(0x04) | Input::Delete => Key::Delete,
+        Input::Char(0x05) | Input::End => Key::End,
+        Input::Char(0x06) | Input::ArrowRight => Key::Right,
+        Input::Char(0x07) => Key::Cancel,
+        Input::Char(0x0b) => Key::KillToEnd,
+        Input::Char(0x0e) | Input::ArrowDown => Key::Down,
+        Input::Char(0x10) | Input::ArrowUp => Key::Up,
+        Input::Char(0x12) => Key::Search,
+        Input::Char(c @ b' '..=b'~') => Key::Char(c),
+        _ => return None,
+    })
+}
+
+/// How many lines the history keeps.
+const HISTORY_SIZE: usize = 64;
+
+/// The last lines that were run, the last one last.
+static mut HISTORY: VecDeque<String> = VecDeque::new();
+
+fn history() -> &'static mut VecDeque<String> {
+    unsafe { &mut *addr_of_mut!(HISTORY) }
+}
+
+/// Put `line` in the history, unless it's empty, or the last line again.
+fn remember(line: &str) {
+    let history = history();
+    if line.trim().is_empty() || history.back().is_some_and(|last| last == line) {
+        return;
+    }
+    if history.len() == HISTORY_SIZE {
+        history.pop_front();
+    }
+    history.push_back(line.into());
+}
+
+/// A ^R search through the history.
+struct Search {
+    /// What's being looked for.
+    query: String,
+    /// Which line of the history has it, the history's length if none
+    /// has yet.
+    found: usize,
+    /// Whether the last try found nothing.
+    failed: bool,
+    /// The line as it was before the search.
+    before: String,
+}
+
+/// The line being typed, and where the cursor is in it.
+struct Editor<'a> {
+    prompt: &'a str,
+    /// Only printable ASCII goes in, so bytes and characters are the same.
+    line: String,
+    cursor: usize,
+    /// How long the line may get.
+    max: usize,
+    /// Which line of the history is recalled, the history's length for the
+    /// one being typed, which is kept in `typed` meanwhile.
+    recalled: usize,
+    typed: String,
+    search: Option<Search>,
+}
+
+impl<'a> Editor<'a> {
+    /// An empty line after `prompt`, which can get `max` long.
+    fn new(prompt: &'a str, max: usize) -> Self {
+        Editor {
+            prompt,
+            line: String::new(),
+            cursor: 0,
+            max,
+            recalled: history().len(),
+            typed: String::new(),
+            search: None,
+        }
+    }
+
+    /// Draw the line from `from`, where the terminal's cursor is, on, clear
+    /// whatever was after it, and move the terminal's cursor to ours.
+    fn redraw(&self, from: usize) {
+        print!("{}\x1b[K", &self.line[from..]);
+        Self::left(self.line.len() - self.cursor);
+    }
+
+    fn left(n: usize) {
+        if n > 0 {
+            print!("\x1b[{}D", n);
+        }
+    }
+
+    fn right(n: usize) {
+        if n > 0 {
+            print!("\x1b[{}C", n);
+        }
+    }
+
+    /// Put `line` in place of the line, with the cursor at its end.
+    fn replace(&mut self, line: &str) {
+        Self::left(self.cursor);
+        self.line = line.chars().take(self.max).collect();
+        self.cursor = self.line.len();
+        self.redraw(0);
+    }
+
+    /// Recall the line `n` of the history, or the line being typed if `n`
+    /// is past the end.
+    fn recall(&mut self, n: usize) {
+        if self.recalled == history().len() {
+            self.typed = self.line.clone();
+        }
+        self.recalled = n;
+        let line = match history().get(n) {
+            Some(line) => line.clone(),
+            None => core::mem::take(&mut self.typed),
+        };
+        self.replace(&line);
+    }
+
+    /// Draw the whole line again, prompt and all, or the search over it.
+    fn redraw_all(&self) {
+        match &self.search {
+            Some(s) => {
+                let failed = if s.failed { "failed " } else { "" };
+                print!(
+                    "\r({}reverse-i-search)`{}': {}\x1b[K",
+                    failed, s.query, self.line
+                );
+            }
+            None => {
+                print!("\r{}", self.prompt);
+                self.redraw(0);
+            }
+        }
+    }
+
+    /// Look for the query in the lines of the history before `before`, and
+    /// take the last one that has it.
+    fn find(&mut self, before: usize) {
+        let Some(s) = &mut self.search else {
+            return;
+        };
+        let history = history();
+        match (0..before)
+            .rev()
+            .find(|&i| history[i].contains(s.query.as_str()))
+        {
+            Some(i) => {
+                s.found = i;
+                s.failed = false;
+                self.line = history[i].chars().take(self.max).collect();
+                self.cursor = self.line.len();
+            }
+            None => {
+                s.failed = true;
+                print!("\x07");
+            }
+        }
+        self.redraw_all();
+    }
+
+    /// Do what `key` does in a search. Returns whether it's done with the
+    /// key, otherwise the search is over and the key does what it does on
+    /// the line that was found.
+    fn search_key(&mut self, key: Key) -> bool {
+        let Some(s) = &mut self.search else {
+            return false;
+        };
+        match key {
+            Key::Search => {
+                let before = s.found;
+                self.find(before);
+            }
+            Key::Char(c) => {
+                s.query.push(c as char);
+                let before = (s.found + 1).min(history().len());
+                self.find(before);
+            }
+            Key::Backspace => {
+                s.query.pop();
+                self.find(history().len());
+            }
+            Key::Cancel => {
+                let before = core::mem::take(&mut s.before);
+                self.search = None;
+                self.line = before;
+                self.cursor = self.line.len();
+                self.redraw_all();
+            }
+            _ => {
+                self.search = None;
+                self.redraw_all();
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Do what `key` does. Returns whether the line is done.
+    fn key(&mut self, key: Key) -> bool {
+        if self.search.is_some() && self.search_key(key) {
+            return false;
+        }
+        match key {
+            Key::Enter => return true,
+            Key::Char(_) if self.line.len() >= self.max => print!("\x07"),
+            Key::Char(c) => {
+                self.line.insert(self.cursor, c as char);
+                self.cursor += 1;
+                self.redraw(self.cursor - 1);
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                Self::left(1);
+                self.redraw(self.cursor);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.redraw(self.cursor);
+            }
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= 1;
+                Self::left(1);
+            }
+            Key::Right if self.cursor < self.line.len() => {
+                self.cursor += 1;
+                Self::right(1);
+            }
+            Key::Home => {
+                Self::left(self.cursor);
+                self.cursor = 0;
+            }
+            Key::End => {
+                Self::right(self.line.len() - self.cursor);
+                self.cursor = self.line.len();
+            }
+            Key::KillToEnd => {
+                self.line.truncate(self.cursor);
+                print!("\x1b[K");
+            }
+            Key::Up if self.recalled > 0 => self.recall(self.recalled - 1),
+            Key::Down if self.recalled < history().len() => self.recall(self.recalled + 1),
+            Key::Search => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    found: history().len(),
+                    failed: false,
+                    before: self.line.clone(),
+                });
+                self.redraw_all();
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+// ///////////////////////////////////
+// / PAGER
+// ///////////////////////////////////
+
+// What a command prints inside paged() is kept until it's done, since it
+// runs with interrupts off and nothing can be typed until then, and then
+// shown a screenful at a time, the way more does. --More-- waits for a
+// key: space for the next screenful, Enter for the next line, q to skip
+// the rest. What fits on the screen is just printed.
+
+/// What's been printed through the pager and not shown yet.
+static mut PENDING: String = String::new();
+
+fn pending() -> &'static mut String {
+    unsafe { &mut *addr_of_mut!(PENDING) }
+}
+
+/// Run `f`, with what it prints going through the pager.
+pub fn paged<R>(f: impl FnOnce() -> R) -> R {
+    let (ret, text) = console::capture(f);
+    pending().push_str(&text);
+    ret
+}
+
+/// How many rows of a terminal `cols` wide `line` takes up.
+fn rows_of(line: &str, cols: usize) -> usize {
+    let mut parser = Parser::output();
+    // Printable characters, counting the first byte of each in UTF-8.
+    let width = line
+        .bytes()
+        .filter(
+            |&b| matches!(parser.feed(b), Some(Sequence::Byte(c)) if c >= b' ' && c & 0xc0 != 0x80),
+        )
+        .count();
+    width.div_ceil(cols).max(1)
+}
+
+/// Wait for the next key. ^C is a q.
+fn next_key() -> Input {
+    let mut keys = Keys::new();
+    loop {
+        if console::interrupted() {
+            console::clear_interrupt();
+            return Input::Char(b'q');
+        }
+        match console::get() {
+            Some(c) => {
+                if let Some(key) = keys.feed(c) {
+                    return key;
+                }
+            }
+            None => {
+                user::nanosleep(&POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Show `text` a screenful at a time.
+fn more(text: &str) {
+    let size = console::winsize();
+    let rows = (size.ws_row as usize).max(2) - 1;
+    let cols = (size.ws_col as usize).max(1);
+    let mut room = rows;
+    for line in text.split_inclusive('\n') {
+        while room == 0 {
+            print!("\x1b[7m--More--\x1b[0m");
+            let key = next_key();
+            print!("\r\x1b[K");
+            match key {
+                Input::Char(b' ') => room = rows,
+                Input::Char(b'\r' | b'\n') => room = 1,
+                Input::Char(b'q') => return,
+                _ => {}
+            }
+        }
+        print!("{}", line);
+        room = room.saturating_sub(rows_of(line.trim_end(), cols));
+    }
+}
+
+// ///////////////////////////////////
+// / BUILT-IN COMMANDS
+// ///////////////////////////////////
+
+fn help(_: &[&str]) -> Result<(), SysError> {
+    for command in commands().values() {
+        println!("  {}", command.help());
+    }
+    Ok(())
+}
+
+fn echo(args: &[&str]) -> Result<(), SysError> {
+    println!("{}", args.join(" "));
+    Ok(())
+}
+
+fn mem(_: &[&str]) -> Result<(), SysError> {
+    let (pages, taken) = page::stats();
+    let (heap, in_use) = kmem::stats();
+    println!(
+        "pages: {} of {} taken, {} KiB free",
+        taken,
+        pages,
+        (pages - taken) * PAGE_SIZE / 1024
+    );
+    println!("heap: {} of {} bytes taken", in_use, heap);
+    Ok(())
+}
+
+fn pages(_: &[&str]) -> Result<(), SysError> {
+    paged(page::print_page_allocations);
+    Ok(())
+}
+
+fn ps(_: &[&str]) -> Result<(), SysError> {
+    paged(|| {
+        println!("  PID  PPID STATE     UID KIND");
+        for p in process::list().iter() {
+            let state = match p.state() {
+                ProcessState::Running => "running",
+                ProcessState::Sleeping => "sleeping",
+                ProcessState::Waiting => "waiting",
+                ProcessState::Zombie => "zombie",
+            };
+            let kind = if p.is_user() { "user" } else { "kernel" };
+            println!(
+                "{:>5} {:>5} {:<8} {:>4} {}",
+                p.pid(),
+                p.ppid(),
+                state,
+                p.cred.uid,
+                kind
+            );
+        }
+    });
+    Ok(())
+}
+
+fn reboot(_: &[&str]) -> Result<(), SysError> {
+    power::reboot();
+}
+
+fn cd(args: &[&str]) -> Result<(), SysError> {
+    let path = match args {
+        [] => "/",
+        [path] => path,
+        _ => return Err(Errno(EINVAL)),
+    };
+    *cwd() = vfs::chdir(&absolute(path))?;
+    Ok(())
+}
+
+fn history_command(_: &[&str]) -> Result<(), SysError> {
+    for (n, line) in history().iter().enumerate() {
+        println!("{:>4}  {}", n + 1, line);
+    }
+    Ok(())
+}
+
+fn pwd(_: &[&str]) -> Result<(), SysError> {
+    println!("{}", shown_cwd());
+    Ok(())
+}
+
+fn trace(args: &[&str]) -> Result<(), SysError> {
+    let (pid, on) = match args {
+        [pid, "on"] => (pid, true),
+        [pid, "off"] => (pid, false),
+        _ => return Err(Errno(EINVAL)),
+    };
+    let pid = pid.parse().map_err(|_| Errno(EINVAL))?;
+    if !strace::set_trace(pid, on) {
+        return Err(Errno(ESRCH));
+    }
+    Ok(())
+}
+
+/// Set variables, NAME=VALUE, or without any, list them.
+fn export(args: &[&str]) -> Result<(), SysError> {
+    if args.is_empty() {
+        for (name, value) in vars().iter() {
+            println!("{}={}", name, value);
+        }
+        return Ok(());
+    }
+    for arg in args {
+        let (name, value) = arg.split_once('=').unwrap_or((arg, ""));
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_';
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(valid)
+        {
+            return Err(Errno(EINVAL));
+        }
+        vars().insert(name.into(), value.into());
+    }
+    Ok(())
+}
+
+fn unset(args: &[&str]) -> Result<(), SysError> {
+    for name in args {
+        vars().remove(*name);
+    }
+    Ok(())
+}
+
+const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "help",
+        help: "help - list the commands",
+        run: help,
+    },
+    Builtin {
+        name: "echo",
+        help: "echo [WORD]... - print the words",
+        run: echo,
+    },
+    Builtin {
+        name: "mem",
+        help: "mem - how much memory is taken",
+        run: mem,
+    },
+    Builtin {
+        name: "pages",
+        help: "pages - list the pages that are taken",
+        run: pages,
+    },
+    Builtin {
+        name: "ps",
+        help: "ps - list the processes",
+        run: ps,
+    },
+    Builtin {
+        name: "reboot",
+        help: "reboot - start the machine again",
+        run: reboot,
+    },
+    Builtin {
+        name: "cd",
+        help: "cd [PATH] - go into a directory, the root if none",
+        run: cd,
+    },
+    Builtin {
+        name: "history",
+        help: "history - list the last lines that were run",
+        run: history_command,
+    },
+    Builtin {
+        name: "pwd",
+        help: "pwd - print the current directory",
+        run: pwd,
+    },
+    Builtin {
+        name: "strace",
+        help: "strace PID on|off - trace a process's system calls or stop",
+        run: trace,
+    },
+    Builtin {
+        name: "export",
+        help: "export [NAME=VALUE]... - set variables, or list them",
+        run: export,
+    },
+    Builtin {
+        name: "unset",
+        help: "unset NAME... - take variables away",
+        run: unset,
+    },
+];
+
+/// Register the shell's own commands.
+pub fn init() {
+    for command in BUILTINS {
+        register(command);
+    }
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::check;
+
+    #[test_case]
+    fn split_test() -> Result<(), String> {
+        let words = split(r#"ls  -l 'a b' "c d" e\ f 'g\h'"#);
+        check(words == ["ls", "-l", "a b", "c d", "e f", r"g\h"], || {
+            format!("split into {:?}", words)
+        })
+    }
+
+    /// Variables, and $? for the last command's status.
+    #[test_case]
+    fn vars_test() -> Result<(), String> {
+        vars().insert("X".into(), "x y".into());
+        unsafe {
+            STATUS = 3;
+        }
+        let words = split(r#"$X ${X}z '$X' "$X" $NOPE "" ${X $ $?"#);
+        vars().remove("X");
+        unsafe {
+            STATUS = 0;
+        }
+        check(
+            words == ["x y", "x yz", "$X", "x y", "", "${X", "$", "3"],
+            || format!("split into {:?}", words),
+        )
+    }
+
+    #[test_case]
+    fn commands_of_test() -> Result<(), String> {
+        let commands = commands_of(r"a; b && c || d 'e;f' \; g&h");
+        let want = [
+            (Then::Always, "a"),
+            (Then::Always, " b "),
+            (Then::IfOk, " c "),
+            (Then::IfFailed, r" d 'e;f' \; g&h"),
+        ];
+        check(commands == want, || {
+            let got: Vec<&str> = commands.iter().map(|&(_, c)| c).collect();
+            format!("split into {:?}", got)
+        })
+    }
+
+    /// && and || go by what the command before came to, and $? is it.
+    #[test_case]
+    fn run_line_test() -> Result<(), String> {
+        console::capture(|| run_line("nosuch && export A=1; nosuch || export B=$?; export C=$?"));
+        let (a, b, c) = (vars().remove("A"), vars().remove("B"), vars().remove("C"));
+        check(a.is_none(), || "&& ran after a failure".into())?;
+        check(b.as_deref() == Some("127"), || format!("B is {:?}", b))?;
+        check(c.as_deref() == Some("0"), || format!("C is {:?}", c))
+    }
+
+    /// What the line editor makes of `input`, as it would come in from the
+    /// terminal, and whether it's done with the line.
+    fn edit(input: &[u8]) -> (String, bool) {
+        let mut editor = Editor::new("# ", 16);
+        let mut keys = Keys::new();
+        let (done, _) = console::capture(|| {
+            input
+                .iter()
+                .filter_map(|&b| keys.feed(b).and_then(key_of))
+                .any(|k| editor.key(k))
+        });
+        (editor.line, done)
+    }
+
+    #[test_case]
+    fn editor_test() -> Result<(), String> {
+        // Left, l, ^A, right, backspace, ^E, !, Enter.
+        let got = edit(b"helo\x1b[Dl\x01\x1b[C\x7f\x05!\r");
+        check(got == ("ello!".into(), true), || {
+            format!("edited into {:?}", got)
+        })?;
+        // Home, ^F, ^K, Delete, i.
+        let got = edit(b"helo\x1b[H\x06\x0b\x1b[3~i");
+        check(got == ("hi".into(), false), || {
+            format!("edited into {:?}", got)
+        })?;
+        // Only so much fits.
+        let got = edit(&[b'x'; 20]);
+        check(got.0.len() == 16, || format!("{} fit in 16", got.0.len()))
+    }
+
+    #[test_case]
+    fn history_test() -> Result<(), String> {
+        let before = history().len();
+        remember("history test one");
+        remember("history test two");
+        remember("history test two");
+        remember(" ");
+        let added = history().len() - before;
+        check(added == 2, || {
+            format!("{} lines went in the history", added)
+        })?;
+        let got = edit(b"x\x1b[A\x1b[A\x1b[B");
+        check(got.0 == "history test two", || {
+            format!("recalled {:?}", got.0)
+        })?;
+        let got = edit(b"x\x10\x0e");
+        check(got.0 == "x", || {
+            format!("the typed line came back as {:?}", got.0)
+        })?;
+        // ^R one, and Enter takes it.
+        let got = edit(b"\x12one\r");
+        check(got == ("history test one".into(), true), || {
+            format!("^R found {:?}", got.0)
+        })?;
+        // ^R q, ^G.
+        let got = edit(b"x\x12q\x07");
+        check(got.0 == "x", || format!("^G left {:?}", got.0))?;
+        history().truncate(before);
+        Ok(())
+    }
+}
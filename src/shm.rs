@@ -0,0 +1,111 @@
+// System V shared memory. A segment is a set of pages that any number of
+// processes can attach to their address space. Segments are reference
+// counted: every attachment holds a reference, and so does the table of
+// segments until the segment is removed with IPC_RMID. The pages are freed
+// when the last reference goes away.
+
+use crate::{
+    page::{self, align_val, PAGE_ORDER, PAGE_SIZE},
+    syscall::{SysError, EEXIST, EINVAL, ENOENT, ENOMEM},
+};
+use alloc::{collections::btree_map::BTreeMap, rc::Rc, vec::Vec};
+use core::ptr::addr_of_mut;
+
+use SysError::Errno;
+
+// Keys and flags for shmget
+pub const IPC_PRIVATE: usize = 0;
+pub const IPC_CREAT: usize = 0o1000;
+pub const IPC_EXCL: usize = 0o2000;
+// Flags for shmat
+pub const SHM_RDONLY: usize = 0o10000;
+pub const SHM_RND: usize = 0o20000;
+// Commands for shmctl
+pub const IPC_RMID: usize = 0;
+
+/// The largest segment we hand out.
+pub const SHMMAX: usize = 16 * 1024 * 1024;
+
+pub struct Segment {
+    key: usize,
+    size: usize,
+    pages: Vec<*mut u8>,
+}
+
+impl Segment {
+    /// The physical address of the `n`th page.
+    pub fn page(&self, n: usize) -> usize {
+        self.pages[n] as usize
+    }
+
+    pub fn num_pages(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        for &p in self.pages.iter() {
+            page::dealloc(p);
+        }
+    }
+}
+
+// The segments that haven't been removed, by id.
+static mut SEGMENTS: BTreeMap<usize, Rc<Segment>> = BTreeMap::new();
+static mut NEXT_ID: usize = 1;
+
+fn segments() -> &'static mut BTreeMap<usize, Rc<Segment>> {
+    unsafe { &mut *addr_of_mut!(SEGMENTS) }
+}
+
+/// Find the segment for `key`, or create it (see shmget(2)). Returns the
+/// segment's id.
+pub fn get(key: usize, size: usize, flags: usize) -> Result<usize, SysError> {
+    if key != IPC_PRIVATE {
+        if let Some((&id, seg)) = segments().iter().find(|(_, s)| s.key == key) {
+            if flags & (IPC_CREAT | IPC_EXCL) == IPC_CREAT | IPC_EXCL {
+                return Err(Errno(EEXIST));
+            }
+            if size > seg.size {
+                return Err(Errno(EINVAL));
+            }
+            return Ok(id);
+        }
+        if flags & IPC_CREAT == 0 {
+            return Err(Errno(ENOENT));
+        }
+    }
+    if size == 0 || size > SHMMAX {
+        return Err(Errno(EINVAL));
+    }
+    let mut pages = Vec::new();
+    for _ in 0..align_val(size, PAGE_ORDER) / PAGE_SIZE {
+        let p = page::zalloc(1);
+        if p.is_null() {
+            for p in pages {
+                page::dealloc(p);
+            }
+            return Err(Errno(ENOMEM));
+        }
+        pages.push(p);
+    }
+    let id = unsafe {
+        let id = NEXT_ID;
+        NEXT_ID += 1;
+        id
+    };
+    segments().insert(id, Rc::new(Segment { key, size, pages }));
+    Ok(id)
+}
+
+/// Get a reference to the segment with the given id.
+pub fn lookup(id: usize) -> Option<Rc<Segment>> {
+    segments().get(&id).cloned()
+}
+
+/// Remove a segment. It can't be found anymore, but it stays around until
+/// every process has detached it.
+pub fn remove(id: usize) -> Result<(), SysError> {
+    segments().remove(&id).map(|_| ()).ok_or(Errno(EINVAL))
+}
@@ -0,0 +1,146 @@
+// The SiFive SPI controller, as on the FU540 and FU740 (HiFive Unleashed and
+// Unmatched). It shifts bytes out of a transmit FIFO and into a receive FIFO,
+// one byte in for every byte out, and drives up to four chip selects. We
+// only use it byte by byte, with the FIFOs as one-byte buffers. What's on
+// the bus is listed under the controller's node in the device tree; SD card
+// slots (mmc-spi-slot) are all we know about.
+
+use crate::{
+    device::{self, Device},
+    fdt, sd,
+};
+
+// Register offsets
+const SCKDIV: usize = 0x00;
+const SCKMODE: usize = 0x04;
+const CSID: usize = 0x10;
+const CSDEF: usize = 0x14;
+const CSMODE: usize = 0x18;
+const FMT: usize = 0x40;
+const TXDATA: usize = 0x48;
+const RXDATA: usize = 0x4c;
+const FCTRL: usize = 0x60;
+const IE: usize = 0x70;
+
+// Chip select modes
+const CSMODE_AUTO: u32 = 0;
+const CSMODE_HOLD: u32 = 2;
+const CSMODE_OFF: u32 = 3;
+/// TXDATA: the FIFO is full. RXDATA: the FIFO is empty.
+const FIFO_FLAG: u32 = 1 << 31;
+/// FMT: single lane, MSB first, 8 bits per frame.
+const FMT_8BIT: u32 = 8 << 16;
+
+/// The clock the controller is fed if the device tree doesn't say. The
+/// FU540's is 500 MHz and the FU740's less, so assuming the fastest one
+/// keeps us at or below the rates we ask for.
+const DEFAULT_CLOCK_HZ: u32 = 500_000_000;
+
+/// A chip select on a SiFive SPI controller.
+#[derive(Clone, Copy)]
+pub struct Spi {
+    base: usize,
+    clock_hz: u32,
+    cs: u32,
+}
+
+impl Spi {
+    fn reg(&self, offset: usize) -> *mut u32 {
+        (self.base + offset) as *mut u32
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { self.reg(offset).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, val: u32) {
+        unsafe { self.reg(offset).write_volatile(val) }
+    }
+
+    /// Run the clock at `hz` or the fastest rate below it. The controller
+    /// divides its input clock by 2 x (div + 1).
+    pub fn set_speed(&self, hz: u32) {
+        let div = self.clock_hz.div_ceil(2 * hz.max(1)).saturating_sub(1);
+        self.write(SCKDIV, div.min(0xfff));
+    }
+
+    /// Send a byte and return the one that came back.
+    pub fn transfer(&self, byte: u8) -> u8 {
+        while self.read(TXDATA) & FIFO_FLAG != 0 {}
+        self.write(TXDATA, byte as u32);
+        loop {
+            let rx = self.read(RXDATA);
+            if rx & FIFO_FLAG == 0 {
+                return rx as u8;
+            }
+        }
+    }
+
+    /// Keep the chip selected between bytes, until deselect().
+    pub fn select(&self) {
+        self.write(CSID, self.cs);
+        self.write(CSMODE, CSMODE_HOLD);
+    }
+
+    pub fn deselect(&self) {
+        self.write(CSMODE, CSMODE_AUTO);
+    }
+
+    /// Leave the chip select alone while clocking bytes out, for devices
+    /// that want clocks without being selected.
+    pub fn without_select(&self) {
+        self.write(CSMODE, CSMODE_OFF);
+    }
+}
+
+/// The driver for the controller.
+pub struct SifiveSpi;
+
+impl device::Driver for SifiveSpi {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["sifive,spi0"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let Some(base) = dev.base() else {
+            return false;
+        };
+        let clock_hz = dev
+            .node
+            .and_then(|n| n.u32_property("clock-frequency"))
+            .unwrap_or(DEFAULT_CLOCK_HZ);
+        let spi = Spi {
+            base,
+            clock_hz,
+            cs: 0,
+        };
+        // Memory-mapped flash mode off, mode 0, chip selects active low,
+        // and polled.
+        spi.write(FCTRL, 0);
+        spi.write(SCKMODE, 0);
+        spi.write(CSDEF, 0xf);
+        spi.write(FMT, FMT_8BIT);
+        spi.write(IE, 0);
+        spi.deselect();
+        let (Some(fdt), Some(node)) = (fdt::get(), dev.node) else {
+            return true;
+        };
+        for child in fdt.children(node) {
+            if !child.is_compatible("mmc-spi-slot") {
+                continue;
+            }
+            let Some((cs, _)) = child.reg() else {
+                continue;
+            };
+            let max_hz = child.u32_property("spi-max-frequency");
+            sd::probe(
+                Spi {
+                    cs: cs as u32,
+                    ..spi
+                },
+                max_hz,
+            );
+        }
+        true
+    }
+}
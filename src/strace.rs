@@ -0,0 +1,274 @@
+// System call tracing. When a process has tracing turned on, every system
+// call it makes is printed along with its arguments and result, like
+// strace would show it. Tracing a chatty process can easily flood the
+// console, so the output is rate limited.
+
+use crate::{
+    cpu::{self, TrapFrame},
+    process,
+    syscall::{self, *},
+};
+
+// How many lines we may print in a burst, and how many more we get each
+// second after that.
+const BURST: u64 = 64;
+const LINES_PER_SEC: u64 = 32;
+
+// The rate limiter is a token bucket: every line takes a token, and tokens
+// come back at LINES_PER_SEC up to BURST. Lines that find the bucket empty
+// are counted and reported once there are tokens again.
+static mut TOKENS: u64 = BURST;
+static mut LAST_REFILL: u64 = 0;
+static mut SUPPRESSED: usize = 0;
+
+/// How an argument is printed.
+#[derive(Clone, Copy)]
+enum Arg {
+    Int,
+    Hex,
+    Fd,
+    Str,
+}
+
+use Arg::{Fd, Hex, Int, Str};
+
+/// The name of system call `number`, if we know it.
+pub fn name(number: usize) -> Option<&'static str> {
+    describe(number).map(|(name, _)| name)
+}
+
+/// The name and arguments of the system calls we know.
+fn describe(number: usize) -> Option<(&'static str, &'static [Arg])> {
+    Some(match number {
+        SYS_GETCWD => ("getcwd", &[Hex, Int]),
+        SYS_DUP => ("dup", &[Fd]),
+        SYS_DUP3 => ("dup3", &[Fd, Fd, Hex]),
+        SYS_FCNTL => ("fcntl", &[Fd, Int, Hex]),
+        SYS_IOCTL => ("ioctl", &[Fd, Hex, Hex]),
+        SYS_FLOCK => ("flock", &[Fd, Hex]),
+        SYS_MKDIRAT => ("mkdirat", &[Fd, Str, Hex]),
+        SYS_UNLINKAT => ("unlinkat", &[Fd, Str, Hex]),
+        SYS_SYMLINKAT => ("symlinkat", &[Str, Fd, Str]),
+        SYS_LINKAT => ("linkat", &[Fd, Str, Fd, Str, Hex]),
+        SYS_UMOUNT2 => ("umount2", &[Str, Hex]),
+        SYS_MOUNT => ("mount", &[Str, Str, Str, Hex, Hex]),
+        SYS_FTRUNCATE => ("ftruncate", &[Fd, Int]),
+        SYS_FACCESSAT => ("faccessat", &[Fd, Str, Int]),
+        SYS_CHDIR => ("chdir", &[Str]),
+        SYS_FCHMODAT => ("fchmodat", &[Fd, Str, Hex]),
+        SYS_FCHOWNAT => ("fchownat", &[Fd, Str, Int, Int, Hex]),
+        SYS_OPENAT => ("openat", &[Fd, Str, Hex, Hex]),
+        SYS_CLOSE => ("close", &[Fd]),
+        SYS_PIPE2 => ("pipe2", &[Hex, Hex]),
+        SYS_GETDENTS64 => ("getdents64", &[Fd, Hex, Int]),
+        SYS_LSEEK => ("lseek", &[Fd, Int, Int]),
+        SYS_READ => ("read", &[Fd, Hex, Int]),
+        SYS_WRITE => ("write", &[Fd, Hex, Int]),
+        SYS_READV => ("readv", &[Fd, Hex, Int]),
+        SYS_WRITEV => ("writev", &[Fd, Hex, Int]),
+        SYS_PREAD64 => ("pread64", &[Fd, Hex, Int, Int]),
+        SYS_PWRITE64 => ("pwrite64", &[Fd, Hex, Int, Int]),
+        SYS_READLINKAT => ("readlinkat", &[Fd, Str, Hex, Int]),
+        SYS_NEWFSTATAT => ("newfstatat", &[Fd, Str, Hex, Hex]),
+        SYS_SYNC => ("sync", &[]),
+        SYS_FSYNC => ("fsync", &[Fd]),
+        SYS_FDATASYNC => ("fdatasync", &[Fd]),
+        SYS_FSTAT => ("fstat", &[Fd, Hex]),
+        SYS_EXIT => ("exit", &[Int]),
+        SYS_EXIT_GROUP => ("exit_group", &[Int]),
+        SYS_SET_TID_ADDRESS => ("set_tid_address", &[Hex]),
+        SYS_SET_ROBUST_LIST => ("set_robust_list", &[Hex, Int]),
+        SYS_NANOSLEEP => ("nanosleep", &[Hex, Hex]),
+        SYS_CLOCK_SETTIME => ("clock_settime", &[Int, Hex]),
+        SYS_CLOCK_GETTIME => ("clock_gettime", &[Int, Hex]),
+        SYS_RT_SIGACTION => ("rt_sigaction", &[Int, Hex, Hex, Int]),
+        SYS_RT_SIGPROCMASK => ("rt_sigprocmask", &[Int, Hex, Hex, Int]),
+        SYS_SCHED_YIELD => ("sched_yield", &[]),
+        SYS_REBOOT => ("reboot", &[Hex, Hex, Hex, Hex]),
+        SYS_SETGID => ("setgid", &[Int]),
+        SYS_SETUID => ("setuid", &[Int]),
+        SYS_UNAME => ("uname", &[Hex]),
+        SYS_GETRLIMIT => ("getrlimit", &[Int, Hex]),
+        SYS_SETRLIMIT => ("setrlimit", &[Int, Hex]),
+        SYS_GETTIMEOFDAY => ("gettimeofday", &[Hex, Hex]),
+        SYS_GETPID => ("getpid", &[]),
+        SYS_GETPPID => ("getppid", &[]),
+        SYS_GETUID => ("getuid", &[]),
+        SYS_GETEUID => ("geteuid", &[]),
+        SYS_GETGID => ("getgid", &[]),
+        SYS_GETEGID => ("getegid", &[]),
+        SYS_GETTID => ("gettid", &[]),
+        SYS_SHMGET => ("shmget", &[Int, Int, Hex]),
+        SYS_SHMCTL => ("shmctl", &[Int, Int, Hex]),
+        SYS_SHMAT => ("shmat", &[Int, Hex, Hex]),
+        SYS_SHMDT => ("shmdt", &[Hex]),
+        SYS_SOCKET => ("socket", &[Int, Hex, Int]),
+        SYS_BIND => ("bind", &[Fd, Hex, Int]),
+        SYS_CONNECT => ("connect", &[Fd, Hex, Int]),
+        SYS_GETSOCKNAME => ("getsockname", &[Fd, Hex, Hex]),
+        SYS_GETPEERNAME => ("getpeername", &[Fd, Hex, Hex]),
+        SYS_SENDTO => ("sendto", &[Fd, Hex, Int, Hex, Hex, Int]),
+        SYS_RECVFROM => ("recvfrom", &[Fd, Hex, Int, Hex, Hex, Hex]),
+        SYS_BRK => ("brk", &[Hex]),
+        SYS_MUNMAP => ("munmap", &[Hex, Int]),
+        SYS_MMAP => ("mmap", &[Hex, Int, Hex, Hex, Fd, Int]),
+        SYS_MSYNC => ("msync", &[Hex, Int, Hex]),
+        SYS_WAIT4 => ("wait4", &[Int, Hex, Hex, Hex]),
+        SYS_PRLIMIT64 => ("prlimit64", &[Int, Int, Hex, Hex]),
+        SYS_RENAMEAT2 => ("renameat2", &[Fd, Str, Fd, Str, Hex]),
+        SYS_GETRANDOM => ("getrandom", &[Hex, Int, Hex]),
+        _ => return None,
+    })
+}
+
+/// Take a token from the bucket. Returns false if the line should be
+/// dropped.
+fn take_token() -> bool {
+    let now = cpu::get_mtime();
+    unsafe {
+        let refill = (now - LAST_REFILL) * LINES_PER_SEC / cpu::FREQ;
+        if refill > 0 {
+            TOKENS = (TOKENS + refill).min(BURST);
+            LAST_REFILL = now;
+        }
+        if TOKENS == 0 {
+            SUPPRESSED += 1;
+            return false;
+        }
+        TOKENS -= 1;
+        let suppressed = SUPPRESSED;
+        if suppressed > 0 {
+            println!("strace: {} lines suppressed", suppressed);
+            SUPPRESSED = 0;
+        }
+    }
+    true
+}
+
+/// Print a string argument, escaping anything that isn't printable. Long
+/// strings are cut short.
+fn print_str(frame: &TrapFrame, ptr: usize) {
+    const MAX: usize = 32;
+    let Ok(s) = syscall::read_user_str(frame, ptr, PATH_MAX) else {
+        print!("0x{:x}", ptr);
+        return;
+    };
+    print!("\"");
+    for &c in s.iter().take(MAX) {
+        match c {
+            b'"' | b'\\' => print!("\\{}", c as char),
+            b'\n' => print!("\\n"),
+            0x20..=0x7e => print!("{}", c as char),
+            _ => print!("\\x{:02x}", c),
+        }
+    }
+    print!("\"");
+    if s.len() > MAX {
+        print!("...");
+    }
+}
+
+/// Print the system call the process whose trap frame is `frame` has just
+/// made, and what it returned. `ret` is None for calls that don't return
+/// and for calls that blocked.
+pub fn trace(frame: &TrapFrame, ret: Option<isize>) {
+    if !process::get_by_pid(frame.pid).is_some_and(|p| p.trace) || !take_token() {
+        return;
+    }
+    let number = syscall::arg(frame, 7);
+    print!("[{}] ", frame.pid);
+    match describe(number) {
+        Some((name, args)) => {
+            print!("{}(", name);
+            for (i, &kind) in args.iter().enumerate() {
+                if i > 0 {
+                    print!(", ");
+                }
+                let val = syscall::arg(frame, i);
+                match kind {
+                    Int => print!("{}", val as isize),
+                    Hex => print!("0x{:x}", val),
+                    Fd if val as isize == AT_FDCWD => print!("AT_FDCWD"),
+                    Fd => print!("{}", val as isize),
+                    Str => print_str(frame, val),
+                }
+            }
+            print!(")");
+        }
+        None => print!(
+            "syscall_{}(0x{:x}, 0x{:x}, 0x{:x})",
+            number,
+            syscall::arg(frame, 0),
+            syscall::arg(frame, 1),
+            syscall::arg(frame, 2)
+        ),
+    }
+    match ret {
+        Some(ret) if ret < 0 => println!(" = {} ({})", ret, errno_name(-ret)),
+        Some(ret) if ret > 0xffff => println!(" = 0x{:x}", ret),
+        Some(ret) => println!(" = {}", ret),
+        None => println!(" = ?"),
+    }
+}
+
+/// The symbolic name of an error number.
+pub fn errno_name(errno: isize) -> &'static str {
+    match errno {
+        EPERM => "EPERM",
+        ENOENT => "ENOENT",
+        ESRCH => "ESRCH",
+        EINTR => "EINTR",
+        EIO => "EIO",
+        ENXIO => "ENXIO",
+        EBADF => "EBADF",
+        ECHILD => "ECHILD",
+        EAGAIN => "EAGAIN",
+        ENOMEM => "ENOMEM",
+        EACCES => "EACCES",
+        EFAULT => "EFAULT",
+        ENOTBLK => "ENOTBLK",
+        EBUSY => "EBUSY",
+        EEXIST => "EEXIST",
+        EXDEV => "EXDEV",
+        ENODEV => "ENODEV",
+        ENOTDIR => "ENOTDIR",
+        EISDIR => "EISDIR",
+        EINVAL => "EINVAL",
+        EMFILE => "EMFILE",
+        ENOTTY => "ENOTTY",
+        EFBIG => "EFBIG",
+        ENOSPC => "ENOSPC",
+        ESPIPE => "ESPIPE",
+        EROFS => "EROFS",
+        EMLINK => "EMLINK",
+        EPIPE => "EPIPE",
+        ERANGE => "ERANGE",
+        EDEADLK => "EDEADLK",
+        ENAMETOOLONG => "ENAMETOOLONG",
+        ENOSYS => "ENOSYS",
+        ENOTEMPTY => "ENOTEMPTY",
+        ELOOP => "ELOOP",
+        ENOTSOCK => "ENOTSOCK",
+        EDESTADDRREQ => "EDESTADDRREQ",
+        EMSGSIZE => "EMSGSIZE",
+        EPROTONOSUPPORT => "EPROTONOSUPPORT",
+        EOPNOTSUPP => "EOPNOTSUPP",
+        EAFNOSUPPORT => "EAFNOSUPPORT",
+        EADDRINUSE => "EADDRINUSE",
+        ENETUNREACH => "ENETUNREACH",
+        ENOTCONN => "ENOTCONN",
+        _ => "?",
+    }
+}
+
+/// Turn tracing of the process `pid` on or off. Returns false if there is
+/// no such process.
+pub fn set_trace(pid: usize, on: bool) -> bool {
+    match process::get_by_pid(pid) {
+        Some(p) => {
+            p.trace = on;
+            true
+        }
+        None => false,
+    }
+}
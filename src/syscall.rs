@@ -0,0 +1,1811 @@
+// System calls. A process makes a system call by putting the syscall number
+// in a7, the arguments in a0-a5 and executing ecall. The result is returned
+// in a0; errors are returned as a negated errno value. The numbers are the
+// ones Linux uses on RISC-V.
+
+use crate::{
+    bcache, block,
+    cpu::{self, gp, Registers, TrapFrame},
+    entropy,
+    file::{self, OpenFile, O_CLOEXEC, O_NONBLOCK, O_RDONLY, O_RDWR, O_WRONLY, S_IFMT, S_IFREG},
+    ktrace::{self, Kind},
+    lockdep,
+    locks::{self, Lock, Owner, F_RDLCK, F_UNLCK, F_WRLCK, LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN},
+    net::{self, Endpoint, SockAddrIn, UdpSocket},
+    page::{self, EntryBits, Table, PAGE_SIZE},
+    pipe, power,
+    process::{self, Cred, FileMap, Process, ProcessState, WaitResult},
+    sched, shm, strace, timer, vfs,
+};
+use alloc::{rc::Rc, vec, vec::Vec};
+use core::{
+    mem::{size_of, MaybeUninit},
+    ptr::addr_of_mut,
+    slice,
+};
+use log::warn;
+
+pub const SYS_GETCWD: usize = 17;
+pub const SYS_DUP: usize = 23;
+pub const SYS_DUP3: usize = 24;
+pub const SYS_FCNTL: usize = 25;
+pub const SYS_IOCTL: usize = 29;
+pub const SYS_FLOCK: usize = 32;
+pub const SYS_MKDIRAT: usize = 34;
+pub const SYS_UNLINKAT: usize = 35;
+pub const SYS_SYMLINKAT: usize = 36;
+pub const SYS_LINKAT: usize = 37;
+pub const SYS_UMOUNT2: usize = 39;
+pub const SYS_MOUNT: usize = 40;
+pub const SYS_FTRUNCATE: usize = 46;
+pub const SYS_FACCESSAT: usize = 48;
+pub const SYS_CHDIR: usize = 49;
+pub const SYS_FCHMODAT: usize = 53;
+pub const SYS_FCHOWNAT: usize = 54;
+pub const SYS_OPENAT: usize = 56;
+pub const SYS_CLOSE: usize = 57;
+pub const SYS_PIPE2: usize = 59;
+pub const SYS_GETDENTS64: usize = 61;
+pub const SYS_LSEEK: usize = 62;
+pub const SYS_READ: usize = 63;
+pub const SYS_WRITE: usize = 64;
+pub const SYS_READV: usize = 65;
+pub const SYS_WRITEV: usize = 66;
+pub const SYS_PREAD64: usize = 67;
+pub const SYS_PWRITE64: usize = 68;
+pub const SYS_READLINKAT: usize = 78;
+pub const SYS_NEWFSTATAT: usize = 79;
+pub const SYS_FSTAT: usize = 80;
+pub const SYS_SYNC: usize = 81;
+pub const SYS_FSYNC: usize = 82;
+pub const SYS_FDATASYNC: usize = 83;
+pub const SYS_EXIT: usize = 93;
+pub const SYS_EXIT_GROUP: usize = 94;
+pub const SYS_SET_TID_ADDRESS: usize = 96;
+pub const SYS_SET_ROBUST_LIST: usize = 99;
+pub const SYS_NANOSLEEP: usize = 101;
+pub const SYS_CLOCK_SETTIME: usize = 112;
+pub const SYS_CLOCK_GETTIME: usize = 113;
+pub const SYS_RT_SIGACTION: usize = 134;
+pub const SYS_RT_SIGPROCMASK: usize = 135;
+pub const SYS_SCHED_YIELD: usize = 124;
+pub const SYS_REBOOT: usize = 142;
+pub const SYS_SETGID: usize = 144;
+pub const SYS_SETUID: usize = 146;
+pub const SYS_UNAME: usize = 160;
+pub const SYS_GETRLIMIT: usize = 163;
+pub const SYS_SETRLIMIT: usize = 164;
+pub const SYS_GETTIMEOFDAY: usize = 169;
+pub const SYS_GETPID: usize = 172;
+pub const SYS_GETPPID: usize = 173;
+pub const SYS_GETUID: usize = 174;
+pub const SYS_GETEUID: usize = 175;
+pub const SYS_GETGID: usize = 176;
+pub const SYS_GETEGID: usize = 177;
+pub const SYS_GETTID: usize = 178;
+pub const SYS_SHMGET: usize = 194;
+pub const SYS_SHMCTL: usize = 195;
+pub const SYS_SHMAT: usize = 196;
+pub const SYS_SHMDT: usize = 197;
+pub const SYS_SOCKET: usize = 198;
+pub const SYS_BIND: usize = 200;
+pub const SYS_CONNECT: usize = 203;
+pub const SYS_GETSOCKNAME: usize = 204;
+pub const SYS_GETPEERNAME: usize = 205;
+pub const SYS_SENDTO: usize = 206;
+pub const SYS_RECVFROM: usize = 207;
+pub const SYS_BRK: usize = 214;
+pub const SYS_MUNMAP: usize = 215;
+pub const SYS_MMAP: usize = 222;
+pub const SYS_MSYNC: usize = 227;
+pub const SYS_WAIT4: usize = 260;
+pub const SYS_PRLIMIT64: usize = 261;
+pub const SYS_RENAMEAT2: usize = 276;
+pub const SYS_GETRANDOM: usize = 278;
+
+// Error numbers
+pub const EPERM: isize = 1;
+pub const ENOENT: isize = 2;
+pub const ESRCH: isize = 3;
+pub const EINTR: isize = 4;
+pub const EIO: isize = 5;
+pub const ENXIO: isize = 6;
+pub const EBADF: isize = 9;
+pub const ECHILD: isize = 10;
+pub const EAGAIN: isize = 11;
+pub const ENOMEM: isize = 12;
+pub const EACCES: isize = 13;
+pub const EFAULT: isize = 14;
+pub const ENOTBLK: isize = 15;
+pub const EBUSY: isize = 16;
+pub const EEXIST: isize = 17;
+pub const EXDEV: isize = 18;
+pub const ENODEV: isize = 19;
+pub const ENOTDIR: isize = 20;
+pub const EISDIR: isize = 21;
+pub const EINVAL: isize = 22;
+pub const EMFILE: isize = 24;
+pub const ENOTTY: isize = 25;
+pub const EFBIG: isize = 27;
+pub const ENOSPC: isize = 28;
+pub const ESPIPE: isize = 29;
+pub const EROFS: isize = 30;
+pub const EMLINK: isize = 31;
+pub const EPIPE: isize = 32;
+pub const ERANGE: isize = 34;
+pub const EDEADLK: isize = 35;
+pub const ENAMETOOLONG: isize = 36;
+pub const ENOSYS: isize = 38;
+pub const ENOTEMPTY: isize = 39;
+pub const ELOOP: isize = 40;
+pub const ENOTSOCK: isize = 88;
+pub const EDESTADDRREQ: isize = 89;
+pub const EMSGSIZE: isize = 90;
+pub const EPROTONOSUPPORT: isize = 93;
+pub const EOPNOTSUPP: isize = 95;
+pub const EAFNOSUPPORT: isize = 97;
+pub const EADDRINUSE: isize = 98;
+pub const ENETUNREACH: isize = 101;
+pub const ENOTCONN: isize = 107;
+
+/// Why a system call didn't produce a value.
+pub enum SysError {
+    /// The call failed, a0 gets the negated error number.
+    Errno(isize),
+    /// The call has to wait for something. The process has already been
+    /// put to sleep, and since we don't move past the ecall, it will simply
+    /// make the same call again once it's woken up.
+    Block,
+}
+
+pub type SysResult = Result<isize, SysError>;
+
+use SysError::{Block, Errno};
+
+/// struct timespec
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TimeSpec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+/// struct timeval
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TimeVal {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+/// struct flock, for fcntl()'s locks
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Flock {
+    pub l_type: i16,
+    pub l_whence: i16,
+    pub l_start: i64,
+    pub l_len: i64,
+    pub l_pid: i32,
+}
+
+/// struct iovec
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct IoVec {
+    pub iov_base: usize,
+    pub iov_len: usize,
+}
+
+/// struct stat, as the generic Linux ABI (which RISC-V uses) lays it out.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_mode: u32,
+    pub st_nlink: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub st_rdev: u64,
+    pub __pad1: u64,
+    pub st_size: i64,
+    pub st_blksize: i32,
+    pub __pad2: i32,
+    pub st_blocks: i64,
+    pub st_atime: i64,
+    pub st_atime_nsec: u64,
+    pub st_mtime: i64,
+    pub st_mtime_nsec: u64,
+    pub st_ctime: i64,
+    pub st_ctime_nsec: u64,
+    pub __unused: [u32; 2],
+}
+
+const _: () = assert!(size_of::<Stat>() == 128);
+
+/// struct utsname
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct UtsName {
+    pub sysname: [u8; 65],
+    pub nodename: [u8; 65],
+    pub release: [u8; 65],
+    pub version: [u8; 65],
+    pub machine: [u8; 65],
+    pub domainname: [u8; 65],
+}
+
+/// struct rlimit
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RLimit {
+    pub rlim_cur: u64,
+    pub rlim_max: u64,
+}
+
+// Clock ids for clock_gettime
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const CLOCK_MONOTONIC_RAW: usize = 4;
+pub const CLOCK_REALTIME_COARSE: usize = 5;
+pub const CLOCK_MONOTONIC_COARSE: usize = 6;
+pub const CLOCK_BOOTTIME: usize = 7;
+
+// Protection and flags for mmap
+pub const PROT_READ: usize = 0x1;
+pub const PROT_WRITE: usize = 0x2;
+pub const PROT_EXEC: usize = 0x4;
+pub const MAP_SHARED: usize = 0x01;
+pub const MAP_PRIVATE: usize = 0x02;
+pub const MAP_FIXED: usize = 0x10;
+pub const MAP_ANONYMOUS: usize = 0x20;
+// msync flags
+pub const MS_ASYNC: usize = 1;
+pub const MS_INVALIDATE: usize = 2;
+pub const MS_SYNC: usize = 4;
+
+// Options for wait4
+pub const WNOHANG: usize = 1;
+
+// Flags for getrandom
+pub const GRND_NONBLOCK: usize = 0x1;
+pub const GRND_RANDOM: usize = 0x2;
+pub const GRND_INSECURE: usize = 0x4;
+
+// Flags for sendto and recvfrom
+pub const MSG_DONTWAIT: usize = 0x40;
+
+// Resources for getrlimit/setrlimit
+pub const RLIMIT_DATA: usize = 2;
+
+/// Standard output. Every process starts with stdin, stdout and stderr
+/// connected to the console.
+pub const STDOUT: usize = 1;
+
+/// The *at calls look up relative paths from the current directory if
+/// they're given this instead of a directory file descriptor.
+pub const AT_FDCWD: isize = -100;
+// Flags for the *at calls
+pub const AT_SYMLINK_NOFOLLOW: usize = 0x100;
+pub const AT_REMOVEDIR: usize = 0x200;
+pub const AT_SYMLINK_FOLLOW: usize = 0x400;
+pub const AT_EMPTY_PATH: usize = 0x1000;
+
+/// The longest path we accept, including the terminating null.
+pub const PATH_MAX: usize = 4096;
+
+/// The size of struct rusage: two struct timevals and 14 longs.
+const RUSAGE_SIZE: usize = 2 * size_of::<TimeVal>() + 14 * 8;
+
+/// The most we read or write in one go, through a kernel buffer.
+const MAX_IO: usize = 64 * 1024;
+/// The most buffers readv and writev take.
+const IOV_MAX: usize = 1024;
+
+/// Handle the system call of the process whose trap frame is `frame` and
+/// return the program counter to go back to.
+pub fn do_syscall(mepc: usize, frame: *mut TrapFrame) -> usize {
+    let frame = unsafe { &mut *frame };
+    let syscall_number = frame.regs[gp(Registers::A7)];
+    ktrace::record(Kind::SyscallEnter, syscall_number, arg(frame, 0));
+    if syscall_number == SYS_EXIT || syscall_number == SYS_EXIT_GROUP {
+        // These don't come back, so trace them now.
+        strace::trace(frame, None);
+    }
+    // The files the call gets at are the process's to get at, as far as
+    // the VFS is concerned, until it's done.
+    vfs::set_cred(current(frame).cred);
+    lockdep::set_current(Some(frame.pid));
+    let ret = match syscall_number {
+        SYS_GETCWD => sys_getcwd(frame),
+        SYS_DUP => sys_dup(frame),
+        SYS_DUP3 => sys_dup3(frame),
+        SYS_FCNTL => sys_fcntl(frame),
+        SYS_IOCTL => sys_ioctl(frame),
+        SYS_FLOCK => sys_flock(frame),
+        SYS_MKDIRAT => sys_mkdirat(frame),
+        SYS_UNLINKAT => sys_unlinkat(frame),
+        SYS_SYMLINKAT => sys_symlinkat(frame),
+        SYS_LINKAT => sys_linkat(frame),
+        SYS_UMOUNT2 => sys_umount2(frame),
+        SYS_MOUNT => sys_mount(frame),
+        SYS_FTRUNCATE => sys_ftruncate(frame),
+        SYS_FACCESSAT => sys_faccessat(frame),
+        SYS_CHDIR => sys_chdir(frame),
+        SYS_FCHMODAT => sys_fchmodat(frame),
+        SYS_FCHOWNAT => sys_fchownat(frame),
+        SYS_OPENAT => sys_openat(frame),
+        SYS_CLOSE => sys_close(frame),
+        SYS_PIPE2 => sys_pipe2(frame),
+        SYS_GETDENTS64 => sys_getdents64(frame),
+        SYS_LSEEK => sys_lseek(frame),
+        SYS_READ => sys_read(frame, None),
+        SYS_WRITE => sys_write(frame, None),
+        SYS_READV => sys_readv(frame),
+        SYS_WRITEV => sys_writev(frame),
+        SYS_PREAD64 => offset_arg(frame, 3).and_then(|offset| sys_read(frame, Some(offset))),
+        SYS_PWRITE64 => offset_arg(frame, 3).and_then(|offset| sys_write(frame, Some(offset))),
+        SYS_READLINKAT => sys_readlinkat(frame),
+        SYS_NEWFSTATAT => sys_newfstatat(frame),
+        SYS_SYNC => sys_sync(frame),
+        SYS_FSYNC | SYS_FDATASYNC => sys_fsync(frame),
+        SYS_FSTAT => sys_fstat(frame),
+        SYS_EXIT | SYS_EXIT_GROUP => sys_exit(frame),
+        // There are no threads, so the thread id is the process id and
+        // nobody will ever look at the robust futex list.
+        SYS_SET_TID_ADDRESS | SYS_GETTID => Ok(frame.pid as isize),
+        SYS_SET_ROBUST_LIST => Ok(0),
+        SYS_NANOSLEEP => sys_nanosleep(frame),
+        SYS_CLOCK_SETTIME => sys_clock_settime(frame),
+        SYS_CLOCK_GETTIME => sys_clock_gettime(frame),
+        // Signals can't be delivered yet, so handlers and masks make no
+        // difference. Accept them so that C runtimes can start up.
+        SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => Ok(0),
+        // Answered once it's our turn again, see below.
+        SYS_SCHED_YIELD => Ok(0),
+        SYS_REBOOT => sys_reboot(frame),
+        SYS_SETGID => sys_setgid(frame),
+        SYS_SETUID => sys_setuid(frame),
+        SYS_UNAME => sys_uname(frame),
+        SYS_GETRLIMIT => sys_prlimit(frame, 0, arg(frame, 0), 0, arg(frame, 1)),
+        SYS_SETRLIMIT => sys_prlimit(frame, 0, arg(frame, 0), arg(frame, 1), 0),
+        SYS_GETTIMEOFDAY => sys_gettimeofday(frame),
+        SYS_GETPID => Ok(frame.pid as isize),
+        SYS_GETPPID => Ok(current(frame).ppid() as isize),
+        // There are no real ids apart from the effective ones.
+        SYS_GETUID | SYS_GETEUID => Ok(current(frame).cred.uid as isize),
+        SYS_GETGID | SYS_GETEGID => Ok(current(frame).cred.gid as isize),
+        SYS_SHMGET => shm::get(arg(frame, 0), arg(frame, 1), arg(frame, 2)).map(|id| id as isize),
+        SYS_SHMCTL => sys_shmctl(frame),
+        SYS_SHMAT => sys_shmat(frame),
+        SYS_SHMDT => sys_shmdt(frame),
+        SYS_SOCKET => sys_socket(frame),
+        SYS_BIND => sys_bind(frame),
+        SYS_CONNECT => sys_connect(frame),
+        SYS_GETSOCKNAME => sys_getsockname(frame, false),
+        SYS_GETPEERNAME => sys_getsockname(frame, true),
+        SYS_SENDTO => sys_sendto(frame),
+        SYS_RECVFROM => sys_recvfrom(frame),
+        SYS_BRK => sys_brk(frame),
+        SYS_MUNMAP => sys_munmap(frame),
+        SYS_MMAP => sys_mmap(frame),
+        SYS_MSYNC => sys_msync(frame),
+        SYS_WAIT4 => sys_wait4(frame),
+        SYS_RENAMEAT2 => sys_renameat2(frame),
+        SYS_GETRANDOM => sys_getrandom(frame),
+        SYS_PRLIMIT64 => sys_prlimit(
+            frame,
+            arg(frame, 0),
+            arg(frame, 1),
+            arg(frame, 2),
+            arg(frame, 3),
+        ),
+        _ => {
+            warn_unknown(frame.pid, syscall_number);
+            Err(Errno(ENOSYS))
+        }
+    };
+    vfs::set_cred(Cred::ROOT);
+    lockdep::set_current(None);
+    let answer = match ret {
+        Ok(ret) => Some(ret),
+        Err(Errno(errno)) => Some(-errno),
+        Err(Block) => None,
+    };
+    strace::trace(frame, answer);
+    match answer {
+        Some(ret) => ktrace::record(Kind::SyscallExit, syscall_number, ret as usize),
+        None => ktrace::record(Kind::SyscallBlock, syscall_number, 0),
+    }
+    match ret {
+        Ok(ret) => {
+            frame.regs[gp(Registers::A0)] = ret as usize;
+            if syscall_number == SYS_SCHED_YIELD {
+                // Let whoever is next run first, and come back after the
+                // ecall when it's our turn again.
+                frame.pc = mepc + 4;
+                cpu::switch_to(sched::schedule());
+            }
+            // Skip over the ecall instruction, it is always 4 bytes since
+            // we don't use compressed instructions.
+            mepc + 4
+        }
+        Err(Errno(errno)) => {
+            frame.regs[gp(Registers::A0)] = -errno as usize;
+            mepc + 4
+        }
+        Err(Block) => cpu::switch_to(sched::schedule()),
+    }
+}
+
+pub fn arg(frame: &TrapFrame, n: usize) -> usize {
+    frame.regs[gp(Registers::A0) + n]
+}
+
+/// Argument `n` as an offset in a file, an off_t, which can't be negative.
+fn offset_arg(frame: &TrapFrame, n: usize) -> Result<usize, SysError> {
+    let offset = arg(frame, n);
+    if offset > isize::MAX as usize {
+        return Err(Errno(EINVAL));
+    }
+    Ok(offset)
+}
+
+// When we last warned about an unknown syscall, and how many we've kept
+// quiet about since.
+static mut UNKNOWN_WARNED: Option<u64> = None;
+static mut UNKNOWN_MISSED: usize = 0;
+
+/// Warn that `pid` made a syscall we don't have, at most once a second. A
+/// program that tries one after another to see what's there, or one in a
+/// loop, would flood the console otherwise.
+fn warn_unknown(pid: usize, number: usize) {
+    let now = cpu::get_mtime();
+    let (warned, missed) = unsafe {
+        (
+            &mut *addr_of_mut!(UNKNOWN_WARNED),
+            &mut *addr_of_mut!(UNKNOWN_MISSED),
+        )
+    };
+    if warned.is_some_and(|at| now - at < cpu::FREQ) {
+        *missed += 1;
+        return;
+    }
+    if *missed > 0 {
+        warn!(
+            "pid {}: unknown syscall {} ({} more not shown)",
+            pid, number, missed
+        );
+    } else {
+        warn!("pid {}: unknown syscall {}", pid, number);
+    }
+    *warned = Some(now);
+    *missed = 0;
+}
+
+pub fn current(frame: &TrapFrame) -> &'static mut Process {
+    process::get_by_pid(frame.pid).expect("syscall from unknown process")
+}
+
+/// What only root may do is EPERM for everyone else.
+fn need_root(frame: &TrapFrame) -> Result<(), SysError> {
+    if !current(frame).cred.is_root() {
+        return Err(Errno(EPERM));
+    }
+    Ok(())
+}
+
+// ///////////////////////////////////
+// / USER MEMORY ACCESS
+// ///////////////////////////////////
+
+/// Call `f` on each physically contiguous piece of the process's memory
+/// at [ptr, ptr + len), in order. The whole range is checked to be mapped
+/// with the permissions we need before `f` is called for the first time.
+/// Pages of a memory mapping that haven't been touched yet are populated
+/// on the way, just like the page fault handler would. Kernel processes
+/// share our address space, so their pointers are used as they are.
+fn for_each_user_chunk(
+    frame: &TrapFrame,
+    ptr: usize,
+    len: usize,
+    write: bool,
+    mut f: impl FnMut(&mut [u8]),
+) -> Result<(), SysError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = ptr.checked_add(len).ok_or(Errno(EFAULT))?;
+    if ptr == 0 {
+        return Err(Errno(EFAULT));
+    }
+    let root = cpu::satp_root(frame.satp) as *mut Table;
+    if root.is_null() {
+        f(unsafe { slice::from_raw_parts_mut(ptr as *mut u8, len) });
+        return Ok(());
+    }
+    let root = unsafe { &mut *root };
+    let access = if write {
+        EntryBits::WRITE
+    } else {
+        EntryBits::READ
+    };
+    let mut vaddr = ptr & !(PAGE_SIZE - 1);
+    while vaddr < end {
+        match page::walk(root, vaddr) {
+            Some(entry) if entry.bits().contains(EntryBits::USER | access) => {}
+            // Not populated yet, or a page of a shared file mapping that
+            // hasn't been written to.
+            _ => {
+                if !current(frame).handle_page_fault(vaddr, access) {
+                    return Err(Errno(EFAULT));
+                }
+            }
+        }
+        vaddr += PAGE_SIZE;
+    }
+    let mut vaddr = ptr;
+    while vaddr < end {
+        let chunk = (PAGE_SIZE - (vaddr & (PAGE_SIZE - 1))).min(end - vaddr);
+        let paddr = page::virt_to_phys(root, vaddr).ok_or(Errno(EFAULT))?;
+        f(unsafe { slice::from_raw_parts_mut(paddr as *mut u8, chunk) });
+        vaddr += chunk;
+    }
+    Ok(())
+}
+
+/// Copy `src` into the process's memory at `ptr`.
+fn copy_to_user(frame: &TrapFrame, ptr: usize, src: &[u8]) -> Result<(), SysError> {
+    let mut done = 0;
+    for_each_user_chunk(frame, ptr, src.len(), true, |chunk| {
+        chunk.copy_from_slice(&src[done..done + chunk.len()]);
+        done += chunk.len();
+    })
+}
+
+/// Fill `dst` from the process's memory at `ptr`.
+fn copy_from_user(frame: &TrapFrame, ptr: usize, dst: &mut [u8]) -> Result<(), SysError> {
+    let mut done = 0;
+    for_each_user_chunk(frame, ptr, dst.len(), false, |chunk| {
+        dst[done..done + chunk.len()].copy_from_slice(chunk);
+        done += chunk.len();
+    })
+}
+
+/// Copy the null-terminated string at `ptr` out of the process's memory,
+/// without the null.
+pub fn read_user_str(frame: &TrapFrame, ptr: usize, max: usize) -> Result<Vec<u8>, SysError> {
+    let mut s = Vec::new();
+    let mut done = false;
+    let mut vaddr = ptr;
+    // Go page by page, the string may end right before an unmapped one.
+    while !done {
+        if s.len() >= max {
+            return Err(Errno(ENAMETOOLONG));
+        }
+        let chunk = (PAGE_SIZE - (vaddr & (PAGE_SIZE - 1))).min(max - s.len());
+        for_each_user_chunk(frame, vaddr, chunk, false, |bytes| {
+            if done {
+                return;
+            }
+            match bytes.iter().position(|&c| c == 0) {
+                Some(n) => {
+                    s.extend_from_slice(&bytes[..n]);
+                    done = true;
+                }
+                None => s.extend_from_slice(bytes),
+            }
+        })?;
+        vaddr += chunk;
+    }
+    Ok(s)
+}
+
+/// Get the path argument of a *at call as an absolute path. A relative
+/// path with AT_FDCWD is from the current directory of the process, and
+/// with another `dirfd` from where that directory was when it was opened.
+fn user_path(frame: &TrapFrame, dirfd: usize, ptr: usize) -> Result<Vec<u8>, SysError> {
+    let path = read_user_str(frame, ptr, PATH_MAX)?;
+    if path.is_empty() {
+        return Err(Errno(ENOENT));
+    }
+    if path.starts_with(b"/") {
+        return Ok(path);
+    }
+    let prc = current(frame);
+    let dir = if dirfd as isize == AT_FDCWD {
+        &prc.cwd[..]
+    } else {
+        prc.files.get(dirfd)?.dir().ok_or(Errno(ENOTDIR))?
+    };
+    let mut abs = Vec::with_capacity(dir.len() + path.len() + 1);
+    abs.extend_from_slice(dir);
+    abs.push(b'/');
+    abs.extend_from_slice(&path);
+    Ok(abs)
+}
+
+/// Read the array of `count` iovecs at `ptr`.
+fn read_iovecs(frame: &TrapFrame, ptr: usize, count: usize) -> Result<Vec<IoVec>, SysError> {
+    if count > IOV_MAX {
+        return Err(Errno(EINVAL));
+    }
+    (0..count)
+        .map(|i| {
+            let at = i
+                .checked_mul(size_of::<IoVec>())
+                .and_then(|off| ptr.checked_add(off))
+                .ok_or(Errno(EFAULT))?;
+            read_user::<IoVec>(frame, at)
+        })
+        .collect()
+}
+
+/// Read a plain-old-data structure from the process's memory.
+pub fn read_user<T: Copy>(frame: &TrapFrame, ptr: usize) -> Result<T, SysError> {
+    let mut val = MaybeUninit::<T>::zeroed();
+    let bytes = unsafe { slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_user(frame, ptr, bytes)?;
+    Ok(unsafe { val.assume_init() })
+}
+
+/// Write a plain-old-data structure into the process's memory.
+pub fn write_user<T: Copy>(frame: &TrapFrame, ptr: usize, val: &T) -> Result<(), SysError> {
+    let bytes = unsafe { slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(frame, ptr, bytes)
+}
+
+// ///////////////////////////////////
+// / SYSTEM CALLS
+// ///////////////////////////////////
+
+/// dup(oldfd)
+fn sys_dup(frame: &mut TrapFrame) -> SysResult {
+    let files = &mut current(frame).files;
+    let file = files.get(arg(frame, 0))?.clone();
+    Ok(files.insert(file)? as isize)
+}
+
+/// dup3(oldfd, newfd, flags)
+/// This is also how dup2 is done on RISC-V, the C library takes care of
+/// the oldfd == newfd case.
+fn sys_dup3(frame: &mut TrapFrame) -> SysResult {
+    let (oldfd, newfd, flags) = (arg(frame, 0), arg(frame, 1), arg(frame, 2));
+    if oldfd == newfd || flags & !O_CLOEXEC != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let files = &mut current(frame).files;
+    let file = files.get(oldfd)?.clone();
+    files.insert_at(newfd, file)?;
+    Ok(newfd as isize)
+}
+
+/// pipe2(pipefd, flags)
+fn sys_pipe2(frame: &mut TrapFrame) -> SysResult {
+    let (pipefd, flags) = (arg(frame, 0), arg(frame, 1));
+    if flags & !(O_CLOEXEC | O_NONBLOCK) != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let (reader, writer) = pipe::new();
+    let files = &mut current(frame).files;
+    let rfd = files.insert(Rc::new(OpenFile::new(Rc::new(reader), O_RDONLY | flags)))?;
+    let wfd = match files.insert(Rc::new(OpenFile::new(Rc::new(writer), O_WRONLY | flags))) {
+        Ok(fd) => fd,
+        Err(e) => {
+            let _ = files.close(rfd);
+            return Err(e);
+        }
+    };
+    if let Err(e) = write_user(frame, pipefd, &[rfd as i32, wfd as i32]) {
+        let files = &mut current(frame).files;
+        let _ = files.close(rfd);
+        let _ = files.close(wfd);
+        return Err(e);
+    }
+    Ok(0)
+}
+
+/// ioctl(fd, cmd, arg)
+/// Requests are handled by whatever is behind the file.
+fn sys_ioctl(frame: &mut TrapFrame) -> SysResult {
+    let file = current(frame).files.get(arg(frame, 0))?.clone();
+    file.file().ioctl(frame, arg(frame, 1), arg(frame, 2))
+}
+
+/// fcntl(fd, cmd, arg)
+/// Only the record locks are there: F_GETLK, F_SETLK and F_SETLKW, which
+/// waits for the lock (see locks.rs).
+fn sys_fcntl(frame: &mut TrapFrame) -> SysResult {
+    const F_GETLK: usize = 5;
+    const F_SETLK: usize = 6;
+    const F_SETLKW: usize = 7;
+    let (cmd, ptr) = (arg(frame, 1), arg(frame, 2));
+    let file = current(frame).files.get(arg(frame, 0))?.clone();
+    if !matches!(cmd, F_GETLK | F_SETLK | F_SETLKW) {
+        return Err(Errno(EINVAL));
+    }
+    let mut fl = read_user::<Flock>(frame, ptr)?;
+    let base = match fl.l_whence as usize {
+        file::SEEK_SET => 0,
+        file::SEEK_CUR => file.offset(),
+        file::SEEK_END => file.file().size().ok_or(Errno(EINVAL))?,
+        _ => return Err(Errno(EINVAL)),
+    } as i64;
+    let start = base.checked_add(fl.l_start).ok_or(Errno(EINVAL))?;
+    // A length of 0 is up to wherever the file ends, a negative one is the
+    // bytes before start.
+    let (start, end) = match fl.l_len {
+        0 => (start, None),
+        len if len > 0 => (start, Some(start.saturating_add(len))),
+        len => (start.checked_add(len).ok_or(Errno(EINVAL))?, Some(start)),
+    };
+    if start < 0 {
+        return Err(Errno(EINVAL));
+    }
+    let (start, end) = (start as u64, end.map_or(u64::MAX, |end| end as u64));
+    let key = locks::key(&file.file().stat())?;
+    let owner = Owner::Process(frame.pid);
+    let lock = Lock {
+        owner,
+        pid: frame.pid,
+        write: fl.l_type == F_WRLCK,
+        start,
+        end,
+    };
+    match fl.l_type {
+        F_RDLCK | F_WRLCK => {}
+        F_UNLCK if cmd != F_GETLK => {
+            locks::unlock(key, owner, start, end);
+            return Ok(0);
+        }
+        _ => return Err(Errno(EINVAL)),
+    }
+    if cmd == F_GETLK {
+        match locks::conflict(key, &lock) {
+            Some(held) => {
+                fl.l_type = if held.write { F_WRLCK } else { F_RDLCK };
+                fl.l_whence = file::SEEK_SET as i16;
+                fl.l_start = held.start as i64;
+                fl.l_len = if held.end == u64::MAX {
+                    0
+                } else {
+                    (held.end - held.start) as i64
+                };
+                fl.l_pid = held.pid as i32;
+            }
+            None => fl.l_type = F_UNLCK,
+        }
+        write_user(frame, ptr, &fl)?;
+        return Ok(0);
+    }
+    if (lock.write && !file.writable()) || (!lock.write && !file.readable()) {
+        return Err(Errno(EBADF));
+    }
+    locks::set(key, lock, cmd == F_SETLKW)?;
+    Ok(0)
+}
+
+/// flock(fd, operation)
+/// The lock is the open file's, so dup()ed file descriptors share it.
+fn sys_flock(frame: &mut TrapFrame) -> SysResult {
+    let op = arg(frame, 1);
+    let file = current(frame).files.get(arg(frame, 0))?.clone();
+    let key = locks::key(&file.file().stat())?;
+    let owner = Owner::File(Rc::as_ptr(&file) as usize);
+    let write = match op & !LOCK_NB {
+        LOCK_SH => false,
+        LOCK_EX => true,
+        LOCK_UN => {
+            locks::unlock(key, owner, 0, u64::MAX);
+            return Ok(0);
+        }
+        _ => return Err(Errno(EINVAL)),
+    };
+    let lock = Lock {
+        owner,
+        pid: frame.pid,
+        write,
+        start: 0,
+        end: u64::MAX,
+    };
+    locks::set(key, lock, op & LOCK_NB == 0)?;
+    Ok(0)
+}
+
+/// mkdirat(dirfd, path, mode)
+fn sys_mkdirat(frame: &mut TrapFrame) -> SysResult {
+    let path = user_path(frame, arg(frame, 0), arg(frame, 1))?;
+    vfs::mkdir(&path, arg(frame, 2) as u32)?;
+    Ok(0)
+}
+
+/// unlinkat(dirfd, path, flags)
+/// With AT_REMOVEDIR this is rmdir, without it unlink.
+fn sys_unlinkat(frame: &mut TrapFrame) -> SysResult {
+    let flags = arg(frame, 2);
+    if flags & !AT_REMOVEDIR != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let path = user_path(frame, arg(frame, 0), arg(frame, 1))?;
+    vfs::unlink(&path, flags & AT_REMOVEDIR != 0)?;
+    Ok(0)
+}
+
+/// linkat(olddirfd, oldpath, newdirfd, newpath, flags)
+/// A symlink gets another name itself, unless AT_SYMLINK_FOLLOW.
+fn sys_linkat(frame: &mut TrapFrame) -> SysResult {
+    let flags = arg(frame, 4);
+    if flags & !AT_SYMLINK_FOLLOW != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let old = user_path(frame, arg(frame, 0), arg(frame, 1))?;
+    let new = user_path(frame, arg(frame, 2), arg(frame, 3))?;
+    vfs::link(&old, &new, flags & AT_SYMLINK_FOLLOW != 0)?;
+    Ok(0)
+}
+
+/// renameat2(olddirfd, oldpath, newdirfd, newpath, flags)
+/// This is also rename and renameat, which RISC-V doesn't have. Only
+/// RENAME_NOREPLACE is there; swapping two files isn't.
+fn sys_renameat2(frame: &mut TrapFrame) -> SysResult {
+    const RENAME_NOREPLACE: usize = 1;
+    let flags = arg(frame, 4);
+    if flags & !RENAME_NOREPLACE != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let old = user_path(frame, arg(frame, 0), arg(frame, 1))?;
+    let new = user_path(frame, arg(frame, 2), arg(frame, 3))?;
+    vfs::rename(&old, &new, flags & RENAME_NOREPLACE != 0)?;
+    Ok(0)
+}
+
+/// ftruncate(fd, length)
+fn sys_ftruncate(frame: &mut TrapFrame) -> SysResult {
+    let file = current(frame).files.get(arg(frame, 0))?.clone();
+    let len = arg(frame, 1) as isize;
+    if len < 0 || !file.writable() {
+        return Err(Errno(EINVAL));
+    }
+    file.file().truncate(len as usize)?;
+    Ok(0)
+}
+
+/// openat(dirfd, path, flags, mode)
+fn sys_openat(frame: &mut TrapFrame) -> SysResult {
+    let path = user_path(frame, arg(frame, 0), arg(frame, 1))?;
+    let file = file::open(&path, arg(frame, 2), arg(frame, 3))?;
+    let fd = current(frame).files.insert(file)?;
+    Ok(fd as isize)
+}
+
+/// close(fd)
+/// Closing any file descriptor of a file lets go of the process's fcntl()
+/// locks on it, if it's a file that can have them.
+fn sys_close(frame: &mut TrapFrame) -> SysResult {
+    let prc = current(frame);
+    let key = locks::key(&prc.files.get(arg(frame, 0))?.file().stat());
+    prc.files.close(arg(frame, 0))?;
+    if let Ok(key) = key {
+        locks::closed(frame.pid, key);
+    }
+    Ok(0)
+}
+
+/// getdents64(fd, dirp, count)
+fn sys_getdents64(frame: &mut TrapFrame) -> SysResult {
+    let file = current(frame).files.get(arg(frame, 0))?.clone();
+    let (ptr, len) = (arg(frame, 1), arg(frame, 2).min(MAX_IO));
+    for_each_user_chunk(frame, ptr, len, true, |_| {})?;
+    let mut buf = vec![0u8; len];
+    let n = file.getdents(&mut buf)?;
+    copy_to_user(frame, ptr, &buf[..n])?;
+    Ok(n as isize)
+}
+
+/// lseek(fd, offset, whence)
+fn sys_lseek(frame: &mut TrapFrame) -> SysResult {
+    let file = current(frame).files.get(arg(frame, 0))?;
+    let offset = file.seek(arg(frame, 1) as isize, arg(frame, 2))?;
+    Ok(offset as isize)
+}
+
+/// read(fd, buf, count) and pread64(fd, buf, count, offset)
+/// If there's nothing to read yet (the console only hands out whole
+/// lines), the call blocks until there is.
+fn sys_read(frame: &mut TrapFrame, offset: Option<usize>) -> SysResult {
+    let file = current(frame).files.get(arg(frame, 0))?.clone();
+    let (ptr, len) = (arg(frame, 1), arg(frame, 2).min(MAX_IO));
+    if len == 0 {
+        return Ok(0);
+    }
+    // Make sure we can store the data before we take it from the file.
+    for_each_user_chunk(frame, ptr, len, true, |_| {})?;
+    let mut buf = vec![0u8; len];
+    let ret = match offset {
+        Some(offset) => file.read_at(offset, &mut buf),
+        None => file.read(&mut buf),
+    };
+    match ret {
+        Ok(n) => {
+            copy_to_user(frame, ptr, &buf[..n])?;
+            Ok(n as isize)
+        }
+        Err(Block) => {
+            file.file().wait(frame.pid);
+            Err(Block)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// write(fd, buf, count) and pwrite64(fd, buf, count, offset)
+fn sys_write(frame: &mut TrapFrame, offset: Option<usize>) -> SysResult {
+    let file = current(frame).files.get(arg(frame, 0))?.clone();
+    let (ptr, len) = (arg(frame, 1), arg(frame, 2).min(MAX_IO));
+    let mut buf = vec![0u8; len];
+    copy_from_user(frame, ptr, &mut buf)?;
+    let ret = match offset {
+        Some(offset) => file.write_at(offset, &buf),
+        None => file.write(&buf),
+    };
+    match ret {
+        Ok(n) => Ok(n as isize),
+        Err(Block) => {
+            file.file().wait(frame.pid);
+            Err(Block)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// readv(fd, iov, iovcnt)
+/// This is a single read into a kernel buffer, which is then scattered
+/// over the buffers.
+fn sys_readv(frame: &mut TrapFrame) -> SysResult {
+    let file = current(frame).files.get(arg(frame, 0))?.clone();
+    let iov = read_iovecs(frame, arg(frame, 1), arg(frame, 2))?;
+    let mut len = 0usize;
+    for v in iov.iter() {
+        for_each_user_chunk(frame, v.iov_base, v.iov_len, true, |_| {})?;
+        len = len.saturating_add(v.iov_len);
+    }
+    let len = len.min(MAX_IO);
+    if len == 0 {
+        return Ok(0);
+    }
+    let mut buf = vec![0u8; len];
+    let n = match file.read(&mut buf) {
+        Ok(n) => n,
+        Err(Block) => {
+            file.file().wait(frame.pid);
+            return Err(Block);
+        }
+        Err(e) => return Err(e),
+    };
+    let mut done = 0;
+    for v in iov.iter() {
+        if done == n {
+            break;
+        }
+        let chunk = v.iov_len.min(n - done);
+        copy_to_user(frame, v.iov_base, &buf[done..done + chunk])?;
+        done += chunk;
+    }
+    Ok(n as isize)
+}
+
+/// writev(fd, iov, iovcnt)
+/// The buffers are gathered into one kernel buffer, so the data is written
+/// in a single piece.
+fn sys_writev(frame: &mut TrapFrame) -> SysResult {
+    let file = current(frame).files.get(arg(frame, 0))?.clone();
+    let iov = read_iovecs(frame, arg(frame, 1), arg(frame, 2))?;
+    let mut buf = Vec::new();
+    for v in iov.iter() {
+        let len = v.iov_len.min(MAX_IO - buf.len());
+        let start = buf.len();
+        buf.resize(start + len, 0);
+        copy_from_user(frame, v.iov_base, &mut buf[start..])?;
+    }
+    match file.write(&buf) {
+        Ok(n) => Ok(n as isize),
+        Err(Block) => {
+            file.file().wait(frame.pid);
+            Err(Block)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// fstat(fd, statbuf)
+fn sys_fstat(frame: &mut TrapFrame) -> SysResult {
+    let st = current(frame).files.get(arg(frame, 0))?.file().stat();
+    write_user(frame, arg(frame, 1), &st)?;
+    Ok(0)
+}
+
+/// mount(source, target, fstype, flags, data)
+/// Only root may mount. Only MS_RDONLY does anything. The flags that only
+/// keep programs from doing things are taken and ignored; the ones that
+/// change what mount does aren't there. So is data: none of the
+/// filesystems have options.
+fn sys_mount(frame: &mut TrapFrame) -> SysResult {
+    const MS_RDONLY: usize = 1;
+    const MS_NOSUID: usize = 2;
+    const MS_NODEV: usize = 4;
+    const MS_NOEXEC: usize = 8;
+    const MS_NOATIME: usize = 1024;
+    const MS_SILENT: usize = 32768;
+    // Old programs put this in the high half of flags.
+    const MS_MGC_VAL: usize = 0xc0ed_0000;
+    const MS_MGC_MSK: usize = 0xffff_0000;
+    let mut flags = arg(frame, 3);
+    if flags & MS_MGC_MSK == MS_MGC_VAL {
+        flags &= !MS_MGC_MSK;
+    }
+    need_root(frame)?;
+    let known = MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC | MS_NOATIME | MS_SILENT;
+    if flags & !known != 0 {
+        return Err(Errno(EINVAL));
+    }
+    // Filesystems without a disk don't need a source.
+    let source = match arg(frame, 0) {
+        0 => Vec::new(),
+        ptr => read_user_str(frame, ptr, PATH_MAX)?,
+    };
+    let target = user_path(frame, AT_FDCWD as usize, arg(frame, 1))?;
+    let fstype = read_user_str(frame, arg(frame, 2), PATH_MAX)?;
+    vfs::do_mount(&source, &target, &fstype, flags & MS_RDONLY != 0)?;
+    Ok(0)
+}
+
+/// umount2(target, flags)
+/// Only root may unmount. MNT_FORCE and MNT_DETACH both take the filesystem out from under
+/// whoever is using it: nothing we have can be forced any further.
+fn sys_umount2(frame: &mut TrapFrame) -> SysResult {
+    const MNT_FORCE: usize = 1;
+    const MNT_DETACH: usize = 2;
+    const UMOUNT_NOFOLLOW: usize = 8;
+    need_root(frame)?;
+    let flags = arg(frame, 1);
+    if flags & !(MNT_FORCE | MNT_DETACH | UMOUNT_NOFOLLOW) != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let target = user_path(frame, AT_FDCWD as usize, arg(frame, 0))?;
+    let force = flags & (MNT_FORCE | MNT_DETACH) != 0;
+    vfs::umount(&target, flags & UMOUNT_NOFOLLOW == 0, force)?;
+    Ok(0)
+}
+
+/// sync()
+/// The disks are waited for, not slept on: there's no one disk to sleep
+/// on. It can't fail.
+fn sys_sync(_frame: &mut TrapFrame) -> SysResult {
+    let _ = block::with_sleep(false, || bcache::sync(None));
+    Ok(0)
+}
+
+/// fsync(fd) and fdatasync(fd)
+fn sys_fsync(frame: &mut TrapFrame) -> SysResult {
+    let file = current(frame).files.get(arg(frame, 0))?.file().clone();
+    match file.sync() {
+        Ok(()) => Ok(0),
+        Err(Block) => {
+            file.wait(frame.pid);
+            Err(Block)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// newfstatat(dirfd, path, statbuf, flags)
+/// With AT_SYMLINK_NOFOLLOW, this is lstat: a symlink at the end of the
+/// path is described, not what it points at.
+fn sys_newfstatat(frame: &mut TrapFrame) -> SysResult {
+    let (dirfd, ptr, statbuf, flags) = (arg(frame, 0), arg(frame, 1), arg(frame, 2), arg(frame, 3));
+    if flags & !(AT_SYMLINK_NOFOLLOW | AT_EMPTY_PATH) != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let st = if flags & AT_EMPTY_PATH != 0 && read_user_str(frame, ptr, PATH_MAX)?.is_empty() {
+        current(frame).files.get(dirfd)?.file().stat()
+    } else {
+        let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+        vfs::stat(&user_path(frame, dirfd, ptr)?, follow)?
+    };
+    write_user(frame, statbuf, &st)?;
+    Ok(0)
+}
+
+/// faccessat(dirfd, path, mode)
+/// Whether the process may do what `mode` says, R_OK, W_OK and X_OK, to
+/// the file, or with F_OK (0) whether there is one. The real ids are the
+/// effective ones, so it doesn't matter which are checked.
+fn sys_faccessat(frame: &mut TrapFrame) -> SysResult {
+    let mode = arg(frame, 2);
+    if mode & !7 != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let path = user_path(frame, arg(frame, 0), arg(frame, 1))?;
+    vfs::access(&path, mode as u32)?;
+    Ok(0)
+}
+
+/// fchmodat(dirfd, path, mode)
+/// There are no flags: symlinks don't have permissions of their own.
+fn sys_fchmodat(frame: &mut TrapFrame) -> SysResult {
+    let path = user_path(frame, arg(frame, 0), arg(frame, 1))?;
+    vfs::chmod(&path, arg(frame, 2) as u32)?;
+    Ok(0)
+}
+
+/// fchownat(dirfd, path, owner, group, flags)
+/// An owner or group of -1 is left as it is. With AT_SYMLINK_NOFOLLOW this
+/// is lchown.
+fn sys_fchownat(frame: &mut TrapFrame) -> SysResult {
+    let flags = arg(frame, 4);
+    if flags & !AT_SYMLINK_NOFOLLOW != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let id = |n| Some(arg(frame, n) as u32).filter(|&id| id != u32::MAX);
+    let (uid, gid) = (id(2), id(3));
+    let path = user_path(frame, arg(frame, 0), arg(frame, 1))?;
+    vfs::chown(&path, flags & AT_SYMLINK_NOFOLLOW == 0, uid, gid)?;
+    Ok(0)
+}
+
+/// symlinkat(target, newdirfd, linkpath)
+fn sys_symlinkat(frame: &mut TrapFrame) -> SysResult {
+    let target = read_user_str(frame, arg(frame, 0), PATH_MAX)?;
+    let path = user_path(frame, arg(frame, 1), arg(frame, 2))?;
+    vfs::symlink(&target, &path)?;
+    Ok(0)
+}
+
+/// readlinkat(dirfd, path, buf, bufsiz)
+/// Where the symlink points goes in buf, without a NUL after it, cut short
+/// if it doesn't fit.
+fn sys_readlinkat(frame: &mut TrapFrame) -> SysResult {
+    let (buf, len) = (arg(frame, 2), arg(frame, 3));
+    if len as isize <= 0 {
+        return Err(Errno(EINVAL));
+    }
+    let path = user_path(frame, arg(frame, 0), arg(frame, 1))?;
+    let mut target = vfs::resolve(&path, false)?.readlink()?;
+    target.truncate(len);
+    copy_to_user(frame, buf, &target)?;
+    Ok(target.len() as isize)
+}
+
+/// getcwd(buf, size)
+/// The path goes in buf with a NUL after it, and what's returned is its
+/// length with the NUL, as Linux's system call does. It's ERANGE if it
+/// doesn't fit.
+fn sys_getcwd(frame: &mut TrapFrame) -> SysResult {
+    let (buf, size) = (arg(frame, 0), arg(frame, 1));
+    let cwd = &current(frame).cwd;
+    let mut path = if cwd.is_empty() {
+        vec![b'/']
+    } else {
+        cwd.clone()
+    };
+    path.push(0);
+    if path.len() > size {
+        return Err(Errno(ERANGE));
+    }
+    copy_to_user(frame, buf, &path)?;
+    Ok(path.len() as isize)
+}
+
+/// chdir(path)
+fn sys_chdir(frame: &mut TrapFrame) -> SysResult {
+    let path = user_path(frame, AT_FDCWD as usize, arg(frame, 0))?;
+    current(frame).cwd = vfs::chdir(&path)?;
+    Ok(0)
+}
+
+/// exit(status) and exit_group(status)
+/// Processes only have one thread, so these are the same. They don't
+/// return, we move on to the next process instead.
+fn sys_exit(frame: &mut TrapFrame) -> ! {
+    process::exit(frame.pid, (arg(frame, 0) & 0xff) << 8);
+    vfs::set_cred(Cred::ROOT);
+    cpu::switch_to(sched::schedule())
+}
+
+/// wait4(pid, wstatus, options, rusage)
+/// Without process groups, 0 and negative pids all mean any child. We
+/// don't keep track of resource usage, so rusage is all zeros.
+fn sys_wait4(frame: &mut TrapFrame) -> SysResult {
+    let pid = arg(frame, 0) as isize;
+    let (wstatus, options, rusage) = (arg(frame, 1), arg(frame, 2), arg(frame, 3));
+    if options & !WNOHANG != 0 {
+        return Err(Errno(EINVAL));
+    }
+    let pid = if pid > 0 { Some(pid as usize) } else { None };
+    match process::reap(frame.pid, pid) {
+        WaitResult::NoChild => Err(Errno(ECHILD)),
+        WaitResult::Running if options & WNOHANG != 0 => Ok(0),
+        WaitResult::Running => {
+            process::wait_for_child(frame.pid);
+            Err(Block)
+        }
+        WaitResult::Exited { pid, status } => {
+            if wstatus != 0 {
+                write_user(frame, wstatus, &(status as i32))?;
+            }
+            if rusage != 0 {
+                copy_to_user(frame, rusage, &[0; RUSAGE_SIZE])?;
+            }
+            Ok(pid as isize)
+        }
+    }
+}
+
+/// nanosleep(req, rem)
+/// The process sleeps on a software timer until the requested time has
+/// elapsed. If it gets woken up before that, which only a signal can do, the
+/// call fails with EINTR and the time left is stored in `rem` (if not null).
+fn sys_nanosleep(frame: &mut TrapFrame) -> SysResult {
+    let prc = current(frame);
+    let now = cpu::get_mtime();
+    if prc.sleep_until == 0 {
+        // First time through, start sleeping.
+        let req = read_user::<TimeSpec>(frame, arg(frame, 0))?;
+        if req.tv_sec < 0 || !(0..timer::NANOS_PER_SEC as i64).contains(&req.tv_nsec) {
+            return Err(Errno(EINVAL));
+        }
+        let ticks = timer::duration_to_ticks(req.tv_sec as u64, req.tv_nsec as u64);
+        if ticks == 0 {
+            return Ok(0);
+        }
+        prc.sleep_until = now.saturating_add(ticks);
+        prc.sleep_timer = timer::add(prc.sleep_until, process::wake_sleeper, frame.pid);
+        prc.set_state(ProcessState::Sleeping);
+        return Err(Block);
+    }
+    // We've been woken up and made the call again.
+    let deadline = prc.sleep_until;
+    prc.sleep_until = 0;
+    if now >= deadline {
+        return Ok(0);
+    }
+    timer::cancel(prc.sleep_timer);
+    if arg(frame, 1) != 0 {
+        let (secs, nanos) = timer::ticks_to_duration(deadline - now);
+        let rem = TimeSpec {
+            tv_sec: secs as i64,
+            tv_nsec: nanos as i64,
+        };
+        write_user(frame, arg(frame, 1), &rem)?;
+    }
+    Err(Errno(EINTR))
+}
+
+/// clock_gettime(clockid, tp)
+fn sys_clock_gettime(frame: &mut TrapFrame) -> SysResult {
+    let ns = match arg(frame, 0) {
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => timer::realtime_ns(),
+        CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+            timer::monotonic_ns()
+        }
+        _ => return Err(Errno(EINVAL)),
+    };
+    let tp = TimeSpec {
+        tv_sec: (ns / timer::NANOS_PER_SEC) as i64,
+        tv_nsec: (ns % timer::NANOS_PER_SEC) as i64,
+    };
+    write_user(frame, arg(frame, 1), &tp)?;
+    Ok(0)
+}
+
+/// clock_settime(clockid, tp)
+/// Only the realtime clock can be set, and only by root.
+fn sys_clock_settime(frame: &mut TrapFrame) -> SysResult {
+    need_root(frame)?;
+    if arg(frame, 0) != CLOCK_REALTIME {
+        return Err(Errno(EINVAL));
+    }
+    let tp = read_user::<TimeSpec>(frame, arg(frame, 1))?;
+    if tp.tv_sec < 0 || !(0..timer::NANOS_PER_SEC as i64).contains(&tp.tv_nsec) {
+        return Err(Errno(EINVAL));
+    }
+    let ns = (tp.tv_sec as u64)
+        .checked_mul(timer::NANOS_PER_SEC)
+        .and_then(|ns| ns.checked_add(tp.tv_nsec as u64))
+        .ok_or(Errno(EINVAL))?;
+    timer::set_realtime_ns(ns);
+    Ok(0)
+}
+
+/// gettimeofday(tv, tz)
+/// The timezone is obsolete, we always report UTC by leaving it alone.
+fn sys_gettimeofday(frame: &mut TrapFrame) -> SysResult {
+    let ns = timer::realtime_ns();
+    if arg(frame, 0) != 0 {
+        let tv = TimeVal {
+            tv_sec: (ns / timer::NANOS_PER_SEC) as i64,
+            tv_usec: (ns % timer::NANOS_PER_SEC / 1000) as i64,
+        };
+        write_user(frame, arg(frame, 0), &tv)?;
+    }
+    Ok(0)
+}
+
+/// setuid(uid)
+/// Root can become anyone, and isn't root any more once it has; anyone
+/// else can only stay who they are.
+fn sys_setuid(frame: &mut TrapFrame) -> SysResult {
+    let (uid, cred) = (arg(frame, 0) as u32, &mut current(frame).cred);
+    if !cred.is_root() && uid != cred.uid {
+        return Err(Errno(EPERM));
+    }
+    cred.uid = uid;
+    Ok(0)
+}
+
+/// setgid(gid)
+/// Only root can change its group.
+fn sys_setgid(frame: &mut TrapFrame) -> SysResult {
+    let (gid, cred) = (arg(frame, 0) as u32, &mut current(frame).cred);
+    if !cred.is_root() && gid != cred.gid {
+        return Err(Errno(EPERM));
+    }
+    cred.gid = gid;
+    Ok(0)
+}
+
+/// reboot(magic1, magic2, cmd, arg)
+/// Only root may do this. Halting is the same as
+/// powering off, and ctrl-alt-del has no keyboard to come from.
+fn sys_reboot(frame: &mut TrapFrame) -> SysResult {
+    const MAGIC1: usize = 0xfee1_dead;
+    const MAGIC2: [usize; 4] = [0x2812_1969, 0x0511_3182, 0x1604_1998, 0x2011_2000];
+    const CMD_RESTART: usize = 0x0123_4567;
+    const CMD_HALT: usize = 0xcdef_0123;
+    const CMD_POWER_OFF: usize = 0x4321_fedc;
+    const CMD_CAD_ON: usize = 0x89ab_cdef;
+    const CMD_CAD_OFF: usize = 0;
+    need_root(frame)?;
+    if arg(frame, 0) as u32 as usize != MAGIC1 || !MAGIC2.contains(&(arg(frame, 1) as u32 as usize))
+    {
+        return Err(Errno(EINVAL));
+    }
+    match arg(frame, 2) as u32 as usize {
+        CMD_RESTART => {
+            println!("Restarting system.");
+            power::reboot()
+        }
+        CMD_HALT | CMD_POWER_OFF => {
+            println!("Power down.");
+            power::power_off()
+        }
+        CMD_CAD_ON | CMD_CAD_OFF => Ok(0),
+        _ => Err(Errno(EINVAL)),
+    }
+}
+
+/// uname(buf)
+/// We call ourselves Linux, since that's the interface we provide and C
+/// libraries check for it.
+fn sys_uname(frame: &mut TrapFrame) -> SysResult {
+    fn field(s: &[u8]) -> [u8; 65] {
+        let mut f = [0; 65];
+        f[..s.len()].copy_from_slice(s);
+        f
+    }
+    let uts = UtsName {
+        sysname: field(b"Linux"),
+        nodename: field(b"riscv"),
+        release: field(b"6.1.0"),
+        version: field(b"rust-riscv-os"),
+        machine: field(if cfg!(target_pointer_width = "64") {
+            b"riscv64"
+        } else {
+            b"riscv32"
+        }),
+        domainname: field(b"(none)"),
+    };
+    write_user(frame, arg(frame, 0), &uts)?;
+    Ok(0)
+}
+
+// ///////////////////////////////////
+// / SHARED MEMORY
+// ///////////////////////////////////
+
+/// shmctl(shmid, cmd, buf)
+/// IPC_RMID is the only command.
+fn sys_shmctl(frame: &mut TrapFrame) -> SysResult {
+    match arg(frame, 1) {
+        shm::IPC_RMID => shm::remove(arg(frame, 0))?,
+        _ => return Err(Errno(EINVAL)),
+    }
+    Ok(0)
+}
+
+/// shmat(shmid, shmaddr, shmflg)
+fn sys_shmat(frame: &mut TrapFrame) -> SysResult {
+    let (id, mut addr, flags) = (arg(frame, 0), arg(frame, 1), arg(frame, 2));
+    let seg = shm::lookup(id).ok_or(Errno(EINVAL))?;
+    if flags & shm::SHM_RND != 0 {
+        addr &= !(PAGE_SIZE - 1);
+    }
+    current(frame)
+        .attach_shared(seg, addr, flags & shm::SHM_RDONLY != 0)
+        .map(|addr| addr as isize)
+        .ok_or(Errno(EINVAL))
+}
+
+/// shmdt(shmaddr)
+fn sys_shmdt(frame: &mut TrapFrame) -> SysResult {
+    if !current(frame).detach_shared(arg(frame, 0)) {
+        return Err(Errno(EINVAL));
+    }
+    Ok(0)
+}
+
+// ///////////////////////////////////
+// / SOCKETS
+// ///////////////////////////////////
+
+/// The socket behind the file descriptor `fd`.
+fn socket(frame: &TrapFrame, fd: usize) -> Result<Rc<OpenFile>, SysError> {
+    let file = current(frame).files.get(fd)?.clone();
+    match file.file().as_udp() {
+        Some(_) => Ok(file),
+        None => Err(Errno(ENOTSOCK)),
+    }
+}
+
+fn udp(file: &OpenFile) -> &UdpSocket {
+    file.file().as_udp().unwrap()
+}
+
+/// Read a struct sockaddr_in that is `len` bytes long.
+fn read_sockaddr(frame: &TrapFrame, ptr: usize, len: usize) -> Result<Endpoint, SysError> {
+    if len < size_of::<SockAddrIn>() {
+        return Err(Errno(EINVAL));
+    }
+    let sa = read_user::<SockAddrIn>(frame, ptr)?;
+    if sa.sin_family as usize != net::AF_INET {
+        return Err(Errno(EAFNOSUPPORT));
+    }
+    Ok(Endpoint::from_sockaddr(&sa))
+}
+
+/// Store an address for a caller that gave us a buffer at `ptr` and its
+/// size at `len_ptr`. Like Linux, this cuts the address short if the
+/// buffer is too small and always reports the full size.
+fn write_sockaddr(
+    frame: &TrapFrame,
+    ptr: usize,
+    len_ptr: usize,
+    ep: Endpoint,
+) -> Result<(), SysError> {
+    let len = read_user::<u32>(frame, len_ptr)? as usize;
+    let sa = ep.to_sockaddr();
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            &sa as *const SockAddrIn as *const u8,
+            size_of::<SockAddrIn>(),
+        )
+    };
+    copy_to_user(frame, ptr, &bytes[..len.min(bytes.len())])?;
+    write_user(frame, len_ptr, &(bytes.len() as u32))
+}
+
+/// socket(domain, type, protocol)
+/// Only UDP for now.
+fn sys_socket(frame: &mut TrapFrame) -> SysResult {
+    let (domain, ty, protocol) = (arg(frame, 0), arg(frame, 1), arg(frame, 2));
+    if domain != net::AF_INET {
+        return Err(Errno(EAFNOSUPPORT));
+    }
+    let flags = ty & (net::SOCK_NONBLOCK | net::SOCK_CLOEXEC);
+    match ty & !flags {
+        net::SOCK_DGRAM if protocol == 0 || protocol == net::IPPROTO_UDP => {}
+        net::SOCK_DGRAM | net::SOCK_STREAM => return Err(Errno(EPROTONOSUPPORT)),
+        _ => return Err(Errno(EINVAL)),
+    }
+    // SOCK_NONBLOCK and SOCK_CLOEXEC are the same as the open flags.
+    let file = OpenFile::new(Rc::new(UdpSocket::new()), O_RDWR | flags);
+    Ok(current(frame).files.insert(Rc::new(file))? as isize)
+}
+
+/// bind(sockfd, addr, addrlen)
+fn sys_bind(frame: &mut TrapFrame) -> SysResult {
+    let file = socket(frame, arg(frame, 0))?;
+    let ep = read_sockaddr(frame, arg(frame, 1), arg(frame, 2))?;
+    udp(&file).bind(ep)?;
+    Ok(0)
+}
+
+/// connect(sockfd, addr, addrlen)
+fn sys_connect(frame: &mut TrapFrame) -> SysResult {
+    let file = socket(frame, arg(frame, 0))?;
+    let ep = read_sockaddr(frame, arg(frame, 1), arg(frame, 2))?;
+    udp(&file).connect(ep)?;
+    Ok(0)
+}
+
+/// getsockname(sockfd, addr, addrlen) and getpeername(sockfd, addr, addrlen)
+fn sys_getsockname(frame: &mut TrapFrame, peer: bool) -> SysResult {
+    let file = socket(frame, arg(frame, 0))?;
+    let sock = udp(&file);
+    let ep = if peer {
+        sock.peer().ok_or(Errno(ENOTCONN))?
+    } else {
+        sock.local().unwrap_or_default()
+    };
+    write_sockaddr(frame, arg(frame, 1), arg(frame, 2), ep)?;
+    Ok(0)
+}
+
+/// sendto(sockfd, buf, len, flags, dest_addr, addrlen)
+/// Sending never blocks, so MSG_DONTWAIT makes no difference.
+fn sys_sendto(frame: &mut TrapFrame) -> SysResult {
+    let file = socket(frame, arg(frame, 0))?;
+    let (ptr, len, flags) = (arg(frame, 1), arg(frame, 2), arg(frame, 3));
+    if flags & !MSG_DONTWAIT != 0 {
+        return Err(Errno(EOPNOTSUPP));
+    }
+    let to = match arg(frame, 4) {
+        0 => None,
+        addr => Some(read_sockaddr(frame, addr, arg(frame, 5))?),
+    };
+    if len > net::MAX_DATAGRAM {
+        return Err(Errno(EMSGSIZE));
+    }
+    let mut buf = vec![0u8; len];
+    copy_from_user(frame, ptr, &mut buf)?;
+    Ok(udp(&file).send_to(&buf, to)? as isize)
+}
+
+/// recvfrom(sockfd, buf, len, flags, src_addr, addrlen)
+fn sys_recvfrom(frame: &mut TrapFrame) -> SysResult {
+    let file = socket(frame, arg(frame, 0))?;
+    let (ptr, len, flags) = (
+        arg(frame, 1),
+        arg(frame, 2).min(net::MAX_DATAGRAM),
+        arg(frame, 3),
+    );
+    if flags & !MSG_DONTWAIT != 0 {
+        return Err(Errno(EOPNOTSUPP));
+    }
+    for_each_user_chunk(frame, ptr, len, true, |_| {})?;
+    let mut buf = vec![0u8; len];
+    let (n, from) = match udp(&file).recv_from(&mut buf) {
+        Ok(ret) => ret,
+        Err(Block) if flags & MSG_DONTWAIT != 0 || file.nonblocking() => {
+            return Err(Errno(EAGAIN));
+        }
+        Err(Block) => {
+            file.file().wait(frame.pid);
+            return Err(Block);
+        }
+        Err(e) => return Err(e),
+    };
+    copy_to_user(frame, ptr, &buf[..n.min(len)])?;
+    if arg(frame, 4) != 0 {
+        write_sockaddr(frame, arg(frame, 4), arg(frame, 5), from)?;
+    }
+    Ok(n.min(len) as isize)
+}
+
+/// brk(addr)
+/// Like Linux, this returns the new program break on success and the
+/// current one on failure, brk(0) is how a process finds out where its
+/// heap starts.
+fn sys_brk(frame: &mut TrapFrame) -> SysResult {
+    Ok(current(frame).set_brk(arg(frame, 0)) as isize)
+}
+
+/// mmap(addr, length, prot, flags, fd, offset)
+/// Nothing is allocated or read here, the pages are populated by the page
+/// fault handler when they're first used. Without fork, a shared anonymous
+/// mapping is the same as a private one. Only regular files can be mapped,
+/// from an offset that's a multiple of the page size; the file has to be
+/// open for reading, and for writing too to be written to through a shared
+/// mapping.
+fn sys_mmap(frame: &mut TrapFrame) -> SysResult {
+    let (addr, len, prot, flags) = (arg(frame, 0), arg(frame, 1), arg(frame, 2), arg(frame, 3));
+    if len == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno(EINVAL));
+    }
+    match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED | MAP_PRIVATE => {}
+        _ => return Err(Errno(EINVAL)),
+    }
+    let file = if flags & MAP_ANONYMOUS == 0 {
+        let (fd, offset) = (arg(frame, 4), arg(frame, 5));
+        if !offset.is_multiple_of(PAGE_SIZE) {
+            return Err(Errno(EINVAL));
+        }
+        let open = current(frame).files.get(fd)?.clone();
+        if open.file().stat().st_mode & S_IFMT != S_IFREG {
+            return Err(Errno(ENODEV));
+        }
+        let shared = flags & MAP_SHARED != 0;
+        if !open.readable() || shared && prot & PROT_WRITE != 0 && !open.writable() {
+            return Err(Errno(EACCES));
+        }
+        Some(FileMap {
+            file: open.file().clone(),
+            offset,
+            shared,
+        })
+    } else {
+        None
+    };
+    let fixed = flags & MAP_FIXED != 0;
+    if fixed && !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(Errno(EINVAL));
+    }
+    let mut bits = EntryBits::empty();
+    if prot & PROT_READ != 0 {
+        bits |= EntryBits::READ;
+    }
+    if prot & PROT_WRITE != 0 {
+        bits |= EntryBits::WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        bits |= EntryBits::EXECUTE;
+    }
+    let p = current(frame);
+    let start = match file {
+        Some(file) => p.map_file(addr, len, bits, fixed, file),
+        None => p.map_anonymous(addr, len, bits, fixed),
+    };
+    start.map(|start| start as isize).ok_or(Errno(ENOMEM))
+}
+
+/// munmap(addr, length)
+/// Unmapping a range that isn't mapped is not an error.
+fn sys_munmap(frame: &mut TrapFrame) -> SysResult {
+    let (addr, len) = (arg(frame, 0), arg(frame, 1));
+    if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+        return Err(Errno(EINVAL));
+    }
+    current(frame).unmap_range(addr, len);
+    Ok(0)
+}
+
+/// msync(addr, length, flags)
+/// What was written to shared file mappings goes back to the files with
+/// either MS_ASYNC or MS_SYNC, and MS_SYNC has the files write it out too.
+/// There's nothing to invalidate: no two mappings share pages.
+fn sys_msync(frame: &mut TrapFrame) -> SysResult {
+    let (addr, len, flags) = (arg(frame, 0), arg(frame, 1), arg(frame, 2));
+    if !addr.is_multiple_of(PAGE_SIZE)
+        || flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0
+        || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC
+    {
+        return Err(Errno(EINVAL));
+    }
+    current(frame).msync(addr, len, flags & MS_SYNC != 0)?;
+    Ok(0)
+}
+
+/// prlimit64(pid, resource, new_limit, old_limit)
+/// RLIMIT_DATA (how far the heap may grow) is the only limit we have.
+/// Another process's is only root's, or its own user's, to see or change.
+fn sys_prlimit(
+    frame: &mut TrapFrame,
+    pid: usize,
+    resource: usize,
+    new_limit: usize,
+    old_limit: usize,
+) -> SysResult {
+    let cred = current(frame).cred;
+    let prc = if pid == 0 {
+        current(frame)
+    } else {
+        let prc = process::get_by_pid(pid).ok_or(Errno(ESRCH))?;
+        if !cred.is_root() && cred.uid != prc.cred.uid {
+            return Err(Errno(EPERM));
+        }
+        prc
+    };
+    if resource != RLIMIT_DATA {
+        return Err(Errno(EINVAL));
+    }
+    if old_limit != 0 {
+        let limit = RLimit {
+            rlim_cur: prc.brk_limit as u64,
+            rlim_max: prc.brk_limit_max as u64,
+        };
+        write_user(frame, old_limit, &limit)?;
+    }
+    if new_limit != 0 {
+        let limit = read_user::<RLimit>(frame, new_limit)?;
+        (prc.brk_limit, prc.brk_limit_max) = new_rlimit(prc.brk_limit_max, limit, cred.is_root())?;
+    }
+    Ok(0)
+}
+
+/// The soft and hard limit a limit whose hard limit was `max` gets when
+/// it's set to `limit`. The soft limit can't be over the hard one, and only
+/// root can raise the hard one.
+fn new_rlimit(max: usize, limit: RLimit, root: bool) -> Result<(usize, usize), SysError> {
+    let clamp = |n: u64| n.min(usize::MAX as u64) as usize;
+    let (cur, new_max) = (clamp(limit.rlim_cur), clamp(limit.rlim_max));
+    if cur > new_max {
+        return Err(Errno(EINVAL));
+    }
+    if new_max > max && !root {
+        return Err(Errno(EPERM));
+    }
+    Ok((cur, new_max))
+}
+
+/// getrandom(buf, buflen, flags)
+/// Until the entropy pool is seeded, this blocks (or fails with EAGAIN with
+/// GRND_NONBLOCK), unless the caller says it can live with GRND_INSECURE.
+/// There's only one pool, so GRND_RANDOM makes no difference.
+fn sys_getrandom(frame: &mut TrapFrame) -> SysResult {
+    let (ptr, len, flags) = (arg(frame, 0), arg(frame, 1).min(MAX_IO), arg(frame, 2));
+    if flags & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0
+        || flags & (GRND_RANDOM | GRND_INSECURE) == GRND_RANDOM | GRND_INSECURE
+    {
+        return Err(Errno(EINVAL));
+    }
+    if !entropy::is_seeded() && flags & GRND_INSECURE == 0 {
+        if flags & GRND_NONBLOCK != 0 {
+            return Err(Errno(EAGAIN));
+        }
+        entropy::wait(frame.pid);
+        return Err(Block);
+    }
+    let mut buf = vec![0u8; len];
+    entropy::fill(&mut buf);
+    copy_to_user(frame, ptr, &buf)?;
+    Ok(len as isize)
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest::check;
+    use alloc::{format, string::String};
+
+    #[test_case]
+    fn rlimit_test() -> Result<(), String> {
+        let limit = |cur, max| RLimit {
+            rlim_cur: cur,
+            rlim_max: max,
+        };
+        let cases = [
+            // Lowering both, and raising the soft one up to the hard one.
+            (limit(10, 100), false, Ok((10, 100))),
+            (limit(1000, 1000), false, Ok((1000, 1000))),
+            // Raising the hard one is root's.
+            (limit(10, 1001), false, Err(EPERM)),
+            (limit(u64::MAX, u64::MAX), false, Err(EPERM)),
+            (
+                limit(u64::MAX, u64::MAX),
+                true,
+                Ok((usize::MAX, usize::MAX)),
+            ),
+            // The soft one over the hard one is wrong even for root.
+            (limit(101, 100), true, Err(EINVAL)),
+        ];
+        for (new, root, want) in cases {
+            let got = new_rlimit(1000, new, root).map_err(|e| match e {
+                Errno(errno) => errno,
+                Block => 0,
+            });
+            check(got == want, || {
+                format!(
+                    "{}/{} as root {} is {:?}",
+                    new.rlim_cur, new.rlim_max, root, got
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
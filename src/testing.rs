@@ -0,0 +1,116 @@
+// The test kernel: what `cargo test` builds, with custom_test_frameworks,
+// which needs a nightly compiler,
+//
+//   cargo +nightly test --bin rust-riscv-os
+//
+// Every function marked #[test_case] anywhere in the kernel is compiled
+// into it, and boot goes as usual up to the first process, which is the
+// one in here instead of the shell. It runs them one after another, then
+// the self-tests (see selftest.rs), with interrupts off the way the
+// shell's commands run, and prints how each did in the format cargo's own
+// harness uses. Then it stops the machine through the test device (see
+// power.rs), which makes QEMU exit with 0 if they all passed and 1 if any
+// failed, so cargo, and whatever runs cargo, knows. .cargo/config has QEMU
+// as the runner, so cargo test starts it.
+//
+// A module's test cases are at its end, in a #[cfg(test)] mod tests, for
+// what can be tried on made-up input without the hardware: the parsers
+// and decoders, the shell's lines, file names. Each is a function marked
+// #[test_case] that returns nothing, or says what went wrong the way the
+// self-tests do, with selftest::check; what more than one module's tests
+// need, like showing bytes as text, is here. The test harness takes the
+// function for itself, so nothing else can call it, which is why the
+// self-tests are run from their registry instead. A test that panics
+// gets the usual panic report, and the panic handler exits QEMU with 1 as
+// well, so it fails the run even though the tests after it don't get to
+// run.
+
+use crate::{
+    cpu, power,
+    selftest::{self, Test},
+};
+use alloc::{string::String, vec::Vec};
+use core::any::type_name;
+
+/// What a test function gives back: nothing, or whether it passed.
+pub trait Outcome {
+    fn into_result(self) -> Result<(), String>;
+}
+
+impl Outcome for () {
+    fn into_result(self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Outcome for Result<(), String> {
+    fn into_result(self) -> Result<(), String> {
+        self
+    }
+}
+
+/// A #[test_case].
+pub trait Testable {
+    fn name(&self) -> &'static str;
+    fn run(&self) -> Result<(), String>;
+}
+
+impl<T: Fn() -> R, R: Outcome> Testable for T {
+    fn name(&self) -> &'static str {
+        // rust_riscv_os::inflate::tests::fixed_test, without the crate.
+        let name = type_name::<T>();
+        name.split_once("::").map_or(name, |(_, name)| name)
+    }
+
+    fn run(&self) -> Result<(), String> {
+        cpu::without_interrupts(|| self().into_result())
+    }
+}
+
+impl Testable for Test {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self) -> Result<(), String> {
+        cpu::without_interrupts(self.run)
+    }
+}
+
+/// `bytes` as text, for saying what a test got.
+pub fn shown(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into()
+}
+
+/// The test runner: run `cases` and the self-tests, say how they did, and
+/// stop the machine.
+pub fn run(cases: &[&dyn Testable]) {
+    let selftests = selftest::all();
+    let tests: Vec<&dyn Testable> = cases
+        .iter()
+        .copied()
+        .chain(selftests.iter().map(|&t| t as &dyn Testable))
+        .collect();
+    println!();
+    println!("running {} tests", tests.len());
+    let mut failed = 0;
+    for t in &tests {
+        print!("test {} ... ", t.name());
+        match t.run() {
+            Ok(()) => println!("ok"),
+            Err(what) => {
+                println!("FAILED: {}", what);
+                failed += 1;
+            }
+        }
+    }
+    let result = if failed == 0 { "ok" } else { "FAILED" };
+    println!();
+    println!(
+        "test result: {}. {} passed; {} failed",
+        result,
+        tests.len() - failed,
+        failed
+    );
+    power::exit(if failed == 0 { 0 } else { 1 });
+}
@@ -0,0 +1,122 @@
+// Software timers. The hart only has one timer compare register, so anything
+// that needs to happen at some point in the future (waking up a sleeping
+// process, ...) registers a software timer here instead. The machine timer
+// interrupt runs the ones that have expired, and the compare register is
+// always armed for whichever comes first: the next software timer or the end
+// of the current time slice.
+
+use crate::{cpu, rtc, sched};
+use alloc::vec::Vec;
+use core::ptr::addr_of_mut;
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+struct Timer {
+    id: usize,
+    deadline: u64,
+    callback: fn(usize),
+    data: usize,
+}
+
+// Pending timers, sorted by deadline so the next one is always at the front.
+static mut TIMERS: Vec<Timer> = Vec::new();
+static mut NEXT_ID: usize = 1;
+// CLOCK_REALTIME is kept as an offset over mtime, which we get by reading
+// the RTC once at boot.
+static mut REALTIME_OFFSET_NS: u64 = 0;
+
+fn timers() -> &'static mut Vec<Timer> {
+    unsafe { &mut *addr_of_mut!(TIMERS) }
+}
+
+/// Convert a duration to mtime ticks, rounding up so that we never wait
+/// less than asked for.
+pub fn duration_to_ticks(secs: u64, nanos: u64) -> u64 {
+    let ticks = secs as u128 * cpu::FREQ as u128
+        + (nanos as u128 * cpu::FREQ as u128).div_ceil(NANOS_PER_SEC as u128);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Convert mtime ticks to (seconds, nanoseconds).
+pub fn ticks_to_duration(ticks: u64) -> (u64, u64) {
+    let secs = ticks / cpu::FREQ;
+    let nanos = (ticks % cpu::FREQ) * (NANOS_PER_SEC / cpu::FREQ);
+    (secs, nanos)
+}
+
+/// Call `callback(data)` from the timer interrupt once mtime reaches
+/// `deadline`. Returns an id that can be used to cancel the timer.
+pub fn add(deadline: u64, callback: fn(usize), data: usize) -> usize {
+    let id = unsafe {
+        let id = NEXT_ID;
+        NEXT_ID += 1;
+        id
+    };
+    let timers = timers();
+    let pos = timers.partition_point(|t| t.deadline <= deadline);
+    timers.insert(
+        pos,
+        Timer {
+            id,
+            deadline,
+            callback,
+            data,
+        },
+    );
+    sched::arm_timer();
+    id
+}
+
+/// Remove a timer that hasn't fired yet. Cancelling a timer that already
+/// fired does nothing.
+pub fn cancel(id: usize) {
+    timers().retain(|t| t.id != id);
+}
+
+/// The deadline of the next timer to fire, if there is any.
+pub fn next_deadline() -> Option<u64> {
+    timers().first().map(|t| t.deadline)
+}
+
+/// Run every timer whose deadline is at or before `now`. This is called
+/// from the machine timer interrupt.
+pub fn run_expired(now: u64) {
+    let timers = timers();
+    while timers.first().is_some_and(|t| t.deadline <= now) {
+        let t = timers.remove(0);
+        (t.callback)(t.data);
+    }
+}
+
+// ///////////////////////////////////
+// / CLOCKS
+// ///////////////////////////////////
+
+/// Anchor the realtime clock to the RTC.
+pub fn init() {
+    let now = monotonic_ns();
+    unsafe {
+        REALTIME_OFFSET_NS = rtc::read_ns().saturating_sub(now);
+    }
+    println!("rtc: {} UTC", rtc::DateTime::now());
+}
+
+/// Nanoseconds since boot. This never goes backwards.
+pub fn monotonic_ns() -> u64 {
+    let (secs, nanos) = ticks_to_duration(cpu::get_mtime());
+    secs * NANOS_PER_SEC + nanos
+}
+
+/// Nanoseconds since the Unix epoch.
+pub fn realtime_ns() -> u64 {
+    monotonic_ns() + unsafe { REALTIME_OFFSET_NS }
+}
+
+/// Set the realtime clock, and the RTC with it so the time survives a
+/// reboot. The monotonic clock isn't affected.
+pub fn set_realtime_ns(ns: u64) {
+    unsafe {
+        REALTIME_OFFSET_NS = ns.saturating_sub(monotonic_ns());
+    }
+    rtc::write_ns(ns);
+}
@@ -0,0 +1,490 @@
+// tmpfs, a filesystem that's only memory. The rootfs is one, and another
+// is mounted at /tmp, so there's somewhere to put files before there's a
+// disk, or on a board without one. What's in it is gone when the machine
+// is.
+//
+// Every file and directory is a Node. A directory holds its entries, a
+// name and the node it's the name of, in the order they were made; a node
+// can be in several, under several names, as many as its link count. A file
+// holds its contents in pages from the page allocator, so a file doesn't
+// need a lot of memory in one piece; running out of pages is ENOSPC, as
+// running out of room on a disk would be. Only the pages that have been
+// written to are there. The others are holes, which read as zeros, so a
+// big sparse image, made by seeking past the end or by truncate(), costs
+// only what's written to it; SEEK_DATA and SEEK_HOLE find them. A node
+// lives as long as a directory has it or somebody has it open, so a file
+// that's unlinked while it's open can still be read and written until it's
+// closed. A symlink holds where it points. Nodes belong to
+// whoever made them.
+
+use crate::{
+    file::{File, O_ACCMODE, O_RDONLY, O_TRUNC, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG},
+    page::{self, PAGE_SIZE},
+    syscall::{
+        Stat, SysError, EEXIST, EFBIG, EINVAL, EISDIR, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, ENXIO,
+    },
+    timer,
+    vfs::{self, Inode},
+};
+use alloc::{
+    collections::{btree_map::Entry, BTreeMap},
+    rc::{Rc, Weak},
+    vec::Vec,
+};
+use core::{
+    cell::{Cell, RefCell},
+    ptr::addr_of_mut,
+    slice,
+};
+
+use SysError::Errno;
+
+/// The biggest a file can get. Offsets past this don't fit in an off_t
+/// once they're pages.
+const MAX_SIZE: usize = isize::MAX as usize & !(PAGE_SIZE - 1);
+
+static mut NEXT_INO: u64 = 1;
+
+fn new_ino() -> u64 {
+    unsafe {
+        let ino = addr_of_mut!(NEXT_INO);
+        *ino += 1;
+        *ino - 1
+    }
+}
+
+/// A page of a file.
+struct Page(*mut u8);
+
+impl Page {
+    fn new() -> Result<Self, SysError> {
+        let page = page::zalloc(1);
+        if page.is_null() {
+            return Err(Errno(ENOSPC));
+        }
+        Ok(Page(page))
+    }
+
+    fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.0, PAGE_SIZE) }
+    }
+
+    fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.0, PAGE_SIZE) }
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        page::dealloc(self.0);
+    }
+}
+
+/// What a node has in it.
+enum Data {
+    /// The pages there are, by their number in the file.
+    File {
+        size: usize,
+        pages: BTreeMap<usize, Page>,
+    },
+    Dir(Vec<(Vec<u8>, Rc<Node>)>),
+    Symlink(Vec<u8>),
+}
+
+/// A file, directory or symlink of a tmpfs.
+struct Node {
+    // The node itself, to hand out as an Inode or a File.
+    this: Weak<Node>,
+    dev: u64,
+    ino: u64,
+    mode: Cell<u32>,
+    uid: Cell<u32>,
+    gid: Cell<u32>,
+    nlink: Cell<u32>,
+    // In nanoseconds since the epoch.
+    mtime: Cell<u64>,
+    ctime: Cell<u64>,
+    data: RefCell<Data>,
+}
+
+impl Node {
+    fn new(dev: u64, mode: u32) -> Rc<Node> {
+        let now = timer::realtime_ns();
+        let (nlink, data) = match mode & S_IFMT {
+            S_IFDIR => (2, Data::Dir(Vec::new())),
+            S_IFLNK => (1, Data::Symlink(Vec::new())),
+            _ => (
+                1,
+                Data::File {
+                    size: 0,
+                    pages: BTreeMap::new(),
+                },
+            ),
+        };
+        let cred = vfs::cred();
+        Rc::new_cyclic(|this| Node {
+            this: this.clone(),
+            dev,
+            ino: new_ino(),
+            mode: Cell::new(mode),
+            uid: Cell::new(cred.uid),
+            gid: Cell::new(cred.gid),
+            nlink: Cell::new(nlink),
+            mtime: Cell::new(now),
+            ctime: Cell::new(now),
+            data: RefCell::new(data),
+        })
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode.get() & S_IFMT == S_IFDIR
+    }
+
+    /// What reading or writing this is, if it isn't a file.
+    fn not_file(&self) -> SysError {
+        if self.is_dir() {
+            Errno(EISDIR)
+        } else {
+            Errno(EINVAL)
+        }
+    }
+
+    fn this(&self) -> Rc<Node> {
+        self.this.upgrade().unwrap()
+    }
+
+    /// The contents changed.
+    fn touch(&self) {
+        self.mtime.set(timer::realtime_ns());
+        self.ctime.set(self.mtime.get());
+    }
+
+    /// The node called `name` in this directory.
+    fn find(&self, name: &[u8]) -> Result<Rc<Node>, SysError> {
+        let Data::Dir(entries) = &*self.data.borrow() else {
+            return Err(Errno(ENOTDIR));
+        };
+        let (_, node) = entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .ok_or(Errno(ENOENT))?;
+        Ok(node.clone())
+    }
+
+    /// Put `node` in this directory as `name`. Its own link count is the
+    /// caller's to change.
+    fn insert(&self, name: &[u8], node: Rc<Node>) -> Result<(), SysError> {
+        match self.find(name) {
+            Ok(_) => return Err(Errno(EEXIST)),
+            Err(Errno(ENOENT)) => {}
+            Err(e) => return Err(e),
+        }
+        if node.is_dir() {
+            // Its .. is a link to us.
+            self.nlink.set(self.nlink.get() + 1);
+        }
+        if let Data::Dir(entries) = &mut *self.data.borrow_mut() {
+            entries.push((name.to_vec(), node));
+        }
+        self.touch();
+        Ok(())
+    }
+
+    /// Take `node`, which is called `name`, out of this directory. Its own
+    /// link count is the caller's to change.
+    fn take(&self, name: &[u8], node: &Node) {
+        if let Data::Dir(entries) = &mut *self.data.borrow_mut() {
+            entries.retain(|(n, _)| n != name);
+        }
+        if node.is_dir() {
+            self.nlink.set(self.nlink.get() - 1);
+        }
+        self.touch();
+    }
+
+    /// Take `node`, which is called `name`, out of this directory, for
+    /// good. A directory has to be empty.
+    fn remove(&self, name: &[u8], node: &Node) -> Result<(), SysError> {
+        if let Data::Dir(entries) = &*node.data.borrow() {
+            if !entries.is_empty() {
+                return Err(Errno(ENOTEMPTY));
+            }
+        }
+        self.take(name, node);
+        // A directory's . goes with it.
+        let links = if node.is_dir() {
+            0
+        } else {
+            node.nlink.get() - 1
+        };
+        node.nlink.set(links);
+        node.ctime.set(timer::realtime_ns());
+        Ok(())
+    }
+
+    /// Add a node of `mode` called `name` to this directory.
+    fn add(&self, name: &[u8], mode: u32) -> Result<Rc<Node>, SysError> {
+        let node = Node::new(self.dev, mode);
+        self.insert(name, node.clone())?;
+        Ok(node)
+    }
+}
+
+impl Inode for Node {
+    fn lookup(&self, name: &[u8]) -> Result<Rc<dyn Inode>, SysError> {
+        Ok(self.find(name)?)
+    }
+
+    fn create(&self, name: &[u8], flags: usize, mode: usize) -> Result<Rc<dyn File>, SysError> {
+        let node = self.add(name, S_IFREG | (mode as u32 & 0o7777))?;
+        node.open(flags)
+    }
+
+    fn mkdir(&self, name: &[u8], mode: u32) -> Result<Rc<dyn Inode>, SysError> {
+        Ok(self.add(name, S_IFDIR | mode)?)
+    }
+
+    fn unlink(&self, name: &[u8], dir: bool) -> Result<(), SysError> {
+        let node = self.find(name)?;
+        match (node.is_dir(), dir) {
+            (true, false) => Err(Errno(EISDIR)),
+            (false, true) => Err(Errno(ENOTDIR)),
+            _ => self.remove(name, &node),
+        }
+    }
+
+    fn link(&self, name: &[u8], inode: &dyn Inode) -> Result<(), SysError> {
+        let node = vfs::downcast::<Node>(inode)?.this();
+        self.insert(name, node.clone())?;
+        node.nlink.set(node.nlink.get() + 1);
+        node.ctime.set(timer::realtime_ns());
+        Ok(())
+    }
+
+    fn rename(&self, old: &[u8], dir: &dyn Inode, new: &[u8]) -> Result<(), SysError> {
+        let to = vfs::downcast::<Node>(dir)?;
+        let node = self.find(old)?;
+        match to.find(new) {
+            Ok(there) if Rc::ptr_eq(&there, &node) => return Ok(()),
+            Ok(there) => to.remove(new, &there)?,
+            Err(Errno(ENOENT)) => {}
+            Err(e) => return Err(e),
+        }
+        self.take(old, &node);
+        to.insert(new, node.clone())?;
+        node.ctime.set(timer::realtime_ns());
+        Ok(())
+    }
+
+    fn symlink(&self, name: &[u8], target: &[u8]) -> Result<(), SysError> {
+        let node = self.add(name, S_IFLNK | 0o777)?;
+        *node.data.borrow_mut() = Data::Symlink(target.to_vec());
+        Ok(())
+    }
+
+    fn readlink(&self) -> Result<Vec<u8>, SysError> {
+        match &*self.data.borrow() {
+            Data::Symlink(target) => Ok(target.clone()),
+            _ => Err(Errno(EINVAL)),
+        }
+    }
+
+    fn chmod(&self, mode: u32) -> Result<(), SysError> {
+        self.mode.set(self.mode.get() & S_IFMT | mode);
+        self.ctime.set(timer::realtime_ns());
+        Ok(())
+    }
+
+    fn chown(&self, uid: u32, gid: u32) -> Result<(), SysError> {
+        self.uid.set(uid);
+        self.gid.set(gid);
+        self.ctime.set(timer::realtime_ns());
+        Ok(())
+    }
+
+    fn open(&self, flags: usize) -> Result<Rc<dyn File>, SysError> {
+        if flags & O_ACCMODE != O_RDONLY {
+            if self.is_dir() {
+                return Err(Errno(EISDIR));
+            }
+            if flags & O_TRUNC != 0 {
+                self.truncate(0)?;
+            }
+        }
+        Ok(self.this())
+    }
+
+    fn metadata(&self) -> Stat {
+        let (size, blocks) = match &*self.data.borrow() {
+            Data::File { size, pages } => (*size, pages.len() * PAGE_SIZE / 512),
+            Data::Dir(entries) => (entries.len(), 0),
+            Data::Symlink(target) => (target.len(), 0),
+        };
+        let (mtime, ctime) = (self.mtime.get(), self.ctime.get());
+        Stat {
+            st_dev: self.dev,
+            st_ino: self.ino,
+            st_mode: self.mode.get(),
+            st_nlink: self.nlink.get(),
+            st_uid: self.uid.get(),
+            st_gid: self.gid.get(),
+            st_size: size as i64,
+            st_blksize: PAGE_SIZE as i32,
+            st_blocks: blocks as i64,
+            st_atime: (mtime / 1_000_000_000) as i64,
+            st_atime_nsec: mtime % 1_000_000_000,
+            st_mtime: (mtime / 1_000_000_000) as i64,
+            st_mtime_nsec: mtime % 1_000_000_000,
+            st_ctime: (ctime / 1_000_000_000) as i64,
+            st_ctime_nsec: ctime % 1_000_000_000,
+            ..Default::default()
+        }
+    }
+}
+
+impl File for Node {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let Data::File { size, pages } = &*self.data.borrow() else {
+            return Err(self.not_file());
+        };
+        let end = (*size).min(offset.saturating_add(buf.len()));
+        let mut pos = offset;
+        while pos < end {
+            let (n, start) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            let len = (PAGE_SIZE - start).min(end - pos);
+            let out = &mut buf[pos - offset..][..len];
+            match pages.get(&n) {
+                Some(page) => out.copy_from_slice(&page.data()[start..][..len]),
+                None => out.fill(0),
+            }
+            pos += len;
+        }
+        Ok(pos.saturating_sub(offset))
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        let mut data = self.data.borrow_mut();
+        let Data::File { size, pages } = &mut *data else {
+            return Err(self.not_file());
+        };
+        let end = offset.saturating_add(buf.len()).min(MAX_SIZE);
+        if end <= offset && !buf.is_empty() {
+            return Err(Errno(EFBIG));
+        }
+        let mut pos = offset;
+        while pos < end {
+            let (n, start) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            // The pages before it that nothing was written to stay holes.
+            let page = match pages.entry(n) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => match Page::new() {
+                    Ok(page) => e.insert(page),
+                    // Out of pages.
+                    Err(_) => break,
+                },
+            };
+            let len = (PAGE_SIZE - start).min(end - pos);
+            page.data_mut()[start..][..len].copy_from_slice(&buf[pos - offset..][..len]);
+            pos += len;
+        }
+        if pos > offset {
+            *size = (*size).max(pos);
+        }
+        drop(data);
+        if pos == offset && !buf.is_empty() {
+            return Err(Errno(ENOSPC));
+        }
+        self.touch();
+        Ok(pos - offset)
+    }
+
+    fn size(&self) -> Option<usize> {
+        match &*self.data.borrow() {
+            Data::File { size, .. } => Some(*size),
+            Data::Dir(_) | Data::Symlink(_) => Some(0),
+        }
+    }
+
+    fn stat(&self) -> Stat {
+        self.metadata()
+    }
+
+    /// Offsets count entries. There are no . and .. entries.
+    fn getdents(&self, offset: usize, buf: &mut [u8]) -> Result<(usize, usize), SysError> {
+        let Data::Dir(entries) = &*self.data.borrow() else {
+            return Err(Errno(ENOTDIR));
+        };
+        let (mut len, mut next) = (0, offset);
+        for (name, node) in entries.iter().skip(offset) {
+            let kind = vfs::dirent_type(node.mode.get());
+            let Some(n) = vfs::put_dirent(&mut buf[len..], node.ino, next as u64 + 1, kind, name)
+            else {
+                break;
+            };
+            len += n;
+            next += 1;
+        }
+        if len == 0 && next < entries.len() {
+            // Not even one entry fits.
+            return Err(Errno(EINVAL));
+        }
+        Ok((len, next))
+    }
+
+    fn seek_data(&self, offset: usize, hole: bool) -> Result<usize, SysError> {
+        let Data::File { size, pages } = &*self.data.borrow() else {
+            return Err(self.not_file());
+        };
+        if offset >= *size {
+            return Err(Errno(ENXIO));
+        }
+        let mut n = offset / PAGE_SIZE;
+        if hole {
+            // The first page from there on that isn't there.
+            for &p in pages.range(n..).map(|(p, _)| p) {
+                if p != n {
+                    break;
+                }
+                n += 1;
+            }
+        } else {
+            n = *pages.range(n..).next().ok_or(Errno(ENXIO))?.0;
+        }
+        Ok((n * PAGE_SIZE).clamp(offset, *size))
+    }
+
+    fn truncate(&self, len: usize) -> Result<(), SysError> {
+        let mut data = self.data.borrow_mut();
+        let Data::File { size, pages } = &mut *data else {
+            return Err(self.not_file());
+        };
+        if len > MAX_SIZE {
+            return Err(Errno(EFBIG));
+        }
+        // Growing the file leaves a hole at the end.
+        if len < *size {
+            pages.split_off(&len.div_ceil(PAGE_SIZE));
+            // What's left of the last page has to read as zeros if the file
+            // grows again.
+            if let Some(last) = pages.get_mut(&(len / PAGE_SIZE)) {
+                last.data_mut()[len % PAGE_SIZE..].fill(0);
+            }
+        }
+        *size = len;
+        drop(data);
+        self.touch();
+        Ok(())
+    }
+}
+
+/// Make a tmpfs, and return its root.
+pub fn new() -> Rc<dyn Inode> {
+    Node::new(vfs::new_dev(), S_IFDIR | 0o1777)
+}
+
+/// Mount a tmpfs at /tmp.
+pub fn init() {
+    if vfs::mount(b"/tmp", new(), "tmpfs", "tmpfs", false).is_err() {
+        println!("tmpfs: couldn't mount /tmp");
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{
+    coredump,
+    cpu::{self, CpuMode, TrapFrame},
+    entropy,
+    ktrace::{self, Kind},
+    page::EntryBits,
+    panic, plic, process, sched, semihosting, syscall, timer,
+};
+use core::ptr::addr_of_mut;
+
+// Set in the wait status of a process that was killed, if a core file was
+// written for it.
+const WCOREFLAG: usize = 0x80;
+
+// How many machine timer interrupts there have been. The PLIC counts the
+// external ones.
+static mut TIMER_INTERRUPTS: u64 = 0;
+
+/// How many machine timer interrupts there have been.
+pub fn timer_interrupts() -> u64 {
+    unsafe { *addr_of_mut!(TIMER_INTERRUPTS) }
+}
+
+#[no_mangle]
+extern "C" fn m_trap(
+    epc: usize,
+    tval: usize,
+    cause: usize,
+    hart: usize,
+    status: usize,
+    frame: *mut TrapFrame,
+) -> usize {
+    // We're going to handle all traps in machine mode. RISC-V lets
+    // us delegate to supervisor mode, but switching out SATP (virtual memory)
+    // gets hairy.
+    // The top bit says it's an interrupt, whether that's bit 63 or bit 31.
+    let is_async = cause >> (usize::BITS - 1) & 1 == 1;
+    // The cause contains the type of trap (sync, async) as well as the cause
+    // number. So, here we narrow down just the cause number.
+    let cause_num = cause & 0xfff;
+    let mut return_pc = epc;
+    // A process that was sent a signal ends before anything it trapped
+    // for is done. An interrupt it was taken by comes again, for whatever
+    // runs next.
+    if let Some(signal) = process::take_signal(unsafe { (*frame).pid }) {
+        kill_user(frame, signal);
+    }
+    if is_async {
+        // Asynchronous trap. When exactly interrupts arrive is a little
+        // unpredictable, which makes it a source of entropy.
+        entropy::add_interrupt();
+        ktrace::record(Kind::IrqEnter, cause_num, 0);
+        match cause_num {
+            3 => {
+                // Machine software. A hart that panicked sends it to stop
+                // the others.
+                if panic::panicking() {
+                    panic::park();
+                }
+                println!("Machine software interrupt CPU#{}", hart);
+            }
+            7 => {
+                // Machine timer. Run the software timers that are due,
+                // and if the current process used up its time slice (or
+                // we were idling and a timer might have woken someone
+                // up), schedule the next one.
+                unsafe {
+                    *addr_of_mut!(TIMER_INTERRUPTS) += 1;
+                }
+                let now = cpu::get_mtime();
+                timer::run_expired(now);
+                if sched::slice_expired(now) || unsafe { (*frame).pid } == 0 {
+                    cpu::switch_to(sched::schedule());
+                }
+                sched::arm_timer();
+            }
+            11 => {
+                // Machine external (interrupt from Platform Interrupt
+                // Controller (PLIC))
+                plic::handle_interrupt();
+                // If we were idling, an interrupt is the only thing that
+                // could have made a process runnable, so don't wait for
+                // the next timer tick to find out.
+                if unsafe { (*frame).pid } == 0 {
+                    cpu::switch_to(sched::schedule());
+                }
+            }
+            _ => {
+                panic!("Unhandled async trap CPU#{} -> {}\n", hart, cause_num);
+            }
+        }
+        ktrace::record(Kind::IrqExit, cause_num, 0);
+    } else {
+        // Synchronous trap
+        match cause_num {
+            2 => {
+                // Illegal instruction
+                kill_user(frame, process::SIGILL);
+                panic!(
+                    "Illegal instruction CPU#{} -> 0x{:08x}: 0x{:08x}\n",
+                    hart, epc, tval
+                );
+            }
+            // MPP says whether it came from the kernel, which is the only
+            // one that makes semihosting calls; a user pc isn't an
+            // address we can read here.
+            3 if status >> 11 & 3 == 3 && semihosting::is_call(epc) => {
+                // A semihosting call, which nobody took.
+                return_pc = semihosting::absent(frame, epc);
+            }
+            8 | 11 => {
+                // Environment (system) call from User or Machine mode
+                return_pc = syscall::do_syscall(return_pc, frame);
+            }
+            12 => {
+                // Instruction page fault. This may just be the first touch of
+                // a lazily populated mapping, in which case we retry the
+                // instruction.
+                if fault_in(frame, tval, EntryBits::EXECUTE) {
+                    return epc;
+                }
+                kill_user(frame, process::SIGSEGV);
+                panic!(
+                    "Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
+                    hart, epc, tval
+                );
+            }
+            13 => {
+                // Load page fault. This may just be the first touch of
+                // a lazily populated mapping, in which case we retry the
+                // instruction.
+                if fault_in(frame, tval, EntryBits::READ) {
+                    return epc;
+                }
+                kill_user(frame, process::SIGSEGV);
+                panic!(
+                    "Load page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
+                    hart, epc, tval
+                );
+            }
+            15 => {
+                // Store page fault. This may just be the first touch of
+                // a lazily populated mapping, in which case we retry the
+                // instruction.
+                if fault_in(frame, tval, EntryBits::WRITE) {
+                    return epc;
+                }
+                kill_user(frame, process::SIGSEGV);
+                panic!(
+                    "Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
+                    hart, epc, tval
+                );
+            }
+            _ => {
+                panic!(
+                    "Unhandled sync trap CPU#{} -> {} at 0x{:08x}: 0x{:08x}\n",
+                    hart, cause_num, epc, tval
+                );
+            }
+        }
+    }
+    // Finally, return the updated program counter
+    return_pc
+}
+
+/// Let the process that faulted populate the page at `vaddr`, if it is part
+/// of one of its memory mappings.
+fn fault_in(frame: *mut TrapFrame, vaddr: usize, access: EntryBits) -> bool {
+    let pid = unsafe { (*frame).pid };
+    match process::get_by_pid(pid) {
+        Some(prc) => prc.handle_page_fault(vaddr, access),
+        None => false,
+    }
+}
+
+/// If the trap came from a user process, kill it with the given signal and
+/// move on to the next process. The kernel can't survive its own faults,
+/// so this returns for anything else.
+fn kill_user(frame: *mut TrapFrame, signal: usize) {
+    let (pid, mode, pc) = unsafe { ((*frame).pid, (*frame).mode, (*frame).pc) };
+    if pid == 0 || mode != CpuMode::User as usize {
+        return;
+    }
+    // What it was doing is written down before exit() frees it. ^C just
+    // ends it.
+    let core = match process::get_by_pid(pid) {
+        Some(p) if signal != process::SIGINT => coredump::dump(p, unsafe { &*frame }, signal),
+        _ => false,
+    };
+    println!(
+        "Process {} killed by signal {} at 0x{:08x}{}",
+        pid,
+        signal,
+        pc,
+        if core { " (core dumped)" } else { "" }
+    );
+    let status = if core { signal | WCOREFLAG } else { signal };
+    process::exit(pid, status);
+    cpu::switch_to(sched::schedule());
+}
@@ -1,99 +1,826 @@
-use core::fmt::{Error, Write};
+// The UART driver, for 16550s and for the simpler UART of SiFive's chips.
+// Every 16550 is tested in loopback mode when it's set up, see
+// Uart::self_test. There can be several UARTs, which are found in the
+// device tree. One of them carries the kernel log (print!) and one is the
+// console that user programs talk to. Both are the device tree's
+// stdout-path unless the kernel command line (bootargs) says otherwise:
+// log=ttyS<n> moves the log and console=ttyS<n> the console, where <n>
+// counts the UARTs in address order. uart.fifo=off runs 16550s without their
+// FIFOs, and uart.fifo=1, 4, 8 or 14 sets how full the receive FIFO gets
+// before it interrupts.
 
-/// NS16550a Universal Asynchronous Receiver / Transmitter
+use crate::{
+    board,
+    console::RingBuffer,
+    cpu,
+    device::{self, Device},
+    fdt, plic, timer,
+};
+use core::{
+    fmt::{Error, Write},
+    ptr::addr_of_mut,
+};
+use log::warn;
+
+/// The clock of a 16550 whose device tree node doesn't say, which is
+/// QEMU's.
+const NS16550_CLOCK_HZ: u32 = 3_686_400;
+/// How many UARTs we drive.
+pub const MAX_UARTS: usize = 4;
+/// The signaling rate we program, in bits per second.
+pub const DEFAULT_BAUD: u32 = 115_200;
+
+/// How much output we queue before writers have to wait for the UART.
+const TX_BUFFER_SIZE: usize = 4096;
+/// How many bytes the transmit FIFO holds.
+const FIFO_SIZE: usize = 16;
+
+/// The parity bit sent after the data bits of every character, if any.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+/// The kinds of UART we know.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Ns16550,
+    /// The UART of SiFive's chips: 32-bit registers, 8-byte FIFOs, no
+    /// modem lines and no line errors.
+    Sifive,
+}
+
+// Register offsets from the base address. Several registers share an
+// offset: which one is accessed depends on whether it's a read or a write,
+// and on the DLAB bit of the LCR.
+const RBR: usize = 0; // Receiver buffer (read)
+const THR: usize = 0; // Transmitter holding (write)
+const DLL: usize = 0; // Divisor latch, low byte (DLAB = 1)
+const IER: usize = 1; // Interrupt enable
+const DLM: usize = 1; // Divisor latch, high byte (DLAB = 1)
+const IIR: usize = 2; // Interrupt identification (read)
+const FCR: usize = 2; // FIFO control (write)
+const LCR: usize = 3; // Line control
+const MCR: usize = 4; // Modem control
+const LSR: usize = 5; // Line status
+const MSR: usize = 6; // Modem status
+const SCR: usize = 7; // Scratch
+
+// IER bits
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+const IER_MODEM_STATUS: u8 = 1 << 3;
+// IIR bits
+/// Both bits are set when the FIFOs are on. A 16550 without the A, whose
+/// FIFOs don't work, only sets the top one, and a 16450 has none.
+const IIR_FIFO_ENABLED: u8 = 0b11 << 6;
+// FCR bits. The top two say how full the receive FIFO gets before it
+// interrupts, see RxTrigger.
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+const FCR_TRIGGER_SHIFT: u8 = 6;
+// LCR bits. The bottom two are the word length, 5 data bits and up: 8
+// data bits, no parity and 1 stop bit is just the word length.
+const LCR_8N1: u8 = 0b11;
+/// 2 stop bits (1.5 with 5 data bits).
+const LCR_STOP_2: u8 = 1 << 2;
+const LCR_PARITY: u8 = 1 << 3;
+/// Even parity instead of odd.
+const LCR_PARITY_EVEN: u8 = 1 << 4;
+const LCR_DLAB: u8 = 1 << 7;
+// MCR bits. OUT2 gates the interrupt line on PC-style boards.
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOP: u8 = 1 << 4;
+const MCR_AUTO_FLOW: u8 = 1 << 5;
+// MSR bits
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DCD: u8 = 1 << 7;
+/// The bits of the MSR that say what the modem lines are.
+const MSR_LINES: u8 = 0xf0;
+// LSR bits
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_PARITY: u8 = 1 << 2;
+const LSR_FRAMING: u8 = 1 << 3;
+const LSR_BREAK: u8 = 1 << 4;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TX_IDLE: u8 = 1 << 6;
+
+// The registers of a SiFive UART
+const SIFIVE_TXDATA: usize = 0x00;
+const SIFIVE_RXDATA: usize = 0x04;
+const SIFIVE_TXCTRL: usize = 0x08;
+const SIFIVE_RXCTRL: usize = 0x0c;
+const SIFIVE_IE: usize = 0x10;
+const SIFIVE_IP: usize = 0x14;
+const SIFIVE_DIV: usize = 0x18;
+/// TXDATA: the FIFO is full. RXDATA: the FIFO is empty.
+const SIFIVE_FIFO_FLAG: u32 = 1 << 31;
+/// TXCTRL and RXCTRL: turn the transmitter or receiver on.
+const SIFIVE_ENABLE: u32 = 1 << 0;
+/// TXCTRL: send 2 stop bits.
+const SIFIVE_NSTOP: u32 = 1 << 1;
+/// TXCTRL: raise the transmit watermark interrupt when the FIFO holds
+/// fewer than one byte.
+const SIFIVE_TXCNT_EMPTY: u32 = 1 << 16;
+const SIFIVE_IE_TXWM: u32 = 1 << 0;
+const SIFIVE_IE_RXWM: u32 = 1 << 1;
+const SIFIVE_FIFO_SIZE: usize = 8;
+
+/// The registers of a 16550. Every access is volatile, since these are
+/// device registers and not memory: reading the RBR pops a byte off the
+/// receive FIFO, for example. Some boards space the registers out, 1 <<
+/// `shift` bytes apart.
+pub struct Registers {
+    base: usize,
+    shift: u32,
+}
+
+impl Registers {
+    pub const fn new(base: usize, shift: u32) -> Self {
+        Registers { base, shift }
+    }
+
+    fn read(&self, offset: usize) -> u8 {
+        let addr = self.base + (offset << self.shift);
+        unsafe { (addr as *const u8).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, val: u8) {
+        let addr = self.base + (offset << self.shift);
+        unsafe { (addr as *mut u8).write_volatile(val) }
+    }
+
+    /// The registers of a SiFive UART are 32 bits wide and not spaced out.
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write32(&self, offset: usize, val: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(val) }
+    }
+
+    pub fn rbr(&self) -> u8 {
+        self.read(RBR)
+    }
+
+    pub fn set_thr(&self, val: u8) {
+        self.write(THR, val)
+    }
+
+    pub fn ier(&self) -> u8 {
+        self.read(IER)
+    }
+
+    pub fn set_ier(&self, val: u8) {
+        self.write(IER, val)
+    }
+
+    pub fn set_fcr(&self, val: u8) {
+        self.write(FCR, val)
+    }
+
+    pub fn lcr(&self) -> u8 {
+        self.read(LCR)
+    }
+
+    pub fn set_lcr(&self, val: u8) {
+        self.write(LCR, val)
+    }
+
+    pub fn set_mcr(&self, val: u8) {
+        self.write(MCR, val)
+    }
+
+    pub fn lsr(&self) -> u8 {
+        self.read(LSR)
+    }
+
+    pub fn msr(&self) -> u8 {
+        self.read(MSR)
+    }
+
+    pub fn iir(&self) -> u8 {
+        self.read(IIR)
+    }
+
+    /// Program the baud rate divisor. The divisor latch shares its offsets
+    /// with the RBR/THR and the IER, so it is only reachable while the
+    /// Divisor Latch Access Bit of the LCR is set.
+    pub fn set_divisor(&self, divisor: u16) {
+        let lcr = self.lcr();
+        self.set_lcr(lcr | LCR_DLAB);
+        self.write(DLL, divisor as u8);
+        self.write(DLM, (divisor >> 8) as u8);
+        self.set_lcr(lcr);
+    }
+}
+
+/// How many bytes the receive FIFO of a 16550 holds before it interrupts.
+/// Fewer bytes than that still interrupt, once nothing more has come in
+/// for four characters' time. A deeper trigger means fewer interrupts, but
+/// less room left for what arrives while we get around to handling one.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RxTrigger {
+    One = 0,
+    Four = 1,
+    Eight = 2,
+    Fourteen = 3,
+}
+
+impl RxTrigger {
+    /// The trigger of `uart.fifo=<n>` on the kernel command line.
+    fn parse(arg: &str) -> Option<RxTrigger> {
+        match arg {
+            "1" => Some(RxTrigger::One),
+            "4" => Some(RxTrigger::Four),
+            "8" => Some(RxTrigger::Eight),
+            "14" => Some(RxTrigger::Fourteen),
+            _ => None,
+        }
+    }
+}
+
+/// How a UART keeps the two sides from overrunning each other.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    /// RTS/CTS handled by the UART itself (the AFE bit of 16750-style
+    /// UARTs): it drops RTS when its receive FIFO fills up and stops
+    /// sending while CTS is low. We still drop RTS when our own buffer
+    /// fills up.
+    Auto,
+    /// RTS/CTS handled by the driver: RTS follows how full the console's
+    /// input buffer is, and output waits for CTS.
+    Software,
+}
+
+/// The flow control that CRTSCTS in the terminal settings turns on. QEMU's
+/// 16550A has no automatic flow control, boards whose UART does can use
+/// FlowControl::Auto.
+pub const CRTSCTS_FLOW_CONTROL: FlowControl = FlowControl::Software;
+
+/// What a UART has been up to: how many bytes went each way, and how often
+/// each of the line errors the LSR reports happened.
+#[derive(Clone, Copy, Default)]
+pub struct Stats {
+    pub rx: usize,
+    pub tx: usize,
+    pub overrun: usize,
+    pub parity: usize,
+    pub framing: usize,
+    pub brk: usize,
+}
+
+impl Stats {
+    fn errors(&self) -> usize {
+        self.overrun + self.parity + self.framing + self.brk
+    }
+}
+
+/// A Universal Asynchronous Receiver / Transmitter
+///
+/// Output is queued in a ring buffer and fed to the transmit FIFO from the
+/// THR empty interrupt, so writers only wait for the UART when the buffer
+/// is full. A panic switches to writing synchronously, since there may
+/// never be another interrupt to drain the buffer.
 pub struct Uart {
-    base_addr: usize,
+    regs: Registers,
+    kind: Kind,
+    clock_hz: u32,
+    irq: u32,
+    tx: RingBuffer<TX_BUFFER_SIZE>,
+    sync: bool,
+    stats: Stats,
+    flow: FlowControl,
+    // Whether we asked the other side to stop sending.
+    throttled: bool,
+    // The trigger level of the receive FIFO, None if the FIFOs are off.
+    fifo: Option<RxTrigger>,
+    // The rate we programmed, and the character format, in LCR bits (a
+    // SiFive UART only has the stop bits of it).
+    baud: u32,
+    lcr: u8,
 }
 
 impl Uart {
-    pub fn new(base_addr: usize) -> Self {
-        Uart { base_addr }
-    }
-
-    pub fn init(&mut self) {
-        let ptr = self.base_addr as *mut u8;
-        unsafe {
-            // First, set the word length, which
-            // are bits 0, and 1 of the line control register (LCR)
-            // which is at base_address + 3
-            // We can easily write the value 3 here or 0b11, but I'm
-            // extending it so that it is clear we're setting two individual
-            // fields
-            //         Word 0     Word 1
-            //         ~~~~~~     ~~~~~~
-            let lcr = (1 << 0) | (1 << 1);
-            ptr.add(3).write_volatile(lcr);
-
-            // Now, enable the FIFO, which is bit index 0 of the FIFO
-            // control register (FCR at offset 2).
-            // Again, we can just write 1 here, but when we use left shift,
-            // it's easier to see that we're trying to write bit index #0.
-            ptr.add(2).write_volatile(1 << 0);
-
-            // Enable receiver buffer interrupts, which is at bit index
-            // 0 of the interrupt enable register (IER at offset 1).
-            ptr.add(1).write_volatile(1 << 0);
-
-            // If we cared about the divisor, the code below would set the divisor
-            // from a global clock rate of 22.729 MHz (22,729,000 cycles per second)
-            // to a signaling rate of 2400 (BAUD). We usually have much faster signalling
-            // rates nowadays, but this demonstrates what the divisor actually does.
-            // The formula given in the NS16500A specification for calculating the divisor
-            // is:
-            // divisor = ceil( (clock_hz) / (baud_sps x 16) )
-            // So, we substitute our values and get:
-            // divisor = ceil( 22_729_000 / (2400 x 16) )
-            // divisor = ceil( 22_729_000 / 38_400 )
-            // divisor = ceil( 591.901 ) = 592
-
-            // The divisor register is two bytes (16 bits), so we need to split the value
-            // 592 into two bytes. Typically, we would calculate this based on measuring
-            // the clock rate, but again, for our purposes [qemu], this doesn't really do
-            // anything.
-            let divisor: u16 = 592;
-            let divisor_least: u8 = (divisor & 0xff) as u8;
-            let divisor_most: u8 = (divisor >> 8) as u8;
-
-            // Notice that the divisor register DLL (divisor latch least) and DLM (divisor
-            // latch most) have the same base address as the receiver/transmitter and the
-            // interrupt enable register. To change what the base address points to, we
-            // open the "divisor latch" by writing 1 into the Divisor Latch Access Bit
-            // (DLAB), which is bit index 7 of the Line Control Register (LCR) which
-            // is at base_address + 3.
-            ptr.add(3).write_volatile(lcr | 1 << 7);
-
-            // Now, base addresses 0 and 1 point to DLL and DLM, respectively.
-            // Put the lower 8 bits of the divisor into DLL
-            ptr.add(0).write_volatile(divisor_least);
-            ptr.add(1).write_volatile(divisor_most);
-
-            // Now that we've written the divisor, we never have to touch this again. In
-            // hardware, this will divide the global clock (22.729 MHz) into one suitable
-            // for 2,400 signals per second. So, to once again get access to the
-            // RBR/THR/IER registers, we need to close the DLAB bit by clearing it to 0.
-            ptr.add(3).write_volatile(lcr);
+    /// A UART whose registers start at `base`, that is fed a clock of
+    /// `clock_hz` and that raises the PLIC interrupt `irq`. Nothing is
+    /// touched until init().
+    pub const fn new(base: usize, clock_hz: u32, irq: u32, kind: Kind) -> Self {
+        Uart {
+            regs: Registers::new(base, 0),
+            kind,
+            clock_hz,
+            irq,
+            tx: RingBuffer::new(),
+            sync: false,
+            stats: Stats {
+                rx: 0,
+                tx: 0,
+                overrun: 0,
+                parity: 0,
+                framing: 0,
+                brk: 0,
+            },
+            flow: FlowControl::None,
+            throttled: false,
+            fifo: None,
+            baud: DEFAULT_BAUD,
+            lcr: LCR_8N1,
+        }
+    }
+
+    /// The divisor for `baud`. The UART samples every bit 16 times, so the
+    /// NS16550A specification gives
+    /// divisor = ceil(clock_hz / (baud x 16))
+    /// QEMU's 3.6864 MHz clock divides evenly into the common rates, at
+    /// 115200 baud the divisor is 2.
+    fn divisor(&self, baud: u32) -> u16 {
+        let div = self.clock_hz.div_ceil(baud * 16).max(1);
+        div.min(u16::MAX as u32) as u16
+    }
+
+    pub fn init(&mut self, baud: u32) {
+        // Get out whatever was printed before we got here (the firmware
+        // left the UART usable), clearing the FIFOs would lose it.
+        self.flush();
+        self.baud = baud;
+        self.lcr = LCR_8N1;
+        if self.kind == Kind::Sifive {
+            self.init_sifive(baud);
+            return;
+        }
+        while self.regs.lsr() & LSR_TX_IDLE == 0 {}
+        let regs = &self.regs;
+        // 8 data bits, no parity and 1 stop bit. This also makes sure the
+        // DLAB is clear.
+        regs.set_lcr(LCR_8N1);
+        // Turn on the FIFOs, and throw away whatever the firmware may have
+        // left in them. If they don't come on (or they're the broken ones
+        // of the first 16550s), go without.
+        regs.set_fcr(FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX);
+        if regs.iir() & IIR_FIFO_ENABLED == IIR_FIFO_ENABLED {
+            self.set_fifo(Some(self.auto_trigger(baud)));
+        } else {
+            self.set_fifo(None);
+        }
+        let regs = &self.regs;
+        regs.set_divisor(self.divisor(baud));
+        // Interrupt us whenever a byte comes in. The THR empty interrupt is
+        // only turned on while there is output queued.
+        regs.set_ier(IER_RX_AVAILABLE);
+        // Tell the other side we're here, and ready to receive.
+        self.set_flow_control(self.flow);
+    }
+
+    /// The receive trigger for interrupt-driven input at `baud`. The
+    /// faster bytes come in, the more interrupts a trigger of one byte
+    /// costs, so above 38400 baud we wait for 8. 14 leaves only two bytes of
+    /// room, which is too little to wait for an interrupt with, unless the
+    /// UART drops RTS by itself. Without an interrupt, input is polled and
+    /// the trigger doesn't matter.
+    fn auto_trigger(&self, baud: u32) -> RxTrigger {
+        if self.irq == 0 || baud <= 38_400 {
+            RxTrigger::One
+        } else if self.flow == FlowControl::Auto {
+            RxTrigger::Fourteen
+        } else {
+            RxTrigger::Eight
+        }
+    }
+
+    /// Turn the FIFOs of a 16550 on, interrupting once the receive FIFO
+    /// holds `trigger` bytes, or off with None. Turning them on or off
+    /// empties them. A SiFive UART's FIFOs are always on, and interrupt
+    /// at the first byte, since it has no timeout for fewer bytes than its
+    /// watermark.
+    pub fn set_fifo(&mut self, fifo: Option<RxTrigger>) {
+        if self.kind == Kind::Sifive {
+            return;
+        }
+        cpu::without_interrupts(|| {
+            // What's in the transmit FIFO has to go out first.
+            self.flush();
+            while self.regs.lsr() & LSR_TX_IDLE == 0 {}
+            if self.fifo.is_some() != fifo.is_some() {
+                // Changing FCR_ENABLE clears the FIFOs anyway.
+                self.regs.set_fcr(FCR_CLEAR_RX | FCR_CLEAR_TX);
+            }
+            self.fifo = fifo;
+            self.reset_fifos(false, false);
+        });
+    }
+
+    /// Throw away what's in the receive FIFO, the transmit FIFO, or both.
+    /// This also writes the current FIFO settings back, which is how they
+    /// are programmed: the FCR can't be read.
+    pub fn reset_fifos(&self, rx: bool, tx: bool) {
+        if self.kind == Kind::Sifive {
+            return;
+        }
+        let Some(trigger) = self.fifo else {
+            self.regs.set_fcr(0);
+            return;
+        };
+        let mut fcr = FCR_ENABLE | (trigger as u8) << FCR_TRIGGER_SHIFT;
+        if rx {
+            fcr |= FCR_CLEAR_RX;
+        }
+        if tx {
+            fcr |= FCR_CLEAR_TX;
+        }
+        self.regs.set_fcr(fcr);
+    }
+
+    /// How many bytes we can write to the THR at once.
+    fn tx_fifo_size(&self) -> usize {
+        if self.fifo.is_some() {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    /// Check that the UART behaves like a 16550A, for boards whose UART
+    /// only claims to be one. In loopback mode the transmitter is wired to
+    /// the receiver and the modem control outputs to the modem status
+    /// inputs, inside the UART, so nothing goes out on the line: we can
+    /// send a FIFO's worth of bytes and see that all of them come back, and
+    /// that the modem lines follow. Returns what's wrong, if anything.
+    /// This has to run right after init(), before there's output queued.
+    fn self_test(&mut self) -> Result<(), &'static str> {
+        if self.kind != Kind::Ns16550 {
+            return Ok(());
+        }
+        let ier = self.regs.ier();
+        let ret = cpu::without_interrupts(|| {
+            self.regs.set_ier(0);
+            self.loopback_test()
+        });
+        // Throw away what's left of the test, and go back to normal.
+        self.reset_fifos(true, true);
+        self.regs.set_ier(ier);
+        self.update_mcr();
+        // Without FIFOs, the UART works, but not like a 16550A.
+        if ret.is_ok() && self.fifo.is_none() {
+            return Err("its FIFOs don't work, so they're off");
+        }
+        ret
+    }
+
+    fn loopback_test(&self) -> Result<(), &'static str> {
+        let regs = &self.regs;
+        // The 8250 didn't have a scratch register.
+        for val in [0x55, 0xaa] {
+            regs.write(SCR, val);
+            if regs.read(SCR) != val {
+                return Err("the scratch register doesn't hold a value");
+            }
+        }
+        // RTS comes back as CTS, OUT2 as DCD.
+        regs.set_mcr(MCR_LOOP);
+        if regs.msr() & MSR_LINES != 0 {
+            return Err("the modem lines don't follow in loopback mode");
+        }
+        regs.set_mcr(MCR_LOOP | MCR_RTS | MCR_OUT2);
+        if regs.msr() & MSR_LINES != MSR_CTS | MSR_DCD {
+            return Err("the modem lines don't follow in loopback mode");
+        }
+        // Waiting for the bytes at the rate we programmed takes a little
+        // over a millisecond, give it plenty more.
+        let deadline = cpu::get_mtime() + timer::duration_to_ticks(0, 50_000_000);
+        self.reset_fifos(true, true);
+        let pattern = |i: usize| (i as u8).wrapping_mul(0x35) ^ 0xa5;
+        let count = self.tx_fifo_size();
+        for i in 0..count {
+            while regs.lsr() & LSR_THR_EMPTY == 0 {
+                if cpu::get_mtime() > deadline {
+                    return Err("the transmit FIFO doesn't drain in loopback mode");
+                }
+            }
+            regs.set_thr(pattern(i));
+        }
+        while regs.lsr() & LSR_TX_IDLE == 0 {
+            if cpu::get_mtime() > deadline {
+                return Err("the transmitter doesn't go idle in loopback mode");
+            }
+        }
+        // All of it has to have fit in the receive FIFO.
+        for i in 0..count {
+            let lsr = regs.lsr();
+            if lsr & LSR_DATA_READY == 0 {
+                return Err("bytes sent in loopback mode didn't come back");
+            }
+            if lsr & LSR_OVERRUN != 0 {
+                return Err("the receive FIFO overran in loopback mode");
+            }
+            if regs.rbr() != pattern(i) {
+                return Err("bytes sent in loopback mode came back wrong");
+            }
+        }
+        if regs.lsr() & LSR_DATA_READY != 0 {
+            return Err("more came back in loopback mode than was sent");
+        }
+        Ok(())
+    }
+
+    /// A SiFive UART sends at clock_hz / (div + 1) baud. If we don't know
+    /// its clock, the divisor the firmware programmed stays.
+    fn init_sifive(&mut self, baud: u32) {
+        let regs = &self.regs;
+        if self.clock_hz != 0 {
+            let div = (self.clock_hz + baud / 2) / baud;
+            regs.write32(SIFIVE_DIV, div.saturating_sub(1));
+        }
+        regs.write32(SIFIVE_TXCTRL, SIFIVE_ENABLE | SIFIVE_TXCNT_EMPTY);
+        // The receive watermark interrupt is raised while the FIFO holds
+        // more than zero bytes.
+        regs.write32(SIFIVE_RXCTRL, SIFIVE_ENABLE);
+        regs.write32(SIFIVE_IE, SIFIVE_IE_RXWM);
+    }
+
+    /// Switch to another signaling rate and character format: 5 to 8 data
+    /// bits, a parity bit or none, and 1 or 2 stop bits. The rate comes
+    /// from dividing the UART's clock, which few rates go into evenly, so
+    /// we get as close to `baud` as the clock allows and return the rate
+    /// we got. A SiFive UART only does 8 data bits without parity, and
+    /// neither kind can change its rate if we don't know its clock. What
+    /// was queued goes out at the old settings first.
+    pub fn configure(
+        &mut self,
+        baud: u32,
+        data_bits: u32,
+        parity: Parity,
+        stop_bits: u32,
+    ) -> Result<u32, &'static str> {
+        if baud == 0 || !(5..=8).contains(&data_bits) || !(1..=2).contains(&stop_bits) {
+            return Err("there's no such line setting");
+        }
+        if baud != self.baud && self.clock_hz == 0 {
+            return Err("its clock is unknown");
+        }
+        let mut lcr = (data_bits - 5) as u8;
+        if stop_bits == 2 {
+            lcr |= LCR_STOP_2;
+        }
+        match parity {
+            Parity::None => {}
+            Parity::Odd => lcr |= LCR_PARITY,
+            Parity::Even => lcr |= LCR_PARITY | LCR_PARITY_EVEN,
+        }
+        if self.kind == Kind::Sifive && lcr & !LCR_STOP_2 != LCR_8N1 {
+            return Err("it only does 8 data bits without parity");
+        }
+        cpu::without_interrupts(|| {
+            self.flush();
+            if self.kind == Kind::Sifive {
+                self.configure_sifive(baud, lcr);
+                return;
+            }
+            while self.regs.lsr() & LSR_TX_IDLE == 0 {}
+            self.regs.set_lcr(lcr);
+            self.lcr = lcr;
+            if baud == self.baud {
+                return;
+            }
+            let div = self.divisor(baud);
+            self.regs.set_divisor(div);
+            self.baud = self.clock_hz / (16 * div as u32);
+            // The FIFO trigger we picked depends on the rate, unless the
+            // command line picked it.
+            if self.fifo.is_some() && fifo_arg().is_none() {
+                self.fifo = Some(self.auto_trigger(self.baud));
+                self.reset_fifos(false, false);
+            }
+        });
+        Ok(self.baud)
+    }
+
+    fn configure_sifive(&mut self, baud: u32, lcr: u8) {
+        let regs = &self.regs;
+        // Once the transmit watermark is pending, the FIFO is empty.
+        while regs.read32(SIFIVE_IP) & SIFIVE_IE_TXWM == 0 {}
+        let mut txctrl = SIFIVE_ENABLE | SIFIVE_TXCNT_EMPTY;
+        if lcr & LCR_STOP_2 != 0 {
+            txctrl |= SIFIVE_NSTOP;
+        }
+        regs.write32(SIFIVE_TXCTRL, txctrl);
+        self.lcr = lcr;
+        if baud != self.baud {
+            let div = ((self.clock_hz + baud / 2) / baud).max(1);
+            regs.write32(SIFIVE_DIV, div - 1);
+            self.baud = self.clock_hz / div;
+        }
+    }
+
+    /// Switch to another kind of flow control. A SiFive UART has no modem
+    /// lines, so there all kinds are no flow control.
+    pub fn set_flow_control(&mut self, flow: FlowControl) {
+        cpu::without_interrupts(|| {
+            self.flow = flow;
+            if self.kind == Kind::Sifive {
+                self.start_tx();
+                return;
+            }
+            self.update_mcr();
+            // To send only while CTS is asserted, we have to hear about it
+            // coming back.
+            let ier = self.regs.ier() & !IER_MODEM_STATUS;
+            if flow == FlowControl::Software {
+                self.regs.set_ier(ier | IER_MODEM_STATUS);
+            } else {
+                self.regs.set_ier(ier);
+            }
+            self.start_tx();
+        });
+    }
+
+    fn update_mcr(&self) {
+        if self.kind == Kind::Sifive {
+            return;
+        }
+        let mut mcr = MCR_DTR | MCR_OUT2;
+        if !self.throttled || self.flow == FlowControl::None {
+            mcr |= MCR_RTS;
+        }
+        if self.flow == FlowControl::Auto {
+            mcr |= MCR_AUTO_FLOW;
+        }
+        self.regs.set_mcr(mcr);
+    }
+
+    /// Ask the other side to stop sending, by deasserting RTS. This does
+    /// nothing without flow control.
+    pub fn throttle_rx(&mut self) {
+        if !self.throttled {
+            self.throttled = true;
+            self.update_mcr();
+        }
+    }
+
+    /// Let the other side send again.
+    pub fn unthrottle_rx(&mut self) {
+        if self.throttled {
+            self.throttled = false;
+            self.update_mcr();
+        }
+    }
+
+    /// Write a byte straight to the transmitter, waiting for room. This
+    /// doesn't wait for CTS: the other side not listening is no reason for
+    /// the kernel to hang.
+    fn put_sync(&mut self, c: u8) {
+        if self.kind == Kind::Sifive {
+            while self.regs.read32(SIFIVE_TXDATA) & SIFIVE_FIFO_FLAG != 0 {}
+            self.regs.write32(SIFIVE_TXDATA, c as u32);
+            return;
+        }
+        while self.regs.lsr() & LSR_THR_EMPTY == 0 {}
+        self.regs.set_thr(c);
+    }
+
+    /// Move as much queued output as fits into the transmit FIFO, and ask
+    /// for an interrupt when it's empty again if there's more to send.
+    fn start_tx(&mut self) {
+        if self.kind == Kind::Sifive {
+            self.start_tx_sifive();
+            return;
+        }
+        // Reading the MSR also acknowledges the modem status interrupt.
+        let cts = self.flow != FlowControl::Software || self.regs.msr() & MSR_CTS != 0;
+        if cts && self.regs.lsr() & LSR_THR_EMPTY != 0 {
+            for _ in 0..self.tx_fifo_size() {
+                let Some(c) = self.tx.pop() else {
+                    break;
+                };
+                self.regs.set_thr(c);
+            }
+        }
+        // While CTS is low, the modem status interrupt tells us when to go
+        // on.
+        let ier = self.regs.ier();
+        if self.tx.is_empty() || !cts {
+            self.regs.set_ier(ier & !IER_THR_EMPTY);
+        } else {
+            self.regs.set_ier(ier | IER_THR_EMPTY);
+        }
+    }
+
+    /// start_tx() for a SiFive UART, whose FIFO says when it's full.
+    fn start_tx_sifive(&mut self) {
+        let regs = &self.regs;
+        for _ in 0..SIFIVE_FIFO_SIZE {
+            if regs.read32(SIFIVE_TXDATA) & SIFIVE_FIFO_FLAG != 0 {
+                break;
+            }
+            let Some(c) = self.tx.pop() else {
+                break;
+            };
+            regs.write32(SIFIVE_TXDATA, c as u32);
+        }
+        let ie = regs.read32(SIFIVE_IE);
+        if self.tx.is_empty() {
+            regs.write32(SIFIVE_IE, ie & !SIFIVE_IE_TXWM);
+        } else {
+            regs.write32(SIFIVE_IE, ie | SIFIVE_IE_TXWM);
         }
     }
 
     pub fn put(&mut self, c: u8) {
-        let ptr = self.base_addr as *mut u8;
-        unsafe {
-            ptr.add(0).write_volatile(c);
+        self.stats.tx += 1;
+        if self.sync {
+            self.put_sync(c);
+            return;
         }
+        // The interrupt handler takes bytes out of the buffer, it mustn't
+        // run while we put one in.
+        cpu::without_interrupts(|| {
+            if self.tx.is_full() {
+                // Make room the slow way.
+                let old = self.tx.pop().unwrap();
+                self.put_sync(old);
+            }
+            self.tx.push(c);
+            self.start_tx();
+        });
     }
 
+    /// How many bytes can be written without waiting for the UART.
+    pub fn room(&self) -> usize {
+        if self.sync {
+            return usize::MAX;
+        }
+        TX_BUFFER_SIZE - self.tx.len()
+    }
+
+    /// Wait until all queued output has been handed to the transmitter.
+    pub fn flush(&mut self) {
+        cpu::without_interrupts(|| {
+            while let Some(c) = self.tx.pop() {
+                self.put_sync(c);
+            }
+            self.start_tx();
+        });
+    }
+
+    /// Flush, and write everything synchronously from now on. This is for
+    /// the panic handler: whatever it prints must come out, even though
+    /// interrupts are never going to be handled again.
+    pub fn make_synchronous(&mut self) {
+        self.flush();
+        self.sync = true;
+    }
+
+    /// Take the next good byte the UART received. Bytes that arrived with a
+    /// parity or framing error are garbage and are dropped, and so are the
+    /// zero bytes a break shows up as. Every error is counted.
     pub fn get(&mut self) -> Option<u8> {
-        let ptr = self.base_addr as *mut u8;
-        unsafe {
-            if ptr.add(5).read_volatile() & 1 == 0 {
+        if self.kind == Kind::Sifive {
+            let rx = self.regs.read32(SIFIVE_RXDATA);
+            if rx & SIFIVE_FIFO_FLAG != 0 {
+                return None;
+            }
+            self.stats.rx += 1;
+            return Some(rx as u8);
+        }
+        loop {
+            // The error bits describe the byte at the front of the receive
+            // FIFO, and reading the LSR clears them.
+            let lsr = self.regs.lsr();
+            if lsr & LSR_DATA_READY == 0 {
                 // The DR bit is 0, meaning no data
-                None
+                return None;
+            }
+            // The DR bit is 1, meaning data!
+            let c = self.regs.rbr();
+            let stats = &mut self.stats;
+            // An overrun means bytes were lost because the FIFO was full,
+            // the byte we got is fine.
+            if lsr & LSR_OVERRUN != 0 {
+                stats.overrun += 1;
+            }
+            if lsr & LSR_BREAK != 0 {
+                stats.brk += 1;
+            } else if lsr & LSR_PARITY != 0 {
+                stats.parity += 1;
+            } else if lsr & LSR_FRAMING != 0 {
+                stats.framing += 1;
             } else {
-                // The DR bit is 1, meaning data!
-                Some(ptr.add(0).read_volatile())
+                stats.rx += 1;
+                return Some(c);
             }
         }
     }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
 }
 
 impl Write for Uart {
@@ -107,3 +834,232 @@ impl Write for Uart {
         Ok(())
     }
 }
+
+static mut UARTS: [Uart; MAX_UARTS] = [
+    Uart::new(
+        board::UART0_BASE,
+        board::UART0_CLOCK_HZ,
+        board::UART0_IRQ,
+        board::UART0_KIND,
+    ),
+    Uart::new(0, 0, 0, Kind::Ns16550),
+    Uart::new(0, 0, 0, Kind::Ns16550),
+    Uart::new(0, 0, 0, Kind::Ns16550),
+];
+static mut COUNT: usize = 1;
+// Which UARTs the kernel log and the console are on.
+static mut LOG: usize = 0;
+static mut CONSOLE: usize = 0;
+
+fn uarts() -> &'static mut [Uart] {
+    unsafe { &mut (&mut *addr_of_mut!(UARTS))[..COUNT] }
+}
+
+/// The UART the kernel log goes to.
+pub fn log() -> &'static mut Uart {
+    &mut uarts()[unsafe { LOG }]
+}
+
+/// The UART the console is on.
+pub fn console() -> &'static mut Uart {
+    &mut uarts()[unsafe { CONSOLE }]
+}
+
+/// Which ttyS<n> the console is on.
+pub fn console_number() -> usize {
+    unsafe { CONSOLE }
+}
+
+/// The n in a ttyS<n> of option `key` on the kernel command line.
+fn tty_arg(bootargs: &str, key: &str) -> Option<usize> {
+    bootargs
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix(key)?.strip_prefix("=ttyS"))
+        .and_then(|n| n.parse().ok())
+}
+
+/// What uart.fifo= on the kernel command line asks of every 16550: off, or
+/// the trigger level of its receive FIFO, instead of the one we'd pick.
+fn fifo_arg() -> Option<Option<RxTrigger>> {
+    let arg = fdt::get()?
+        .find("/chosen")?
+        .str_property("bootargs")?
+        .split_ascii_whitespace()
+        .find_map(|arg| arg.strip_prefix("uart.fifo="))?;
+    if arg == "off" {
+        return Some(None);
+    }
+    RxTrigger::parse(arg).map(Some)
+}
+
+/// The driver for 16550s. The first UART either driver is given replaces
+/// the default one, which is the board's.
+pub struct Ns16550;
+
+impl device::Driver for Ns16550 {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["ns16550a"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let Some(base) = dev.base() else {
+            return false;
+        };
+        let node = dev.node;
+        let clock_hz = node
+            .and_then(|n| n.u32_property("clock-frequency"))
+            .unwrap_or(NS16550_CLOCK_HZ);
+        let shift = node.and_then(|n| n.u32_property("reg-shift")).unwrap_or(0);
+        add(base, clock_hz, dev.irq().unwrap_or(0), shift, Kind::Ns16550)
+    }
+
+    fn remove(&self, dev: &Device) {
+        remove(dev);
+    }
+}
+
+/// The driver for SiFive UARTs. Their clock is the chip's peripheral
+/// clock, which device trees give as a clocks reference we don't follow,
+/// so unless the node has a clock-frequency we keep the baud rate the
+/// firmware set.
+pub struct SifiveUart;
+
+impl device::Driver for SifiveUart {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["sifive,uart0"]
+    }
+
+    fn probe(&self, dev: &Device) -> bool {
+        let Some(base) = dev.base() else {
+            return false;
+        };
+        let clock_hz = dev
+            .node
+            .and_then(|n| n.u32_property("clock-frequency"))
+            .unwrap_or(0);
+        add(base, clock_hz, dev.irq().unwrap_or(0), 0, Kind::Sifive)
+    }
+
+    fn remove(&self, dev: &Device) {
+        remove(dev);
+    }
+}
+
+/// Stop using the UART of `dev`: it's about to go away, anything printed
+/// after this has to come out right away.
+fn remove(dev: &Device) {
+    if let Some(uart) = uarts().iter_mut().find(|u| Some(u.regs.base) == dev.base()) {
+        uart.make_synchronous();
+    }
+}
+
+// Whether the default UART has been replaced by one we were told about.
+static mut FOUND: bool = false;
+
+/// Set up a UART and let it interrupt us. Returns false if we have as many
+/// as we can drive.
+fn add(base: usize, clock_hz: u32, irq: u32, shift: u32, kind: Kind) -> bool {
+    let all = unsafe { &mut *addr_of_mut!(UARTS) };
+    let test;
+    unsafe {
+        if !FOUND {
+            // Whatever was printed so far went to the default UART, get it
+            // out before that gets replaced.
+            log().flush();
+            COUNT = 0;
+            FOUND = true;
+        }
+        if COUNT == MAX_UARTS {
+            return false;
+        }
+        all[COUNT] = Uart::new(base, clock_hz, irq, kind);
+        all[COUNT].regs.shift = shift;
+        all[COUNT].init(DEFAULT_BAUD);
+        if let Some(fifo) = fifo_arg() {
+            all[COUNT].set_fifo(fifo);
+        }
+        test = all[COUNT].self_test();
+        COUNT += 1;
+    }
+    plic::enable(irq);
+    plic::set_priority(irq, 1);
+    pick();
+    // Now that it's among our UARTs, the log may be on it.
+    if let Err(why) = test {
+        warn!("ttyS{}: this is no 16550A, {}", uarts().len() - 1, why);
+    }
+    true
+}
+
+/// Decide which UARTs the log and the console are on, out of the ones we
+/// have so far.
+fn pick() {
+    let Some(fdt) = fdt::get() else {
+        return;
+    };
+    let count = uarts().len();
+    let chosen = fdt.find("/chosen");
+    // stdout-path can have options after a colon, like "serial0:115200n8".
+    let stdout = chosen
+        .and_then(|c| c.str_property("stdout-path"))
+        .and_then(|path| fdt.find(path.split(':').next().unwrap()))
+        .and_then(|node| node.reg())
+        .and_then(|(base, _)| uarts().iter().position(|u| u.regs.base == base))
+        .unwrap_or(0);
+    let bootargs = chosen
+        .and_then(|c| c.str_property("bootargs"))
+        .unwrap_or("");
+    let pick = |key| {
+        tty_arg(bootargs, key)
+            .filter(|&n| n < count)
+            .unwrap_or(stdout)
+    };
+    unsafe {
+        LOG = pick("log");
+        CONSOLE = pick("console");
+    }
+}
+
+/// Switch every UART to synchronous output, see Uart::make_synchronous.
+pub fn make_synchronous() {
+    for uart in uarts().iter_mut() {
+        uart.make_synchronous();
+    }
+}
+
+/// Is `irq` the interrupt of one of our UARTs?
+pub fn has_irq(irq: u32) -> bool {
+    uarts().iter().any(|u| u.irq == irq)
+}
+
+/// Drain the receivers of the UARTs that raise `irq` and keep their
+/// transmitters going. What the console's UART receives is console input,
+/// other UARTs don't have anyone listening yet. This is called by the PLIC
+/// handler.
+pub fn handle_interrupt(irq: u32) {
+    let console = unsafe { CONSOLE };
+    for (n, uart) in uarts().iter_mut().enumerate() {
+        if uart.irq != irq {
+            continue;
+        }
+        let before = uart.stats();
+        while let Some(c) = uart.get() {
+            if n == console {
+                crate::console::push(crate::console::Source::Uart, c);
+            }
+        }
+        uart.start_tx();
+        // Say so if the line is bad, but only once per interrupt.
+        let after = uart.stats();
+        if after.errors() != before.errors() {
+            warn!(
+                "ttyS{}: {} overrun, {} parity, {} framing, {} break",
+                n,
+                after.overrun - before.overrun,
+                after.parity - before.parity,
+                after.framing - before.framing,
+                after.brk - before.brk
+            );
+        }
+    }
+}
@@ -0,0 +1,249 @@
+// Programs that run in user mode. Until we can load programs from disk,
+// they are compiled into the kernel and run with the kernel's text and
+// read-only data mapped into their address space. They must not touch any
+// kernel data (no println!, no statics), only make system calls.
+
+use crate::{
+    file::{O_DIRECTORY, O_RDONLY, SEEK_SET, S_IFCHR, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG},
+    syscall::{
+        Stat, TimeSpec, AT_FDCWD, AT_SYMLINK_NOFOLLOW, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ,
+        PROT_WRITE, STDOUT, SYS_BRK, SYS_CHDIR, SYS_CLOSE, SYS_GETCWD, SYS_GETDENTS64, SYS_GETPID,
+        SYS_LSEEK, SYS_MMAP, SYS_MUNMAP, SYS_NANOSLEEP, SYS_NEWFSTATAT, SYS_OPENAT, SYS_WRITE,
+    },
+    vfs,
+};
+use core::arch::asm;
+
+// ///////////////////////////////////
+// / SYSTEM CALL WRAPPERS
+// ///////////////////////////////////
+
+fn syscall(number: usize, a0: usize, a1: usize, a2: usize) -> isize {
+    syscall6(number, [a0, a1, a2, 0, 0, 0])
+}
+
+fn syscall6(number: usize, args: [usize; 6]) -> isize {
+    let ret: isize;
+    unsafe {
+        asm!("ecall",
+            inlateout("a0") args[0] => ret,
+            in("a1") args[1],
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a5") args[5],
+            in("a7") number);
+    }
+    ret
+}
+
+pub fn write(fd: usize, buf: &[u8]) -> isize {
+    syscall(SYS_WRITE, fd, buf.as_ptr() as usize, buf.len())
+}
+
+pub fn nanosleep(req: &TimeSpec) -> isize {
+    syscall(SYS_NANOSLEEP, req as *const TimeSpec as usize, 0, 0)
+}
+
+pub fn getpid() -> isize {
+    syscall(SYS_GETPID, 0, 0, 0)
+}
+
+pub fn brk(addr: usize) -> usize {
+    syscall(SYS_BRK, addr, 0, 0) as usize
+}
+
+pub fn mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: isize, off: usize) -> isize {
+    syscall6(SYS_MMAP, [addr, len, prot, flags, fd as usize, off])
+}
+
+pub fn munmap(addr: usize, len: usize) -> isize {
+    syscall(SYS_MUNMAP, addr, len, 0)
+}
+
+/// `path` has to end in a NUL.
+pub fn openat(dirfd: isize, path: &[u8], flags: usize) -> isize {
+    syscall(SYS_OPENAT, dirfd as usize, path.as_ptr() as usize, flags)
+}
+
+pub fn close(fd: usize) -> isize {
+    syscall(SYS_CLOSE, fd, 0, 0)
+}
+
+pub fn getdents64(fd: usize, buf: &mut [u8]) -> isize {
+    syscall(SYS_GETDENTS64, fd, buf.as_mut_ptr() as usize, buf.len())
+}
+
+pub fn lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    syscall(SYS_LSEEK, fd, offset as usize, whence)
+}
+
+pub fn getcwd(buf: &mut [u8]) -> isize {
+    syscall(SYS_GETCWD, buf.as_mut_ptr() as usize, buf.len(), 0)
+}
+
+/// `path` has to end in a NUL.
+pub fn chdir(path: &[u8]) -> isize {
+    syscall(SYS_CHDIR, path.as_ptr() as usize, 0, 0)
+}
+
+/// `path` has to end in a NUL.
+pub fn newfstatat(dirfd: isize, path: &[u8], st: &mut Stat, flags: usize) -> isize {
+    let (path, st) = (path.as_ptr() as usize, st as *mut Stat as usize);
+    syscall6(SYS_NEWFSTATAT, [dirfd as usize, path, st, flags, 0, 0])
+}
+
+// ///////////////////////////////////
+// / HELPERS
+// ///////////////////////////////////
+
+/// Write `n` in decimal.
+fn write_num(fd: usize, mut n: u64) {
+    let mut digits = [0; 20];
+    let mut i = digits.len();
+    loop {
+        i -= 1;
+        digits[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    write(fd, &digits[i..]);
+}
+
+/// Read the entries of the directory open as `fd`, at the path `dir`,
+/// from where its offset is to the end, and return how many there
+/// were. If `print`, print each one like ls -l would, more or less: its
+/// type, size and name.
+fn list(fd: usize, dir: &[u8], print: bool) -> Option<usize> {
+    // Paths are put together here, there's no heap to put them in.
+    let mut path = [0; 256];
+    let mut at = dir.len();
+    path[..at].copy_from_slice(dir);
+    if !dir.ends_with(b"/") {
+        path[at] = b'/';
+        at += 1;
+    }
+    let (mut buf, mut count) = ([0; 512], 0);
+    loop {
+        let n = getdents64(fd, &mut buf);
+        if n < 0 {
+            return None;
+        }
+        if n == 0 {
+            return Some(count);
+        }
+        for (_, _, name) in vfs::dirents(&buf[..n as usize]) {
+            count += 1;
+            let Some(end) = path.get_mut(at..at + name.len() + 1).filter(|_| print) else {
+                continue;
+            };
+            end[..name.len()].copy_from_slice(name);
+            end[name.len()] = 0;
+            let mut st = Stat::default();
+            newfstatat(AT_FDCWD, &path, &mut st, AT_SYMLINK_NOFOLLOW);
+            let kind = match st.st_mode & S_IFMT {
+                S_IFDIR => b"  d ",
+                S_IFREG => b"  - ",
+                S_IFLNK => b"  l ",
+                S_IFCHR => b"  c ",
+                _ => b"  ? ",
+            };
+            write(STDOUT, kind);
+            write_num(STDOUT, st.st_size as u64);
+            write(STDOUT, b" ");
+            write(STDOUT, name);
+            write(STDOUT, b"\r\n");
+        }
+    }
+}
+
+/// List the directory at `dir`, then seek back to its start
+/// and read it again, which has to give as many entries.
+fn ls(dir: &[u8]) {
+    let mut path = [0; 256];
+    path[..dir.len()].copy_from_slice(dir);
+    let fd = openat(AT_FDCWD, &path, O_RDONLY | O_DIRECTORY);
+    if fd < 0 {
+        write(STDOUT, b"init: can't open the directory to list\r\n");
+        return;
+    }
+    let fd = fd as usize;
+    let first = list(fd, dir, true);
+    lseek(fd, 0, SEEK_SET);
+    if first.is_none() || list(fd, dir, false) != first {
+        write(
+            STDOUT,
+            b"init: listing the directory again gave something else\r\n",
+        );
+    }
+    close(fd);
+}
+
+// ///////////////////////////////////
+// / PROGRAMS
+// ///////////////////////////////////
+
+/// The first user process. It checks that the heap can grow and that
+/// memory can be mapped, goes into the root directory and lists it, then
+/// idles. The test kernel doesn't start it.
+#[cfg_attr(test, allow(dead_code))]
+pub fn init() {
+    write(STDOUT, b"init: running in user mode\r\n");
+    let start = brk(0);
+    let end = brk(start + 4096);
+    if end == start + 4096 {
+        // The new page must be there and zeroed.
+        let ptr = start as *mut u64;
+        unsafe {
+            if ptr.read_volatile() == 0 {
+                ptr.write_volatile(getpid() as u64);
+            }
+        }
+        brk(start);
+    } else {
+        write(STDOUT, b"init: brk failed\r\n");
+    }
+    // Two pages of anonymous memory, each populated on first touch.
+    let len = 2 * 4096;
+    let addr = mmap(
+        0,
+        len,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        -1,
+        0,
+    );
+    if addr < 0 {
+        write(STDOUT, b"init: mmap failed\r\n");
+    } else {
+        let ptr = addr as *mut u64;
+        unsafe {
+            ptr.write_volatile(1);
+            ptr.add(512).write_volatile(2);
+        }
+        munmap(addr as usize, len);
+    }
+    let mut cwd = [0; 256];
+    let n = if chdir(b"/\0") == 0 {
+        getcwd(&mut cwd)
+    } else {
+        -1
+    };
+    if n > 0 {
+        write(STDOUT, b"init: ls ");
+        write(STDOUT, &cwd[..n as usize - 1]);
+        write(STDOUT, b"\r\n");
+        ls(b".");
+    } else {
+        write(STDOUT, b"init: can't go into /\r\n");
+    }
+    let second = TimeSpec {
+        tv_sec: 1,
+        tv_nsec: 0,
+    };
+    loop {
+        nanosleep(&second);
+    }
+}
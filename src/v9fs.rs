@@ -0,0 +1,661 @@
+// Host directories shared over virtio-9p, like QEMU's -virtfs
+// local,path=<dir>,mount_tag=<tag>,security_model=none. The device has one
+// queue, and every request on it is a 9P message and room for the answer.
+// We speak 9P2000.L, the dialect of 9P that Linux's own client uses: it has
+// Linux's open flags, errnos and file attributes, so files on the host
+// look here the way they look there.
+//
+// Each share is mounted at /mnt/<tag> when its device is set up. In 9P,
+// files are reached through fids, numbers we pick for them: the root of
+// the share is attached as fid 0, and walking from a directory's fid to a
+// name in it gives that file a fid of its own. An inode holds on to its
+// fid; opening it makes a copy of the fid, with a walk to no names, which
+// is opened, read, written and finally clunked when the file is closed.
+//
+// Requests are polled for, one at a time: the host answers them from its
+// own threads, so that's quick, and the kernel never has two going.
+
+use crate::{
+    dma::{self, DmaBuffer},
+    file::{File, O_ACCMODE, O_TRUNC},
+    syscall::{Stat, SysError, AT_REMOVEDIR, EINVAL, EIO, ENOENT, EXDEV},
+    vfs::{self, Inode},
+    virtio::{Buffer, Device, Queue},
+};
+use alloc::{format, rc::Rc, string::String, vec, vec::Vec};
+use core::ptr::addr_of_mut;
+use log::{error, info};
+
+use SysError::Errno;
+
+const REQUEST_QUEUE: u32 = 0;
+/// The device has a mount tag in its configuration.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+/// The biggest message we send or take, if the host agrees.
+const MSIZE: usize = 64 * 1024;
+/// What a read or write message has besides its data.
+const IOHDRSZ: usize = 24;
+
+const VERSION: &[u8] = b"9P2000.L";
+/// The tag of the version message, and the one every other message has:
+/// there's never more than one at a time.
+const NOTAG: u16 = 0xffff;
+const TAG: u16 = 0;
+const NOFID: u32 = u32::MAX;
+/// The fid the root of a share is attached as.
+const ROOT_FID: u32 = 0;
+
+// Message types. Every answer is its request's type plus one.
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+
+/// The attributes Tgetattr asks for: the ones of struct stat.
+const GETATTR_BASIC: u64 = 0x7ff;
+// The attributes Tsetattr can set that we do
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+
+/// A share of the host's.
+struct Mount {
+    tag: String,
+    // The device number its files have.
+    st_dev: u64,
+    dev: Device,
+    queue: Queue,
+    // The request goes in the first half, the answer comes in the second.
+    buf: DmaBuffer,
+    msize: usize,
+    // The fids given back, and the next one never used.
+    free_fids: Vec<u32>,
+    next_fid: u32,
+}
+
+static mut MOUNTS: Vec<Mount> = Vec::new();
+
+fn mounts() -> &'static mut Vec<Mount> {
+    unsafe { &mut *addr_of_mut!(MOUNTS) }
+}
+
+/// A request being put together.
+struct Msg(Vec<u8>);
+
+impl Msg {
+    fn new(kind: u8) -> Msg {
+        let tag = if kind == TVERSION { NOTAG } else { TAG };
+        // The size goes in front once we know it.
+        let mut m = Msg(vec![0; 4]);
+        m.u8(kind).u16(tag);
+        m
+    }
+
+    fn u8(&mut self, v: u8) -> &mut Msg {
+        self.0.push(v);
+        self
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Msg {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Msg {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Msg {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    /// A string: its length, then its bytes.
+    fn str(&mut self, s: &[u8]) -> &mut Msg {
+        self.u16(s.len() as u16);
+        self.0.extend_from_slice(s);
+        self
+    }
+}
+
+/// An answer being taken apart. Answers that are too short are the host's
+/// fault, and fail with EIO.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&mut self, n: usize) -> Result<&[u8], SysError> {
+        if self.0.len() < n {
+            return Err(Errno(EIO));
+        }
+        let (b, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, SysError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SysError> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SysError> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SysError> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&[u8], SysError> {
+        let n = self.u16()? as usize;
+        self.bytes(n)
+    }
+
+    /// A qid, the host's name for a file: its type, version and path.
+    /// The path is what's unique, so that's all we keep.
+    fn qid(&mut self) -> Result<u64, SysError> {
+        self.bytes(5)?;
+        self.u64()
+    }
+}
+
+impl Mount {
+    /// Send `msg` and wait for the answer. Returns what the answer has
+    /// after its header, or the errno the host answered with.
+    fn rpc(&mut self, msg: &mut Msg) -> Result<Vec<u8>, SysError> {
+        let len = msg.0.len();
+        if len > self.msize {
+            return Err(Errno(EINVAL));
+        }
+        let kind = msg.0[4];
+        msg.0[..4].copy_from_slice(&(len as u32).to_le_bytes());
+        self.buf.as_mut_slice()[..len].copy_from_slice(&msg.0);
+        let buffers = [
+            Buffer {
+                addr: self.buf.bus_addr(),
+                len,
+                writable: false,
+            },
+            Buffer {
+                addr: self.buf.bus_addr() + MSIZE,
+                len: self.msize,
+                writable: true,
+            },
+        ];
+        let head = self.queue.add_chain(&buffers).ok_or(Errno(EIO))?;
+        self.queue.submit(head);
+        let written = loop {
+            if let Some((id, written)) = self.queue.pop_used() {
+                self.queue.free_chain(id);
+                break written as usize;
+            }
+        };
+        let answer = &self.buf.as_mut_slice()[MSIZE..MSIZE + written.min(self.msize)];
+        let mut r = Reader(answer);
+        let size = r.u32()? as usize;
+        let (rkind, _tag) = (r.u8()?, r.u16()?);
+        if size < 7 || size > answer.len() {
+            return Err(Errno(EIO));
+        }
+        let body = &answer[7..size];
+        if rkind == RLERROR {
+            return Err(Errno(Reader(body).u32()? as isize));
+        }
+        if rkind != kind + 1 {
+            return Err(Errno(EIO));
+        }
+        Ok(body.to_vec())
+    }
+
+    fn alloc_fid(&mut self) -> u32 {
+        self.free_fids.pop().unwrap_or_else(|| {
+            self.next_fid += 1;
+            self.next_fid
+        })
+    }
+
+    /// Let go of `fid`. It's free again even if the host complains.
+    fn clunk(&mut self, fid: u32) {
+        let _ = self.rpc(Msg::new(TCLUNK).u32(fid));
+        self.free_fids.push(fid);
+    }
+
+    /// Walk from the fid `from` to `names`, at most one, and return the
+    /// fid the file got. A walk to no names makes a copy of `from`.
+    fn walk(&mut self, from: u32, names: &[&[u8]]) -> Result<u32, SysError> {
+        let fid = self.alloc_fid();
+        let mut msg = Msg::new(TWALK);
+        msg.u32(from).u32(fid).u16(names.len() as u16);
+        for name in names {
+            msg.str(name);
+        }
+        // The host answers with a qid for every name it got to. If that
+        // isn't all of them, there's no new fid.
+        let walked = self
+            .rpc(&mut msg)
+            .and_then(|body| Ok(Reader(&body).u16()? as usize));
+        match walked {
+            Ok(n) if n == names.len() => Ok(fid),
+            ret => {
+                self.free_fids.push(fid);
+                Err(ret.err().unwrap_or(Errno(ENOENT)))
+            }
+        }
+    }
+
+    fn getattr(&mut self, fid: u32) -> Result<Attr, SysError> {
+        let body = self.rpc(Msg::new(TGETATTR).u32(fid).u64(GETATTR_BASIC))?;
+        let mut r = Reader(&body);
+        let _valid = r.u64()?;
+        let ino = r.qid()?;
+        let (mode, uid, gid) = (r.u32()?, r.u32()?, r.u32()?);
+        let (nlink, rdev, size, blksize, blocks) =
+            (r.u64()?, r.u64()?, r.u64()?, r.u64()?, r.u64()?);
+        let mut times = [0; 6];
+        for t in times.iter_mut() {
+            *t = r.u64()?;
+        }
+        Ok(Attr {
+            ino,
+            mode,
+            uid,
+            gid,
+            nlink,
+            rdev,
+            size,
+            blksize,
+            blocks,
+            times,
+        })
+    }
+}
+
+/// A file's attributes, as Tgetattr gives them.
+struct Attr {
+    ino: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u64,
+    rdev: u64,
+    size: u64,
+    blksize: u64,
+    blocks: u64,
+    // The access, modification and change times, seconds then
+    // nanoseconds.
+    times: [u64; 6],
+}
+
+/// Set up a virtio-9p device and mount its share. Returns false if it
+/// didn't work out.
+pub fn setup(mut dev: Device) -> bool {
+    let Some(features) = dev.begin_init(|_| VIRTIO_9P_MOUNT_TAG) else {
+        return false;
+    };
+    if features & VIRTIO_9P_MOUNT_TAG == 0 {
+        error!("the device has no mount tag");
+        dev.fail();
+        return false;
+    }
+    let Some(queue) = dev.setup_queue(REQUEST_QUEUE, 2) else {
+        dev.fail();
+        return false;
+    };
+    let Some(buf) = dma::alloc_coherent(2 * MSIZE) else {
+        dev.fail();
+        return false;
+    };
+    dev.driver_ok();
+    // The tag's length, then the tag, which has no NUL at the end.
+    let len: u16 = dev.config(0);
+    let tag: Vec<u8> = (0..len as usize).map(|i| dev.config::<u8>(2 + i)).collect();
+    let mut m = Mount {
+        tag: String::from_utf8_lossy(&tag).into(),
+        st_dev: vfs::new_dev(),
+        dev,
+        queue,
+        buf,
+        msize: MSIZE,
+        free_fids: Vec::new(),
+        next_fid: ROOT_FID,
+    };
+    if attach(&mut m).is_err() {
+        error!("mounting {} failed", m.tag);
+        return false;
+    }
+    let path = format!("/mnt/{}", m.tag);
+    let tag = m.tag.clone();
+    mounts().push(m);
+    let root = HostInode {
+        mount: mounts().len() - 1,
+        fid: ROOT_FID,
+    };
+    if vfs::mount(path.as_bytes(), Rc::new(root), &tag, "9p", false).is_err() {
+        error!("can't mount {} at {}", tag, path);
+        return false;
+    }
+    info!("{} mounted at {}", tag, path);
+    true
+}
+
+/// Agree on the version and the message size with the host, and attach
+/// the root of the share.
+fn attach(m: &mut Mount) -> Result<(), SysError> {
+    let body = m.rpc(Msg::new(TVERSION).u32(MSIZE as u32).str(VERSION))?;
+    let mut r = Reader(&body);
+    let msize = r.u32()? as usize;
+    if r.str()? != VERSION || msize <= IOHDRSZ {
+        return Err(Errno(EINVAL));
+    }
+    m.msize = msize.min(MSIZE);
+    m.rpc(
+        Msg::new(TATTACH)
+            .u32(ROOT_FID)
+            .u32(NOFID)
+            .str(b"root")
+            .str(b"")
+            .u32(0),
+    )?;
+    Ok(())
+}
+
+/// The device in `slot` interrupted us. Requests are polled for, so
+/// there's nothing to do but acknowledge it.
+pub fn handle_interrupt(slot: usize) {
+    if let Some(m) = mounts().iter().find(|m| m.dev.slot == slot) {
+        m.dev.ack_interrupt();
+    }
+}
+
+/// The flags the host opens a file with. We handle O_APPEND ourselves, the
+/// host only gets what tells it how to open the file.
+fn lflags(flags: usize) -> u32 {
+    (flags & (O_ACCMODE | O_TRUNC)) as u32
+}
+
+/// Describe the file with the fid `fid` in share `mount`.
+fn stat(mount: usize, fid: u32) -> Stat {
+    let m = &mut mounts()[mount];
+    let Ok(a) = m.getattr(fid) else {
+        return Stat::default();
+    };
+    Stat {
+        st_dev: m.st_dev,
+        st_ino: a.ino,
+        st_mode: a.mode,
+        st_nlink: a.nlink as u32,
+        st_uid: a.uid,
+        st_gid: a.gid,
+        st_rdev: a.rdev,
+        st_size: a.size as i64,
+        st_blksize: a.blksize as i32,
+        st_blocks: a.blocks as i64,
+        st_atime: a.times[0] as i64,
+        st_atime_nsec: a.times[1],
+        st_mtime: a.times[2] as i64,
+        st_mtime_nsec: a.times[3],
+        st_ctime: a.times[4] as i64,
+        st_ctime_nsec: a.times[5],
+        ..Default::default()
+    }
+}
+
+/// A file in a share, not opened, by its fid.
+struct HostInode {
+    mount: usize,
+    fid: u32,
+}
+
+impl HostInode {
+    fn mount(&self) -> &'static mut Mount {
+        &mut mounts()[self.mount]
+    }
+
+    /// Set the attributes in `valid` to the ones given. The size and the
+    /// times are left alone.
+    fn setattr(&self, valid: u32, mode: u32, uid: u32, gid: u32) -> Result<(), SysError> {
+        let mut msg = Msg::new(TSETATTR);
+        msg.u32(self.fid).u32(valid).u32(mode).u32(uid).u32(gid);
+        for _ in 0..5 {
+            msg.u64(0);
+        }
+        self.mount().rpc(&mut msg)?;
+        Ok(())
+    }
+
+    /// The fid of `inode`, if it's in the same share.
+    fn same_mount(&self, inode: &dyn Inode) -> Result<u32, SysError> {
+        let other = vfs::downcast::<HostInode>(inode)?;
+        if other.mount != self.mount {
+            return Err(Errno(EXDEV));
+        }
+        Ok(other.fid)
+    }
+}
+
+impl Drop for HostInode {
+    fn drop(&mut self) {
+        self.mount().clunk(self.fid);
+    }
+}
+
+impl Inode for HostInode {
+    fn lookup(&self, name: &[u8]) -> Result<Rc<dyn Inode>, SysError> {
+        let fid = self.mount().walk(self.fid, &[name])?;
+        Ok(Rc::new(HostInode {
+            mount: self.mount,
+            fid,
+        }))
+    }
+
+    /// Creating a file opens the directory's fid as the new file, so it's
+    /// a copy that gets created in.
+    fn create(&self, name: &[u8], flags: usize, mode: usize) -> Result<Rc<dyn File>, SysError> {
+        let m = self.mount();
+        let fid = m.walk(self.fid, &[])?;
+        // Once it's made, the file clunks the fid when it's dropped.
+        let file = HostFile {
+            mount: self.mount,
+            fid,
+        };
+        m.rpc(
+            Msg::new(TLCREATE)
+                .u32(fid)
+                .str(name)
+                .u32(lflags(flags))
+                .u32((mode & 0o7777) as u32)
+                .u32(vfs::cred().gid),
+        )?;
+        Ok(Rc::new(file))
+    }
+
+    fn mkdir(&self, name: &[u8], mode: u32) -> Result<Rc<dyn Inode>, SysError> {
+        let m = self.mount();
+        let mut msg = Msg::new(TMKDIR);
+        m.rpc(msg.u32(self.fid).str(name).u32(mode).u32(vfs::cred().gid))?;
+        self.lookup(name)
+    }
+
+    fn unlink(&self, name: &[u8], dir: bool) -> Result<(), SysError> {
+        let flags = if dir { AT_REMOVEDIR as u32 } else { 0 };
+        let mut msg = Msg::new(TUNLINKAT);
+        self.mount().rpc(msg.u32(self.fid).str(name).u32(flags))?;
+        Ok(())
+    }
+
+    fn link(&self, name: &[u8], inode: &dyn Inode) -> Result<(), SysError> {
+        let fid = self.same_mount(inode)?;
+        let mut msg = Msg::new(TLINK);
+        self.mount().rpc(msg.u32(self.fid).u32(fid).str(name))?;
+        Ok(())
+    }
+
+    fn rename(&self, old: &[u8], dir: &dyn Inode, new: &[u8]) -> Result<(), SysError> {
+        let to = self.same_mount(dir)?;
+        let mut msg = Msg::new(TRENAMEAT);
+        self.mount()
+            .rpc(msg.u32(self.fid).str(old).u32(to).str(new))?;
+        Ok(())
+    }
+
+    fn symlink(&self, name: &[u8], target: &[u8]) -> Result<(), SysError> {
+        let mut msg = Msg::new(TSYMLINK);
+        self.mount()
+            .rpc(msg.u32(self.fid).str(name).str(target).u32(vfs::cred().gid))?;
+        Ok(())
+    }
+
+    fn readlink(&self) -> Result<Vec<u8>, SysError> {
+        let body = self.mount().rpc(Msg::new(TREADLINK).u32(self.fid))?;
+        Ok(Reader(&body).str()?.to_vec())
+    }
+
+    fn chmod(&self, mode: u32) -> Result<(), SysError> {
+        self.setattr(SETATTR_MODE, mode, 0, 0)
+    }
+
+    /// Whether the host lets us is up to it: with security_model=none,
+    /// QEMU can only do what the user it runs as can.
+    fn chown(&self, uid: u32, gid: u32) -> Result<(), SysError> {
+        self.setattr(SETATTR_UID | SETATTR_GID, 0, uid, gid)
+    }
+
+    fn open(&self, flags: usize) -> Result<Rc<dyn File>, SysError> {
+        let m = self.mount();
+        let fid = m.walk(self.fid, &[])?;
+        let file = HostFile {
+            mount: self.mount,
+            fid,
+        };
+        m.rpc(Msg::new(TLOPEN).u32(fid).u32(lflags(flags)))?;
+        Ok(Rc::new(file))
+    }
+
+    fn metadata(&self) -> Stat {
+        stat(self.mount, self.fid)
+    }
+}
+
+/// A file in a share, by its fid.
+struct HostFile {
+    mount: usize,
+    fid: u32,
+}
+
+impl HostFile {
+    fn mount(&self) -> &'static mut Mount {
+        &mut mounts()[self.mount]
+    }
+
+    /// The most data one read or write message can have.
+    fn max_io(&self) -> usize {
+        self.mount().msize - IOHDRSZ
+    }
+}
+
+impl Drop for HostFile {
+    fn drop(&mut self) {
+        self.mount().clunk(self.fid);
+    }
+}
+
+impl File for HostFile {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(self.max_io());
+            let body = self.mount().rpc(
+                Msg::new(TREAD)
+                    .u32(self.fid)
+                    .u64((offset + done) as u64)
+                    .u32(count as u32),
+            )?;
+            let mut r = Reader(&body);
+            let n = (r.u32()? as usize).min(count);
+            buf[done..done + n].copy_from_slice(r.bytes(n)?);
+            done += n;
+            if n < count {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, SysError> {
+        let mut done = 0;
+        while done < buf.len() {
+            let count = (buf.len() - done).min(self.max_io());
+            let mut msg = Msg::new(TWRITE);
+            msg.u32(self.fid)
+                .u64((offset + done) as u64)
+                .u32(count as u32);
+            msg.0.extend_from_slice(&buf[done..done + count]);
+            let body = self.mount().rpc(&mut msg)?;
+            let n = (Reader(&body).u32()? as usize).min(count);
+            done += n;
+            if n < count {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn size(&self) -> Option<usize> {
+        let attr = self.mount().getattr(self.fid).ok()?;
+        Some(attr.size as usize)
+    }
+
+    fn stat(&self) -> Stat {
+        stat(self.mount, self.fid)
+    }
+
+    /// Rreaddir's entries are a qid, the offset of the next entry, a type
+    /// and a name. They become linux_dirent64s, for as many as fit.
+    fn getdents(&self, offset: usize, buf: &mut [u8]) -> Result<(usize, usize), SysError> {
+        let count = buf.len().min(self.max_io());
+        let body = self.mount().rpc(
+            Msg::new(TREADDIR)
+                .u32(self.fid)
+                .u64(offset as u64)
+                .u32(count as u32),
+        )?;
+        let mut r = Reader(&body);
+        let n = r.u32()? as usize;
+        let mut r = Reader(r.bytes(n)?);
+        let (mut len, mut next) = (0, offset);
+        while !r.0.is_empty() {
+            let ino = r.qid()?;
+            let off = r.u64()?;
+            let kind = r.u8()?;
+            let name = r.str()?;
+            let Some(reclen) = vfs::put_dirent(&mut buf[len..], ino, off, kind, name) else {
+                break;
+            };
+            len += reclen;
+            next = off as usize;
+        }
+        if len == 0 && n != 0 {
+            // Not even one entry fits.
+            return Err(Errno(EINVAL));
+        }
+        Ok((len, next))
+    }
+}
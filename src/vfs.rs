@@ -0,0 +1,1073 @@
+// The virtual filesystem: every file there is, in one tree. Each kind of
+// filesystem (the devices in devfs.rs, the shares of the host in v9fs.rs,
+// ...) gives us its files as inodes, which can be described for stat,
+// opened, which gives a File (see file.rs) to read and write, or to list if
+// it's a directory, and, if they are directories, looked up in and made
+// files in. Mounting a filesystem at a directory makes its root show up
+// there instead of the directory. Walking a path (resolve()) starts from
+// the root of everything and looks up one name after the other, crossing
+// into whatever is mounted at the directories on the way, and going on
+// from where the symlinks on the way point, 40 of them at most.
+//
+// Names are made and removed, linked and renamed through the directories
+// they're in. Links and renames only go within a filesystem: a filesystem
+// gets the other directory, or the file, as an Inode, and finds its own
+// type behind it (they're Any), or EXDEV if it isn't.
+//
+// Underneath it all is the rootfs, a tmpfs (see tmpfs.rs). The directories
+// mount points need are made in it as they're needed: devfs is mounted at
+// /dev, procfs at /proc, another tmpfs at /tmp, and the shares of the host
+// and the disks at /mnt/<tag> and /mnt/<disk>.
+//
+// The mount syscall mounts more once we're up, on directories that are
+// there already; umount takes them away again, unless they're in use.
+//
+// Files have an owner, a group and permission bits, and what a process
+// may do with one is checked here, against the credentials of the process
+// whose system call we're in (set_cred()), the way Unix does: the owner's
+// bits count for the owner, the group's for the group and the others' for
+// everybody else. Walking through a directory takes x on it, opening a
+// file r or w, and making or removing names in a directory w and x; in a
+// sticky directory, such as /tmp, only the owner of a file can remove it.
+// Root can do anything, except run a file that nobody can. Outside of
+// system calls, the kernel acts as root.
+
+use crate::{
+    bcache,
+    block::{self, S_IFBLK},
+    file::{
+        File, O_ACCMODE, O_CREAT, O_DIRECTORY, O_EXCL, O_NOFOLLOW, O_RDONLY, O_TRUNC, O_WRONLY,
+        S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_ISGID, S_ISUID, S_ISVTX,
+    },
+    process::{self, Cred},
+    shell::{self, Builtin},
+    syscall::{
+        Stat, SysError, EACCES, EBUSY, EEXIST, EINVAL, EISDIR, ELOOP, ENAMETOOLONG, ENODEV, ENOENT,
+        ENOTBLK, ENOTDIR, ENXIO, EPERM, EXDEV,
+    },
+    tmpfs,
+};
+use alloc::{format, rc::Rc, string::String, vec, vec::Vec};
+use core::{any::Any, ptr::addr_of_mut};
+
+use SysError::Errno;
+
+/// The longest name a file can have.
+pub const NAME_MAX: usize = 255;
+
+/// The most symlinks a path walk follows before it gives up, as on Linux.
+const MAX_SYMLINKS: usize = 40;
+
+/// A file in a filesystem, as opposed to a file that's open.
+pub trait Inode: Any {
+    /// Find the file called `name` in this directory. `name` is never
+    /// empty, "." or "..": the path walk takes care of those.
+    fn lookup(&self, _name: &[u8]) -> Result<Rc<dyn Inode>, SysError> {
+        Err(Errno(ENOTDIR))
+    }
+
+    /// Make a file called `name` in this directory, with `mode`, and open
+    /// it with `flags`. There isn't one called that.
+    fn create(&self, _name: &[u8], _flags: usize, _mode: usize) -> Result<Rc<dyn File>, SysError> {
+        Err(Errno(EPERM))
+    }
+
+    /// Make a directory called `name` in this directory, with the
+    /// permission bits of `mode`. There isn't anything called that.
+    fn mkdir(&self, _name: &[u8], _mode: u32) -> Result<Rc<dyn Inode>, SysError> {
+        Err(Errno(EPERM))
+    }
+
+    /// Remove the name `name` from this directory: a directory, which has
+    /// to be empty, if `dir`, anything else if not.
+    fn unlink(&self, _name: &[u8], _dir: bool) -> Result<(), SysError> {
+        Err(Errno(EPERM))
+    }
+
+    /// Give `inode`, which is not a directory, the name `name` in this
+    /// directory too. There isn't anything called that.
+    fn link(&self, _name: &[u8], _inode: &dyn Inode) -> Result<(), SysError> {
+        Err(Errno(EPERM))
+    }
+
+    /// Move what's called `old` in this directory to `new` in `dir`, in
+    /// place of what's there, which is the same kind of file (a directory,
+    /// which has to be empty, or not), if anything is.
+    fn rename(&self, _old: &[u8], _dir: &dyn Inode, _new: &[u8]) -> Result<(), SysError> {
+        Err(Errno(EPERM))
+    }
+
+    /// Make a symlink called `name` in this directory, pointing at
+    /// `target`. There isn't anything called that.
+    fn symlink(&self, _name: &[u8], _target: &[u8]) -> Result<(), SysError> {
+        Err(Errno(EPERM))
+    }
+
+    /// Where the symlink points.
+    fn readlink(&self) -> Result<Vec<u8>, SysError> {
+        Err(Errno(EINVAL))
+    }
+
+    /// Set the permission bits, and the setuid, setgid and sticky ones, to
+    /// `mode`. Whether that's allowed has been checked.
+    fn chmod(&self, _mode: u32) -> Result<(), SysError> {
+        Err(Errno(EPERM))
+    }
+
+    /// Give the file to the user `uid` and the group `gid`. Whether that's
+    /// allowed has been checked.
+    fn chown(&self, _uid: u32, _gid: u32) -> Result<(), SysError> {
+        Err(Errno(EPERM))
+    }
+
+    /// Open the file with `flags`. The path walk doesn't open symlinks.
+    fn open(&self, flags: usize) -> Result<Rc<dyn File>, SysError>;
+
+    /// Describe the file for stat.
+    fn metadata(&self) -> Stat;
+}
+
+/// Whether `inode` is a directory.
+pub fn is_dir(inode: &dyn Inode) -> bool {
+    inode.metadata().st_mode & S_IFMT == S_IFDIR
+}
+
+/// Whether `inode` is a symlink.
+pub fn is_symlink(inode: &dyn Inode) -> bool {
+    inode.metadata().st_mode & S_IFMT == S_IFLNK
+}
+
+/// The d_type of a directory entry for a file of `mode`, which happens to
+/// be its type in st_mode, shifted down.
+pub fn dirent_type(mode: u32) -> u8 {
+    ((mode & S_IFMT) >> 12) as u8
+}
+
+/// Put a struct linux_dirent64 at the start of `buf`: the entry `name` of
+/// type `kind`, whose inode number is `ino`, after which the listing goes
+/// on from `next`. Returns its size, or None if it doesn't fit.
+pub fn put_dirent(buf: &mut [u8], ino: u64, next: u64, kind: u8, name: &[u8]) -> Option<usize> {
+    // The name has a NUL after it, and the entry is padded to 8 bytes.
+    const HEADER: usize = 19;
+    let reclen = (HEADER + name.len() + 1).next_multiple_of(8);
+    let d = buf.get_mut(..reclen)?;
+    d.fill(0);
+    d[0..8].copy_from_slice(&ino.to_le_bytes());
+    d[8..16].copy_from_slice(&next.to_le_bytes());
+    d[16..18].copy_from_slice(&(reclen as u16).to_le_bytes());
+    d[18] = kind;
+    d[HEADER..HEADER + name.len()].copy_from_slice(name);
+    Some(reclen)
+}
+
+/// The struct linux_dirent64s in `buf`, as getdents puts them there: the
+/// inode number, type and name of each.
+pub fn dirents(buf: &[u8]) -> impl Iterator<Item = (u64, u8, &[u8])> {
+    let mut rest = buf;
+    core::iter::from_fn(move || {
+        let reclen = u16::from_le_bytes(rest.get(16..18)?.try_into().unwrap()) as usize;
+        if reclen < 20 || reclen > rest.len() {
+            return None;
+        }
+        let (d, next) = rest.split_at(reclen);
+        rest = next;
+        let name = &d[19..];
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        Some((
+            u64::from_le_bytes(d[..8].try_into().unwrap()),
+            d[18],
+            &name[..len],
+        ))
+    })
+}
+
+static mut NEXT_DEV: u64 = 1;
+
+/// A device number for a filesystem without a device behind it, made up
+/// like the ones Linux gives those.
+pub fn new_dev() -> u64 {
+    unsafe {
+        NEXT_DEV += 1;
+        NEXT_DEV - 1
+    }
+}
+
+// ///////////////////////////////////
+// / MOUNT TABLE
+// ///////////////////////////////////
+
+/// A filesystem mounted somewhere.
+pub struct Mount {
+    /// Where it's mounted, an absolute path without . or .. in it.
+    pub path: Vec<u8>,
+    root: Rc<dyn Inode>,
+    /// What's mounted: the disk, the share of the host, or for filesystems
+    /// without either, the name of the filesystem.
+    pub source: String,
+    pub fstype: &'static str,
+    pub read_only: bool,
+    // The block device number of the disk, if it's on one.
+    disk: Option<usize>,
+}
+
+static mut ROOT: Option<Rc<dyn Inode>> = None;
+static mut MOUNTS: Vec<Mount> = Vec::new();
+
+fn mounts() -> &'static mut Vec<Mount> {
+    unsafe { &mut *addr_of_mut!(MOUNTS) }
+}
+
+fn root() -> Rc<dyn Inode> {
+    unsafe { (*addr_of_mut!(ROOT)).clone().unwrap() }
+}
+
+/// Every filesystem that's mounted, in the order they were. The rootfs
+/// isn't one of them.
+pub fn mount_table() -> &'static [Mount] {
+    mounts()
+}
+
+/// The root of the filesystem mounted at `path`, if one is.
+fn mounted(path: &[u8]) -> Option<Rc<dyn Inode>> {
+    let m = mounts().iter().rev().find(|m| m.path == path)?;
+    Some(m.root.clone())
+}
+
+/// Mount the filesystem whose root is `root` at the absolute `path`. The
+/// directories it takes that aren't there are made in the rootfs. The
+/// rest is only for the mount table to say.
+pub fn mount(
+    path: &[u8],
+    root: Rc<dyn Inode>,
+    source: &str,
+    fstype: &'static str,
+    read_only: bool,
+) -> Result<(), SysError> {
+    let (dir, path) = walk(path, true, true)?;
+    add_mount(dir, path, root, source.into(), fstype, read_only, None)
+}
+
+/// Put a mount at `path`, where `dir` is, in the mount table.
+fn add_mount(
+    dir: Rc<dyn Inode>,
+    path: Vec<u8>,
+    root: Rc<dyn Inode>,
+    source: String,
+    fstype: &'static str,
+    read_only: bool,
+    disk: Option<usize>,
+) -> Result<(), SysError> {
+    if !is_dir(&*dir) {
+        return Err(Errno(ENOTDIR));
+    }
+    mounts().push(Mount {
+        path,
+        root,
+        source,
+        fstype,
+        read_only,
+        disk,
+    });
+    Ok(())
+}
+
+/// What makes the filesystem of a disk: its root, read-only if the bool
+/// says so. It fails on disks that don't have one.
+pub type MountDisk = fn(usize, bool) -> Result<Rc<dyn Inode>, SysError>;
+
+/// Mount the filesystem `name` of every disk that has one at /mnt/<disk>.
+/// `mount` makes the filesystem of a disk. Filesystems that can only be
+/// read say so with `read_only`.
+pub fn mount_disks(name: &'static str, mount: MountDisk, read_only: bool) {
+    for (n, dev) in (0..).map_while(|n| Some((n, block::get(n)?))) {
+        let Ok(root) = mount(n, false) else {
+            continue;
+        };
+        let at = format!("/mnt/{}", dev.name());
+        let source = format!("/dev/{}", dev.name());
+        let read_only = read_only || dev.read_only();
+        let Ok((dir, path)) = walk(at.as_bytes(), true, true) else {
+            continue;
+        };
+        if add_mount(dir, path, root, source, name, read_only, Some(n)).is_ok() {
+            println!("{}: {} mounted at {}", name, dev.name(), at);
+        }
+    }
+}
+
+/// How mount() makes a filesystem of a type.
+enum FsType {
+    /// From a disk, by its device file. If the bool is set, it can only be
+    /// read.
+    Disk(MountDisk, bool),
+    /// From nothing. A source can be given, but it only goes in the mount
+    /// table.
+    Virtual(fn() -> Rc<dyn Inode>),
+}
+
+/// The filesystems mount() knows, by the names Linux has for them. 9p
+/// isn't one: a share is mounted when its device is set up.
+const FS_TYPES: &[(&str, FsType)] = &[
+    ("devfs", FsType::Virtual(crate::devfs::new)),
+    ("ext2", FsType::Disk(crate::ext2::mount, true)),
+    ("minix", FsType::Disk(crate::minix::mount, false)),
+    ("proc", FsType::Virtual(crate::procfs::new)),
+    ("tmpfs", FsType::Virtual(tmpfs::new)),
+    ("vfat", FsType::Disk(crate::fat::mount, false)),
+];
+
+/// Mount a filesystem of type `fstype` at the absolute `target`, which has
+/// to be a directory, from `source`: the absolute path of a disk, for
+/// filesystems that are on one. A disk can only be mounted once.
+pub fn do_mount(
+    source: &[u8],
+    target: &[u8],
+    fstype: &[u8],
+    read_only: bool,
+) -> Result<(), SysError> {
+    let (fstype, kind) = FS_TYPES
+        .iter()
+        .find(|(name, _)| name.as_bytes() == fstype)
+        .ok_or(Errno(ENODEV))?;
+    let (dir, path) = walk(target, true, false)?;
+    if path.is_empty() {
+        // That's the rootfs, which stays where it is.
+        return Err(Errno(EBUSY));
+    }
+    match *kind {
+        FsType::Disk(mount, only_read) => {
+            let st = stat(source, true)?;
+            if st.st_mode & S_IFMT != S_IFBLK {
+                return Err(Errno(ENOTBLK));
+            }
+            let n = block::find_rdev(st.st_rdev).ok_or(Errno(ENXIO))?;
+            if mounts().iter().any(|m| m.disk == Some(n)) {
+                return Err(Errno(EBUSY));
+            }
+            let disk = block::get(n).unwrap();
+            let read_only = read_only || only_read || disk.read_only();
+            let source = format!("/dev/{}", disk.name());
+            let root = mount(n, read_only)?;
+            add_mount(dir, path, root, source, fstype, read_only, Some(n))
+        }
+        FsType::Virtual(new) => {
+            let source = match source {
+                b"" => String::from(*fstype),
+                _ => String::from_utf8_lossy(source).into_owned(),
+            };
+            add_mount(dir, path, new(), source, fstype, read_only, None)
+        }
+    }
+}
+
+/// Unmount what's mounted at the absolute `target`, the last thing if
+/// several are, and write out what the cache has of its disk. Unless
+/// `force`, it's EBUSY if something is mounted in it, or a process has a
+/// file of it open or mapped, or its current directory in it; otherwise those files
+/// stay usable, the filesystem is only gone from the tree, and so are
+/// those directories.
+pub fn umount(target: &[u8], follow: bool, force: bool) -> Result<(), SysError> {
+    let (_, path) = walk(target, follow, false)?;
+    let i = mounts()
+        .iter()
+        .rposition(|m| m.path == path && !path.is_empty())
+        .ok_or(Errno(EINVAL))?;
+    let m = &mounts()[i];
+    let below_path = |p: &[u8]| p.starts_with(&path) && p.get(path.len()) == Some(&b'/');
+    let below = |other: &Mount| below_path(&other.path);
+    let dev = m.root.metadata().st_dev;
+    let in_it = |cwd: &[u8]| cwd == path || below_path(cwd);
+    let open = || {
+        process::list().iter().any(|p| {
+            let mut files = p.files.iter().map(|f| f.file()).chain(p.mapped_files());
+            in_it(&p.cwd) || files.any(|f| f.stat().st_dev == dev)
+        })
+    };
+    if !force && (mounts().iter().any(below) || open()) {
+        return Err(Errno(EBUSY));
+    }
+    let m = mounts().remove(i);
+    if let Some(n) = m.disk {
+        if block::with_sleep(false, || bcache::sync(Some(n))).is_err() {
+            println!(
+                "vfs: some changes to {} didn't make it to the disk",
+                m.source
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Make the rootfs, and mount devfs, procfs and a tmpfs on it.
+pub fn init() {
+    unsafe {
+        ROOT = Some(tmpfs::new());
+    }
+    crate::devfs::init();
+    crate::procfs::init();
+    tmpfs::init();
+    for command in COMMANDS {
+        shell::register(command);
+    }
+}
+
+// ///////////////////////////////////
+// / SHELL COMMANDS
+// ///////////////////////////////////
+
+/// Without arguments, list what's mounted.
+fn mount_command(args: &[&str]) -> Result<(), SysError> {
+    let (source, target, fstype, read_only) = match args {
+        [] => {
+            for m in mount_table() {
+                let path = if m.path.is_empty() { b"/" } else { &m.path[..] };
+                let mode = if m.read_only { "ro" } else { "rw" };
+                let at = String::from_utf8_lossy(path);
+                println!("{} on {} type {} ({})", m.source, at, m.fstype, mode);
+            }
+            return Ok(());
+        }
+        [source, target, fstype] => (source, target, fstype, false),
+        [source, target, fstype, "ro"] => (source, target, fstype, true),
+        _ => return Err(Errno(EINVAL)),
+    };
+    let source = if source.is_empty() {
+        Vec::new()
+    } else {
+        shell::absolute(source)
+    };
+    do_mount(
+        &source,
+        &shell::absolute(target),
+        fstype.as_bytes(),
+        read_only,
+    )
+}
+
+fn umount_command(args: &[&str]) -> Result<(), SysError> {
+    match args {
+        [target] => umount(&shell::absolute(target), true, false),
+        [target, "-f"] => umount(&shell::absolute(target), true, true),
+        _ => Err(Errno(EINVAL)),
+    }
+}
+
+/// Directories have a / after their names.
+fn ls(args: &[&str]) -> Result<(), SysError> {
+    let path = match args {
+        [] => shell::absolute("."),
+        [path] => shell::absolute(path),
+        _ => return Err(Errno(EINVAL)),
+    };
+    for entry in read_dir(&path)? {
+        let slash = if entry.kind == dirent_type(S_IFDIR) {
+            "/"
+        } else {
+            ""
+        };
+        println!("{}{}", String::from_utf8_lossy(&entry.name), slash);
+    }
+    Ok(())
+}
+
+const COMMANDS: &[Builtin] = &[
+    Builtin {
+        name: "mount",
+        help: "mount [SOURCE TARGET TYPE [ro]] - mount a filesystem, or list them",
+        run: mount_command,
+    },
+    Builtin {
+        name: "umount",
+        help: "umount TARGET [-f] - unmount a filesystem",
+        run: umount_command,
+    },
+    Builtin {
+        name: "ls",
+        help: "ls [PATH] - list a directory",
+        run: ls,
+    },
+];
+
+// ///////////////////////////////////
+// / PERMISSIONS
+// ///////////////////////////////////
+
+// What can be asked of a file, as the bits of each of the owner, group and
+// others in its mode.
+pub const MAY_EXEC: u32 = 1;
+pub const MAY_WRITE: u32 = 2;
+pub const MAY_READ: u32 = 4;
+
+static mut CRED: Cred = Cred::ROOT;
+
+/// Act for a process with the credentials `cred` from now on, or as the
+/// kernel again with Cred::ROOT.
+pub fn set_cred(cred: Cred) {
+    unsafe {
+        *addr_of_mut!(CRED) = cred;
+    }
+}
+
+/// Who we're acting for. Files that are made are theirs.
+pub fn cred() -> Cred {
+    unsafe { *addr_of_mut!(CRED) }
+}
+
+/// Whether we may do `want`, some of MAY_READ, MAY_WRITE and MAY_EXEC, to
+/// the file `st` describes. It's EACCES if not.
+pub fn permission(st: &Stat, want: u32) -> Result<(), SysError> {
+    let cred = cred();
+    let bits = if cred.is_root() {
+        // Root has every bit, but a file is only run if it can be run at
+        // all. Directories are always searched.
+        let runnable = st.st_mode & S_IFMT == S_IFDIR || st.st_mode & 0o111 != 0;
+        if runnable {
+            7
+        } else {
+            MAY_READ | MAY_WRITE
+        }
+    } else if st.st_uid == cred.uid {
+        st.st_mode >> 6 & 7
+    } else if st.st_gid == cred.gid {
+        st.st_mode >> 3 & 7
+    } else {
+        st.st_mode & 7
+    };
+    if want & !bits != 0 {
+        return Err(Errno(EACCES));
+    }
+    Ok(())
+}
+
+/// Whether we may go through the directory `dir` to what's in it.
+fn may_search(dir: &dyn Inode) -> Result<(), SysError> {
+    // Root always may, without asking the filesystem what the directory is.
+    if cred().is_root() {
+        return Ok(());
+    }
+    permission(&dir.metadata(), MAY_EXEC)
+}
+
+/// Whether we may remove the name `name` from the directory `dir`.
+fn may_delete(dir: &dyn Inode, name: &[u8]) -> Result<(), SysError> {
+    let st = dir.metadata();
+    permission(&st, MAY_WRITE | MAY_EXEC)?;
+    let cred = cred();
+    if st.st_mode & S_ISVTX != 0 && !cred.is_root() && st.st_uid != cred.uid {
+        // Only the file's owner can, or the directory's.
+        if dir.lookup(name)?.metadata().st_uid != cred.uid {
+            return Err(Errno(EPERM));
+        }
+    }
+    Ok(())
+}
+
+/// Change the permission bits of the file at the absolute `path` to
+/// `mode`. Only its owner may, or root.
+pub fn chmod(path: &[u8], mode: u32) -> Result<(), SysError> {
+    let inode = resolve(path, true)?;
+    let st = inode.metadata();
+    let cred = cred();
+    if !cred.is_root() && st.st_uid != cred.uid {
+        return Err(Errno(EPERM));
+    }
+    let mut mode = mode & 0o7777;
+    if !cred.is_root() && st.st_gid != cred.gid {
+        // Nobody gets to run a file as a group they aren't in.
+        mode &= !S_ISGID;
+    }
+    inode.chmod(mode)
+}
+
+/// Give the file at the absolute `path`, or the symlink itself unless
+/// `follow`, to the user `uid` and the group `gid`, where None leaves
+/// those as they are. Only root can give files away; an owner can only
+/// change the group, to their own.
+pub fn chown(
+    path: &[u8],
+    follow: bool,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<(), SysError> {
+    let inode = resolve(path, follow)?;
+    let st = inode.metadata();
+    let cred = cred();
+    let (uid, gid) = (uid.unwrap_or(st.st_uid), gid.unwrap_or(st.st_gid));
+    let allowed =
+        st.st_uid == cred.uid && uid == st.st_uid && (gid == st.st_gid || gid == cred.gid);
+    if !cred.is_root() && !allowed {
+        return Err(Errno(EPERM));
+    }
+    inode.chown(uid, gid)?;
+    // A program that ran as whoever had it doesn't run as whoever has it
+    // now.
+    let set_id = S_ISUID | S_ISGID;
+    if st.st_mode & S_IFMT == S_IFREG && st.st_mode & set_id != 0 {
+        inode.chmod(st.st_mode & 0o7777 & !set_id)?;
+    }
+    Ok(())
+}
+
+/// Whether we may do `mode`, some of MAY_READ, MAY_WRITE and MAY_EXEC or
+/// none, to the file at the absolute `path`. With none, this is whether
+/// it's there.
+pub fn access(path: &[u8], mode: u32) -> Result<(), SysError> {
+    permission(&stat(path, true)?, mode)
+}
+
+// ///////////////////////////////////
+// / PATHS
+// ///////////////////////////////////
+
+/// Find the file at the absolute `path`. If it's a symlink, that's what's
+/// found, unless `follow`.
+pub fn resolve(path: &[u8], follow: bool) -> Result<Rc<dyn Inode>, SysError> {
+    Ok(walk(path, follow, false)?.0)
+}
+
+/// The path of the directory at the absolute `path`, without . or .. or
+/// symlinks in it, to be the current directory of a process. Going into a
+/// directory takes x on it.
+pub fn chdir(path: &[u8]) -> Result<Vec<u8>, SysError> {
+    let (dir, at) = walk(path, true, false)?;
+    if !is_dir(&*dir) {
+        return Err(Errno(ENOTDIR));
+    }
+    may_search(&*dir)?;
+    Ok(at)
+}
+
+/// Describe the file at the absolute `path`: what it points at if it's a
+/// symlink and `follow`, or the symlink itself.
+pub fn stat(path: &[u8], follow: bool) -> Result<Stat, SysError> {
+    Ok(resolve(path, follow)?.metadata())
+}
+
+/// The entries of the directory at the absolute `path`, as getdents has
+/// them: in the directory's order, and with . and .. if it has those.
+pub fn read_dir(path: &[u8]) -> Result<Vec<Entry>, SysError> {
+    let dir = open(path, O_RDONLY | O_DIRECTORY, 0)?;
+    let (mut buf, mut entries, mut offset) = (vec![0; 4096], Vec::new(), 0);
+    loop {
+        let (n, next) = dir.getdents(offset, &mut buf)?;
+        if n == 0 {
+            return Ok(entries);
+        }
+        for (ino, kind, name) in dirents(&buf[..n]) {
+            let name = name.to_vec();
+            entries.push(Entry { name, ino, kind });
+        }
+        offset = next;
+    }
+}
+
+/// The names of `path`, last one first.
+fn names_of(path: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    let names = path.rsplit(|&c| c == b'/').filter(|n| !n.is_empty());
+    names.map(|n| n.to_vec())
+}
+
+/// Find the file at the absolute `path`, following symlinks on the way, and
+/// the one at the end if `follow`, and making the directories on the way
+/// that aren't there if `make_dirs`. Returns it and its path without . or
+/// .. or symlinks in it.
+fn walk(path: &[u8], follow: bool, make_dirs: bool) -> Result<(Rc<dyn Inode>, Vec<u8>), SysError> {
+    // The inodes from the root to where we are, and the path of where we
+    // are, so that .. can go back up, across mount points too.
+    let mut stack = Vec::from([root()]);
+    let mut at = Vec::new();
+    // The names left to look up, the next one last, so that where a
+    // symlink points can be put in front of them.
+    let mut names: Vec<Vec<u8>> = names_of(path).collect();
+    // A path that ends in a slash names a directory, so a symlink at the
+    // end of it is followed to one.
+    let dir_only = path.ends_with(b"/");
+    let mut links = 0;
+    while let Some(name) = names.pop() {
+        match &name[..] {
+            b"." => continue,
+            b".." => {
+                if stack.len() > 1 {
+                    stack.pop();
+                    at.truncate(at.iter().rposition(|&c| c == b'/').unwrap());
+                }
+                continue;
+            }
+            _ if name.len() > NAME_MAX => return Err(Errno(ENAMETOOLONG)),
+            _ => {}
+        }
+        let dir = stack.last().unwrap();
+        may_search(&**dir)?;
+        let inode = match dir.lookup(&name) {
+            Err(Errno(ENOENT)) if make_dirs => dir.mkdir(&name, 0o755)?,
+            ret => ret?,
+        };
+        if is_symlink(&*inode) && (!names.is_empty() || follow || dir_only) {
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(Errno(ELOOP));
+            }
+            let target = inode.readlink()?;
+            if target.is_empty() {
+                return Err(Errno(ENOENT));
+            }
+            // Relative to the directory the symlink is in, which is where we
+            // are.
+            if target.starts_with(b"/") {
+                stack.truncate(1);
+                at.clear();
+            }
+            names.extend(names_of(&target));
+            continue;
+        }
+        at.push(b'/');
+        at.extend_from_slice(&name);
+        stack.push(mounted(&at).unwrap_or(inode));
+    }
+    let inode = stack.pop().unwrap();
+    if dir_only && !is_dir(&*inode) {
+        return Err(Errno(ENOTDIR));
+    }
+    Ok((inode, at))
+}
+
+/// Open the file at the absolute `path` with `flags`, or with O_CREAT
+/// make it, with `mode`, if there isn't one.
+pub fn open(path: &[u8], flags: usize, mode: usize) -> Result<Rc<dyn File>, SysError> {
+    Ok(open_dir(path, flags, mode)?.0)
+}
+
+/// An opened file, and where it is if it's a directory.
+pub type Opened = (Rc<dyn File>, Option<Vec<u8>>);
+
+/// open(), and if what it opened is a directory, the path of it without .
+/// or .. or symlinks in it, for the *at calls to start from.
+pub fn open_dir(path: &[u8], flags: usize, mode: usize) -> Result<Opened, SysError> {
+    // With O_EXCL, whatever is there is in the way, even a symlink.
+    let exclusive = flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL;
+    let follow = flags & O_NOFOLLOW == 0 && !exclusive;
+    let (inode, at) = match walk(path, follow, false) {
+        Ok(_) if exclusive => return Err(Errno(EEXIST)),
+        Ok(found) => found,
+        Err(Errno(ENOENT)) if flags & O_CREAT != 0 => {
+            return Ok((create(path, flags, mode)?, None))
+        }
+        Err(e) => return Err(e),
+    };
+    if is_symlink(&*inode) {
+        // O_NOFOLLOW, and it is one.
+        return Err(Errno(ELOOP));
+    }
+    if flags & O_DIRECTORY != 0 && !is_dir(&*inode) {
+        return Err(Errno(ENOTDIR));
+    }
+    let want = match flags & O_ACCMODE {
+        O_RDONLY => MAY_READ,
+        O_WRONLY => MAY_WRITE,
+        _ => MAY_READ | MAY_WRITE,
+    };
+    let want = if flags & O_TRUNC != 0 {
+        want | MAY_WRITE
+    } else {
+        want
+    };
+    permission(&inode.metadata(), want)?;
+    let at = is_dir(&*inode).then_some(at);
+    Ok((inode.open(flags)?, at))
+}
+
+/// A directory, its path without . or .. in it, and a name in it.
+type Parent<'a> = (Rc<dyn Inode>, Vec<u8>, &'a [u8]);
+
+/// Split the absolute `path` into the directory it's in and its last name,
+/// which may be empty (for the root), . or .. still. Slashes at the end
+/// don't count.
+fn parent(path: &[u8]) -> Result<Parent<'_>, SysError> {
+    let end = path.iter().rposition(|&c| c != b'/').map_or(0, |i| i + 1);
+    let path = &path[..end];
+    let Some(i) = path.iter().rposition(|&c| c == b'/') else {
+        return Ok((root(), Vec::new(), b""));
+    };
+    let (dir, at) = walk(&path[..i + 1], true, false)?;
+    Ok((dir, at, &path[i + 1..]))
+}
+
+/// Make the file at `path` in the directory it goes in.
+fn create(path: &[u8], flags: usize, mode: usize) -> Result<Rc<dyn File>, SysError> {
+    let (dir, _, name) = parent(path)?;
+    match name {
+        // A path that ends in a slash, or in . or .., names a directory,
+        // which open can't make.
+        _ if path.ends_with(b"/") => Err(Errno(EISDIR)),
+        b"" | b"." | b".." => Err(Errno(EISDIR)),
+        _ if name.len() > NAME_MAX => Err(Errno(ENAMETOOLONG)),
+        _ => {
+            permission(&dir.metadata(), MAY_WRITE | MAY_EXEC)?;
+            dir.create(name, flags, mode)
+        }
+    }
+}
+
+/// Make the directory at the absolute `path` in the directory it goes in,
+/// with the permission and sticky bits of `mode`.
+pub fn mkdir(path: &[u8], mode: u32) -> Result<(), SysError> {
+    let (dir, _, name) = parent(path)?;
+    match name {
+        b"" | b"." | b".." => Err(Errno(EEXIST)),
+        _ if name.len() > NAME_MAX => Err(Errno(ENAMETOOLONG)),
+        _ => {
+            permission(&dir.metadata(), MAY_WRITE | MAY_EXEC)?;
+            dir.mkdir(name, mode & 0o1777).map(|_| ())
+        }
+    }
+}
+
+/// Make a symlink at the absolute `path` that points at `target`.
+pub fn symlink(target: &[u8], path: &[u8]) -> Result<(), SysError> {
+    if target.is_empty() {
+        return Err(Errno(ENOENT));
+    }
+    if path.ends_with(b"/") {
+        // It would have to be a directory.
+        return Err(Errno(if resolve(path, false).is_ok() {
+            EEXIST
+        } else {
+            ENOENT
+        }));
+    }
+    let (dir, _, name) = parent(path)?;
+    match name {
+        b"" | b"." | b".." => Err(Errno(EEXIST)),
+        _ if name.len() > NAME_MAX => Err(Errno(ENAMETOOLONG)),
+        _ => {
+            permission(&dir.metadata(), MAY_WRITE | MAY_EXEC)?;
+            dir.symlink(name, target)
+        }
+    }
+}
+
+/// Remove the file at the absolute `path`, or the empty directory if
+/// `dir`.
+pub fn unlink(path: &[u8], dir: bool) -> Result<(), SysError> {
+    if !dir && path.ends_with(b"/") {
+        // Only a directory can be named like that, and unlink doesn't take
+        // those. If it isn't one, that's ENOTDIR.
+        resolve(path, true)?;
+        return Err(Errno(EISDIR));
+    }
+    let (parent, mut at, name) = parent(path)?;
+    match name {
+        // The root, or a path ending in . or .., which rmdir won't take
+        // apart, and which is a directory for unlink.
+        b"" if dir => return Err(Errno(EBUSY)),
+        b"" | b"." | b".." if dir => return Err(Errno(EINVAL)),
+        b"" | b"." | b".." => return Err(Errno(EISDIR)),
+        _ if name.len() > NAME_MAX => return Err(Errno(ENAMETOOLONG)),
+        _ => {}
+    }
+    at.push(b'/');
+    at.extend_from_slice(name);
+    if mounted(&at).is_some() {
+        return Err(Errno(EBUSY));
+    }
+    may_delete(&*parent, name)?;
+    parent.unlink(name, dir)
+}
+
+/// Whether `a` and `b` are the same file.
+fn same_file(a: &Stat, b: &Stat) -> bool {
+    a.st_dev == b.st_dev && a.st_ino == b.st_ino
+}
+
+/// Make a hard link at the absolute `new` to the file at the absolute
+/// `old`, or to what it points at if it's a symlink and `follow`.
+pub fn link(old: &[u8], new: &[u8], follow: bool) -> Result<(), SysError> {
+    let inode = resolve(old, follow)?;
+    let st = inode.metadata();
+    if st.st_mode & S_IFMT == S_IFDIR {
+        return Err(Errno(EPERM));
+    }
+    if new.ends_with(b"/") {
+        // Only a directory can be named like that.
+        return Err(Errno(if resolve(new, false).is_ok() {
+            EEXIST
+        } else {
+            ENOENT
+        }));
+    }
+    let (dir, _, name) = parent(new)?;
+    match name {
+        b"" | b"." | b".." => return Err(Errno(EEXIST)),
+        _ if name.len() > NAME_MAX => return Err(Errno(ENAMETOOLONG)),
+        _ => {}
+    }
+    let dir_st = dir.metadata();
+    if dir_st.st_dev != st.st_dev {
+        return Err(Errno(EXDEV));
+    }
+    permission(&dir_st, MAY_WRITE | MAY_EXEC)?;
+    dir.link(name, &*inode)
+}
+
+/// Move the file at the absolute `old` to the absolute `new`, in place of
+/// what's there unless `no_replace`, in which case that's EEXIST. A
+/// directory can only replace an empty directory, and anything else only
+/// something else that isn't one. Renaming a file to itself, under this
+/// name or another of its links, does nothing.
+pub fn rename(old: &[u8], new: &[u8], no_replace: bool) -> Result<(), SysError> {
+    let (from, mut old_at, old_name) = parent(old)?;
+    let (to, mut new_at, new_name) = parent(new)?;
+    for name in [old_name, new_name] {
+        match name {
+            // The root, or what a path ending in . or .. names, which has
+            // a name somewhere else.
+            b"" | b"." | b".." => return Err(Errno(EBUSY)),
+            _ if name.len() > NAME_MAX => return Err(Errno(ENAMETOOLONG)),
+            _ => {}
+        }
+    }
+    old_at.push(b'/');
+    old_at.extend_from_slice(old_name);
+    new_at.push(b'/');
+    new_at.extend_from_slice(new_name);
+    let inode = from.lookup(old_name)?;
+    let st = inode.metadata();
+    let is_dir = st.st_mode & S_IFMT == S_IFDIR;
+    if !is_dir && (old.ends_with(b"/") || new.ends_with(b"/")) {
+        return Err(Errno(ENOTDIR));
+    }
+    if mounted(&old_at).is_some() || mounted(&new_at).is_some() {
+        return Err(Errno(EBUSY));
+    }
+    let to_st = to.metadata();
+    if from.metadata().st_dev != to_st.st_dev || st.st_dev != to_st.st_dev {
+        return Err(Errno(EXDEV));
+    }
+    match to.lookup(new_name) {
+        Ok(target) => {
+            let target = target.metadata();
+            if same_file(&st, &target) {
+                return Ok(());
+            }
+            if no_replace {
+                return Err(Errno(EEXIST));
+            }
+            match (is_dir, target.st_mode & S_IFMT == S_IFDIR) {
+                (true, false) => return Err(Errno(ENOTDIR)),
+                (false, true) => return Err(Errno(EISDIR)),
+                _ => {}
+            }
+            may_delete(&*to, new_name)?;
+        }
+        Err(Errno(ENOENT)) => permission(&to_st, MAY_WRITE | MAY_EXEC)?,
+        Err(e) => return Err(e),
+    }
+    if is_dir && new_at.starts_with(&old_at) && new_at.get(old_at.len()) == Some(&b'/') {
+        // It would be inside itself.
+        return Err(Errno(EINVAL));
+    }
+    may_delete(&*from, old_name)?;
+    if is_dir && !same_file(&from.metadata(), &to_st) {
+        // Its .. changes.
+        permission(&st, MAY_WRITE)?;
+    }
+    from.rename(old_name, &*to, new_name)
+}
+
+/// What's behind `inode`, if it's a `T`: how filesystems find their own
+/// inodes among the ones they're given. It's EXDEV if it isn't one.
+pub fn downcast<T: Inode>(inode: &dyn Inode) -> Result<&T, SysError> {
+    (inode as &dyn Any).downcast_ref().ok_or(Errno(EXDEV))
+}
+
+// ///////////////////////////////////
+// / DIRECTORY LISTINGS
+// ///////////////////////////////////
+
+/// An entry of a directory listing.
+pub struct Entry {
+    pub name: Vec<u8>,
+    pub ino: u64,
+    pub kind: u8,
+}
+
+/// A directory opened for listing, with the entries it had when it was
+/// opened, for filesystems that know them all at once. Offsets into it
+/// count entries. There are no . and .. entries.
+pub struct Listing {
+    stat: Stat,
+    entries: Vec<Entry>,
+}
+
+impl Listing {
+    /// Open a directory, described by `stat`, with `flags`. Directories
+    /// can only be opened for reading.
+    pub fn open(stat: Stat, entries: Vec<Entry>, flags: usize) -> Result<Rc<dyn File>, SysError> {
+        if flags & O_ACCMODE != O_RDONLY {
+            return Err(Errno(EISDIR));
+        }
+        Ok(Rc::new(Listing { stat, entries }))
+    }
+}
+
+impl File for Listing {
+    fn read(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize, SysError> {
+        Err(Errno(EISDIR))
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, SysError> {
+        Err(Errno(EISDIR))
+    }
+
+    fn stat(&self) -> Stat {
+        self.stat
+    }
+
+    fn getdents(&self, offset: usize, buf: &mut [u8]) -> Result<(usize, usize), SysError> {
+        let (mut len, mut next) = (0, offset);
+        for e in self.entries.iter().skip(offset) {
+            let Some(n) = put_dirent(&mut buf[len..], e.ino, next as u64 + 1, e.kind, &e.name)
+            else {
+                break;
+            };
+            len += n;
+            next += 1;
+        }
+        if len == 0 && next < self.entries.len() {
+            // Not even one entry fits.
+            return Err(Errno(EINVAL));
+        }
+        Ok((len, next))
+    }
+}
+
+// ///////////////////////////////////
+// / TESTS
+// ///////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{selftest::check, testing::shown};
+    use alloc::{format, string::String};
+
+    /// Opening a directory says where it is, however the path got there;
+    /// opening anything else doesn't.
+    #[test_case]
+    fn open_dir_test() -> Result<(), String> {
+        let cases: [(&[u8], Option<&[u8]>); 4] = [
+            (b"/", Some(b"")),
+            (b"/dev/../dev/./", Some(b"/dev")),
+            (b"//dev", Some(b"/dev")),
+            (b"/dev/null", None),
+        ];
+        for (path, want) in cases {
+            let (_, at) =
+                open_dir(path, O_RDONLY, 0).map_err(|_| format!("{} didn't open", shown(path)))?;
+            check(at.as_deref() == want, || {
+                format!("{} is at {:?}", shown(path), at.as_deref().map(shown))
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,839 @@
+// Virtio over MMIO and PCI. QEMU's virt machine has eight virtio-mmio
+// slots, each a page of registers, and every -device virtio-*-device on the
+// command line goes into one of them. The device tree lists the slots;
+// without one, we look where QEMU puts them. -device virtio-*-pci devices
+// go on the PCI bus instead, where their registers are in their BARs. A device is set up with the handshake from the
+// virtio specification (reset, acknowledge, negotiate features, set up the
+// virtqueues, driver ok) and then talked to through its virtqueues: rings
+// of buffer descriptors in memory that the driver hands to the device and
+// the device hands back when it's done with them.
+// Both the legacy (version 1, QEMU's default) and the modern (version 2)
+// register layouts are supported.
+
+use crate::{
+    block, device,
+    dma::{self, DmaBuffer},
+    gpu, hvc, input, nic,
+    page::{align_val, PAGE_ORDER, PAGE_SIZE},
+    pci, plic, rng, v9fs,
+};
+use alloc::{format, string::String, vec::Vec};
+use core::ptr::addr_of_mut;
+use log::{error, info, warn};
+
+/// How many devices we drive.
+const MAX_SLOTS: usize = 16;
+
+/// "virt", in the MagicValue register.
+const MAGIC: u32 = 0x7472_6976;
+
+// Register offsets
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028; // Legacy only
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c; // Legacy only
+const QUEUE_PFN: usize = 0x040; // Legacy only
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG: usize = 0x100;
+
+// virtio-pci: the vendor capabilities that point at the structures, and
+// the registers of the common configuration structure.
+const PCI_VENDOR_VIRTIO: u16 = 0x1af4;
+const PCI_CAP_VENDOR: u8 = 0x09;
+const PCI_CAP_COMMON_CFG: u8 = 1;
+const PCI_CAP_NOTIFY_CFG: u8 = 2;
+const PCI_CAP_ISR_CFG: u8 = 3;
+const PCI_CAP_DEVICE_CFG: u8 = 4;
+const PCI_DEVICE_FEATURE_SELECT: usize = 0x00;
+const PCI_DEVICE_FEATURE: usize = 0x04;
+const PCI_DRIVER_FEATURE_SELECT: usize = 0x08;
+const PCI_DRIVER_FEATURE: usize = 0x0c;
+const PCI_DEVICE_STATUS: usize = 0x14;
+const PCI_QUEUE_SELECT: usize = 0x16;
+const PCI_QUEUE_SIZE: usize = 0x18;
+const PCI_QUEUE_ENABLE: usize = 0x1c;
+const PCI_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const PCI_QUEUE_DESC: usize = 0x20;
+const PCI_QUEUE_DRIVER: usize = 0x28;
+const PCI_QUEUE_DEVICE: usize = 0x30;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+/// Modern devices insist on this feature, it says we're not a legacy
+/// driver.
+pub const F_VERSION_1: u64 = 1 << 32;
+
+// Device types, in the DeviceID register. 0 means the slot is empty.
+pub const DEVICE_NET: u32 = 1;
+pub const DEVICE_BLOCK: u32 = 2;
+pub const DEVICE_CONSOLE: u32 = 3;
+pub const DEVICE_RNG: u32 = 4;
+pub const DEVICE_9P: u32 = 9;
+pub const DEVICE_GPU: u32 = 16;
+pub const DEVICE_INPUT: u32 = 18;
+
+/// The longest queue we set up. The device may offer fewer entries.
+pub const MAX_QUEUE_SIZE: u16 = 128;
+
+// Descriptor flags
+pub const DESC_F_NEXT: u16 = 1;
+pub const DESC_F_WRITE: u16 = 2;
+
+fn device_name(id: u32) -> &'static str {
+    match id {
+        DEVICE_NET => "network",
+        DEVICE_BLOCK => "block",
+        DEVICE_CONSOLE => "console",
+        DEVICE_RNG => "entropy",
+        DEVICE_9P => "9P transport",
+        DEVICE_GPU => "GPU",
+        DEVICE_INPUT => "input",
+        _ => "unknown",
+    }
+}
+
+// ///////////////////////////////////
+// / DEVICES
+// ///////////////////////////////////
+
+/// How a device's registers are reached.
+enum Transport {
+    /// A virtio-mmio slot. Version 1 is the legacy register layout.
+    Mmio { base: usize, version: u32 },
+    /// A virtio-pci function, with the modern register layout.
+    Pci(PciRegs),
+}
+
+/// Where the structures of a virtio-pci function are, from its
+/// capabilities.
+struct PciRegs {
+    common: usize,
+    notify: usize,
+    notify_multiplier: u32,
+    isr: usize,
+    device: usize,
+}
+
+/// A virtio device, in an MMIO slot or on the PCI bus.
+pub struct Device {
+    transport: Transport,
+    pub slot: usize,
+}
+
+fn read32(addr: usize) -> u32 {
+    unsafe { (addr as *const u32).read_volatile() }
+}
+
+fn write32(addr: usize, val: u32) {
+    unsafe { (addr as *mut u32).write_volatile(val) }
+}
+
+fn read16(addr: usize) -> u16 {
+    unsafe { (addr as *const u16).read_volatile() }
+}
+
+fn write16(addr: usize, val: u16) {
+    unsafe { (addr as *mut u16).write_volatile(val) }
+}
+
+impl Device {
+    fn is_legacy(&self) -> bool {
+        matches!(self.transport, Transport::Mmio { version: 1, .. })
+    }
+
+    fn status(&self) -> u32 {
+        match &self.transport {
+            Transport::Mmio { base, .. } => read32(base + STATUS),
+            Transport::Pci(p) => unsafe {
+                ((p.common + PCI_DEVICE_STATUS) as *const u8).read_volatile() as u32
+            },
+        }
+    }
+
+    fn set_status(&self, status: u32) {
+        match &self.transport {
+            Transport::Mmio { base, .. } => write32(base + STATUS, status),
+            Transport::Pci(p) => unsafe {
+                ((p.common + PCI_DEVICE_STATUS) as *mut u8).write_volatile(status as u8)
+            },
+        }
+    }
+
+    /// Half `sel` of the features the device offers.
+    fn device_features(&self, sel: u32) -> u32 {
+        match &self.transport {
+            Transport::Mmio { base, .. } => {
+                write32(base + DEVICE_FEATURES_SEL, sel);
+                read32(base + DEVICE_FEATURES)
+            }
+            Transport::Pci(p) => {
+                write32(p.common + PCI_DEVICE_FEATURE_SELECT, sel);
+                read32(p.common + PCI_DEVICE_FEATURE)
+            }
+        }
+    }
+
+    fn set_driver_features(&self, sel: u32, val: u32) {
+        match &self.transport {
+            Transport::Mmio { base, .. } => {
+                write32(base + DRIVER_FEATURES_SEL, sel);
+                write32(base + DRIVER_FEATURES, val);
+            }
+            Transport::Pci(p) => {
+                write32(p.common + PCI_DRIVER_FEATURE_SELECT, sel);
+                write32(p.common + PCI_DRIVER_FEATURE, val);
+            }
+        }
+    }
+
+    /// Reset the device and negotiate features with it: of the features
+    /// the device offers, we take the ones `wanted` returns. Returns the
+    /// features we ended up with, or None if the device didn't accept
+    /// them. The queues are set up next, then driver_ok() is called.
+    pub fn begin_init(&mut self, wanted: impl FnOnce(u64) -> u64) -> Option<u64> {
+        self.set_status(0);
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let mut offered = 0;
+        for sel in 0..2 {
+            offered |= (self.device_features(sel) as u64) << (32 * sel);
+        }
+        let mut features = wanted(offered) & offered;
+        if !self.is_legacy() {
+            features |= F_VERSION_1;
+        }
+        for sel in 0..2 {
+            self.set_driver_features(sel, (features >> (32 * sel)) as u32);
+        }
+        if let Transport::Mmio { base, version: 1 } = self.transport {
+            // Legacy devices don't have the FEATURES_OK step, but they
+            // need to know how big our pages are for the queue addresses.
+            write32(base + GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            return Some(features);
+        }
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        self.set_status(status);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return None;
+        }
+        Some(features)
+    }
+
+    /// Set up virtqueue `index` with up to `size` entries.
+    pub fn setup_queue(&mut self, index: u32, size: u16) -> Option<Queue> {
+        match &self.transport {
+            Transport::Mmio { base, version } => {
+                let base = *base;
+                write32(base + QUEUE_SEL, index);
+                let max = read32(base + QUEUE_NUM_MAX);
+                if max == 0 {
+                    // The device doesn't have that queue.
+                    return None;
+                }
+                let size = size.min(MAX_QUEUE_SIZE).min(max as u16);
+                // Legacy devices take a 32-bit page number, which on RV32
+                // reaches past anything there is.
+                let limit = if *version == 1 {
+                    1usize
+                        .checked_shl(32 + PAGE_ORDER as u32)
+                        .unwrap_or(dma::NO_LIMIT)
+                } else {
+                    dma::NO_LIMIT
+                };
+                let queue = Queue::new(size, base + QUEUE_NOTIFY, index, limit)?;
+                write32(base + QUEUE_NUM, size as u32);
+                if *version == 1 {
+                    // The rings have to be laid out the way Queue::new does
+                    // it.
+                    write32(base + QUEUE_ALIGN, PAGE_SIZE as u32);
+                    write32(
+                        base + QUEUE_PFN,
+                        (queue.mem.bus_addr() >> PAGE_ORDER) as u32,
+                    );
+                } else {
+                    let regs = [
+                        (QUEUE_DESC_LOW, QUEUE_DESC_HIGH, queue.bus_addr(queue.desc)),
+                        (
+                            QUEUE_DRIVER_LOW,
+                            QUEUE_DRIVER_HIGH,
+                            queue.bus_addr(queue.avail),
+                        ),
+                        (
+                            QUEUE_DEVICE_LOW,
+                            QUEUE_DEVICE_HIGH,
+                            queue.bus_addr(queue.used),
+                        ),
+                    ];
+                    for (low, high, addr) in regs {
+                        write32(base + low, addr as u32);
+                        write32(base + high, ((addr as u64) >> 32) as u32);
+                    }
+                    write32(base + QUEUE_READY, 1);
+                }
+                Some(queue)
+            }
+            Transport::Pci(p) => {
+                let common = p.common;
+                write16(common + PCI_QUEUE_SELECT, index as u16);
+                let max = read16(common + PCI_QUEUE_SIZE);
+                if max == 0 {
+                    return None;
+                }
+                let size = size.min(MAX_QUEUE_SIZE).min(max);
+                // Every queue has its own place in the notification
+                // structure.
+                let notify_off = read16(common + PCI_QUEUE_NOTIFY_OFF) as usize;
+                let notify = p.notify + notify_off * p.notify_multiplier as usize;
+                let queue = Queue::new(size, notify, index, dma::NO_LIMIT)?;
+                write16(common + PCI_QUEUE_SIZE, size);
+                let regs = [
+                    (PCI_QUEUE_DESC, queue.bus_addr(queue.desc)),
+                    (PCI_QUEUE_DRIVER, queue.bus_addr(queue.avail)),
+                    (PCI_QUEUE_DEVICE, queue.bus_addr(queue.used)),
+                ];
+                for (reg, addr) in regs {
+                    write32(common + reg, addr as u32);
+                    write32(common + reg + 4, ((addr as u64) >> 32) as u32);
+                }
+                write16(common + PCI_QUEUE_ENABLE, 1);
+                Some(queue)
+            }
+        }
+    }
+
+    /// Tell the device we're done setting it up. It is live after this.
+    pub fn driver_ok(&mut self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// Give up on the device.
+    pub fn fail(&mut self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    /// Acknowledge an interrupt. Returns the reasons for it: bit 0 means
+    /// buffers were used, bit 1 that the configuration changed.
+    pub fn ack_interrupt(&self) -> u32 {
+        match &self.transport {
+            Transport::Mmio { base, .. } => {
+                let status = read32(base + INTERRUPT_STATUS);
+                write32(base + INTERRUPT_ACK, status);
+                status
+            }
+            // Reading the ISR status acknowledges it.
+            Transport::Pci(p) => unsafe { (p.isr as *const u8).read_volatile() as u32 },
+        }
+    }
+
+    fn config_base(&self) -> usize {
+        match &self.transport {
+            Transport::Mmio { base, .. } => base + CONFIG,
+            Transport::Pci(p) => p.device,
+        }
+    }
+
+    /// Read from the device specific configuration space.
+    pub fn config<T: Copy>(&self, offset: usize) -> T {
+        unsafe { ((self.config_base() + offset) as *const T).read_volatile() }
+    }
+
+    /// Write to the device specific configuration space.
+    pub fn set_config<T: Copy>(&self, offset: usize, val: T) {
+        unsafe { ((self.config_base() + offset) as *mut T).write_volatile(val) }
+    }
+}
+
+// ///////////////////////////////////
+// / VIRTQUEUES
+// ///////////////////////////////////
+
+/// A descriptor of the descriptor table: one buffer the device reads from
+/// or (with DESC_F_WRITE) writes to. Buffers are chained by `next` with
+/// DESC_F_NEXT.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+/// An element of the used ring: the head of a chain and how much the
+/// device wrote into it.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// A buffer to put in a descriptor chain.
+pub struct Buffer {
+    /// Its bus address.
+    pub addr: usize,
+    pub len: usize,
+    /// Is the device going to write to it?
+    pub writable: bool,
+}
+
+/// A split virtqueue. The descriptor table, the available ring (where we
+/// put chains for the device) and the used ring (where the device puts
+/// them back) are laid out in contiguous pages the way legacy devices
+/// expect: the used ring starts on a page boundary.
+pub struct Queue {
+    mem: DmaBuffer,
+    size: u16,
+    desc: *mut Descriptor,
+    // flags, idx, ring[size], used_event
+    avail: *mut u16,
+    // flags, idx, then the ring of UsedElem
+    used: *mut u16,
+    notify: *mut u32,
+    index: u32,
+    free: Vec<u16>,
+    // The used ring index we've seen up to.
+    last_used: u16,
+}
+
+impl Queue {
+    /// A queue of `size` entries whose rings are below `limit` for the
+    /// device.
+    fn new(size: u16, notify: usize, index: u32, limit: usize) -> Option<Queue> {
+        let n = size as usize;
+        let used_offset = align_val(16 * n + 6 + 2 * n, PAGE_ORDER);
+        let mem = dma::alloc_coherent_below(used_offset + 6 + 8 * n, limit)?;
+        let p = mem.as_ptr();
+        Some(Queue {
+            mem,
+            size,
+            desc: p as *mut Descriptor,
+            avail: unsafe { p.add(16 * n) } as *mut u16,
+            used: unsafe { p.add(used_offset) } as *mut u16,
+            notify: notify as *mut u32,
+            index,
+            free: (0..size).rev().collect(),
+            last_used: 0,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// The bus address of the ring `p` points into.
+    fn bus_addr<T>(&self, p: *mut T) -> usize {
+        self.mem.bus_addr() + (p as usize - self.mem.as_ptr() as usize)
+    }
+
+    /// How many descriptors are free.
+    pub fn num_free(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn alloc_desc(&mut self) -> Option<u16> {
+        self.free.pop()
+    }
+
+    pub fn free_desc(&mut self, i: u16) {
+        self.free.push(i);
+    }
+
+    pub fn desc(&mut self, i: u16) -> &mut Descriptor {
+        assert!(i < self.size);
+        unsafe { &mut *self.desc.add(i as usize) }
+    }
+
+    /// Free a chain of descriptors, starting from its head.
+    pub fn free_chain(&mut self, head: u16) {
+        let mut i = head;
+        loop {
+            let d = *self.desc(i);
+            self.free_desc(i);
+            if d.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            i = d.next;
+        }
+    }
+
+    /// Put `buffers` in a chain of descriptors and return its head, or
+    /// None if there aren't enough free descriptors.
+    pub fn add_chain(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free() {
+            return None;
+        }
+        let mut next = 0;
+        let mut flags = 0;
+        for b in buffers.iter().rev() {
+            let i = self.alloc_desc().unwrap();
+            let write = if b.writable { DESC_F_WRITE } else { 0 };
+            *self.desc(i) = Descriptor {
+                addr: b.addr as u64,
+                len: b.len as u32,
+                flags: flags | write,
+                next,
+            };
+            next = i;
+            flags = DESC_F_NEXT;
+        }
+        Some(next)
+    }
+
+    /// Make the chain starting at `head` available to the device and tell
+    /// it about it.
+    pub fn submit(&mut self, head: u16) {
+        unsafe {
+            let idx = self.avail.add(1).read_volatile();
+            self.avail
+                .add(2 + (idx % self.size) as usize)
+                .write_volatile(head);
+            // The device must see the ring entry before the new index, and
+            // the new index before we tell it to look.
+            dma::wmb();
+            self.avail.add(1).write_volatile(idx.wrapping_add(1));
+            dma::mb();
+            self.notify.write_volatile(self.index);
+        }
+    }
+
+    /// Take the next chain the device is done with: its head and how many
+    /// bytes the device wrote into it. The chain still has to be freed.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        unsafe {
+            if self.used.add(1).read_volatile() == self.last_used {
+                return None;
+            }
+            // Don't read the entry before the index that says it's there.
+            dma::rmb();
+            let ring = self.used.add(2) as *mut UsedElem;
+            let elem = ring
+                .add((self.last_used % self.size) as usize)
+                .read_volatile();
+            self.last_used = self.last_used.wrapping_add(1);
+            Some((elem.id as u16, elem.len))
+        }
+    }
+}
+
+/// Where the loopback test's queue is notified.
+static mut LOOPBACK_NOTIFY: u32 = u32::MAX;
+
+/// A self-test of the queue, with no device: we play its part, taking each
+/// chain off the available ring, copying what it reads into what it
+/// writes, and putting the chain on the used ring. Several times around
+/// the rings, so that their indices wrap.
+pub fn loopback_test() -> Result<(), String> {
+    const SIZE: u16 = 8;
+    const LEN: usize = 16;
+    let notify = addr_of_mut!(LOOPBACK_NOTIFY) as usize;
+    let mut q = Queue::new(SIZE, notify, 3, dma::NO_LIMIT).ok_or("out of memory")?;
+    let (Some(mut out), Some(mut back)) = (dma::alloc_coherent(LEN), dma::alloc_coherent(LEN))
+    else {
+        return Err("out of memory".into());
+    };
+    for round in 0..3 * SIZE as usize {
+        out.as_mut_slice()[..LEN].fill(round as u8);
+        let buffers = [
+            Buffer {
+                addr: out.bus_addr(),
+                len: LEN,
+                writable: false,
+            },
+            Buffer {
+                addr: back.bus_addr(),
+                len: LEN,
+                writable: true,
+            },
+        ];
+        let head = q.add_chain(&buffers).ok_or("no descriptors for a chain")?;
+        q.submit(head);
+        if unsafe { *addr_of_mut!(LOOPBACK_NOTIFY) } != 3 {
+            return Err(format!("round {}: the device wasn't notified", round));
+        }
+        unsafe {
+            *addr_of_mut!(LOOPBACK_NOTIFY) = u32::MAX;
+        }
+        // The device's side.
+        let avail = unsafe { q.avail.add(2 + round % SIZE as usize).read_volatile() };
+        if avail != head {
+            return Err(format!(
+                "round {}: {} is available, not {}",
+                round, avail, head
+            ));
+        }
+        let first = *q.desc(head);
+        let second = *q.desc(first.next);
+        if first.flags != DESC_F_NEXT
+            || second.flags != DESC_F_WRITE
+            || first.addr != out.bus_addr() as u64
+            || second.addr != back.bus_addr() as u64
+        {
+            return Err(format!("round {}: the chain isn't what was added", round));
+        }
+        let data = out.as_mut_slice()[..LEN].to_vec();
+        back.as_mut_slice()[..LEN].copy_from_slice(&data);
+        unsafe {
+            let ring = q.used.add(2) as *mut UsedElem;
+            ring.add(round % SIZE as usize).write_volatile(UsedElem {
+                id: head as u32,
+                len: LEN as u32,
+            });
+            q.used.add(1).write_volatile((round + 1) as u16);
+        }
+        // Ours again.
+        let used = q.pop_used();
+        if used != Some((head, LEN as u32)) {
+            return Err(format!("round {}: {:?} came back used", round, used));
+        }
+        q.free_chain(head);
+        if back.as_mut_slice()[..LEN] != out.as_mut_slice()[..LEN] {
+            return Err(format!("round {}: the wrong data came back", round));
+        }
+    }
+    if q.pop_used().is_some() || q.num_free() != SIZE as usize {
+        return Err("descriptors went missing".into());
+    }
+    Ok(())
+}
+
+// ///////////////////////////////////
+// / PROBING
+// ///////////////////////////////////
+
+/// What a driver provides: a function that sets up a device (and returns
+/// false if that failed), and its interrupt handler, which gets the slot.
+struct Driver {
+    setup: fn(Device) -> bool,
+    handle_interrupt: fn(usize),
+}
+
+fn driver(device_id: u32) -> Option<Driver> {
+    match device_id {
+        DEVICE_BLOCK => Some(Driver {
+            setup: block::setup,
+            handle_interrupt: block::handle_interrupt,
+        }),
+        DEVICE_CONSOLE => Some(Driver {
+            setup: hvc::setup,
+            handle_interrupt: hvc::handle_interrupt,
+        }),
+        DEVICE_GPU => Some(Driver {
+            setup: gpu::setup,
+            handle_interrupt: gpu::handle_interrupt,
+        }),
+        DEVICE_INPUT => Some(Driver {
+            setup: input::setup,
+            handle_interrupt: input::handle_interrupt,
+        }),
+        DEVICE_NET => Some(Driver {
+            setup: nic::setup,
+            handle_interrupt: nic::handle_interrupt,
+        }),
+        DEVICE_RNG => Some(Driver {
+            setup: rng::setup,
+            handle_interrupt: rng::handle_interrupt,
+        }),
+        DEVICE_9P => Some(Driver {
+            setup: v9fs::setup,
+            handle_interrupt: v9fs::handle_interrupt,
+        }),
+        _ => None,
+    }
+}
+
+/// The interrupt of a slot that has a device with a driver, and the
+/// driver's interrupt handler.
+#[derive(Clone, Copy)]
+struct Handler {
+    irq: u32,
+    handle: fn(usize),
+}
+
+static mut HANDLERS: [Option<Handler>; MAX_SLOTS] = [None; MAX_SLOTS];
+
+/// Where the structures of a virtio-pci function are, or None if it
+/// doesn't have the modern interface.
+fn pci_regs(f: &pci::Function) -> Option<PciRegs> {
+    let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+    let mut notify_multiplier = 0;
+    for (id, cap) in f.capabilities() {
+        if id != PCI_CAP_VENDOR {
+            continue;
+        }
+        // struct virtio_pci_cap: the type, then which BAR the structure is
+        // in and where.
+        let kind = f.read8(cap + 3);
+        let Some(bar) = f.bar(f.read8(cap + 4) as usize) else {
+            continue;
+        };
+        let addr = bar + f.read32(cap + 8) as usize;
+        // If there are several of a type, the first one is the one to use.
+        match kind {
+            PCI_CAP_COMMON_CFG => _ = common.get_or_insert(addr),
+            PCI_CAP_NOTIFY_CFG if notify.is_none() => {
+                notify = Some(addr);
+                notify_multiplier = f.read32(cap + 16);
+            }
+            PCI_CAP_ISR_CFG => _ = isr.get_or_insert(addr),
+            PCI_CAP_DEVICE_CFG => _ = device.get_or_insert(addr),
+            _ => {}
+        }
+    }
+    Some(PciRegs {
+        common: common?,
+        notify: notify?,
+        notify_multiplier,
+        isr: isr?,
+        // Devices without any configuration don't have the structure
+        // either, and their drivers don't look for it.
+        device: device.unwrap_or(0),
+    })
+}
+
+static mut NEXT_SLOT: usize = 0;
+
+/// Hand a device to the driver for its type, and let it interrupt us on
+/// `irq` once it's set up. Returns false if that didn't work out.
+fn attach(transport: Transport, device_id: u32, irq: u32) -> bool {
+    let slot = unsafe { NEXT_SLOT };
+    if slot == MAX_SLOTS {
+        warn!("too many devices");
+        return false;
+    }
+    unsafe {
+        NEXT_SLOT += 1;
+    }
+    let Some(driver) = driver(device_id) else {
+        warn!("no driver for it");
+        return false;
+    };
+    // Set the handler first, the device may interrupt as soon as it's set
+    // up.
+    unsafe {
+        (*addr_of_mut!(HANDLERS))[slot] = Some(Handler {
+            irq,
+            handle: driver.handle_interrupt,
+        });
+    }
+    if !(driver.setup)(Device { transport, slot }) {
+        error!("setting up the device failed");
+        unsafe {
+            (*addr_of_mut!(HANDLERS))[slot] = None;
+        }
+        return false;
+    }
+    plic::enable(irq);
+    plic::set_priority(irq, 1);
+    true
+}
+
+/// The driver for virtio-mmio slots. The slot numbers the drivers for the
+/// device types get go in the order the devices are probed.
+pub struct VirtioMmio;
+
+impl device::Driver for VirtioMmio {
+    fn compatible(&self) -> &'static [&'static str] {
+        &["virtio,mmio"]
+    }
+
+    fn probe(&self, dev: &device::Device) -> bool {
+        let (Some(base), Some(irq)) = (dev.base(), dev.irq()) else {
+            return false;
+        };
+        if read32(base + MAGIC_VALUE) != MAGIC {
+            return false;
+        }
+        let device_id = read32(base + DEVICE_ID);
+        if device_id == 0 {
+            // Nothing plugged in.
+            return false;
+        }
+        let version = read32(base + VERSION);
+        info!(
+            "{} device (v{}) at 0x{:x}",
+            device_name(device_id),
+            version,
+            base
+        );
+        attach(Transport::Mmio { base, version }, device_id, irq)
+    }
+}
+
+/// The driver for virtio devices on the PCI bus.
+pub struct VirtioPci;
+
+impl device::Driver for VirtioPci {
+    fn matches(&self, dev: &device::Device) -> bool {
+        dev.pci
+            .as_ref()
+            .is_some_and(|f| f.vendor_id == PCI_VENDOR_VIRTIO)
+    }
+
+    fn probe(&self, dev: &device::Device) -> bool {
+        let Some(f) = &dev.pci else {
+            return false;
+        };
+        // Modern devices are 0x1040 plus the device type, transitional
+        // ones have the type in the subsystem ID.
+        let device_id = match f.device_id {
+            0x1040..=0x107f => f.device_id - 0x1040,
+            0x1000..=0x103f => f.subsystem_id(),
+            _ => return false,
+        } as u32;
+        info!(
+            "{} device (pci) at {:02x}:{:02x}.{}",
+            device_name(device_id),
+            f.bus,
+            f.dev,
+            f.func
+        );
+        let (Some(regs), Some(irq)) = (pci_regs(f), dev.irq()) else {
+            warn!("it has no modern interface or no interrupt");
+            return false;
+        };
+        attach(Transport::Pci(regs), device_id, irq)
+    }
+}
+
+fn handlers() -> &'static [Option<Handler>; MAX_SLOTS] {
+    unsafe { &*addr_of_mut!(HANDLERS) }
+}
+
+/// Is `irq` the interrupt of a virtio device?
+pub fn has_irq(irq: u32) -> bool {
+    handlers().iter().flatten().any(|h| h.irq == irq)
+}
+
+/// Handle an interrupt from the slots that raise `irq`.
+pub fn handle_interrupt(irq: u32) {
+    for (slot, handler) in handlers().iter().enumerate() {
+        if let Some(h) = handler.filter(|h| h.irq == irq) {
+            (h.handle)(slot);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+// An XMODEM receiver, to get files into a running system over the console
+// UART, from a terminal program's "send file": reading /dev/xmodem starts
+// a transfer, and gives the bytes of the file as they come in, until end
+// of file once the sender is done. So `cat /dev/xmodem > /mnt/host/prog`
+// puts what's sent into a file, and a program can also just read it into
+// its memory.
+//
+// XMODEM sends a file in numbered blocks of 128 bytes (or 1024, in the
+// variant called XMODEM-1K), each of which we answer with ACK, or with NAK
+// to get it sent again. A block is SOH (STX for 1024 bytes), its number,
+// the number's complement, the data and a check: the sum of the data
+// bytes, or a CRC-16 if the receiver asked for that by starting the
+// transfer with 'C' instead of NAK. We ask for CRCs a few times, then fall
+// back to sums. The last block is padded with SUBs (^Z), which stay: there
+// is nothing that says where the file really ends. EOT ends the transfer,
+// two CANs call it off, and so do we if too many blocks in a row go wrong.
+//
+// YMODEM senders are understood too: they start with a block 0 that holds
+// the file's name and size, so then the padding is cut off, and end with
+// an empty block 0. Only the first file of a batch is taken.
+//
+// While a transfer runs, whatever the console UART receives goes to it
+// instead of to the console. Anything the kernel prints meanwhile goes to
+// the sender too, and usually ruins a block, which then gets sent again.
+
+use crate::{
+    console::Source,
+    cpu,
+    file::{File, S_IFCHR},
+    process::WaitQueue,
+    syscall::{Stat, SysError, EBADF, EBUSY, EFBIG, EIO},
+    timer, uart,
+};
+use alloc::{rc::Rc, vec::Vec};
+use core::{cell::Cell, mem, ptr::addr_of_mut};
+
+use SysError::{Block, Errno};
+
+// Control characters
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Asks for CRCs, sent instead of the first NAK.
+const CRC: u8 = b'C';
+
+/// How many times we ask for the transfer to start, or for a block, before
+/// giving up. The first few ask for CRCs.
+const MAX_TRIES: u32 = 10;
+const CRC_TRIES: u32 = 3;
+/// How long we wait for the sender, in seconds: to start, then for a
+/// block, and for the next byte of a block.
+const START_TIMEOUT: u64 = 3;
+const BLOCK_TIMEOUT: u64 = 10;
+const BYTE_TIMEOUT: u64 = 1;
+/// The biggest file we take: it's all kept in memory.
+const MAX_SIZE: usize = 16 << 20;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Running,
+    Done,
+    Failed(isize),
+}
+
+struct Transfer {
+    state: State,
+    data: Vec<u8>,
+    // The block being received, from its SOH or STX on.
+    block: Vec<u8>,
+    // Whether blocks end in a CRC, which is whatever we asked for last
+    // before the first one came.
+    crc: bool,
+    // The number of the block we want next, and whether any came yet.
+    next: u8,
+    started: bool,
+    // Whether it's YMODEM, the file's size if it said, and whether the
+    // file's EOT came, after which there's the empty block 0 that ends the
+    // batch.
+    ymodem: bool,
+    size: Option<usize>,
+    ending: bool,
+    tries: u32,
+    cans: u32,
+    timer: usize,
+    readers: WaitQueue,
+}
+
+static mut TRANSFER: Transfer = Transfer {
+    state: State::Idle,
+    data: Vec::new(),
+    block: Vec::new(),
+    crc: true,
+    next: 1,
+    started: false,
+    ymodem: false,
+    size: None,
+    ending: false,
+    tries: 0,
+    cans: 0,
+    timer: 0,
+    readers: WaitQueue::new("xmodem readers"),
+};
+
+fn transfer() -> &'static mut Transfer {
+    unsafe { &mut *addr_of_mut!(TRANSFER) }
+}
+
+fn send(c: u8) {
+    uart::console().put(c);
+}
+
+fn send_cancel() {
+    for _ in 0..3 {
+        send(CAN);
+    }
+}
+
+/// CRC-16 with the polynomial 0x1021 and no initial value, XMODEM's.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+impl Transfer {
+    fn start(&mut self) {
+        self.state = State::Running;
+        self.data = Vec::new();
+        self.block.clear();
+        self.next = 1;
+        self.started = false;
+        self.ymodem = false;
+        self.size = None;
+        self.ending = false;
+        self.tries = 0;
+        self.cans = 0;
+        self.ask();
+    }
+
+    /// Ask for the transfer to start, or for the block we want again,
+    /// and wait for it.
+    fn ask(&mut self) {
+        if self.started {
+            send(NAK);
+            self.wait_for(BLOCK_TIMEOUT);
+            return;
+        }
+        self.crc = self.tries < CRC_TRIES;
+        send(if self.crc { CRC } else { NAK });
+        self.wait_for(START_TIMEOUT);
+    }
+
+    fn wait_for(&mut self, secs: u64) {
+        timer::cancel(self.timer);
+        let deadline = cpu::get_mtime() + timer::duration_to_ticks(secs, 0);
+        self.timer = timer::add(deadline, timeout, 0);
+    }
+
+    /// Stop, with `state`, and wake up the readers to see it.
+    fn finish(&mut self, state: State) {
+        timer::cancel(self.timer);
+        self.state = state;
+        self.block = Vec::new();
+        self.readers.wake_all();
+    }
+
+    /// Call the transfer off. Several CANs, in case one gets lost.
+    fn cancel(&mut self, errno: isize) {
+        send_cancel();
+        self.finish(State::Failed(errno));
+    }
+
+    /// Something went wrong with the block we wanted: ask for it again, or
+    /// give up.
+    fn retry(&mut self) {
+        self.block.clear();
+        self.tries += 1;
+        if self.tries >= MAX_TRIES {
+            self.cancel(EIO);
+        } else {
+            self.ask();
+        }
+    }
+
+    fn receive(&mut self, c: u8) {
+        if !self.block.is_empty() {
+            self.block.push(c);
+            let len = if self.block[0] == STX { 1024 } else { 128 };
+            let check = if self.crc { 2 } else { 1 };
+            if self.block.len() == 3 + len + check {
+                self.receive_block(len);
+            } else {
+                self.wait_for(BYTE_TIMEOUT);
+            }
+            return;
+        }
+        if c != CAN {
+            self.cans = 0;
+        }
+        match c {
+            SOH | STX => {
+                self.block.push(c);
+                self.wait_for(BYTE_TIMEOUT);
+            }
+            EOT => {
+                send(ACK);
+                self.tries = 0;
+                if self.ymodem && !self.ending {
+                    // What's left of a YMODEM batch.
+                    self.ending = true;
+                    self.next = 0;
+                    send(CRC);
+                    self.wait_for(BLOCK_TIMEOUT);
+                } else {
+                    self.finish(State::Done);
+                }
+            }
+            CAN => {
+                self.cans += 1;
+                if self.cans == 2 {
+                    self.finish(State::Failed(EIO));
+                }
+            }
+            // Noise between blocks.
+            _ => {}
+        }
+    }
+
+    fn receive_block(&mut self, len: usize) {
+        let block = mem::take(&mut self.block);
+        let (n, data) = (block[1], &block[3..3 + len]);
+        let good = if self.crc {
+            let crc = u16::from_be_bytes([block[3 + len], block[4 + len]]);
+            crc16(data) == crc
+        } else {
+            data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == block[3 + len]
+        };
+        if !good || n != !block[2] {
+            self.retry();
+            return;
+        }
+        self.tries = 0;
+        if !self.started && n == 0 && self.crc {
+            // A YMODEM header: the name, a NUL, then the size in decimal
+            // and, who knows, more things after a space. No name means
+            // there's nothing to send after all.
+            self.started = true;
+            self.ymodem = true;
+            send(ACK);
+            let mut fields = data.split(|&b| b == 0);
+            if fields.next().is_none_or(|name| name.is_empty()) {
+                self.finish(State::Done);
+                return;
+            }
+            let size = fields
+                .next()
+                .and_then(|f| f.split(|&b| b == b' ').next())
+                .and_then(|f| core::str::from_utf8(f).ok())
+                .and_then(|f| f.parse().ok());
+            if size.is_some_and(|size| size > MAX_SIZE) {
+                self.cancel(EFBIG);
+                return;
+            }
+            self.size = size;
+            // The data will come once we ask for it.
+            send(CRC);
+            self.wait_for(BLOCK_TIMEOUT);
+            return;
+        }
+        if self.ending {
+            // The empty block 0 ending the batch, or the next file of it,
+            // which we don't want.
+            send(ACK);
+            if data[0] != 0 {
+                send_cancel();
+            }
+            self.finish(State::Done);
+            return;
+        }
+        if self.started && n == self.next.wrapping_sub(1) {
+            // Our ACK got lost, and the sender sent it again.
+            send(ACK);
+            self.wait_for(BLOCK_TIMEOUT);
+            return;
+        }
+        if n != self.next {
+            self.cancel(EIO);
+            return;
+        }
+        // Past the size YMODEM gave, it's padding.
+        let room = self
+            .size
+            .unwrap_or(usize::MAX)
+            .saturating_sub(self.data.len());
+        let len = len.min(room);
+        if self.data.len() + len > MAX_SIZE {
+            self.cancel(EFBIG);
+            return;
+        }
+        self.data.extend_from_slice(&data[..len]);
+        self.started = true;
+        self.next = n.wrapping_add(1);
+        send(ACK);
+        self.wait_for(BLOCK_TIMEOUT);
+        self.readers.wake_all();
+    }
+}
+
+/// Take a byte `source` received, if it's the console UART and a transfer
+/// is running. Returns whether it was taken. This is called from the
+/// interrupt handlers, through console::push().
+pub fn push(source: Source, c: u8) -> bool {
+    let t = transfer();
+    if source != Source::Uart || t.state != State::Running {
+        return false;
+    }
+    t.receive(c);
+    true
+}
+
+fn timeout(_: usize) {
+    let t = transfer();
+    if t.state == State::Running {
+        t.retry();
+    }
+}
+
+/// /dev/xmodem. The first read starts a transfer, which then belongs to
+/// this file: reads from others fail with EBUSY until it's closed, which
+/// also calls the transfer off if it isn't over.
+pub struct Xmodem {
+    owner: Cell<bool>,
+}
+
+pub fn open() -> Rc<dyn File> {
+    Rc::new(Xmodem {
+        owner: Cell::new(false),
+    })
+}
+
+impl File for Xmodem {
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, SysError> {
+        let t = transfer();
+        if !self.owner.get() {
+            if t.state != State::Idle {
+                return Err(Errno(EBUSY));
+            }
+            self.owner.set(true);
+            cpu::without_interrupts(|| t.start());
+        }
+        if offset < t.data.len() {
+            let len = buf.len().min(t.data.len() - offset);
+            buf[..len].copy_from_slice(&t.data[offset..offset + len]);
+            return Ok(len);
+        }
+        match t.state {
+            State::Running => Err(Block),
+            State::Failed(errno) => Err(Errno(errno)),
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&self, _offset: usize, _buf: &[u8]) -> Result<usize, SysError> {
+        Err(Errno(EBADF))
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            st_mode: S_IFCHR | 0o400,
+            st_nlink: 1,
+            ..Default::default()
+        }
+    }
+
+    fn wait(&self, pid: usize) {
+        transfer().readers.wait(pid);
+    }
+}
+
+impl Drop for Xmodem {
+    fn drop(&mut self) {
+        if !self.owner.get() {
+            return;
+        }
+        let t = transfer();
+        cpu::without_interrupts(|| {
+            if t.state == State::Running {
+                t.cancel(EIO);
+            }
+            t.state = State::Idle;
+            t.data = Vec::new();
+        });
+    }
+}